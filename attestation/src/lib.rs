@@ -41,7 +41,7 @@ pub enum Error {
 pub type Result<T> = core::result::Result<T, Error>;
 
 /// Number of static measurement registers
-pub const STATIC_MSMT_REGISTERS: usize = 4;
+pub const STATIC_MSMT_REGISTERS: usize = 5;
 
 /// Number of dynamically extensible measurement registers
 pub const DYNAMIC_MSMT_REGISTERS: usize = 4;
@@ -65,6 +65,9 @@ pub enum TcgPcrIndex {
     /// TVM configuration and data (PCR3)
     TvmConfiguration = 3,
 
+    /// TVM signer identity (PCR4)
+    TvmIdentity = 4,
+
     /// Runtime measurement (PCR17)
     RuntimePcr0 = 17,
 
@@ -86,6 +89,7 @@ impl TryFrom<u8> for TcgPcrIndex {
             1 => Ok(TcgPcrIndex::PlatformConfiguration),
             2 => Ok(TcgPcrIndex::TvmPage),
             3 => Ok(TcgPcrIndex::TvmConfiguration),
+            4 => Ok(TcgPcrIndex::TvmIdentity),
             17 => Ok(TcgPcrIndex::RuntimePcr0),
             18 => Ok(TcgPcrIndex::RuntimePcr1),
             19 => Ok(TcgPcrIndex::RuntimePcr2),
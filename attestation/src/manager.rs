@@ -37,6 +37,9 @@ pub struct TvmConfiguration {
 
     // Initial TVM argument (ARG1).
     entry_arg: u64,
+
+    // TVM attributes, e.g. whether the TVM can be debugged by the host.
+    attributes: u64,
 }
 
 impl TvmConfiguration {
@@ -47,6 +50,10 @@ impl TvmConfiguration {
     fn set_arg(&mut self, a1: u64) {
         self.entry_arg = a1;
     }
+
+    fn set_attributes(&mut self, attributes: u64) {
+        self.attributes = attributes;
+    }
 }
 
 /// The attestation manager.
@@ -169,16 +176,50 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
     /// This is a extend_msmt_register wrapper, where the address is not
     /// optional, and the measurement register is fixed to TvmPage.
     pub fn extend_tvm_configuration(&self) -> Result<()> {
-        self.extend_msmt_register(
-            TcgPcrIndex::TvmConfiguration,
-            &self.tvm_config.read().entry_pc.to_le_bytes(),
-            None,
-        )?;
-        self.extend_msmt_register(
-            TcgPcrIndex::TvmConfiguration,
-            &self.tvm_config.read().entry_arg.to_le_bytes(),
-            None,
-        )
+        for value in self.tvm_configuration_values() {
+            self.extend_msmt_register(TcgPcrIndex::TvmConfiguration, &value.to_le_bytes(), None)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the digest the TVM configuration measurement will have once it's extended by
+    /// `extend_tvm_configuration()`, without extending it.
+    pub fn tvm_configuration_digest(&self) -> Result<MeasurementRegisterDigest<D>> {
+        let digest = self.read_msmt_register(TcgPcrIndex::TvmConfiguration)?;
+        Ok(self
+            .tvm_configuration_values()
+            .iter()
+            .fold(digest, |digest, value| {
+                MeasurementRegister::<D>::extend_digest(&digest, &value.to_le_bytes(), None)
+            }))
+    }
+
+    // Returns the TVM configuration values, in the order they are measured.
+    fn tvm_configuration_values(&self) -> ArrayVec<u64, 3> {
+        let config = self.tvm_config.read();
+        let mut values = ArrayVec::new();
+        values.push(config.entry_pc);
+        values.push(config.entry_arg);
+        // Only measure attributes when there are some so that the measurement of a TVM without
+        // any is unchanged.
+        if config.attributes != 0 {
+            values.push(config.attributes);
+        }
+        values
+    }
+
+    /// Extend the TVM identity measurement with the signer of the TVM's manifest and the product
+    /// ID and SVN it signed. The register is extended with the hash of `public_key`, then with
+    /// `product_id` and finally with `svn`.
+    pub fn extend_tvm_identity(
+        &self,
+        public_key: &[u8],
+        product_id: &[u8],
+        svn: u32,
+    ) -> Result<()> {
+        self.extend_msmt_register(TcgPcrIndex::TvmIdentity, &D::digest(public_key), None)?;
+        self.extend_msmt_register(TcgPcrIndex::TvmIdentity, product_id, None)?;
+        self.extend_msmt_register(TcgPcrIndex::TvmIdentity, &svn.to_le_bytes(), None)
     }
 
     fn attestation_tci(&self) -> GenericArray<u8, <D as OutputSizeUser>::OutputSize> {
@@ -208,9 +249,9 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
     /// Finalize locks all measurement registers that must no longer be
     /// extended. This should be called after the platform boot process is
     /// finished in order to only allow for dynamic measurements.
+    /// The TVM configuration must have been measured with
+    /// `extend_tvm_configuration` beforehand.
     pub fn finalize(&self) -> Result<()> {
-        // Extend the TVM configuration PCR.
-        self.extend_tvm_configuration()?;
         for m in self.measurements.write().iter_mut() {
             m.finalize()
         }
@@ -253,6 +294,11 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         self.tvm_config.write().set_arg(a1);
     }
 
+    /// Set the TVM attributes.
+    pub fn set_attributes(&self, attributes: u64) {
+        self.tvm_config.write().set_attributes(attributes);
+    }
+
     /// Build the attestation capabilities.
     pub fn capabilities(&self) -> Result<AttestationCapabilities> {
         let mut caps = AttestationCapabilities::new(
@@ -276,3 +322,60 @@ impl<'a, D: Digest, H: HmacImpl<D>> AttestationManager<D, H> {
         self.attestation_layer.current_cdi()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha384;
+
+    const CDI: [u8; CDI_LEN] = [0x5a; CDI_LEN];
+
+    fn manager() -> AttestationManager<Sha384> {
+        AttestationManager::new(&CDI, &CDI, 1, const_oid::db::rfc5912::ID_SHA_384).unwrap()
+    }
+
+    #[test]
+    fn tvm_configuration_digest_matches_extend() {
+        let mgr = manager();
+        mgr.set_epc(0x8020_0000);
+        mgr.set_arg(0x1);
+        mgr.set_attributes(0x2);
+        let initial = mgr
+            .read_msmt_register(TcgPcrIndex::TvmConfiguration)
+            .unwrap();
+        let expected = mgr.tvm_configuration_digest().unwrap();
+        assert_ne!(expected, initial);
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmConfiguration)
+                .unwrap(),
+            initial
+        );
+        mgr.extend_tvm_configuration().unwrap();
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmConfiguration)
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn tvm_configuration_digest_allows_retry() {
+        // A finalize attempt that's rejected after checking the prospective configuration digest
+        // must leave the TVM able to finalize with a different configuration.
+        let mgr = manager();
+        mgr.set_epc(0x8020_0000);
+        mgr.set_arg(0x1);
+        let rejected = mgr.tvm_configuration_digest().unwrap();
+        mgr.set_arg(0x0);
+        let accepted = mgr.tvm_configuration_digest().unwrap();
+        assert_ne!(rejected, accepted);
+        mgr.extend_tvm_configuration().unwrap();
+        mgr.finalize().unwrap();
+        assert_eq!(
+            mgr.read_msmt_register(TcgPcrIndex::TvmConfiguration)
+                .unwrap(),
+            accepted
+        );
+        assert!(mgr.extend_tvm_configuration().is_err());
+    }
+}
@@ -94,6 +94,12 @@ pub const TVM_MSMT_REGISTERS: [MeasurementRegisterBuilder; MSMT_REGISTERS] = [
     msmt_dynamic_reg!(6, TcgPcrIndex::RuntimePcr2, true),
     // TCG PCR 20 - Dynamic measurements
     msmt_dynamic_reg!(7, TcgPcrIndex::RuntimePcr3, true),
+    // TCG PCR 4 - TVM signer identity
+    // The signer of the TVM's manifest and the product ID and SVN it signed,
+    // if the TVM was finalized with a verified signed manifest. Left at zero
+    // otherwise. Added last so that the other registers keep their FWID
+    // indexes.
+    msmt_static_reg!(8, TcgPcrIndex::TvmIdentity, true),
 ];
 
 /// Type of the register measured data hash.
@@ -138,18 +144,27 @@ pub struct MeasurementRegister<D: Digest> {
 
 impl<D: Digest> MeasurementRegister<D> {
     pub fn extend(&mut self, bytes: &[u8], address: Option<u64>) -> Result<()> {
-        let mut hasher = self
-            .extensible
-            .then_some(D::new_with_prefix(self.digest.clone()))
-            .ok_or(Error::LockedMeasurementRegister(self.fwid_index))?;
+        if !self.extensible {
+            return Err(Error::LockedMeasurementRegister(self.fwid_index));
+        }
+        self.digest = Self::extend_digest(&self.digest, bytes, address);
+
+        Ok(())
+    }
 
+    /// Returns `digest` extended with `bytes` and, if given, their `address`, in the same way as
+    /// `extend()` does.
+    pub fn extend_digest(
+        digest: &MeasurementRegisterDigest<D>,
+        bytes: &[u8],
+        address: Option<u64>,
+    ) -> MeasurementRegisterDigest<D> {
+        let mut hasher = D::new_with_prefix(digest.clone());
         if let Some(address) = address {
             hasher.update(address.to_le_bytes());
         }
         hasher.update(bytes);
-        self.digest = hasher.finalize();
-
-        Ok(())
+        hasher.finalize()
     }
 
     pub fn finalize(&mut self) {
@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prints a crash dump saved from salus' crash region.
+//!
+//! Usage: crash_dump_parser <DUMP>
+//!
+//! DUMP is a copy of the crash region, e.g. saved with `pmemsave` from the QEMU monitor after the
+//! next boot. Backtrace addresses can be resolved to source lines with
+//! `scripts/resolve_stack_addr.sh`.
+
+use crash_dump::*;
+use std::env;
+use std::fs;
+use std::process;
+
+const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+fn usage(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("Usage: crash_dump_parser <DUMP>");
+    process::exit(1);
+}
+
+fn print_hart(state: &HartState) {
+    let panicked = if state.flags & HART_FLAG_PANICKED != 0 {
+        " (panicked)"
+    } else {
+        ""
+    };
+    println!("Hart {}{}:", state.hart_id, panicked);
+    if state.flags & HART_FLAG_TRAP_FRAME != 0 {
+        println!(
+            "  sepc: 0x{:016x}, sstatus: 0x{:016x}",
+            state.sepc, state.sstatus
+        );
+        for (names, regs) in GPR_NAMES.chunks(4).zip(state.gprs.chunks(4)) {
+            let line: Vec<String> = names
+                .iter()
+                .zip(regs)
+                .map(|(name, reg)| format!("{:>4}: 0x{:016x}", name, reg))
+                .collect();
+            println!("  {}", line.join(", "));
+        }
+    }
+    println!("  Backtrace:");
+    for addr in state.frames() {
+        println!("    0x{:016x}", addr);
+    }
+}
+
+fn print_tvm(tvm: &TvmSummary) {
+    let state = if tvm.flags & TVM_FLAG_FINALIZED != 0 {
+        "finalized"
+    } else {
+        "initializing"
+    };
+    println!("TVM {}: {}", tvm.id, state);
+    for (id, state) in tvm.vcpus() {
+        println!("  vCPU{}: {}", id, state);
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().unwrap_or_else(|| usage("No dump path"));
+    let bytes = fs::read(&path).expect("error reading dump");
+    let dump = Dump::parse(&bytes).unwrap_or_else(|e| usage(&format!("Invalid dump: {}", e)));
+
+    let truncated = if dump.header().flags & DUMP_FLAG_TRUNCATED != 0 {
+        ", truncated"
+    } else {
+        ""
+    };
+    println!(
+        "Salus crash dump: {} bytes{}",
+        dump.header().size,
+        truncated
+    );
+    for record in dump.records() {
+        let record = record.unwrap_or_else(|e| usage(&format!("Invalid dump: {}", e)));
+        let result = match record.kind {
+            RecordType::Panic => {
+                println!("Panic: {}", record.text());
+                Ok(())
+            }
+            RecordType::Hart => HartState::decode(record.payload).map(|s| print_hart(&s)),
+            RecordType::Log => {
+                println!("Recent console output:");
+                println!("{}", record.text());
+                Ok(())
+            }
+            RecordType::MemRegion => MemRegion::decode(record.payload).map(|r| {
+                println!(
+                    "Memory region: 0x{:016x} -> 0x{:016x}, {}",
+                    r.base,
+                    (r.base + r.size).saturating_sub(1),
+                    r.name()
+                )
+            }),
+            RecordType::Tvm => TvmSummary::decode(record.payload).map(|t| print_tvm(&t)),
+            RecordType::Unknown(kind) => {
+                println!(
+                    "Unknown record type {} ({} bytes)",
+                    kind,
+                    record.payload.len()
+                );
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            println!("{}", e);
+        }
+    }
+}
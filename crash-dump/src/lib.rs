@@ -0,0 +1,801 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_std]
+
+//! The format of the crash dumps salus writes to its reserved crash region when it panics, so that
+//! they can be read back after the next boot.
+//!
+//! All fields are little-endian. A dump is laid out as follows:
+//!
+//! ```text
+//! +----------------------------+ 0
+//! | Header                     |
+//! +----------------------------+ HEADER_SIZE
+//! | Record                     | RECORD_HEADER_SIZE + payload, padded to RECORD_ALIGN
+//! | ...                        |
+//! +----------------------------+ Header::size
+//! ```
+//!
+//! Records are appended one by one, and the header's size and checksum are updated as each one is
+//! completed. A dump cut short by a hang or a nested fault is therefore still valid, up to the last
+//! record that was completed. The checksum is a CRC-32 (IEEE) of everything after the header.
+
+// For testing use the std crate.
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::{fmt, result, str};
+
+/// The magic number at the start of a dump.
+pub const MAGIC: [u8; 8] = *b"SALUSDMP";
+/// The version of the format described here.
+pub const VERSION: u32 = 1;
+
+/// Size in bytes of the dump header.
+pub const HEADER_SIZE: usize = 32;
+/// Size in bytes of the header in front of each record.
+pub const RECORD_HEADER_SIZE: usize = 8;
+/// Alignment of each record.
+pub const RECORD_ALIGN: usize = 8;
+
+/// Set in the header if some records were dropped for lack of space.
+pub const DUMP_FLAG_TRUNCATED: u32 = 1 << 0;
+
+/// The maximum number of return addresses in a hart's backtrace.
+pub const MAX_BACKTRACE_FRAMES: usize = 32;
+/// Size in bytes of a `HartState` record.
+pub const HART_STATE_SIZE: usize = 8 * (5 + 32 + MAX_BACKTRACE_FRAMES);
+/// Size in bytes of the description of a memory region.
+pub const MEM_REGION_NAME_SIZE: usize = 32;
+/// Size in bytes of a `MemRegion` record.
+pub const MEM_REGION_SIZE: usize = 16 + MEM_REGION_NAME_SIZE;
+/// Size in bytes of the fixed part of a `TvmSummary` record.
+pub const TVM_SUMMARY_SIZE: usize = 16;
+/// Size in bytes of each vCPU in a `TvmSummary` record.
+pub const TVM_VCPU_SIZE: usize = 8;
+
+/// Dump parsing errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The dump or a record is smaller than its header says.
+    Truncated,
+    /// The magic number is wrong.
+    BadMagic,
+    /// The version isn't one we understand.
+    UnsupportedVersion(u32),
+    /// The checksum doesn't match the contents.
+    BadChecksum,
+    /// A record's payload is malformed.
+    BadRecord(u32),
+}
+
+/// Custom crash dump result.
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Truncated => write!(f, "Crash dump is truncated"),
+            BadMagic => write!(f, "Not a crash dump"),
+            UnsupportedVersion(v) => write!(f, "Unsupported crash dump version {}", v),
+            BadChecksum => write!(f, "Crash dump checksum mismatch"),
+            BadRecord(kind) => write!(f, "Malformed record of type {}", kind),
+        }
+    }
+}
+
+/// Updates the CRC-32 (IEEE) `crc` of some data with the following `bytes`. Start with 0.
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// Reads little-endian integers out of a fixed-layout structure.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&self, offset: usize) -> u32 {
+        // Unwrap ok: the slice is 4 bytes long.
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        // Unwrap ok: the slice is 8 bytes long.
+        u64::from_le_bytes(self.bytes[offset..offset + 8].try_into().unwrap())
+    }
+}
+
+// Writes little-endian integers into a fixed-layout structure.
+struct Writer<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> Writer<'a> {
+    fn u32(&mut self, offset: usize, val: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn u64(&mut self, offset: usize, val: u64) {
+        self.bytes[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn slice(&mut self, offset: usize, val: &[u8]) {
+        self.bytes[offset..offset + val.len()].copy_from_slice(val);
+    }
+}
+
+/// The fixed-size header at the start of a dump.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+    /// `DUMP_FLAG_*` bits.
+    pub flags: u32,
+    /// Size in bytes of the dump, including this header.
+    pub size: u64,
+    /// CRC-32 of the `size - HEADER_SIZE` bytes following the header.
+    pub checksum: u32,
+}
+
+impl Header {
+    /// Decodes and validates the header at the start of `bytes`.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        if bytes[0..8] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let r = Reader { bytes };
+        let version = r.u32(8);
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            flags: r.u32(12),
+            size: r.u64(16),
+            checksum: r.u32(24),
+        })
+    }
+
+    /// Encodes the header.
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.slice(0, &MAGIC);
+        w.u32(8, VERSION);
+        w.u32(12, self.flags);
+        w.u64(16, self.size);
+        w.u32(24, self.checksum);
+        bytes
+    }
+}
+
+/// The kinds of records in a dump.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RecordType {
+    /// The panic message and location, as text.
+    Panic,
+    /// The registers and backtrace of a hart; see `HartState`.
+    Hart,
+    /// The most recent console output, as text.
+    Log,
+    /// A region of the hardware memory map; see `MemRegion`.
+    MemRegion,
+    /// A TVM and the state of its vCPUs; see `TvmSummary`.
+    Tvm,
+    /// A record type this version doesn't know of.
+    Unknown(u32),
+}
+
+impl RecordType {
+    /// Returns the record type with the given raw value.
+    pub fn from_raw(raw: u32) -> Self {
+        use RecordType::*;
+        match raw {
+            1 => Panic,
+            2 => Hart,
+            3 => Log,
+            4 => MemRegion,
+            5 => Tvm,
+            _ => Unknown(raw),
+        }
+    }
+
+    /// Returns the raw value of this record type.
+    pub fn raw(&self) -> u32 {
+        use RecordType::*;
+        match self {
+            Panic => 1,
+            Hart => 2,
+            Log => 3,
+            MemRegion => 4,
+            Tvm => 5,
+            Unknown(raw) => *raw,
+        }
+    }
+}
+
+/// Builds a dump in place, in the buffer it was created with.
+pub struct DumpWriter<'a> {
+    buf: &'a mut [u8],
+    header: Header,
+}
+
+impl<'a> DumpWriter<'a> {
+    /// Starts an empty dump at the start of `buf`, overwriting whatever was there. Returns `None`
+    /// if `buf` can't even hold the header.
+    pub fn new(buf: &'a mut [u8]) -> Option<Self> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        let mut writer = Self {
+            buf,
+            header: Header {
+                flags: 0,
+                size: HEADER_SIZE as u64,
+                checksum: crc32(0, &[]),
+            },
+        };
+        writer.update_header();
+        Some(writer)
+    }
+
+    /// Starts a record of type `kind`. The record is added to the dump once the returned
+    /// `RecordWriter` is dropped, or dropped from the dump if it didn't fit.
+    pub fn record(&mut self, kind: RecordType) -> RecordWriter<'_, 'a> {
+        let start = self.header.size as usize;
+        RecordWriter {
+            dump: self,
+            kind,
+            start,
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Adds a record of type `kind` with `payload`.
+    pub fn add(&mut self, kind: RecordType, payload: &[u8]) {
+        self.record(kind).write_bytes(payload);
+    }
+
+    /// Returns the current size of the dump.
+    pub fn size(&self) -> usize {
+        self.header.size as usize
+    }
+
+    fn update_header(&mut self) {
+        self.buf[..HEADER_SIZE].copy_from_slice(&self.header.encode());
+    }
+}
+
+/// Writes the payload of a record. See `DumpWriter::record()`.
+pub struct RecordWriter<'w, 'a> {
+    dump: &'w mut DumpWriter<'a>,
+    kind: RecordType,
+    start: usize,
+    len: usize,
+    overflowed: bool,
+}
+
+impl<'w, 'a> RecordWriter<'w, 'a> {
+    /// Appends `bytes` to the payload.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        let offset = self.start + RECORD_HEADER_SIZE + self.len;
+        match self.dump.buf.get_mut(offset..offset + bytes.len()) {
+            Some(dest) if !self.overflowed => {
+                dest.copy_from_slice(bytes);
+                self.len += bytes.len();
+            }
+            _ => self.overflowed = true,
+        }
+    }
+
+    /// Appends a `u32` to the payload.
+    pub fn write_u32(&mut self, val: u32) {
+        self.write_bytes(&val.to_le_bytes());
+    }
+
+    /// Appends a `u64` to the payload.
+    pub fn write_u64(&mut self, val: u64) {
+        self.write_bytes(&val.to_le_bytes());
+    }
+}
+
+impl fmt::Write for RecordWriter<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Drop for RecordWriter<'_, '_> {
+    fn drop(&mut self) {
+        let padded_len = (self.len + RECORD_ALIGN - 1) & !(RECORD_ALIGN - 1);
+        let end = self.start + RECORD_HEADER_SIZE + padded_len;
+        if self.overflowed || end > self.dump.buf.len() {
+            self.dump.header.flags |= DUMP_FLAG_TRUNCATED;
+            self.dump.update_header();
+            return;
+        }
+        let record = &mut self.dump.buf[self.start..end];
+        let mut w = Writer { bytes: record };
+        w.u32(0, self.kind.raw());
+        w.u32(4, self.len as u32);
+        record[RECORD_HEADER_SIZE + self.len..].fill(0);
+        let header = &mut self.dump.header;
+        header.checksum = crc32(header.checksum, record);
+        header.size = end as u64;
+        self.dump.update_header();
+    }
+}
+
+/// A record read from a dump.
+#[derive(Clone, Copy, Debug)]
+pub struct Record<'a> {
+    /// The type of the record.
+    pub kind: RecordType,
+    /// The record's payload, without padding.
+    pub payload: &'a [u8],
+}
+
+impl<'a> Record<'a> {
+    /// Returns the payload of a text record, replacing it with a placeholder if isn't UTF-8.
+    pub fn text(&self) -> &'a str {
+        str::from_utf8(self.payload).unwrap_or("<invalid UTF-8>")
+    }
+}
+
+/// A dump read back from a crash region.
+#[derive(Clone, Copy, Debug)]
+pub struct Dump<'a> {
+    header: Header,
+    records: &'a [u8],
+}
+
+impl<'a> Dump<'a> {
+    /// Parses and checks the dump at the start of `bytes`.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let header = Header::decode(bytes)?;
+        let records = usize::try_from(header.size)
+            .ok()
+            .filter(|&size| size >= HEADER_SIZE)
+            .and_then(|size| bytes.get(HEADER_SIZE..size))
+            .ok_or(Error::Truncated)?;
+        if crc32(0, records) != header.checksum {
+            return Err(Error::BadChecksum);
+        }
+        Ok(Self { header, records })
+    }
+
+    /// Returns the dump's header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns an iterator over the dump's records.
+    pub fn records(&self) -> RecordIter<'a> {
+        RecordIter {
+            bytes: self.records,
+        }
+    }
+
+    /// Returns the panic message, if the dump has one.
+    pub fn panic_message(&self) -> Option<&'a str> {
+        self.records()
+            .filter_map(|r| r.ok())
+            .find(|r| r.kind == RecordType::Panic)
+            .map(|r| r.text())
+    }
+}
+
+/// An iterator over the records of a dump.
+pub struct RecordIter<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for RecordIter<'a> {
+    type Item = Result<Record<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        if self.bytes.len() < RECORD_HEADER_SIZE {
+            self.bytes = &[];
+            return Some(Err(Error::Truncated));
+        }
+        let r = Reader { bytes: self.bytes };
+        let kind = RecordType::from_raw(r.u32(0));
+        let len = r.u32(4) as usize;
+        let padded_len = (len + RECORD_ALIGN - 1) & !(RECORD_ALIGN - 1);
+        let Some(payload) = self.bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len) else {
+            self.bytes = &[];
+            return Some(Err(Error::Truncated));
+        };
+        self.bytes = self
+            .bytes
+            .get(RECORD_HEADER_SIZE + padded_len..)
+            .unwrap_or(&[]);
+        Some(Ok(Record { kind, payload }))
+    }
+}
+
+/// Set in `HartState::flags` for the hart that panicked.
+pub const HART_FLAG_PANICKED: u64 = 1 << 0;
+/// Set in `HartState::flags` if `sepc`, `sstatus` and `gprs` hold the registers of a trap the hart
+/// took in the hypervisor.
+pub const HART_FLAG_TRAP_FRAME: u64 = 1 << 1;
+
+/// The state of a hart when the dump was taken.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HartState {
+    /// The hart ID.
+    pub hart_id: u64,
+    /// `HART_FLAG_*` bits.
+    pub flags: u64,
+    /// SEPC of the trap frame.
+    pub sepc: u64,
+    /// SSTATUS of the trap frame.
+    pub sstatus: u64,
+    /// The general purpose registers of the trap frame, x0 to x31.
+    pub gprs: [u64; 32],
+    /// The number of valid entries in `backtrace`.
+    pub num_frames: u64,
+    /// The hart's return addresses, innermost first.
+    pub backtrace: [u64; MAX_BACKTRACE_FRAMES],
+}
+
+impl HartState {
+    /// Decodes a hart record's payload.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != HART_STATE_SIZE {
+            return Err(Error::BadRecord(RecordType::Hart.raw()));
+        }
+        let r = Reader { bytes };
+        let mut state = Self {
+            hart_id: r.u64(0),
+            flags: r.u64(8),
+            sepc: r.u64(16),
+            sstatus: r.u64(24),
+            num_frames: r.u64(32),
+            ..Default::default()
+        };
+        if state.num_frames > MAX_BACKTRACE_FRAMES as u64 {
+            return Err(Error::BadRecord(RecordType::Hart.raw()));
+        }
+        for (i, reg) in state.gprs.iter_mut().enumerate() {
+            *reg = r.u64(40 + i * 8);
+        }
+        for (i, addr) in state.backtrace.iter_mut().enumerate() {
+            *addr = r.u64(40 + 32 * 8 + i * 8);
+        }
+        Ok(state)
+    }
+
+    /// Encodes the hart record's payload.
+    pub fn encode(&self) -> [u8; HART_STATE_SIZE] {
+        let mut bytes = [0u8; HART_STATE_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u64(0, self.hart_id);
+        w.u64(8, self.flags);
+        w.u64(16, self.sepc);
+        w.u64(24, self.sstatus);
+        w.u64(32, self.num_frames);
+        for (i, reg) in self.gprs.iter().enumerate() {
+            w.u64(40 + i * 8, *reg);
+        }
+        for (i, addr) in self.backtrace.iter().enumerate() {
+            w.u64(40 + 32 * 8 + i * 8, *addr);
+        }
+        bytes
+    }
+
+    /// Returns the valid part of the backtrace.
+    pub fn frames(&self) -> &[u64] {
+        &self.backtrace[..self.num_frames as usize]
+    }
+}
+
+/// A region of the hypervisor's memory map.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemRegion {
+    /// The base physical address of the region.
+    pub base: u64,
+    /// The size of the region in bytes.
+    pub size: u64,
+    /// What the region is used for, as text, NUL-padded.
+    pub name: [u8; MEM_REGION_NAME_SIZE],
+}
+
+impl MemRegion {
+    /// Decodes a memory region record's payload.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != MEM_REGION_SIZE {
+            return Err(Error::BadRecord(RecordType::MemRegion.raw()));
+        }
+        let r = Reader { bytes };
+        let mut name = [0u8; MEM_REGION_NAME_SIZE];
+        name.copy_from_slice(&bytes[16..]);
+        Ok(Self {
+            base: r.u64(0),
+            size: r.u64(8),
+            name,
+        })
+    }
+
+    /// Encodes the memory region record's payload.
+    pub fn encode(&self) -> [u8; MEM_REGION_SIZE] {
+        let mut bytes = [0u8; MEM_REGION_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u64(0, self.base);
+        w.u64(8, self.size);
+        w.slice(16, &self.name);
+        bytes
+    }
+
+    /// Returns the region's name without padding.
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(MEM_REGION_NAME_SIZE);
+        str::from_utf8(&self.name[..len]).unwrap_or("<invalid UTF-8>")
+    }
+}
+
+impl fmt::Write for MemRegion {
+    // Appends to the name, dropping whatever doesn't fit.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.name().len();
+        let mut count = s.len().min(MEM_REGION_NAME_SIZE - len);
+        while !s.is_char_boundary(count) {
+            count -= 1;
+        }
+        self.name[len..len + count].copy_from_slice(&s.as_bytes()[..count]);
+        Ok(())
+    }
+}
+
+/// The state of a vCPU.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VcpuState {
+    /// The vCPU is powered off.
+    PoweredOff,
+    /// The vCPU may be run.
+    Runnable,
+    /// The vCPU is running on a CPU.
+    Running,
+    /// A state this version doesn't know of.
+    Unknown(u32),
+}
+
+impl VcpuState {
+    /// Returns the vCPU state with the given raw value.
+    pub fn from_raw(raw: u32) -> Self {
+        use VcpuState::*;
+        match raw {
+            0 => PoweredOff,
+            1 => Runnable,
+            2 => Running,
+            _ => Unknown(raw),
+        }
+    }
+
+    /// Returns the raw value of this vCPU state.
+    pub fn raw(&self) -> u32 {
+        use VcpuState::*;
+        match self {
+            PoweredOff => 0,
+            Runnable => 1,
+            Running => 2,
+            Unknown(raw) => *raw,
+        }
+    }
+}
+
+impl fmt::Display for VcpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use VcpuState::*;
+        match self {
+            PoweredOff => write!(f, "powered off"),
+            Runnable => write!(f, "runnable"),
+            Running => write!(f, "running"),
+            Unknown(raw) => write!(f, "unknown ({})", raw),
+        }
+    }
+}
+
+/// Set in `TvmSummary::flags` once the TVM has been finalized.
+pub const TVM_FLAG_FINALIZED: u32 = 1 << 0;
+
+/// A TVM and its vCPUs. The fixed part of a TVM record; each vCPU follows as its ID and
+/// `VcpuState`, both `u32`s.
+#[derive(Clone, Copy, Debug)]
+pub struct TvmSummary<'a> {
+    /// The TVM's ID.
+    pub id: u64,
+    /// `TVM_FLAG_*` bits.
+    pub flags: u32,
+    vcpus: &'a [u8],
+}
+
+impl<'a> TvmSummary<'a> {
+    /// Decodes a TVM record's payload.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self> {
+        let bad_record = Error::BadRecord(RecordType::Tvm.raw());
+        if bytes.len() < TVM_SUMMARY_SIZE {
+            return Err(bad_record);
+        }
+        let r = Reader { bytes };
+        let num_vcpus = r.u32(12) as usize;
+        let vcpus = num_vcpus
+            .checked_mul(TVM_VCPU_SIZE)
+            .and_then(|len| bytes.get(TVM_SUMMARY_SIZE..TVM_SUMMARY_SIZE + len))
+            .ok_or(bad_record)?;
+        Ok(Self {
+            id: r.u64(0),
+            flags: r.u32(8),
+            vcpus,
+        })
+    }
+
+    /// Encodes the fixed part of a TVM record's payload for a TVM with `num_vcpus` vCPUs.
+    pub fn encode_header(id: u64, flags: u32, num_vcpus: u32) -> [u8; TVM_SUMMARY_SIZE] {
+        let mut bytes = [0u8; TVM_SUMMARY_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u64(0, id);
+        w.u32(8, flags);
+        w.u32(12, num_vcpus);
+        bytes
+    }
+
+    /// Encodes a vCPU of a TVM record's payload.
+    pub fn encode_vcpu(vcpu_id: u32, state: VcpuState) -> [u8; TVM_VCPU_SIZE] {
+        let mut bytes = [0u8; TVM_VCPU_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u32(0, vcpu_id);
+        w.u32(4, state.raw());
+        bytes
+    }
+
+    /// Returns an iterator over the IDs and states of the TVM's vCPUs.
+    pub fn vcpus(&self) -> impl Iterator<Item = (u32, VcpuState)> + 'a {
+        self.vcpus.chunks_exact(TVM_VCPU_SIZE).map(|vcpu| {
+            let r = Reader { bytes: vcpu };
+            (r.u32(0), VcpuState::from_raw(r.u32(4)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use std::vec::Vec;
+
+    fn hart_state() -> HartState {
+        let mut state = HartState {
+            hart_id: 3,
+            flags: HART_FLAG_PANICKED | HART_FLAG_TRAP_FRAME,
+            sepc: 0x8020_1234,
+            sstatus: 0x8000_0000_0000_0100,
+            num_frames: 2,
+            ..Default::default()
+        };
+        state.gprs[1] = 0x8020_5678;
+        state.backtrace[0] = 0x8020_0010;
+        state.backtrace[1] = 0x8020_0020;
+        state
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let mut buf = vec![0xa5u8; 4096];
+        let mut writer = DumpWriter::new(&mut buf).unwrap();
+        let line = 42;
+        write!(
+            writer.record(RecordType::Panic),
+            "panicked at src/main.rs:{}",
+            line
+        )
+        .unwrap();
+        writer.add(RecordType::Hart, &hart_state().encode());
+        writer.add(RecordType::Log, b"Salus: Boot test VM");
+        let mut region = MemRegion {
+            base: 0x8000_0000,
+            size: 0x20_0000,
+            ..Default::default()
+        };
+        write!(region, "reserved (crash dump)").unwrap();
+        writer.add(RecordType::MemRegion, &region.encode());
+        {
+            let mut tvm = writer.record(RecordType::Tvm);
+            tvm.write_bytes(&TvmSummary::encode_header(7, TVM_FLAG_FINALIZED, 2));
+            tvm.write_bytes(&TvmSummary::encode_vcpu(0, VcpuState::Running));
+            tvm.write_bytes(&TvmSummary::encode_vcpu(1, VcpuState::PoweredOff));
+        }
+        let size = writer.size();
+        assert_eq!(size % RECORD_ALIGN, 0);
+
+        let dump = Dump::parse(&buf).unwrap();
+        assert_eq!(dump.header().flags, 0);
+        assert_eq!(dump.header().size, size as u64);
+        assert_eq!(dump.panic_message(), Some("panicked at src/main.rs:42"));
+        let records: Vec<Record> = dump.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 5);
+        assert_eq!(HartState::decode(records[1].payload).unwrap(), hart_state());
+        assert_eq!(hart_state().frames(), &[0x8020_0010, 0x8020_0020]);
+        assert_eq!(records[2].text(), "Salus: Boot test VM");
+        let region = MemRegion::decode(records[3].payload).unwrap();
+        assert_eq!(region.base, 0x8000_0000);
+        assert_eq!(region.name(), "reserved (crash dump)");
+        let tvm = TvmSummary::decode(records[4].payload).unwrap();
+        assert_eq!(tvm.id, 7);
+        assert_eq!(tvm.flags, TVM_FLAG_FINALIZED);
+        let vcpus: Vec<(u32, VcpuState)> = tvm.vcpus().collect();
+        assert_eq!(vcpus, [(0, VcpuState::Running), (1, VcpuState::PoweredOff)]);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut buf = vec![0u8; HEADER_SIZE + RECORD_HEADER_SIZE + 16];
+        let mut writer = DumpWriter::new(&mut buf).unwrap();
+        writer.add(RecordType::Log, b"fits");
+        // Records that don't fit are dropped, leaving the rest of the dump intact.
+        writer.add(RecordType::Hart, &hart_state().encode());
+        let dump = Dump::parse(&buf).unwrap();
+        assert_eq!(dump.header().flags, DUMP_FLAG_TRUNCATED);
+        let records: Vec<Record> = dump.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].text(), "fits");
+
+        let mut region = MemRegion::default();
+        write!(region, "{}", "x".repeat(40)).unwrap();
+        assert_eq!(region.name().len(), MEM_REGION_NAME_SIZE);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut buf = vec![0u8; 256];
+        assert_eq!(Dump::parse(&buf).err(), Some(Error::BadMagic));
+        let mut writer = DumpWriter::new(&mut buf).unwrap();
+        writer.add(RecordType::Log, b"log");
+        assert!(Dump::parse(&buf).is_ok());
+
+        let mut bad = buf.clone();
+        bad[HEADER_SIZE + RECORD_HEADER_SIZE] ^= 1;
+        assert_eq!(Dump::parse(&bad).err(), Some(Error::BadChecksum));
+        let mut bad = buf.clone();
+        bad[20] = 0xff;
+        assert_eq!(Dump::parse(&bad).err(), Some(Error::Truncated));
+        let mut bad = buf.clone();
+        bad[8] = 2;
+        assert_eq!(Dump::parse(&bad).err(), Some(Error::UnsupportedVersion(2)));
+        assert_eq!(
+            HartState::decode(&[0u8; 8]).err(),
+            Some(Error::BadRecord(RecordType::Hart.raw()))
+        );
+        assert_eq!(
+            TvmSummary::decode(&TvmSummary::encode_header(1, 0, 4)).err(),
+            Some(Error::BadRecord(RecordType::Tvm.raw()))
+        );
+    }
+}
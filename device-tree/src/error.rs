@@ -17,6 +17,8 @@ pub enum Error {
     MalformedFdt,
     /// The given property isn't found in the node.
     PropNotFound,
+    /// The property's value isn't the expected size.
+    BadPropSize(usize),
     /// Propagated error from `fdt_rs` parsing.
     FdtError(DevTreeError),
     /// Couldn't allocate space to store the node/property.
@@ -48,6 +50,7 @@ impl fmt::Display for Error {
             Error::InvalidNodeId => write!(f, "Invalid node ID"),
             Error::MalformedFdt => write!(f, "Malformed FDT"),
             Error::PropNotFound => write!(f, "Property not found"),
+            Error::BadPropSize(len) => write!(f, "Unexpected property size {len}"),
             Error::FdtError(e) => write!(f, "FDT error: {e}"),
             Error::AllocError(e) => write!(f, "Memory allocation error: {e}"),
         }
@@ -4,7 +4,7 @@
 
 //! Wrapper for basic FDT interaction.
 
-use crate::DeviceTreeResult;
+use crate::{DeviceTreeError, DeviceTreeResult};
 use core::str;
 use fdt_rs::base::iters::{DevTreeNodeIter, DevTreeReserveEntryIter};
 use fdt_rs::base::parse::ParsedTok;
@@ -82,6 +82,31 @@ impl<'a> Fdt<'a> {
             .map(|n| ImsicInfo { inner: n })
     }
 
+    /// Returns the hypervisor configuration given by the `salus,*` and `rng-seed` properties of the
+    /// `/chosen` node. Settings that aren't present are left as `None`.
+    pub fn salus_config(&self) -> DeviceTreeResult<SalusConfig<'a>> {
+        let mut config = SalusConfig::default();
+        let Some(chosen) = self.inner.nodes().find(|n| Ok(n.name()? == "chosen"))? else {
+            return Ok(config);
+        };
+        let mut props = chosen.props();
+        while let Some(prop) = props.next()? {
+            match prop.name()? {
+                "salus,heap-size" => config.heap_size = Some(prop_cells_u64(&prop)?),
+                "salus,stack-pages" => config.stack_pages = Some(prop_cells_u64(&prop)?),
+                "salus,max-tvms" => config.max_tvms = Some(prop_cells_u64(&prop)?),
+                "salus,runtime-heap-pages" => {
+                    config.runtime_heap_pages = Some(prop_cells_u64(&prop)?)
+                }
+                "salus,log-level" => config.log_level = Some(prop.str()?),
+                "salus,crash-dump" => config.crash_dump = Some(prop_region(&prop)?),
+                "rng-seed" => config.rng_seed = Some(prop.propbuf()),
+                _ => (),
+            }
+        }
+        Ok(config)
+    }
+
     /// This returns the property buf for the first property with the give name
     pub fn get_property(&self, name: &str) -> Option<&str> {
         let mut iter = self.inner.parse_iter();
@@ -140,6 +165,76 @@ impl<'a> Fdt<'a> {
     }
 }
 
+// Reads a property holding a single one- or two-cell integer.
+fn prop_cells_u64(prop: &DevTreeProp) -> DeviceTreeResult<u64> {
+    match prop.length() {
+        4 => Ok(prop.u32(0)? as u64),
+        8 => Ok(prop.u64(0)?),
+        len => Err(DeviceTreeError::BadPropSize(len)),
+    }
+}
+
+// Reads a property holding a single two-cell base address and two-cell size.
+fn prop_region(prop: &DevTreeProp) -> DeviceTreeResult<FdtMemoryRegion> {
+    match prop.length() {
+        16 => Ok(FdtMemoryRegion {
+            base: prop.u64(0)?,
+            size: prop.u64(1)?,
+        }),
+        len => Err(DeviceTreeError::BadPropSize(len)),
+    }
+}
+
+/// Hypervisor configuration passed in the `/chosen` node of the FDT.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SalusConfig<'a> {
+    heap_size: Option<u64>,
+    stack_pages: Option<u64>,
+    max_tvms: Option<u64>,
+    runtime_heap_pages: Option<u64>,
+    log_level: Option<&'a str>,
+    crash_dump: Option<FdtMemoryRegion>,
+    rng_seed: Option<&'a [u8]>,
+}
+
+impl<'a> SalusConfig<'a> {
+    /// Returns the size in bytes of the hypervisor's boot heap (`salus,heap-size`).
+    pub fn heap_size(&self) -> Option<u64> {
+        self.heap_size
+    }
+
+    /// Returns the number of 4kB pages in each CPU's hypervisor stack (`salus,stack-pages`).
+    pub fn stack_pages(&self) -> Option<u64> {
+        self.stack_pages
+    }
+
+    /// Returns the maximum number of TVMs that may exist at once (`salus,max-tvms`).
+    pub fn max_tvms(&self) -> Option<u64> {
+        self.max_tvms
+    }
+
+    /// Returns the number of 4kB pages set aside for the hypervisor's heap after boot
+    /// (`salus,runtime-heap-pages`).
+    pub fn runtime_heap_pages(&self) -> Option<u64> {
+        self.runtime_heap_pages
+    }
+
+    /// Returns the name of the console log level (`salus,log-level`).
+    pub fn log_level(&self) -> Option<&'a str> {
+        self.log_level
+    }
+
+    /// Returns the memory region crash dumps are saved to (`salus,crash-dump`).
+    pub fn crash_dump(&self) -> Option<FdtMemoryRegion> {
+        self.crash_dump
+    }
+
+    /// Returns the random bytes provided by the bootloader (`rng-seed`).
+    pub fn rng_seed(&self) -> Option<&'a [u8]> {
+        self.rng_seed
+    }
+}
+
 /// A base address + length pair representing a region of memory.
 #[derive(Copy, Clone, Debug, Default)]
 pub struct FdtMemoryRegion {
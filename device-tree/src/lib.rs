@@ -16,5 +16,5 @@ mod serialize;
 pub use crate::device_tree::{DeviceTree, DeviceTreeIter, DeviceTreeNode};
 pub use error::Error as DeviceTreeError;
 pub use error::Result as DeviceTreeResult;
-pub use fdt::{Cpu, Fdt, FdtMemoryRegion, ImsicInfo};
+pub use fdt::{Cpu, Fdt, FdtMemoryRegion, ImsicInfo, SalusConfig};
 pub use serialize::DeviceTreeSerializer;
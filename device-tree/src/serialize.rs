@@ -308,8 +308,9 @@ impl<'a> DeviceTreeSerializer<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Fdt;
+    use crate::{DeviceTreeError, DeviceTreeNode, Fdt};
     use alloc::vec;
+    use alloc::vec::Vec;
 
     fn stub_tree() -> DeviceTree {
         // Create a tree with basic 'memory' and 'chosen' nodes.
@@ -369,4 +370,95 @@ mod tests {
         assert!(iter.next().is_none());
         assert!(fdt.reserved_memory_regions().next().is_none());
     }
+
+    // Adds the properties set by `f` to the 'chosen' node of the stub tree and returns the
+    // serialized FDT.
+    fn stub_fdt_with_chosen(f: impl FnOnce(&mut DeviceTreeNode)) -> Vec<u8> {
+        let mut tree = stub_tree();
+        let chosen = tree.iter().find(|n| n.name() == "chosen").unwrap().id();
+        f(tree.get_mut_node(chosen).unwrap());
+        let writer = DeviceTreeSerializer::new(&tree);
+        let mut buf = vec![0; writer.output_size()];
+        writer.write_to(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn salus_config() {
+        let buf = stub_fdt_with_chosen(|node| {
+            node.add_prop("salus,heap-size")
+                .unwrap()
+                .set_value_u64(&[0x200_0000])
+                .unwrap();
+            node.add_prop("salus,stack-pages")
+                .unwrap()
+                .set_value_u32(&[8])
+                .unwrap();
+            node.add_prop("salus,runtime-heap-pages")
+                .unwrap()
+                .set_value_u32(&[0x800])
+                .unwrap();
+            node.add_prop("salus,log-level")
+                .unwrap()
+                .set_value_str("debug")
+                .unwrap();
+            node.add_prop("salus,crash-dump")
+                .unwrap()
+                .set_value_u64(&[0x9000_0000, 0x20_0000])
+                .unwrap();
+            node.add_prop("rng-seed")
+                .unwrap()
+                .set_value_raw(&[1, 2, 3, 4])
+                .unwrap();
+        });
+        let fdt = unsafe {
+            // Not safe, but it's just a test.
+            Fdt::new_from_raw_pointer(buf.as_ptr()).unwrap()
+        };
+        let config = fdt.salus_config().unwrap();
+        assert_eq!(config.heap_size(), Some(0x200_0000));
+        assert_eq!(config.stack_pages(), Some(8));
+        assert_eq!(config.max_tvms(), None);
+        assert_eq!(config.runtime_heap_pages(), Some(0x800));
+        assert_eq!(config.log_level(), Some("debug"));
+        let crash_dump = config.crash_dump().unwrap();
+        assert_eq!(crash_dump.base(), 0x9000_0000);
+        assert_eq!(crash_dump.size(), 0x20_0000);
+        assert_eq!(config.rng_seed(), Some(&[1u8, 2, 3, 4][..]));
+    }
+
+    #[test]
+    fn salus_config_defaults() {
+        let buf = stub_fdt_with_chosen(|_| ());
+        let fdt = unsafe {
+            // Not safe, but it's just a test.
+            Fdt::new_from_raw_pointer(buf.as_ptr()).unwrap()
+        };
+        let config = fdt.salus_config().unwrap();
+        assert!(config.heap_size().is_none());
+        assert!(config.stack_pages().is_none());
+        assert!(config.max_tvms().is_none());
+        assert!(config.runtime_heap_pages().is_none());
+        assert!(config.log_level().is_none());
+        assert!(config.crash_dump().is_none());
+        assert!(config.rng_seed().is_none());
+    }
+
+    #[test]
+    fn salus_config_bad_size() {
+        let buf = stub_fdt_with_chosen(|node| {
+            node.add_prop("salus,max-tvms")
+                .unwrap()
+                .set_value_raw(&[0, 0, 1])
+                .unwrap();
+        });
+        let fdt = unsafe {
+            // Not safe, but it's just a test.
+            Fdt::new_from_raw_pointer(buf.as_ptr()).unwrap()
+        };
+        assert_eq!(
+            fdt.salus_config().unwrap_err(),
+            DeviceTreeError::BadPropSize(3)
+        );
+    }
 }
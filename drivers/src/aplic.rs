@@ -0,0 +1,166 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use core::ptr::NonNull;
+use device_tree::DeviceTree;
+use page_tracking::HwMemMap;
+use riscv_pages::{DeviceMemType, RawAddr};
+use sync::{Mutex, Once};
+
+/// Errors that can be returned by the APLIC driver.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No APLIC delivering MSIs to our IMSIC found in the device tree.
+    AplicNotFound,
+    /// Missing `reg` property or cells in the device tree node.
+    MissingRegisters,
+    /// Misaligned or otherwise invalid register set specified in the device tree.
+    InvalidRegisterLocation,
+    /// Failed to add an MMIO region to the system memory map.
+    AddingMmioRegion(page_tracking::MemMapError),
+    /// The interrupt source number is out of range for the domain.
+    InvalidSource(u32),
+    /// The interrupt source hasn't been delegated to the domain by firmware.
+    SourceNotDelegated(u32),
+}
+
+/// Holds the result of an APLIC driver operation.
+pub type Result<T> = core::result::Result<T, Error>;
+
+// Register offsets, in bytes, from the base of the domain's register set.
+const APLIC_DOMAINCFG: usize = 0x0000;
+const APLIC_SOURCECFG_BASE: usize = 0x0004;
+const APLIC_SETIENUM: usize = 0x1edc;
+const APLIC_TARGET_BASE: usize = 0x3004;
+
+// The domain's register set runs up to the last target register; the IDCs aren't used in MSI mode.
+const APLIC_MIN_REGISTERS_LEN: u64 = 0x4000;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM_MSI: u32 = 1 << 2;
+const SOURCECFG_SM_LEVEL_HIGH: u32 = 6;
+const TARGET_HART_INDEX_SHIFT: u32 = 18;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+static APLIC: Once<Aplic> = Once::new();
+
+/// Driver for the supervisor-level interrupt domain of an APLIC from the AIA spec, in MSI delivery
+/// mode. Used to route the wired interrupts of devices we own to our IMSIC; everything else about
+/// the domain, including the MSI address configuration, is left to firmware.
+pub struct Aplic {
+    base_address: Mutex<NonNull<u32>>,
+    phandle: u32,
+    num_sources: u32,
+}
+
+impl Aplic {
+    /// Probes for the APLIC domain delivering MSIs to the IMSIC with `imsic_phandle` from `dt`,
+    /// adding its MMIO registers to `mem_map` and enabling the domain.
+    pub fn probe_from(dt: &DeviceTree, mem_map: &mut HwMemMap, imsic_phandle: u32) -> Result<()> {
+        let node = dt
+            .iter()
+            .find(|n| {
+                n.compatible(["riscv,aplic"])
+                    && n.props()
+                        .find(|p| p.name() == "msi-parent")
+                        .and_then(|p| p.value_u32().next())
+                        == Some(imsic_phandle)
+            })
+            .ok_or(Error::AplicNotFound)?;
+        let phandle = node
+            .props()
+            .find(|p| p.name() == "phandle")
+            .and_then(|p| p.value_u32().next())
+            .ok_or(Error::AplicNotFound)?;
+        let num_sources = node
+            .props()
+            .find(|p| p.name() == "riscv,num-sources")
+            .and_then(|p| p.value_u32().next())
+            .ok_or(Error::AplicNotFound)?;
+        let mut regs = node
+            .props()
+            .find(|p| p.name() == "reg")
+            .ok_or(Error::MissingRegisters)?
+            .value_u64();
+        let base_address = regs.next().ok_or(Error::MissingRegisters)?;
+        let len = regs.next().ok_or(Error::MissingRegisters)?;
+        if base_address % 4096 != 0 || base_address == 0 || len < APLIC_MIN_REGISTERS_LEN {
+            return Err(Error::InvalidRegisterLocation);
+        }
+        // Safety: We trust that the device tree accurately described the location of the APLIC.
+        unsafe {
+            mem_map
+                .add_mmio_region(DeviceMemType::Aplic, RawAddr::supervisor(base_address), len)
+                .map_err(Error::AddingMmioRegion)
+        }?;
+        // Unwrap ok, we've already verified that base_address is non-NULL.
+        let aplic = Aplic {
+            base_address: Mutex::new(NonNull::new(base_address as _).unwrap()),
+            phandle,
+            num_sources,
+        };
+        aplic.write_reg(APLIC_DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM_MSI);
+        APLIC.call_once(|| aplic);
+        Ok(())
+    }
+
+    /// Returns the APLIC domain, if one has been probed.
+    pub fn get() -> Option<&'static Aplic> {
+        APLIC.get()
+    }
+
+    /// Returns the phandle of the APLIC domain, as used by devices' `interrupt-parent` property.
+    pub fn phandle(&self) -> u32 {
+        self.phandle
+    }
+
+    /// Routes the active-high, level-triggered wired interrupt `source` to the IMSIC at
+    /// `hart_index` as external interrupt `eiid`, and enables it.
+    pub fn route_interrupt(&self, source: u32, hart_index: u32, eiid: u32) -> Result<()> {
+        if source == 0 || source > self.num_sources {
+            return Err(Error::InvalidSource(source));
+        }
+        let index = (source - 1) as usize;
+        let sourcecfg = APLIC_SOURCECFG_BASE + index * 4;
+        self.write_reg(sourcecfg, SOURCECFG_SM_LEVEL_HIGH);
+        // Sources not delegated to this domain are read-only zero.
+        if self.read_reg(sourcecfg) != SOURCECFG_SM_LEVEL_HIGH {
+            return Err(Error::SourceNotDelegated(source));
+        }
+        self.write_reg(
+            APLIC_TARGET_BASE + index * 4,
+            (hart_index << TARGET_HART_INDEX_SHIFT) | (eiid & TARGET_EIID_MASK),
+        );
+        self.write_reg(APLIC_SETIENUM, source);
+        Ok(())
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        let base_address = self.base_address.lock();
+        // Safety: the device tree told us the domain's registers are at `base_address` and we've
+        // checked that `offset` is within them.
+        unsafe { core::ptr::read_volatile(reg_ptr(*base_address, offset)) }
+    }
+
+    fn write_reg(&self, offset: usize, val: u32) {
+        let base_address = self.base_address.lock();
+        // Safety: the device tree told us the domain's registers are at `base_address` and we've
+        // checked that `offset` is within them.
+        unsafe { core::ptr::write_volatile(reg_ptr(*base_address, offset), val) };
+    }
+}
+
+// Returns a pointer to the register at `offset` bytes from `base_address`.
+fn reg_ptr(base_address: NonNull<u32>, offset: usize) -> *mut u32 {
+    base_address
+        .as_ptr()
+        .cast::<u8>()
+        .wrapping_add(offset)
+        .cast()
+}
+
+// Safety: Access to the pointer to the APLIC's registers is guarded by a Mutex and the Aplic API
+// guarantees that it is used safely.
+unsafe impl Send for Aplic {}
+unsafe impl Sync for Aplic {}
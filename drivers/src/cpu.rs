@@ -5,6 +5,7 @@
 use arrayvec::{ArrayString, ArrayVec};
 use core::fmt;
 use device_tree::{DeviceTree, DeviceTreeNode, DeviceTreeResult};
+use riscv_regs::{pause, RiscvCsrInterface, CSR};
 use sync::Once;
 
 const MAX_ISA_STRING_LEN: usize = 256;
@@ -220,6 +221,29 @@ impl CpuInfo {
         self.has_vector
     }
 
+    /// Returns the frequency of the `time` counter in Hz.
+    pub fn timer_frequency(&self) -> u32 {
+        self.timer_frequency
+    }
+
+    /// Returns the value of the `time` counter in microseconds.
+    pub fn time_us(&self) -> u64 {
+        // `hpmcounter[1]` is the `time` CSR.
+        let ticks = CSR.hpmcounter[1].get_value();
+        let freq = self.timer_frequency as u64;
+        (ticks / freq) * 1_000_000 + (ticks % freq) * 1_000_000 / freq
+    }
+
+    /// Busy-waits for at least `us` microseconds using the `time` counter.
+    pub fn delay_us(&self, us: u64) {
+        let ticks = (self.timer_frequency as u64 * us) / 1_000_000;
+        // `hpmcounter[1]` is the `time` CSR.
+        let start = CSR.hpmcounter[1].get_value();
+        while CSR.hpmcounter[1].get_value().wrapping_sub(start) < ticks {
+            pause();
+        }
+    }
+
     /// Returns the total number of CPUs.
     pub fn num_cpus(&self) -> usize {
         self.hart_ids.len()
@@ -24,12 +24,14 @@ const MAX_GUEST_FILES: usize = 7;
 const MAX_MMIO_REGIONS: usize = 8;
 
 /// IMSIC external interrupt IDs.
-/// For now, we only expect to handle IPIs at HS-level.
+/// For now, we only expect to handle IPIs and console input at HS-level.
 #[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ImsicInterruptId {
     /// Interrupt ID for inter-processer notifications.
     Ipi = 1,
+    /// Interrupt ID for the UART, when routed to us through an APLIC.
+    Uart = 2,
 }
 
 impl ImsicInterruptId {
@@ -37,6 +39,7 @@ impl ImsicInterruptId {
     fn from_raw(id: u64) -> Option<Self> {
         match id {
             1 => Some(ImsicInterruptId::Ipi),
+            2 => Some(ImsicInterruptId::Uart),
             _ => None,
         }
     }
@@ -440,16 +443,18 @@ impl Imsic {
     }
 
     /// Initializes the IMSIC-related CSRs on this CPU. Upon return, the IMSIC on this CPU is set
-    /// up to receive IPIs.
+    /// up to receive IPIs and UART interrupts.
     pub fn setup_this_cpu() {
         // Enable external interrupt delivery.
         CSR.si_eidelivery.set(1);
         // We don't care about prioritization, so just set EITHRESHOLD to 0.
         CSR.si_eithreshold.set(0);
 
-        // The only interrupts we handle right now are IPIs.
-        let (offset, bit) = ImsicInterruptId::Ipi.offset_and_bit();
-        CSR.si_eie[offset].read_and_set_bits(1 << bit);
+        // The only interrupts we handle right now are IPIs and UART interrupts.
+        for id in [ImsicInterruptId::Ipi, ImsicInterruptId::Uart] {
+            let (offset, bit) = id.offset_and_bit();
+            CSR.si_eie[offset].read_and_set_bits(1 << bit);
+        }
     }
 
     /// Returns a reference to the global IMSIC state.
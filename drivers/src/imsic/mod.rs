@@ -13,4 +13,4 @@ pub use self::core::{
 pub use error::Error as ImsicError;
 pub use error::Result as ImsicResult;
 pub use geometry::*;
-pub use sw_file::SwFile;
+pub use sw_file::{SwFile, SW_FILE_ENTRIES};
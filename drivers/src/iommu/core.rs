@@ -14,7 +14,7 @@ use super::error::{Error, Result};
 use super::msi_page_table::MsiPageTable;
 use super::queue::*;
 use super::registers::*;
-use crate::pci::{self, PciArenaId, PciDevice, PcieRoot};
+use crate::pci::{self, PciArenaId, PciDevice, PcieRoots};
 
 // Tracks the state of an allocated global soft-context ID (GSCID).
 #[derive(Clone, Copy, Debug)]
@@ -47,25 +47,26 @@ const IOMMU_VENDOR_ID: u16 = 0x1efd;
 const IOMMU_DEVICE_ID: u16 = 0xedf1;
 
 impl Iommu {
-    /// Probes for and initializes the IOMMU device on the given PCI root. Uses `get_page` to
-    /// allocate pages for IOMMU-internal structures.
+    /// Probes for and initializes the IOMMU device on the given PCI roots. Uses `get_page` to
+    /// allocate pages for IOMMU-internal structures. Devices under all of the roots are added to
+    /// the device directory.
     pub fn probe_from(
-        pci: &PcieRoot,
+        pci: &PcieRoots,
         get_page: &mut dyn FnMut() -> Option<Page<InternalClean>>,
     ) -> Result<()> {
-        let arena_id = pci
+        let (root, arena_id) = pci
             .take_and_enable_hypervisor_device(
                 pci::VendorId::new(IOMMU_VENDOR_ID),
                 pci::DeviceId::new(IOMMU_DEVICE_ID),
             )
             .map_err(Error::ProbingIommu)?;
         let (iommu_addr, regs_base, regs_size) = {
-            let dev = pci.get_device(arena_id).unwrap().lock();
+            let dev = root.get_device(arena_id).unwrap().lock();
             // IOMMU registers are in BAR0.
             let bar = dev.bar_info().get(0).ok_or(Error::MissingRegisters)?;
             // Unwrap ok: we've already determined BAR0 is valid.
             let pci_addr = dev.get_bar_addr(0).unwrap();
-            let regs_base = pci.pci_to_physical_addr(pci_addr).unwrap();
+            let regs_base = root.pci_to_physical_addr(pci_addr).unwrap();
             let regs_size = bar.size();
             (dev.info().address(), regs_base, regs_size)
         };
@@ -197,7 +198,7 @@ impl Iommu {
             return Err(Error::OwnerMismatch);
         }
         self.ddt.enable_device(dev_id, pt, msi_pt, gscid)?;
-        dev.set_iommu_attached();
+        dev.set_iommu_attached(gscid);
         state.ref_count += 1;
         Ok(())
     }
@@ -21,7 +21,9 @@ const LEAF_INDEX_BITS: usize = 6;
 const NON_LEAF_INDEX_BITS: usize = 9;
 
 /// The device ID. Used to index into the device directory table. For PCI devices behind an IOMMU
-/// this is equivalent to the requester ID of the PCI device (i.e. the bits of the B/D/F).
+/// this is equivalent to the requester ID of the PCI device (i.e. the bits of the B/D/F), with the
+/// PCI segment number in bits 23:16. Devices in segments that don't fit in the device ID are
+/// unsupported.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct DeviceId(u32);
 
@@ -21,7 +21,10 @@ mod tests {
     use super::queue::*;
     use super::*;
     use crate::imsic::*;
-    use page_tracking::{HwMemMapBuilder, HypPageAlloc, PageList, PageTracker};
+    use page_tracking::{
+        HwMemMapBuilder, HypPageAlloc, PageList, PageTracker, DEFAULT_HYP_RESERVE_PAGES,
+        DEFAULT_MAX_GUESTS,
+    };
     use riscv_page_tables::{GuestStagePageTable, PagingMode, Sv48x4};
     use riscv_pages::*;
     use std::marker::PhantomData;
@@ -90,7 +93,12 @@ mod tests {
                 .build()
         };
         let hyp_mem = HypPageAlloc::new(&mut hw_map).unwrap();
-        let (page_tracker, host_pages) = PageTracker::from(hyp_mem, PageSize::Size4k as u64);
+        let (page_tracker, host_pages) = PageTracker::from(
+            hyp_mem,
+            PageSize::Size4k as u64,
+            DEFAULT_MAX_GUESTS,
+            DEFAULT_HYP_RESERVE_PAGES,
+        );
         // Leak the backing ram so it doesn't get freed
         std::mem::forget(backing_mem);
         (page_tracker, host_pages)
@@ -14,6 +14,8 @@ extern crate alloc;
 #[macro_use]
 extern crate std;
 
+/// Provides the driver for the APLIC from the AIA spec, for routing wired interrupts to us.
+pub mod aplic;
 /// Provides access to topology and static properties of the CPU the hypervisor is running on.
 pub mod cpu;
 /// Provides the driver for the IMSIC from the AIA spec.
@@ -26,7 +28,7 @@ pub mod pci;
 pub mod pmu;
 /// Provide a simple platform reset driver
 pub mod reset;
-/// Provides a simple UART driver for console output.
+/// Provides a simple UART driver for console input and output.
 pub mod uart;
 
 pub use cpu::{CpuId, CpuInfo, MAX_CPUS};
@@ -142,9 +142,9 @@ impl Address {
         Address(seg.0 << Segment::SHIFT | bus.0 << Bus::SHIFT | dev.0 << Device::SHIFT | func.0)
     }
 
-    /// Creates an `Address` for the given `Bus` on the first segment.
-    pub fn bus_address(bus: Bus) -> Address {
-        Address(bus.0 << Bus::SHIFT)
+    /// Creates an `Address` for the first device and function of `bus` on `segment`.
+    pub fn bus_address(segment: Segment, bus: Bus) -> Address {
+        Address(segment.0 << Segment::SHIFT | bus.0 << Bus::SHIFT)
     }
 
     /// Returns the function portion of the address.
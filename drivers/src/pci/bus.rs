@@ -60,13 +60,60 @@ impl PciBus {
             }
         }
 
+        // VFs are disabled at this point so they won't have been discovered above. Reserve an entry
+        // for every VF that each SR-IOV physical function on this bus supports so that they can be
+        // tracked once the PF's owner enables them.
+        let num_functions = devices.len();
+        for i in 0..num_functions {
+            let pf_id = devices[i].id;
+            let (pf_info, layout) = {
+                // ID must be valid, we just added it above.
+                let mut pf = device_arena.get(pf_id).unwrap().lock();
+                match pf.probe_vf_layout()? {
+                    Some(layout) => (pf.info().clone(), layout),
+                    None => continue,
+                }
+            };
+            let mut vfs = Vec::new();
+            vfs.try_reserve(layout.total_vfs() as usize)
+                .map_err(|_| Error::AllocError)?;
+            for index in 0..layout.total_vfs() {
+                // Stop at the first VF we can't track, either because it's on another bus or it
+                // collides with another function.
+                let Some(address) = layout.vf_address(pf_info.address(), index) else {
+                    break;
+                };
+                if devices.iter().any(|bd| bd.address == address) {
+                    break;
+                }
+                let Some(registers_ptr) = config_space.registers_for(address) else {
+                    break;
+                };
+                // Safety: We trust that PciConfigSpace returned a valid config space pointer for
+                // `address`, and we've checked above that no other device claims that address.
+                let vf =
+                    unsafe { PciDevice::new_vf(registers_ptr, address, pf_id, &pf_info, &layout) };
+                let id = device_arena
+                    .try_insert(Mutex::new(vf))
+                    .map_err(|_| Error::AllocError)?;
+                devices.try_reserve(1).map_err(|_| Error::AllocError)?;
+                devices.push(BusDevice { address, id });
+                vfs.push(id);
+            }
+            device_arena
+                .get(pf_id)
+                .unwrap()
+                .lock()
+                .set_virtual_functions(vfs, layout);
+        }
+
         // Recursively enumerate the buses behind any bridges on this bus.
         let mut cur_bus = bus_num;
         for bd in devices.iter() {
             let bridge_id = bd.id;
             let sec_bus = cur_bus.next().ok_or(Error::OutOfBuses)?;
             // ID must be valid, we just added it above.
-            match *device_arena.get(bridge_id).unwrap().lock() {
+            let hotplug_slot = match *device_arena.get(bridge_id).unwrap().lock() {
                 PciDevice::Bridge(ref mut bridge) => {
                     // Set the bridge to cover everything beyond sec_bus until we've enumerated
                     // the buses behind the bridge.
@@ -74,11 +121,15 @@ impl PciBus {
                         start: sec_bus,
                         end: Bus::max(),
                     });
+                    bridge.is_hotplug_capable()
                 }
                 _ => continue,
             };
 
-            let child_bus = PciBus::enumerate(config_space, sec_bus, device_arena)?;
+            let mut child_bus = PciBus::enumerate(config_space, sec_bus, device_arena)?;
+            if hotplug_slot {
+                child_bus.reserve_slot(config_space, device_arena)?;
+            }
             let sub_bus = child_bus.subordinate_bus_num();
 
             // Avoid double mutable borrow of device_arena by re-acquiring the reference to the bridge
@@ -109,6 +160,37 @@ impl PciBus {
         })
     }
 
+    // Reserves an absent device for each function of the slot on this bus that isn't occupied at
+    // enumeration time so that devices hot-added to the slot later on can be tracked.
+    fn reserve_slot(
+        &mut self,
+        config_space: &PciConfigSpace,
+        device_arena: &mut PciDeviceArena,
+    ) -> Result<()> {
+        let mut next = Some(Address::bus_address(
+            config_space.segment(),
+            self.bus_range.start,
+        ));
+        while let Some(address) = next {
+            next = address.next_function();
+            if self.devices.iter().any(|bd| bd.address == address) {
+                continue;
+            }
+            let Some(registers_ptr) = config_space.registers_for(address) else {
+                continue;
+            };
+            // Safety: We trust that PciConfigSpace returned a valid config space pointer for
+            // `address`, and we've checked above that no other device claims that address.
+            let dev = unsafe { PciDevice::new_absent(registers_ptr, address) };
+            let id = device_arena
+                .try_insert(Mutex::new(dev))
+                .map_err(|_| Error::AllocError)?;
+            self.devices.try_reserve(1).map_err(|_| Error::AllocError)?;
+            self.devices.push(BusDevice { address, id });
+        }
+        Ok(())
+    }
+
     /// Returns an iterator over the device IDs on this bus.
     pub fn devices(&self) -> core::slice::Iter<BusDevice> {
         self.devices.iter()
@@ -6,12 +6,16 @@ use arrayvec::ArrayVec;
 use core::mem::size_of;
 use enum_dispatch::enum_dispatch;
 use memoffset::offset_of;
-use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 use tock_registers::LocalRegisterCopy;
 
 use super::error::*;
 use super::mmio_builder::{MmioReadBuilder, MmioWriteBuilder};
 use super::registers::*;
+use super::resource::PciResourceType;
+
+// The D3hot power state in the PM control/status register.
+const PCI_PM_STATE_D3HOT: u16 = 3;
 
 // Standard PCI capability IDs.
 #[repr(u8)]
@@ -41,6 +45,35 @@ impl CapabilityId {
     }
 }
 
+// PCI Express extended capability IDs.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ExtendedCapabilityId {
+    Null = 0x0,
+    Aer = 0x1,
+    Acs = 0xd,
+    Ats = 0xf,
+    SrIov = 0x10,
+    Pasid = 0x1b,
+    Doe = 0x2e,
+}
+
+impl ExtendedCapabilityId {
+    // Returns the `ExtendedCapabilityId` from the raw register value.
+    fn from_raw(id: u16) -> Option<Self> {
+        use ExtendedCapabilityId::*;
+        match id {
+            0x1 => Some(Aer),
+            0xd => Some(Acs),
+            0xf => Some(Ats),
+            0x10 => Some(SrIov),
+            0x1b => Some(Pasid),
+            0x2e => Some(Doe),
+            _ => None,
+        }
+    }
+}
+
 mod header_offsets {
     use super::CapabilityHeader;
     use crate::define_field_span;
@@ -103,6 +136,82 @@ mod express_offsets {
     define_field_span!(ExpressRegisters, dev_control, u16);
     define_field_span!(ExpressRegisters, link_caps, u32);
     define_field_span!(ExpressRegisters, link_status, u16);
+    define_field_span!(ExpressRegisters, slot_caps, u32);
+    define_field_span!(ExpressRegisters, slot_control, u16);
+    define_field_span!(ExpressRegisters, slot_status, u16);
+}
+
+mod ext_header_offsets {
+    use super::ExtendedCapabilityHeader;
+    use crate::define_field_span;
+
+    define_field_span!(ExtendedCapabilityHeader, header, u32);
+}
+
+mod aer_offsets {
+    use super::AerRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(AerRegisters, uncor_status, u32);
+    define_field_span!(AerRegisters, uncor_mask, u32);
+    define_field_span!(AerRegisters, uncor_severity, u32);
+    define_field_span!(AerRegisters, cor_status, u32);
+    define_field_span!(AerRegisters, cor_mask, u32);
+    define_field_span!(AerRegisters, cap_control, u32);
+    define_field_span!(AerRegisters, header_log, [u32; 4]);
+}
+
+mod acs_offsets {
+    use super::AcsRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(AcsRegisters, acs_caps, u16);
+    define_field_span!(AcsRegisters, acs_control, u16);
+}
+
+mod ats_offsets {
+    use super::AtsRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(AtsRegisters, ats_caps, u16);
+    define_field_span!(AtsRegisters, ats_control, u16);
+}
+
+mod pasid_offsets {
+    use super::PasidRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(PasidRegisters, pasid_caps, u16);
+    define_field_span!(PasidRegisters, pasid_control, u16);
+}
+
+mod sriov_offsets {
+    use super::SriovRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(SriovRegisters, sriov_caps, u32);
+    define_field_span!(SriovRegisters, sriov_control, u16);
+    define_field_span!(SriovRegisters, initial_vfs, u16);
+    define_field_span!(SriovRegisters, total_vfs, u16);
+    define_field_span!(SriovRegisters, num_vfs, u16);
+    define_field_span!(SriovRegisters, func_dep_link, u8);
+    define_field_span!(SriovRegisters, first_vf_offset, u16);
+    define_field_span!(SriovRegisters, vf_stride, u16);
+    define_field_span!(SriovRegisters, vf_dev_id, u16);
+    define_field_span!(SriovRegisters, supported_page_sizes, u32);
+    define_field_span!(SriovRegisters, system_page_size, u32);
+    define_field_span!(SriovRegisters, vf_bar, [u32; 6]);
+}
+
+mod doe_offsets {
+    use super::DoeRegisters;
+    use crate::define_field_span;
+
+    define_field_span!(DoeRegisters, doe_caps, u32);
+    define_field_span!(DoeRegisters, doe_control, u32);
+    define_field_span!(DoeRegisters, doe_status, u32);
+    define_field_span!(DoeRegisters, write_mailbox, u32);
+    define_field_span!(DoeRegisters, read_mailbox, u32);
 }
 
 // Type-specific capability structures.
@@ -116,8 +225,19 @@ enum CapabilityType {
     PciExpress,
 }
 
+// Type-specific extended capability structures.
+#[enum_dispatch]
+enum ExtendedCapabilityType {
+    Aer,
+    Acs,
+    Ats,
+    Pasid,
+    SrIov,
+    Doe,
+}
+
 // Common functionality required by all capabilities.
-#[enum_dispatch(CapabilityType)]
+#[enum_dispatch(CapabilityType, ExtendedCapabilityType)]
 trait Capability {
     // Returns the length of the capability, including the common header.
     fn length(&self) -> usize;
@@ -144,6 +264,18 @@ impl PowerManagement {
         };
         Self { registers }
     }
+
+    // Returns if the function performs an internal reset when transitioning from D3hot to D0.
+    fn has_soft_reset(&self) -> bool {
+        !self.registers.pmcsr.is_set(PmControlStatus::NoSoftReset)
+    }
+
+    // Moves the function to the given power state.
+    fn set_power_state(&mut self, state: u16) {
+        self.registers
+            .pmcsr
+            .modify(PmControlStatus::PowerState.val(state));
+    }
 }
 
 impl Capability for PowerManagement {
@@ -480,6 +612,21 @@ impl PciExpressDeviceType {
         )
     }
 
+    // Returns if this device type is an endpoint, for which function-level reset is defined.
+    fn is_endpoint(&self) -> bool {
+        use PciExpressDeviceType::*;
+        matches!(
+            self,
+            Endpoint | LegacyEndpoint | RootComplexIntegratedEndpoint
+        )
+    }
+
+    // Returns if this device type may implement a slot.
+    fn may_have_slot(&self) -> bool {
+        use PciExpressDeviceType::*;
+        matches!(self, RootPort | DownstreamSwitchPort)
+    }
+
     // Returns if this device type implements the link control and status registers.
     fn has_link_control(&self) -> bool {
         use PciExpressDeviceType::*;
@@ -500,6 +647,9 @@ struct PciExpress {
     registers: &'static mut ExpressRegisters,
     version: u8,
     device_type: PciExpressDeviceType,
+    flr_requested: bool,
+    slot_control_request: Option<u16>,
+    slot_event: bool,
 }
 
 impl PciExpress {
@@ -522,15 +672,76 @@ impl PciExpress {
             registers,
             version,
             device_type,
+            flr_requested: false,
+            slot_control_request: None,
+            slot_event: false,
         })
     }
+
+    // Returns if the port implements the slot registers.
+    fn has_slot(&self) -> bool {
+        self.device_type.may_have_slot()
+            && self
+                .registers
+                .exp_caps
+                .is_set(ExpressCapabilities::SlotImplemented)
+    }
+
+    // Returns if the port's slot supports hotplug.
+    fn is_hotplug_capable(&self) -> bool {
+        self.has_slot()
+            && self
+                .registers
+                .slot_caps
+                .is_set(SlotCapabilities::HotPlugCapable)
+    }
+
+    // Returns if a device is present in the port's slot and its link is up. Ports that can't report
+    // the state of the link rely on presence detection alone.
+    fn slot_occupied(&self) -> bool {
+        let link_active = !self
+            .registers
+            .link_caps
+            .is_set(LinkCapabilities::DataLinkLayerLinkActiveReportingCapable)
+            || self
+                .registers
+                .link_status
+                .is_set(LinkStatus::DataLinkLayerLinkActive);
+        self.registers
+            .slot_status
+            .is_set(SlotStatus::PresenceDetectState)
+            && link_active
+    }
+
+    // Returns if the function supports function-level reset.
+    fn has_flr(&self) -> bool {
+        self.device_type.is_endpoint()
+            && self
+                .registers
+                .dev_caps
+                .is_set(DeviceCapabilities::FunctionLevelReset)
+    }
+
+    // Returns if the function has non-posted requests that have yet to complete.
+    fn transactions_pending(&self) -> bool {
+        self.registers
+            .dev_status
+            .is_set(DeviceStatus::TransactionsPending)
+    }
+
+    // Initiates a function-level reset.
+    fn initiate_flr(&mut self) {
+        self.registers
+            .dev_control
+            .modify(DeviceControl::FunctionLevelReset::SET);
+    }
 }
 
 impl Capability for PciExpress {
     fn length(&self) -> usize {
         if self.version == 2 {
             size_of::<ExpressRegisters>()
-        } else if self.device_type.has_root_control() {
+        } else if self.device_type.has_root_control() || self.device_type.may_have_slot() {
             offset_of!(ExpressRegisters, dev_caps2)
         } else if self.device_type.has_link_control() {
             offset_of!(ExpressRegisters, slot_caps)
@@ -557,6 +768,15 @@ impl Capability for PciExpress {
             link_status::span!() if self.device_type.has_link_control() => {
                 op.push_word(self.registers.link_status.readable_bits());
             }
+            slot_caps::span!() if self.has_slot() => {
+                op.push_dword(self.registers.slot_caps.readable_bits());
+            }
+            slot_control::span!() if self.has_slot() => {
+                op.push_word(self.registers.slot_control.readable_bits());
+            }
+            slot_status::span!() if self.has_slot() => {
+                op.push_word(self.registers.slot_status.readable_bits());
+            }
             _ => {
                 // Make all other capability and status bits appear unimplemented.
                 op.push_byte(0);
@@ -571,8 +791,41 @@ impl Capability for PciExpress {
                 let reg = LocalRegisterCopy::<u16, DeviceControl::Register>::new(
                     op.pop_word(self.registers.dev_control.get()),
                 );
+                // The reset itself is carried out by the hypervisor once the write completes.
+                if reg.is_set(DeviceControl::FunctionLevelReset) && self.has_flr() {
+                    self.flr_requested = true;
+                }
                 self.registers.dev_control.set(reg.writeable_bits());
             }
+            slot_control::span!() if self.has_slot() => {
+                let mut reg = LocalRegisterCopy::<u16, SlotControl::Register>::new(
+                    op.pop_word(self.registers.slot_control.get()),
+                );
+                // Powering the slot on or off adds or removes the devices in it, so the change is
+                // validated and carried out by the hypervisor once the write completes.
+                let power = self
+                    .registers
+                    .slot_control
+                    .read(SlotControl::PowerControllerControl);
+                if reg.read(SlotControl::PowerControllerControl) != power {
+                    self.slot_control_request = Some(reg.writeable_bits());
+                    reg.modify(SlotControl::PowerControllerControl.val(power));
+                }
+                self.registers.slot_control.set(reg.writeable_bits());
+            }
+            slot_status::span!() if self.has_slot() => {
+                let reg = LocalRegisterCopy::<u16, SlotStatus::Register>::new(
+                    op.pop_word(self.registers.slot_status.non_clearable_bits()),
+                );
+                // The VM is acknowledging a change in the slot's state. Rescan the slot once the
+                // write completes.
+                if reg.is_set(SlotStatus::PresenceDetectChanged)
+                    || reg.is_set(SlotStatus::DataLinkLayerStateChanged)
+                {
+                    self.slot_event = true;
+                }
+                self.registers.slot_status.set(reg.writeable_bits());
+            }
             _ => {
                 // We don't support writes to any of the othe control registers for now.
                 op.pop_byte();
@@ -581,170 +834,733 @@ impl Capability for PciExpress {
     }
 }
 
-// Represents a single PCI capability.
-struct PciCapability {
-    id: CapabilityId,
-    offset: usize,
-    next: usize,
-    cap_type: CapabilityType,
+struct Aer {
+    registers: &'static mut AerRegisters,
 }
 
-impl PciCapability {
-    // Creates a new capability of type `id` at `header`, which itself is at `offset` within the
-    // configuration space.
-    fn new(header: &mut CapabilityHeader, id: CapabilityId, offset: usize) -> Result<Self> {
-        let cap_type = match id {
-            CapabilityId::PowerManagement => PowerManagement::new(header).into(),
-            CapabilityId::Msi => Msi::new(header)?.into(),
-            CapabilityId::MsiX => MsiX::new(header).into(),
-            CapabilityId::Vendor => Vendor::new(header)?.into(),
-            CapabilityId::BridgeSubsystem => BridgeSubsystem::new(header).into(),
-            CapabilityId::PciExpress => PciExpress::new(header)?.into(),
+impl Aer {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut AerRegisters)
+                .as_mut()
+                .unwrap()
         };
-        Ok(PciCapability {
-            id,
-            offset,
-            next: 0,
-            cap_type,
-        })
-    }
-
-    // Returns the ID of this capability.
-    fn id(&self) -> CapabilityId {
-        self.id
-    }
-
-    // Returns the offset of this capability within the configuration space of this device.
-    fn offset(&self) -> usize {
-        self.offset
-    }
-
-    // Sets the offset of the next capability (from the start of the configuration space) in the
-    // linked list.
-    fn set_next(&mut self, next: usize) {
-        self.next = next;
+        Self { registers }
     }
+}
 
-    // Returns the length of this capability structure.
+// AER is passed through to the owner of the device so that it can handle its own errors.
+impl Capability for Aer {
     fn length(&self) -> usize {
-        self.cap_type.length()
+        size_of::<AerRegisters>()
     }
 
-    // Emulates a read from this capability structure.
-    fn emulate_read(&self, op: &mut MmioReadBuilder) {
-        let cap_offset = op.offset() - self.offset;
-        use header_offsets::*;
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use aer_offsets::*;
         match cap_offset {
-            cap_id::span!() => {
-                op.push_byte(self.id as u8);
+            uncor_status::span!() => {
+                op.push_dword(self.registers.uncor_status.get());
             }
-            next_cap::span!() => {
-                op.push_byte(self.next as u8);
+            uncor_mask::span!() => {
+                op.push_dword(self.registers.uncor_mask.get());
+            }
+            uncor_severity::span!() => {
+                op.push_dword(self.registers.uncor_severity.get());
+            }
+            cor_status::span!() => {
+                op.push_dword(self.registers.cor_status.get());
+            }
+            cor_mask::span!() => {
+                op.push_dword(self.registers.cor_mask.get());
+            }
+            cap_control::span!() => {
+                op.push_dword(self.registers.cap_control.readable_bits());
+            }
+            header_log::span!() => {
+                let index = (cap_offset - header_log::START_OFFSET) / size_of::<u32>();
+                op.push_dword(self.registers.header_log[index].get());
             }
             _ => {
-                self.cap_type.emulate_read(op, cap_offset);
+                op.push_byte(0);
             }
         }
     }
 
-    // Emulates a write to this capability structure.
-    fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
-        let cap_offset = op.offset() - self.offset;
-        use header_offsets::*;
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use aer_offsets::*;
         match cap_offset {
-            cap_id::span!() | next_cap::span!() => {
-                op.pop_byte();
+            uncor_status::span!() => {
+                // All the status bits are RW1C, so only write the bytes covered by the operation.
+                let reg = op.pop_dword(0);
+                self.registers.uncor_status.set(reg);
+            }
+            uncor_mask::span!() => {
+                let reg = op.pop_dword(self.registers.uncor_mask.get());
+                self.registers.uncor_mask.set(reg);
+            }
+            uncor_severity::span!() => {
+                let reg = op.pop_dword(self.registers.uncor_severity.get());
+                self.registers.uncor_severity.set(reg);
+            }
+            cor_status::span!() => {
+                let reg = op.pop_dword(0);
+                self.registers.cor_status.set(reg);
+            }
+            cor_mask::span!() => {
+                let reg = op.pop_dword(self.registers.cor_mask.get());
+                self.registers.cor_mask.set(reg);
+            }
+            cap_control::span!() => {
+                let reg = LocalRegisterCopy::<u32, AerCapabilitiesControl::Register>::new(
+                    op.pop_dword(self.registers.cap_control.get()),
+                );
+                self.registers.cap_control.set(reg.writeable_bits());
             }
             _ => {
-                self.cap_type.emulate_write(op, cap_offset);
+                op.pop_byte();
             }
         }
     }
 }
 
-// The maximum number of capabilities we support for a single device. Enough for the typical PCI
-// devices virtualized by QEMU.
-const MAX_PCI_CAPS: usize = 8;
+struct Acs {
+    registers: &'static mut AcsRegisters,
+}
 
-/// Maps the location of PCI capabilities in a device's config space and handles emulation of
-/// reads and writes to these capabilities.
-pub struct PciCapabilities {
-    caps: ArrayVec<PciCapability, MAX_PCI_CAPS>,
+impl Acs {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut AcsRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        Self { registers }
+    }
 }
 
-impl PciCapabilities {
-    /// Creates a new `PciCapabilities` by parsing the PCI capability linked-list starting at
-    /// `start_offset` within the standard PCI configuration space pointed to by `config_regs`.
-    pub fn new(config_regs: &mut CommonRegisters, start_offset: usize) -> Result<Self> {
-        let mut caps = ArrayVec::new();
-        let mut current_offset = start_offset;
-        while current_offset > PCI_TYPE_HEADER_END && current_offset < PCI_CONFIG_SPACE_END {
-            let cap_ptr = (config_regs as *mut CommonRegisters as usize + current_offset)
-                as *mut CapabilityHeader;
-            // Safety: `cap_ptr` is within the valid and uniquely-owned PCI configuration space
-            // referred to by `config_regs` and we are trusting that the hardware has initialized the
-            // capability offset registers such that they refer to valid PCI capability headers.
-            let header = unsafe { cap_ptr.as_mut().unwrap() };
-            let offset = current_offset;
-            // Per the spec, the bottom two bits of the next capability pointer should always be
-            // discarded.
-            //
-            // TODO: We should really check that capabilities don't overlap, but this is difficult
-            // since they're dynamically sized and the list can be in any order. So for now we trust
-            // that the hardware provides a valid config space.
-            current_offset = (header.next_cap.get() as usize) & !0x3;
-            if let Some(id) = CapabilityId::from_raw(header.cap_id.get()) {
-                let cap = PciCapability::new(header, id, offset)?;
-                caps.try_push(cap).map_err(|_| Error::TooManyCapabilities)?;
-            };
-        }
+impl Capability for Acs {
+    fn length(&self) -> usize {
+        // We don't expose the egress control vector.
+        size_of::<AcsRegisters>()
+    }
 
-        // Now link all the capabilities together to form a linked-list in the virtual config space.
-        for i in 1..caps.len() {
-            let next_ptr = caps[i].offset();
-            caps[i - 1].set_next(next_ptr);
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use acs_offsets::*;
+        match cap_offset {
+            acs_caps::span!() => {
+                op.push_word(self.registers.acs_caps.get());
+            }
+            acs_control::span!() => {
+                op.push_word(self.registers.acs_control.readable_bits());
+            }
+            _ => {
+                op.push_byte(0);
+            }
         }
-
-        Ok(Self { caps })
     }
 
-    /// Returns if an MSI capability is present.
-    pub fn has_msi(&self) -> bool {
-        self.capability_by_id(CapabilityId::Msi).is_some()
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, _cap_offset: usize) {
+        // ACS is under the control of the hypervisor and can't be changed by VMs.
+        op.pop_byte();
     }
+}
 
-    /// Returns if an MSI-X capability is present.
-    pub fn has_msix(&self) -> bool {
-        self.capability_by_id(CapabilityId::MsiX).is_some()
-    }
+struct Ats {
+    registers: &'static mut AtsRegisters,
+}
 
-    /// Returns if a PCI-Express capability is present.
-    pub fn is_pcie(&self) -> bool {
-        self.capability_by_id(CapabilityId::PciExpress).is_some()
+impl Ats {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut AtsRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        // ATS stays disabled until a VM that is permitted to use it enables it.
+        registers.ats_control.set(0);
+        Self { registers }
     }
+}
 
-    /// Emulates a read from this device's capabilities structures.
-    pub fn emulate_read(&self, op: &mut MmioReadBuilder) {
-        if let Some(cap) = self.capability_by_offset(op.offset()) {
-            cap.emulate_read(op);
-        } else {
-            op.push_byte(0);
-        }
+impl Capability for Ats {
+    fn length(&self) -> usize {
+        size_of::<AtsRegisters>()
     }
 
-    /// Emulates a write to this device's capabilities structures.
-    pub fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
-        if let Some(cap) = self.capability_by_offset_mut(op.offset()) {
-            cap.emulate_write(op);
-        } else {
-            op.pop_byte();
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use ats_offsets::*;
+        match cap_offset {
+            ats_caps::span!() => {
+                op.push_word(self.registers.ats_caps.get());
+            }
+            ats_control::span!() => {
+                op.push_word(self.registers.ats_control.readable_bits());
+            }
+            _ => {
+                op.push_byte(0);
+            }
         }
     }
 
-    /// Returns the offset (in the PCI configuration space) of the first emulated capability.
-    pub fn start_offset(&self) -> usize {
-        self.caps.get(0).map(|cap| cap.offset()).unwrap_or(0)
-    }
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use ats_offsets::*;
+        match cap_offset {
+            ats_control::span!() => {
+                let reg = LocalRegisterCopy::<u16, AtsControl::Register>::new(
+                    op.pop_word(self.registers.ats_control.get()),
+                );
+                self.registers.ats_control.set(reg.writeable_bits());
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+struct Pasid {
+    registers: &'static mut PasidRegisters,
+}
+
+impl Pasid {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut PasidRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        // Like ATS, PASID stays disabled until a permitted VM enables it.
+        registers.pasid_control.set(0);
+        Self { registers }
+    }
+}
+
+impl Capability for Pasid {
+    fn length(&self) -> usize {
+        size_of::<PasidRegisters>()
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use pasid_offsets::*;
+        match cap_offset {
+            pasid_caps::span!() => {
+                op.push_word(self.registers.pasid_caps.get());
+            }
+            pasid_control::span!() => {
+                op.push_word(self.registers.pasid_control.readable_bits());
+            }
+            _ => {
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use pasid_offsets::*;
+        match cap_offset {
+            pasid_control::span!() => {
+                let reg = LocalRegisterCopy::<u16, PasidControl::Register>::new(
+                    op.pop_word(self.registers.pasid_control.get()),
+                );
+                self.registers.pasid_control.set(reg.writeable_bits());
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+struct SrIov {
+    registers: &'static mut SriovRegisters,
+    control_request: Option<u16>,
+    // The size of each VF BAR, indexed by its (lower) register, or 0 if the register doesn't start
+    // an implemented VF BAR. Set once the VF BARs have been probed.
+    vf_bar_sizes: [u64; PCI_SRIOV_VF_BARS],
+}
+
+impl SrIov {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut SriovRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        // Make sure no VFs are enabled behind our back.
+        registers.sriov_control.set(0);
+        Self {
+            registers,
+            control_request: None,
+            vf_bar_sizes: [0; PCI_SRIOV_VF_BARS],
+        }
+    }
+
+    // Returns if `reg` may be written to the VF BAR register at `index`. The register must be part
+    // of an implemented VF BAR, the BAR type bits are read-only, and the address must be aligned to
+    // the size of the VF BAR. Writing all 1s is always allowed so that the owner can size the BAR.
+    fn vf_bar_write_is_valid(&self, index: usize, reg: u32) -> bool {
+        if reg == !0 {
+            return true;
+        }
+        let type_bits = (1u32 << BaseAddress::Address.shift) - 1;
+        let size = self.vf_bar_sizes[index];
+        if size != 0 {
+            let old = self.registers.vf_bar[index].get();
+            return reg & type_bits == old & type_bits && reg & !type_bits & (size - 1) as u32 == 0;
+        }
+        // Otherwise this must be the upper half of a 64-bit VF BAR.
+        let Some(lower) = index.checked_sub(1) else {
+            return false;
+        };
+        let size = self.vf_bar_sizes[lower];
+        size != 0
+            && PciResourceType::from_bar_register(self.registers.vf_bar[lower].extract()).is_64bit()
+            && reg & ((size - 1) >> 32) as u32 == 0
+    }
+}
+
+impl Capability for SrIov {
+    fn length(&self) -> usize {
+        size_of::<SriovRegisters>()
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use sriov_offsets::*;
+        match cap_offset {
+            sriov_caps::span!() => {
+                op.push_dword(self.registers.sriov_caps.readable_bits());
+            }
+            sriov_control::span!() => {
+                op.push_word(self.registers.sriov_control.readable_bits());
+            }
+            initial_vfs::span!() => {
+                op.push_word(self.registers.initial_vfs.get());
+            }
+            total_vfs::span!() => {
+                op.push_word(self.registers.total_vfs.get());
+            }
+            num_vfs::span!() => {
+                op.push_word(self.registers.num_vfs.get());
+            }
+            func_dep_link::span!() => {
+                op.push_byte(self.registers.func_dep_link.get());
+            }
+            first_vf_offset::span!() => {
+                op.push_word(self.registers.first_vf_offset.get());
+            }
+            vf_stride::span!() => {
+                op.push_word(self.registers.vf_stride.get());
+            }
+            vf_dev_id::span!() => {
+                op.push_word(self.registers.vf_dev_id.get());
+            }
+            supported_page_sizes::span!() => {
+                op.push_dword(self.registers.supported_page_sizes.get());
+            }
+            system_page_size::span!() => {
+                op.push_dword(self.registers.system_page_size.get());
+            }
+            vf_bar::span!() => {
+                let index = (cap_offset - vf_bar::START_OFFSET) / size_of::<u32>();
+                op.push_dword(self.registers.vf_bar[index].get());
+            }
+            _ => {
+                // VF migration is not supported.
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use sriov_offsets::*;
+        let vf_enabled = self.registers.sriov_control.is_set(SriovControl::VfEnable);
+        let vf_mem_enabled = self
+            .registers
+            .sriov_control
+            .is_set(SriovControl::VfMemorySpaceEnable);
+        match cap_offset {
+            sriov_control::span!() => {
+                let mut reg = LocalRegisterCopy::<u16, SriovControl::Register>::new(
+                    op.pop_word(self.registers.sriov_control.get()),
+                );
+                // Enabling or disabling VFs or VF memory space affects the VFs' presence and
+                // ownership, so those changes are validated and applied by the hypervisor once the
+                // write completes.
+                if reg.is_set(SriovControl::VfEnable) != vf_enabled
+                    || reg.is_set(SriovControl::VfMemorySpaceEnable) != vf_mem_enabled
+                {
+                    self.control_request = Some(reg.writeable_bits());
+                }
+                reg.modify(SriovControl::VfEnable.val(vf_enabled as u16));
+                reg.modify(SriovControl::VfMemorySpaceEnable.val(vf_mem_enabled as u16));
+                // ARI Capable Hierarchy changes VF routing and may only be changed while VFs are
+                // disabled.
+                if vf_enabled {
+                    let ari = self
+                        .registers
+                        .sriov_control
+                        .read(SriovControl::AriCapableHierarchy);
+                    reg.modify(SriovControl::AriCapableHierarchy.val(ari));
+                }
+                self.registers.sriov_control.set(reg.writeable_bits());
+            }
+            num_vfs::span!() => {
+                // NumVFs may only be changed while VFs are disabled.
+                let reg = op.pop_word(self.registers.num_vfs.get());
+                if !vf_enabled {
+                    self.registers.num_vfs.set(reg);
+                }
+            }
+            system_page_size::span!() => {
+                let reg = op.pop_dword(self.registers.system_page_size.get());
+                if !vf_enabled {
+                    self.registers.system_page_size.set(reg);
+                }
+            }
+            vf_bar::span!() => {
+                let index = (cap_offset - vf_bar::START_OFFSET) / size_of::<u32>();
+                let reg = op.pop_dword(self.registers.vf_bar[index].get());
+                // Discard VF BAR writes if VFs are enabled since VF BAR assignments are fixed at
+                // the time the VFs are enabled.
+                if !vf_enabled && self.vf_bar_write_is_valid(index, reg) {
+                    self.registers.vf_bar[index].set(reg);
+                }
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+struct Doe {
+    registers: &'static mut DoeRegisters,
+}
+
+impl Doe {
+    fn new(header: &mut ExtendedCapabilityHeader) -> Self {
+        // Safety: `header` points to a valid and unqiuely-owned capability structure and we are
+        // trusting that the hardware reported the type of the capability correctly.
+        let registers = unsafe {
+            (header as *mut ExtendedCapabilityHeader as *mut DoeRegisters)
+                .as_mut()
+                .unwrap()
+        };
+        Self { registers }
+    }
+}
+
+// The DOE mailboxes are passed through so that the owner of the device can exchange data objects
+// (e.g. for component measurement and authentication) with it.
+impl Capability for Doe {
+    fn length(&self) -> usize {
+        size_of::<DoeRegisters>()
+    }
+
+    fn emulate_read(&self, op: &mut MmioReadBuilder, cap_offset: usize) {
+        use doe_offsets::*;
+        match cap_offset {
+            doe_caps::span!() => {
+                op.push_dword(self.registers.doe_caps.get());
+            }
+            doe_control::span!() => {
+                op.push_dword(self.registers.doe_control.readable_bits());
+            }
+            doe_status::span!() => {
+                op.push_dword(self.registers.doe_status.readable_bits());
+            }
+            read_mailbox::span!() => {
+                op.push_dword(self.registers.read_mailbox.get());
+            }
+            _ => {
+                // The write mailbox always reads as zero.
+                op.push_byte(0);
+            }
+        }
+    }
+
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder, cap_offset: usize) {
+        use doe_offsets::*;
+        match cap_offset {
+            doe_control::span!() => {
+                let reg = LocalRegisterCopy::<u32, DoeControl::Register>::new(
+                    op.pop_dword(self.registers.doe_control.readable_bits()),
+                );
+                self.registers.doe_control.set(reg.writeable_bits());
+            }
+            doe_status::span!() => {
+                let reg = LocalRegisterCopy::<u32, DoeStatus::Register>::new(op.pop_dword(0));
+                self.registers.doe_status.set(reg.writeable_bits());
+            }
+            write_mailbox::span!() => {
+                let reg = op.pop_dword(0);
+                self.registers.write_mailbox.set(reg);
+            }
+            read_mailbox::span!() => {
+                // Any write to the read mailbox pops the current dword from the response.
+                let reg = op.pop_dword(0);
+                self.registers.read_mailbox.set(reg);
+            }
+            _ => {
+                op.pop_byte();
+            }
+        }
+    }
+}
+
+// Represents a single PCI capability.
+struct PciCapability {
+    id: CapabilityId,
+    offset: usize,
+    next: usize,
+    cap_type: CapabilityType,
+}
+
+impl PciCapability {
+    // Creates a new capability of type `id` at `header`, which itself is at `offset` within the
+    // configuration space.
+    fn new(header: &mut CapabilityHeader, id: CapabilityId, offset: usize) -> Result<Self> {
+        let cap_type = match id {
+            CapabilityId::PowerManagement => PowerManagement::new(header).into(),
+            CapabilityId::Msi => Msi::new(header)?.into(),
+            CapabilityId::MsiX => MsiX::new(header).into(),
+            CapabilityId::Vendor => Vendor::new(header)?.into(),
+            CapabilityId::BridgeSubsystem => BridgeSubsystem::new(header).into(),
+            CapabilityId::PciExpress => PciExpress::new(header)?.into(),
+        };
+        Ok(PciCapability {
+            id,
+            offset,
+            next: 0,
+            cap_type,
+        })
+    }
+
+    // Returns the ID of this capability.
+    fn id(&self) -> CapabilityId {
+        self.id
+    }
+
+    // Returns the offset of this capability within the configuration space of this device.
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // Sets the offset of the next capability (from the start of the configuration space) in the
+    // linked list.
+    fn set_next(&mut self, next: usize) {
+        self.next = next;
+    }
+
+    // Returns the length of this capability structure.
+    fn length(&self) -> usize {
+        self.cap_type.length()
+    }
+
+    // Emulates a read from this capability structure.
+    fn emulate_read(&self, op: &mut MmioReadBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use header_offsets::*;
+        match cap_offset {
+            cap_id::span!() => {
+                op.push_byte(self.id as u8);
+            }
+            next_cap::span!() => {
+                op.push_byte(self.next as u8);
+            }
+            _ => {
+                self.cap_type.emulate_read(op, cap_offset);
+            }
+        }
+    }
+
+    // Emulates a write to this capability structure.
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use header_offsets::*;
+        match cap_offset {
+            cap_id::span!() | next_cap::span!() => {
+                op.pop_byte();
+            }
+            _ => {
+                self.cap_type.emulate_write(op, cap_offset);
+            }
+        }
+    }
+}
+
+// The maximum number of capabilities we support for a single device. Enough for the typical PCI
+// devices virtualized by QEMU.
+const MAX_PCI_CAPS: usize = 8;
+
+/// Maps the location of PCI capabilities in a device's config space and handles emulation of
+/// reads and writes to these capabilities.
+#[derive(Default)]
+pub struct PciCapabilities {
+    caps: ArrayVec<PciCapability, MAX_PCI_CAPS>,
+}
+
+impl PciCapabilities {
+    /// Creates a new `PciCapabilities` by parsing the PCI capability linked-list starting at
+    /// `start_offset` within the standard PCI configuration space pointed to by `config_regs`.
+    pub fn new(config_regs: &mut CommonRegisters, start_offset: usize) -> Result<Self> {
+        let mut caps = ArrayVec::new();
+        let mut current_offset = start_offset;
+        while current_offset > PCI_TYPE_HEADER_END && current_offset < PCI_CONFIG_SPACE_END {
+            let cap_ptr = (config_regs as *mut CommonRegisters as usize + current_offset)
+                as *mut CapabilityHeader;
+            // Safety: `cap_ptr` is within the valid and uniquely-owned PCI configuration space
+            // referred to by `config_regs` and we are trusting that the hardware has initialized the
+            // capability offset registers such that they refer to valid PCI capability headers.
+            let header = unsafe { cap_ptr.as_mut().unwrap() };
+            let offset = current_offset;
+            // Per the spec, the bottom two bits of the next capability pointer should always be
+            // discarded.
+            //
+            // TODO: We should really check that capabilities don't overlap, but this is difficult
+            // since they're dynamically sized and the list can be in any order. So for now we trust
+            // that the hardware provides a valid config space.
+            current_offset = (header.next_cap.get() as usize) & !0x3;
+            if let Some(id) = CapabilityId::from_raw(header.cap_id.get()) {
+                let cap = PciCapability::new(header, id, offset)?;
+                caps.try_push(cap).map_err(|_| Error::TooManyCapabilities)?;
+            };
+        }
+
+        // Now link all the capabilities together to form a linked-list in the virtual config space.
+        for i in 1..caps.len() {
+            let next_ptr = caps[i].offset();
+            caps[i - 1].set_next(next_ptr);
+        }
+
+        Ok(Self { caps })
+    }
+
+    /// Returns if an MSI capability is present.
+    pub fn has_msi(&self) -> bool {
+        self.capability_by_id(CapabilityId::Msi).is_some()
+    }
+
+    /// Returns if an MSI-X capability is present.
+    pub fn has_msix(&self) -> bool {
+        self.capability_by_id(CapabilityId::MsiX).is_some()
+    }
+
+    /// Returns if a PCI-Express capability is present.
+    pub fn is_pcie(&self) -> bool {
+        self.capability_by_id(CapabilityId::PciExpress).is_some()
+    }
+
+    /// Returns if the device supports function-level reset.
+    pub fn has_flr(&self) -> bool {
+        self.express().map(|e| e.has_flr()).unwrap_or(false)
+    }
+
+    /// Returns if the device will reset its internal state when transitioning from D3hot to D0.
+    pub fn has_pm_soft_reset(&self) -> bool {
+        self.power_management()
+            .map(|pm| pm.has_soft_reset())
+            .unwrap_or(false)
+    }
+
+    /// Returns if the device has outstanding non-posted requests.
+    pub fn transactions_pending(&self) -> bool {
+        self.express()
+            .map(|e| e.transactions_pending())
+            .unwrap_or(false)
+    }
+
+    /// Initiates a function-level reset of the device. The caller is responsible for waiting for
+    /// the reset to complete.
+    pub fn initiate_flr(&mut self) -> Result<()> {
+        let express = self
+            .express_mut()
+            .filter(|e| e.has_flr())
+            .ok_or(Error::ResetUnsupported)?;
+        express.initiate_flr();
+        Ok(())
+    }
+
+    /// Moves the device into the D3hot (`true`) or D0 (`false`) power state. The caller is
+    /// responsible for waiting for the transition to complete.
+    pub fn set_d3hot(&mut self, d3hot: bool) -> Result<()> {
+        let pm = self.power_management_mut().ok_or(Error::ResetUnsupported)?;
+        pm.set_power_state(if d3hot { PCI_PM_STATE_D3HOT } else { 0 });
+        Ok(())
+    }
+
+    /// Returns and clears any function-level reset request made by a VM since the last call.
+    pub fn take_flr_request(&mut self) -> bool {
+        self.express_mut()
+            .map(|e| core::mem::take(&mut e.flr_requested))
+            .unwrap_or(false)
+    }
+
+    /// Returns if the device is a PCIe port with a hotplug-capable slot.
+    pub fn is_hotplug_capable(&self) -> bool {
+        self.express()
+            .map(|e| e.is_hotplug_capable())
+            .unwrap_or(false)
+    }
+
+    /// Returns if the slot of this PCIe port holds a device whose link is up.
+    pub fn slot_occupied(&self) -> bool {
+        self.express().map(|e| e.slot_occupied()).unwrap_or(false)
+    }
+
+    /// Returns and clears any hotplug event acknowledged by a VM since the last call.
+    pub fn take_slot_event(&mut self) -> bool {
+        self.express_mut()
+            .map(|e| core::mem::take(&mut e.slot_event))
+            .unwrap_or(false)
+    }
+
+    /// Returns and clears the value a VM last attempted to write to the slot control register if
+    /// it would have turned power to the slot on or off.
+    pub fn take_slot_control_request(&mut self) -> Option<u16> {
+        self.express_mut()
+            .and_then(|e| e.slot_control_request.take())
+    }
+
+    /// Sets the slot control register of this PCIe port.
+    pub fn set_slot_control(&mut self, control: u16) {
+        if let Some(e) = self.express_mut() {
+            e.registers.slot_control.set(control);
+        }
+    }
+
+    /// Emulates a read from this device's capabilities structures.
+    pub fn emulate_read(&self, op: &mut MmioReadBuilder) {
+        if let Some(cap) = self.capability_by_offset(op.offset()) {
+            cap.emulate_read(op);
+        } else {
+            op.push_byte(0);
+        }
+    }
+
+    /// Emulates a write to this device's capabilities structures.
+    pub fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
+        if let Some(cap) = self.capability_by_offset_mut(op.offset()) {
+            cap.emulate_write(op);
+        } else {
+            op.pop_byte();
+        }
+    }
+
+    /// Returns the offset (in the PCI configuration space) of the first emulated capability.
+    pub fn start_offset(&self) -> usize {
+        self.caps.get(0).map(|cap| cap.offset()).unwrap_or(0)
+    }
 
     // Returns a reference to the capability at `offset`.
     fn capability_by_offset(&self, offset: usize) -> Option<&PciCapability> {
@@ -764,6 +1580,334 @@ impl PciCapabilities {
     fn capability_by_id(&self, id: CapabilityId) -> Option<&PciCapability> {
         self.caps.iter().find(|cap| cap.id() == id)
     }
+
+    // Returns a reference to the PCI Express capability, if present.
+    fn express(&self) -> Option<&PciExpress> {
+        self.caps.iter().find_map(|cap| match cap.cap_type {
+            CapabilityType::PciExpress(ref e) => Some(e),
+            _ => None,
+        })
+    }
+
+    // Returns a mutable reference to the PCI Express capability, if present.
+    fn express_mut(&mut self) -> Option<&mut PciExpress> {
+        self.caps.iter_mut().find_map(|cap| match cap.cap_type {
+            CapabilityType::PciExpress(ref mut e) => Some(e),
+            _ => None,
+        })
+    }
+
+    // Returns a reference to the power management capability, if present.
+    fn power_management(&self) -> Option<&PowerManagement> {
+        self.caps.iter().find_map(|cap| match cap.cap_type {
+            CapabilityType::PowerManagement(ref pm) => Some(pm),
+            _ => None,
+        })
+    }
+
+    // Returns a mutable reference to the power management capability, if present.
+    fn power_management_mut(&mut self) -> Option<&mut PowerManagement> {
+        self.caps.iter_mut().find_map(|cap| match cap.cap_type {
+            CapabilityType::PowerManagement(ref mut pm) => Some(pm),
+            _ => None,
+        })
+    }
+}
+
+/// Extended capabilities that can affect the security of the system and are therefore hidden from
+/// the owner of a device unless explicitly permitted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedCapabilityPermissions {
+    /// Allows the device to issue pre-translated DMA requests via ATS.
+    pub ats: bool,
+    /// Allows the device to tag DMA requests with a PASID.
+    pub pasid: bool,
+    /// Allows the owner of the device to enable SR-IOV virtual functions.
+    pub sriov: bool,
+}
+
+// Represents a single PCI Express extended capability.
+struct PciExtendedCapability {
+    id: ExtendedCapabilityId,
+    version: u8,
+    offset: usize,
+    cap_type: ExtendedCapabilityType,
+}
+
+impl PciExtendedCapability {
+    // Creates a new extended capability of type `id` at `header`, which itself is at `offset`
+    // within the configuration space.
+    fn new(
+        header: &mut ExtendedCapabilityHeader,
+        id: ExtendedCapabilityId,
+        version: u8,
+        offset: usize,
+    ) -> Option<Self> {
+        let cap_type = match id {
+            ExtendedCapabilityId::Aer => Aer::new(header).into(),
+            ExtendedCapabilityId::Acs => Acs::new(header).into(),
+            ExtendedCapabilityId::Ats => Ats::new(header).into(),
+            ExtendedCapabilityId::Pasid => Pasid::new(header).into(),
+            ExtendedCapabilityId::SrIov => SrIov::new(header).into(),
+            ExtendedCapabilityId::Doe => Doe::new(header).into(),
+            ExtendedCapabilityId::Null => {
+                return None;
+            }
+        };
+        Some(PciExtendedCapability {
+            id,
+            version,
+            offset,
+            cap_type,
+        })
+    }
+
+    // Returns the ID of this capability.
+    fn id(&self) -> ExtendedCapabilityId {
+        self.id
+    }
+
+    // Returns the offset of this capability within the configuration space of this device.
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // Returns the length of this capability structure.
+    fn length(&self) -> usize {
+        self.cap_type.length()
+    }
+
+    // Returns if the owner of the device is allowed to see this capability.
+    fn is_permitted(&self, permissions: ExtendedCapabilityPermissions) -> bool {
+        match self.id {
+            ExtendedCapabilityId::Ats => permissions.ats,
+            ExtendedCapabilityId::Pasid => permissions.pasid,
+            ExtendedCapabilityId::SrIov => permissions.sriov,
+            _ => true,
+        }
+    }
+
+    // Emulates a read from this capability structure. `next` is the offset of the next visible
+    // capability in the list.
+    fn emulate_read(&self, op: &mut MmioReadBuilder, next: usize) {
+        let cap_offset = op.offset() - self.offset;
+        use ext_header_offsets::*;
+        match cap_offset {
+            header::span!() => {
+                op.push_dword(extended_header(self.id, self.version, next));
+            }
+            _ => {
+                self.cap_type.emulate_read(op, cap_offset);
+            }
+        }
+    }
+
+    // Emulates a write to this capability structure.
+    fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
+        let cap_offset = op.offset() - self.offset;
+        use ext_header_offsets::*;
+        match cap_offset {
+            header::span!() => {
+                op.pop_byte();
+            }
+            _ => {
+                self.cap_type.emulate_write(op, cap_offset);
+            }
+        }
+    }
+}
+
+// Returns the raw value of an extended capability header.
+fn extended_header(id: ExtendedCapabilityId, version: u8, next: usize) -> u32 {
+    let mut reg = LocalRegisterCopy::<u32, ExtendedHeader::Register>::new(0);
+    reg.modify(ExtendedHeader::Id.val(id as u32));
+    reg.modify(ExtendedHeader::Version.val(version as u32));
+    reg.modify(ExtendedHeader::NextOffset.val(next as u32));
+    reg.get()
+}
+
+// The maximum number of extended capabilities we support for a single device.
+const MAX_PCI_EXT_CAPS: usize = 16;
+
+/// Maps the location of PCI Express extended capabilities in a device's extended config space and
+/// handles emulation of reads and writes to these capabilities.
+///
+/// Unknown capabilities, and capabilities that haven't been permitted via
+/// `ExtendedCapabilityPermissions`, are hidden from the VM by unlinking them from the virtual
+/// capability list.
+#[derive(Default)]
+pub struct PciExtendedCapabilities {
+    caps: ArrayVec<PciExtendedCapability, MAX_PCI_EXT_CAPS>,
+    permissions: ExtendedCapabilityPermissions,
+}
+
+impl PciExtendedCapabilities {
+    /// Creates a new `PciExtendedCapabilities` by parsing the extended capability linked-list in
+    /// the PCI Express configuration space pointed to by `config_regs`.
+    pub fn new(config_regs: &mut CommonRegisters) -> Result<Self> {
+        let mut caps = ArrayVec::<PciExtendedCapability, MAX_PCI_EXT_CAPS>::new();
+        let mut current_offset = PCIE_EXT_CAPS_START;
+        // Bound the number of headers we'll walk in case the list contains a loop.
+        let mut remaining = (PCIE_CONFIG_SPACE_END + 1 - PCIE_EXT_CAPS_START)
+            / size_of::<ExtendedCapabilityHeader>();
+        while current_offset != 0 {
+            if !(PCIE_EXT_CAPS_START..PCIE_CONFIG_SPACE_END).contains(&current_offset)
+                || remaining == 0
+            {
+                return Err(Error::InvalidExtendedCapabilityOffset(current_offset));
+            }
+            remaining -= 1;
+            let cap_ptr = (config_regs as *mut CommonRegisters as usize + current_offset)
+                as *mut ExtendedCapabilityHeader;
+            // Safety: `cap_ptr` is within the valid and uniquely-owned PCI Express configuration
+            // space referred to by `config_regs` and we are trusting that the hardware has
+            // initialized the capability offset registers such that they refer to valid extended
+            // capability headers.
+            let header = unsafe { cap_ptr.as_mut().unwrap() };
+            let raw = header.header.get();
+            if raw == 0 || raw == !0 {
+                // An all-0s header at the start of the list means there are no extended
+                // capabilities, and all-1s means the extended config space isn't accessible.
+                break;
+            }
+            let offset = current_offset;
+            // As with standard capabilities, the bottom two bits of the next pointer are reserved.
+            current_offset = (header.header.read(ExtendedHeader::NextOffset) as usize) & !0x3;
+            let version = header.header.read(ExtendedHeader::Version) as u8;
+            let raw_id = header.header.read(ExtendedHeader::Id) as u16;
+            if let Some(cap) = ExtendedCapabilityId::from_raw(raw_id)
+                .and_then(|id| PciExtendedCapability::new(header, id, version, offset))
+            {
+                caps.try_push(cap)
+                    .map_err(|_| Error::TooManyExtendedCapabilities)?;
+            }
+        }
+
+        Ok(Self {
+            caps,
+            permissions: ExtendedCapabilityPermissions::default(),
+        })
+    }
+
+    /// Returns if an SR-IOV capability is present.
+    pub fn has_sriov(&self) -> bool {
+        self.capability_by_id(ExtendedCapabilityId::SrIov).is_some()
+    }
+
+    /// Returns the SR-IOV registers if an SR-IOV capability is present.
+    pub fn sriov_registers(&self) -> Option<&SriovRegisters> {
+        self.caps.iter().find_map(|cap| match cap.cap_type {
+            ExtendedCapabilityType::SrIov(ref sriov) => Some(&*sriov.registers),
+            _ => None,
+        })
+    }
+
+    /// Returns the SR-IOV registers if an SR-IOV capability is present.
+    pub fn sriov_registers_mut(&mut self) -> Option<&mut SriovRegisters> {
+        self.caps.iter_mut().find_map(|cap| match cap.cap_type {
+            ExtendedCapabilityType::SrIov(ref mut sriov) => Some(&mut *sriov.registers),
+            _ => None,
+        })
+    }
+
+    /// Records the sizes of the VF BARs probed from the SR-IOV capability, indexed by the register
+    /// each BAR starts at, so that writes to the VF BARs can be validated.
+    pub fn set_vf_bar_sizes(&mut self, sizes: [u64; PCI_SRIOV_VF_BARS]) {
+        for cap in self.caps.iter_mut() {
+            if let ExtendedCapabilityType::SrIov(ref mut sriov) = cap.cap_type {
+                sriov.vf_bar_sizes = sizes;
+            }
+        }
+    }
+
+    /// Returns and clears the value the owner of the device last attempted to write to the SR-IOV
+    /// control register if the write would have changed VF Enable or VF Memory Space Enable.
+    pub fn take_sriov_control_request(&mut self) -> Option<u16> {
+        self.caps.iter_mut().find_map(|cap| match cap.cap_type {
+            ExtendedCapabilityType::SrIov(ref mut sriov) => sriov.control_request.take(),
+            _ => None,
+        })
+    }
+
+    /// Returns if an ATS capability is present.
+    pub fn has_ats(&self) -> bool {
+        self.capability_by_id(ExtendedCapabilityId::Ats).is_some()
+    }
+
+    /// Returns the set of extended capabilities the owner of the device is permitted to use.
+    pub fn permissions(&self) -> ExtendedCapabilityPermissions {
+        self.permissions
+    }
+
+    /// Sets the extended capabilities the owner of the device is permitted to use. Capabilities
+    /// which are no longer permitted are disabled.
+    pub fn set_permissions(&mut self, permissions: ExtendedCapabilityPermissions) {
+        self.permissions = permissions;
+        for cap in self.caps.iter_mut() {
+            if cap.is_permitted(permissions) {
+                continue;
+            }
+            match cap.cap_type {
+                ExtendedCapabilityType::Ats(ref mut ats) => ats.registers.ats_control.set(0),
+                ExtendedCapabilityType::Pasid(ref mut pasid) => {
+                    pasid.registers.pasid_control.set(0)
+                }
+                ExtendedCapabilityType::SrIov(ref mut sriov) => {
+                    sriov.registers.sriov_control.set(0)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Emulates a read from this device's extended capabilities structures.
+    pub fn emulate_read(&self, op: &mut MmioReadBuilder) {
+        if let Some(index) = self.visible_index_by_offset(op.offset()) {
+            let next = self.next_visible_offset(index + 1);
+            self.caps[index].emulate_read(op, next);
+        } else if op.offset() < PCIE_EXT_CAPS_START + size_of::<ExtendedCapabilityHeader>() {
+            // The extended capability list must start at a fixed offset. If the first capability
+            // is hidden, put a null capability in its place that links to the first visible one.
+            let next = self.next_visible_offset(0);
+            op.push_dword(extended_header(ExtendedCapabilityId::Null, 0, next));
+        } else {
+            op.push_byte(0);
+        }
+    }
+
+    /// Emulates a write to this device's extended capabilities structures.
+    pub fn emulate_write(&mut self, op: &mut MmioWriteBuilder) {
+        if let Some(index) = self.visible_index_by_offset(op.offset()) {
+            self.caps[index].emulate_write(op);
+        } else {
+            op.pop_byte();
+        }
+    }
+
+    // Returns the index of the visible capability at `offset`.
+    fn visible_index_by_offset(&self, offset: usize) -> Option<usize> {
+        self.caps.iter().position(|cap| {
+            cap.offset() <= offset
+                && offset < (cap.offset() + cap.length())
+                && cap.is_permitted(self.permissions)
+        })
+    }
+
+    // Returns the offset of the first visible capability at or after `index` in the list, or 0 if
+    // there are none.
+    fn next_visible_offset(&self, index: usize) -> usize {
+        self.caps
+            .iter()
+            .skip(index)
+            .find(|cap| cap.is_permitted(self.permissions))
+            .map(|cap| cap.offset())
+            .unwrap_or(0)
+    }
+
+    // Gets the capability with the given ID.
+    fn capability_by_id(&self, id: ExtendedCapabilityId) -> Option<&PciExtendedCapability> {
+        self.caps.iter().find(|cap| cap.id() == id)
+    }
 }
 
 #[cfg(test)]
@@ -807,4 +1951,180 @@ mod tests {
             0x5c
         );
     }
+
+    #[test]
+    fn flr_request() {
+        let mut test_config: [u32; 64] = [0; 64];
+        test_config[16] = 0x0002_0010; // PCIe v2 endpoint
+        test_config[17] = 0x1000_0000; // FLR capable
+        let mut header_mem: Vec<u8> = test_config
+            .iter()
+            .map(|v| v.to_le_bytes())
+            .flatten()
+            .collect();
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciCapabilities::new(regs, 0x40).unwrap();
+        assert!(caps.is_pcie());
+        assert!(caps.has_flr());
+        assert!(!caps.take_flr_request());
+
+        // Writing DEV_CTL.FLR records a request but doesn't pass the bit through.
+        let mut op = MmioWriteBuilder::new(0x48, 0x8000, 2);
+        while !op.done() {
+            caps.emulate_write(&mut op);
+        }
+        assert_eq!(test_config_word(&header_mem, 0x48), 0);
+        assert!(caps.take_flr_request());
+        assert!(!caps.take_flr_request());
+    }
+
+    #[test]
+    fn parse_ext_caps() {
+        let mut test_config: [u32; 1024] = [0; 1024];
+        test_config[64] = 0x1101_000f; // ATS
+        test_config[65] = 0x8000_0020;
+        test_config[68] = 0x1481_0001; // AER
+        test_config[69] = 0x0000_00ff;
+        test_config[82] = 0x1501_000d; // ACS
+        test_config[83] = 0x0001_001f;
+        test_config[84] = 0x1601_0019; // Secondary PCIe (unknown)
+        test_config[88] = 0x0001_0010; // SR-IOV
+        let mut header_mem: Vec<u8> = test_config
+            .iter()
+            .map(|v| v.to_le_bytes())
+            .flatten()
+            .collect();
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciExtendedCapabilities::new(regs).unwrap();
+        assert!(caps.has_ats());
+        assert!(caps.has_sriov());
+        assert_eq!(
+            caps.capability_by_id(ExtendedCapabilityId::Acs)
+                .unwrap()
+                .offset(),
+            0x148
+        );
+
+        let read_dword = |caps: &PciExtendedCapabilities, offset| {
+            let mut op = MmioReadBuilder::new(offset, 4);
+            while !op.done() {
+                caps.emulate_read(&mut op);
+            }
+            op.result()
+        };
+
+        // ATS is hidden by default and replaced with a null capability. The unknown capability and
+        // SR-IOV are hidden as well.
+        assert_eq!(read_dword(&caps, 0x100), 0x1100_0000);
+        assert_eq!(read_dword(&caps, 0x104), 0);
+        assert_eq!(read_dword(&caps, 0x110), 0x1481_0001);
+        assert_eq!(read_dword(&caps, 0x114), 0xff);
+        assert_eq!(read_dword(&caps, 0x148), 0x0001_000d);
+        assert_eq!(read_dword(&caps, 0x150), 0);
+
+        // ACS control is read-only.
+        let mut op = MmioWriteBuilder::new(0x14e, 0, 2);
+        while !op.done() {
+            caps.emulate_write(&mut op);
+        }
+        assert_eq!(read_dword(&caps, 0x14c), 0x0001_001f);
+
+        // Writes to hidden capabilities are dropped.
+        let mut op = MmioWriteBuilder::new(0x106, 0x8000, 2);
+        while !op.done() {
+            caps.emulate_write(&mut op);
+        }
+        assert_eq!(test_config_dword(&header_mem, 0x104), 0x20);
+
+        caps.set_permissions(ExtendedCapabilityPermissions {
+            ats: true,
+            pasid: false,
+            sriov: true,
+        });
+        assert_eq!(read_dword(&caps, 0x100), 0x1101_000f);
+        assert_eq!(read_dword(&caps, 0x148), 0x1601_000d);
+        assert_eq!(read_dword(&caps, 0x160), 0x0001_0010);
+        let mut op = MmioWriteBuilder::new(0x106, 0x8000, 2);
+        while !op.done() {
+            caps.emulate_write(&mut op);
+        }
+        assert_eq!(test_config_dword(&header_mem, 0x104), 0x8000_0020);
+
+        // Setting VF Enable is deferred to the hypervisor.
+        let mut op = MmioWriteBuilder::new(0x168, 0x1, 2);
+        while !op.done() {
+            caps.emulate_write(&mut op);
+        }
+        assert_eq!(test_config_word(&header_mem, 0x168), 0);
+        assert_eq!(caps.take_sriov_control_request(), Some(0x1));
+        assert_eq!(caps.take_sriov_control_request(), None);
+    }
+
+    #[test]
+    fn sriov_vf_bar_writes() {
+        let mut test_config: [u32; 1024] = [0; 1024];
+        test_config[64] = 0x0001_0010; // SR-IOV
+        test_config[73] = 0x0000_000c; // 64-bit prefetchable VF BAR0
+        let mut header_mem: Vec<u8> = test_config
+            .iter()
+            .map(|v| v.to_le_bytes())
+            .flatten()
+            .collect();
+        // Not safe, just a test.
+        let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_mut() }.unwrap();
+        let mut caps = PciExtendedCapabilities::new(regs).unwrap();
+        caps.set_permissions(ExtendedCapabilityPermissions {
+            ats: false,
+            pasid: false,
+            sriov: true,
+        });
+        let write_dword = |caps: &mut PciExtendedCapabilities, offset, value| {
+            let mut op = MmioWriteBuilder::new(offset, value, 4);
+            while !op.done() {
+                caps.emulate_write(&mut op);
+            }
+        };
+
+        // VF BAR writes are dropped until the VF BARs have been probed.
+        write_dword(&mut caps, 0x124, 0x1000_400c);
+        assert_eq!(test_config_dword(&header_mem, 0x124), 0xc);
+
+        caps.set_vf_bar_sizes([0x4000, 0, 0x1000, 0, 0, 0]);
+        // Misaligned addresses and changes to the BAR type are rejected.
+        write_dword(&mut caps, 0x124, 0x1000_200c);
+        assert_eq!(test_config_dword(&header_mem, 0x124), 0xc);
+        write_dword(&mut caps, 0x124, 0x1000_4000);
+        assert_eq!(test_config_dword(&header_mem, 0x124), 0xc);
+        write_dword(&mut caps, 0x124, 0x1000_400c);
+        assert_eq!(test_config_dword(&header_mem, 0x124), 0x1000_400c);
+        // The upper half of the 64-bit BAR. Writing all 1s to size the BAR is allowed.
+        write_dword(&mut caps, 0x128, !0);
+        assert_eq!(test_config_dword(&header_mem, 0x128), !0);
+        write_dword(&mut caps, 0x128, 0x1);
+        assert_eq!(test_config_dword(&header_mem, 0x128), 0x1);
+
+        write_dword(&mut caps, 0x12c, 0x2000_0800);
+        assert_eq!(test_config_dword(&header_mem, 0x12c), 0);
+        write_dword(&mut caps, 0x12c, 0x2000_1000);
+        assert_eq!(test_config_dword(&header_mem, 0x12c), 0x2000_1000);
+
+        // Registers that aren't part of a VF BAR can't be written.
+        write_dword(&mut caps, 0x130, 0x3000_0000);
+        assert_eq!(test_config_dword(&header_mem, 0x130), 0);
+
+        // Nor can the VF BARs once VFs are enabled.
+        caps.sriov_registers_mut().unwrap().sriov_control.set(0x1);
+        write_dword(&mut caps, 0x12c, 0x2000_2000);
+        assert_eq!(test_config_dword(&header_mem, 0x12c), 0x2000_1000);
+    }
+
+    fn test_config_word(mem: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(mem[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn test_config_dword(mem: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(mem[offset..offset + 4].try_into().unwrap())
+    }
 }
@@ -123,8 +123,11 @@ impl PciConfigSpace {
 
     // Returns the offset of the given address within this PciConfigSpace.
     fn config_space_offset(&self, address: Address) -> Option<u64> {
+        if address.segment() != self.segment {
+            return None;
+        }
         (address.bits() as u64)
-            .checked_sub(Address::bus_address(self.bus_range.start).bits() as u64)
+            .checked_sub(Address::bus_address(self.segment, self.bus_range.start).bits() as u64)
             .map(|a| a << PCIE_ECAM_FN_SHIFT)
     }
 }
@@ -235,5 +238,7 @@ mod tests {
         assert!(config_space.info_for(first_addr).is_some());
         let second_address = first_addr.next_function().unwrap();
         assert!(config_space.info_for(second_address).is_none());
+        let other_segment = Address::try_from_components(1, 0, 0, 0).unwrap();
+        assert!(config_space.info_for(other_segment).is_none());
     }
 }
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use alloc::vec::Vec;
 use arrayvec::ArrayVec;
 use core::fmt;
 use core::mem::size_of;
@@ -19,6 +20,25 @@ use super::error::*;
 use super::mmio_builder::*;
 use super::registers::*;
 use super::resource::*;
+use super::root::PciArenaId;
+use crate::iommu::{GscId, Iommu};
+
+// Time for which the secondary bus reset bit is held asserted.
+const BUS_RESET_ASSERT_US: u64 = 2_000;
+// Time to wait after a conventional reset before issuing config requests, per PCIe 6.0
+// section 6.6.1.
+const RESET_RECOVERY_US: u64 = 100_000;
+// Time to wait for outstanding transactions to drain before issuing an FLR.
+const FLR_PENDING_TIMEOUT_US: u64 = 100_000;
+// Time for an FLR to complete, per PCIe 6.0 section 6.6.2.
+const FLR_COMPLETION_US: u64 = 100_000;
+// Time for a D3hot <-> D0 transition, per PCI PM 1.2 section 5.6.1.
+const PM_TRANSITION_US: u64 = 10_000;
+// Time for which a device may return CRS completions after a reset.
+const RESET_READY_TIMEOUT_US: u64 = 1_000_000;
+// Time to wait after setting VF Enable before issuing config requests to the VFs, per PCIe 6.0
+// section 9.3.3.3.1.
+const VF_ENABLE_DELAY_US: u64 = 100_000;
 
 /// The Vendor Id from the PCI header.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
@@ -127,6 +147,34 @@ impl PciDeviceInfo {
         Some(info)
     }
 
+    // Creates the `PciDeviceInfo` for an SR-IOV virtual function at `address`. VFs don't implement
+    // the vendor and device ID registers, so these are taken from the physical function instead.
+    fn for_vf(address: Address, pf_info: &PciDeviceInfo, device_id: DeviceId) -> Self {
+        Self {
+            address,
+            vendor_id: pf_info.vendor_id,
+            device_id,
+            // The class code of a VF must match that of its PF.
+            class: pf_info.class,
+            subclass: pf_info.subclass,
+            multi_function: false,
+            header_type: HeaderType::Endpoint,
+        }
+    }
+
+    // Creates the `PciDeviceInfo` for a function at `address` which is not currently present.
+    fn absent(address: Address) -> Self {
+        Self {
+            address,
+            vendor_id: VendorId::invalid(),
+            device_id: DeviceId::default(),
+            class: Class::default(),
+            subclass: SubClass::default(),
+            multi_function: false,
+            header_type: HeaderType::Endpoint,
+        }
+    }
+
     /// Returns the PCI Adress of this PCI header.
     pub fn address(&self) -> Address {
         self.address
@@ -324,19 +372,151 @@ impl PciDeviceBarInfo {
     }
 }
 
+/// Describes the virtual functions that may be enabled by an SR-IOV physical function.
+#[derive(Clone)]
+pub struct PciVfLayout {
+    total_vfs: u16,
+    first_vf_offset: u16,
+    vf_stride: u16,
+    device_id: DeviceId,
+    bar_info: PciDeviceBarInfo,
+}
+
+impl PciVfLayout {
+    /// Returns the maximum number of VFs supported by the physical function.
+    pub fn total_vfs(&self) -> u16 {
+        self.total_vfs
+    }
+
+    /// Returns the address of the VF at `index` of the physical function at `pf_address`, or
+    /// `None` if the VF wouldn't be on the same bus as the physical function.
+    pub fn vf_address(&self, pf_address: Address, index: u16) -> Option<Address> {
+        let pf_routing_id = (pf_address.bus().bits() << 8)
+            | (pf_address.device().bits() << 3)
+            | pf_address.function().bits();
+        let routing_id = pf_routing_id as u64
+            + self.first_vf_offset as u64
+            + (index as u64) * (self.vf_stride as u64);
+        // VFs on other buses would require reserving bus numbers behind the upstream bridge, which
+        // we don't support.
+        if routing_id >> 8 != pf_address.bus().bits() as u64 {
+            return None;
+        }
+        Address::try_from_components(
+            pf_address.segment().bits(),
+            (routing_id >> 8) as u32,
+            ((routing_id >> 3) & 0x1f) as u32,
+            (routing_id & 0x7) as u32,
+        )
+    }
+}
+
+// State tracked for an SR-IOV physical function.
+struct PciPfState {
+    vfs: Vec<PciArenaId>,
+    layout: PciVfLayout,
+    // Set while VFs are being enabled to the time from which they can be accessed.
+    vf_enable_deadline_us: Option<u64>,
+}
+
+// State tracked for an SR-IOV virtual function.
+struct PciVfState {
+    pf_id: PciArenaId,
+    bar_addrs: [u64; PCI_SRIOV_VF_BARS],
+}
+
+// The stages of a reset. Each stage lasts until its deadline passes, or until the function is done
+// with it for the stages that poll the function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResetStage {
+    // Waiting for outstanding non-posted requests to drain before issuing an FLR.
+    DrainTransactions,
+    // Waiting for an FLR to complete.
+    Flr,
+    // Waiting for the function to enter D3hot.
+    D3hot,
+    // Waiting for the function to return to D0.
+    D0,
+    // Held in reset by a secondary bus reset of a bridge above the function, which moves the
+    // function on to `WaitReady` once the reset is over.
+    BusReset,
+    // Holding the secondary bus of a bridge in reset.
+    BusResetAsserted,
+    // Waiting for the devices below a bridge to recover from a secondary bus reset.
+    BusResetRecovery,
+    // Waiting for the function to respond to config requests again.
+    WaitReady,
+}
+
+// A reset in progress, along with the time, in microseconds of the `time` counter, at which its
+// current stage ends.
+#[derive(Clone, Copy, Debug)]
+struct PendingReset {
+    stage: ResetStage,
+    deadline_us: u64,
+}
+
+impl PendingReset {
+    fn new(stage: ResetStage, now_us: u64, duration_us: u64) -> Self {
+        Self {
+            stage,
+            deadline_us: now_us.saturating_add(duration_us),
+        }
+    }
+}
+
+/// The result of advancing a reset in progress with `PciDevice::advance_reset()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum ResetProgress {
+    /// No reset is in progress.
+    Idle,
+    /// The reset hasn't completed yet.
+    InProgress,
+    /// The function has been reset and is ready to be used again.
+    Done,
+    /// The secondary bus reset of this bridge is over. The devices below it must be moved on with
+    /// `PciDevice::wait_ready_after_reset()`.
+    BusResetDone,
+}
+
 // Common state between bridges and endpoints.
 struct PciDeviceCommon {
     info: PciDeviceInfo,
     capabilities: PciCapabilities,
+    ext_capabilities: PciExtendedCapabilities,
     bar_info: PciDeviceBarInfo,
     owner: Option<PageOwnerId>,
-    iommu_attached: bool,
+    // The GSCID of the IOMMU context the device is attached to, if any.
+    iommu_gscid: Option<GscId>,
+    needs_reset: bool,
+    reset: Option<PendingReset>,
+    present: bool,
+}
+
+impl PciDeviceCommon {
+    // Creates the common state for a function that is not yet present. Capabilities are parsed
+    // once the function appears.
+    fn absent(info: PciDeviceInfo, bar_info: PciDeviceBarInfo) -> Self {
+        Self {
+            info,
+            capabilities: PciCapabilities::default(),
+            ext_capabilities: PciExtendedCapabilities::default(),
+            bar_info,
+            owner: None,
+            iommu_gscid: None,
+            needs_reset: false,
+            reset: None,
+            present: false,
+        }
+    }
 }
 
 /// Represents a PCI endpoint.
 pub struct PciEndpoint {
     registers: &'static mut EndpointRegisters,
     common: PciDeviceCommon,
+    pf: Option<PciPfState>,
+    vf: Option<PciVfState>,
 }
 
 impl PciEndpoint {
@@ -344,15 +524,64 @@ impl PciEndpoint {
     fn new(registers: &'static mut EndpointRegisters, info: PciDeviceInfo) -> Result<Self> {
         let capabilities =
             PciCapabilities::new(&mut registers.common, registers.cap_ptr.get() as usize)?;
+        let ext_capabilities = if capabilities.is_pcie() {
+            PciExtendedCapabilities::new(&mut registers.common)?
+        } else {
+            PciExtendedCapabilities::default()
+        };
         let bar_info = PciDeviceBarInfo::new(&mut registers.bar)?;
         let common = PciDeviceCommon {
             info,
             capabilities,
+            ext_capabilities,
             bar_info,
             owner: None,
-            iommu_attached: false,
+            iommu_gscid: None,
+            needs_reset: false,
+            reset: None,
+            present: true,
+        };
+        Ok(Self {
+            registers,
+            common,
+            pf: None,
+            vf: None,
+        })
+    }
+
+    /// Creates a `PciEndpoint` for the SR-IOV virtual function of the PF with `pf_id` using the
+    /// config space at `registers`. The VF is initially disabled and its capabilities are parsed
+    /// once the PF enables it.
+    fn new_vf(
+        registers: &'static mut EndpointRegisters,
+        info: PciDeviceInfo,
+        pf_id: PciArenaId,
+        bar_info: PciDeviceBarInfo,
+    ) -> Self {
+        let vf = PciVfState {
+            pf_id,
+            bar_addrs: [0; PCI_SRIOV_VF_BARS],
         };
-        Ok(Self { registers, common })
+        Self {
+            registers,
+            common: PciDeviceCommon::absent(info, bar_info),
+            pf: None,
+            vf: Some(vf),
+        }
+    }
+
+    /// Creates a `PciEndpoint` for an empty function in a hotplug slot using the config space at
+    /// `registers`.
+    fn new_absent(registers: &'static mut EndpointRegisters, info: PciDeviceInfo) -> Self {
+        let bar_info = PciDeviceBarInfo {
+            bars: ArrayVec::new(),
+        };
+        Self {
+            registers,
+            common: PciDeviceCommon::absent(info, bar_info),
+            pf: None,
+            vf: None,
+        }
     }
 
     // Emulate a read from the endpoint-specific registers of this device's config space.
@@ -412,6 +641,7 @@ pub struct PciBridge {
     child_bus: Option<PciBus>,
     virtual_primary_bus: Bus,
     virtual_bus_reset: u16,
+    bus_reset_requested: bool,
     has_io_window: bool,
     has_pref_window: bool,
 }
@@ -440,13 +670,22 @@ impl PciBridge {
         };
         let capabilities =
             PciCapabilities::new(&mut registers.common, registers.cap_ptr.get() as usize)?;
+        let ext_capabilities = if capabilities.is_pcie() {
+            PciExtendedCapabilities::new(&mut registers.common)?
+        } else {
+            PciExtendedCapabilities::default()
+        };
         let bar_info = PciDeviceBarInfo::new(&mut registers.bar)?;
         let common = PciDeviceCommon {
             info,
             capabilities,
+            ext_capabilities,
             bar_info,
             owner: None,
-            iommu_attached: false,
+            iommu_gscid: None,
+            needs_reset: false,
+            reset: None,
+            present: true,
         };
         Ok(Self {
             registers,
@@ -455,6 +694,7 @@ impl PciBridge {
             child_bus: None,
             virtual_primary_bus: Bus::default(),
             virtual_bus_reset: 0,
+            bus_reset_requested: false,
             has_io_window,
             has_pref_window,
         })
@@ -480,6 +720,61 @@ impl PciBridge {
         self.child_bus.as_ref()
     }
 
+    /// Returns and clears any secondary bus reset request made by a VM since the last call.
+    pub(super) fn take_bus_reset_request(&mut self) -> bool {
+        core::mem::take(&mut self.bus_reset_requested)
+    }
+
+    /// Returns if this bridge is a PCIe port with a hotplug-capable slot.
+    pub(super) fn is_hotplug_capable(&self) -> bool {
+        self.common.capabilities.is_hotplug_capable()
+    }
+
+    /// Returns if the slot of this bridge holds a device that is ready to be enumerated.
+    pub(super) fn slot_occupied(&self) -> bool {
+        self.common.capabilities.slot_occupied()
+    }
+
+    /// Returns and clears any hotplug event acknowledged by a VM since the last call.
+    pub(super) fn take_slot_event(&mut self) -> bool {
+        self.common.capabilities.take_slot_event()
+    }
+
+    /// Returns and clears the value a VM last attempted to write to the slot control register if
+    /// it would have turned power to the slot on or off.
+    pub(super) fn take_slot_control_request(&mut self) -> Option<u16> {
+        self.common.capabilities.take_slot_control_request()
+    }
+
+    /// Sets the slot control register of this bridge.
+    pub(super) fn set_slot_control(&mut self, control: u16) {
+        self.common.capabilities.set_slot_control(control);
+    }
+
+    /// Returns an allocator over the memory and IO windows currently forwarded by this bridge.
+    pub(super) fn window_allocator(&self) -> PciWindowAllocator {
+        PciWindowAllocator::new(
+            self.get_io_window(),
+            self.get_mem_window(),
+            self.get_pref_window(),
+        )
+    }
+
+    /// Starts a reset of all devices on the secondary bus of this bridge at `now_us`. The reset
+    /// is driven to completion by `PciDevice::advance_reset()`, after which the caller is
+    /// responsible for restoring the state of the devices below the bridge. Any reset of the
+    /// bridge itself that is in progress is abandoned.
+    pub(super) fn start_secondary_bus_reset(&mut self, now_us: u64) {
+        self.registers
+            .bridge_control
+            .modify(BridgeControl::SecondaryBusReset::SET);
+        self.common.reset = Some(PendingReset::new(
+            ResetStage::BusResetAsserted,
+            now_us,
+            BUS_RESET_ASSERT_US,
+        ));
+    }
+
     // Emulate a read from the bridge-specific registers of this device's config space.
     fn emulate_config_read(&self, op: &mut MmioReadBuilder) {
         use bridge_offsets::*;
@@ -660,9 +955,13 @@ impl PciBridge {
                 let reg = LocalRegisterCopy::<u16, BridgeControl::Register>::new(
                     op.pop_word(self.registers.bridge_control.get()),
                 );
-                // TODO: We virtualize the secondary bus reset bit for now. Implement a VM-triggered
-                // reset if necessary and it's safe to do so.
-                self.virtual_bus_reset = reg.read(BridgeControl::SecondaryBusReset);
+                // The reset itself is validated and carried out by the hypervisor once the write
+                // completes. The bit continues to read back as the VM last wrote it.
+                let bus_reset = reg.read(BridgeControl::SecondaryBusReset);
+                if bus_reset != 0 && self.virtual_bus_reset == 0 {
+                    self.bus_reset_requested = true;
+                }
+                self.virtual_bus_reset = bus_reset;
                 self.registers.bridge_control.set(reg.writeable_bits());
             }
             _ => {
@@ -734,6 +1033,17 @@ impl PciBridge {
     }
 }
 
+/// The mechanisms that can be used to reset a PCI device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciResetMethod {
+    /// A PCIe function-level reset.
+    FunctionLevel,
+    /// A transition from D3hot to D0 on a device that doesn't set `No_Soft_Reset`.
+    PowerManagement,
+    /// A reset of the secondary bus of the bridge directly upstream of the device.
+    SecondaryBus,
+}
+
 /// Represents a single PCI device.
 pub enum PciDevice {
     /// A function endpoint (type 0) device.
@@ -825,22 +1135,592 @@ impl PciDevice {
         self.common().capabilities.is_pcie()
     }
 
+    /// Returns if the device supports SR-IOV.
+    pub fn has_sriov(&self) -> bool {
+        self.common().ext_capabilities.has_sriov()
+    }
+
+    /// Returns if the device is an SR-IOV virtual function.
+    pub fn is_vf(&self) -> bool {
+        self.vf().is_some()
+    }
+
+    /// Returns the ID of the physical function of this device if it is an SR-IOV virtual function.
+    pub fn physical_function(&self) -> Option<PciArenaId> {
+        self.vf().map(|vf| vf.pf_id)
+    }
+
+    /// Returns the IDs of the virtual functions of this device if it is an SR-IOV physical
+    /// function. Only the first `num_vfs_enabled()` VFs are present.
+    pub fn virtual_functions(&self) -> &[PciArenaId] {
+        self.pf().map(|pf| pf.vfs.as_slice()).unwrap_or(&[])
+    }
+
+    /// Returns the number of VFs that are currently enabled if this device is an SR-IOV physical
+    /// function.
+    pub fn num_vfs_enabled(&self) -> usize {
+        self.common()
+            .ext_capabilities
+            .sriov_registers()
+            .filter(|sriov| sriov.sriov_control.is_set(SriovControl::VfEnable))
+            .map(|sriov| sriov.num_vfs.get() as usize)
+            .unwrap_or(0)
+    }
+
+    /// Returns if the device is present. SR-IOV virtual functions are only present while enabled
+    /// by their physical function, and functions in hotplug slots only while a device is inserted.
+    pub fn is_present(&self) -> bool {
+        self.common().present
+    }
+
+    /// Returns the extended capabilities the owner of this device is permitted to use.
+    pub fn extended_capability_permissions(&self) -> ExtendedCapabilityPermissions {
+        self.common().ext_capabilities.permissions()
+    }
+
+    /// Sets the extended capabilities (e.g. ATS or SR-IOV) the owner of this device is permitted
+    /// to use. Capabilities that are not permitted are disabled and hidden from the owner.
+    pub fn set_extended_capability_permissions(
+        &mut self,
+        permissions: ExtendedCapabilityPermissions,
+    ) {
+        self.common_mut()
+            .ext_capabilities
+            .set_permissions(permissions);
+    }
+
     /// Returns the device's owner.
     pub fn owner(&self) -> Option<PageOwnerId> {
         self.common().owner
     }
 
-    /// Takes ownership over the device if it is not already owned.
+    /// Takes ownership over the device if it is not already owned. A device that has been released
+    /// by a previous owner must be reset before it can be taken.
     pub(super) fn take(&mut self, owner: PageOwnerId) -> Result<()> {
         if self.owner().is_some() {
             return Err(Error::DeviceOwned);
         }
-        // TODO: Need to prevent transfer of ownership if attached to an IOMMU. Not an issue yet
-        // since we don't allow devices to be reassigned.
+        if self.needs_reset() {
+            return Err(Error::DeviceNeedsReset);
+        }
         self.common_mut().owner = Some(owner);
         Ok(())
     }
 
+    /// Releases ownership of the device. The device must be detached from any IOMMU context first,
+    /// and must be reset before it can be taken by a new owner.
+    pub(super) fn release(&mut self) -> Result<()> {
+        if self.common().iommu_gscid.is_some() {
+            return Err(Error::DeviceAttached);
+        }
+        self.quiesce();
+        let common = self.common_mut();
+        common.owner = None;
+        common.needs_reset = true;
+        Ok(())
+    }
+
+    /// Returns if the device must be reset before it can be assigned to a new owner.
+    pub fn needs_reset(&self) -> bool {
+        self.common().needs_reset
+    }
+
+    /// Returns the method that can be used to reset this function without affecting any other
+    /// device, if any.
+    pub fn function_reset_method(&self) -> Option<PciResetMethod> {
+        let caps = &self.common().capabilities;
+        if caps.has_flr() {
+            Some(PciResetMethod::FunctionLevel)
+        } else if caps.has_pm_soft_reset() {
+            Some(PciResetMethod::PowerManagement)
+        } else {
+            None
+        }
+    }
+
+    /// Starts a reset of this function at `now_us` using the method returned by
+    /// `function_reset_method()`, quiescing it first. The reset is driven to completion by
+    /// `advance_reset()`; the function is marked as needing a reset until then, so that it isn't
+    /// used or handed out before it's ready, or at all if the reset fails. Returns
+    /// `Error::ResetUnsupported` if the function can only be reset by resetting the secondary bus
+    /// of its parent bridge.
+    pub(super) fn start_reset(&mut self, now_us: u64) -> Result<PciResetMethod> {
+        // Resetting a PF disables its VFs, which may be owned by someone else.
+        if self.num_vfs_enabled() != 0 {
+            return Err(Error::VfsEnabled);
+        }
+        let method = self
+            .function_reset_method()
+            .ok_or(Error::ResetUnsupported)?;
+        self.quiesce();
+        let common = self.common_mut();
+        common.needs_reset = true;
+        common.reset = None;
+        let reset = if method == PciResetMethod::FunctionLevel {
+            // Give outstanding non-posted requests a chance to complete. The FLR is issued
+            // regardless once the timeout expires.
+            PendingReset::new(
+                ResetStage::DrainTransactions,
+                now_us,
+                FLR_PENDING_TIMEOUT_US,
+            )
+        } else {
+            self.common_mut().capabilities.set_d3hot(true)?;
+            PendingReset::new(ResetStage::D3hot, now_us, PM_TRANSITION_US)
+        };
+        self.common_mut().reset = Some(reset);
+        Ok(method)
+    }
+
+    /// Transfers ownership of this enabled SR-IOV virtual function from `from` to `to`, starting a
+    /// reset of it at `now_us` so that `to` doesn't see any of `from`'s state. The VF must already
+    /// have been detached from the IOMMU, and `to` can't use it until the reset completes.
+    /// Ownership of the VF's physical function is unaffected.
+    pub(super) fn transfer_vf(
+        &mut self,
+        from: PageOwnerId,
+        to: PageOwnerId,
+        now_us: u64,
+    ) -> Result<()> {
+        if !self.is_vf() {
+            return Err(Error::NotVirtualFunction);
+        }
+        if !self.is_present() {
+            return Err(Error::DeviceNotPresent(self.info().address()));
+        }
+        if self.owner() != Some(from) {
+            return Err(Error::DeviceNotOwned);
+        }
+        // VFs are required to support FLR, but check anyway so that we don't leave the VF stranded
+        // without an owner.
+        if self.function_reset_method().is_none() {
+            return Err(Error::ResetUnsupported);
+        }
+        self.release()?;
+        self.start_reset(now_us)?;
+        self.common_mut().owner = Some(to);
+        Ok(())
+    }
+
+    /// Returns if a reset of this function, or of the secondary bus below it if it's a bridge, is
+    /// in progress.
+    pub fn reset_in_progress(&self) -> bool {
+        self.common().reset.is_some()
+    }
+
+    /// Advances the reset in progress, if any, as far as it can go at `now_us`. A function that
+    /// fails to reset or doesn't become ready in time is left needing a reset.
+    pub(super) fn advance_reset(&mut self, now_us: u64) -> Result<ResetProgress> {
+        use ResetStage::*;
+        while let Some(reset) = self.common().reset {
+            let expired = now_us >= reset.deadline_us;
+            let (stage, duration_us) = match reset.stage {
+                DrainTransactions
+                    if expired || !self.common().capabilities.transactions_pending() =>
+                {
+                    self.common_mut()
+                        .capabilities
+                        .initiate_flr()
+                        .map_err(|e| self.abort_reset(e))?;
+                    (Flr, FLR_COMPLETION_US)
+                }
+                D3hot if expired => {
+                    self.common_mut()
+                        .capabilities
+                        .set_d3hot(false)
+                        .map_err(|e| self.abort_reset(e))?;
+                    (D0, PM_TRANSITION_US)
+                }
+                Flr | D0 if expired => (WaitReady, RESET_READY_TIMEOUT_US),
+                BusResetAsserted if expired => {
+                    if let PciDevice::Bridge(bridge) = self {
+                        bridge
+                            .registers
+                            .bridge_control
+                            .modify(BridgeControl::SecondaryBusReset::CLEAR);
+                    }
+                    (BusResetRecovery, RESET_RECOVERY_US)
+                }
+                BusResetRecovery if expired => {
+                    self.common_mut().reset = None;
+                    return Ok(ResetProgress::BusResetDone);
+                }
+                WaitReady if self.ready_after_reset() => {
+                    self.common_mut().reset = None;
+                    self.restore_after_reset();
+                    return Ok(ResetProgress::Done);
+                }
+                WaitReady if expired => {
+                    let address = self.info().address();
+                    return Err(self.abort_reset(Error::ResetTimeout(address)));
+                }
+                _ => return Ok(ResetProgress::InProgress),
+            };
+            self.common_mut().reset = Some(PendingReset::new(stage, now_us, duration_us));
+        }
+        Ok(ResetProgress::Idle)
+    }
+
+    /// Marks this function as being held in reset by a secondary bus reset of a bridge above it.
+    /// The function can't be used until it's moved on with `wait_ready_after_reset()` and becomes
+    /// ready.
+    pub(super) fn hold_in_bus_reset(&mut self) {
+        let common = self.common_mut();
+        common.needs_reset = true;
+        common.reset = Some(PendingReset {
+            stage: ResetStage::BusReset,
+            deadline_us: u64::MAX,
+        });
+    }
+
+    /// Starts waiting at `now_us` for this function to respond to config requests again following
+    /// the end of a secondary bus reset.
+    pub(super) fn wait_ready_after_reset(&mut self, now_us: u64) {
+        self.common_mut().reset = Some(PendingReset::new(
+            ResetStage::WaitReady,
+            now_us,
+            RESET_READY_TIMEOUT_US,
+        ));
+    }
+
+    // Abandons the reset in progress following `error`, leaving the function needing a reset.
+    fn abort_reset(&mut self, error: Error) -> Error {
+        self.common_mut().reset = None;
+        error
+    }
+
+    // Returns if the function responds to config requests following a reset.
+    fn ready_after_reset(&self) -> bool {
+        // VFs don't implement the vendor ID register, but must be ready once the FLR completes.
+        let vendor_id = self.info().vendor_id().bits();
+        self.is_vf() || self.common_registers().vendor_id.get() == vendor_id
+    }
+
+    // Restores the state managed by the hypervisor once the function is ready following a reset.
+    // Everything else, including BAR assignments, is left for the owner to reprogram.
+    fn restore_after_reset(&mut self) {
+        if let PciDevice::Bridge(bridge) = self {
+            bridge.assign_bus_range(bridge.bus_range);
+            bridge.virtual_bus_reset = 0;
+        }
+        let permissions = self.extended_capability_permissions();
+        self.set_extended_capability_permissions(permissions);
+        self.common_mut().needs_reset = false;
+    }
+
+    /// Probes the virtual functions that may be enabled by this device if it is an SR-IOV physical
+    /// function.
+    pub(super) fn probe_vf_layout(&mut self) -> Result<Option<PciVfLayout>> {
+        let PciDevice::Endpoint(ep) = self else {
+            return Ok(None);
+        };
+        let Some(sriov) = ep.common.ext_capabilities.sriov_registers_mut() else {
+            return Ok(None);
+        };
+        // The routing of VFs may depend on NumVFs, so probe it with every VF enabled. We refuse to
+        // enable VFs later if the routing turns out to be different for a smaller NumVFs.
+        let total_vfs = sriov.total_vfs.get();
+        sriov.num_vfs.set(total_vfs);
+        let first_vf_offset = sriov.first_vf_offset.get();
+        let vf_stride = sriov.vf_stride.get();
+        sriov.num_vfs.set(0);
+        let device_id = DeviceId(sriov.vf_dev_id.get());
+        let bar_info = PciDeviceBarInfo::new(&mut sriov.vf_bar)?;
+        let mut vf_bar_sizes = [0; PCI_SRIOV_VF_BARS];
+        for bar in bar_info.bars() {
+            vf_bar_sizes[bar.index()] = bar.size();
+        }
+        ep.common.ext_capabilities.set_vf_bar_sizes(vf_bar_sizes);
+        Ok(Some(PciVfLayout {
+            total_vfs,
+            first_vf_offset,
+            vf_stride,
+            device_id,
+            bar_info,
+        }))
+    }
+
+    /// Creates a `PciDevice` for the virtual function of the physical function with `pf_id` and
+    /// `pf_info` at `address`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `registers_ptr` points to a valid and uniquely-owned
+    /// configuration space for the function at `address`.
+    pub(super) unsafe fn new_vf(
+        registers_ptr: NonNull<CommonRegisters>,
+        address: Address,
+        pf_id: PciArenaId,
+        pf_info: &PciDeviceInfo,
+        layout: &PciVfLayout,
+    ) -> Self {
+        let info = PciDeviceInfo::for_vf(address, pf_info, layout.device_id);
+        let registers = registers_ptr.cast().as_mut();
+        let ep = PciEndpoint::new_vf(registers, info, pf_id, layout.bar_info.clone());
+        PciDevice::Endpoint(ep)
+    }
+
+    /// Records the IDs of the virtual functions of this SR-IOV physical function.
+    pub(super) fn set_virtual_functions(&mut self, vfs: Vec<PciArenaId>, layout: PciVfLayout) {
+        if let PciDevice::Endpoint(ep) = self {
+            ep.pf = Some(PciPfState {
+                vfs,
+                layout,
+                vf_enable_deadline_us: None,
+            });
+        }
+    }
+
+    /// Returns and clears the value the owner of this SR-IOV physical function last attempted to
+    /// write to the SR-IOV control register if it would have enabled or disabled VFs or VF memory
+    /// space.
+    pub(super) fn take_sriov_control_request(&mut self) -> Option<u16> {
+        self.common_mut()
+            .ext_capabilities
+            .take_sriov_control_request()
+    }
+
+    /// Sets the SR-IOV control register of this physical function. Clearing VF Enable abandons the
+    /// enabling of VFs if it's in progress.
+    pub(super) fn set_sriov_control(&mut self, control: u16) {
+        if let Some(sriov) = self.common().ext_capabilities.sriov_registers() {
+            sriov.sriov_control.set(control);
+        }
+        let reg = LocalRegisterCopy::<u16, SriovControl::Register>::new(control);
+        if !reg.is_set(SriovControl::VfEnable) && let Some(pf) = self.pf_mut() {
+            pf.vf_enable_deadline_us = None;
+        }
+    }
+
+    /// Starts waiting at `now_us` for the VFs of this physical function to become accessible after
+    /// VF Enable has been set. The VFs stay absent until `take_ready_vf_enable()` says they can be
+    /// enabled.
+    pub(super) fn start_vf_enable(&mut self, now_us: u64) {
+        if let Some(pf) = self.pf_mut() {
+            pf.vf_enable_deadline_us = Some(now_us.saturating_add(VF_ENABLE_DELAY_US));
+        }
+    }
+
+    /// Returns if this physical function is waiting for its VFs to become accessible.
+    pub fn vf_enable_pending(&self) -> bool {
+        self.pf()
+            .map(|pf| pf.vf_enable_deadline_us.is_some())
+            .unwrap_or(false)
+    }
+
+    /// Returns true, once, if the VFs of this physical function have become accessible at `now_us`
+    /// and can be enabled.
+    pub(super) fn take_ready_vf_enable(&mut self, now_us: u64) -> bool {
+        let Some(pf) = self.pf_mut() else {
+            return false;
+        };
+        match pf.vf_enable_deadline_us {
+            Some(deadline_us) if now_us >= deadline_us => {
+                pf.vf_enable_deadline_us = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns `Ok` if the currently programmed NumVFs VFs can be enabled.
+    pub(super) fn can_enable_vfs(&self) -> Result<()> {
+        let pf = self.pf().ok_or(Error::NotPhysicalFunction)?;
+        // Unwrap ok: a PF must have an SR-IOV capability.
+        let sriov = self.common().ext_capabilities.sriov_registers().unwrap();
+        let num_vfs = sriov.num_vfs.get();
+        if num_vfs as usize > pf.vfs.len() {
+            return Err(Error::TooManyVfs(num_vfs));
+        }
+        if sriov.first_vf_offset.get() != pf.layout.first_vf_offset
+            || sriov.vf_stride.get() != pf.layout.vf_stride
+        {
+            return Err(Error::VfRoutingChanged);
+        }
+        Ok(())
+    }
+
+    /// Returns `Ok` if VF memory space can be safely enabled for the VM in `context`.
+    pub(super) fn can_enable_vf_mem_space(&self, context: &MmioEmulationContext) -> Result<()> {
+        let pf = self.pf().ok_or(Error::NotPhysicalFunction)?;
+        // Unwrap ok: a PF must have an SR-IOV capability.
+        let num_vfs = self
+            .common()
+            .ext_capabilities
+            .sriov_registers()
+            .unwrap()
+            .num_vfs
+            .get();
+        for bar in pf.layout.bar_info.bars() {
+            // Each VF BAR covers the corresponding BAR of every VF.
+            let pci_addr = self.vf_bar_base(bar);
+            let phys_addr = context
+                .resources
+                .pci_to_physical_addr(pci_addr)
+                .ok_or(Error::InvalidBarAddress(pci_addr))?;
+            let page_range = SupervisorPageRange::new(
+                PageAddr::with_round_down(phys_addr, PageSize::Size4k),
+                PageSize::num_4k_pages(bar.size() * num_vfs as u64),
+            );
+            bar_range_is_owned(page_range, &context.page_tracker, context.guest_id)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses of the BARs of the VF at `index` of this physical function.
+    pub(super) fn vf_bar_addrs(&self, index: usize) -> [u64; PCI_SRIOV_VF_BARS] {
+        let mut addrs = [0; PCI_SRIOV_VF_BARS];
+        if let Some(pf) = self.pf() {
+            for bar in pf.layout.bar_info.bars() {
+                addrs[bar.index()] = self.vf_bar_base(bar) + bar.size() * index as u64;
+            }
+        }
+        addrs
+    }
+
+    /// Marks this virtual function as enabled with BARs at `bar_addrs` now that its physical
+    /// function has enabled it, parsing the capabilities of the VF.
+    pub(super) fn enable_vf(&mut self, bar_addrs: [u64; PCI_SRIOV_VF_BARS]) -> Result<()> {
+        let PciDevice::Endpoint(ep) = self else {
+            return Err(Error::NotVirtualFunction);
+        };
+        let vf = ep.vf.as_mut().ok_or(Error::NotVirtualFunction)?;
+        let capabilities = PciCapabilities::new(
+            &mut ep.registers.common,
+            ep.registers.cap_ptr.get() as usize,
+        )?;
+        let mut ext_capabilities = if capabilities.is_pcie() {
+            PciExtendedCapabilities::new(&mut ep.registers.common)?
+        } else {
+            PciExtendedCapabilities::default()
+        };
+        ext_capabilities.set_permissions(ep.common.ext_capabilities.permissions());
+        ep.common.capabilities = capabilities;
+        ep.common.ext_capabilities = ext_capabilities;
+        vf.bar_addrs = bar_addrs;
+        ep.common.present = true;
+        Ok(())
+    }
+
+    /// Marks this virtual function as disabled following the disabling of VFs by its physical
+    /// function. Disabling a VF resets it.
+    pub(super) fn disable_vf(&mut self) {
+        if let PciDevice::Endpoint(ep) = self {
+            if ep.vf.is_some() {
+                ep.common.present = false;
+                ep.common.needs_reset = false;
+                ep.common.reset = None;
+            }
+        }
+    }
+
+    /// Creates a `PciDevice` for the function at `address` in an empty hotplug slot. The function is
+    /// absent until a device is hot-added to the slot.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `registers_ptr` points to a valid and uniquely-owned
+    /// configuration space for the function at `address`.
+    pub(super) unsafe fn new_absent(
+        registers_ptr: NonNull<CommonRegisters>,
+        address: Address,
+    ) -> Self {
+        let registers = registers_ptr.cast().as_mut();
+        let ep = PciEndpoint::new_absent(registers, PciDeviceInfo::absent(address));
+        PciDevice::Endpoint(ep)
+    }
+
+    /// Probes the function following the insertion of a device in its hotplug slot, making it
+    /// present if the function responds. The new device is unowned and detached from the IOMMU; it
+    /// must be taken and attached like any other device before it can be used. It inherits the
+    /// capability permissions of the absent function. Bridges can't be hot-added since there's no
+    /// room to grow the bus hierarchy.
+    pub(super) fn hot_add(&mut self) -> Result<()> {
+        if self.is_present() {
+            return Ok(());
+        }
+        let address = self.info().address();
+        if self.is_vf() {
+            return Err(Error::UnsupportedHotplugDevice(address));
+        }
+        let registers_ptr = self.common_registers_ptr();
+        // Safety: `registers_ptr` points to the config space of this function, which we own.
+        let info = PciDeviceInfo::read_from(address, unsafe { registers_ptr.as_ref() })
+            .ok_or(Error::DeviceNotPresent(address))?;
+        if info.header_type() != HeaderType::Endpoint {
+            return Err(Error::UnsupportedHotplugDevice(address));
+        }
+        // Safety: `registers_ptr` is uniquely owned by this function and `info` was read from it.
+        // The old device, and with it our reference to the config space, is replaced below.
+        let mut dev = unsafe { PciDevice::new(registers_ptr, info) }?;
+        dev.common_mut()
+            .ext_capabilities
+            .set_permissions(self.extended_capability_permissions());
+        *self = dev;
+        Ok(())
+    }
+
+    /// Marks the function as absent following the removal of the device from its hotplug slot. The
+    /// function is detached from the IOMMU and released by its owner.
+    pub(super) fn hot_remove(&mut self) {
+        if let Some(gscid) = self.common().iommu_gscid {
+            // Unwrap ok: the device can only have been attached if there's an IOMMU, and the GSCID
+            // stays valid while devices are attached with it.
+            Iommu::get()
+                .unwrap()
+                .detach_pci_device(self, gscid)
+                .unwrap();
+        }
+        if self.is_vf() {
+            self.disable_vf();
+        } else {
+            self.common_mut().present = false;
+        }
+        self.common_mut().owner = None;
+    }
+
+    /// Assigns addresses from `windows` to the BARs of this device. BARs are allocated largest
+    /// first to keep them naturally aligned without leaving gaps between them. Every BAR must land
+    /// in the part of the root's resources that is passed through to the host, otherwise none of
+    /// the BARs are programmed.
+    pub(super) fn assign_bars(
+        &mut self,
+        windows: &mut PciWindowAllocator,
+        resources: &PciRootResources,
+    ) -> Result<()> {
+        let mut bars: ArrayVec<PciBarInfo, PCI_ENDPOINT_BARS> =
+            self.bar_info().bars().cloned().collect();
+        bars.sort_unstable_by(|a, b| b.size().cmp(&a.size()));
+        let mut addrs = ArrayVec::<u64, PCI_ENDPOINT_BARS>::new();
+        for bar in bars.iter() {
+            let pci_addr = windows.alloc(bar.bar_type(), bar.size())?;
+            if !resources.host_range_contains(pci_addr, bar.size()) {
+                return Err(Error::InvalidBarAddress(pci_addr));
+            }
+            addrs.push(pci_addr);
+        }
+        for (bar, pci_addr) in bars.iter().zip(addrs) {
+            self.set_bar_addr(bar.index(), pci_addr)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the secondary bus directly downstream of this device if it is a bridge.
+    pub(super) fn child_bus(&self) -> Option<&PciBus> {
+        match self {
+            PciDevice::Bridge(bridge) => bridge.child_bus(),
+            PciDevice::Endpoint(_) => None,
+        }
+    }
+
+    /// Returns a mutable reference to the `PciBridge` if this device is a bridge.
+    pub(super) fn as_bridge_mut(&mut self) -> Option<&mut PciBridge> {
+        match self {
+            PciDevice::Bridge(bridge) => Some(bridge),
+            PciDevice::Endpoint(_) => None,
+        }
+    }
+
     /// Emulates a read from the configuration space of this device at `offset`.
     pub(super) fn emulate_config_read(
         &self,
@@ -892,16 +1772,18 @@ impl PciDevice {
                 PCI_CAPS_START..=PCI_CONFIG_SPACE_END => {
                     self.common().capabilities.emulate_read(&mut op);
                 }
+                PCIE_EXT_CAPS_START..=PCIE_CONFIG_SPACE_END if self.is_pcie() => {
+                    self.common().ext_capabilities.emulate_read(&mut op);
+                }
                 offset => {
                     if offset <= PCI_COMMON_HEADER_END {
                         // Everything else in the common part of the header is unimplemented and we can
                         // safely return 0.
                         op.push_byte(0);
                     } else {
-                        // Make everything beyond the standard PCI configuration space appear
+                        // Conventional PCI devices don't have an extended configuration space, so
+                        // make everything beyond the standard PCI configuration space appear
                         // unimplemented.
-                        //
-                        // TODO: Extended config space emulation?
                         op.push_dword(!0x0);
                     }
                 }
@@ -940,7 +1822,7 @@ impl PciDevice {
                     }
 
                     // Only allow DMA to be enabled if the device is attached to an IOMMU.
-                    if reg.is_set(Command::BusMasterEnable) && !self.common().iommu_attached {
+                    if reg.is_set(Command::BusMasterEnable) && self.common().iommu_gscid.is_none() {
                         reg.modify(Command::BusMasterEnable.val(0));
                     }
 
@@ -959,23 +1841,34 @@ impl PciDevice {
                 PCI_CAPS_START..=PCI_CONFIG_SPACE_END => {
                     self.common_mut().capabilities.emulate_write(&mut op);
                 }
+                PCIE_EXT_CAPS_START..=PCIE_CONFIG_SPACE_END if self.is_pcie() => {
+                    self.common_mut().ext_capabilities.emulate_write(&mut op);
+                }
                 _ => {
-                    // We don't allow writes to other bits of the common header and everything beyond
-                    // the standard config space is unimplemented.
-                    //
-                    // TODO: Extended config space emulation?
+                    // We don't allow writes to other bits of the common header, and everything beyond
+                    // the standard config space is unimplemented for conventional PCI devices.
                     op.pop_byte();
                 }
             }
         }
     }
 
+    /// Returns and clears whether the owner of this function requested a function-level reset in a
+    /// config space write.
+    pub(super) fn take_flr_request(&mut self) -> bool {
+        self.common_mut().capabilities.take_flr_request()
+    }
+
     /// Returns the PCI bus address programmed in the BAR at `bar_index`.
     pub fn get_bar_addr(&self, index: usize) -> Result<u64> {
         let bar = self
             .bar_info()
             .get(index)
             .ok_or(Error::BarNotPresent(index))?;
+        // The BARs of VFs are programmed through the SR-IOV capability of their PF.
+        if let Some(vf) = self.vf() {
+            return Ok(vf.bar_addrs[index]);
+        }
         let regs = self.bar_registers();
         let addr_lo = regs[index].get() & !((1u32 << BaseAddress::Address.shift) - 1);
         let addr_hi = if bar.bar_type().is_64bit() {
@@ -1021,10 +1914,15 @@ impl PciDevice {
             .modify(Command::BusMasterEnable.val(1));
     }
 
-    /// Marks the device as being attached to an active IOMMU context, allowing DMA to be safely
-    /// enabled.
-    pub(crate) fn set_iommu_attached(&mut self) {
-        self.common_mut().iommu_attached = true;
+    /// Returns if the device is attached to an active IOMMU context.
+    pub fn is_iommu_attached(&self) -> bool {
+        self.common().iommu_gscid.is_some()
+    }
+
+    /// Marks the device as being attached to the active IOMMU context with `gscid`, allowing DMA
+    /// to be safely enabled.
+    pub(crate) fn set_iommu_attached(&mut self, gscid: GscId) {
+        self.common_mut().iommu_gscid = Some(gscid);
     }
 
     /// Marks the device as no longer being attached to an active IOMMU context.
@@ -1033,7 +1931,12 @@ impl PciDevice {
         self.common_registers()
             .command
             .modify(Command::BusMasterEnable.val(1));
-        self.common_mut().iommu_attached = false;
+        self.common_mut().iommu_gscid = None;
+    }
+
+    // Disables IO, memory and DMA access for this device ahead of a reset or ownership transfer.
+    fn quiesce(&mut self) {
+        self.common_registers().command.set(0);
     }
 
     // Returns `Ok` if the specified BAR is assigned a valid address for the VM in `context`.
@@ -1098,6 +2001,14 @@ impl PciDevice {
         }
     }
 
+    // Returns a pointer to the common portion of this device's PCI header.
+    fn common_registers_ptr(&mut self) -> NonNull<CommonRegisters> {
+        match self {
+            PciDevice::Endpoint(ep) => NonNull::from(&mut ep.registers.common),
+            PciDevice::Bridge(bridge) => NonNull::from(&mut bridge.registers.common),
+        }
+    }
+
     // Returns a reference to this device's BAR registers.
     fn bar_registers(&self) -> &[ReadWrite<u32, BaseAddress::Register>] {
         match self {
@@ -1122,6 +2033,48 @@ impl PciDevice {
         }
     }
 
+    // Returns the base address of the VF BAR described by `bar` of this SR-IOV physical function.
+    fn vf_bar_base(&self, bar: &PciBarInfo) -> u64 {
+        // Unwrap ok: only called for PFs, which must have an SR-IOV capability.
+        let regs = &self
+            .common()
+            .ext_capabilities
+            .sriov_registers()
+            .unwrap()
+            .vf_bar;
+        let addr_lo = regs[bar.index()].get() & !((1u32 << BaseAddress::Address.shift) - 1);
+        let addr_hi = if bar.bar_type().is_64bit() {
+            regs[bar.index() + 1].get()
+        } else {
+            0
+        };
+        (addr_lo as u64) | ((addr_hi as u64) << 32)
+    }
+
+    // Returns the SR-IOV physical function state for this device, if it is a PF.
+    fn pf(&self) -> Option<&PciPfState> {
+        match self {
+            PciDevice::Endpoint(ep) => ep.pf.as_ref(),
+            PciDevice::Bridge(_) => None,
+        }
+    }
+
+    // Returns the mutable SR-IOV physical function state for this device, if it is a PF.
+    fn pf_mut(&mut self) -> Option<&mut PciPfState> {
+        match self {
+            PciDevice::Endpoint(ep) => ep.pf.as_mut(),
+            PciDevice::Bridge(_) => None,
+        }
+    }
+
+    // Returns the SR-IOV virtual function state for this device, if it is a VF.
+    fn vf(&self) -> Option<&PciVfState> {
+        match self {
+            PciDevice::Endpoint(ep) => ep.vf.as_ref(),
+            PciDevice::Bridge(_) => None,
+        }
+    }
+
     // Returns a reference to the `PciDeviceCommon` for this device.
     fn common(&self) -> &PciDeviceCommon {
         match self {
@@ -1147,7 +2100,10 @@ unsafe impl Sync for PciDevice {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::alloc::Global;
+    use hyp_alloc::Arena;
     use std::vec::Vec;
+    use sync::Mutex;
 
     #[test]
     fn dev_info() {
@@ -1192,4 +2148,181 @@ mod tests {
         let regs = unsafe { (header_mem.as_mut_ptr() as *mut CommonRegisters).as_ref() }.unwrap();
         assert!(PciDeviceInfo::read_from(Address::default(), regs).is_none());
     }
+
+    fn test_vf_layout() -> PciVfLayout {
+        PciVfLayout {
+            total_vfs: 8,
+            first_vf_offset: 0x80,
+            vf_stride: 2,
+            device_id: DeviceId(0x1234),
+            bar_info: PciDeviceBarInfo {
+                bars: ArrayVec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn vf_routing() {
+        let layout = test_vf_layout();
+        let pf_address = Address::try_from_components(0, 3, 0, 1).unwrap();
+        assert_eq!(
+            layout.vf_address(pf_address, 0),
+            Address::try_from_components(0, 3, 0x10, 1)
+        );
+        assert_eq!(
+            layout.vf_address(pf_address, 3),
+            Address::try_from_components(0, 3, 0x10, 7)
+        );
+        assert_eq!(
+            layout.vf_address(pf_address, 4),
+            Address::try_from_components(0, 3, 0x11, 1)
+        );
+        // VFs beyond the PF's bus aren't supported.
+        let pf_address = Address::try_from_components(0, 3, 0x1f, 0).unwrap();
+        assert!(layout.vf_address(pf_address, 0).is_none());
+    }
+
+    // Returns the config space of a PCIe endpoint that supports FLR.
+    fn flr_endpoint_config() -> Vec<u32> {
+        let mut config = vec![0u32; 1024];
+        config[0] = 0xa9a9_b8b8; // device and vendor id
+        config[13] = 0x40; // Start of the capability list.
+        config[16] = 0x0002_0010; // PCIe v2 endpoint
+        config[17] = 0x1000_0000; // FLR capable
+        config
+    }
+
+    fn test_device(config: &mut [u32]) -> PciDevice {
+        // Not safe, just a test.
+        let regs = NonNull::new(config.as_mut_ptr() as *mut CommonRegisters).unwrap();
+        let info = PciDeviceInfo::read_from(Address::default(), unsafe { regs.as_ref() })
+            .expect("can't create header");
+        unsafe { PciDevice::new(regs, info) }.unwrap()
+    }
+
+    #[test]
+    fn function_level_reset() {
+        let mut config = flr_endpoint_config();
+        let mut dev = test_device(&mut config);
+        dev.take(PageOwnerId::host()).unwrap();
+        dev.release().unwrap();
+        assert_eq!(dev.advance_reset(0).unwrap(), ResetProgress::Idle);
+        assert_eq!(dev.start_reset(0).unwrap(), PciResetMethod::FunctionLevel);
+        assert!(dev.reset_in_progress());
+
+        // Nothing is outstanding, so the FLR is issued right away.
+        assert_eq!(dev.advance_reset(0).unwrap(), ResetProgress::InProgress);
+        assert_ne!(config[18] & 0x8000, 0);
+        // The function can't be handed out until the FLR completes and it's ready again.
+        assert_eq!(
+            dev.advance_reset(FLR_COMPLETION_US - 1).unwrap(),
+            ResetProgress::InProgress
+        );
+        assert!(dev.take(PageOwnerId::host()).is_err());
+        assert_eq!(
+            dev.advance_reset(FLR_COMPLETION_US).unwrap(),
+            ResetProgress::Done
+        );
+        assert!(!dev.reset_in_progress());
+        assert!(!dev.needs_reset());
+        dev.take(PageOwnerId::host()).unwrap();
+    }
+
+    #[test]
+    fn reset_timeout() {
+        let mut config = flr_endpoint_config();
+        let mut dev = test_device(&mut config);
+        dev.start_reset(0).unwrap();
+        assert_eq!(dev.advance_reset(0).unwrap(), ResetProgress::InProgress);
+        // The function stops responding to config requests.
+        config[0] = !0;
+        assert_eq!(
+            dev.advance_reset(FLR_COMPLETION_US).unwrap(),
+            ResetProgress::InProgress
+        );
+        assert!(matches!(
+            dev.advance_reset(FLR_COMPLETION_US + RESET_READY_TIMEOUT_US),
+            Err(Error::ResetTimeout(_))
+        ));
+        assert!(!dev.reset_in_progress());
+        assert!(dev.needs_reset());
+    }
+
+    #[test]
+    fn bus_reset_hold() {
+        let mut config = flr_endpoint_config();
+        let mut dev = test_device(&mut config);
+        dev.hold_in_bus_reset();
+        assert!(dev.needs_reset());
+        // The function is held in reset until the bridge above moves it on.
+        assert_eq!(
+            dev.advance_reset(u64::MAX - 1).unwrap(),
+            ResetProgress::InProgress
+        );
+        dev.wait_ready_after_reset(10);
+        assert_eq!(dev.advance_reset(10).unwrap(), ResetProgress::Done);
+        assert!(!dev.needs_reset());
+    }
+
+    #[test]
+    fn vf_enable_delay() {
+        let mut config = flr_endpoint_config();
+        let mut pf = test_device(&mut config);
+        pf.set_virtual_functions(Vec::new(), test_vf_layout());
+        pf.start_vf_enable(0);
+        assert!(pf.vf_enable_pending());
+        assert!(!pf.take_ready_vf_enable(VF_ENABLE_DELAY_US - 1));
+        assert!(pf.take_ready_vf_enable(VF_ENABLE_DELAY_US));
+        assert!(!pf.vf_enable_pending());
+        assert!(!pf.take_ready_vf_enable(VF_ENABLE_DELAY_US));
+
+        // Clearing VF Enable abandons the enable.
+        pf.start_vf_enable(0);
+        pf.set_sriov_control(0);
+        assert!(!pf.vf_enable_pending());
+        assert!(!pf.take_ready_vf_enable(VF_ENABLE_DELAY_US));
+    }
+
+    #[test]
+    fn vf_transfer() {
+        let mut pf_config = flr_endpoint_config();
+        let mut vf_config = flr_endpoint_config();
+        let mut arena = Arena::new(Global);
+        let pf = test_device(&mut pf_config);
+        let pf_info = pf.info().clone();
+        let pf_id = arena.insert(Mutex::new(pf));
+        let layout = test_vf_layout();
+        let vf_address = layout.vf_address(Address::default(), 0).unwrap();
+        // Not safe, just a test.
+        let regs = NonNull::new(vf_config.as_mut_ptr() as *mut CommonRegisters).unwrap();
+        let mut vf = unsafe { PciDevice::new_vf(regs, vf_address, pf_id, &pf_info, &layout) };
+        vf.enable_vf([0; PCI_SRIOV_VF_BARS]).unwrap();
+        let host = PageOwnerId::host();
+        let tvm = PageOwnerId::new(2).unwrap();
+        arena.get(pf_id).unwrap().lock().take(host).unwrap();
+        vf.take(host).unwrap();
+
+        // Only the VF's owner can give it away, and PFs can't be given away.
+        assert!(matches!(
+            vf.transfer_vf(tvm, host, 0),
+            Err(Error::DeviceNotOwned)
+        ));
+        assert!(matches!(
+            arena.get(pf_id).unwrap().lock().transfer_vf(host, tvm, 0),
+            Err(Error::NotVirtualFunction)
+        ));
+
+        vf.transfer_vf(host, tvm, 0).unwrap();
+        assert_eq!(vf.owner(), Some(tvm));
+        assert_eq!(arena.get(pf_id).unwrap().lock().owner(), Some(host));
+        // The TVM can't use the VF until it's been reset.
+        assert!(vf.needs_reset());
+        assert_eq!(vf.advance_reset(0).unwrap(), ResetProgress::InProgress);
+        assert_eq!(
+            vf.advance_reset(FLR_COMPLETION_US).unwrap(),
+            ResetProgress::Done
+        );
+        assert!(!vf.needs_reset());
+        assert_eq!(vf.owner(), Some(tvm));
+    }
 }
@@ -4,7 +4,7 @@
 
 use riscv_pages::SupervisorPageAddr;
 
-use super::address::{Address, Bus};
+use super::address::{Address, Bus, Segment};
 use super::device::HeaderType;
 use super::resource::PciResourceType;
 
@@ -26,6 +26,12 @@ pub enum Error {
     NoConfigBase,
     /// No compatible PCI host controller found in the device tree.
     NoCompatibleHostNode,
+    /// The device tree specified an unsupported '#address-cells' or '#size-cells' value.
+    InvalidCellCount(u32),
+    /// Multiple PCI host controllers in the device tree claim the same segment.
+    DuplicateSegment(Segment),
+    /// There are no free segments left to assign to a PCI host controller.
+    OutOfSegments,
     /// The device tree entry for the PCI host didn't provide a size register for Configuration
     /// Space.
     NoConfigSize,
@@ -63,6 +69,10 @@ pub enum Error {
     DeviceNotPresent(Address),
     /// Too many capabilities were found for a PCI device.
     TooManyCapabilities,
+    /// Too many extended capabilities were found for a PCI Express device.
+    TooManyExtendedCapabilities,
+    /// The extended capability list of a PCI Express device contains an invalid offset.
+    InvalidExtendedCapabilityOffset(usize),
     /// The device has MSI support, but is not 64-bit capable.
     MsiNot64BitCapable,
     /// The device has a vendor capability structure with an invalid length field.
@@ -89,6 +99,32 @@ pub enum Error {
     DeviceNotFound,
     /// The PCI device was expected to be on the root bus, but wasn't.
     DeviceNotOnRootBus,
+    /// The PCI device doesn't support any reset method that can be used in its current topology.
+    ResetUnsupported,
+    /// The PCI device didn't become ready again after being reset.
+    ResetTimeout(Address),
+    /// A secondary bus reset would affect devices that aren't owned by the requestor.
+    BusResetNotPermitted,
+    /// The PCI device must be reset before it can be assigned to a new owner.
+    DeviceNeedsReset,
+    /// The PCI device is still attached to an IOMMU context.
+    DeviceAttached,
+    /// The PCI device is not an SR-IOV physical function.
+    NotPhysicalFunction,
+    /// The PCI device is not an SR-IOV virtual function.
+    NotVirtualFunction,
+    /// The requested number of VFs exceeds the number of VFs that were enumerated.
+    TooManyVfs(u16),
+    /// The routing of VFs differs from the routing found at enumeration time.
+    VfRoutingChanged,
+    /// The operation isn't permitted while the physical function has VFs enabled.
+    VfsEnabled,
+    /// One or more VFs of the physical function are owned by another VM.
+    VfsInUse,
+    /// The device inserted in a hotplug slot can't be added to the hierarchy.
+    UnsupportedHotplugDevice(Address),
+    /// The hotplug slot holds functions that are owned by another VM.
+    SlotInUse,
 }
 
 /// Holds results for PCI operations.
@@ -13,9 +13,10 @@ mod registers;
 mod resource;
 mod root;
 
-pub use address::Address;
-pub use device::{DeviceId, PciDevice, PciDeviceInfo, VendorId};
+pub use address::{Address, Segment};
+pub use capabilities::ExtendedCapabilityPermissions;
+pub use device::{DeviceId, PciDevice, PciDeviceInfo, PciResetMethod, VendorId};
 pub use error::Error as PciError;
 pub use error::Result as PciResult;
 pub use resource::PciResourceType;
-pub use root::{PciArenaId, PciBarPage, PciBarPageIter, PciResourceIter, PcieRoot};
+pub use root::{PciArenaId, PciBarPage, PciBarPageIter, PciResourceIter, PcieRoot, PcieRoots};
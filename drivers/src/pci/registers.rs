@@ -103,14 +103,87 @@ register_bitfields![u16,
         FunctionLevelReset OFFSET(15) NUMBITS(1),
     ],
 
+    pub DeviceStatus [
+        TransactionsPending OFFSET(5) NUMBITS(1),
+    ],
+
     pub LinkStatus [
         LinkSpeed OFFSET(0) NUMBITS(4),
         LinkWidth OFFSET(4) NUMBITS(6),
+        DataLinkLayerLinkActive OFFSET(13) NUMBITS(1),
+    ],
+
+    pub SlotControl [
+        AttentionButtonPressedEnable OFFSET(0) NUMBITS(1),
+        PowerFaultDetectedEnable OFFSET(1) NUMBITS(1),
+        MrlSensorChangedEnable OFFSET(2) NUMBITS(1),
+        PresenceDetectChangedEnable OFFSET(3) NUMBITS(1),
+        CommandCompletedInterruptEnable OFFSET(4) NUMBITS(1),
+        HotPlugInterruptEnable OFFSET(5) NUMBITS(1),
+        AttentionIndicatorControl OFFSET(6) NUMBITS(2),
+        PowerIndicatorControl OFFSET(8) NUMBITS(2),
+        PowerControllerControl OFFSET(10) NUMBITS(1),
+        ElectromechanicalInterlockControl OFFSET(11) NUMBITS(1),
+        DataLinkLayerStateChangedEnable OFFSET(12) NUMBITS(1),
+    ],
+
+    pub SlotStatus [
+        AttentionButtonPressed OFFSET(0) NUMBITS(1),
+        PowerFaultDetected OFFSET(1) NUMBITS(1),
+        MrlSensorChanged OFFSET(2) NUMBITS(1),
+        PresenceDetectChanged OFFSET(3) NUMBITS(1),
+        CommandCompleted OFFSET(4) NUMBITS(1),
+        MrlSensorState OFFSET(5) NUMBITS(1),
+        PresenceDetectState OFFSET(6) NUMBITS(1),
+        ElectromechanicalInterlockStatus OFFSET(7) NUMBITS(1),
+        DataLinkLayerStateChanged OFFSET(8) NUMBITS(1),
     ],
 
     pub MemWindow [
         Address OFFSET(4) NUMBITS(12) [],
     ],
+
+    pub AcsControl [
+        SourceValidation OFFSET(0) NUMBITS(1),
+        TranslationBlocking OFFSET(1) NUMBITS(1),
+        P2pRequestRedirect OFFSET(2) NUMBITS(1),
+        P2pCompletionRedirect OFFSET(3) NUMBITS(1),
+        UpstreamForwarding OFFSET(4) NUMBITS(1),
+        P2pEgressControl OFFSET(5) NUMBITS(1),
+        DirectTranslatedP2p OFFSET(6) NUMBITS(1),
+    ],
+
+    pub AtsCapabilities [
+        InvalidateQueueDepth OFFSET(0) NUMBITS(5),
+        PageAlignedRequest OFFSET(5) NUMBITS(1),
+        GlobalInvalidate OFFSET(6) NUMBITS(1),
+        RelaxedOrdering OFFSET(7) NUMBITS(1),
+    ],
+
+    pub AtsControl [
+        SmallestTranslationUnit OFFSET(0) NUMBITS(5),
+        Enable OFFSET(15) NUMBITS(1),
+    ],
+
+    pub PasidCapabilities [
+        ExecutePermission OFFSET(1) NUMBITS(1),
+        PrivilegedMode OFFSET(2) NUMBITS(1),
+        MaxPasidWidth OFFSET(8) NUMBITS(5),
+    ],
+
+    pub PasidControl [
+        Enable OFFSET(0) NUMBITS(1),
+        ExecutePermissionEnable OFFSET(1) NUMBITS(1),
+        PrivilegedModeEnable OFFSET(2) NUMBITS(1),
+    ],
+
+    pub SriovControl [
+        VfEnable OFFSET(0) NUMBITS(1),
+        VfMigrationEnable OFFSET(1) NUMBITS(1),
+        VfMigrationInterruptEnable OFFSET(2) NUMBITS(1),
+        VfMemorySpaceEnable OFFSET(3) NUMBITS(1),
+        AriCapableHierarchy OFFSET(4) NUMBITS(1),
+    ],
 ];
 
 register_bitfields![u8,
@@ -155,8 +228,65 @@ register_bitfields![u32,
     pub LinkCapabilities [
         MaxLinkSpeed OFFSET(0) NUMBITS(4),
         MaxLinkWidth OFFSET(4) NUMBITS(6),
+        DataLinkLayerLinkActiveReportingCapable OFFSET(20) NUMBITS(1),
         PortNumber OFFSET(24) NUMBITS(8),
     ],
+
+    pub SlotCapabilities [
+        AttentionButtonPresent OFFSET(0) NUMBITS(1),
+        PowerControllerPresent OFFSET(1) NUMBITS(1),
+        MrlSensorPresent OFFSET(2) NUMBITS(1),
+        AttentionIndicatorPresent OFFSET(3) NUMBITS(1),
+        PowerIndicatorPresent OFFSET(4) NUMBITS(1),
+        HotPlugSurprise OFFSET(5) NUMBITS(1),
+        HotPlugCapable OFFSET(6) NUMBITS(1),
+        SlotPowerLimitValue OFFSET(7) NUMBITS(8),
+        SlotPowerLimitScale OFFSET(15) NUMBITS(2),
+        ElectromechanicalInterlockPresent OFFSET(17) NUMBITS(1),
+        NoCommandCompletedSupport OFFSET(18) NUMBITS(1),
+        PhysicalSlotNumber OFFSET(19) NUMBITS(13),
+    ],
+
+    pub ExtendedHeader [
+        Id OFFSET(0) NUMBITS(16),
+        Version OFFSET(16) NUMBITS(4),
+        NextOffset OFFSET(20) NUMBITS(12),
+    ],
+
+    pub AerCapabilitiesControl [
+        FirstErrorPointer OFFSET(0) NUMBITS(5),
+        EcrcGenerationCapable OFFSET(5) NUMBITS(1),
+        EcrcGenerationEnable OFFSET(6) NUMBITS(1),
+        EcrcCheckCapable OFFSET(7) NUMBITS(1),
+        EcrcCheckEnable OFFSET(8) NUMBITS(1),
+        MultipleHeaderRecordingCapable OFFSET(9) NUMBITS(1),
+        MultipleHeaderRecordingEnable OFFSET(10) NUMBITS(1),
+        TlpPrefixLogPresent OFFSET(11) NUMBITS(1),
+    ],
+
+    pub SriovCapabilities [
+        VfMigrationCapable OFFSET(0) NUMBITS(1),
+        AriCapableHierarchyPreserved OFFSET(1) NUMBITS(1),
+        VfMigrationInterruptMessageNumber OFFSET(21) NUMBITS(11),
+    ],
+
+    pub DoeCapabilities [
+        InterruptSupport OFFSET(0) NUMBITS(1),
+        InterruptMessageNumber OFFSET(1) NUMBITS(11),
+    ],
+
+    pub DoeControl [
+        Abort OFFSET(0) NUMBITS(1),
+        InterruptEnable OFFSET(1) NUMBITS(1),
+        Go OFFSET(31) NUMBITS(1),
+    ],
+
+    pub DoeStatus [
+        Busy OFFSET(0) NUMBITS(1),
+        InterruptStatus OFFSET(1) NUMBITS(1),
+        Error OFFSET(2) NUMBITS(1),
+        DataObjectReady OFFSET(31) NUMBITS(1),
+    ],
 ];
 
 /// Common portion of the PCI configuration header.
@@ -320,16 +450,15 @@ pub struct ExpressRegisters {
     pub exp_caps: ReadOnly<u16, ExpressCapabilities::Register>,
     pub dev_caps: ReadOnly<u32, DeviceCapabilities::Register>,
     pub dev_control: ReadWrite<u16, DeviceControl::Register>,
-    pub dev_status: ReadWrite<u16>,
-    // All devices with links. We only expose the link speed and width.
+    pub dev_status: ReadWrite<u16, DeviceStatus::Register>,
+    // All devices with links. We only expose the link speed, width and state.
     pub link_caps: ReadOnly<u32, LinkCapabilities::Register>,
     pub link_control: ReadWrite<u16>,
     pub link_status: ReadWrite<u16, LinkStatus::Register>,
-    // All devices with slots. We hide the FLAGS_SLOT bit, so these registers will appear as
-    // unimplemented to a VM.
-    pub slot_caps: ReadOnly<u32>,
-    pub slot_control: ReadWrite<u16>,
-    pub slot_status: ReadWrite<u16>,
+    // All devices with slots. Only the hotplug-related bits are exposed.
+    pub slot_caps: ReadOnly<u32, SlotCapabilities::Register>,
+    pub slot_control: ReadWrite<u16, SlotControl::Register>,
+    pub slot_status: ReadWrite<u16, SlotStatus::Register>,
     // All root ports or root complex event collectors. We don't expose any of the capabilities here.
     pub root_control: ReadWrite<u16>,
     pub root_caps: ReadOnly<u16>,
@@ -346,6 +475,100 @@ pub struct ExpressRegisters {
     pub slot_status2: ReadOnly<u16>,
 }
 
+/// Start byte offset of the PCI Express extended configuration space, where the extended capability
+/// list begins.
+pub const PCIE_EXT_CAPS_START: usize = PCI_CONFIG_SPACE_END + 1;
+/// End byte offset of the PCI Express extended configuration space.
+pub const PCIE_CONFIG_SPACE_END: usize = 0xfff;
+
+/// PCI Express extended capability header.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct ExtendedCapabilityHeader {
+    pub header: ReadOnly<u32, ExtendedHeader::Register>,
+}
+
+/// Advanced Error Reporting extended capability.
+///
+/// The root port error registers that follow the header log are not exposed.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct AerRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub uncor_status: ReadWrite<u32>,
+    pub uncor_mask: ReadWrite<u32>,
+    pub uncor_severity: ReadWrite<u32>,
+    pub cor_status: ReadWrite<u32>,
+    pub cor_mask: ReadWrite<u32>,
+    pub cap_control: ReadWrite<u32, AerCapabilitiesControl::Register>,
+    pub header_log: [ReadOnly<u32>; 4],
+}
+
+/// Access Control Services extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct AcsRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub acs_caps: ReadOnly<u16>,
+    pub acs_control: ReadWrite<u16, AcsControl::Register>,
+}
+
+/// Address Translation Services extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct AtsRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub ats_caps: ReadOnly<u16, AtsCapabilities::Register>,
+    pub ats_control: ReadWrite<u16, AtsControl::Register>,
+}
+
+/// Process Address Space ID extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct PasidRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub pasid_caps: ReadOnly<u16, PasidCapabilities::Register>,
+    pub pasid_control: ReadWrite<u16, PasidControl::Register>,
+}
+
+/// Number of VF BAR registers in an SR-IOV capability.
+pub const PCI_SRIOV_VF_BARS: usize = 6;
+
+/// Single Root I/O Virtualization extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct SriovRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub sriov_caps: ReadOnly<u32, SriovCapabilities::Register>,
+    pub sriov_control: ReadWrite<u16, SriovControl::Register>,
+    pub sriov_status: ReadWrite<u16>,
+    pub initial_vfs: ReadOnly<u16>,
+    pub total_vfs: ReadOnly<u16>,
+    pub num_vfs: ReadWrite<u16>,
+    pub func_dep_link: ReadOnly<u8>,
+    _reserved0: u8,
+    pub first_vf_offset: ReadOnly<u16>,
+    pub vf_stride: ReadOnly<u16>,
+    _reserved1: u16,
+    pub vf_dev_id: ReadOnly<u16>,
+    pub supported_page_sizes: ReadOnly<u32>,
+    pub system_page_size: ReadWrite<u32>,
+    pub vf_bar: [ReadWrite<u32, BaseAddress::Register>; PCI_SRIOV_VF_BARS],
+    pub vf_migration_state: ReadOnly<u32>,
+}
+
+/// Data Object Exchange extended capability.
+#[repr(C)]
+#[derive(FieldOffsets)]
+pub struct DoeRegisters {
+    pub header: ExtendedCapabilityHeader,
+    pub doe_caps: ReadOnly<u32, DoeCapabilities::Register>,
+    pub doe_control: ReadWrite<u32, DoeControl::Register>,
+    pub doe_status: ReadWrite<u32, DoeStatus::Register>,
+    pub write_mailbox: ReadWrite<u32>,
+    pub read_mailbox: ReadWrite<u32>,
+}
+
 /// Trait for specifying various mask values for a register.
 ///
 /// TODO: Make the `*_mask()` functions const values.
@@ -453,7 +676,8 @@ impl RegisterMasks for SecondaryStatus::Register {
     }
 }
 
-// BRIDGE_CTL.BUS_RESET is virtualized.
+// BRIDGE_CTL.BUS_RESET is virtualized. Setting it requests a secondary bus reset which is validated
+// and carried out by the hypervisor.
 impl RegisterMasks for BridgeControl::Register {
     type RegType = u16;
 
@@ -570,7 +794,8 @@ impl RegisterMasks for MsiXMessageControl::Register {
     }
 }
 
-// Hide FLAGS_SLOT so that we can leave the slot registers unimplemented.
+// The slot registers are emulated for ports that implement a slot so that VMs can handle hotplug
+// events.
 impl RegisterMasks for ExpressCapabilities::Register {
     type RegType = u16;
 
@@ -582,6 +807,7 @@ impl RegisterMasks for ExpressCapabilities::Register {
         let mut mask = LocalRegisterCopy::<u16, ExpressCapabilities::Register>::new(0);
         mask.modify(ExpressCapabilities::Version.val(ExpressCapabilities::Version.mask));
         mask.modify(ExpressCapabilities::DeviceType.val(ExpressCapabilities::DeviceType.mask));
+        mask.modify(ExpressCapabilities::SlotImplemented.val(1));
         mask.modify(
             ExpressCapabilities::InterruptMessageNumber
                 .val(ExpressCapabilities::InterruptMessageNumber.mask),
@@ -594,10 +820,8 @@ impl RegisterMasks for ExpressCapabilities::Register {
     }
 }
 
-// Hide everything but MPS for now. Phantom functions, extended tags, etc could affect requester IDs
-// and confuse the IOMMU.
-//
-// TODO: Expose function-level reset capability.
+// Hide everything but MPS and FLR for now. Phantom functions, extended tags, etc could affect
+// requester IDs and confuse the IOMMU.
 impl RegisterMasks for DeviceCapabilities::Register {
     type RegType = u32;
 
@@ -610,6 +834,7 @@ impl RegisterMasks for DeviceCapabilities::Register {
         mask.modify(
             DeviceCapabilities::MaxPayloadSize.val(DeviceCapabilities::MaxPayloadSize.mask),
         );
+        mask.modify(DeviceCapabilities::FunctionLevelReset.val(1));
         mask.get()
     }
 
@@ -618,9 +843,9 @@ impl RegisterMasks for DeviceCapabilities::Register {
     }
 }
 
-// Allow reads from (but not writes to) payload size fields since they can have system-wide effects
-//
-// TODO: Allow function-level resets.
+// Allow reads from (but not writes to) payload size fields since they can have system-wide effects.
+// DEV_CTL.FLR always reads as 0; writing it requests a function-level reset which is carried out by
+// the hypervisor.
 impl RegisterMasks for DeviceControl::Register {
     type RegType = u16;
 
@@ -644,7 +869,7 @@ impl RegisterMasks for DeviceControl::Register {
     }
 }
 
-// Hide everything but link speed/width and port number.
+// Hide everything but link speed/width, port number and whether the link state is reported.
 impl RegisterMasks for LinkCapabilities::Register {
     type RegType = u32;
 
@@ -656,6 +881,7 @@ impl RegisterMasks for LinkCapabilities::Register {
         let mut mask = LocalRegisterCopy::<u32, LinkCapabilities::Register>::new(0);
         mask.modify(LinkCapabilities::MaxLinkSpeed.val(LinkCapabilities::MaxLinkSpeed.mask));
         mask.modify(LinkCapabilities::MaxLinkWidth.val(LinkCapabilities::MaxLinkWidth.mask));
+        mask.modify(LinkCapabilities::DataLinkLayerLinkActiveReportingCapable.val(1));
         mask.modify(LinkCapabilities::PortNumber.val(LinkCapabilities::PortNumber.mask));
         mask.get()
     }
@@ -665,7 +891,8 @@ impl RegisterMasks for LinkCapabilities::Register {
     }
 }
 
-// Like LNKCAP, hide everything except link speed/width.
+// Like LNKCAP, hide everything except link speed/width. The data link layer state is needed by
+// hotplug drivers to tell when a device in a slot is ready.
 impl RegisterMasks for LinkStatus::Register {
     type RegType = u16;
 
@@ -677,6 +904,7 @@ impl RegisterMasks for LinkStatus::Register {
         let mut mask = LocalRegisterCopy::<u16, LinkStatus::Register>::new(0);
         mask.modify(LinkStatus::LinkSpeed.val(LinkStatus::LinkSpeed.mask));
         mask.modify(LinkStatus::LinkWidth.val(LinkStatus::LinkWidth.mask));
+        mask.modify(LinkStatus::DataLinkLayerLinkActive.val(1));
         mask.get()
     }
 
@@ -685,6 +913,259 @@ impl RegisterMasks for LinkStatus::Register {
     }
 }
 
+// Expose the hotplug capabilities of the slot, but hide the slot power limit; it's set by the
+// host firmware and is of no use to a VM.
+impl RegisterMasks for SlotCapabilities::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        0
+    }
+
+    fn readable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, SlotCapabilities::Register>::new(0);
+        mask.modify(SlotCapabilities::AttentionButtonPresent.val(1));
+        mask.modify(SlotCapabilities::PowerControllerPresent.val(1));
+        mask.modify(SlotCapabilities::MrlSensorPresent.val(1));
+        mask.modify(SlotCapabilities::AttentionIndicatorPresent.val(1));
+        mask.modify(SlotCapabilities::PowerIndicatorPresent.val(1));
+        mask.modify(SlotCapabilities::HotPlugSurprise.val(1));
+        mask.modify(SlotCapabilities::HotPlugCapable.val(1));
+        mask.modify(SlotCapabilities::ElectromechanicalInterlockPresent.val(1));
+        mask.modify(SlotCapabilities::NoCommandCompletedSupport.val(1));
+        mask.modify(
+            SlotCapabilities::PhysicalSlotNumber.val(SlotCapabilities::PhysicalSlotNumber.mask),
+        );
+        mask.get()
+    }
+
+    fn clearable_mask() -> u32 {
+        0
+    }
+}
+
+// The slot controls are passed through. Changes to the slot's power are tracked by the hypervisor
+// so that it can add or remove the devices in the slot.
+impl RegisterMasks for SlotControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SlotControl::Register>::new(0);
+        mask.modify(SlotControl::AttentionButtonPressedEnable.val(1));
+        mask.modify(SlotControl::PowerFaultDetectedEnable.val(1));
+        mask.modify(SlotControl::MrlSensorChangedEnable.val(1));
+        mask.modify(SlotControl::PresenceDetectChangedEnable.val(1));
+        mask.modify(SlotControl::CommandCompletedInterruptEnable.val(1));
+        mask.modify(SlotControl::HotPlugInterruptEnable.val(1));
+        mask.modify(
+            SlotControl::AttentionIndicatorControl.val(SlotControl::AttentionIndicatorControl.mask),
+        );
+        mask.modify(
+            SlotControl::PowerIndicatorControl.val(SlotControl::PowerIndicatorControl.mask),
+        );
+        mask.modify(SlotControl::PowerControllerControl.val(1));
+        mask.modify(SlotControl::ElectromechanicalInterlockControl.val(1));
+        mask.modify(SlotControl::DataLinkLayerStateChangedEnable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+// Clearing the presence or link state change bits acknowledges a hotplug event, after which the
+// hypervisor rescans the slot.
+impl RegisterMasks for SlotStatus::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SlotStatus::Register>::new(0);
+        mask.modify(SlotStatus::AttentionButtonPressed.val(1));
+        mask.modify(SlotStatus::PowerFaultDetected.val(1));
+        mask.modify(SlotStatus::MrlSensorChanged.val(1));
+        mask.modify(SlotStatus::PresenceDetectChanged.val(1));
+        mask.modify(SlotStatus::CommandCompleted.val(1));
+        mask.modify(SlotStatus::DataLinkLayerStateChanged.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SlotStatus::Register>::new(Self::writeable_mask());
+        mask.modify(SlotStatus::MrlSensorState.val(1));
+        mask.modify(SlotStatus::PresenceDetectState.val(1));
+        mask.modify(SlotStatus::ElectromechanicalInterlockStatus.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+}
+
+// ACS controls the isolation of the device from its peers. Only the host may change it, so make it
+// read-only to VMs.
+impl RegisterMasks for AcsControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        0
+    }
+
+    fn readable_mask() -> u16 {
+        !0
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+impl RegisterMasks for AtsControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, AtsControl::Register>::new(0);
+        mask.modify(
+            AtsControl::SmallestTranslationUnit.val(AtsControl::SmallestTranslationUnit.mask),
+        );
+        mask.modify(AtsControl::Enable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+impl RegisterMasks for PasidControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, PasidControl::Register>::new(0);
+        mask.modify(PasidControl::Enable.val(1));
+        mask.modify(PasidControl::ExecutePermissionEnable.val(1));
+        mask.modify(PasidControl::PrivilegedModeEnable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+// The ECRC and multiple header recording enables are the only writeable bits.
+impl RegisterMasks for AerCapabilitiesControl::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, AerCapabilitiesControl::Register>::new(0);
+        mask.modify(AerCapabilitiesControl::EcrcGenerationEnable.val(1));
+        mask.modify(AerCapabilitiesControl::EcrcCheckEnable.val(1));
+        mask.modify(AerCapabilitiesControl::MultipleHeaderRecordingEnable.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u32 {
+        !0
+    }
+
+    fn clearable_mask() -> u32 {
+        0
+    }
+}
+
+// Hide VF migration, which was deprecated in later revisions of the spec.
+impl RegisterMasks for SriovCapabilities::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        0
+    }
+
+    fn readable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, SriovCapabilities::Register>::new(0);
+        mask.modify(SriovCapabilities::AriCapableHierarchyPreserved.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u32 {
+        0
+    }
+}
+
+impl RegisterMasks for SriovControl::Register {
+    type RegType = u16;
+
+    fn writeable_mask() -> u16 {
+        let mut mask = LocalRegisterCopy::<u16, SriovControl::Register>::new(0);
+        mask.modify(SriovControl::VfEnable.val(1));
+        mask.modify(SriovControl::VfMemorySpaceEnable.val(1));
+        mask.modify(SriovControl::AriCapableHierarchy.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u16 {
+        Self::writeable_mask()
+    }
+
+    fn clearable_mask() -> u16 {
+        0
+    }
+}
+
+impl RegisterMasks for DoeControl::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, DoeControl::Register>::new(0);
+        mask.modify(DoeControl::Abort.val(1));
+        mask.modify(DoeControl::InterruptEnable.val(1));
+        mask.modify(DoeControl::Go.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, DoeControl::Register>::new(0);
+        mask.modify(DoeControl::InterruptEnable.val(1));
+        mask.get()
+    }
+
+    fn clearable_mask() -> u32 {
+        0
+    }
+}
+
+impl RegisterMasks for DoeStatus::Register {
+    type RegType = u32;
+
+    fn writeable_mask() -> u32 {
+        let mut mask = LocalRegisterCopy::<u32, DoeStatus::Register>::new(0);
+        mask.modify(DoeStatus::InterruptStatus.val(1));
+        mask.get()
+    }
+
+    fn readable_mask() -> u32 {
+        !0
+    }
+
+    fn clearable_mask() -> u32 {
+        Self::writeable_mask()
+    }
+}
+
 // Macro to implement RegisterHelpers for the given type.
 macro_rules! reg_helpers_impl {
     ($reg_type:tt) => {
@@ -714,6 +1195,12 @@ fn _assert_register_layout() {
     const_assert!(core::mem::size_of::<CommonRegisters>() == 0x10);
     const_assert!(core::mem::size_of::<EndpointRegisters>() == 0x40);
     const_assert!(core::mem::size_of::<BridgeRegisters>() == 0x40);
+    const_assert!(core::mem::size_of::<AerRegisters>() == 0x2c);
+    const_assert!(core::mem::size_of::<AcsRegisters>() == 0x8);
+    const_assert!(core::mem::size_of::<AtsRegisters>() == 0x8);
+    const_assert!(core::mem::size_of::<PasidRegisters>() == 0x8);
+    const_assert!(core::mem::size_of::<SriovRegisters>() == 0x40);
+    const_assert!(core::mem::size_of::<DoeRegisters>() == 0x18);
 }
 
 /// Macro that itself defines a `span!()` macro for the given struct field which evaluates to a
@@ -111,6 +111,14 @@ impl PciResourceType {
         }
     }
 
+    /// Returns if this resource is prefetchable.
+    pub fn is_prefetchable(&self) -> bool {
+        matches!(
+            self,
+            PciResourceType::PrefetchableMem32 | PciResourceType::PrefetchableMem64
+        )
+    }
+
     /// Returns if this resource uses 64-bit addresses.
     pub fn is_64bit(&self) -> bool {
         matches!(
@@ -270,6 +278,18 @@ impl PciRootResources {
             .checked_increment(pci_addr.checked_sub(res.pci_addr())?)
     }
 
+    /// Returns if the range of `size` bytes at `pci_addr` lies entirely within the portion of a
+    /// single resource that is passed through to the host.
+    pub fn host_range_contains(&self, pci_addr: u64, size: u64) -> bool {
+        let Some(end) = pci_addr.checked_add(size) else {
+            return false;
+        };
+        self.resources
+            .iter()
+            .flatten()
+            .any(|r| r.pci_addr() <= pci_addr && end <= r.pci_addr() + r.host_size())
+    }
+
     /// Translates the given CPU physical address to a PCI bus address.
     pub fn physical_to_pci_addr(&self, addr: SupervisorPhysAddr) -> Option<u64> {
         let res = self
@@ -293,3 +313,114 @@ impl Default for PciRootResources {
         Self::new()
     }
 }
+
+/// Allocates PCI bus addresses for BARs from the windows a bridge forwards to its secondary bus.
+pub struct PciWindowAllocator {
+    // The next free address and the (inclusive) limit of each window.
+    io: Option<(u64, u64)>,
+    mem: Option<(u64, u64)>,
+    pref: Option<(u64, u64)>,
+}
+
+impl PciWindowAllocator {
+    /// Creates an allocator for the IO, non-prefetchable and prefetchable memory windows specified
+    /// as (base, limit) pairs.
+    pub fn new(io: Option<(u64, u64)>, mem: Option<(u64, u64)>, pref: Option<(u64, u64)>) -> Self {
+        Self { io, mem, pref }
+    }
+
+    /// Allocates a naturally-aligned range of `size` bytes for a BAR of type `bar_type`, returning
+    /// its PCI bus address. Prefetchable BARs are placed in the non-prefetchable window if they
+    /// don't fit in the prefetchable one.
+    pub fn alloc(&mut self, bar_type: PciResourceType, size: u64) -> Result<u64> {
+        if !size.is_power_of_two() {
+            return Err(Error::InvalidBarSize(size));
+        }
+        let max_addr = if bar_type.is_64bit() {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        let addr = match bar_type {
+            PciResourceType::IoPort => Self::alloc_from(&mut self.io, size, max_addr),
+            t if t.is_prefetchable() => Self::alloc_from(&mut self.pref, size, max_addr)
+                .or_else(|| Self::alloc_from(&mut self.mem, size, max_addr)),
+            _ => Self::alloc_from(&mut self.mem, size, max_addr),
+        };
+        addr.ok_or(Error::OutOfResources)
+    }
+
+    /// Marks the range of `size` bytes at `pci_addr` as in use, preventing any further allocations
+    /// below its end in the window containing it.
+    pub fn reserve(&mut self, pci_addr: u64, size: u64) {
+        let end = pci_addr.saturating_add(size);
+        for (next, limit) in [&mut self.io, &mut self.mem, &mut self.pref]
+            .into_iter()
+            .flatten()
+        {
+            if pci_addr <= *limit && end > *next {
+                *next = end;
+            }
+        }
+    }
+
+    // Allocates `size` bytes, aligned to `size`, from `window` below `max_addr`.
+    fn alloc_from(window: &mut Option<(u64, u64)>, size: u64, max_addr: u64) -> Option<u64> {
+        let (next, limit) = window.as_mut()?;
+        let base = next.checked_add(size - 1)? & !(size - 1);
+        let end = base.checked_add(size - 1)?;
+        if end > *limit || end > max_addr {
+            return None;
+        }
+        *next = end.saturating_add(1);
+        Some(base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_alloc() {
+        let mut windows = PciWindowAllocator::new(
+            None,
+            Some((0x4000_0000, 0x400f_ffff)),
+            Some((0x1_0000_0000, 0x1_001f_ffff)),
+        );
+        // BARs are naturally aligned within their window.
+        assert_eq!(
+            windows.alloc(PciResourceType::Mem32, 0x1000).unwrap(),
+            0x4000_0000
+        );
+        assert_eq!(
+            windows.alloc(PciResourceType::Mem64, 0x4000).unwrap(),
+            0x4000_4000
+        );
+        assert_eq!(
+            windows
+                .alloc(PciResourceType::PrefetchableMem64, 0x10_0000)
+                .unwrap(),
+            0x1_0000_0000
+        );
+        // 32-bit prefetchable BARs can't use a window above 4GB.
+        assert_eq!(
+            windows
+                .alloc(PciResourceType::PrefetchableMem32, 0x1000)
+                .unwrap(),
+            0x4000_8000
+        );
+        assert!(windows.alloc(PciResourceType::IoPort, 0x100).is_err());
+        assert!(windows.alloc(PciResourceType::Mem32, 0x10_0000).is_err());
+        assert!(windows.alloc(PciResourceType::Mem32, 0x3000).is_err());
+
+        // Reserved ranges are skipped.
+        windows.reserve(0x1_0010_0000, 0x8_0000);
+        assert_eq!(
+            windows
+                .alloc(PciResourceType::PrefetchableMem64, 0x8_0000)
+                .unwrap(),
+            0x1_0018_0000
+        );
+    }
+}
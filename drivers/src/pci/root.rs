@@ -5,20 +5,26 @@
 use alloc::alloc::Global;
 use arrayvec::{ArrayString, ArrayVec};
 use core::marker::PhantomData;
-use device_tree::{DeviceTree, DeviceTreeResult};
+use core::sync::atomic::{AtomicBool, Ordering};
+use device_tree::{DeviceTree, DeviceTreeNode, DeviceTreeResult};
 use hyp_alloc::{Arena, ArenaId};
 use page_tracking::{HwMemMap, PageTracker};
 use riscv_pages::*;
 use sync::{Mutex, Once};
+use tock_registers::interfaces::{ReadWriteable, Readable};
+use tock_registers::LocalRegisterCopy;
 
 use crate::imsic::Imsic;
+use crate::CpuInfo;
 
 use super::address::*;
 use super::bus::PciBus;
+use super::capabilities::ExtendedCapabilityPermissions;
 use super::config_space::PciConfigSpace;
 use super::device::*;
 use super::error::*;
 use super::mmio_builder::MmioEmulationContext;
+use super::registers::{SlotControl, SriovControl};
 use super::resource::*;
 
 /// An arena of PCI devices.
@@ -28,9 +34,12 @@ pub type PciDeviceArena = Arena<Mutex<PciDevice>, Global>;
 pub type PciArenaId = ArenaId<Mutex<PciDevice>>;
 
 // The number of "PCI address" cells, as specified in the standard PCI binding.
-const PCI_ADDR_CELLS: usize = 3;
-// Number of u32 cells per 'ranges' property in the device tree.
-const CELLS_PER_RANGE: usize = PCI_ADDR_CELLS + 4;
+const PCI_ADDR_CELLS: u32 = 3;
+// Number of u32 cells per 'ranges' entry in the PCI nodes we generate for the host, which always
+// use 64-bit CPU addresses and sizes.
+const HOST_CELLS_PER_RANGE: usize = PCI_ADDR_CELLS as usize + 4;
+// The maximum number of cells making up an address or size that we support.
+const MAX_VALUE_CELLS: u32 = 2;
 
 /// Represents a PCI-Express root complex.
 pub struct PcieRoot {
@@ -39,16 +48,174 @@ pub struct PcieRoot {
     device_arena: PciDeviceArena,
     resources: Mutex<PciRootResources>,
     msi_parent_phandle: u32,
+    // Set when devices are taken by a VM after boot, e.g. when they're hot-added to a slot, and may
+    // need to be attached to the VM's IOMMU context.
+    devices_added: AtomicBool,
 }
 
-static PCIE_ROOT: Once<PcieRoot> = Once::new();
+/// The set of PCI-Express root complexes (host bridges) in the system, each of which occupies a
+/// distinct PCI segment. Devices are tracked per-root, so a `PciArenaId` is only meaningful in
+/// combination with the `PcieRoot` it came from.
+pub struct PcieRoots {
+    roots: Vec<PcieRoot>,
+}
+
+static PCIE_ROOTS: Once<PcieRoots> = Once::new();
+
+// Returns the value of the cell count property `name` of `node`, or `default` if it's absent.
+fn cell_count(node: &DeviceTreeNode, name: &str, default: u32) -> u32 {
+    node.props()
+        .find(|p| p.name() == name)
+        .and_then(|p| p.value_u32().next())
+        .unwrap_or(default)
+}
+
+// Reads a value made up of `cells` big-endian u32 cells from `iter`.
+fn read_cells(iter: &mut impl Iterator<Item = u32>, cells: u32) -> Option<u64> {
+    let mut val = 0;
+    for _ in 0..cells {
+        val = (val << 32) | iter.next()? as u64;
+    }
+    Some(val)
+}
 
-// A `u64` from two `u32` cells in a device tree.
-struct U64Cell(u32, u32);
+// Returns the segment explicitly assigned to the PCI host bridge at `node`, if any.
+fn pci_domain(node: &DeviceTreeNode) -> Option<Segment> {
+    node.props()
+        .find(|p| p.name() == "linux,pci-domain")
+        .and_then(|p| p.value_u32().next())
+        .and_then(|v| Segment::try_from(v).ok())
+}
+
+// Returns if `node` is a PCI host bridge that we support.
+fn is_pcie_host_node(node: &DeviceTreeNode) -> bool {
+    node.compatible(["pci-host-ecam-generic"]) && !node.disabled()
+}
+
+impl PcieRoots {
+    /// Creates the `PcieRoots` singleton by probing every supported PCI host bridge in the passed
+    /// `DeviceTree`. Host bridges that aren't explicitly assigned a segment with the
+    /// 'linux,pci-domain' property are assigned the lowest segment that's not otherwise in use.
+    pub fn probe_from(dt: &DeviceTree, mem_map: &mut HwMemMap) -> Result<()> {
+        let mut roots: Vec<PcieRoot> = Vec::new();
+        for node in dt.iter().filter(|n| is_pcie_host_node(n)) {
+            let segment = match pci_domain(node) {
+                Some(s) => {
+                    if roots.iter().any(|r| r.segment() == s) {
+                        return Err(Error::DuplicateSegment(s));
+                    }
+                    s
+                }
+                None => {
+                    let in_use = |s: Segment| {
+                        roots.iter().any(|r| r.segment() == s)
+                            || dt
+                                .iter()
+                                .any(|n| is_pcie_host_node(n) && pci_domain(n) == Some(s))
+                    };
+                    core::iter::successors(Some(Segment::default()), |s| s.next())
+                        .find(|&s| !in_use(s))
+                        .ok_or(Error::OutOfSegments)?
+                }
+            };
+            let root = PcieRoot::probe_node(dt, node, segment, mem_map)?;
+            roots.try_reserve(1).map_err(|_| Error::AllocError)?;
+            roots.push(root);
+        }
+        if roots.is_empty() {
+            return Err(Error::NoCompatibleHostNode);
+        }
+
+        PCIE_ROOTS.call_once(|| Self { roots });
+        Ok(())
+    }
+
+    /// Gets a reference to the `PcieRoots` singleton. Panics if `PcieRoots::probe_from()` has not
+    /// yet been called to initialize it.
+    pub fn get() -> &'static Self {
+        PCIE_ROOTS.get().unwrap()
+    }
+
+    /// Returns an iterator over the root complexes.
+    pub fn iter(&self) -> impl Iterator<Item = &PcieRoot> {
+        self.roots.iter()
+    }
+
+    /// Returns the root complex for `segment`.
+    pub fn get_root(&self, segment: Segment) -> Option<&PcieRoot> {
+        self.roots.iter().find(|r| r.segment() == segment)
+    }
+
+    /// Returns an iterator over all PCI devices under every root complex.
+    pub fn devices(&self) -> impl Iterator<Item = &Mutex<PciDevice>> {
+        self.roots.iter().flat_map(|r| r.devices())
+    }
+
+    /// Returns the root complex whose config space contains the physical address `addr`, along
+    /// with the offset of `addr` within that config space.
+    pub fn root_for_config_addr(&self, addr: u64) -> Option<(&PcieRoot, u64)> {
+        self.roots.iter().find_map(|r| {
+            let config_mem = r.config_space();
+            let offset = addr.checked_sub(config_mem.base().bits())?;
+            (offset < config_mem.length_bytes()).then_some((r, offset))
+        })
+    }
+
+    /// Takes ownership over all unowned devices under every root complex on behalf of the host VM.
+    pub fn take_host_devices(&self) {
+        for root in self.roots.iter() {
+            root.take_host_devices();
+        }
+    }
+
+    /// Releases and resets the devices owned by `owner` under every root complex. See
+    /// `PcieRoot::release_devices()`.
+    pub fn release_devices(&self, owner: PageOwnerId) {
+        for root in self.roots.iter() {
+            root.release_devices(owner);
+        }
+    }
+
+    /// Transfers every SR-IOV virtual function owned by `from` under every root complex to `to`. See
+    /// `PcieRoot::transfer_vfs()`.
+    pub fn transfer_vfs(&self, from: PageOwnerId, to: PageOwnerId) {
+        for root in self.roots.iter() {
+            root.transfer_vfs(from, to);
+        }
+    }
+
+    /// Returns and clears whether devices have been taken by a VM under any of the root complexes
+    /// since the last call. See `PcieRoot::take_devices_added()`.
+    pub fn take_devices_added(&self) -> bool {
+        self.roots
+            .iter()
+            .fold(false, |added, r| r.take_devices_added() || added)
+    }
+
+    /// Takes ownership over the first PCI device with the given `vendor_id` and `device_id` under
+    /// any of the root complexes, and enables it for use within the hypervisor. Returns the root
+    /// complex the device is under along with the device's ID within that root.
+    pub fn take_and_enable_hypervisor_device(
+        &self,
+        vendor_id: VendorId,
+        device_id: DeviceId,
+    ) -> Result<(&PcieRoot, PciArenaId)> {
+        for root in self.roots.iter() {
+            match root.take_and_enable_hypervisor_device(vendor_id, device_id) {
+                Err(Error::DeviceNotFound) => continue,
+                result => return result.map(|id| (root, id)),
+            }
+        }
+        Err(Error::DeviceNotFound)
+    }
 
-impl From<U64Cell> for u64 {
-    fn from(u: U64Cell) -> u64 {
-        ((u.0 as u64) << 32) | (u.1 as u64)
+    /// Adds a node for each root complex to the host's device tree in `dt`. See
+    /// `PcieRoot::add_host_pcie_node()`.
+    pub fn add_host_pcie_nodes(&self, dt: &mut DeviceTree) -> DeviceTreeResult<()> {
+        for root in self.roots.iter() {
+            root.add_host_pcie_node(dt)?;
+        }
+        Ok(())
     }
 }
 
@@ -59,25 +226,44 @@ fn valid_config_mmio_access(offset: u64, len: usize) -> bool {
 }
 
 impl PcieRoot {
-    /// Creates a `PcieRoot` singleton by finding a supported configuration in the passed `DeviceTree`.
-    pub fn probe_from(dt: &DeviceTree, mem_map: &mut HwMemMap) -> Result<()> {
-        let pci_node = dt
-            .iter()
-            .find(|n| n.compatible(["pci-host-ecam-generic"]) && !n.disabled())
-            .ok_or(Error::NoCompatibleHostNode)?;
+    // Probes the PCI host bridge described by `node` in `dt`, assigning it `segment`.
+    fn probe_node(
+        dt: &DeviceTree,
+        pci_node: &DeviceTreeNode,
+        segment: Segment,
+        mem_map: &mut HwMemMap,
+    ) -> Result<Self> {
+        // The 'reg' and CPU addresses in 'ranges' are encoded using the cell sizes of the parent
+        // node, while the PCI addresses and sizes in 'ranges' use those of the host bridge node.
+        let parent = pci_node.parent().and_then(|id| dt.get_node(id));
+        let parent_addr_cells = parent
+            .map(|n| cell_count(n, "#address-cells", 2))
+            .unwrap_or(2);
+        let parent_size_cells = parent.map(|n| cell_count(n, "#size-cells", 1)).unwrap_or(1);
+        let pci_addr_cells = cell_count(pci_node, "#address-cells", PCI_ADDR_CELLS);
+        let pci_size_cells = cell_count(pci_node, "#size-cells", 2);
+        if pci_addr_cells != PCI_ADDR_CELLS {
+            return Err(Error::InvalidCellCount(pci_addr_cells));
+        }
+        for cells in [parent_addr_cells, parent_size_cells, pci_size_cells] {
+            if cells == 0 || cells > MAX_VALUE_CELLS {
+                return Err(Error::InvalidCellCount(cells));
+            }
+        }
 
         // Find the ECAM MMIO region, which should be the first entry in the `reg` property.
         let mut regs = pci_node
             .props()
             .find(|p| p.name() == "reg")
             .ok_or(Error::NoRegProperty)?
-            .value_u64();
+            .value_u32();
 
-        let config_addr_raw = regs.next().ok_or(Error::NoConfigBase)?;
+        let config_addr_raw =
+            read_cells(&mut regs, parent_addr_cells).ok_or(Error::NoConfigBase)?;
         let config_base = PageAddr::new(RawAddr::supervisor(config_addr_raw))
             .ok_or(Error::ConfigSpaceMisaligned(config_addr_raw))?;
 
-        let config_size = regs.next().ok_or(Error::NoConfigSize)?;
+        let config_size = read_cells(&mut regs, parent_size_cells).ok_or(Error::NoConfigSize)?;
         if config_size % (PageSize::Size4k as u64) != 0 {
             return Err(Error::ConfigSpaceNotPageMultiple(config_size));
         }
@@ -129,49 +315,40 @@ impl PcieRoot {
             }
         };
 
-        // Find the segment number specified in the device tree, if any.
-        let segment = pci_node
-            .props()
-            .find(|p| p.name() == "linux,pci-domain")
-            .and_then(|p| p.value_u32().next())
-            .and_then(|v| Segment::try_from(v).ok())
-            .unwrap_or_default();
         let config_space = PciConfigSpace::new(config_base, config_size, segment, bus_range);
 
-        // Parse the 'ranges' property for the various BAR resources. Assuming '#address-cells' is 3
-        // and '#size-cells' is 2, each range is 7x u32 cells:
+        // Parse the 'ranges' property for the various BAR resources. Each range consists of:
         //
-        // cells[0] describes the type of resource.
-        // cells[1:2] are the 64-bit PCI address of the resource.
-        // cells[3:4] are the 64-bit CPU address of the resource.
-        // cells[5:6] are the 64-bit size of the resource.
+        // - the 3-cell PCI address, the first cell of which describes the type of resource,
+        // - the CPU address of the resource in `parent_addr_cells` cells, and
+        // - the size of the resource in `pci_size_cells` cells.
         //
         // See IEEE 1275-1994 for more details.
-        //
-        // TODO: Assuming fixed '#address-cells'/'#size-cells'.
         let ranges_prop = pci_node
             .props()
             .find(|p| p.name() == "ranges")
             .ok_or(Error::NoRangesProperty)?;
+        let cells_per_range = (PCI_ADDR_CELLS + parent_addr_cells + pci_size_cells) as usize;
         let mut ranges = ranges_prop.value_u32();
         let mut resources = PciRootResources::new();
-        while ranges.len() >= CELLS_PER_RANGE {
+        while ranges.len() >= cells_per_range {
             // Get the resource type from the first cell. We've already guaranteed there are enough
             // cells for this range.
             let resource_type = match PciResourceType::from_dt_cell(ranges.next().unwrap()) {
                 Some(r) => r,
                 None => {
-                    ranges.advance_by(CELLS_PER_RANGE - 1).unwrap();
+                    ranges.advance_by(cells_per_range - 1).unwrap();
                     continue;
                 }
             };
 
-            // Next is the PCI address, followed by the CPU address and region size.
-            let pci_addr = U64Cell(ranges.next().unwrap(), ranges.next().unwrap()).into();
-            let cpu_addr = U64Cell(ranges.next().unwrap(), ranges.next().unwrap()).into();
+            // Next is the PCI address, followed by the CPU address and region size. Unwraps ok:
+            // we've checked that there are enough cells above.
+            let pci_addr = read_cells(&mut ranges, PCI_ADDR_CELLS - 1).unwrap();
+            let cpu_addr = read_cells(&mut ranges, parent_addr_cells).unwrap();
             let addr = PageAddr::new(RawAddr::supervisor(cpu_addr))
                 .ok_or(Error::ResourceMisaligned(cpu_addr))?;
-            let size = U64Cell(ranges.next().unwrap(), ranges.next().unwrap()).into();
+            let size = read_cells(&mut ranges, pci_size_cells).unwrap();
             if size % (PageSize::Size4k as u64) != 0 {
                 return Err(Error::ResourceNotPageMultiple(size));
             }
@@ -192,20 +369,19 @@ impl PcieRoot {
         let mut device_arena = PciDeviceArena::new(Global);
         let root_bus = PciBus::enumerate(&config_space, bus_range.start, &mut device_arena)?;
 
-        PCIE_ROOT.call_once(|| Self {
+        Ok(Self {
             config_space,
             root_bus,
             device_arena,
             resources: Mutex::new(resources),
             msi_parent_phandle,
-        });
-        Ok(())
+            devices_added: AtomicBool::new(false),
+        })
     }
 
-    /// Gets a reference to the `PcieRoot` singleton. Panics if `PcieRoot::probe_from()` has not yet
-    /// been called to initialize it.
-    pub fn get() -> &'static Self {
-        PCIE_ROOT.get().unwrap()
+    /// Returns the PCI segment (domain) covered by this root complex.
+    pub fn segment(&self) -> Segment {
+        self.config_space.segment()
     }
 
     /// Returns an iterator over all PCI devices.
@@ -271,10 +447,68 @@ impl PcieRoot {
         self.device_arena.get(arena_id)
     }
 
-    /// Takes ownership over all unowned devices in the PCI hierarchy on behalf of the host VM.
+    /// Takes ownership over all unowned devices in the PCI hierarchy on behalf of the host VM. The
+    /// host is responsible for managing SR-IOV physical functions, so it is permitted to enable
+    /// VFs on any devices it takes.
     pub fn take_host_devices(&self) {
         for dev in self.devices() {
-            let _ = dev.lock().take(PageOwnerId::host());
+            let mut dev = dev.lock();
+            if dev.take(PageOwnerId::host()).is_ok() && dev.has_sriov() {
+                let permissions = ExtendedCapabilityPermissions {
+                    sriov: true,
+                    ..dev.extended_capability_permissions()
+                };
+                dev.set_extended_capability_permissions(permissions);
+            }
+        }
+    }
+
+    /// Returns and clears whether devices have been taken by a VM since the last call, e.g. because
+    /// they were hot-added to one of its slots. The VM must attach them to its IOMMU context before
+    /// they can use DMA.
+    pub fn take_devices_added(&self) -> bool {
+        self.devices_added.swap(false, Ordering::AcqRel)
+    }
+
+    /// Returns the ID of the device at `address` in the virtualized PCI hierarchy seen by VMs.
+    pub fn device_by_virtual_address(&self, address: Address) -> Option<PciArenaId> {
+        if address.segment() != self.segment() {
+            return None;
+        }
+        self.device_by_virtual_address_on(&self.root_bus, address)
+    }
+
+    /// Transfers ownership of the enabled SR-IOV virtual function with `vf_id` from `from` to `to`,
+    /// resetting it in the process. The VF must already have been detached from any IOMMU context,
+    /// and `to` can't use it until the reset completes. Ownership of the VF's physical function is
+    /// unaffected.
+    pub fn transfer_vf(&self, vf_id: PciArenaId, from: PageOwnerId, to: PageOwnerId) -> Result<()> {
+        self.device_arena
+            .get(vf_id)
+            .ok_or(Error::DeviceNotFound)?
+            .lock()
+            .transfer_vf(from, to, CpuInfo::get().time_us())?;
+        // `to` may need to attach it to its IOMMU context.
+        self.devices_added.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Transfers every SR-IOV virtual function owned by `from` to `to`, e.g. to hand them back to
+    /// the host when the VM they were assigned to is destroyed. A VF that can't be transferred is
+    /// released and reset instead, as by `release_devices()`.
+    pub fn transfer_vfs(&self, from: PageOwnerId, to: PageOwnerId) {
+        for vf_id in self.device_arena.ids() {
+            // Unwrap ok: the ID comes from the arena.
+            let owned = {
+                let vf = self.device_arena.get(vf_id).unwrap().lock();
+                vf.is_vf() && vf.owner() == Some(from)
+            };
+            if owned
+                && self.transfer_vf(vf_id, from, to).is_err()
+                && self.release_device(vf_id, from).is_ok()
+            {
+                let _ = self.reset_device(vf_id, None);
+            }
         }
     }
 
@@ -328,6 +562,64 @@ impl PcieRoot {
         Ok(dev_id)
     }
 
+    /// Releases `owner`'s ownership of the device with `dev_id`. The device must be reset using
+    /// `reset_device()` before it can be assigned to another owner.
+    pub fn release_device(&self, dev_id: PciArenaId, owner: PageOwnerId) -> Result<()> {
+        let mut dev = self
+            .device_arena
+            .get(dev_id)
+            .ok_or(Error::DeviceNotFound)?
+            .lock();
+        if dev.owner() != Some(owner) {
+            return Err(Error::DeviceNotOwned);
+        }
+        dev.release()
+    }
+
+    /// Releases every device owned by `owner`, e.g. because `owner` is being destroyed, and resets
+    /// them so that they can be assigned to a new owner. The devices must have been detached from
+    /// the IOMMU. A device that fails to reset is left needing a reset and can't be taken again.
+    pub fn release_devices(&self, owner: PageOwnerId) {
+        for dev_id in self.device_arena.ids() {
+            // Unwrap ok: the ID comes from the arena.
+            if self.device_arena.get(dev_id).unwrap().lock().owner() != Some(owner) {
+                continue;
+            }
+            if self.release_device(dev_id, owner).is_ok() {
+                let _ = self.reset_device(dev_id, None);
+            }
+        }
+    }
+
+    /// Starts a reset of the device with `dev_id`, which must be owned by `owner` or, if `owner` is
+    /// `None`, be unowned (e.g. after having been released by its previous owner). A function-level
+    /// reset is used if the device supports one. Otherwise, the secondary bus of the device's parent
+    /// bridge is reset, provided every device below the bridge is unowned or owned by `owner`.
+    /// Returns the method used to reset the device.
+    ///
+    /// The reset completes in the background: config accesses see the device as not ready until
+    /// it is, and the device can't be taken by a new owner until then.
+    pub fn reset_device(
+        &self,
+        dev_id: PciArenaId,
+        owner: Option<PageOwnerId>,
+    ) -> Result<PciResetMethod> {
+        match self.reset_function(dev_id, owner) {
+            Err(Error::ResetUnsupported) => (),
+            result => return result,
+        }
+
+        // We can't reset the root bus, so the device must be behind a bridge.
+        let bridge_id = self
+            .parent_bridge_on(&self.root_bus, dev_id)
+            .ok_or(Error::ResetUnsupported)?;
+        let mut dev = self.device_arena.get(bridge_id).unwrap().lock();
+        // Unwrap ok: `parent_bridge_on()` only returns bridges.
+        let bridge = dev.as_bridge_mut().unwrap();
+        self.reset_secondary_bus(bridge, owner)?;
+        Ok(PciResetMethod::SecondaryBus)
+    }
+
     /// Adds a node for this PCIe root complex to the host's device tree in `dt`. It's assumed that
     /// the config space and BAR resources will be identity-mapped into the host VM's guest physical
     /// address space (i.e. GPA == SPA for the various PCI memory regions). It is up to the caller to
@@ -355,7 +647,7 @@ impl PcieRoot {
         pci_node
             .add_prop("reg")?
             .set_value_u64(&[config_mem.base().bits(), config_mem.length_bytes()])?;
-        let mut ranges = ArrayVec::<u32, { CELLS_PER_RANGE * MAX_RESOURCE_TYPES }>::new();
+        let mut ranges = ArrayVec::<u32, { HOST_CELLS_PER_RANGE * MAX_RESOURCE_TYPES }>::new();
         let resources = self.resources.lock();
         for i in 0..MAX_RESOURCE_TYPES {
             let res_type = PciResourceType::from_index(i).unwrap();
@@ -424,11 +716,26 @@ impl PcieRoot {
             return Err(Error::UnsupportedConfigAccess);
         }
         let (dev_id, dev_offset) = self.virtual_config_offset_to_device(offset as usize)?;
+        self.update_pending_state(dev_id);
         // If the device ID is present in the hierarchy, then it must be in the arena.
-        let dev = self.device_arena.get(dev_id).unwrap().lock();
+        let mut dev = self.device_arena.get(dev_id).unwrap().lock();
+        if !dev.is_present() && !dev.is_vf() {
+            // The function is in a hotplug slot. The device in the slot may not have been ready to
+            // respond to config requests when the slot was last scanned, so try again.
+            drop(dev);
+            self.rescan_slot_of(dev_id)?;
+            dev = self.device_arena.get(dev_id).unwrap().lock();
+        }
+        if !dev.is_present() {
+            return Err(Error::DeviceNotPresent(dev.info().address()));
+        }
         if dev.owner() != Some(guest_id) {
             return Err(Error::DeviceNotOwned);
         }
+        // The device is being reset, or failed to reset.
+        if dev.needs_reset() {
+            return Err(Error::DeviceNeedsReset);
+        }
         let resources = self.resources.lock();
         let context = MmioEmulationContext {
             page_tracker,
@@ -450,11 +757,19 @@ impl PcieRoot {
             return Err(Error::UnsupportedConfigAccess);
         }
         let (dev_id, dev_offset) = self.virtual_config_offset_to_device(offset as usize)?;
+        self.update_pending_state(dev_id);
         // If the device ID is present in the hierarchy, then it must be in the arena.
         let mut dev = self.device_arena.get(dev_id).unwrap().lock();
+        if !dev.is_present() {
+            return Err(Error::DeviceNotPresent(dev.info().address()));
+        }
         if dev.owner() != Some(guest_id) {
             return Err(Error::DeviceNotOwned);
         }
+        // The device is being reset, or failed to reset.
+        if dev.needs_reset() {
+            return Err(Error::DeviceNeedsReset);
+        }
         let resources = self.resources.lock();
         let context = MmioEmulationContext {
             page_tracker,
@@ -462,9 +777,369 @@ impl PcieRoot {
             resources: &resources,
         };
         dev.emulate_config_write(dev_offset, value as u32, len, context);
+        if dev.take_flr_request() {
+            drop(resources);
+            drop(dev);
+            // Nothing to report back to the VM if the reset fails; the device is left quiesced
+            // and can't be used until it's successfully reset.
+            let _ = self.reset_function(dev_id, Some(guest_id));
+            return Ok(());
+        }
+        if let Some(control) = dev.take_sriov_control_request() {
+            let context = MmioEmulationContext {
+                page_tracker,
+                guest_id,
+                resources: &resources,
+            };
+            self.update_sriov_control(&mut dev, control, &context)?;
+        }
+        drop(resources);
+        if let Some(bridge) = dev.as_bridge_mut() {
+            if bridge.take_bus_reset_request() {
+                self.reset_secondary_bus(bridge, Some(guest_id))?;
+            }
+            if let Some(control) = bridge.take_slot_control_request() {
+                self.update_slot_power(bridge, control, guest_id)?;
+            }
+            if bridge.take_slot_event() {
+                self.rescan_slot(bridge, guest_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Applies a write of `control` to the slot control register of the hotplug-capable port
+    // `bridge` by `owner`, turning power to the slot on or off. Power can only be turned off if
+    // every device in the slot is either unowned or owned by `owner`.
+    fn update_slot_power(
+        &self,
+        bridge: &mut PciBridge,
+        control: u16,
+        owner: PageOwnerId,
+    ) -> Result<()> {
+        let reg = LocalRegisterCopy::<u16, SlotControl::Register>::new(control);
+        let power_off = reg.is_set(SlotControl::PowerControllerControl);
+        if let Some(child_bus) = bridge.child_bus() {
+            if power_off && !self.bus_owned_by(child_bus, Some(owner)) {
+                return Err(Error::SlotInUse);
+            }
+        }
+        bridge.set_slot_control(control);
+        // Devices in the slot are added once the link comes up and the owner acknowledges it.
+        if power_off {
+            if let Some(child_bus) = bridge.child_bus() {
+                self.remove_bus_devices(child_bus);
+            }
+        }
+        Ok(())
+    }
+
+    // Adds or removes the devices in the slot below the hotplug-capable port `bridge`, which is
+    // owned by `owner`, so that they match the current state of the slot.
+    fn rescan_slot(&self, bridge: &PciBridge, owner: PageOwnerId) -> Result<()> {
+        let Some(child_bus) = bridge.child_bus() else {
+            return Ok(());
+        };
+        if bridge.slot_occupied() {
+            self.add_slot_devices(bridge, child_bus, owner)
+        } else {
+            self.remove_bus_devices(child_bus);
+            Ok(())
+        }
+    }
+
+    // Rescans the slot holding the device with `dev_id` for newly-inserted devices.
+    fn rescan_slot_of(&self, dev_id: PciArenaId) -> Result<()> {
+        let Some(bridge_id) = self.parent_bridge_on(&self.root_bus, dev_id) else {
+            return Ok(());
+        };
+        let dev = self.device_arena.get(bridge_id).unwrap().lock();
+        let Some(owner) = dev.owner() else {
+            return Ok(());
+        };
+        match *dev {
+            PciDevice::Bridge(ref bridge) if bridge.is_hotplug_capable() => {
+                if let Some(child_bus) = bridge.child_bus() {
+                    if bridge.slot_occupied() {
+                        return self.add_slot_devices(bridge, child_bus, owner);
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Adds the functions of the device inserted in the slot on `bus`, directly below `bridge`,
+    // that have become ready since the slot was last scanned. The added functions are taken by
+    // `owner`, the owner of `bridge`, and must be attached to its IOMMU context before they can
+    // use DMA. Their BARs are assigned from the windows of `bridge`, avoiding the BARs of
+    // functions that are already present.
+    fn add_slot_devices(&self, bridge: &PciBridge, bus: &PciBus, owner: PageOwnerId) -> Result<()> {
+        let mut windows = bridge.window_allocator();
+        for bd in bus.devices() {
+            let dev = self.device_arena.get(bd.id).unwrap().lock();
+            if dev.is_present() && !dev.is_vf() {
+                for bar in dev.bar_info().bars() {
+                    // Unwrap ok: the BAR index comes from the device's own BAR info.
+                    windows.reserve(dev.get_bar_addr(bar.index()).unwrap(), bar.size());
+                }
+            }
+        }
+
+        let mut multi_function = false;
+        for bd in bus.devices() {
+            let mut dev = self.device_arena.get(bd.id).unwrap().lock();
+            if dev.is_vf() {
+                continue;
+            }
+            let function = bd.address.function().bits();
+            if function != 0 && !multi_function {
+                break;
+            }
+            if !dev.is_present() {
+                match dev.hot_add().and_then(|_| dev.take(owner)) {
+                    Ok(()) => {
+                        // Leave the BARs for the owner to assign if there's no room for them.
+                        let resources = self.resources.lock();
+                        let _ = dev.assign_bars(&mut windows, &resources);
+                        self.devices_added.store(true, Ordering::Release);
+                    }
+                    // Not ready yet; we'll try again when the function is next accessed.
+                    Err(Error::DeviceNotPresent(_)) if function == 0 => return Ok(()),
+                    Err(Error::DeviceNotPresent(_)) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            if function == 0 {
+                multi_function = dev.info().multi_function();
+            }
+        }
+        Ok(())
+    }
+
+    // Marks every device on or below `bus` as absent following their removal from a hotplug slot.
+    fn remove_bus_devices(&self, bus: &PciBus) {
+        for bd in bus.devices() {
+            let mut dev = self.device_arena.get(bd.id).unwrap().lock();
+            if let Some(child_bus) = dev.child_bus() {
+                self.remove_bus_devices(child_bus);
+            }
+            dev.hot_remove();
+        }
+    }
+
+    // Applies a write of `control` to the SR-IOV control register of the physical function `pf` by
+    // the VM in `context`, enabling or disabling the PF's VFs as requested. VFs can only be
+    // disabled while they're all owned by the PF's owner.
+    fn update_sriov_control(
+        &self,
+        pf: &mut PciDevice,
+        control: u16,
+        context: &MmioEmulationContext,
+    ) -> Result<()> {
+        let mut reg = LocalRegisterCopy::<u16, SriovControl::Register>::new(control);
+        if reg.is_set(SriovControl::VfMemorySpaceEnable)
+            && pf.can_enable_vf_mem_space(context).is_err()
+        {
+            reg.modify(SriovControl::VfMemorySpaceEnable::CLEAR);
+        }
+
+        let num_enabled = pf.num_vfs_enabled();
+        let enabled_vfs = &pf.virtual_functions()[..num_enabled];
+        if reg.is_set(SriovControl::VfEnable) && num_enabled == 0 {
+            pf.can_enable_vfs()?;
+            pf.set_sriov_control(reg.get());
+            // VFs aren't guaranteed to respond to config requests until 100ms after VF Enable is
+            // set, so they're enabled by the first access after that.
+            pf.start_vf_enable(CpuInfo::get().time_us());
+            Ok(())
+        } else if !reg.is_set(SriovControl::VfEnable) && num_enabled != 0 {
+            if enabled_vfs
+                .iter()
+                .any(|&id| self.device_arena.get(id).unwrap().lock().owner() != pf.owner())
+            {
+                return Err(Error::VfsInUse);
+            }
+            for &id in enabled_vfs {
+                self.device_arena.get(id).unwrap().lock().disable_vf();
+            }
+            pf.set_sriov_control(reg.get());
+            Ok(())
+        } else {
+            pf.set_sriov_control(reg.get());
+            Ok(())
+        }
+    }
+
+    // Enables the VFs of the physical function with `pf_id` if they've become accessible by
+    // `now_us` since VF Enable was set. VF Enable is cleared again if any of them can't be enabled.
+    fn finish_vf_enable(&self, pf_id: PciArenaId, now_us: u64) {
+        let mut pf = self.device_arena.get(pf_id).unwrap().lock();
+        if !pf.take_ready_vf_enable(now_us) {
+            return;
+        }
+        let num_vfs = pf.num_vfs_enabled();
+        let result = pf.virtual_functions()[..num_vfs]
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, &id)| {
+                let mut vf = self.device_arena.get(id).unwrap().lock();
+                vf.enable_vf(pf.vf_bar_addrs(index))
+            });
+        if result.is_err() {
+            for &id in &pf.virtual_functions()[..num_vfs] {
+                self.device_arena.get(id).unwrap().lock().disable_vf();
+            }
+            pf.set_sriov_control(0);
+        }
+    }
+
+    // Starts a reset of the secondary bus of `bridge` on behalf of `owner`, provided that every
+    // device that would be reset is either unowned or owned by `owner`. The devices below the
+    // bridge are held in reset until the bus reset is over, and are then restored as they become
+    // ready.
+    fn reset_secondary_bus(
+        &self,
+        bridge: &mut PciBridge,
+        owner: Option<PageOwnerId>,
+    ) -> Result<()> {
+        let child_bus = bridge.child_bus().ok_or(Error::ResetUnsupported)?;
+        if !self.bus_owned_by(child_bus, owner) {
+            return Err(Error::BusResetNotPermitted);
+        }
+        self.hold_bus_in_reset(child_bus);
+        bridge.start_secondary_bus_reset(CpuInfo::get().time_us());
         Ok(())
     }
 
+    // Returns if every device on or below `bus` is either unowned or owned by `owner`.
+    fn bus_owned_by(&self, bus: &PciBus, owner: Option<PageOwnerId>) -> bool {
+        bus.devices().all(|bd| {
+            let dev = self.device_arena.get(bd.id).unwrap().lock();
+            if dev.owner().is_some() && dev.owner() != owner {
+                return false;
+            }
+            dev.child_bus()
+                .map(|b| self.bus_owned_by(b, owner))
+                .unwrap_or(true)
+        })
+    }
+
+    // Marks every device on or below `bus` as being held in reset by a secondary bus reset.
+    fn hold_bus_in_reset(&self, bus: &PciBus) {
+        for bd in bus.devices() {
+            let mut dev = self.device_arena.get(bd.id).unwrap().lock();
+            // The reset disables any VFs along with their PFs.
+            if dev.is_vf() {
+                dev.disable_vf();
+                continue;
+            }
+            dev.hold_in_bus_reset();
+            if let Some(child_bus) = dev.child_bus() {
+                self.hold_bus_in_reset(child_bus);
+            }
+        }
+    }
+
+    // Starts waiting at `now_us` for every device on or below `bus` to become ready following the
+    // end of a secondary bus reset.
+    fn wait_bus_ready_after_reset(&self, bus: &PciBus, now_us: u64) {
+        for bd in bus.devices() {
+            let mut dev = self.device_arena.get(bd.id).unwrap().lock();
+            if dev.is_vf() {
+                continue;
+            }
+            dev.wait_ready_after_reset(now_us);
+            if let Some(child_bus) = dev.child_bus() {
+                self.wait_bus_ready_after_reset(child_bus, now_us);
+            }
+        }
+    }
+
+    // Starts a reset of the function with `dev_id`, which must be owned by `owner`, with its
+    // function-level reset method. If the reset fails the device is left needing a reset, so that
+    // it isn't handed out again.
+    fn reset_function(
+        &self,
+        dev_id: PciArenaId,
+        owner: Option<PageOwnerId>,
+    ) -> Result<PciResetMethod> {
+        let mut dev = self
+            .device_arena
+            .get(dev_id)
+            .ok_or(Error::DeviceNotFound)?
+            .lock();
+        if dev.owner() != owner {
+            return Err(Error::DeviceNotOwned);
+        }
+        dev.start_reset(CpuInfo::get().time_us())
+    }
+
+    // Brings the state of the device with `dev_id` up to date ahead of an access to it, completing
+    // any reset or enabling of VFs whose deadline has passed so that the access sees the device as
+    // ready. A device whose reset fails is left needing a reset.
+    fn update_pending_state(&self, dev_id: PciArenaId) {
+        // Unwrap ok: `dev_id` is only passed for devices in the arena.
+        let dev = self.device_arena.get(dev_id).unwrap().lock();
+        // Absent VFs may be waiting on their PF to enable them.
+        let pf_id = if dev.vf_enable_pending() {
+            Some(dev_id)
+        } else if !dev.is_present() {
+            dev.physical_function()
+        } else {
+            None
+        };
+        let reset_in_progress = dev.reset_in_progress();
+        drop(dev);
+        if pf_id.is_none() && !reset_in_progress {
+            return;
+        }
+        let now_us = CpuInfo::get().time_us();
+        if let Some(pf_id) = pf_id {
+            self.finish_vf_enable(pf_id, now_us);
+        }
+        if !reset_in_progress {
+            return;
+        }
+        // Bridges above the device may be resetting their secondary bus, in which case they have to
+        // be restored before the device can be reached.
+        let mut ids = Vec::new();
+        let mut id = dev_id;
+        while let Some(bridge_id) = self.parent_bridge_on(&self.root_bus, id) {
+            if ids.try_reserve(1).is_err() {
+                return;
+            }
+            ids.push(bridge_id);
+            id = bridge_id;
+        }
+        for &id in ids.iter().rev().chain(core::iter::once(&dev_id)) {
+            let mut dev = self.device_arena.get(id).unwrap().lock();
+            if let Ok(ResetProgress::BusResetDone) = dev.advance_reset(now_us) &&
+                let Some(child_bus) = dev.child_bus()
+            {
+                self.wait_bus_ready_after_reset(child_bus, now_us);
+            }
+        }
+    }
+
+    // Returns the ID of the bridge on or below `bus` whose secondary bus holds the device with
+    // `dev_id`.
+    fn parent_bridge_on(&self, bus: &PciBus, dev_id: PciArenaId) -> Option<PciArenaId> {
+        for bd in bus.devices() {
+            let dev = self.device_arena.get(bd.id).unwrap().lock();
+            if let Some(child_bus) = dev.child_bus() {
+                if child_bus.devices().any(|cd| cd.id == dev_id) {
+                    return Some(bd.id);
+                }
+                if let Some(id) = self.parent_bridge_on(child_bus, dev_id) {
+                    return Some(id);
+                }
+            }
+        }
+        None
+    }
+
     // Returns the device ID for the device at the virtualized PCI address `address` on `bus`.
     fn device_by_virtual_address_on(&self, bus: &PciBus, address: Address) -> Option<PciArenaId> {
         if address.bus() == bus.virtual_secondary_bus_num() {
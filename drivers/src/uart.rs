@@ -28,11 +28,34 @@ pub type Result<T> = core::result::Result<T, Error>;
 // Standard 16500a register set length.
 const UART_REGISTERS_LEN: u64 = 8;
 
+// Offsets of the registers we use from the UART's base address.
+const UART_RBR: usize = 0;
+const UART_IER: usize = 1;
+const UART_MCR: usize = 4;
+const UART_LSR: usize = 5;
+
+// Interrupt enable register bits.
+const IER_RX_DATA_AVAILABLE: u8 = 1 << 0;
+// Modem control register bits.
+const MCR_OUT2: u8 = 1 << 3;
+// Line status register bits.
+const LSR_DATA_READY: u8 = 1 << 0;
+
 static UART_DRIVER: Once<UartDriver> = Once::new();
 
 /// Driver for a standard UART.
 pub struct UartDriver {
     base_address: Mutex<NonNull<u8>>,
+    interrupt: Option<UartInterrupt>,
+}
+
+/// Describes how the UART's interrupt is wired.
+#[derive(Clone, Copy, Debug)]
+pub struct UartInterrupt {
+    /// The phandle of the interrupt controller the UART's interrupt is wired to.
+    pub parent: u32,
+    /// The UART's interrupt number at `parent`.
+    pub source: u32,
 }
 
 impl UartDriver {
@@ -60,14 +83,68 @@ impl UartDriver {
                 .add_mmio_region(DeviceMemType::Uart, RawAddr::supervisor(base_address), len)
                 .map_err(Error::AddingMmioRegion)
         }?;
+        let source = node
+            .props()
+            .find(|p| p.name() == "interrupts")
+            .and_then(|p| p.value_u32().next());
+        let parent = node
+            .props()
+            .find(|p| p.name() == "interrupt-parent")
+            .and_then(|p| p.value_u32().next());
+        let interrupt = source
+            .zip(parent)
+            .map(|(source, parent)| UartInterrupt { parent, source });
         // Unwrap ok, we've already verified that base_address is non-NULL.
         let uart = UartDriver {
             base_address: Mutex::new(NonNull::new(base_address as _).unwrap()),
+            interrupt,
         };
         UART_DRIVER.call_once(|| uart);
         Console::set_writer(UART_DRIVER.get().unwrap());
         Ok(())
     }
+
+    /// Returns the system UART, if one has been probed.
+    pub fn get() -> Option<&'static UartDriver> {
+        UART_DRIVER.get()
+    }
+
+    /// Returns how this UART's interrupt is wired, if the device tree says.
+    pub fn interrupt(&self) -> Option<UartInterrupt> {
+        self.interrupt
+    }
+
+    /// Enables the interrupt for received data. The caller must have routed the UART's interrupt
+    /// to us.
+    pub fn enable_rx_interrupt(&self) {
+        let base_address = self.base_address.lock();
+        // Safety: the caller of ::new() had to guarantee that the given address belongs to an actual
+        // UART and that nobody else is using it, thereby making this defined behavior.
+        unsafe {
+            let mcr = base_address.as_ptr().add(UART_MCR);
+            // OUT2 gates the interrupt line on real 16550s.
+            core::ptr::write_volatile(mcr, core::ptr::read_volatile(mcr) | MCR_OUT2);
+            core::ptr::write_volatile(base_address.as_ptr().add(UART_IER), IER_RX_DATA_AVAILABLE);
+        }
+    }
+
+    /// Returns the next byte received by this UART, or `None` if none is pending. Used both to
+    /// poll for input and to drain it on a received data interrupt.
+    pub fn read_byte(&self) -> Option<u8> {
+        let base_address = self.base_address.lock();
+        // Safety: the caller of ::new() had to guarantee that the given address belongs to an actual
+        // UART and that nobody else is using it, thereby making this defined behavior.
+        unsafe {
+            let lsr = core::ptr::read_volatile(base_address.as_ptr().add(UART_LSR));
+            if lsr & LSR_DATA_READY != 0 {
+                Some(core::ptr::read_volatile(
+                    base_address.as_ptr().add(UART_RBR),
+                ))
+            } else {
+                None
+            }
+        }
+    }
 }
 
 impl ConsoleWriter for UartDriver {
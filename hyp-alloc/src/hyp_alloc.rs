@@ -105,6 +105,13 @@ impl HypAlloc {
     pub fn seal(&self) {
         self.inner.lock().seal();
     }
+
+    /// Returns true if `ptr` points into the allocator's storage.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let inner = self.inner.lock();
+        let base = inner.mem.as_mut_ptr() as usize;
+        (base..base + inner.capacity()).contains(&(ptr as usize))
+    }
 }
 
 // Safety: HypAlloc is synchronized internally via a mutex, so it is safe to share a reference to it
@@ -189,4 +196,13 @@ mod tests {
         let mut vec_b: Vec<u32, &HypAlloc> = Vec::new_in(&alloc);
         assert!(vec_b.try_reserve(10).is_err());
     }
+
+    #[test]
+    fn contains() {
+        let alloc = stub_heap();
+        let five = Box::new_in(5u64, &alloc);
+        assert!(alloc.contains(&*five as *const u64 as *const u8));
+        let outside = 5u64;
+        assert!(!alloc.contains(&outside as *const u64 as *const u8));
+    }
 }
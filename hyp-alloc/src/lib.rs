@@ -18,9 +18,12 @@ extern crate alloc;
 pub mod arena;
 /// A simple thread-safe bump-pointer allocator backed by a fixed-length contiguous range of Pages.
 pub mod hyp_alloc;
+/// A thread-safe slab allocator with per-CPU caches which supports freeing and grows on demand.
+pub mod slab_alloc;
 
 pub use crate::hyp_alloc::HypAlloc;
 pub use arena::{Arena, ArenaId};
+pub use slab_alloc::{AllocStats, SlabAlloc, SlabBackend};
 
 #[cfg(test)]
 #[macro_use]
@@ -0,0 +1,483 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use core::alloc::{AllocError, Allocator, Layout};
+use core::ptr::NonNull;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv_pages::{InternalClean, PageSize, SequentialPages};
+use sync::Mutex;
+
+/// Provides the memory and CPU identification `SlabAlloc` needs from its environment.
+pub trait SlabBackend: Send + Sync {
+    /// Returns `count` physically contiguous, clean pages to grow the heap with, or `None` if there
+    /// are none left. The pages are owned by the allocator from then on and never given back.
+    fn take_pages(&self, count: usize) -> Option<SequentialPages<InternalClean>>;
+
+    /// Returns the index of the calling CPU, which selects its cache of free objects.
+    fn cpu_index(&self) -> usize;
+}
+
+/// CPUs with an index at or above this allocate straight from the shared free lists.
+pub const MAX_CACHED_CPUS: usize = 64;
+
+const PAGE_SIZE: usize = PageSize::Size4k as usize;
+
+// Allocations are rounded up to one of these sizes, and larger ones are made up of whole pages.
+// Objects are carved out of page-aligned slabs, so a power-of-two size class is also the alignment
+// its objects are guaranteed to have.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const NUM_SIZE_CLASSES: usize = SIZE_CLASSES.len();
+
+// The number of free objects moved between a CPU's cache and the shared free lists at a time.
+const CACHE_BATCH: usize = 16;
+// A CPU's cache gives a batch of objects back once it holds more than this many of a size class.
+const CACHE_MAX: usize = 2 * CACHE_BATCH;
+
+// A free object, linked through its first word.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+// A singly-linked list of free objects of one size class.
+struct FreeList {
+    head: Option<NonNull<FreeObject>>,
+    len: usize,
+}
+
+impl FreeList {
+    const EMPTY: FreeList = FreeList { head: None, len: 0 };
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        let obj = self.head?;
+        // Safety: Objects on the list are free and were initialized as a `FreeObject` by `push()`.
+        self.head = unsafe { obj.as_ref().next };
+        self.len -= 1;
+        Some(obj.cast())
+    }
+
+    // Safety: `obj` must point to a free object at least as large and aligned as a `FreeObject`
+    // which isn't on any other list.
+    unsafe fn push(&mut self, obj: NonNull<u8>) {
+        let obj = obj.cast::<FreeObject>();
+        obj.as_ptr().write(FreeObject { next: self.head });
+        self.head = Some(obj);
+        self.len += 1;
+    }
+
+    // Moves up to `count` objects from `self` to `other`.
+    fn move_to(&mut self, other: &mut FreeList, count: usize) {
+        for _ in 0..count {
+            let Some(obj) = self.pop() else {
+                break;
+            };
+            // Safety: `obj` was just taken off of our list.
+            unsafe { other.push(obj) };
+        }
+    }
+}
+
+// A CPU's cache of free objects of each size class.
+struct CpuCache {
+    classes: [FreeList; NUM_SIZE_CLASSES],
+}
+
+// A free run of pages, linked through its first page.
+struct FreeRun {
+    next: Option<NonNull<FreeRun>>,
+    num_pages: usize,
+}
+
+// State shared by all CPUs.
+struct SharedState {
+    classes: [FreeList; NUM_SIZE_CLASSES],
+    // Runs of pages freed by large allocations. Neither sorted nor coalesced.
+    free_runs: Option<NonNull<FreeRun>>,
+}
+
+/// Allocation statistics for a `SlabAlloc`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// The number of allocations that haven't been freed yet.
+    pub live_allocs: usize,
+    /// The number of bytes requested by allocations that haven't been freed yet.
+    pub live_bytes: usize,
+    /// The total number of successful allocations.
+    pub total_allocs: usize,
+    /// The number of allocations that failed for lack of memory or unsupported alignment.
+    pub failed_allocs: usize,
+    /// The number of pages taken from the backend.
+    pub heap_pages: usize,
+}
+
+#[derive(Default)]
+struct AllocCounters {
+    live_allocs: AtomicUsize,
+    live_bytes: AtomicUsize,
+    total_allocs: AtomicUsize,
+    failed_allocs: AtomicUsize,
+    heap_pages: AtomicUsize,
+}
+
+/// A thread-safe allocator which, unlike `HypAlloc`, supports freeing. Small allocations are served
+/// from slabs of fixed-size objects, with per-CPU caches of free objects to avoid contending on a
+/// shared lock; allocations larger than half a page are made up of whole pages. The heap grows on
+/// demand by taking pages from a `SlabBackend`.
+///
+/// Pages carved into objects stay dedicated to their size class once freed, and freed runs of pages
+/// aren't coalesced, so the heap never shrinks and may fragment over time.
+pub struct SlabAlloc<B: SlabBackend> {
+    backend: B,
+    caches: [Mutex<CpuCache>; MAX_CACHED_CPUS],
+    shared: Mutex<SharedState>,
+    counters: AllocCounters,
+}
+
+impl<B: SlabBackend> SlabAlloc<B> {
+    /// Creates an empty allocator which takes pages from `backend` as it needs them.
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            caches: core::array::from_fn(|_| {
+                Mutex::new(CpuCache {
+                    classes: [FreeList::EMPTY; NUM_SIZE_CLASSES],
+                })
+            }),
+            shared: Mutex::new(SharedState {
+                classes: [FreeList::EMPTY; NUM_SIZE_CLASSES],
+                free_runs: None,
+            }),
+            counters: AllocCounters::default(),
+        }
+    }
+
+    /// Returns a snapshot of the allocation statistics.
+    pub fn stats(&self) -> AllocStats {
+        AllocStats {
+            live_allocs: self.counters.live_allocs.load(Ordering::Relaxed),
+            live_bytes: self.counters.live_bytes.load(Ordering::Relaxed),
+            total_allocs: self.counters.total_allocs.load(Ordering::Relaxed),
+            failed_allocs: self.counters.failed_allocs.load(Ordering::Relaxed),
+            heap_pages: self.counters.heap_pages.load(Ordering::Relaxed),
+        }
+    }
+
+    // Returns the index of the size class for `layout`, or `None` if it needs whole pages.
+    fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&s| s >= size)
+    }
+
+    // Returns the number of pages backing a large allocation with `layout`.
+    fn num_pages(layout: Layout) -> usize {
+        PageSize::num_4k_pages(layout.size() as u64) as usize
+    }
+
+    fn allocate_small(&self, class: usize) -> Result<NonNull<u8>, AllocError> {
+        let Some(cache) = self.caches.get(self.backend.cpu_index()) else {
+            let mut shared = self.shared.lock();
+            if shared.classes[class].len == 0 {
+                self.grow_class(&mut shared, class)?;
+            }
+            return shared.classes[class].pop().ok_or(AllocError);
+        };
+        let mut cache = cache.lock();
+        let list = &mut cache.classes[class];
+        if list.len == 0 {
+            let mut shared = self.shared.lock();
+            if shared.classes[class].len == 0 {
+                self.grow_class(&mut shared, class)?;
+            }
+            shared.classes[class].move_to(list, CACHE_BATCH);
+        }
+        list.pop().ok_or(AllocError)
+    }
+
+    // Safety: `ptr` must have been returned by `allocate_small()` for `class` and not freed since.
+    unsafe fn deallocate_small(&self, ptr: NonNull<u8>, class: usize) {
+        let Some(cache) = self.caches.get(self.backend.cpu_index()) else {
+            self.shared.lock().classes[class].push(ptr);
+            return;
+        };
+        let mut cache = cache.lock();
+        let list = &mut cache.classes[class];
+        list.push(ptr);
+        if list.len > CACHE_MAX {
+            list.move_to(&mut self.shared.lock().classes[class], CACHE_BATCH);
+        }
+    }
+
+    // Carves a new page into objects of size class `class`, adding them to the shared free list.
+    fn grow_class(&self, shared: &mut SharedState, class: usize) -> Result<(), AllocError> {
+        let page = self.take_run(shared, 1)?;
+        let size = SIZE_CLASSES[class];
+        for offset in (0..PAGE_SIZE).step_by(size) {
+            // Safety: The page is ours and `offset` is within it. Objects are at least as large and
+            // aligned as a `FreeObject` since the smallest size class is.
+            unsafe {
+                shared.classes[class].push(NonNull::new_unchecked(page.as_ptr().add(offset)));
+            }
+        }
+        Ok(())
+    }
+
+    // Takes a run of `num_pages` contiguous pages from the freed runs, or the backend if there's no
+    // run large enough.
+    fn take_run(
+        &self,
+        shared: &mut SharedState,
+        num_pages: usize,
+    ) -> Result<NonNull<u8>, AllocError> {
+        let mut prev: Option<NonNull<FreeRun>> = None;
+        let mut cur = shared.free_runs;
+        while let Some(mut run_ptr) = cur {
+            // Safety: Runs on the list are free and were initialized by `free_run()`.
+            let run = unsafe { run_ptr.as_mut() };
+            if run.num_pages == num_pages {
+                match prev {
+                    // Safety: As above.
+                    Some(mut p) => unsafe { p.as_mut().next = run.next },
+                    None => shared.free_runs = run.next,
+                }
+                return Ok(run_ptr.cast());
+            } else if run.num_pages > num_pages {
+                // Split off the tail of the run so that its head stays on the list.
+                run.num_pages -= num_pages;
+                let offset = run.num_pages * PAGE_SIZE;
+                // Safety: The tail is within the run.
+                return Ok(unsafe {
+                    NonNull::new_unchecked(run_ptr.cast::<u8>().as_ptr().add(offset))
+                });
+            }
+            prev = cur;
+            cur = run.next;
+        }
+
+        let pages = self.backend.take_pages(num_pages).ok_or(AllocError)?;
+        if pages.length_bytes() < (num_pages * PAGE_SIZE) as u64 {
+            return Err(AllocError);
+        }
+        self.counters
+            .heap_pages
+            .fetch_add(num_pages, Ordering::Relaxed);
+        NonNull::new(pages.base().bits() as *mut u8).ok_or(AllocError)
+    }
+
+    // Safety: `ptr` must point to `num_pages` pages returned by `take_run()` and no longer in use.
+    unsafe fn free_run(&self, ptr: NonNull<u8>, num_pages: usize) {
+        let mut shared = self.shared.lock();
+        let run = ptr.cast::<FreeRun>();
+        run.as_ptr().write(FreeRun {
+            next: shared.free_runs,
+            num_pages,
+        });
+        shared.free_runs = Some(run);
+    }
+}
+
+// Safety: SlabAlloc's free lists are only accessed under their locks, and it uniquely owns the
+// memory they point to.
+unsafe impl<B: SlabBackend> Sync for SlabAlloc<B> {}
+// Safety: As above.
+unsafe impl<B: SlabBackend> Send for SlabAlloc<B> {}
+
+unsafe impl<B: SlabBackend> Allocator for SlabAlloc<B> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let size = layout.size();
+        if size == 0 {
+            // SAFETY: align is always nonzero.
+            let aligned_ptr = unsafe { NonNull::new_unchecked(layout.align() as *mut u8) };
+            return Ok(NonNull::slice_from_raw_parts(aligned_ptr, 0));
+        }
+
+        let result = match Self::size_class(layout) {
+            Some(class) => self.allocate_small(class),
+            None if layout.align() <= PAGE_SIZE => {
+                self.take_run(&mut self.shared.lock(), Self::num_pages(layout))
+            }
+            None => Err(AllocError),
+        };
+        match result {
+            Ok(ptr) => {
+                self.counters.live_allocs.fetch_add(1, Ordering::Relaxed);
+                self.counters.live_bytes.fetch_add(size, Ordering::Relaxed);
+                self.counters.total_allocs.fetch_add(1, Ordering::Relaxed);
+                // SAFETY: The block at `ptr` is at least `size` bytes and is ours to hand out.
+                let block = unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), size) };
+                Ok(NonNull::from(block))
+            }
+            Err(e) => {
+                self.counters.failed_allocs.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        match Self::size_class(layout) {
+            Some(class) => self.deallocate_small(ptr, class),
+            None => self.free_run(ptr, Self::num_pages(layout)),
+        }
+        self.counters.live_allocs.fetch_sub(1, Ordering::Relaxed);
+        self.counters
+            .live_bytes
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use riscv_pages::{InternalDirty, PageAddr, RawAddr};
+
+    // Hands out pages from a leaked chunk of host memory, pretending to be on CPU `cpu`.
+    struct TestBackend {
+        next: Mutex<u64>,
+        end: u64,
+        cpu: AtomicUsize,
+    }
+
+    impl TestBackend {
+        fn new(num_pages: usize) -> Self {
+            let backing_mem = vec![0u8; (num_pages + 1) * PAGE_SIZE];
+            let start = unsafe {
+                // Not safe - just a test
+                backing_mem
+                    .as_ptr()
+                    .add(backing_mem.as_ptr().align_offset(PAGE_SIZE))
+            } as u64;
+            // Leak the backing ram so it doesn't get freed
+            std::mem::forget(backing_mem);
+            Self {
+                next: Mutex::new(start),
+                end: start + (num_pages * PAGE_SIZE) as u64,
+                cpu: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl SlabBackend for TestBackend {
+        fn take_pages(&self, count: usize) -> Option<SequentialPages<InternalClean>> {
+            let mut next = self.next.lock();
+            let base = *next;
+            if base + (count * PAGE_SIZE) as u64 > self.end {
+                return None;
+            }
+            *next += (count * PAGE_SIZE) as u64;
+            let start_page = PageAddr::new(RawAddr::supervisor(base)).unwrap();
+            let pages: SequentialPages<InternalDirty> = unsafe {
+                // Not safe - just a test
+                SequentialPages::from_mem_range(start_page, PageSize::Size4k, count as u64).unwrap()
+            };
+            Some(pages.clean())
+        }
+
+        fn cpu_index(&self) -> usize {
+            self.cpu.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let alloc = SlabAlloc::new(TestBackend::new(64));
+        {
+            let mut vec = Vec::new_in(&alloc);
+            for i in 0..1000u64 {
+                vec.push(i);
+            }
+            assert_eq!(vec.iter().sum::<u64>(), 999 * 1000 / 2);
+
+            let five = Box::new_in(5u8, &alloc);
+            assert_eq!(*five, 5);
+            let big = Box::new_in([7u64; 1024], &alloc);
+            assert!(big.iter().all(|&v| v == 7));
+        }
+        // Everything allocated above must have been freed.
+        let stats = alloc.stats();
+        assert_eq!(stats.live_allocs, 0);
+        assert_eq!(stats.live_bytes, 0);
+        assert!(stats.total_allocs > 0);
+    }
+
+    #[test]
+    fn reuse_freed_memory() {
+        let alloc = SlabAlloc::new(TestBackend::new(4));
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let first = alloc.allocate(layout).unwrap();
+        unsafe { alloc.deallocate(first.as_non_null_ptr(), layout) };
+        let second = alloc.allocate(layout).unwrap();
+        assert_eq!(first.as_mut_ptr(), second.as_mut_ptr());
+        unsafe { alloc.deallocate(second.as_non_null_ptr(), layout) };
+
+        // Freed pages are reused for large allocations of the same or smaller size.
+        let large = Layout::from_size_align(2 * PAGE_SIZE, 8).unwrap();
+        let pages = alloc.allocate(large).unwrap();
+        unsafe { alloc.deallocate(pages.as_non_null_ptr(), large) };
+        let heap_pages = alloc.stats().heap_pages;
+        let small_large = Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap();
+        let page = alloc.allocate(small_large).unwrap();
+        assert_eq!(page.as_mut_ptr() as usize % PAGE_SIZE, 0);
+        assert_eq!(alloc.stats().heap_pages, heap_pages);
+        unsafe { alloc.deallocate(page.as_non_null_ptr(), small_large) };
+        assert_eq!(alloc.stats().live_allocs, 0);
+    }
+
+    #[test]
+    fn alignment() {
+        let alloc = SlabAlloc::new(TestBackend::new(16));
+        for align in [1, 8, 64, 512, PAGE_SIZE] {
+            let layout = Layout::from_size_align(3, align).unwrap();
+            let ptr = alloc.allocate(layout).unwrap();
+            assert_eq!(ptr.as_mut_ptr() as usize % align, 0);
+            unsafe { alloc.deallocate(ptr.as_non_null_ptr(), layout) };
+        }
+        let layout = Layout::from_size_align(8, 2 * PAGE_SIZE).unwrap();
+        assert!(alloc.allocate(layout).is_err());
+        assert_eq!(alloc.stats().failed_allocs, 1);
+        assert_eq!(alloc.stats().live_allocs, 0);
+    }
+
+    #[test]
+    fn cross_cpu_free() {
+        let alloc = SlabAlloc::new(TestBackend::new(16));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let ptrs: Vec<_> = (0..100).map(|_| alloc.allocate(layout).unwrap()).collect();
+        // Free from another CPU, and from one without a cache.
+        alloc.backend.cpu.store(1, Ordering::Relaxed);
+        for p in &ptrs[..50] {
+            unsafe { alloc.deallocate(p.as_non_null_ptr(), layout) };
+        }
+        alloc.backend.cpu.store(MAX_CACHED_CPUS, Ordering::Relaxed);
+        for p in &ptrs[50..] {
+            unsafe { alloc.deallocate(p.as_non_null_ptr(), layout) };
+        }
+        assert_eq!(alloc.stats().live_allocs, 0);
+        // All objects can be allocated again without growing the heap.
+        let heap_pages = alloc.stats().heap_pages;
+        alloc.backend.cpu.store(0, Ordering::Relaxed);
+        let ptrs: Vec<_> = (0..100).map(|_| alloc.allocate(layout).unwrap()).collect();
+        assert_eq!(alloc.stats().heap_pages, heap_pages);
+        for p in ptrs {
+            unsafe { alloc.deallocate(p.as_non_null_ptr(), layout) };
+        }
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let alloc = SlabAlloc::new(TestBackend::new(2));
+        let layout = Layout::from_size_align(3 * PAGE_SIZE, 8).unwrap();
+        assert!(alloc.allocate(layout).is_err());
+        let layout = Layout::from_size_align(2 * PAGE_SIZE, 8).unwrap();
+        let ptr = alloc.allocate(layout).unwrap();
+        assert!(alloc.allocate(Layout::new::<u64>()).is_err());
+        unsafe { alloc.deallocate(ptr.as_non_null_ptr(), layout) };
+        assert_eq!(alloc.stats().failed_allocs, 2);
+        assert_eq!(alloc.stats().live_allocs, 0);
+    }
+}
@@ -95,6 +95,10 @@ pub enum HwReservedMemType {
     /// The host VM's initramfs image as loaded by firmware into (otherwise usable) memory. The
     /// hypervisor should take care not to overwrite these.
     HostInitramfsImage,
+
+    /// The region salus saves crash dumps to. Preserved across reboots so that a dump can be
+    /// retrieved after the system comes back up.
+    CrashDump,
 }
 
 /// Errors that can be raised while building the memory map.
@@ -383,6 +387,7 @@ impl fmt::Display for HwReservedMemType {
             HwReservedMemType::PageMap => write!(f, "page map"),
             HwReservedMemType::HostKernelImage => write!(f, "host kernel"),
             HwReservedMemType::HostInitramfsImage => write!(f, "host initramfs"),
+            HwReservedMemType::CrashDump => write!(f, "crash dump"),
         }
     }
 }
@@ -40,11 +40,11 @@ pub mod tlb_version;
 pub use hw_mem_map::Error as MemMapError;
 pub use hw_mem_map::Result as MemMapResult;
 pub use hw_mem_map::{HwMemMap, HwMemMapBuilder, HwMemRegion, HwMemRegionType, HwReservedMemType};
-pub use page_info::MAX_PAGE_OWNERS;
+pub use page_info::{PageStateCounts, MAX_PAGE_OWNERS};
 pub use page_list::{LockedPageList, PageList};
 pub use page_tracker::Error as PageTrackingError;
 pub use page_tracker::Result as PageTrackingResult;
-pub use page_tracker::{HypPageAlloc, PageTracker};
+pub use page_tracker::{HypPageAlloc, PageTracker, DEFAULT_HYP_RESERVE_PAGES, DEFAULT_MAX_GUESTS};
 pub use tlb_version::TlbVersion;
 
 #[cfg(test)]
@@ -61,6 +61,22 @@ pub enum PageState {
     BlockedShared(u64, TlbVersion),
 }
 
+/// The number of pages in each `PageState` in a `PageMap`, as reported by
+/// `PageMap::state_counts()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PageStateCounts {
+    pub reserved: usize,
+    pub free: usize,
+    pub mapped: usize,
+    pub shared: usize,
+    pub vm_state: usize,
+    pub hyp_state: usize,
+    /// Pages that are `Converting`, `Converted` or `ConvertedLocked`.
+    pub converting: usize,
+    /// Pages that are `Blocked` or `BlockedShared`.
+    pub blocked: usize,
+}
+
 /// The maximum length for an ownership chain. Enough for the host VM to assign to a guest VM
 /// without further nesting. An empty owners vector indicates that the page is hypervisor-owned.
 pub const MAX_PAGE_OWNERS: usize = 2;
@@ -604,6 +620,26 @@ impl PageMap {
         PageMapIter::new(self, addr)
     }
 
+    /// Returns the number of pages in the map in each state.
+    pub fn state_counts(&self) -> PageStateCounts {
+        let mut counts = PageStateCounts::default();
+        for page in self.pages.iter() {
+            use PageState::*;
+            let count = match page.state() {
+                Reserved => &mut counts.reserved,
+                Free => &mut counts.free,
+                Mapped => &mut counts.mapped,
+                Shared(_) => &mut counts.shared,
+                VmState => &mut counts.vm_state,
+                HypState => &mut counts.hyp_state,
+                Converting(_) | Converted | ConvertedLocked => &mut counts.converting,
+                Blocked(_) | BlockedShared(..) => &mut counts.blocked,
+            };
+            *count += 1;
+        }
+        counts
+    }
+
     /// Returns the index in the `PageMap` for the given address.
     fn get_map_index(&self, addr: SupervisorPageAddr) -> Option<usize> {
         self.sparse_map
@@ -788,6 +824,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn state_counts() {
+        let pages = stub_page_vec();
+        let mut mem_map = unsafe {
+            // Not safe - just a test.
+            HwMemMapBuilder::new(PageSize::Size4k as u64)
+                .add_memory_region(RawAddr::supervisor(0x1000_0000), 0x2_0000)
+                .unwrap()
+                .add_mmio_region(
+                    DeviceMemType::Imsic,
+                    RawAddr::supervisor(0x4000_0000),
+                    0x2000,
+                )
+                .unwrap()
+                .build()
+        };
+        mem_map
+            .reserve_region(
+                HwReservedMemType::FirmwareReserved,
+                RawAddr::supervisor(0x1000_4000),
+                0x1000,
+            )
+            .unwrap();
+        mem_map
+            .reserve_region(
+                HwReservedMemType::HostKernelImage,
+                RawAddr::supervisor(0x1001_0000),
+                0x2000,
+            )
+            .unwrap();
+        let mut pages = PageMap::new(pages);
+        pages.populate_from(&mem_map);
+
+        // The host kernel image and MMIO pages start out hypervisor-owned.
+        let counts = pages.state_counts();
+        assert_eq!(counts.free, 29);
+        assert_eq!(counts.reserved, 1);
+        assert_eq!(counts.converting, 4);
+        assert_eq!(counts.mapped + counts.blocked + counts.hyp_state, 0);
+
+        let hyp_addr = PageAddr::new(RawAddr::supervisor(0x1000_0000)).unwrap();
+        let page = pages.get_mut(hyp_addr).unwrap();
+        page.assign(PageOwnerId::hypervisor(), PageState::HypState)
+            .unwrap();
+        let mapped_addr = PageAddr::new(RawAddr::supervisor(0x1000_1000)).unwrap();
+        let page = pages.get_mut(mapped_addr).unwrap();
+        page.assign(PageOwnerId::hypervisor(), PageState::ConvertedLocked)
+            .unwrap();
+        page.assign(PageOwnerId::host(), PageState::Mapped).unwrap();
+        let blocked_addr = PageAddr::new(RawAddr::supervisor(0x1000_2000)).unwrap();
+        let page = pages.get_mut(blocked_addr).unwrap();
+        page.assign(PageOwnerId::hypervisor(), PageState::ConvertedLocked)
+            .unwrap();
+        page.assign(PageOwnerId::host(), PageState::Mapped).unwrap();
+        page.block(TlbVersion::new()).unwrap();
+
+        let counts = pages.state_counts();
+        assert_eq!(counts.free, 26);
+        assert_eq!(counts.hyp_state, 1);
+        assert_eq!(counts.mapped, 1);
+        assert_eq!(counts.blocked, 1);
+        assert_eq!(counts.reserved, 1);
+        assert_eq!(counts.converting, 4);
+    }
+
     #[test]
     fn page_ownership() {
         let mut page = PageInfo::new();
@@ -6,7 +6,7 @@ use riscv_pages::*;
 use sync::Mutex;
 
 use crate::collections::{RawPageVec, StaticPageRef};
-use crate::page_info::{PageInfo, PageMap, PageState};
+use crate::page_info::{PageInfo, PageMap, PageState, PageStateCounts};
 use crate::{hw_mem_map, HwMemMap, PageList, TlbVersion};
 
 /// Errors related to managing physical page information.
@@ -69,6 +69,13 @@ pub enum Error {
     PageMapNoSpace,
 }
 
+/// The default number of pages `PageTracker` sets aside for the hypervisor's use after boot, e.g. to
+/// grow its heap.
+pub const DEFAULT_HYP_RESERVE_PAGES: usize = 1024;
+
+/// The default number of guests `PageTracker` allows to be active at once, not counting the host.
+pub const DEFAULT_MAX_GUESTS: usize = 1024;
+
 /// Holds the result of page tracking operations.
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -76,7 +83,12 @@ pub type Result<T> = core::result::Result<T, Error>;
 struct PageTrackerInner {
     next_owner_id: u64,
     active_guests: RawPageVec<PageOwnerId>,
+    // The maximum number of guests, not counting the host, that may be active at once.
+    max_guests: usize,
     pages: PageMap,
+    // The pages set aside for the hypervisor that haven't been taken yet.
+    hyp_reserve_base: SupervisorPageAddr,
+    hyp_reserve_pages: u64,
 }
 
 impl PageTrackerInner {
@@ -100,12 +112,18 @@ pub struct PageTracker {
 impl PageTracker {
     /// Creates a new PageTracker representing all pages in the system and returns all pages that are
     /// available for the primary host to use, starting at the next `host_alignment`-aligned chunk.
+    /// Up to `max_guests` guests, in addition to the host, may be active at once. `hyp_reserve_pages`
+    /// pages are set aside for the hypervisor to take with `take_hyp_pages()` after boot.
     pub fn from(
         mut hyp_mem: HypPageAlloc,
         host_alignment: u64,
+        max_guests: usize,
+        hyp_reserve_pages: usize,
     ) -> (Self, PageList<Page<ConvertedClean>>) {
-        // TODO - hard coded to two pages worth of guests. - really dumb if page size is 1G
-        let mut active_guests = RawPageVec::from(hyp_mem.take_pages_for_host_state(2));
+        let active_guests_size = (max_guests + 1) * core::mem::size_of::<PageOwnerId>();
+        let active_guests_pages = PageSize::num_4k_pages(active_guests_size as u64);
+        let mut active_guests =
+            RawPageVec::from(hyp_mem.take_pages_for_host_state(active_guests_pages as usize));
         active_guests.push(PageOwnerId::host());
 
         let state_storage_page = hyp_mem
@@ -114,6 +132,8 @@ impl PageTracker {
             .next()
             .unwrap();
 
+        let hyp_reserve = hyp_mem.take_pages_for_hyp_state(hyp_reserve_pages);
+
         // Discard a host_alignment sized chunk to align ourselves.
         let _ = hyp_mem.take_pages(
             (host_alignment / PageSize::Size4k as u64)
@@ -129,7 +149,10 @@ impl PageTracker {
                 // Start at two for owners as host and hypervisor reserve 0 and 1.
                 next_owner_id: 2,
                 active_guests,
+                max_guests,
                 pages: page_map,
+                hyp_reserve_base: hyp_reserve.base(),
+                hyp_reserve_pages: hyp_reserve.len(),
             }),
             state_storage_page,
         );
@@ -168,7 +191,12 @@ impl PageTracker {
                 .build()
         };
         let hyp_mem = HypPageAlloc::new(&mut hw_map).unwrap();
-        let (page_tracker, host_pages) = PageTracker::from(hyp_mem, PageSize::Size4k as u64);
+        let (page_tracker, host_pages) = PageTracker::from(
+            hyp_mem,
+            PageSize::Size4k as u64,
+            DEFAULT_MAX_GUESTS,
+            DEFAULT_HYP_RESERVE_PAGES,
+        );
         // Leak the backing ram so it doesn't get freed
         std::mem::forget(backing_mem);
         (page_tracker, host_pages)
@@ -177,6 +205,10 @@ impl PageTracker {
     /// Adds a new guest to the system, giving it the next ID.
     pub fn add_active_guest(&self) -> Result<PageOwnerId> {
         let mut page_tracker = self.inner.lock();
+        // The host is always in the list of active guests.
+        if page_tracker.active_guests.len() > page_tracker.max_guests {
+            return Err(Error::GuestOverflow);
+        }
         // unwrap is fine as next_owner_id is guaranteed to be valid.
         let id = PageOwnerId::new(page_tracker.next_owner_id).unwrap();
         // TODO handle very rare roll over cleaner.
@@ -193,6 +225,20 @@ impl PageTracker {
         Ok(id)
     }
 
+    /// Takes `count` contiguous pages from those set aside for the hypervisor's use after boot.
+    /// Returns `None` if there aren't enough left.
+    pub fn take_hyp_pages(&self, count: usize) -> Option<SequentialPages<InternalClean>> {
+        let mut inner = self.inner.lock();
+        let count = count as u64;
+        let remaining = inner.hyp_reserve_pages.checked_sub(count)?;
+        let base = inner.hyp_reserve_base;
+        inner.hyp_reserve_base = base.checked_add_pages(count)?;
+        inner.hyp_reserve_pages = remaining;
+        // Safe since the reserved pages were assigned to the hypervisor and cleaned in `from()`,
+        // and we've just given up ownership of this range.
+        unsafe { SequentialPages::from_mem_range(base, PageSize::Size4k, count) }.ok()
+    }
+
     /// Removes an active guest previously added by `add_active_guest`.
     pub fn rm_active_guest(&self, remove_id: PageOwnerId) {
         let mut page_tracker = self.inner.lock();
@@ -396,6 +442,13 @@ impl PageTracker {
         self.for_each_page(page.addr(), page.size(), |info| info.unlock())
     }
 
+    /// Returns the number of pages in each state, or `None` if the page map is currently locked.
+    /// Meant for diagnostics that may run while the lock is held, so it doesn't wait for it.
+    pub fn try_page_state_counts(&self) -> Option<PageStateCounts> {
+        let inner = self.inner.try_lock()?;
+        Some(inner.pages.state_counts())
+    }
+
     /// Returns true if and only if `addr` is a page owned by `owner`.
     pub fn is_owned(
         &self,
@@ -661,20 +714,28 @@ impl HypPageAlloc {
         }
     }
 
-    // Core allocator function. Finds `count` contiguous pages with the requested alignment from the
-    // system map. Sets the hypervisor as the owner and `page_state` as the state, for the returned
-    // pages and for any pages consumed for alignment purposes.
-    // Returns a pair of supervisor addresses containing the first and the last page address of the
-    // allocated range.
-    fn alloc_pages(
-        &mut self,
-        count: usize,
-        align: u64,
-        page_state: PageState,
-    ) -> SupervisorPageRange {
+    /// Returns the number of pages that are free to be taken. Unlike `pages_remaining()`, this
+    /// doesn't include reserved pages.
+    pub fn free_pages(&self) -> u64 {
+        self.next_page
+            .and_then(|addr| self.pages.iter_from(addr))
+            .map_or(0, |i| i.filter(|p| p.page.is_free()).count() as u64)
+    }
+
+    /// Returns true if `count` contiguous pages with the requested alignment can be taken, i.e. if
+    /// taking them won't panic.
+    pub fn can_take_pages(&self, count: usize, align: u64) -> bool {
+        self.find_free_range(count, align).is_some()
+    }
+
+    // Returns the address of the first page of the first free range of `count` pages aligned to
+    // `align`, if there is one.
+    fn find_free_range(&self, count: usize, align: u64) -> Option<SupervisorPageAddr> {
         // Helper to test whether a contiguous range of `count` pages is free and aligned.
         let range_is_free_and_aligned = |start: SupervisorPageAddr| {
-            let end = start.checked_add_pages(count as u64).unwrap();
+            let Some(end) = start.checked_add_pages(count as u64) else {
+                return false;
+            };
             if start.bits() & (align - 1) != 0 {
                 return false;
             }
@@ -683,16 +744,27 @@ impl HypPageAlloc {
                 .take_while(|&a| a != end)
                 .all(|a| self.pages.get(a).map_or(false, |p| p.is_free()))
         };
+        self.pages
+            .iter_from(self.next_page?)
+            .and_then(|mut i| i.find(|p| range_is_free_and_aligned(p.addr)))
+            .map(|p| p.addr)
+    }
 
+    // Core allocator function. Finds `count` contiguous pages with the requested alignment from the
+    // system map. Sets the hypervisor as the owner and `page_state` as the state, for the returned
+    // pages and for any pages consumed for alignment purposes.
+    // Returns a pair of supervisor addresses containing the first and the last page address of the
+    // allocated range.
+    fn alloc_pages(
+        &mut self,
+        count: usize,
+        align: u64,
+        page_state: PageState,
+    ) -> SupervisorPageRange {
         // Find the free page rage and mark it, and any free pages we skipped in between,
         // as hypervisor-owned.
         let start_page = self.next_page.unwrap();
-        let first_page = self
-            .pages
-            .iter_from(start_page)
-            .and_then(|mut i| i.find(|p| range_is_free_and_aligned(p.addr)))
-            .map(|p| p.addr)
-            .unwrap();
+        let first_page = self.find_free_range(count, align).unwrap();
         let last_page = first_page.checked_add_pages(count as u64).unwrap();
         for page in start_page.iter_from().take_while(|&a| a != last_page) {
             if let Some(page_info) = self.pages.get_mut(page) {
@@ -814,7 +886,12 @@ mod tests {
 
     fn stub_page_tracker() -> (PageTracker, PageList<Page<ConvertedClean>>) {
         let hyp_mem = stub_hyp_mem();
-        PageTracker::from(hyp_mem, PageSize::Size4k as u64)
+        PageTracker::from(
+            hyp_mem,
+            PageSize::Size4k as u64,
+            DEFAULT_MAX_GUESTS,
+            DEFAULT_HYP_RESERVE_PAGES,
+        )
     }
 
     #[test]
@@ -853,11 +930,29 @@ mod tests {
         assert_eq!(range.base().bits() & (16 * 1024 - 1), 0);
     }
 
+    #[test]
+    fn hyp_mem_free_pages() {
+        let mut hyp_mem = stub_hyp_mem();
+        let free = hyp_mem.free_pages();
+        assert!(free > 0 && free <= hyp_mem.pages_remaining());
+        assert!(hyp_mem.can_take_pages(free as usize, PageSize::Size4k as u64));
+        assert!(!hyp_mem.can_take_pages(free as usize + 1, PageSize::Size4k as u64));
+
+        hyp_mem.take_pages(4, PageSize::Size4k as u64);
+        assert_eq!(hyp_mem.free_pages(), free - 4);
+        assert!(!hyp_mem.can_take_pages(free as usize - 3, PageSize::Size4k as u64));
+    }
+
     #[test]
     fn hyp_mem_drain() {
         let hyp_mem = stub_hyp_mem();
         let remaining = hyp_mem.pages_remaining();
-        let (_, host_pages) = PageTracker::from(hyp_mem, PageSize::Size4k as u64);
+        let (_, host_pages) = PageTracker::from(
+            hyp_mem,
+            PageSize::Size4k as u64,
+            DEFAULT_MAX_GUESTS,
+            DEFAULT_HYP_RESERVE_PAGES,
+        );
         assert!(host_pages.len() > 0);
         assert!((host_pages.len() as u64) < remaining);
     }
@@ -875,4 +970,34 @@ mod tests {
 
         assert_eq!(page_tracker.inner.lock().active_guests.len(), 1);
     }
+
+    #[test]
+    fn max_active_guests() {
+        let (page_tracker, _host_mem) = stub_page_tracker();
+        for _ in 0..DEFAULT_MAX_GUESTS {
+            page_tracker.add_active_guest().unwrap();
+        }
+        assert!(matches!(
+            page_tracker.add_active_guest(),
+            Err(Error::GuestOverflow)
+        ));
+    }
+
+    #[test]
+    fn take_hyp_pages() {
+        let (page_tracker, _host_mem) = stub_page_tracker();
+        let first = page_tracker.take_hyp_pages(2).unwrap();
+        let second = page_tracker.take_hyp_pages(1).unwrap();
+        assert_eq!(
+            second.base().bits(),
+            first.base().bits() + 2 * PageSize::Size4k as u64
+        );
+        assert!(page_tracker
+            .take_hyp_pages(DEFAULT_HYP_RESERVE_PAGES)
+            .is_none());
+        assert!(page_tracker
+            .take_hyp_pages(DEFAULT_HYP_RESERVE_PAGES - 3)
+            .is_some());
+        assert!(page_tracker.take_hyp_pages(1).is_none());
+    }
 }
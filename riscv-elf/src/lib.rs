@@ -12,9 +12,10 @@
 extern crate std;
 
 use arrayvec::ArrayVec;
+use core::ops::Range;
 use core::{fmt, result};
 
-// Maximum size of Program Headers supported by the loader.
+// Maximum number of loadable segments supported by the loader.
 const ELF_SEGMENTS_MAX: usize = 8;
 
 /// Elf Offset Helper
@@ -70,6 +71,15 @@ fn slice_get_range(bytes: &[u8], offset: ElfOffset64, len: usize) -> Option<&[u8
     }
 }
 
+// Reads a `T` at `offset` in `bytes`. `T` must be one of the plain ELF structures below, valid for
+// any bit pattern.
+fn slice_read<T: Copy>(bytes: &[u8], offset: ElfOffset64) -> Option<T> {
+    let tbytes = slice_get_range(bytes, offset, core::mem::size_of::<T>())?;
+    // Safe because the slice is exactly the size of `T`, `read_unaligned()` has no alignment
+    // requirement and `T` is valid for any bit pattern.
+    Some(unsafe { core::ptr::read_unaligned(tbytes.as_ptr() as *const T) })
+}
+
 /// ELF64 Program Header Table Entry
 #[repr(packed, C)]
 #[derive(Copy, Clone)]
@@ -87,6 +97,12 @@ pub struct ElfProgramHeader64 {
 // ELF Segment Types
 // The array element specifies a loadable segment
 const PT_LOAD: u32 = 1;
+// The array element specifies dynamic linking information
+const PT_DYNAMIC: u32 = 2;
+// The array element specifies the Thread-Local Storage template
+const PT_TLS: u32 = 7;
+// The array element specifies the permissions of the stack
+const PT_GNU_STACK: u32 = 0x6474_e551;
 
 // Elf Segment Permission
 // Execute
@@ -127,10 +143,90 @@ const EI_CLASS_64: u8 = 2;
 const EI_DATA_LE: u8 = 1;
 const EI_VERSION_1: u8 = 1;
 const E_TYPE_EXEC: u16 = 2;
+const E_TYPE_DYN: u16 = 3;
 const E_MACHINE_RISCV: u16 = 0xf3;
 const E_VERSION_1: u32 = 1;
 const E_EHSIZE: u16 = 0x40;
 
+/// ELF64 Dynamic Section Entry
+#[repr(packed, C)]
+#[derive(Copy, Clone)]
+struct ElfDyn64 {
+    d_tag: i64,
+    d_val: u64,
+}
+
+// Dynamic Section Tags
+// Marks the end of the dynamic section
+const DT_NULL: i64 = 0;
+// Address of the symbol hash table
+const DT_HASH: i64 = 4;
+// Address of the string table
+const DT_STRTAB: i64 = 5;
+// Address of the symbol table
+const DT_SYMTAB: i64 = 6;
+// Address of the relocation table
+const DT_RELA: i64 = 7;
+// Size in bytes of the relocation table
+const DT_RELASZ: i64 = 8;
+// Size in bytes of a relocation table entry
+const DT_RELAENT: i64 = 9;
+// Size in bytes of the string table
+const DT_STRSZ: i64 = 10;
+// Size in bytes of a symbol table entry
+const DT_SYMENT: i64 = 11;
+
+/// ELF64 Relocation Entry with Addend
+#[repr(packed, C)]
+#[derive(Copy, Clone)]
+struct ElfRela64 {
+    r_offset: u64,
+    r_info: u64,
+    r_addend: i64,
+}
+
+impl ElfRela64 {
+    fn sym(&self) -> u32 {
+        (self.r_info >> 32) as u32
+    }
+
+    fn rtype(&self) -> u32 {
+        self.r_info as u32
+    }
+}
+
+// RISC-V Relocation Types
+// No relocation
+const R_RISCV_NONE: u32 = 0;
+// S + A
+const R_RISCV_64: u32 = 2;
+// B + A
+const R_RISCV_RELATIVE: u32 = 3;
+
+/// ELF64 Symbol Table Entry
+#[repr(packed, C)]
+#[derive(Copy, Clone)]
+struct ElfSym64 {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+// Symbol Binding
+// Weak symbols resolve to zero if undefined
+const STB_WEAK: u8 = 2;
+// Symbol Types
+// Thread-local symbol, its value is an offset in the TLS block
+const STT_TLS: u8 = 6;
+// Section Indices
+// Undefined symbol
+const SHN_UNDEF: u16 = 0;
+// Absolute symbol, not affected by relocation
+const SHN_ABS: u16 = 0xfff1;
+
 /// ELF Loader Errors.
 #[derive(Debug)]
 pub enum Error {
@@ -158,6 +254,20 @@ pub enum Error {
     ProgramHeaderMalformed,
     /// Segment Permissions Unsupported
     UnsupportedProgramHeaderFlags(u32),
+    /// More loadable segments than the loader supports.
+    TooManySegments,
+    /// The stack is required to be executable.
+    ExecutableStack,
+    /// Malformed TLS segment.
+    TlsMalformed,
+    /// Malformed or inconsistent dynamic section.
+    DynamicMalformed,
+    /// Relocation type not supported by the loader.
+    UnsupportedRelocation(u32),
+    /// Relocation patches memory outside the data of the loadable segments.
+    RelocationOutOfRange(u64),
+    /// Relocation refers to an undefined symbol.
+    UndefinedSymbol,
 }
 
 #[derive(Debug)]
@@ -169,6 +279,9 @@ pub enum ElfSegmentPerms {
     ReadWrite,
     /// Executable Page (Read Only)
     ReadOnlyExecute,
+    /// Writable and Executable Page, as found in e.g. Linux kernels. It's up to the loader to
+    /// decide whether to map these.
+    ReadWriteExecute,
 }
 
 impl fmt::Display for ElfSegmentPerms {
@@ -177,6 +290,7 @@ impl fmt::Display for ElfSegmentPerms {
             Self::ReadOnly => write!(f, "RO"),
             Self::ReadWrite => write!(f, "RW"),
             Self::ReadOnlyExecute => write!(f, "RX"),
+            Self::ReadWriteExecute => write!(f, "RWX"),
         }
     }
 }
@@ -203,6 +317,8 @@ impl<'elf> ElfSegment<'elf> {
             Ok(ElfSegmentPerms::ReadWrite)
         } else if flags == PF_R | PF_X {
             Ok(ElfSegmentPerms::ReadOnlyExecute)
+        } else if flags == PF_R | PF_W | PF_X {
+            Ok(ElfSegmentPerms::ReadWriteExecute)
         } else {
             Err(Error::UnsupportedProgramHeaderFlags(flags))
         }?;
@@ -239,10 +355,208 @@ impl<'elf> ElfSegment<'elf> {
     }
 }
 
+/// A structure representing the Thread-Local Storage template of an ELF file.
+#[derive(Debug)]
+pub struct ElfTls<'elf> {
+    data: Option<&'elf [u8]>,
+    vaddr: u64,
+    size: usize,
+    align: u64,
+}
+
+impl<'elf> ElfTls<'elf> {
+    /// Returns the initialized data at the beginning of each thread's TLS block.
+    pub fn data(&self) -> Option<&'elf [u8]> {
+        self.data
+    }
+
+    /// Returns the link-time Virtual Address of the template.
+    pub fn vaddr(&self) -> u64 {
+        self.vaddr
+    }
+
+    /// Returns the size of each thread's TLS block, including the zero-initialized part.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the required alignment of each thread's TLS block.
+    pub fn align(&self) -> u64 {
+        self.align
+    }
+}
+
+/// A defined symbol from the dynamic symbol table of an ELF file.
+#[derive(Clone, Copy, Debug)]
+pub struct ElfSymbol<'elf> {
+    name: &'elf str,
+    value: u64,
+    size: u64,
+    absolute: bool,
+}
+
+impl<'elf> ElfSymbol<'elf> {
+    /// Returns the name of the symbol.
+    pub fn name(&self) -> &'elf str {
+        self.name
+    }
+
+    /// Returns the link-time address of the symbol, or its value if it is absolute.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// Returns the size of the object the symbol refers to.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns true if the value of the symbol doesn't move with the load address.
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+}
+
+// The dynamic relocations and symbols of an ELF file.
+struct ElfDynamic<'elf> {
+    rela: &'elf [u8],
+    symtab: &'elf [u8],
+    strtab: &'elf [u8],
+    // Number of symbols in `symtab`, zero if there's no hash table to tell.
+    nsyms: usize,
+}
+
+impl<'elf> ElfDynamic<'elf> {
+    fn symbol(&self, index: u32) -> Option<ElfSym64> {
+        let offset = (index as usize).checked_mul(core::mem::size_of::<ElfSym64>())?;
+        slice_read(self.symtab, offset.into())
+    }
+
+    fn symbol_name(&self, sym: &ElfSym64) -> Option<&'elf str> {
+        let bytes = self.strtab.get(sym.st_name as usize..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    fn relocations(&self) -> impl Iterator<Item = ElfRela64> + '_ {
+        self.rela
+            .chunks_exact(core::mem::size_of::<ElfRela64>())
+            .filter_map(|b| slice_read(b, 0.into()))
+    }
+}
+
+// Returns the data of the loadable segment containing `vaddr`, from `vaddr` to the end of the
+// segment's data.
+fn segment_data_from<'elf>(segments: &[ElfSegment<'elf>], vaddr: u64) -> Option<&'elf [u8]> {
+    segments.iter().find_map(|s| {
+        let offset = vaddr.checked_sub(s.vaddr)? as usize;
+        let data = s.data?;
+        if offset < data.len() {
+            Some(&data[offset..])
+        } else {
+            None
+        }
+    })
+}
+
+// Returns `len` bytes of the data of the loadable segment containing `vaddr`.
+fn segment_data<'elf>(segments: &[ElfSegment<'elf>], vaddr: u64, len: u64) -> Option<&'elf [u8]> {
+    segment_data_from(segments, vaddr)?.get(..len as usize)
+}
+
+// Parses the dynamic section at `offset` in `bytes`, resolving the tables it points to in the
+// loadable `segments`.
+fn parse_dynamic<'elf>(
+    bytes: &'elf [u8],
+    offset: ElfOffset64,
+    size: usize,
+    segments: &[ElfSegment<'elf>],
+) -> Result<ElfDynamic<'elf>, Error> {
+    let mut rela = None;
+    let mut relasz = 0;
+    let mut relaent = core::mem::size_of::<ElfRela64>() as u64;
+    let mut symtab = None;
+    let mut syment = core::mem::size_of::<ElfSym64>() as u64;
+    let mut strtab = None;
+    let mut strsz = 0;
+    let mut hash = None;
+    let dynsize = core::mem::size_of::<ElfDyn64>();
+    for i in 0..size / dynsize {
+        let entry_offset = offset.usize_add(i * dynsize).ok_or(Error::BadOffset)?;
+        let dyn64: ElfDyn64 = slice_read(bytes, entry_offset).ok_or(Error::BadOffset)?;
+        match dyn64.d_tag {
+            DT_NULL => break,
+            DT_HASH => hash = Some(dyn64.d_val),
+            DT_STRTAB => strtab = Some(dyn64.d_val),
+            DT_SYMTAB => symtab = Some(dyn64.d_val),
+            DT_RELA => rela = Some(dyn64.d_val),
+            DT_RELASZ => relasz = dyn64.d_val,
+            DT_RELAENT => relaent = dyn64.d_val,
+            DT_STRSZ => strsz = dyn64.d_val,
+            DT_SYMENT => syment = dyn64.d_val,
+            _ => (),
+        }
+    }
+
+    // Relocations.
+    if relaent != core::mem::size_of::<ElfRela64>() as u64 {
+        return Err(Error::BadEntrySize);
+    }
+    if relasz % relaent != 0 {
+        return Err(Error::DynamicMalformed);
+    }
+    let rela = match rela {
+        Some(vaddr) if relasz > 0 => {
+            segment_data(segments, vaddr, relasz).ok_or(Error::DynamicMalformed)?
+        }
+        _ => &[],
+    };
+
+    // Symbols.
+    if syment != core::mem::size_of::<ElfSym64>() as u64 {
+        return Err(Error::BadEntrySize);
+    }
+    let (symtab, strtab, nsyms) = match symtab {
+        Some(vaddr) => {
+            // The second word of the hash table is the number of entries in the symbol table.
+            let nsyms = match hash {
+                Some(hash) => {
+                    let nchain =
+                        segment_data(segments, hash + 4, 4).ok_or(Error::DynamicMalformed)?;
+                    // Unwrap okay: `nchain` is 4 bytes long.
+                    u32::from_le_bytes(nchain.try_into().unwrap()) as usize
+                }
+                None => 0,
+            };
+            let symtab = if nsyms > 0 {
+                segment_data(segments, vaddr, (nsyms as u64) * syment)
+            } else {
+                segment_data_from(segments, vaddr)
+            }
+            .ok_or(Error::DynamicMalformed)?;
+            let strtab = strtab
+                .and_then(|vaddr| segment_data(segments, vaddr, strsz))
+                .ok_or(Error::DynamicMalformed)?;
+            (symtab, strtab, nsyms)
+        }
+        None => (&[][..], &[][..], 0),
+    };
+
+    Ok(ElfDynamic {
+        rela,
+        symtab,
+        strtab,
+        nsyms,
+    })
+}
+
 /// A structure that checks and prepares and ELF for loading into memory.
 pub struct ElfMap<'elf> {
     entry: u64,
+    pie: bool,
     segments: ArrayVec<ElfSegment<'elf>, ELF_SEGMENTS_MAX>,
+    tls: Option<ElfTls<'elf>>,
+    dynamic: Option<ElfDynamic<'elf>>,
 }
 
 impl<'elf> ElfMap<'elf> {
@@ -269,7 +583,8 @@ impl<'elf> ElfMap<'elf> {
         if header.ei_version != EI_VERSION_1 || header.e_version != E_VERSION_1 {
             return Err(Error::BadElfVersion);
         }
-        if header.e_type != E_TYPE_EXEC {
+        // Check it's an executable, either at a fixed address or position-independent.
+        if header.e_type != E_TYPE_EXEC && header.e_type != E_TYPE_DYN {
             return Err(Error::BadElfType);
         }
         // Check is RISC-V.
@@ -298,8 +613,9 @@ impl<'elf> ElfMap<'elf> {
 
         // Load segments
         let mut segments = ArrayVec::<ElfSegment, ELF_SEGMENTS_MAX>::new();
-        let num_segs = core::cmp::min(phnum, ELF_SEGMENTS_MAX);
-        for i in 0..num_segs {
+        let mut tls = None;
+        let mut dynamic_ph = None;
+        for i in 0..phnum {
             // Find the i-th ELF Program Header.
             let phbytes = slice_get_range(program_headers, (i * phentsize).into(), phentsize)
                 .ok_or(Error::BadOffset)?;
@@ -307,40 +623,183 @@ impl<'elf> ElfMap<'elf> {
             let ph: &'elf ElfProgramHeader64 =
                 unsafe { &*(phbytes.as_ptr() as *const ElfProgramHeader64) };
 
-            // Ignore if not a load segment.
-            if ph.p_type != PT_LOAD {
-                continue;
+            match ph.p_type {
+                PT_LOAD if ph.p_memsz != 0 => {
+                    // Create a segment from the PH.
+                    let data_size = ph.p_filesz as usize;
+                    let data = if data_size > 0 {
+                        Some(
+                            slice_get_range(bytes, ph.p_offset, data_size)
+                                .ok_or(Error::BadOffset)?,
+                        )
+                    } else {
+                        None
+                    };
+                    let vaddr = ph.p_vaddr;
+                    let size = ph.p_memsz as usize;
+                    let flags = ph.p_flags;
+                    let segment = ElfSegment::new(data, vaddr, size, flags)?;
+                    segments
+                        .try_push(segment)
+                        .map_err(|_| Error::TooManySegments)?;
+                }
+                PT_TLS => {
+                    if ph.p_filesz > ph.p_memsz
+                        || (ph.p_align != 0 && !ph.p_align.is_power_of_two())
+                    {
+                        return Err(Error::TlsMalformed);
+                    }
+                    let data_size = ph.p_filesz as usize;
+                    let data = if data_size > 0 {
+                        Some(
+                            slice_get_range(bytes, ph.p_offset, data_size)
+                                .ok_or(Error::BadOffset)?,
+                        )
+                    } else {
+                        None
+                    };
+                    tls = Some(ElfTls {
+                        data,
+                        vaddr: ph.p_vaddr,
+                        size: ph.p_memsz as usize,
+                        align: core::cmp::max(ph.p_align, 1),
+                    });
+                }
+                PT_DYNAMIC => dynamic_ph = Some((ph.p_offset, ph.p_filesz as usize)),
+                PT_GNU_STACK if ph.p_flags & PF_X != 0 => return Err(Error::ExecutableStack),
+                // Ignore anything else.
+                _ => (),
             }
-            if ph.p_memsz != 0 {
-                // Create a segment from the PH.
-                let data_size = ph.p_filesz as usize;
-                let data = if data_size > 0 {
-                    Some(slice_get_range(bytes, ph.p_offset, data_size).ok_or(Error::BadOffset)?)
-                } else {
-                    None
-                };
-                let vaddr = ph.p_vaddr;
-                let size = ph.p_memsz as usize;
-                let flags = ph.p_flags;
-                let segment = ElfSegment::new(data, vaddr, size, flags)?;
-                segments.push(segment);
+        }
+
+        let dynamic = dynamic_ph
+            .map(|(offset, size)| parse_dynamic(bytes, offset, size, &segments))
+            .transpose()?;
+        // Check that relocations can be applied, so that `relocate()` can't fail.
+        if let Some(dynamic) = &dynamic {
+            for rela in dynamic.relocations() {
+                match rela.rtype() {
+                    R_RISCV_NONE => continue,
+                    R_RISCV_RELATIVE => (),
+                    R_RISCV_64 => {
+                        let sym = dynamic.symbol(rela.sym()).ok_or(Error::DynamicMalformed)?;
+                        if sym.st_shndx == SHN_UNDEF && sym.st_info >> 4 != STB_WEAK {
+                            return Err(Error::UndefinedSymbol);
+                        }
+                    }
+                    rtype => return Err(Error::UnsupportedRelocation(rtype)),
+                }
+                if segment_data(&segments, rela.r_offset, 8).is_none() {
+                    return Err(Error::RelocationOutOfRange(rela.r_offset));
+                }
             }
         }
+
         Ok(Self {
             entry: header.e_entry,
+            pie: header.e_type == E_TYPE_DYN,
             segments,
+            tls,
+            dynamic,
         })
     }
 
-    /// Return the entry of this executable.
+    /// Return the entry of this executable, at its link-time address.
     pub fn entry(&self) -> u64 {
         self.entry
     }
 
+    /// Returns true if this is a position-independent executable that can be loaded at any
+    /// page-aligned offset from its link-time addresses. Other executables must be loaded at their
+    /// link-time addresses.
+    pub fn is_pie(&self) -> bool {
+        self.pie
+    }
+
     /// Return an iterator containings loadable segments of this ELF file.
     pub fn segments(&self) -> impl Iterator<Item = &ElfSegment<'elf>> {
         self.segments.iter()
     }
+
+    /// Returns the range of link-time addresses covered by the loadable segments.
+    pub fn vaddr_range(&self) -> Range<u64> {
+        let start = self.segments.iter().map(|s| s.vaddr).min().unwrap_or(0);
+        let end = self
+            .segments
+            .iter()
+            .map(|s| s.vaddr + s.size as u64)
+            .max()
+            .unwrap_or(0);
+        start..end
+    }
+
+    /// Returns the Thread-Local Storage template, if this ELF file has one.
+    pub fn tls(&self) -> Option<&ElfTls<'elf>> {
+        self.tls.as_ref()
+    }
+
+    /// Applies the dynamic relocations of this ELF file for a load `load_bias` bytes above its
+    /// link-time addresses. `dest` holds a copy of the data to be loaded at link-time address
+    /// `vaddr`; relocations that patch bytes outside `dest` are skipped, so segments can be
+    /// relocated one at a time.
+    pub fn relocate(&self, load_bias: u64, vaddr: u64, dest: &mut [u8]) {
+        let Some(dynamic) = &self.dynamic else {
+            return;
+        };
+        let dest_end = vaddr.saturating_add(dest.len() as u64);
+        for rela in dynamic.relocations() {
+            let value = match rela.rtype() {
+                R_RISCV_RELATIVE => load_bias.wrapping_add(rela.r_addend as u64),
+                R_RISCV_64 => {
+                    // Unwrap okay: symbols of relocations are checked in `new()`.
+                    let sym = dynamic.symbol(rela.sym()).unwrap();
+                    let sym_value = match sym.st_shndx {
+                        SHN_UNDEF => 0,
+                        SHN_ABS => sym.st_value,
+                        _ => sym.st_value.wrapping_add(load_bias),
+                    };
+                    sym_value.wrapping_add(rela.r_addend as u64)
+                }
+                _ => continue,
+            };
+            // Copy the part of the patched value that falls within `dest`. Can't overflow, the
+            // location was checked to be within a segment in `new()`.
+            let start = core::cmp::max(rela.r_offset, vaddr);
+            let end = core::cmp::min(rela.r_offset + 8, dest_end);
+            if start < end {
+                let value = value.to_le_bytes();
+                dest[(start - vaddr) as usize..(end - vaddr) as usize].copy_from_slice(
+                    &value[(start - rela.r_offset) as usize..(end - rela.r_offset) as usize],
+                );
+            }
+        }
+    }
+
+    /// Returns an iterator over the defined symbols in the dynamic symbol table, except for
+    /// thread-local ones. Symbols are only listed if the ELF file has a hash table giving their
+    /// count.
+    pub fn dynamic_symbols(&self) -> impl Iterator<Item = ElfSymbol<'elf>> + '_ {
+        let nsyms = self.dynamic.as_ref().map(|d| d.nsyms).unwrap_or(0);
+        // Entry 0 is always the undefined symbol.
+        (1..nsyms as u32).filter_map(|i| {
+            let dynamic = self.dynamic.as_ref()?;
+            let sym = dynamic.symbol(i)?;
+            if sym.st_shndx == SHN_UNDEF || sym.st_info & 0xf == STT_TLS {
+                return None;
+            }
+            Some(ElfSymbol {
+                name: dynamic.symbol_name(&sym)?,
+                value: sym.st_value,
+                size: sym.st_size,
+                absolute: sym.st_shndx == SHN_ABS,
+            })
+        })
+    }
+
+    /// Returns the defined dynamic symbol called `name`.
+    pub fn find_symbol(&self, name: &str) -> Option<ElfSymbol<'elf>> {
+        self.dynamic_symbols().find(|s| s.name == name)
+    }
 }
 
 #[cfg(test)]
@@ -556,6 +1015,224 @@ mod tests {
         }
     }
 
+    fn set_struct<T: Copy>(bytes: &mut [u8], off: usize, val: &T) {
+        assert!(off + core::mem::size_of::<T>() <= bytes.len());
+        // Safe because we can fit a `T` at offset `off` in `bytes`.
+        unsafe { core::ptr::write_unaligned(bytes[off..].as_mut_ptr() as *mut T, *val) };
+    }
+
+    // Offsets (and link-time addresses) of the parts of the PIE built by `build_pie()`.
+    const PIE_DYNAMIC: usize = 0x200;
+    const PIE_RELA: usize = 0x300;
+    const PIE_SYMTAB: usize = 0x380;
+    const PIE_STRTAB: usize = 0x400;
+    const PIE_HASH: usize = 0x420;
+    const PIE_SYM_FOO: u64 = 0x480;
+    const PIE_RELOC_TARGETS: usize = 0x500;
+    const PIE_TLS: usize = 0x510;
+    const PIE_SIZE: usize = 0x600;
+
+    // Builds a position-independent ELF with one RW segment covering the whole file, mapped at
+    // address 0, and with the given relocations.
+    fn build_pie(relas: &[ElfRela64], stack_flags: u32) -> std::vec::Vec<u8> {
+        const HEADER_SIZE: usize = core::mem::size_of::<ElfHeader64>();
+        const PH_SIZE: usize = core::mem::size_of::<ElfProgramHeader64>();
+        let mut bytes = vec![0u8; PIE_SIZE];
+
+        let mut header = build_header();
+        header.e_type = E_TYPE_DYN;
+        header.e_phnum = 4;
+        header.e_phoff = ElfOffset64::from(HEADER_SIZE);
+        set_header(&mut bytes, &header);
+        let load = build_ph(
+            PT_LOAD,
+            PF_R | PF_W,
+            0,
+            PIE_SIZE as u64,
+            PIE_SIZE as u64 + 0x100,
+        );
+        set_ph(&mut bytes, HEADER_SIZE, &load);
+        let mut dynamic = build_ph(PT_DYNAMIC, PF_R | PF_W, PIE_DYNAMIC, 0x100, 0x100);
+        dynamic.p_vaddr = PIE_DYNAMIC as u64;
+        set_ph(&mut bytes, HEADER_SIZE + PH_SIZE, &dynamic);
+        let mut tls = build_ph(PT_TLS, PF_R, PIE_TLS, 4, 16);
+        tls.p_vaddr = PIE_TLS as u64;
+        tls.p_align = 8;
+        set_ph(&mut bytes, HEADER_SIZE + PH_SIZE * 2, &tls);
+        let stack = build_ph(PT_GNU_STACK, stack_flags, 0, 0, 0);
+        set_ph(&mut bytes, HEADER_SIZE + PH_SIZE * 3, &stack);
+
+        let rela_size = core::mem::size_of_val(relas) as u64;
+        let dyns = [
+            (DT_RELA, PIE_RELA as u64),
+            (DT_RELASZ, rela_size),
+            (DT_RELAENT, core::mem::size_of::<ElfRela64>() as u64),
+            (DT_SYMTAB, PIE_SYMTAB as u64),
+            (DT_SYMENT, core::mem::size_of::<ElfSym64>() as u64),
+            (DT_STRTAB, PIE_STRTAB as u64),
+            (DT_STRSZ, 16),
+            (DT_HASH, PIE_HASH as u64),
+            (DT_NULL, 0),
+        ];
+        for (i, (d_tag, d_val)) in dyns.into_iter().enumerate() {
+            let off = PIE_DYNAMIC + i * core::mem::size_of::<ElfDyn64>();
+            set_struct(&mut bytes, off, &ElfDyn64 { d_tag, d_val });
+        }
+        for (i, rela) in relas.iter().enumerate() {
+            set_struct(
+                &mut bytes,
+                PIE_RELA + i * core::mem::size_of::<ElfRela64>(),
+                rela,
+            );
+        }
+        let foo = ElfSym64 {
+            st_name: 1,
+            st_info: 0x12, // STB_GLOBAL, STT_FUNC
+            st_other: 0,
+            st_shndx: 1,
+            st_value: PIE_SYM_FOO,
+            st_size: 8,
+        };
+        set_struct(
+            &mut bytes,
+            PIE_SYMTAB + core::mem::size_of::<ElfSym64>(),
+            &foo,
+        );
+        bytes[PIE_STRTAB..PIE_STRTAB + 5].copy_from_slice(b"\0foo\0");
+        // One bucket, two symbols.
+        for (i, word) in [1u32, 2, 1, 0, 0].into_iter().enumerate() {
+            set_struct(&mut bytes, PIE_HASH + i * 4, &word);
+        }
+        bytes[PIE_TLS..PIE_TLS + 4].copy_from_slice(b"tls!");
+        bytes
+    }
+
+    fn build_rela(r_offset: usize, sym: u32, rtype: u32, r_addend: i64) -> ElfRela64 {
+        ElfRela64 {
+            r_offset: r_offset as u64,
+            r_info: ((sym as u64) << 32) | rtype as u64,
+            r_addend,
+        }
+    }
+
+    #[test]
+    fn pie_test() {
+        const LOAD_BIAS: u64 = 0x1000_0000;
+        let relas = [
+            build_rela(PIE_RELOC_TARGETS, 0, R_RISCV_RELATIVE, 0x40),
+            build_rela(PIE_RELOC_TARGETS + 8, 1, R_RISCV_64, 8),
+            build_rela(0, 0, R_RISCV_NONE, 0),
+        ];
+        let bytes = build_pie(&relas, PF_R | PF_W);
+        let map = ElfMap::new(&bytes).unwrap();
+        assert!(map.is_pie());
+        assert_eq!(map.vaddr_range(), 0..PIE_SIZE as u64 + 0x100);
+
+        // Relocate the whole segment.
+        let seg = map.segments().next().unwrap();
+        let mut image = seg.data().unwrap().to_vec();
+        map.relocate(LOAD_BIAS, seg.vaddr(), &mut image);
+        let read_u64 =
+            |image: &[u8], off: usize| u64::from_le_bytes(image[off..off + 8].try_into().unwrap());
+        assert_eq!(read_u64(&image, PIE_RELOC_TARGETS), LOAD_BIAS + 0x40);
+        assert_eq!(
+            read_u64(&image, PIE_RELOC_TARGETS + 8),
+            LOAD_BIAS + PIE_SYM_FOO + 8
+        );
+        // Nothing else changed.
+        assert_eq!(image[..PIE_RELOC_TARGETS], bytes[..PIE_RELOC_TARGETS]);
+        assert_eq!(
+            image[PIE_RELOC_TARGETS + 16..],
+            bytes[PIE_RELOC_TARGETS + 16..]
+        );
+
+        // Relocate a range that splits both patched locations.
+        let vaddr = PIE_RELOC_TARGETS + 4;
+        let mut part = bytes[vaddr..vaddr + 8].to_vec();
+        map.relocate(LOAD_BIAS, vaddr as u64, &mut part);
+        assert_eq!(part, image[vaddr..vaddr + 8]);
+
+        let mut symbols = map.dynamic_symbols();
+        let foo = symbols.next().unwrap();
+        assert_eq!(foo.name(), "foo");
+        assert_eq!(foo.value(), PIE_SYM_FOO);
+        assert_eq!(foo.size(), 8);
+        assert!(!foo.is_absolute());
+        assert!(symbols.next().is_none());
+        assert!(map.find_symbol("foo").is_some());
+        assert!(map.find_symbol("bar").is_none());
+
+        let tls = map.tls().unwrap();
+        assert_eq!(tls.vaddr(), PIE_TLS as u64);
+        assert_eq!(tls.data().unwrap(), b"tls!");
+        assert_eq!(tls.size(), 16);
+        assert_eq!(tls.align(), 8);
+    }
+
+    #[test]
+    fn segment_perms_test() {
+        const HEADER_SIZE: usize = core::mem::size_of::<ElfHeader64>();
+        const PH_SIZE: usize = core::mem::size_of::<ElfProgramHeader64>();
+        let mut bytes = [0u8; HEADER_SIZE + PH_SIZE];
+        let mut header = build_header();
+        header.e_phnum = 1;
+        header.e_phoff = ElfOffset64::from(HEADER_SIZE);
+        set_header(&mut bytes, &header);
+        let perms = |bytes: &mut [u8], flags| {
+            let ph = build_ph(PT_LOAD, flags, 0, 0, 0x1000);
+            set_ph(bytes, HEADER_SIZE, &ph);
+            ElfMap::new(bytes).map(|map| format!("{}", map.segments().next().unwrap().perms()))
+        };
+        assert_eq!(perms(&mut bytes, PF_R).unwrap(), "RO");
+        assert_eq!(perms(&mut bytes, PF_R | PF_W).unwrap(), "RW");
+        assert_eq!(perms(&mut bytes, PF_R | PF_X).unwrap(), "RX");
+        // Linux kernels have writable and executable segments.
+        assert_eq!(perms(&mut bytes, PF_R | PF_W | PF_X).unwrap(), "RWX");
+        assert!(matches!(
+            perms(&mut bytes, PF_W),
+            Err(Error::UnsupportedProgramHeaderFlags(PF_W))
+        ));
+        assert!(matches!(
+            perms(&mut bytes, PF_W | PF_X),
+            Err(Error::UnsupportedProgramHeaderFlags(_))
+        ));
+    }
+
+    #[test]
+    fn pie_errors_test() {
+        let relative = build_rela(PIE_RELOC_TARGETS, 0, R_RISCV_RELATIVE, 0);
+        // Executable stack.
+        let bytes = build_pie(&[relative], PF_R | PF_W | PF_X);
+        assert!(matches!(ElfMap::new(&bytes), Err(Error::ExecutableStack)));
+        // Unsupported relocation type (R_RISCV_COPY).
+        let bytes = build_pie(&[build_rela(PIE_RELOC_TARGETS, 1, 4, 0)], PF_R | PF_W);
+        assert!(matches!(
+            ElfMap::new(&bytes),
+            Err(Error::UnsupportedRelocation(4))
+        ));
+        // Relocation past the end of the segment's data.
+        let bytes = build_pie(
+            &[build_rela(PIE_SIZE - 4, 0, R_RISCV_RELATIVE, 0)],
+            PF_R | PF_W,
+        );
+        assert!(matches!(
+            ElfMap::new(&bytes),
+            Err(Error::RelocationOutOfRange(_))
+        ));
+        // Symbol out of the symbol table.
+        let bytes = build_pie(
+            &[build_rela(PIE_RELOC_TARGETS, 2, R_RISCV_64, 0)],
+            PF_R | PF_W,
+        );
+        assert!(matches!(ElfMap::new(&bytes), Err(Error::DynamicMalformed)));
+        // Undefined symbol.
+        let bytes = build_pie(
+            &[build_rela(PIE_RELOC_TARGETS, 0, R_RISCV_64, 0)],
+            PF_R | PF_W,
+        );
+        assert!(matches!(ElfMap::new(&bytes), Err(Error::UndefinedSymbol)));
+    }
+
     #[test]
     fn offset_test() {
         let bytes1 = [0u8; 5];
@@ -38,7 +38,12 @@ pub fn stub_sys_memory() -> StubState {
     let mut hyp_mem = HypPageAlloc::new(&mut hw_map).unwrap();
     let root_pages = hyp_mem.take_pages_for_host_state_with_alignment(4, Sv48x4::TOP_LEVEL_ALIGN);
     let pte_pages = hyp_mem.take_pages_for_host_state(3);
-    let (page_tracker, host_pages) = PageTracker::from(hyp_mem, MEM_ALIGN as u64);
+    let (page_tracker, host_pages) = PageTracker::from(
+        hyp_mem,
+        MEM_ALIGN as u64,
+        DEFAULT_MAX_GUESTS,
+        DEFAULT_HYP_RESERVE_PAGES,
+    );
     // Leak the backing ram so it doesn't get freed
     std::mem::forget(backing_mem);
     StubState {
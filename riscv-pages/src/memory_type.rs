@@ -27,6 +27,8 @@ pub enum DeviceMemType {
     Uart,
     /// Reset device.
     Reset,
+    /// APLIC interrupt domain.
+    Aplic,
     // TODO: Add more types here.
 }
 
@@ -47,6 +49,7 @@ impl fmt::Display for DeviceMemType {
             DeviceMemType::PciBar => write!(f, "PCI BAR"),
             DeviceMemType::Uart => write!(f, "UART"),
             DeviceMemType::Reset => write!(f, "RESET"),
+            DeviceMemType::Aplic => write!(f, "APLIC"),
         }
     }
 }
@@ -70,6 +70,7 @@ register_bitfields![u64,
         ssoft OFFSET(1) NUMBITS(1) [],
         stimer OFFSET(5) NUMBITS(1) [],
         sext OFFSET(9) NUMBITS(1) [],
+        lcofi OFFSET(13) NUMBITS(1) [],
     ]
 ];
 
@@ -104,6 +105,13 @@ register_bitfields![u64,
     ]
 ];
 
+// Counter overflow status (Sscofpmf). Bits are in the same format as scounteren.
+register_bitfields![u64,
+    pub scountovf [
+        hpm OFFSET(3) NUMBITS(29) [],
+    ]
+];
+
 // Scratch register for supervisor use.
 register_bitfields![u64,
     pub sscratch [
@@ -149,6 +157,7 @@ register_bitfields![u64,
         ssoft OFFSET(1) NUMBITS(1) [],
         stimer OFFSET(5) NUMBITS(1) [],
         sext OFFSET(9) NUMBITS(1) [],
+        lcofi OFFSET(13) NUMBITS(1) [],
     ]
 ];
 
@@ -343,12 +352,21 @@ register_bitfields![u64,
     ]
 ];
 
+// Hypervisor virtual interrupt enables (AIA). Allows interrupts 13-63 to be injected into VS mode
+// through HVIP.
+register_bitfields![u64,
+    pub hvien [
+        lcofi OFFSET(13) NUMBITS(1) [],
+    ]
+];
+
 // Hypervisor virtual interrupt pending.
 register_bitfields![u64,
     pub hvip [
         vssoft OFFSET(2) NUMBITS(1) [],
         vstimer OFFSET(6) NUMBITS(1) [],
         vsext OFFSET(10) NUMBITS(1) [],
+        lcofi OFFSET(13) NUMBITS(1) [],
     ]
 ];
 
@@ -40,6 +40,7 @@ pub struct CSR {
     pub sie: ReadWriteRiscvCsr<sie::Register, CSR_SIE>,
     pub stvec: ReadWriteRiscvCsr<stvec::Register, CSR_STVEC>,
     pub scounteren: ReadWriteRiscvCsr<scounteren::Register, CSR_SCOUNTEREN>,
+    pub scountovf: ReadWriteRiscvCsr<scountovf::Register, CSR_SCOUNTOVF>,
     pub sscratch: ReadWriteRiscvCsr<sscratch::Register, CSR_SSCRATCH>,
     pub sepc: ReadWriteRiscvCsr<sepc::Register, CSR_SEPC>,
     pub scause: ReadWriteRiscvCsr<scause::Register, CSR_SCAUSE>,
@@ -56,6 +57,7 @@ pub struct CSR {
     pub hie: ReadWriteRiscvCsr<hie::Register, CSR_HIE>,
     pub hcounteren: ReadWriteRiscvCsr<hcounteren::Register, CSR_HCOUNTEREN>,
     pub hgeie: ReadWriteRiscvCsr<hgeie::Register, CSR_HGEIE>,
+    pub hvien: ReadWriteRiscvCsr<hvien::Register, CSR_HVIEN>,
     pub hvictl: ReadWriteRiscvCsr<hvictl::Register, CSR_HVICTL>,
     pub htval: ReadWriteRiscvCsr<htval::Register, CSR_HTVAL>,
     pub hip: ReadWriteRiscvCsr<hip::Register, CSR_HIP>,
@@ -78,6 +80,7 @@ pub struct CSR {
     pub vstopei: ReadWriteRiscvCsr<stopei::Register, CSR_VSTOPEI>,
     pub vsatp: ReadWriteRiscvCsr<satp::Register, CSR_VSATP>,
     pub vstopi: ReadWriteRiscvCsr<stopi::Register, CSR_VSTOPI>,
+    pub vsiselect: ReadWriteRiscvCsr<siselect::Register, CSR_VSISELECT>,
 
     pub vstart: ReadWriteRiscvCsr<vstart::Register, CSR_VSTART>,
     pub vcsr: ReadWriteRiscvCsr<vcsr::Register, CSR_VCSR>,
@@ -119,6 +122,7 @@ pub const CSR: &CSR = &CSR {
     sie: ReadWriteRiscvCsr::new(),
     stvec: ReadWriteRiscvCsr::new(),
     scounteren: ReadWriteRiscvCsr::new(),
+    scountovf: ReadWriteRiscvCsr::new(),
     sscratch: ReadWriteRiscvCsr::new(),
     sepc: ReadWriteRiscvCsr::new(),
     scause: ReadWriteRiscvCsr::new(),
@@ -135,6 +139,7 @@ pub const CSR: &CSR = &CSR {
     hie: ReadWriteRiscvCsr::new(),
     hcounteren: ReadWriteRiscvCsr::new(),
     hgeie: ReadWriteRiscvCsr::new(),
+    hvien: ReadWriteRiscvCsr::new(),
     hvictl: ReadWriteRiscvCsr::new(),
     htval: ReadWriteRiscvCsr::new(),
     hip: ReadWriteRiscvCsr::new(),
@@ -157,6 +162,7 @@ pub const CSR: &CSR = &CSR {
     vstopei: ReadWriteRiscvCsr::new(),
     vsatp: ReadWriteRiscvCsr::new(),
     vstopi: ReadWriteRiscvCsr::new(),
+    vsiselect: VSISELECT,
 
     vstart: ReadWriteRiscvCsr::new(),
     vcsr: ReadWriteRiscvCsr::new(),
@@ -101,6 +101,7 @@ pub enum Interrupt {
     VirtualSupervisorExternal = 10,
     MachineExternal = 11,
     SupervisorGuestExternal = 12,
+    LocalCounterOverflow = 13,
 }
 
 /// Exception causes.
@@ -145,6 +146,7 @@ impl Interrupt {
             10 => Ok(Interrupt::VirtualSupervisorExternal),
             11 => Ok(Interrupt::MachineExternal),
             12 => Ok(Interrupt::SupervisorGuestExternal),
+            13 => Ok(Interrupt::LocalCounterOverflow),
             v => Err(Error::UnknownCause(v)),
         }
     }
@@ -154,6 +156,7 @@ impl Interrupt {
             Interrupt::SupervisorSoft => Ok(sie::ssoft.val(1)),
             Interrupt::SupervisorTimer => Ok(sie::stimer.val(1)),
             Interrupt::SupervisorExternal => Ok(sie::sext.val(1)),
+            Interrupt::LocalCounterOverflow => Ok(sie::lcofi.val(1)),
             _ => Err(Error::InvalidCause),
         }
     }
@@ -163,6 +166,7 @@ impl Interrupt {
             Interrupt::SupervisorSoft => Ok(sip::ssoft.val(1)),
             Interrupt::SupervisorTimer => Ok(sip::stimer.val(1)),
             Interrupt::SupervisorExternal => Ok(sip::sext.val(1)),
+            Interrupt::LocalCounterOverflow => Ok(sip::lcofi.val(1)),
             _ => Err(Error::InvalidCause),
         }
     }
@@ -201,6 +205,7 @@ impl Interrupt {
             Interrupt::VirtualSupervisorSoft => Ok(hvip::vssoft.val(1)),
             Interrupt::VirtualSupervisorTimer => Ok(hvip::vstimer.val(1)),
             Interrupt::VirtualSupervisorExternal => Ok(hvip::vsext.val(1)),
+            Interrupt::LocalCounterOverflow => Ok(hvip::lcofi.val(1)),
             _ => Err(Error::InvalidCause),
         }
     }
@@ -2580,6 +2580,7 @@ pub const CSR_SIREG: u16 = 0x151;
 pub const CSR_STOPEI: u16 = 0x15c;
 pub const CSR_SATP: u16 = 0x180;
 pub const CSR_STOPI: u16 = 0xdb0;
+pub const CSR_SCOUNTOVF: u16 = 0xda0;
 pub const CSR_SCONTEXT: u16 = 0x5a8;
 pub const CSR_VSSTATUS: u16 = 0x200;
 pub const CSR_VSIE: u16 = 0x204;
@@ -2602,6 +2603,7 @@ pub const CSR_HIE: u16 = 0x604;
 pub const CSR_HTIMEDELTA: u16 = 0x605;
 pub const CSR_HCOUNTEREN: u16 = 0x606;
 pub const CSR_HGEIE: u16 = 0x607;
+pub const CSR_HVIEN: u16 = 0x608;
 pub const CSR_HVICTL: u16 = 0x609;
 pub const CSR_HENVCFG: u16 = 0x60a;
 pub const CSR_HTVAL: u16 = 0x643;
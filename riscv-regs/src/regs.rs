@@ -127,19 +127,13 @@ impl GeneralPurposeRegisters {
 #[repr(C)]
 pub struct FloatingPointRegisters([u64; 32]);
 
-/// The vector register file. We don't expect to directly interact with a guest's vector state
-/// other than for saving/restoring the registers, so simply treat the register file as an array
-/// of 256b values. This actually depends on the vlenb csr, so if the register is greater than 256
-/// bits (i.e. the vlenb csr is greater than 32) we will need to increase this.
+/// The number of registers in the vector register file.
+pub const NUM_VECTOR_REGISTERS: usize = 32;
 
-// The width of a vector register in bytes
-pub const MAX_VECTOR_REGISTER_LEN: usize = 32;
-const U64S_IN_REGISTER: usize = MAX_VECTOR_REGISTER_LEN >> 3;
-
-#[derive(Default)]
-#[repr(C)]
-pub struct VectorRegister([u64; U64S_IN_REGISTER]);
-
-#[derive(Default)]
-#[repr(C)]
-pub struct VectorRegisters([VectorRegister; 32]);
+/// Returns the size in bytes of the vector register file, given the width of a single vector
+/// register in bytes as reported by the vlenb CSR. The width of the vector registers isn't known
+/// until runtime, so a guest's vector state must be saved to a buffer of this size rather than to
+/// a fixed-size structure.
+pub const fn vector_register_file_size(vlenb: u64) -> u64 {
+    vlenb * NUM_VECTOR_REGISTERS as u64
+}
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use core::sync::atomic::{AtomicU8, Ordering};
 use sync::Mutex;
 
 pub use crate::{print, println};
@@ -12,25 +13,117 @@ pub trait ConsoleWriter: Sync {
     fn write_bytes(&self, bytes: &[u8]);
 }
 
+/// Number of bytes of the most recent console output kept in memory, e.g. for crash dumps.
+pub const LOG_RING_SIZE: usize = 8192;
+
+// The most recent console output, overwriting the oldest once full.
+struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_RING_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[(self.head + self.len) % LOG_RING_SIZE] = b;
+            if self.len < LOG_RING_SIZE {
+                self.len += 1;
+            } else {
+                self.head = (self.head + 1) % LOG_RING_SIZE;
+            }
+        }
+    }
+
+    // Returns the contents, oldest first, as two slices.
+    fn contents(&self) -> (&[u8], &[u8]) {
+        if self.head + self.len <= LOG_RING_SIZE {
+            (&self.buf[self.head..self.head + self.len], &[])
+        } else {
+            (
+                &self.buf[self.head..],
+                &self.buf[..self.head + self.len - LOG_RING_SIZE],
+            )
+        }
+    }
+}
+
 /// Represents the system console, used by the `print!` and `println!` macros.
 pub struct Console {
     writer: Option<&'static dyn ConsoleWriter>,
+    recent: LogRing,
 }
 
 impl Console {
     const fn new() -> Self {
-        Self { writer: None }
+        Self {
+            writer: None,
+            recent: LogRing::new(),
+        }
     }
 
     /// Sets the writer for the system console.
     pub fn set_writer(writer: &'static dyn ConsoleWriter) {
         CONSOLE.lock().writer = Some(writer);
     }
+
+    /// Returns up to the last `LOG_RING_SIZE` bytes written to the console, oldest first, as two
+    /// slices to be read one after the other.
+    pub fn recent_output(&self) -> (&[u8], &[u8]) {
+        self.recent.contents()
+    }
 }
 
 /// The `Console` singleton.
 pub static CONSOLE: Mutex<Console> = Mutex::new(Console::new());
 
+/// How much the hypervisor prints to the console, from least to most verbose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+    /// Only fatal errors.
+    Error,
+    /// Errors and anything unexpected.
+    Warn,
+    /// The above, plus informational messages such as the memory map printed at boot.
+    Info,
+    /// Everything.
+    Debug,
+}
+
+impl LogLevel {
+    /// Returns the level with the given name, e.g. "info".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Sets the level of messages that are printed to the console.
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns true if messages at `level` should be printed to the console.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
 /// `print` macro based on writing to `CONSOLE`.
 #[macro_export]
 macro_rules! print {
@@ -55,6 +148,7 @@ macro_rules! println {
 
 impl core::fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.recent.push(s.as_bytes());
         if let Some(w) = self.writer {
             w.write_bytes(s.as_bytes());
         }
@@ -5,10 +5,27 @@
 /// Get an array of backtrace addresses.
 ///
 /// This needs `force-frame-pointers` enabled for rustc
-use crate::hyp_layout::{HYP_STACK_BOTTOM, HYP_STACK_TOP};
+use crate::hyp_layout::{hyp_stack_bottom_page_addr, HYP_STACK_TOP};
 use alloc::fmt::{Display, Formatter, Result};
-use core::arch::asm;
+use core::arch::{asm, global_asm};
 use core::mem::size_of;
+use symbol_table::{SymbolTable, SECTION_SIZE};
+
+// Reserves the section the function symbols of salus and U-mode are written to after linking.
+global_asm!(
+    ".pushsection .salus_symbols, \"a\", @progbits",
+    ".global _salus_symbols",
+    "_salus_symbols:",
+    ".space {size}",
+    ".popsection",
+    size = const SECTION_SIZE,
+);
+
+extern "C" {
+    // Declared rather than defined in Rust so that reads aren't folded to the zeroes it's linked
+    // with.
+    static _salus_symbols: [u8; SECTION_SIZE];
+}
 
 #[cfg(test)]
 extern "C" {
@@ -27,7 +44,7 @@ impl Display for BTReturnAddress {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
             BTReturnAddress::ReturnAddress(addr) => {
-                writeln!(f, "0x{addr:x}")
+                writeln!(f, "{}", Symbolized::return_address(*addr))
             }
             BTReturnAddress::InvalidFramePointer(addr) => {
                 writeln!(f, "Invalid frame pointer: 0x{addr:x}")
@@ -39,6 +56,68 @@ impl Display for BTReturnAddress {
     }
 }
 
+/// An address in salus or in the U-mode binary, displayed as `function+offset` when the function
+/// containing it is known.
+pub(crate) struct Symbolized {
+    addr: u64,
+    image: &'static str,
+    load_bias: u64,
+    // Return addresses point past the call, which may be the start of the next function.
+    return_address: bool,
+}
+
+impl Symbolized {
+    /// Symbolizes the address of an instruction in salus.
+    pub(crate) fn pc(addr: u64) -> Self {
+        Self {
+            addr,
+            image: "salus",
+            load_bias: 0,
+            return_address: false,
+        }
+    }
+
+    /// Symbolizes a return address in salus.
+    pub(crate) fn return_address(addr: u64) -> Self {
+        Self {
+            return_address: true,
+            ..Self::pc(addr)
+        }
+    }
+
+    /// Symbolizes an address in the U-mode binary, loaded `load_bias` bytes above the address it
+    /// was linked at.
+    pub(crate) fn umode(addr: u64, load_bias: u64, return_address: bool) -> Self {
+        Self {
+            addr,
+            image: "umode",
+            load_bias,
+            return_address,
+        }
+    }
+}
+
+impl Display for Symbolized {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "0x{:016x}", self.addr)?;
+        let addr = self.addr.wrapping_sub(self.load_bias);
+        let lookup_addr = if self.return_address {
+            addr.wrapping_sub(1)
+        } else {
+            addr
+        };
+        // Safe because the section is only written to at build time.
+        let symbols = unsafe { &*core::ptr::addr_of!(_salus_symbols) };
+        // The table is empty if the image wasn't symbolized; print the bare address then.
+        if let Ok(Some(symbol)) =
+            SymbolTable::find(symbols, self.image).and_then(|table| table.lookup(lookup_addr))
+        {
+            write!(f, " {}+0x{:x}", symbol.name, addr - symbol.address)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct BackTrace {
     fp: Option<u64>,
     stack_start: u64,
@@ -125,9 +204,14 @@ pub(crate) fn backtrace() -> Option<BackTrace> {
     BackTrace::get_current(fp)
 }
 
+/// Returns the backtrace starting at the frame pointed to by `fp`, e.g. taken from a trap frame.
+pub(crate) fn backtrace_from(fp: u64) -> Option<BackTrace> {
+    BackTrace::get_current(fp)
+}
+
 #[cfg(not(test))]
 fn stack_limits() -> (u64, u64) {
-    (HYP_STACK_BOTTOM, HYP_STACK_TOP)
+    (hyp_stack_bottom_page_addr().bits(), HYP_STACK_TOP)
 }
 
 #[cfg(test)]
@@ -0,0 +1,439 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use core::fmt;
+use device_tree::{DeviceTreeError, Fdt, FdtMemoryRegion};
+use page_tracking::{DEFAULT_HYP_RESERVE_PAGES, DEFAULT_MAX_GUESTS};
+use riscv_pages::PageSize;
+use s_mode_utils::print::LogLevel;
+
+use crate::host_vm::HOST_VM_ALIGN;
+use crate::hyp_layout::{DEFAULT_HYP_STACK_PAGES, MAX_HYP_STACK_PAGES, MIN_HYP_STACK_PAGES};
+
+/// Size in bytes of the boot heap if `salus,heap-size` isn't given.
+const DEFAULT_HEAP_SIZE: u64 = 16 * 1024 * 1024;
+/// Smallest boot heap that may be configured. Boot takes a few hundred kB on small systems.
+const MIN_HEAP_SIZE: u64 = 1024 * 1024;
+/// Largest number of TVMs that may be configured.
+const MAX_TVMS_LIMIT: u64 = 0x1_0000;
+/// Smallest runtime heap that may be configured, in pages. Each slab size class takes a page.
+const MIN_RUNTIME_HEAP_PAGES: u64 = 64;
+/// Largest runtime heap that may be configured, in pages (4 GiB).
+const MAX_RUNTIME_HEAP_PAGES: u64 = 0x10_0000;
+
+/// Errors from reading the boot configuration.
+#[derive(Debug)]
+pub enum Error {
+    /// Problem reading the `/chosen` node.
+    Fdt(DeviceTreeError),
+    /// `salus,heap-size` is out of range or not page aligned.
+    HeapSize(u64),
+    /// `salus,stack-pages` is out of range.
+    StackPages(u64),
+    /// `salus,max-tvms` is out of range.
+    MaxTvms(u64),
+    /// `salus,runtime-heap-pages` is out of range.
+    RuntimeHeapPages(u64),
+    /// `salus,log-level` isn't one of "error", "warn", "info" or "debug".
+    LogLevel,
+    /// `salus,crash-dump` is empty or not aligned to `HOST_VM_ALIGN`.
+    CrashDump(FdtMemoryRegion),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Fdt(e) => write!(f, "Failed to read /chosen: {}", e),
+            HeapSize(size) => write!(
+                f,
+                "Invalid heap size 0x{:x}; must be page aligned and at least 0x{:x}",
+                size, MIN_HEAP_SIZE
+            ),
+            StackPages(pages) => write!(
+                f,
+                "Invalid stack size of {} pages; must be from {} to {}",
+                pages, MIN_HYP_STACK_PAGES, MAX_HYP_STACK_PAGES
+            ),
+            MaxTvms(max) => write!(
+                f,
+                "Invalid maximum of {} TVMs; must be from 1 to {}",
+                max, MAX_TVMS_LIMIT
+            ),
+            RuntimeHeapPages(pages) => write!(
+                f,
+                "Invalid runtime heap size of {} pages; must be from {} to {}",
+                pages, MIN_RUNTIME_HEAP_PAGES, MAX_RUNTIME_HEAP_PAGES
+            ),
+            LogLevel => write!(f, "Unknown log level"),
+            CrashDump(r) => write!(
+                f,
+                "Invalid crash dump region 0x{:x}+0x{:x}; must be non-empty and 0x{:x} aligned",
+                r.base(),
+                r.size(),
+                HOST_VM_ALIGN as u64
+            ),
+        }
+    }
+}
+
+/// Hypervisor settings given by the `salus,*` properties of the device tree's `/chosen` node, with
+/// defaults for those that aren't present.
+#[derive(Clone, Copy, Debug)]
+pub struct BootConfig {
+    heap_size: u64,
+    stack_pages: u64,
+    max_tvms: usize,
+    runtime_heap_pages: usize,
+    log_level: LogLevel,
+    crash_dump: Option<FdtMemoryRegion>,
+    rng_seed: Option<u64>,
+}
+
+impl BootConfig {
+    /// Reads and validates the configuration from `fdt`.
+    pub fn from_fdt(fdt: &Fdt) -> Result<Self, Error> {
+        let config = fdt.salus_config().map_err(Error::Fdt)?;
+
+        let heap_size = config.heap_size().unwrap_or(DEFAULT_HEAP_SIZE);
+        if heap_size < MIN_HEAP_SIZE || !PageSize::Size4k.is_aligned(heap_size) {
+            return Err(Error::HeapSize(heap_size));
+        }
+        let stack_pages = config.stack_pages().unwrap_or(DEFAULT_HYP_STACK_PAGES);
+        if !(MIN_HYP_STACK_PAGES..=MAX_HYP_STACK_PAGES).contains(&stack_pages) {
+            return Err(Error::StackPages(stack_pages));
+        }
+        let max_tvms = config.max_tvms().unwrap_or(DEFAULT_MAX_GUESTS as u64);
+        if !(1..=MAX_TVMS_LIMIT).contains(&max_tvms) {
+            return Err(Error::MaxTvms(max_tvms));
+        }
+        let runtime_heap_pages = config
+            .runtime_heap_pages()
+            .unwrap_or(DEFAULT_HYP_RESERVE_PAGES as u64);
+        if !(MIN_RUNTIME_HEAP_PAGES..=MAX_RUNTIME_HEAP_PAGES).contains(&runtime_heap_pages) {
+            return Err(Error::RuntimeHeapPages(runtime_heap_pages));
+        }
+        let log_level = match config.log_level() {
+            Some(name) => LogLevel::from_name(name).ok_or(Error::LogLevel)?,
+            None => LogLevel::Info,
+        };
+        // The memory map reserves whole `HOST_VM_ALIGN` blocks, so don't let a misaligned region
+        // take memory from around it.
+        let crash_dump = config.crash_dump();
+        if let Some(r) = crash_dump {
+            if r.size() == 0
+                || !HOST_VM_ALIGN.is_aligned(r.base())
+                || !HOST_VM_ALIGN.is_aligned(r.size())
+            {
+                return Err(Error::CrashDump(r));
+            }
+        }
+
+        // Fold however many bytes of entropy we were given into a single word.
+        let rng_seed = config.rng_seed().filter(|s| !s.is_empty()).map(|seed| {
+            seed.chunks(8).fold(0, |acc, chunk| {
+                let mut word = [0u8; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                acc ^ u64::from_le_bytes(word)
+            })
+        });
+
+        Ok(Self {
+            heap_size,
+            stack_pages,
+            max_tvms: max_tvms as usize,
+            runtime_heap_pages: runtime_heap_pages as usize,
+            log_level,
+            crash_dump,
+            rng_seed,
+        })
+    }
+
+    /// Returns the size in bytes of the heap used for allocations during boot.
+    pub fn heap_size(&self) -> u64 {
+        self.heap_size
+    }
+
+    /// Returns the number of 4k-pages in each CPU's hypervisor stack.
+    pub fn stack_pages(&self) -> u64 {
+        self.stack_pages
+    }
+
+    /// Returns the maximum number of TVMs that may exist at once.
+    pub fn max_tvms(&self) -> usize {
+        self.max_tvms
+    }
+
+    /// Returns the number of 4k-pages set aside for the heap used for allocations after boot.
+    pub fn runtime_heap_pages(&self) -> usize {
+        self.runtime_heap_pages
+    }
+
+    /// Returns the level of messages to print to the console.
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level
+    }
+
+    /// Returns the region of memory to save crash dumps to, if one was given.
+    pub fn crash_dump(&self) -> Option<FdtMemoryRegion> {
+        self.crash_dump
+    }
+
+    /// Returns the random seed passed by the bootloader in `/chosen/rng-seed`, if any.
+    pub fn rng_seed(&self) -> Option<u64> {
+        self.rng_seed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use device_tree::{DeviceTree, DeviceTreeNode, DeviceTreeSerializer};
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    #[repr(C, align(32))]
+    struct FdtBuf([u8; 512]);
+
+    // Reads the boot configuration from an FDT with the properties set by `f` in its `/chosen`
+    // node.
+    fn config_from(f: impl FnOnce(&mut DeviceTreeNode)) -> Result<BootConfig, Error> {
+        let mut tree = DeviceTree::new();
+        let root = tree.add_node("", None).unwrap();
+        {
+            let node = tree.get_mut_node(root).unwrap();
+            node.add_prop("#address-cells")
+                .unwrap()
+                .set_value_u32(&[2])
+                .unwrap();
+            node.add_prop("#size-cells")
+                .unwrap()
+                .set_value_u32(&[2])
+                .unwrap();
+        }
+        let chosen = tree.add_node("chosen", Some(root)).unwrap();
+        f(tree.get_mut_node(chosen).unwrap());
+        let writer = DeviceTreeSerializer::new(&tree);
+        let mut buf = FdtBuf([0; 512]);
+        writer.write_to(&mut buf.0[..writer.output_size()]);
+        // Safety: `buf` is suitably aligned and holds the FDT written by `writer`.
+        let fdt = unsafe { Fdt::new_from_raw_pointer(buf.0.as_ptr()) }.expect("Fdt::new");
+        BootConfig::from_fdt(&fdt)
+    }
+
+    #[test_case]
+    fn BootConfigDefaultsTest() -> TestResult {
+        let config = config_from(|_| {}).expect("BootConfig defaults");
+        test_result_true!(
+            config.heap_size() == DEFAULT_HEAP_SIZE
+                && config.stack_pages() == DEFAULT_HYP_STACK_PAGES
+                && config.max_tvms() == DEFAULT_MAX_GUESTS
+                && config.runtime_heap_pages() == DEFAULT_HYP_RESERVE_PAGES
+                && config.log_level() == LogLevel::Info,
+            "BootConfig defaults"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn BootConfigFromFdtTest() -> TestResult {
+        let config = config_from(|node| {
+            node.add_prop("salus,heap-size")
+                .unwrap()
+                .set_value_u64(&[0x200_0000])
+                .unwrap();
+            node.add_prop("salus,stack-pages")
+                .unwrap()
+                .set_value_u32(&[0x100])
+                .unwrap();
+            node.add_prop("salus,max-tvms")
+                .unwrap()
+                .set_value_u32(&[4])
+                .unwrap();
+            node.add_prop("salus,runtime-heap-pages")
+                .unwrap()
+                .set_value_u32(&[0x1000])
+                .unwrap();
+            node.add_prop("salus,log-level")
+                .unwrap()
+                .set_value_str("warn")
+                .unwrap();
+        })
+        .expect("BootConfig from FDT");
+        test_result_true!(
+            config.heap_size() == 0x200_0000
+                && config.stack_pages() == 0x100
+                && config.max_tvms() == 4
+                && config.runtime_heap_pages() == 0x1000
+                && config.log_level() == LogLevel::Warn,
+            "BootConfig settings"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn BootConfigErrorsTest() -> TestResult {
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,heap-size")
+                        .unwrap()
+                        .set_value_u32(&[0x1000])
+                        .unwrap();
+                }),
+                Err(Error::HeapSize(0x1000))
+            ),
+            "heap too small"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,heap-size")
+                        .unwrap()
+                        .set_value_u32(&[0x100_0800])
+                        .unwrap();
+                }),
+                Err(Error::HeapSize(0x100_0800))
+            ),
+            "heap unaligned"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,stack-pages")
+                        .unwrap()
+                        .set_value_u32(&[0])
+                        .unwrap();
+                }),
+                Err(Error::StackPages(0))
+            ),
+            "no stack pages"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,max-tvms")
+                        .unwrap()
+                        .set_value_u32(&[0])
+                        .unwrap();
+                }),
+                Err(Error::MaxTvms(0))
+            ),
+            "no TVMs"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,runtime-heap-pages")
+                        .unwrap()
+                        .set_value_u32(&[16])
+                        .unwrap();
+                }),
+                Err(Error::RuntimeHeapPages(16))
+            ),
+            "runtime heap too small"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,log-level")
+                        .unwrap()
+                        .set_value_str("loud")
+                        .unwrap();
+                }),
+                Err(Error::LogLevel)
+            ),
+            "unknown log level"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,max-tvms")
+                        .unwrap()
+                        .set_value_raw(&[0, 0, 1])
+                        .unwrap();
+                }),
+                Err(Error::Fdt(DeviceTreeError::BadPropSize(3)))
+            ),
+            "bad property size"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn BootConfigRngSeedTest() -> TestResult {
+        test_result_true!(
+            config_from(|_| {}).is_ok_and(|c| c.rng_seed().is_none()),
+            "no RNG seed"
+        )?;
+        test_result_true!(
+            config_from(|node| {
+                node.add_prop("rng-seed")
+                    .unwrap()
+                    .set_value_raw(&[])
+                    .unwrap();
+            })
+            .is_ok_and(|c| c.rng_seed().is_none()),
+            "empty RNG seed"
+        )?;
+        // The seed's words are folded together, with the last one zero-extended.
+        test_result_true!(
+            config_from(|node| {
+                node.add_prop("rng-seed")
+                    .unwrap()
+                    .set_value_raw(&[1, 0, 0, 0, 0, 0, 0, 0, 3])
+                    .unwrap();
+            })
+            .is_ok_and(|c| c.rng_seed() == Some(2)),
+            "folded RNG seed"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn BootConfigCrashDumpTest() -> TestResult {
+        test_result_true!(
+            config_from(|_| {}).is_ok_and(|c| c.crash_dump().is_none()),
+            "no crash dump region"
+        )?;
+        test_result_true!(
+            config_from(|node| {
+                node.add_prop("salus,crash-dump")
+                    .unwrap()
+                    .set_value_u64(&[0x9000_0000, 0x40_0000])
+                    .unwrap();
+            })
+            .is_ok_and(|c| {
+                c.crash_dump()
+                    .is_some_and(|r| r.base() == 0x9000_0000 && r.size() == 0x40_0000)
+            }),
+            "crash dump region"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,crash-dump")
+                        .unwrap()
+                        .set_value_u64(&[0x9000_0000, 0])
+                        .unwrap();
+                }),
+                Err(Error::CrashDump(_))
+            ),
+            "empty crash dump region"
+        )?;
+        test_result_true!(
+            matches!(
+                config_from(|node| {
+                    node.add_prop("salus,crash-dump")
+                        .unwrap()
+                        .set_value_u64(&[0x9000_1000, 0x40_0000])
+                        .unwrap();
+                }),
+                Err(Error::CrashDump(_))
+            ),
+            "unaligned crash dump region"
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Saves a crash dump to the memory region given by `salus,crash-dump` when Salus panics, so that
+//! it can be read back after the next boot. See the `crash-dump` crate for the format and
+//! `crash_dump_parser` for printing a dump.
+//!
+//! The first hart to panic owns the dump. It writes the panic message, its own registers and
+//! backtrace, the recent console output and the memory map, then stops the other CPUs with an IPI
+//! so that each adds its own registers and backtrace, and finally summarizes the TVMs. CPUs only
+//! take IPIs while idle or running a guest, so a CPU that's busy in the hypervisor is left out
+//! after a while. Each record is committed as soon as it's complete, so a dump cut short by a hang
+//! or a nested panic keeps what was written before it.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::slice;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crash_dump::{
+    Dump, DumpWriter, Error as DumpError, HartState, MemRegion, RecordType, TvmSummary, VcpuState,
+    HART_FLAG_PANICKED, HART_FLAG_TRAP_FRAME, TVM_FLAG_FINALIZED,
+};
+use drivers::{CpuId, CpuInfo};
+use riscv_regs::GprIndex;
+use s_mode_utils::abort::abort;
+use s_mode_utils::print::*;
+use sync::{Mutex, Once};
+
+use crate::backtrace::{backtrace, backtrace_from, BTReturnAddress};
+use crate::hyp_map::HypMap;
+use crate::smp::{self, PerCpu};
+use crate::vm_cpu::VmCpuStatus;
+use crate::HOST_VM;
+
+/// The registers of a trap taken in the hypervisor.
+#[derive(Clone, Copy)]
+pub struct TrapRegs {
+    /// The PC the trap was taken at.
+    pub sepc: u64,
+    /// SSTATUS when the trap was taken.
+    pub sstatus: u64,
+    /// The general purpose registers, x0 to x31.
+    pub gprs: [u64; 32],
+}
+
+// Where dumps are saved.
+struct CrashRegion {
+    base: u64,
+    size: u64,
+    boot_hart_id: u64,
+}
+
+static CRASH_REGION: Once<CrashRegion> = Once::new();
+
+// `DUMP_OWNER` while no hart is writing a dump.
+const NO_OWNER: u64 = u64::MAX;
+
+// The ID of the hart writing the dump, the first one to panic.
+static DUMP_OWNER: AtomicU64 = AtomicU64::new(NO_OWNER);
+
+// The dump being written, once the owner has started it. The other harts add their state to it
+// while the owner waits for them.
+static DUMP: Mutex<Option<DumpWriter<'static>>> = Mutex::new(None);
+
+// The number of other harts that have added their state to the dump and stopped.
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+// The ID of the last hart to take a trap it couldn't handle, and the registers of that trap.
+static FATAL_TRAP: Mutex<Option<(u64, TrapRegs)>> = Mutex::new(None);
+
+// How long to wait for the other harts to add their state before giving up on them.
+const STOP_SPIN_LIMIT: usize = 10_000_000;
+
+// The frames of the backtrace taken in `capture()` that are always the same: `capture()` itself
+// and the panic handling code.
+const PANIC_FRAMES: usize = 3;
+
+/// Saves crash dumps to the `size` bytes at `base`, which must be reserved for them in the memory
+/// map, and reports the dump left there by a previous boot, if any. `boot_hart_id` is the ID of
+/// the calling hart.
+pub fn init(base: u64, size: u64, boot_hart_id: u64) {
+    // Safety: the region is reserved for crash dumps, and identity-mapped both now and once the
+    // hypervisor page tables are enabled. Nothing writes to it until we panic.
+    let region = unsafe { slice::from_raw_parts(base as *const u8, size as usize) };
+    match Dump::parse(region) {
+        Ok(dump) if log_enabled(LogLevel::Warn) => println!(
+            "Found a crash dump at 0x{:x} ({} bytes): {}",
+            base,
+            dump.header().size,
+            dump.panic_message().unwrap_or("no panic message")
+        ),
+        // Nothing was ever saved there.
+        Err(DumpError::BadMagic) => (),
+        Err(e) if log_enabled(LogLevel::Warn) => {
+            println!("Invalid crash dump at 0x{:x}: {}", base, e)
+        }
+        _ => (),
+    }
+    CRASH_REGION.call_once(|| CrashRegion {
+        base,
+        size,
+        boot_hart_id,
+    });
+}
+
+/// Records the registers of a trap the calling hart can't recover from, for the dump taken when
+/// it panics because of it.
+pub fn record_fatal_trap(regs: TrapRegs) {
+    let Some(region) = CRASH_REGION.get() else {
+        return;
+    };
+    *FATAL_TRAP.lock() = Some((this_hart_id(region), regs));
+}
+
+/// Saves a crash dump for the panic described by `info`, if a crash region was configured. Called
+/// from the panic handler.
+pub fn capture(info: &PanicInfo) {
+    let Some(region) = CRASH_REGION.get() else {
+        return;
+    };
+    let hart_id = this_hart_id(region);
+    let fatal_trap = take_fatal_trap(hart_id);
+    if let Err(owner) =
+        DUMP_OWNER.compare_exchange(NO_OWNER, hart_id, Ordering::AcqRel, Ordering::Acquire)
+    {
+        if owner != hart_id {
+            // Another hart got there first; just add our state to its dump.
+            add_hart(&panicked_hart_state(hart_id, fatal_trap.as_ref()));
+            STOPPED.fetch_add(1, Ordering::Release);
+        }
+        // Otherwise we panicked while writing the dump. Keep what we have.
+        return;
+    }
+
+    // Safety: the region is reserved for crash dumps and identity-mapped, and only the owner
+    // writes to it.
+    let buf = unsafe { slice::from_raw_parts_mut(region.base as *mut u8, region.size as usize) };
+    let Some(mut dump) = DumpWriter::new(buf) else {
+        return;
+    };
+    let _ = write!(dump.record(RecordType::Panic), "{}", info);
+    dump.add(
+        RecordType::Hart,
+        &panicked_hart_state(hart_id, fatal_trap.as_ref()).encode(),
+    );
+    add_recent_output(&mut dump);
+    add_mem_map(&mut dump);
+    *DUMP.lock() = Some(dump);
+
+    let expected = stop_other_cpus();
+    let mut spins = 0;
+    while STOPPED.load(Ordering::Acquire) < expected && spins < STOP_SPIN_LIMIT {
+        core::hint::spin_loop();
+        spins += 1;
+    }
+    let stopped = STOPPED.load(Ordering::Acquire);
+    if stopped < expected {
+        println!(
+            "Crash dump: {} CPU(s) still running in the hypervisor",
+            expected - stopped
+        );
+    }
+
+    let mut guard = DUMP.lock();
+    let Some(dump) = guard.as_mut() else {
+        return;
+    };
+    add_tvms(dump);
+    println!(
+        "Crash dump written to 0x{:x} ({} bytes)",
+        region.base,
+        dump.size()
+    );
+}
+
+/// Adds this hart's state to the dump and stops it if another hart is writing one. Called on IPIs,
+/// with the registers of the trap the IPI was taken from if it interrupted the hypervisor.
+pub fn handle_ipi(regs: Option<&TrapRegs>) {
+    let owner = DUMP_OWNER.load(Ordering::Acquire);
+    let Some(region) = CRASH_REGION.get() else {
+        return;
+    };
+    let hart_id = this_hart_id(region);
+    if owner == NO_OWNER || owner == hart_id {
+        return;
+    }
+    let state = match regs {
+        Some(regs) => hart_state(
+            hart_id,
+            0,
+            Some(regs),
+            backtrace_from(regs.gprs[GprIndex::S0 as usize])
+                .into_iter()
+                .flatten(),
+        ),
+        None => hart_state(hart_id, 0, None, backtrace().into_iter().flatten()),
+    };
+    add_hart(&state);
+    STOPPED.fetch_add(1, Ordering::Release);
+    abort();
+}
+
+// Returns the ID of the calling hart. Only the boot hart runs until the per-CPU areas are set up.
+fn this_hart_id(region: &CrashRegion) -> u64 {
+    PerCpu::try_this_cpu()
+        .and_then(|pcpu| CpuInfo::get().cpu_to_hart_id(pcpu.cpu_id()))
+        .map_or(region.boot_hart_id, u64::from)
+}
+
+// Returns the registers of the fatal trap `hart_id` took, if the last one was taken by it.
+fn take_fatal_trap(hart_id: u64) -> Option<TrapRegs> {
+    FATAL_TRAP
+        .lock()
+        .take()
+        .filter(|(id, _)| *id == hart_id)
+        .map(|(_, regs)| regs)
+}
+
+// Builds the state of a hart from the registers of the trap it took, if any, and its backtrace.
+fn hart_state(
+    hart_id: u64,
+    flags: u64,
+    regs: Option<&TrapRegs>,
+    frames: impl Iterator<Item = BTReturnAddress>,
+) -> HartState {
+    let mut state = HartState {
+        hart_id,
+        flags,
+        ..Default::default()
+    };
+    if let Some(regs) = regs {
+        state.flags |= HART_FLAG_TRAP_FRAME;
+        state.sepc = regs.sepc;
+        state.sstatus = regs.sstatus;
+        state.gprs = regs.gprs;
+    }
+    let return_addresses = frames.map_while(|frame| match frame {
+        BTReturnAddress::ReturnAddress(addr) => Some(addr),
+        _ => None,
+    });
+    for (slot, addr) in state.backtrace.iter_mut().zip(return_addresses) {
+        *slot = addr;
+        state.num_frames += 1;
+    }
+    state
+}
+
+// Builds the state of a panicking hart. The backtrace of a fatal trap starts from where it was
+// taken rather than from the panic.
+fn panicked_hart_state(hart_id: u64, fatal_trap: Option<&TrapRegs>) -> HartState {
+    match fatal_trap {
+        Some(regs) => hart_state(
+            hart_id,
+            HART_FLAG_PANICKED,
+            Some(regs),
+            backtrace_from(regs.gprs[GprIndex::S0 as usize])
+                .into_iter()
+                .flatten(),
+        ),
+        None => hart_state(
+            hart_id,
+            HART_FLAG_PANICKED,
+            None,
+            backtrace().into_iter().flatten().skip(PANIC_FRAMES),
+        ),
+    }
+}
+
+// Adds `state` to the dump once the owner has started it. Gives up rather than wait forever if the
+// owner never gets that far.
+fn add_hart(state: &HartState) {
+    for _ in 0..STOP_SPIN_LIMIT {
+        if let Some(dump) = DUMP.lock().as_mut() {
+            dump.add(RecordType::Hart, &state.encode());
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Adds the recent console output, which ends with the panic message and backtrace.
+fn add_recent_output(dump: &mut DumpWriter) {
+    let console = CONSOLE.lock();
+    let (mut first, mut second) = console.recent_output();
+    // The oldest character may have been partly overwritten. Drop what's left of it so that the
+    // log stays valid UTF-8.
+    while let Some((&b, rest)) = first.split_first().or_else(|| second.split_first()) {
+        if b & 0xc0 != 0x80 {
+            break;
+        }
+        if first.is_empty() {
+            second = rest;
+        } else {
+            first = rest;
+        }
+    }
+    let mut record = dump.record(RecordType::Log);
+    record.write_bytes(first);
+    record.write_bytes(second);
+}
+
+// Adds the regions of the hardware memory map, once the hypervisor map has taken it over.
+fn add_mem_map(dump: &mut DumpWriter) {
+    let Some(hyp_map) = HypMap::try_get() else {
+        return;
+    };
+    for r in hyp_map.mem_map().regions() {
+        let mut region = MemRegion {
+            base: r.base().bits(),
+            size: r.size(),
+            ..Default::default()
+        };
+        let _ = write!(region, "{}", r.region_type());
+        dump.add(RecordType::MemRegion, &region.encode());
+    }
+}
+
+// Sends an IPI to each of the other online CPUs so that they add their state to the dump and stop.
+// Returns the number of CPUs sent one.
+fn stop_other_cpus() -> usize {
+    // The CPU topology and the IMSIC are set up before the per-CPU areas.
+    let Some(this_cpu) = PerCpu::try_this_cpu() else {
+        return 0;
+    };
+    let mut count = 0;
+    for i in 0..CpuInfo::get().num_cpus() {
+        let cpu = CpuId::new(i);
+        if cpu != this_cpu.cpu_id() && PerCpu::is_online(cpu) {
+            smp::send_ipi(cpu);
+            count += 1;
+        }
+    }
+    count
+}
+
+// Adds a summary of each TVM, unless the guest list is locked, e.g. by the code that panicked.
+fn add_tvms(dump: &mut DumpWriter) {
+    let Some(host) = HOST_VM.get() else {
+        return;
+    };
+    let listed = host.vm().try_for_each_guest(|guest| {
+        let flags = if guest.as_finalized_vm().is_some() {
+            TVM_FLAG_FINALIZED
+        } else {
+            0
+        };
+        let vm = guest.as_any_vm();
+        let num_vcpus = vm.vcpu_statuses().count() as u32;
+        let mut record = dump.record(RecordType::Tvm);
+        record.write_bytes(&TvmSummary::encode_header(
+            vm.page_owner_id().raw(),
+            flags,
+            num_vcpus,
+        ));
+        for (id, status) in vm.vcpu_statuses() {
+            record.write_bytes(&TvmSummary::encode_vcpu(id as u32, vcpu_state(status)));
+        }
+    });
+    if !listed {
+        println!("Crash dump: TVM list busy, skipping TVMs");
+    }
+}
+
+fn vcpu_state(status: VmCpuStatus) -> VcpuState {
+    match status {
+        VmCpuStatus::PoweredOff => VcpuState::PoweredOff,
+        VmCpuStatus::Runnable => VcpuState::Runnable,
+        VmCpuStatus::Running => VcpuState::Running,
+    }
+}
@@ -0,0 +1,279 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use drivers::{uart::UartDriver, CpuId, CpuInfo};
+use s_mode_utils::print::*;
+use sync::Mutex;
+
+use crate::hyp_map::HypMap;
+use crate::platform_log::PlatformLog;
+use crate::smp::PerCpu;
+use crate::vm_cpu::VmCpuStatus;
+use crate::HOST_VM;
+
+/// The legacy SBI console getchar extension ID. Not covered by `SbiMessage`, so we decode it
+/// ourselves.
+pub const EXT_GET_CHAR: u64 = 2;
+/// The function ID of `sbi_debug_console_read()` within the DBCN extension. Not covered by
+/// `DebugConsoleFunction`, so we decode it ourselves.
+pub const CONSOLE_READ_FID: u64 = 1;
+
+// Typing Ctrl-] on the console enters the monitor instead of passing the byte on to the host.
+const MONITOR_ESCAPE: u8 = 0x1d;
+
+// Console input that's waiting for the host VM to read it.
+const HOST_INPUT_LEN: usize = 256;
+
+struct InputQueue {
+    buf: [u8; HOST_INPUT_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl InputQueue {
+    const fn new() -> Self {
+        Self {
+            buf: [0; HOST_INPUT_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // Appends `byte` to the queue, dropping it if the queue is full.
+    fn push(&mut self, byte: u8) {
+        if self.len < HOST_INPUT_LEN {
+            self.buf[(self.head + self.len) % HOST_INPUT_LEN] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % HOST_INPUT_LEN;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static HOST_INPUT: Mutex<InputQueue> = Mutex::new(InputQueue::new());
+
+// Held by the CPU draining the UART so that input, and the monitor, isn't split across CPUs.
+static POLLING: Mutex<()> = Mutex::new(());
+
+// Set when the escape key is read until a CPU picks up the request and runs the monitor.
+static MONITOR_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Drains any input pending at the UART, queueing it for the host VM or requesting the monitor if
+/// the escape key was pressed. Called on the UART's interrupt, or when the host VM reads from the
+/// console if the UART's interrupt couldn't be routed to us. Never blocks, so it's safe to call in
+/// interrupt context; the monitor itself is run by `run_requested_monitor()`.
+pub fn poll_input() {
+    let Some(uart) = UartDriver::get() else {
+        return;
+    };
+    // If another CPU is already draining the UART, it'll pick up anything that's pending.
+    let Some(_polling) = POLLING.try_lock() else {
+        return;
+    };
+    while let Some(byte) = uart.read_byte() {
+        if byte == MONITOR_ESCAPE {
+            MONITOR_REQUESTED.store(true, Ordering::Release);
+        } else {
+            HOST_INPUT.lock().push(byte);
+        }
+    }
+}
+
+/// Runs the monitor on this CPU if it was requested on the console, returning once the user quits
+/// it. Blocks waiting for input, so it must only be called outside of interrupt context, e.g. from
+/// a vCPU's run loop after the UART's interrupt has been handled.
+pub fn run_requested_monitor() {
+    if !MONITOR_REQUESTED.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let Some(uart) = UartDriver::get() else {
+        return;
+    };
+    // Keep other CPUs from taking the monitor's input off the UART.
+    let _polling = POLLING.lock();
+    run_monitor(uart);
+}
+
+/// Reads pending console input for the host VM into `buf`, returning the number of bytes read.
+pub fn read_host_input(buf: &mut [u8]) -> usize {
+    poll_input();
+    run_requested_monitor();
+    let mut input = HOST_INPUT.lock();
+    let mut count = 0;
+    for b in buf.iter_mut() {
+        let Some(byte) = input.pop() else {
+            break;
+        };
+        *b = byte;
+        count += 1;
+    }
+    count
+}
+
+// Runs the monitor on this CPU until the user quits it. The rest of the system keeps running, except
+// for anything waiting on this CPU.
+fn run_monitor(uart: &UartDriver) {
+    println!();
+    println!("Salus monitor; 'h' for help");
+    loop {
+        print!("salus> ");
+        let cmd = loop {
+            if let Some(byte) = uart.read_byte() {
+                break byte;
+            }
+            core::hint::spin_loop();
+        };
+        println!("{}", cmd as char);
+        match cmd {
+            b'm' => dump_mem_map(),
+            b'p' => dump_page_states(),
+            b'c' => dump_cpus(),
+            b'v' => dump_tvms(),
+            b'l' => dump_platform_log(),
+            b'q' => break,
+            b'h' => print_help(),
+            _ => println!("Unknown command; 'h' for help"),
+        }
+    }
+}
+
+fn print_help() {
+    println!("  m: dump the hardware memory map");
+    println!("  p: count pages in each state");
+    println!("  c: dump per-CPU state");
+    println!("  v: list TVMs and their vCPUs");
+    println!("  l: dump the platform measurement log");
+    println!("  q: return to the host");
+}
+
+fn dump_mem_map() {
+    for (i, r) in HypMap::get().mem_map().regions().enumerate() {
+        println!(
+            "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
+            i,
+            r.base().bits(),
+            r.end().bits() - 1,
+            r.region_type()
+        );
+    }
+}
+
+fn dump_page_states() {
+    let Some(host) = HOST_VM.get() else {
+        println!("Host VM not created yet");
+        return;
+    };
+    // The page map lock may be held by the code we interrupted, so don't wait for it.
+    let Some(counts) = host.vm().page_tracker().try_page_state_counts() else {
+        println!("Page map busy, try again");
+        return;
+    };
+    println!("Reserved:   {}", counts.reserved);
+    println!("Free:       {}", counts.free);
+    println!("Mapped:     {}", counts.mapped);
+    println!("Shared:     {}", counts.shared);
+    println!("VmState:    {}", counts.vm_state);
+    println!("HypState:   {}", counts.hyp_state);
+    println!("Converting: {}", counts.converting);
+    println!("Blocked:    {}", counts.blocked);
+}
+
+fn dump_cpus() {
+    let cpu_info = CpuInfo::get();
+    let host = HOST_VM.get().map(|h| h.vm());
+    for i in 0..cpu_info.num_cpus() {
+        let cpu_id = CpuId::new(i);
+        // The host VM has a vCPU for each CPU, with the same ID.
+        let host_vcpu_status = host
+            .as_ref()
+            .and_then(|vm| vm.vcpu_statuses().find(|(id, _)| *id == i as u64))
+            .map(|(_, status)| status);
+        println!(
+            "CPU{}: hart {}, online: {}, host vCPU: {}",
+            i,
+            cpu_info.cpu_to_hart_id(cpu_id).unwrap(),
+            PerCpu::is_online(cpu_id),
+            vcpu_status_str(host_vcpu_status)
+        );
+    }
+}
+
+fn dump_tvms() {
+    let Some(host) = HOST_VM.get() else {
+        println!("Host VM not created yet");
+        return;
+    };
+    let mut count = 0;
+    host.vm().for_each_guest(|guest| {
+        let state = if guest.as_finalized_vm().is_some() {
+            "finalized"
+        } else {
+            "initializing"
+        };
+        let vm = guest.as_any_vm();
+        println!("TVM {}: {}", vm.page_owner_id().raw(), state);
+        for (id, status) in vm.vcpu_statuses() {
+            println!("  vCPU{}: {}", id, vcpu_status_str(Some(status)));
+        }
+        count += 1;
+    });
+    println!("{} TVM(s)", count);
+}
+
+fn dump_platform_log() {
+    let Some(log) = PlatformLog::get() else {
+        println!("Platform measurements not complete");
+        return;
+    };
+    for e in log.events() {
+        println!("{}", e);
+    }
+}
+
+fn vcpu_status_str(status: Option<VmCpuStatus>) -> &'static str {
+    match status {
+        Some(VmCpuStatus::PoweredOff) => "powered off",
+        Some(VmCpuStatus::Runnable) => "runnable",
+        Some(VmCpuStatus::Running) => "running",
+        None => "none",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_system::*;
+
+    #[test_case]
+    fn InputQueueTest() -> TestResult {
+        let mut queue = InputQueue::new();
+        test_result_true!(queue.pop().is_none(), "empty")?;
+        queue.push(b'a');
+        queue.push(b'b');
+        test_result_true!(
+            queue.pop() == Some(b'a') && queue.pop() == Some(b'b') && queue.pop().is_none(),
+            "in order"
+        )?;
+
+        // Wrap around the end of the buffer, dropping input once it's full.
+        for i in 0..HOST_INPUT_LEN + 1 {
+            queue.push(i as u8);
+        }
+        test_result_true!(queue.len == HOST_INPUT_LEN, "full")?;
+        for i in 0..HOST_INPUT_LEN {
+            test_result_true!(queue.pop() == Some(i as u8), "wrapped")?;
+        }
+        test_result_true!(queue.pop().is_none(), "overflow dropped")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,576 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A GDB remote serial protocol stub for debugging Salus itself over the system UART, for use where
+//! there's no QEMU gdbstub to lean on. Only built with the `gdbstub` feature.
+//!
+//! The stub is entered whenever Salus executes an EBREAK, either one compiled in (see
+//! `wait_for_debugger()`) or one planted by the debugger, and halts the other CPUs with an IPI
+//! while the debugger is in control. CPUs only take IPIs while idle or running a guest, so a CPU
+//! that's busy in the hypervisor keeps running until it next does one of those.
+//!
+//! Software breakpoints are implemented by patching EBREAK into the hypervisor image, which is
+//! mapped RWX. There's no single-step support; GDB single-steps RISC-V targets by planting
+//! temporary breakpoints. The UART is only polled while stopped, so a running hypervisor can't be
+//! interrupted from the debugger.
+
+use arrayvec::ArrayVec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use drivers::{uart::UartDriver, CpuId, CpuInfo};
+use riscv_regs::{GeneralPurposeRegisters, GprIndex};
+use s_mode_utils::print::*;
+use sync::Mutex;
+
+use crate::smp::{self, PerCpu};
+
+extern "C" {
+    // Fault-tolerant memcpy()s. Despite their names they work for any address mapped by the
+    // hypervisor page table.
+    fn _copy_to_user(dest_addr: u64, src: *const u8, len: usize) -> usize;
+    fn _copy_from_user(dest: *mut u8, src_addr: u64, len: usize) -> usize;
+}
+
+// The largest packet we accept or send. Advertised to GDB in reply to qSupported.
+const MAX_PACKET_LEN: usize = 4096;
+
+// The maximum number of software breakpoints that can be set at once.
+const MAX_BREAKPOINTS: usize = 32;
+
+// GDB's number for the PC register; x0-x31 are numbered 0-31.
+const PC_REGNUM: u64 = 32;
+const NUM_REGS: u64 = PC_REGNUM + 1;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+// How long to wait for the other CPUs to halt before giving up on them.
+const HALT_SPIN_LIMIT: usize = 10_000_000;
+
+// The stop reply we give GDB: stopped with SIGTRAP.
+const STOP_REPLY: &[u8] = b"S05";
+
+// Tells GDB that we only have the RV64 integer registers.
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target version="1.0"><architecture>riscv:rv64</architecture>"#,
+    r#"<feature name="org.gnu.gdb.riscv.cpu">"#,
+    r#"<reg name="zero" bitsize="64" type="int" regnum="0"/>"#,
+    r#"<reg name="ra" bitsize="64" type="code_ptr"/>"#,
+    r#"<reg name="sp" bitsize="64" type="data_ptr"/>"#,
+    r#"<reg name="gp" bitsize="64" type="data_ptr"/>"#,
+    r#"<reg name="tp" bitsize="64" type="data_ptr"/>"#,
+    r#"<reg name="t0" bitsize="64" type="int"/>"#,
+    r#"<reg name="t1" bitsize="64" type="int"/>"#,
+    r#"<reg name="t2" bitsize="64" type="int"/>"#,
+    r#"<reg name="fp" bitsize="64" type="data_ptr"/>"#,
+    r#"<reg name="s1" bitsize="64" type="int"/>"#,
+    r#"<reg name="a0" bitsize="64" type="int"/>"#,
+    r#"<reg name="a1" bitsize="64" type="int"/>"#,
+    r#"<reg name="a2" bitsize="64" type="int"/>"#,
+    r#"<reg name="a3" bitsize="64" type="int"/>"#,
+    r#"<reg name="a4" bitsize="64" type="int"/>"#,
+    r#"<reg name="a5" bitsize="64" type="int"/>"#,
+    r#"<reg name="a6" bitsize="64" type="int"/>"#,
+    r#"<reg name="a7" bitsize="64" type="int"/>"#,
+    r#"<reg name="s2" bitsize="64" type="int"/>"#,
+    r#"<reg name="s3" bitsize="64" type="int"/>"#,
+    r#"<reg name="s4" bitsize="64" type="int"/>"#,
+    r#"<reg name="s5" bitsize="64" type="int"/>"#,
+    r#"<reg name="s6" bitsize="64" type="int"/>"#,
+    r#"<reg name="s7" bitsize="64" type="int"/>"#,
+    r#"<reg name="s8" bitsize="64" type="int"/>"#,
+    r#"<reg name="s9" bitsize="64" type="int"/>"#,
+    r#"<reg name="s10" bitsize="64" type="int"/>"#,
+    r#"<reg name="s11" bitsize="64" type="int"/>"#,
+    r#"<reg name="t3" bitsize="64" type="int"/>"#,
+    r#"<reg name="t4" bitsize="64" type="int"/>"#,
+    r#"<reg name="t5" bitsize="64" type="int"/>"#,
+    r#"<reg name="t6" bitsize="64" type="int"/>"#,
+    r#"<reg name="pc" bitsize="64" type="code_ptr"/>"#,
+    r#"</feature></target>"#,
+);
+
+// Set while a CPU is in the debugger, halting the others.
+static HALTED: AtomicBool = AtomicBool::new(false);
+// The number of CPUs waiting in `park()`.
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+// Set once GDB has talked to us, and so expects to be told when we stop.
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+// A software breakpoint planted in the hypervisor's text.
+struct Breakpoint {
+    addr: u64,
+    // The instruction bytes replaced by the EBREAK.
+    orig: [u8; 4],
+    len: usize,
+}
+
+static BREAKPOINTS: Mutex<ArrayVec<Breakpoint, MAX_BREAKPOINTS>> =
+    Mutex::new(ArrayVec::new_const());
+
+type Response = ArrayVec<u8, MAX_PACKET_LEN>;
+
+// What to do after handling a packet.
+enum Action {
+    Reply,
+    Resume,
+    Detach,
+}
+
+/// Stops in the debugger and waits for GDB to attach over the UART.
+pub fn wait_for_debugger() {
+    println!("Salus: Waiting for GDB on the UART");
+    // Safety: EBREAK traps to `handle_breakpoint()`, which resumes after it.
+    unsafe { asm!("ebreak") };
+}
+
+/// Enters the debugger for an EBREAK taken by Salus at `sepc`, returning once the debugger resumes
+/// execution. Returns false if there's no UART to debug over.
+pub fn handle_breakpoint(gprs: &mut GeneralPurposeRegisters, sepc: &mut u64) -> bool {
+    let Some(uart) = UartDriver::get() else {
+        return false;
+    };
+    if HALTED.swap(true, Ordering::AcqRel) {
+        // Another CPU is already in the debugger. Wait along with the rest and take the breakpoint
+        // again once resumed if it's still there.
+        park();
+        return true;
+    }
+    if !BREAKPOINTS.lock().iter().any(|bp| bp.addr == *sepc) {
+        // Step over EBREAKs that aren't ours so we don't take them again when resumed.
+        *sepc += instruction_len(*sepc);
+    }
+    halt_other_cpus();
+
+    let conn = GdbConnection { uart };
+    if ATTACHED.load(Ordering::Acquire) {
+        conn.send_packet(STOP_REPLY);
+    }
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    let mut resp = Response::new();
+    loop {
+        let packet = conn.read_packet(&mut buf);
+        ATTACHED.store(true, Ordering::Release);
+        resp.clear();
+        match handle_packet(packet, &mut resp, gprs, sepc) {
+            Action::Reply => conn.send_packet(&resp),
+            Action::Resume => break,
+            Action::Detach => {
+                if !resp.is_empty() {
+                    conn.send_packet(&resp);
+                }
+                remove_all_breakpoints();
+                ATTACHED.store(false, Ordering::Release);
+                break;
+            }
+        }
+    }
+
+    // Make sure we and the CPUs we halted see any changes made to the text.
+    fence_i();
+    HALTED.store(false, Ordering::Release);
+    true
+}
+
+/// Halts this CPU if another CPU is in the debugger. Called on receipt of an IPI.
+pub fn handle_ipi() {
+    if HALTED.load(Ordering::Acquire) {
+        park();
+    }
+}
+
+// Waits until the CPU in the debugger resumes execution.
+fn park() {
+    PARKED.fetch_add(1, Ordering::AcqRel);
+    while HALTED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    PARKED.fetch_sub(1, Ordering::AcqRel);
+    // Breakpoints may have been added or removed while we were halted.
+    fence_i();
+}
+
+// Sends an IPI to every other CPU and waits a bounded time for them to park.
+fn halt_other_cpus() {
+    let this_cpu = PerCpu::this_cpu().cpu_id();
+    let num_cpus = CpuInfo::get().num_cpus();
+    for i in 0..num_cpus {
+        let cpu = CpuId::new(i);
+        if cpu != this_cpu {
+            smp::send_ipi(cpu);
+        }
+    }
+    let mut spins = 0;
+    while PARKED.load(Ordering::Acquire) < num_cpus - 1 && spins < HALT_SPIN_LIMIT {
+        core::hint::spin_loop();
+        spins += 1;
+    }
+    let parked = PARKED.load(Ordering::Acquire);
+    if parked < num_cpus - 1 {
+        println!(
+            "gdbstub: {} CPU(s) still running in the hypervisor",
+            num_cpus - 1 - parked
+        );
+    }
+}
+
+fn fence_i() {
+    // Safety: FENCE.I only orders instruction fetches.
+    unsafe { asm!("fence.i", options(nostack)) };
+}
+
+// Returns the length of the instruction at `pc`.
+fn instruction_len(pc: u64) -> u64 {
+    // Safety: `pc` is in the hypervisor's text, which is always mapped.
+    let low = unsafe { core::ptr::read_volatile(pc as *const u16) };
+    if low & 0x3 == 0x3 {
+        4
+    } else {
+        2
+    }
+}
+
+// Copies memory at `addr` into `buf`, returning the number of bytes read before faulting.
+fn read_memory(addr: u64, buf: &mut [u8]) -> usize {
+    // Safety: _copy_from_user writes at most `buf.len()` bytes to `buf` and recovers from faults
+    // on `addr`.
+    unsafe { _copy_from_user(buf.as_mut_ptr(), addr, buf.len()) }
+}
+
+// Copies `data` to memory at `addr`, returning the number of bytes written before faulting.
+fn write_memory(addr: u64, data: &[u8]) -> usize {
+    // Safety: _copy_to_user reads at most `data.len()` bytes from `data` and recovers from faults
+    // on `addr`. Writing arbitrary hypervisor memory is exactly what the debugger asked for.
+    unsafe { _copy_to_user(addr, data.as_ptr(), data.len()) }
+}
+
+// Plants a breakpoint of `len` bytes (2 for C.EBREAK, 4 for EBREAK) at `addr`.
+fn insert_breakpoint(addr: u64, len: usize) -> bool {
+    let ebreak = match len {
+        2 => C_EBREAK,
+        4 => EBREAK,
+        _ => return false,
+    };
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.iter().any(|bp| bp.addr == addr) {
+        return true;
+    }
+    if breakpoints.is_full() {
+        return false;
+    }
+    let mut orig = [0u8; 4];
+    if read_memory(addr, &mut orig[..len]) != len
+        || write_memory(addr, &ebreak.to_le_bytes()[..len]) != len
+    {
+        return false;
+    }
+    breakpoints.push(Breakpoint { addr, orig, len });
+    true
+}
+
+// Restores the original instruction at the breakpoint at `addr`.
+fn remove_breakpoint(addr: u64) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+    let Some(index) = breakpoints.iter().position(|bp| bp.addr == addr) else {
+        return false;
+    };
+    let bp = breakpoints.remove(index);
+    write_memory(bp.addr, &bp.orig[..bp.len]) == bp.len
+}
+
+fn remove_all_breakpoints() {
+    let mut breakpoints = BREAKPOINTS.lock();
+    for bp in breakpoints.drain(..) {
+        write_memory(bp.addr, &bp.orig[..bp.len]);
+    }
+}
+
+fn read_reg(gprs: &GeneralPurposeRegisters, sepc: u64, regnum: u64) -> Option<u64> {
+    match regnum {
+        PC_REGNUM => Some(sepc),
+        _ => GprIndex::from_raw(regnum.try_into().ok()?).map(|r| gprs.reg(r)),
+    }
+}
+
+fn write_reg(gprs: &mut GeneralPurposeRegisters, sepc: &mut u64, regnum: u64, val: u64) -> bool {
+    match regnum {
+        PC_REGNUM => *sepc = val,
+        _ => match regnum.try_into().ok().and_then(GprIndex::from_raw) {
+            Some(r) => gprs.set_reg(r, val),
+            None => return false,
+        },
+    }
+    true
+}
+
+// Handles a single packet from GDB, writing any reply to `resp`.
+fn handle_packet(
+    packet: &[u8],
+    resp: &mut Response,
+    gprs: &mut GeneralPurposeRegisters,
+    sepc: &mut u64,
+) -> Action {
+    let Some((&cmd, args)) = packet.split_first() else {
+        return Action::Reply;
+    };
+    match cmd {
+        b'?' => push_bytes(resp, STOP_REPLY),
+        b'g' => {
+            for regnum in 0..NUM_REGS {
+                // Unwrap ok, all register numbers below NUM_REGS are valid.
+                push_u64(resp, read_reg(gprs, *sepc, regnum).unwrap());
+            }
+        }
+        b'G' => {
+            let ok = args.len() == NUM_REGS as usize * 16
+                && args
+                    .chunks(16)
+                    .enumerate()
+                    .all(|(regnum, hex)| match parse_u64_le(hex) {
+                        Some(val) => write_reg(gprs, sepc, regnum as u64, val),
+                        None => false,
+                    });
+            push_result(resp, ok);
+        }
+        b'p' => match parse_hex(args).and_then(|regnum| read_reg(gprs, *sepc, regnum)) {
+            Some(val) => push_u64(resp, val),
+            None => push_bytes(resp, b"E01"),
+        },
+        b'P' => {
+            let ok = split_once(args, b'=')
+                .and_then(|(regnum, val)| Some((parse_hex(regnum)?, parse_u64_le(val)?)))
+                .is_some_and(|(regnum, val)| write_reg(gprs, sepc, regnum, val));
+            push_result(resp, ok);
+        }
+        b'm' => {
+            let Some((addr, len)) = parse_addr_len(args) else {
+                push_bytes(resp, b"E01");
+                return Action::Reply;
+            };
+            let mut buf = [0u8; MAX_PACKET_LEN / 2];
+            let len = (len as usize).min(buf.len());
+            let read = read_memory(addr, &mut buf[..len]);
+            if read == 0 && len != 0 {
+                push_bytes(resp, b"E14");
+            } else {
+                buf[..read].iter().for_each(|&b| push_hex_byte(resp, b));
+            }
+        }
+        b'M' => {
+            let mut buf = [0u8; MAX_PACKET_LEN / 2];
+            let ok = match split_once(args, b':') {
+                Some((addr_len, data)) => {
+                    match (parse_addr_len(addr_len), parse_hex_bytes(data, &mut buf)) {
+                        (Some((addr, len)), Some(data)) if data.len() as u64 == len => {
+                            write_memory(addr, data) == data.len()
+                        }
+                        _ => false,
+                    }
+                }
+                None => false,
+            };
+            // The debugger may have written code.
+            fence_i();
+            push_result(resp, ok);
+        }
+        b'Z' | b'z' => {
+            let ok = match split_once(args, b',') {
+                Some((b"0", bp)) => parse_addr_len(bp).is_some_and(|(addr, len)| {
+                    if cmd == b'Z' {
+                        insert_breakpoint(addr, len as usize)
+                    } else {
+                        remove_breakpoint(addr)
+                    }
+                }),
+                _ => {
+                    // Only software breakpoints are supported.
+                    return Action::Reply;
+                }
+            };
+            fence_i();
+            push_result(resp, ok);
+        }
+        b'c' => {
+            if !args.is_empty() {
+                match parse_hex(args) {
+                    Some(addr) => *sepc = addr,
+                    None => {
+                        push_bytes(resp, b"E01");
+                        return Action::Reply;
+                    }
+                }
+            }
+            return Action::Resume;
+        }
+        b'D' => {
+            push_bytes(resp, b"OK");
+            return Action::Detach;
+        }
+        // There's nothing to kill; just let Salus carry on without the debugger.
+        b'k' => return Action::Detach,
+        b'H' => push_bytes(resp, b"OK"),
+        b'q' => handle_query(args, resp),
+        _ => {}
+    }
+    Action::Reply
+}
+
+// Handles the general query `query` (less the leading 'q').
+fn handle_query(query: &[u8], resp: &mut Response) {
+    if query.starts_with(b"Supported") {
+        push_bytes(resp, b"PacketSize=1000;qXfer:features:read+");
+    } else if query == b"Attached" {
+        push_bytes(resp, b"1");
+    } else if let Some(args) = query.strip_prefix(b"Xfer:features:read:target.xml:") {
+        let Some((offset, len)) = parse_addr_len(args) else {
+            push_bytes(resp, b"E01");
+            return;
+        };
+        let xml = TARGET_XML.as_bytes();
+        let start = (offset as usize).min(xml.len());
+        let end = start
+            .saturating_add(len as usize)
+            .min(xml.len())
+            .min(start + MAX_PACKET_LEN - 1);
+        resp.push(if end == xml.len() { b'l' } else { b'm' });
+        push_bytes(resp, &xml[start..end]);
+    }
+}
+
+// A connection to GDB over the UART.
+struct GdbConnection {
+    uart: &'static UartDriver,
+}
+
+impl GdbConnection {
+    fn getc(&self) -> u8 {
+        loop {
+            if let Some(c) = self.uart.read_byte() {
+                return c;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // Reads the next well-formed packet into `buf`, acknowledging it, and returns its contents.
+    fn read_packet<'a>(&self, buf: &'a mut [u8]) -> &'a [u8] {
+        loop {
+            // Skip acks and interrupt requests up to the start of the packet.
+            while self.getc() != b'$' {}
+            let mut len = 0;
+            let mut checksum = 0u8;
+            let mut overflow = false;
+            loop {
+                let c = self.getc();
+                if c == b'#' {
+                    break;
+                }
+                checksum = checksum.wrapping_add(c);
+                if len < buf.len() {
+                    buf[len] = c;
+                    len += 1;
+                } else {
+                    overflow = true;
+                }
+            }
+            let expected = [self.getc(), self.getc()];
+            if !overflow && parse_hex(&expected) == Some(checksum as u64) {
+                self.uart.write_bytes(b"+");
+                return &buf[..len];
+            }
+            self.uart.write_bytes(b"-");
+        }
+    }
+
+    // Sends `data` as a packet, retransmitting until GDB acknowledges it.
+    fn send_packet(&self, data: &[u8]) {
+        let checksum = data.iter().fold(0u8, |sum, &c| sum.wrapping_add(c));
+        loop {
+            self.uart.write_bytes(b"$");
+            self.uart.write_bytes(data);
+            self.uart.write_bytes(b"#");
+            self.uart
+                .write_bytes(&[hex_char(checksum >> 4), hex_char(checksum)]);
+            loop {
+                match self.getc() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn hex_char(nibble: u8) -> u8 {
+    b"0123456789abcdef"[(nibble & 0xf) as usize]
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+// Parses a big-endian hex number as used for addresses, lengths, and register numbers.
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |val, &c| Some((val << 4) | hex_digit(c)? as u64))
+}
+
+// Parses hex-encoded bytes from `s` into `buf`, returning the bytes parsed.
+fn parse_hex_bytes<'a>(s: &[u8], buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    if s.len() % 2 != 0 || s.len() / 2 > buf.len() {
+        return None;
+    }
+    for (byte, hex) in buf.iter_mut().zip(s.chunks(2)) {
+        *byte = (hex_digit(hex[0])? << 4) | hex_digit(hex[1])?;
+    }
+    Some(&buf[..s.len() / 2])
+}
+
+// Parses a register value, which GDB sends as target-endian bytes.
+fn parse_u64_le(s: &[u8]) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    if s.len() != 16 {
+        return None;
+    }
+    parse_hex_bytes(s, &mut bytes)?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+// Parses an "addr,length" pair.
+fn parse_addr_len(s: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split_once(s, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn split_once(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let index = s.iter().position(|&c| c == sep)?;
+    Some((&s[..index], &s[index + 1..]))
+}
+
+fn push_bytes(resp: &mut Response, bytes: &[u8]) {
+    // Replies are bounded well below MAX_PACKET_LEN, so this never truncates in practice.
+    let len = bytes.len().min(resp.remaining_capacity());
+    // Unwrap ok, we've limited `len` to the remaining capacity.
+    resp.try_extend_from_slice(&bytes[..len]).unwrap();
+}
+
+fn push_hex_byte(resp: &mut Response, byte: u8) {
+    push_bytes(resp, &[hex_char(byte >> 4), hex_char(byte)]);
+}
+
+fn push_u64(resp: &mut Response, val: u64) {
+    val.to_le_bytes()
+        .iter()
+        .for_each(|&b| push_hex_byte(resp, b));
+}
+
+fn push_result(resp: &mut Response, ok: bool) {
+    let reply: &[u8] = if ok { b"OK" } else { b"E01" };
+    push_bytes(resp, reply);
+}
@@ -10,6 +10,7 @@ use riscv_pages::{InternalClean, PageOwnerId, SequentialPages};
 use sync::{Mutex, RwLock, RwLockReadGuard};
 
 use crate::vm::{AnyVm, FinalizedVm, InitializingVm, Vm, VmRef};
+use crate::vm_manifest::SignedManifest;
 
 /// Guest tracking-related errors.
 #[derive(Debug)]
@@ -47,12 +48,17 @@ impl<T: GuestStagePagingMode> GuestVmInner<T> {
     }
 
     // Converts `self` from an initializing VM to a finalized VM.
-    fn finalize(&mut self, entry_sepc: u64, entry_arg: u64) -> Result<()> {
+    fn finalize(
+        &mut self,
+        entry_sepc: u64,
+        entry_arg: u64,
+        signed_manifest: Option<&SignedManifest>,
+    ) -> Result<()> {
         if self.state != GuestState::Init {
             return Err(Error::GuestNotInitializing);
         }
         self.vm
-            .finalize(entry_sepc, entry_arg)
+            .finalize(entry_sepc, entry_arg, signed_manifest)
             .map_err(Error::VmFinalizeFailed)?;
         self.state = GuestState::Running;
         Ok(())
@@ -139,14 +145,20 @@ impl<T: GuestStagePagingMode> GuestVm<T> {
         self.inner.read().vm.page_owner_id()
     }
 
-    /// Converts the guest from the initializing to the finalized state.
-    pub fn finalize(&self, entry_sepc: u64, entry_arg: u64) -> Result<()> {
+    /// Converts the guest from the initializing to the finalized state. If `signed_manifest` is
+    /// given, the guest's measurements must match it.
+    pub fn finalize(
+        &self,
+        entry_sepc: u64,
+        entry_arg: u64,
+        signed_manifest: Option<&SignedManifest>,
+    ) -> Result<()> {
         // Use try_write() here since there shouldn't be any outstanding references to a VM that
         // we're attempting to finalize. This prevents us from blocking on a potentially
         // long-running operation on a VM that isn't even in the proper state (e.g. a finalized
         // VM that's running a vCPU).
         let mut inner = self.inner.try_write().ok_or(Error::GuestInUse)?;
-        inner.finalize(entry_sepc, entry_arg)
+        inner.finalize(entry_sepc, entry_arg, signed_manifest)
     }
 }
 
@@ -179,6 +191,21 @@ impl<T: GuestStagePagingMode> Guests<T> {
         guests.iter().find(|g| g.page_owner_id() == id).cloned()
     }
 
+    /// Calls `f` for each guest in this tracking table.
+    pub fn for_each<F: FnMut(&GuestVm<T>)>(&self, f: F) {
+        self.guests.lock().iter().for_each(f);
+    }
+
+    /// Calls `f` for each guest in this tracking table, unless the table is locked. Returns false
+    /// if it was.
+    pub fn try_for_each<F: FnMut(&GuestVm<T>)>(&self, f: F) -> bool {
+        let Some(guests) = self.guests.try_lock() else {
+            return false;
+        };
+        guests.iter().for_each(f);
+        true
+    }
+
     /// Removes the guest with the given ID if there are no outstanding references to it.
     pub fn remove(&self, id: PageOwnerId) -> Result<()> {
         // Pull the last reference to this guest out of the vector first so we don't do the final
@@ -0,0 +1,482 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Loading of the host VM's kernel from either a flat RISC-V Linux `Image` or an ELF file.
+
+use core::{fmt, slice};
+use page_tracking::{HwMemRegion, HypPageAlloc};
+use riscv_elf::{ElfMap, Error as ElfError};
+use riscv_pages::*;
+use s_mode_utils::print::*;
+
+use crate::host_vm::HOST_VM_ALIGN;
+use crate::linux_image::{Error as LinuxImageError, LinuxImageHeader};
+
+// Where ELF kernels without an `Image` header are placed in RAM, unless they're linked at a physical
+// address in the host's RAM. This is where Linux expects to be loaded on RV64.
+const DEFAULT_KERNEL_OFFSET: u64 = 0x20_0000;
+
+// ELF kernels linked at or above this address are virtually addressed.
+const KERNEL_VIRT_START: u64 = 1 << 63;
+
+// Kernels must end below this offset in RAM; no guest physical address space we support is larger.
+const MAX_KERNEL_END: u64 = 1 << 50;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// Reasons the host kernel can't be loaded.
+#[derive(Debug)]
+pub enum Error {
+    /// The kernel is a flat image with an invalid header.
+    Image(LinuxImageError),
+    /// The kernel is an ELF that couldn't be parsed.
+    Elf(ElfError),
+    /// The ELF has no loadable segments.
+    NoSegments,
+    /// The ELF's entry point isn't in any of its segments.
+    EntryOutOfRange(u64),
+    /// The kernel's offset from the start of RAM isn't `HOST_VM_ALIGN`-aligned.
+    OffsetUnaligned(u64),
+    /// The kernel extends beyond any guest physical address space.
+    TooLarge,
+    /// The kernel extends beyond the host VM's RAM, or there aren't enough free pages to load it.
+    InsufficientMemory { required: u64, available: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Image(e) => write!(f, "Invalid kernel image: {}", e),
+            Elf(e) => write!(f, "Invalid kernel ELF: {:?}", e),
+            NoSegments => write!(f, "Kernel ELF has no loadable segments"),
+            EntryOutOfRange(entry) => write!(f, "Kernel entry point 0x{:x} isn't loaded", entry),
+            OffsetUnaligned(offset) => write!(f, "Kernel offset 0x{:x} is not aligned", offset),
+            TooLarge => write!(f, "Kernel offset or size is too large"),
+            InsufficientMemory {
+                required,
+                available,
+            } => write!(
+                f,
+                "Kernel needs 0x{:x} bytes of memory but only 0x{:x} are available",
+                required, available
+            ),
+        }
+    }
+}
+
+/// The host VM's kernel, ready to be mapped into its address space.
+pub struct HostKernel {
+    pages: SequentialPages<ConvertedInitialized>,
+    image_pages: Option<SequentialPages<ConvertedClean>>,
+    offset: u64,
+    entry_offset: u64,
+    footprint: u64,
+}
+
+impl HostKernel {
+    /// Loads the kernel found in `region` for a host VM whose RAM starts at `ram_base` and is at most
+    /// `ram_size` bytes. Flat `Image`s, which include EFI-stub kernels since they're booted through
+    /// their `Image` entry point, are mapped in place. ELF kernels have their segments copied to
+    /// pages taken from `page_alloc`, after which the pages holding the ELF file are cleared and
+    /// handed back through `take_image_pages()`.
+    pub fn load(
+        region: HwMemRegion,
+        ram_base: u64,
+        ram_size: u64,
+        page_alloc: &mut HypPageAlloc,
+    ) -> Result<Self, Error> {
+        // Safe since the kernel image was reserved in the memory map at boot, and we're still
+        // running with a 1:1 map of all physical memory.
+        let image = unsafe {
+            slice::from_raw_parts(
+                region.base().bits() as *const u8,
+                region.size().try_into().unwrap(),
+            )
+        };
+        if image.starts_with(ELF_MAGIC) {
+            Self::load_elf(region, image, ram_base, ram_size, page_alloc)
+        } else {
+            Self::load_flat(region, image, ram_size)
+        }
+    }
+
+    fn load_flat(region: HwMemRegion, image: &[u8], ram_size: u64) -> Result<Self, Error> {
+        let header = LinuxImageHeader::parse(image).map_err(Error::Image)?;
+        let (major, minor) = header.version();
+        if log_enabled(LogLevel::Info) {
+            println!(
+                "Host kernel: Image v{}.{}, text offset 0x{:x}, size 0x{:x}",
+                major,
+                minor,
+                header.text_offset(),
+                header.image_size()
+            );
+        }
+
+        // The kernel uses `image_size` bytes once its BSS is included, which may be more than the
+        // image we were given.
+        let footprint = header.image_size().max(region.size());
+        check_placement(header.text_offset(), footprint, ram_size)?;
+
+        let pages = unsafe {
+            // Safe because HwMemMap reserved this region.
+            SequentialPages::from_mem_range(
+                region.base(),
+                PageSize::Size4k,
+                region.size() / PageSize::Size4k as u64,
+            )
+            .unwrap()
+        };
+        Ok(Self {
+            pages,
+            image_pages: None,
+            offset: header.text_offset(),
+            entry_offset: 0,
+            footprint,
+        })
+    }
+
+    fn load_elf(
+        region: HwMemRegion,
+        image: &[u8],
+        ram_base: u64,
+        ram_size: u64,
+        page_alloc: &mut HypPageAlloc,
+    ) -> Result<Self, Error> {
+        let elf = ElfMap::new(image).map_err(Error::Elf)?;
+        let layout = ElfLayout::new(&elf, ram_base, ram_size)?;
+        if log_enabled(LogLevel::Info) {
+            println!(
+                "Host kernel: ELF with {} segment(s) at 0x{:x}-0x{:x}, entry 0x{:x}",
+                elf.segments().count(),
+                layout.base,
+                layout.end,
+                elf.entry()
+            );
+        }
+
+        // Copy the segments to fresh (and so zeroed) pages, laid out as they were linked.
+        let num_pages = layout.load_size / PageSize::Size4k as u64;
+        if !page_alloc.can_take_pages(num_pages as usize, HOST_VM_ALIGN as u64) {
+            return Err(Error::InsufficientMemory {
+                required: layout.load_size,
+                available: page_alloc.free_pages() * PageSize::Size4k as u64,
+            });
+        }
+        let pages = page_alloc.take_pages(num_pages as usize, HOST_VM_ALIGN as u64);
+        // Safe because we own these pages, and they're mapped 1:1.
+        let dest = unsafe {
+            slice::from_raw_parts_mut(
+                pages.base().bits() as *mut u8,
+                pages.length_bytes().try_into().unwrap(),
+            )
+        };
+        for s in elf.segments() {
+            if let Some(data) = s.data() {
+                let len = data.len().min(s.size());
+                let start = (s.vaddr() - layout.base) as usize;
+                dest[start..start + len].copy_from_slice(&data[..len]);
+            }
+        }
+        let pages = SequentialPages::from_pages(pages.into_iter().map(|p| p.to_initialized_page()))
+            .unwrap();
+
+        // The ELF file isn't needed once its segments are copied out. Clear the pages holding it so
+        // that they can be given to the host VM as RAM.
+        let image_pages: SequentialPages<ConvertedDirty> = unsafe {
+            // Safe because HwMemMap reserved this region, and neither `elf` nor `image` are used
+            // from here on.
+            SequentialPages::from_mem_range(
+                region.base(),
+                PageSize::Size4k,
+                region.size() / PageSize::Size4k as u64,
+            )
+            .unwrap()
+        };
+        Ok(Self {
+            pages,
+            image_pages: Some(image_pages.clean()),
+            offset: layout.offset,
+            entry_offset: layout.entry_offset,
+            footprint: layout.footprint,
+        })
+    }
+
+    /// Returns the offset from the start of RAM at which the kernel is loaded.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the offset of the kernel's entry point from where it's loaded.
+    pub fn entry_offset(&self) -> u64 {
+        self.entry_offset
+    }
+
+    /// Returns the size of the region the kernel uses, starting from where it's loaded. This may
+    /// extend beyond the pages holding the kernel, e.g. for its BSS.
+    pub fn footprint(&self) -> u64 {
+        self.footprint
+    }
+
+    /// Returns the size in bytes of the pages holding the kernel.
+    pub fn length_bytes(&self) -> u64 {
+        self.pages.length_bytes()
+    }
+
+    /// Takes the pages that held an ELF kernel's file. They're cleared, and no longer needed once the
+    /// kernel's segments have been copied out.
+    pub fn take_image_pages(&mut self) -> Option<SequentialPages<ConvertedClean>> {
+        self.image_pages.take()
+    }
+
+    /// Consumes the kernel, returning the pages to map at its load offset.
+    pub fn into_pages(self) -> SequentialPages<ConvertedInitialized> {
+        self.pages
+    }
+}
+
+// Checks that a kernel placed `offset` bytes into RAM and using `footprint` bytes from there can be
+// mapped into a host VM with at most `ram_size` bytes of RAM.
+fn check_placement(offset: u64, footprint: u64, ram_size: u64) -> Result<(), Error> {
+    if !HOST_VM_ALIGN.is_aligned(offset) {
+        return Err(Error::OffsetUnaligned(offset));
+    }
+    let end = offset
+        .checked_add(footprint)
+        .filter(|&end| end <= MAX_KERNEL_END)
+        .ok_or(Error::TooLarge)?;
+    if end > ram_size {
+        return Err(Error::InsufficientMemory {
+            required: end,
+            available: ram_size,
+        });
+    }
+    Ok(())
+}
+
+// Where an ELF kernel's segments are placed in RAM.
+struct ElfLayout {
+    // The range of link addresses covered by the segments, starting on a page boundary.
+    base: u64,
+    end: u64,
+    // The size of the pages the segments are copied to.
+    load_size: u64,
+    offset: u64,
+    entry_offset: u64,
+    footprint: u64,
+}
+
+impl ElfLayout {
+    // Lays out `elf` in a host VM whose RAM starts at `ram_base` and is at most `ram_size` bytes,
+    // checking that it fits.
+    fn new(elf: &ElfMap, ram_base: u64, ram_size: u64) -> Result<Self, Error> {
+        let first = elf
+            .segments()
+            .min_by_key(|s| s.vaddr())
+            .ok_or(Error::NoSegments)?;
+        let base = PageSize::Size4k.round_down(first.vaddr());
+        // Unwrap ok: there's at least one segment, and `ElfMap` checks that they don't overflow.
+        let end = elf
+            .segments()
+            .map(|s| s.vaddr() + s.size() as u64)
+            .max()
+            .unwrap();
+        if !(base..end).contains(&elf.entry()) {
+            return Err(Error::EntryOutOfRange(elf.entry()));
+        }
+
+        // vmlinux starts with the `Image` header, which says where it should be loaded. Otherwise
+        // load the kernel at its link address if that's a physical address, or the default offset.
+        let header = first
+            .data()
+            .and_then(|data| LinuxImageHeader::parse(data).ok());
+        let offset = match header {
+            Some(h) => h.text_offset(),
+            None if (ram_base..KERNEL_VIRT_START).contains(&base) => base - ram_base,
+            None => DEFAULT_KERNEL_OFFSET,
+        };
+        if end - base > MAX_KERNEL_END {
+            return Err(Error::TooLarge);
+        }
+        let load_size = HOST_VM_ALIGN.round_up(end - base);
+        let footprint = header.map(|h| h.image_size()).unwrap_or(0).max(load_size);
+        check_placement(offset, footprint, ram_size)?;
+
+        Ok(Self {
+            base,
+            end,
+            load_size,
+            offset,
+            entry_offset: elf.entry() - base,
+            footprint,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    const RAM_BASE: u64 = 0x8000_0000;
+    const RAM_SIZE: u64 = 0x4000_0000;
+    const PH_OFFSET: usize = 64;
+    const DATA_OFFSET: usize = 0x100;
+
+    // `ElfMap` reads the headers in place, so they must be suitably aligned.
+    #[repr(C, align(8))]
+    struct ElfBytes([u8; 0x200]);
+
+    // Returns an ELF file with a single RWX segment of `size` bytes linked at `vaddr`, whose data
+    // is `data`.
+    fn build_elf(vaddr: u64, size: u64, entry: u64, data: &[u8]) -> ElfBytes {
+        let mut bytes = ElfBytes([0u8; 0x200]);
+        let mut put = |offset: usize, value: &[u8]| {
+            bytes.0[offset..offset + value.len()].copy_from_slice(value)
+        };
+        // ELF header: 64-bit, little-endian, version 1, executable, RISC-V.
+        put(0, b"\x7fELF\x02\x01\x01");
+        put(16, &2u16.to_le_bytes());
+        put(18, &243u16.to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+        put(24, &entry.to_le_bytes());
+        put(32, &(PH_OFFSET as u64).to_le_bytes());
+        put(52, &64u16.to_le_bytes());
+        put(54, &56u16.to_le_bytes());
+        put(56, &1u16.to_le_bytes());
+        // PT_LOAD, PF_R | PF_W | PF_X.
+        put(PH_OFFSET, &1u32.to_le_bytes());
+        put(PH_OFFSET + 4, &7u32.to_le_bytes());
+        put(PH_OFFSET + 8, &(DATA_OFFSET as u64).to_le_bytes());
+        put(PH_OFFSET + 16, &vaddr.to_le_bytes());
+        put(PH_OFFSET + 32, &(data.len() as u64).to_le_bytes());
+        put(PH_OFFSET + 40, &size.to_le_bytes());
+        put(DATA_OFFSET, data);
+        bytes
+    }
+
+    // Returns an `Image` header asking to be loaded at `text_offset` and using `image_size` bytes.
+    fn image_header(text_offset: u64, image_size: u64) -> [u8; 64] {
+        let mut header = [0u8; 64];
+        header[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        header[16..24].copy_from_slice(&image_size.to_le_bytes());
+        header[56..60].copy_from_slice(b"RSC\x05");
+        header
+    }
+
+    #[test_case]
+    fn HostKernelElfLayoutTest() -> TestResult {
+        // Linked at a physical address in RAM, and loaded there.
+        let bytes = build_elf(RAM_BASE + 0x40_0000, 0x1234, RAM_BASE + 0x40_0010, &[]);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new physical");
+        let layout = ElfLayout::new(&elf, RAM_BASE, RAM_SIZE).expect("ElfLayout::new physical");
+        test_result_true!(
+            layout.offset == 0x40_0000
+                && layout.entry_offset == 0x10
+                && layout.load_size == HOST_VM_ALIGN as u64
+                && layout.footprint == HOST_VM_ALIGN as u64,
+            "ElfLayout physical"
+        )?;
+
+        // Linked at a virtual address, without an `Image` header.
+        let vaddr = 0xffff_ffff_8000_0000;
+        let bytes = build_elf(vaddr, 0x1000, vaddr, &[]);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new virtual");
+        let layout = ElfLayout::new(&elf, RAM_BASE, RAM_SIZE).expect("ElfLayout::new virtual");
+        test_result_true!(
+            layout.offset == DEFAULT_KERNEL_OFFSET && layout.entry_offset == 0,
+            "ElfLayout virtual"
+        )?;
+
+        // vmlinux, whose `Image` header gives the load offset and a footprint including its BSS.
+        let header = image_header(0x60_0000, 0x50_0000);
+        let bytes = build_elf(vaddr, 0x1000, vaddr + 0x20, &header);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new vmlinux");
+        let layout = ElfLayout::new(&elf, RAM_BASE, RAM_SIZE).expect("ElfLayout::new vmlinux");
+        test_result_true!(
+            layout.offset == 0x60_0000
+                && layout.entry_offset == 0x20
+                && layout.load_size == HOST_VM_ALIGN as u64
+                && layout.footprint == 0x50_0000,
+            "ElfLayout vmlinux"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn HostKernelElfLayoutErrorsTest() -> TestResult {
+        let bytes = build_elf(RAM_BASE + 0x40_0000, 0x1000, RAM_BASE, &[]);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new bad entry");
+        test_result_true!(
+            matches!(
+                ElfLayout::new(&elf, RAM_BASE, RAM_SIZE),
+                Err(Error::EntryOutOfRange(RAM_BASE))
+            ),
+            "ElfLayout entry out of range"
+        )?;
+
+        let bytes = build_elf(RAM_BASE + 0x1000, 0x1000, RAM_BASE + 0x1000, &[]);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new unaligned");
+        test_result_true!(
+            matches!(
+                ElfLayout::new(&elf, RAM_BASE, RAM_SIZE),
+                Err(Error::OffsetUnaligned(0x1000))
+            ),
+            "ElfLayout unaligned"
+        )?;
+
+        // Too large to ever be loaded, which must be caught before allocating pages for it.
+        let bytes = build_elf(RAM_BASE, MAX_KERNEL_END + 0x1000, RAM_BASE, &[]);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new too large");
+        test_result_true!(
+            matches!(
+                ElfLayout::new(&elf, RAM_BASE, RAM_SIZE),
+                Err(Error::TooLarge)
+            ),
+            "ElfLayout too large"
+        )?;
+        let bytes = build_elf(
+            RAM_BASE + 0x20_0000,
+            MAX_KERNEL_END - 0x10_0000,
+            RAM_BASE + 0x20_0000,
+            &[],
+        );
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new too large once placed");
+        test_result_true!(
+            matches!(
+                ElfLayout::new(&elf, RAM_BASE, RAM_SIZE),
+                Err(Error::TooLarge)
+            ),
+            "ElfLayout too large once placed"
+        )?;
+
+        // Fits in the guest physical address space, but not in the host's RAM.
+        let bytes = build_elf(RAM_BASE, RAM_SIZE + 0x1000, RAM_BASE, &[]);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new larger than RAM");
+        test_result_true!(
+            matches!(
+                ElfLayout::new(&elf, RAM_BASE, RAM_SIZE),
+                Err(Error::InsufficientMemory {
+                    required,
+                    available: RAM_SIZE,
+                }) if required == RAM_SIZE + HOST_VM_ALIGN as u64
+            ),
+            "ElfLayout larger than RAM"
+        )?;
+        let header = image_header(0x20_0000, RAM_SIZE);
+        let vaddr = 0xffff_ffff_8000_0000;
+        let bytes = build_elf(vaddr, 0x1000, vaddr, &header);
+        let elf = ElfMap::new(&bytes.0).expect("ElfMap::new BSS larger than RAM");
+        test_result_true!(
+            matches!(
+                ElfLayout::new(&elf, RAM_BASE, RAM_SIZE),
+                Err(Error::InsufficientMemory { .. })
+            ),
+            "ElfLayout BSS larger than RAM"
+        )?;
+        Ok(())
+    }
+}
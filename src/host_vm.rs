@@ -2,11 +2,11 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use alloc::string::String;
 use arrayvec::{ArrayString, ArrayVec};
 use core::{fmt, num, ops::ControlFlow, slice};
 use device_tree::{DeviceTree, DeviceTreeResult, DeviceTreeSerializer};
 use drivers::{imsic::*, iommu::*, pci::*, CpuId, CpuInfo};
-use page_tracking::collections::PageBox;
 use page_tracking::{HwMemRegion, HypPageAlloc, PageList, PageTracker};
 use riscv_page_tables::{GuestStagePageTable, GuestStagePagingMode};
 use riscv_pages::*;
@@ -18,22 +18,71 @@ use s_mode_utils::print::*;
 use sbi_rs::{self, DebugConsoleFunction, Error as SbiError, SbiMessage, SbiReturn, StateFunction};
 
 use crate::guest_tracking::{GuestVm, Guests, Result as GuestTrackingResult};
+use crate::host_kernel::{Error as HostKernelError, HostKernel};
+use crate::platform_log::{PlatformEventType, PlatformLog};
 use crate::smp;
 use crate::vm::{FinalizedVm, Vm};
 use crate::vm_cpu::{VmCpu, VmCpuExitReporting, VmCpuParent, VmCpus};
+use crate::vm_interrupts;
 use crate::vm_pages::VmPages;
 
 // Page size of host VM pages.
 pub const HOST_VM_ALIGN: PageSize = PageSize::Size2M;
 
-// Where the kernel, initramfs, and FDT will be located in the guest physical address space.
-// All these offsets must be `HOST_VM_ALIGN`-aligned.
-//
-// TODO: Kernel offset should be pulled from the header in the kernel image.
-const KERNEL_OFFSET: u64 = 0x20_0000;
-const INITRAMFS_OFFSET: u64 = KERNEL_OFFSET + 0x800_0000;
-// Assuming RAM base at 2GB, ends up at 3GB - 16MB which is consistent with QEMU.
-const FDT_OFFSET: u64 = 0x3f00_0000;
+/// Errors from loading the host VM.
+#[derive(Debug)]
+pub enum Error {
+    /// The kernel couldn't be loaded.
+    Kernel(HostKernelError),
+    /// The kernel, initramfs and FDT don't fit in the host VM's memory.
+    InsufficientMemory { required: u64, available: u64 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Kernel(e) => write!(f, "Failed to load kernel: {}", e),
+            InsufficientMemory {
+                required,
+                available,
+            } => write!(
+                f,
+                "Host images need 0x{:x} bytes of memory but only 0x{:x} are available",
+                required, available
+            ),
+        }
+    }
+}
+
+// Where the kernel, initramfs, and FDT are located in the guest physical address space, as offsets
+// from the start of RAM. All these offsets are `HOST_VM_ALIGN`-aligned.
+#[derive(Clone, Copy, Debug)]
+struct HostImageLayout {
+    kernel_offset: u64,
+    kernel_entry: u64,
+    initramfs_offset: u64,
+    fdt_offset: u64,
+    // The end of the FDT, i.e. the minimum size of RAM.
+    end: u64,
+}
+
+impl HostImageLayout {
+    // Lays out the kernel where it asks to be loaded, followed by the initramfs and the FDT.
+    fn new(kernel: &HostKernel, initramfs: Option<&HwMemRegion>, fdt_size: u64) -> Self {
+        // Don't place anything else in the range the kernel will use.
+        let kernel_end = HOST_VM_ALIGN.round_up(kernel.offset() + kernel.footprint());
+        let initramfs_offset = kernel_end;
+        let fdt_offset = initramfs_offset + initramfs.map(|r| r.size()).unwrap_or(0);
+        Self {
+            kernel_offset: kernel.offset(),
+            kernel_entry: kernel.offset() + kernel.entry_offset(),
+            initramfs_offset,
+            fdt_offset,
+            end: fdt_offset + fdt_size,
+        }
+    }
+}
 
 // A builder for the host VM's device-tree. Starting with the hypervisor's device-tree, makes the
 // necessary modifications to create a device-tree that reflects the hardware available to the
@@ -53,12 +102,31 @@ impl HostDtBuilder {
         // Clone the properties of the root node as-is.
         host_root.set_props(hyp_root.props().cloned())?;
 
-        // Add a 'chosen' node and copy the bootargs if they exist.
+        // Add a 'chosen' node with the bootargs, if any. `salus,host-bootargs` replaces the
+        // bootargs given to us, and `salus,host-bootargs-append` is added to the end of them.
         let host_chosen_id = host_dt.add_node("chosen", Some(host_root_id))?;
         let host_chosen = host_dt.get_mut_node(host_chosen_id).unwrap();
         if let Some(hyp_chosen) = hyp_dt.iter().find(|n| n.name() == "chosen") {
-            if let Some(p) = hyp_chosen.props().find(|p| p.name() == "bootargs") {
-                host_chosen.insert_prop(p.clone())?;
+            let prop_str = |name: &str| {
+                hyp_chosen
+                    .props()
+                    .find(|p| p.name() == name)
+                    .and_then(|p| p.value_str())
+            };
+            let bootargs = prop_str("salus,host-bootargs").or_else(|| prop_str("bootargs"));
+            let append = prop_str("salus,host-bootargs-append");
+            let mut host_bootargs = String::new();
+            for args in [bootargs, append].into_iter().flatten() {
+                host_bootargs.try_reserve(args.len() + 1)?;
+                if !host_bootargs.is_empty() {
+                    host_bootargs.push(' ');
+                }
+                host_bootargs.push_str(args);
+            }
+            if !host_bootargs.is_empty() {
+                host_chosen
+                    .add_prop("bootargs")?
+                    .set_value_str(&host_bootargs)?;
             }
         }
 
@@ -103,7 +171,7 @@ impl HostDtBuilder {
         soc_node.add_prop("ranges")?;
 
         Imsic::get().add_host_imsic_node(&mut self.tree)?;
-        PcieRoot::get().add_host_pcie_node(&mut self.tree)?;
+        PcieRoots::get().add_host_pcie_nodes(&mut self.tree)?;
 
         Ok(self)
     }
@@ -150,26 +218,32 @@ enum FdtPages {
 /// a contiguous `HOST_VM_ALIGN` block of the host physical address space.
 pub struct HostVmLoader<T: GuestStagePagingMode> {
     hypervisor_dt: DeviceTree,
-    kernel: HwMemRegion,
+    kernel: HostKernel,
     initramfs: Option<HwMemRegion>,
     vm: HostVm<T>,
     fdt_pages: FdtPages,
     zero_pages: PageList<Page<ConvertedClean>>,
     guest_ram_base: GuestPageAddr,
     ram_size: u64,
+    layout: HostImageLayout,
 }
 
 impl<T: GuestStagePagingMode> HostVmLoader<T> {
     /// Creates a new loader with the given device-tree and kernel & initramfs images. Uses
-    /// `page_alloc` to allocate any additional pages that are necessary to load the VM.
+    /// `page_alloc` to allocate any additional pages that are necessary to load the VM, including
+    /// those used to track up to `max_tvms` TVMs, and sets aside `runtime_heap_pages` pages for the
+    /// hypervisor's heap. Fails if the kernel image isn't a valid RISC-V Linux `Image` or if the
+    /// images don't fit in the host VM's memory.
     pub fn new(
         hypervisor_dt: DeviceTree,
         kernel: HwMemRegion,
         initramfs: Option<HwMemRegion>,
         guest_ram_base: GuestPageAddr,
         guest_phys_size: u64,
+        max_tvms: usize,
+        runtime_heap_pages: usize,
         mut page_alloc: HypPageAlloc,
-    ) -> Self {
+    ) -> Result<Self, Error> {
         // Reserve a contiguous chunk for the host's FDT. We assume it will be no bigger than the
         // size of the hypervisor's FDT and we align it to `HOST_VM_ALIGN` to maintain the
         // contiguous mapping guarantee from GPA -> HPA mentioned above.
@@ -180,18 +254,43 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
         let num_fdt_pages = HOST_VM_ALIGN.round_up(fdt_size) / PageSize::Size4k as u64;
         let fdt_pages =
             page_alloc.take_pages(num_fdt_pages.try_into().unwrap(), HOST_VM_ALIGN as u64);
-
-        let (zero_pages, vm) = HostVm::from_hyp_mem(page_alloc, guest_phys_size);
+        // The host's RAM can't be any larger than the memory that's still free plus the pages
+        // holding its images, so reject kernels that wouldn't fit before allocating for them.
+        let max_ram_size = page_alloc.free_pages() * PageSize::Size4k as u64
+            + fdt_pages.length_bytes()
+            + kernel.size()
+            + initramfs.map(|r| r.size()).unwrap_or(0);
+        let mut kernel =
+            HostKernel::load(kernel, guest_ram_base.bits(), max_ram_size, &mut page_alloc)
+                .map_err(Error::Kernel)?;
+        let layout = HostImageLayout::new(&kernel, initramfs.as_ref(), fdt_pages.length_bytes());
+
+        let (mut zero_pages, vm) =
+            HostVm::from_hyp_mem(page_alloc, guest_phys_size, max_tvms, runtime_heap_pages);
+        // The pages an ELF kernel was copied from are now free, so give them to the host as RAM.
+        // They're a `HOST_VM_ALIGN`-aligned block, as are the host's other pages, so appending them
+        // keeps the GPA -> HPA mapping contiguous within each block.
+        if let Some(pages) = kernel.take_image_pages() {
+            for p in pages {
+                // Unwrap ok: the pages were reserved for the kernel and aren't in any other list.
+                zero_pages.push(p).unwrap();
+            }
+        }
 
         // Now that the hypervisor is done claiming memory, determine the actual size of the host's
         // address space.
         let ram_size = zero_pages.len() as u64 * PageSize::Size4k as u64
             + fdt_pages.length_bytes()
-            + kernel.size()
+            + kernel.length_bytes()
             + initramfs.map(|r| r.size()).unwrap_or(0);
-        assert!(ram_size >= FDT_OFFSET + fdt_pages.length_bytes());
+        if ram_size < layout.end {
+            return Err(Error::InsufficientMemory {
+                required: layout.end,
+                available: ram_size,
+            });
+        }
 
-        Self {
+        Ok(Self {
             hypervisor_dt,
             kernel,
             initramfs,
@@ -200,12 +299,14 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             zero_pages,
             guest_ram_base,
             ram_size,
-        }
+            layout,
+        })
     }
 
     /// Builds a device tree for the host VM, flattening it to a range of pages that will be
-    /// mapped into the address space in `build_address_space()`.
-    pub fn build_device_tree(mut self) -> Self {
+    /// mapped into the address space in `build_address_space()`. The flattened tree is measured
+    /// into `platform_log`.
+    pub fn build_device_tree(mut self, platform_log: &mut PlatformLog) -> Self {
         let fdt_pages = match self.fdt_pages {
             FdtPages::Clean(pages) => pages,
             _ => panic!("Device tree already written"),
@@ -225,7 +326,7 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
                 .set_initramfs_addr(
                     self.guest_ram_base
                         .raw()
-                        .checked_increment(INITRAMFS_OFFSET)
+                        .checked_increment(self.layout.initramfs_offset)
                         .unwrap(),
                     r.size(),
                 )
@@ -233,7 +334,9 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
         }
         let host_dt = host_dt_builder.tree();
 
-        println!("Host DT: {}", host_dt);
+        if log_enabled(LogLevel::Debug) {
+            println!("Host DT: {}", host_dt);
+        }
 
         // Serialize the device-tree.
         let dt_writer = DeviceTreeSerializer::new(&host_dt);
@@ -246,6 +349,10 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             )
         };
         dt_writer.write_to(fdt_slice);
+        platform_log.measure(
+            PlatformEventType::HostDeviceTree,
+            &fdt_slice[..dt_writer.output_size()],
+        );
         let fdt_pages =
             SequentialPages::from_pages(fdt_pages.into_iter().map(|p| p.to_initialized_page()))
                 .unwrap();
@@ -265,14 +372,16 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             self.vm.add_imsic_pages(cpu_id, imsic_pages);
         }
 
-        let pci = PcieRoot::get();
+        let pci = PcieRoots::get();
         pci.take_host_devices();
-        // Identity-map the PCIe BAR resources.
-        for (res_type, range) in pci.resources() {
-            let gpa = range.base().as_guest_phys(PageOwnerId::host());
-            self.vm.add_pci_region(gpa, range.length_bytes());
-            let pages = pci.take_host_resource(res_type).unwrap();
-            self.vm.add_pci_pages(gpa, pages);
+        // Identity-map the PCIe BAR resources of each root complex.
+        for root in pci.iter() {
+            for (res_type, range) in root.resources() {
+                let gpa = range.base().as_guest_phys(PageOwnerId::host());
+                self.vm.add_pci_region(gpa, range.length_bytes());
+                let pages = root.take_host_resource(res_type).unwrap();
+                self.vm.add_pci_pages(gpa, pages);
+            }
         }
         // Attach our PCI devices to the IOMMU.
         if Iommu::get().is_some() {
@@ -296,27 +405,25 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
         self.vm
             .add_confidential_memory_region(current_gpa, self.ram_size);
 
-        let mut zero_ranges = ArrayVec::<_, 3>::new();
-        let num_pages = KERNEL_OFFSET / PageSize::Size4k as u64;
+        let mut zero_ranges = ArrayVec::<_, 2>::new();
+        let num_pages = self.layout.kernel_offset / PageSize::Size4k as u64;
         zero_ranges.push(PageAddrRange::new(current_gpa, num_pages));
         current_gpa = current_gpa.checked_add_pages(num_pages).unwrap();
 
-        let num_kernel_pages = self.kernel.size() / PageSize::Size4k as u64;
-        let kernel_pages: SequentialPages<ConvertedInitialized> = unsafe {
-            // Safe because HwMemMap reserved this region.
-            SequentialPages::from_mem_range(self.kernel.base(), PageSize::Size4k, num_kernel_pages)
-                .unwrap()
-        };
+        let kernel_pages = self.kernel.into_pages();
+        let num_kernel_pages = kernel_pages.len();
         self.vm
             .add_measured_pages(current_gpa, kernel_pages.into_iter());
         current_gpa = current_gpa.checked_add_pages(num_kernel_pages).unwrap();
 
-        if let Some(r) = self.initramfs {
-            let num_pages =
-                (INITRAMFS_OFFSET - (KERNEL_OFFSET + self.kernel.size())) / PageSize::Size4k as u64;
-            zero_ranges.push(PageAddrRange::new(current_gpa, num_pages));
-            current_gpa = current_gpa.checked_add_pages(num_pages).unwrap();
+        // Zero pages for the rest of the kernel's footprint.
+        let num_pages = (self.layout.initramfs_offset
+            - (current_gpa.bits() - self.guest_ram_base.bits()))
+            / PageSize::Size4k as u64;
+        zero_ranges.push(PageAddrRange::new(current_gpa, num_pages));
+        current_gpa = current_gpa.checked_add_pages(num_pages).unwrap();
 
+        if let Some(r) = self.initramfs {
             let num_initramfs_pages = r.size() / PageSize::Size4k as u64;
             let initramfs_pages: SequentialPages<ConvertedInitialized> = unsafe {
                 // Safe because HwMemMap reserved this region.
@@ -328,11 +435,11 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             current_gpa = current_gpa.checked_add_pages(num_initramfs_pages).unwrap();
         }
 
-        let num_pages = (FDT_OFFSET - (current_gpa.bits() - self.guest_ram_base.bits()))
-            / PageSize::Size4k as u64;
-        zero_ranges.push(PageAddrRange::new(current_gpa, num_pages));
-        current_gpa = current_gpa.checked_add_pages(num_pages).unwrap();
-
+        // The FDT immediately follows the initramfs (or the kernel if there's no initramfs).
+        assert_eq!(
+            current_gpa.bits() - self.guest_ram_base.bits(),
+            self.layout.fdt_offset
+        );
         let fdt_pages = match self.fdt_pages {
             FdtPages::Initialized(pages) => pages,
             _ => panic!("FDT pages not initialized"),
@@ -346,11 +453,11 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
             .finalize(
                 self.guest_ram_base
                     .raw()
-                    .checked_increment(KERNEL_OFFSET)
+                    .checked_increment(self.layout.kernel_entry)
                     .unwrap(),
                 self.guest_ram_base
                     .raw()
-                    .checked_increment(FDT_OFFSET)
+                    .checked_increment(self.layout.fdt_offset)
                     .unwrap(),
             )
             .unwrap();
@@ -364,11 +471,13 @@ impl<T: GuestStagePagingMode> HostVmLoader<T> {
         }
         self.vm.add_zero_pages(current_gpa, self.zero_pages);
 
-        // Set up MMIO emulation for the PCIe config space.
-        let config_mem = pci.config_space();
-        let config_gpa = config_mem.base().as_guest_phys(PageOwnerId::host());
-        self.vm
-            .add_mmio_region(config_gpa, config_mem.length_bytes());
+        // Set up MMIO emulation for the config space of each PCIe segment.
+        for root in pci.iter() {
+            let config_mem = root.config_space();
+            let config_gpa = config_mem.base().as_guest_phys(PageOwnerId::host());
+            self.vm
+                .add_mmio_region(config_gpa, config_mem.length_bytes());
+        }
 
         self.vm
     }
@@ -392,6 +501,25 @@ impl core::fmt::Display for MmioEmulationError {
     }
 }
 
+// Attaches the PCI devices owned by the host VM that aren't attached to the IOMMU yet, such as
+// devices hot-added to a slot, to the host VM's IOMMU context.
+fn attach_new_pci_devices<T: GuestStagePagingMode>(vm: &FinalizedVm<T>) {
+    if Iommu::get().is_none() {
+        return;
+    }
+    for dev in PcieRoots::get().devices() {
+        let mut dev = dev.lock();
+        if dev.owner() == Some(PageOwnerId::host()) && dev.is_present() && !dev.is_iommu_attached()
+        {
+            if let Err(e) = vm.vm_pages().attach_pci_device(&mut dev) &&
+                log_enabled(LogLevel::Warn)
+            {
+                println!("Failed to attach {}: {:?}", dev.info().address(), e);
+            }
+        }
+    }
+}
+
 #[derive(Default)]
 struct HostVmRunner {
     scause: u64,
@@ -414,6 +542,8 @@ impl HostVmRunner {
     ) -> ControlFlow<()> {
         // Run until we shut down, or this vCPU stops.
         loop {
+            // Save any TVM vCPUs that are moving off this CPU before re-entering the host.
+            vm.save_migrating_vcpus();
             vm.run_vcpu(vcpu_id, VmCpuParent::Tsm(self)).unwrap();
             if let Ok(Trap::Exception(e)) = Trap::from_scause(self.scause) {
                 use Exception::*;
@@ -423,7 +553,9 @@ impl HostVmRunner {
                         use SbiMessage::*;
                         match SbiMessage::from_regs(self.gprs.a_regs()) {
                             Ok(Reset(_)) => {
-                                println!("Host VM requested shutdown");
+                                if log_enabled(LogLevel::Info) {
+                                    println!("Host VM requested shutdown");
+                                }
                                 return ControlFlow::Break(());
                             }
                             Ok(HartState(StateFunction::HartStart { hart_id, .. })) => {
@@ -459,6 +591,10 @@ impl HostVmRunner {
                             println!("Unhandled page fault: {}", err);
                             return ControlFlow::Break(());
                         }
+                        // The access may have caused devices to be hot-added to one of our slots.
+                        if PcieRoots::get().take_devices_added() {
+                            attach_new_pci_devices(&vm);
+                        }
                     }
                     _ => {
                         println!("Unhandled host VM exception {:?}", e);
@@ -478,14 +614,9 @@ impl HostVmRunner {
     ) -> core::result::Result<(), MmioEmulationError> {
         // For now, the only thing we're expecting is MMIO emulation faults in PCI config space.
         let addr = (self.htval << 2) | (self.stval & 0x3);
-        let pci = PcieRoot::get();
-        if addr < pci.config_space().base().bits() {
-            return Err(MmioEmulationError::InvalidAddress(addr));
-        }
-        let offset = addr - pci.config_space().base().bits();
-        if offset > pci.config_space().length_bytes() {
-            return Err(MmioEmulationError::InvalidAddress(addr));
-        }
+        let (pci, offset) = PcieRoots::get()
+            .root_for_config_addr(addr)
+            .ok_or(MmioEmulationError::InvalidAddress(addr))?;
 
         // Figure out from HTINST what the MMIO operation was. We know the source/destination is
         // always A0.
@@ -591,9 +722,6 @@ impl VmCpuExitReporting for HostVmRunner {
     }
 }
 
-// Pages used by the `PageVec` for the Host VM guest tracking.
-const HOSTVM_GUEST_TRACKING_PAGES: usize = 2;
-
 /// Represents the special VM that serves as the host for the system.
 pub struct HostVm<T: GuestStagePagingMode> {
     inner: GuestVm<T>,
@@ -601,11 +729,14 @@ pub struct HostVm<T: GuestStagePagingMode> {
 
 impl<T: GuestStagePagingMode> HostVm<T> {
     // Creates an initializing host VM with an expected guest physical address space size of
-    // `host_gpa_size` from the hypervisor page allocator. Returns the remaining free pages
-    // from the allocator, along with the newly constructed `HostVm`.
+    // `host_gpa_size` and room for `max_tvms` TVMs from the hypervisor page allocator, setting aside
+    // `hyp_reserve_pages` pages for the hypervisor's heap. Returns the remaining free pages from the
+    // allocator, along with the newly constructed `HostVm`.
     fn from_hyp_mem(
         mut hyp_mem: HypPageAlloc,
         host_gpa_size: u64,
+        max_tvms: usize,
+        hyp_reserve_pages: usize,
     ) -> (PageList<Page<ConvertedClean>>, Self) {
         let root_table_pages =
             hyp_mem.take_pages_for_host_state_with_alignment(4, T::TOP_LEVEL_ALIGN);
@@ -615,7 +746,9 @@ impl<T: GuestStagePagingMode> HostVm<T> {
             .into_iter();
         let vm_state_pages =
             hyp_mem.take_pages_for_host_state(GuestVm::<T>::required_pages() as usize);
-        let guest_tracking_pages = hyp_mem.take_pages_for_host_state(HOSTVM_GUEST_TRACKING_PAGES);
+        let guest_tracking_size = max_tvms * core::mem::size_of::<GuestVm<T>>();
+        let guest_tracking_pages = hyp_mem
+            .take_pages_for_host_state(PageSize::num_4k_pages(guest_tracking_size as u64) as usize);
 
         // Pages for the array of vCPUs.
         let num_cpus = CpuInfo::get().num_cpus();
@@ -633,7 +766,8 @@ impl<T: GuestStagePagingMode> HostVm<T> {
             )
         });
 
-        let (page_tracker, host_pages) = PageTracker::from(hyp_mem, HOST_VM_ALIGN as u64);
+        let (page_tracker, host_pages) =
+            PageTracker::from(hyp_mem, HOST_VM_ALIGN as u64, max_tvms, hyp_reserve_pages);
         let root =
             GuestStagePageTable::new(root_table_pages, PageOwnerId::host(), page_tracker).unwrap();
         let vm_pages = VmPages::new(root, 0);
@@ -665,9 +799,8 @@ impl<T: GuestStagePagingMode> HostVm<T> {
                 .into_chunks_iter(num::NonZeroU64::new(vcpu_required_state_pages).unwrap());
             for i in 0..num_cpus {
                 // Allocate vCPU.
-                let vcpu = VmCpu::new(i as u64, PageOwnerId::host());
                 let vcpu_pages = state_pages_iter.next().unwrap();
-                let vcpu_box = PageBox::new_with(vcpu, vcpu_pages, page_tracker);
+                let vcpu_box = VmCpu::new(i as u64, PageOwnerId::host(), vcpu_pages, page_tracker);
                 init_vm.add_vcpu(vcpu_box).unwrap();
 
                 let imsic_loc = imsic
@@ -795,7 +928,8 @@ impl<T: GuestStagePagingMode> HostVm<T> {
         entry_addr: GuestPhysAddr,
         fdt_addr: GuestPhysAddr,
     ) -> GuestTrackingResult<()> {
-        self.inner.finalize(entry_addr.bits(), fdt_addr.bits())
+        self.inner
+            .finalize(entry_addr.bits(), fdt_addr.bits(), None)
     }
 
     // Add zero pages to the host page tables. Requires that the GPA map the SPA in
@@ -841,13 +975,30 @@ impl<T: GuestStagePagingMode> HostVm<T> {
         vm.bind_vcpu_end(vcpu_id).unwrap();
     }
 
+    /// Returns a reference to the host VM, which is finalized once it's been built.
+    pub fn vm(&self) -> FinalizedVm<T> {
+        self.inner.as_finalized_vm().unwrap()
+    }
+
     /// Run the host VM's vCPU with ID `vcpu_id`. Does not return.
     pub fn run(&self, vcpu_id: u64) {
         self.bind_vcpu(vcpu_id);
         loop {
-            // Wait until this vCPU is ready to run.
+            // Wait until this vCPU is ready to run. TVM vCPUs bound to this CPU may receive
+            // interrupts in the meantime, so make sure we're woken up for those as well.
             while !self.vcpu_is_runnable(vcpu_id) {
+                // Other CPUs may be waiting on us to save the TVM vCPUs they're taking from us. Their
+                // requests come with an IPI, which also wakes us up, but those that have to wait on
+                // a TLB fence are retried without one.
+                let vm = self.inner.as_finalized_vm().unwrap();
+                vm.save_migrating_vcpus();
+                if vm_interrupts::has_deferred_saves() {
+                    core::hint::spin_loop();
+                    continue;
+                }
+                smp::PerCpu::this_cpu().guest_files_mut().arm_wakeups();
                 smp::wfi();
+                smp::PerCpu::this_cpu().guest_files_mut().disarm_wakeups();
             }
 
             let vm = self.inner.as_finalized_vm().unwrap();
@@ -10,7 +10,7 @@
 // +-------------------------+ (Highest HwMemoryMap address)
 // | (unused)                |
 // +-------------------------+ 0xffff_ffff_0000_0000 (UMODE_START, UMODE_BINARY_START)
-// | U-mode ELF mappings     |
+// | U-mode ELF mappings     | (loaded at a random offset if position-independent)
 // +-------------------------+ +UMODE_BINARY_SIZE (UMODE_BINARY_END)
 // | (unused 4Mb)            |
 // +-------------------------+ UMODE_MAPPINGS_START
@@ -21,15 +21,16 @@
 // | (unused 4Mb)            |
 // +-------------------------+ UMODE_INPUT_START
 // | Umode Input Area        |
-// +-------------------------+ +UMODE_INPUT_SIZE
+// +-------------------------+ +UMODE_INPUT_SIZE (UMODE_INPUT_END)
 // | (unused)                |
-// +-------------------------+ HYP_STACK_BOTTOM (HYP_STACK_TOP - HYP_STACK_SIZE)
+// +-------------------------+ Stack bottom (HYP_STACK_TOP - configured stack size)
 // | Hypervisor Stack        |
 // +-------------------------+ HYP_STACK_TOP (0xffff_ffff_ffe0_0000)
 // | (unused 2Mb)            |
 // +-------------------------+ End of Address Space.
 
-use riscv_pages::{PageAddr, PageSize, SupervisorVirt};
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv_pages::{PageAddr, PageSize, RawAddr, SupervisorVirt};
 use static_assertions::const_assert;
 
 /// U-mode mappings start here.
@@ -81,20 +82,50 @@ const_assert!(PageSize::Size4k.is_aligned(UMODE_INPUT_START));
 /// Size of the U-mode Input Region.
 pub const UMODE_INPUT_SIZE: u64 = 4 * 1024;
 const_assert!(PageSize::Size4k.is_aligned(UMODE_INPUT_SIZE));
+/// End of the U-mode Input Region.
+pub const UMODE_INPUT_END: u64 = UMODE_INPUT_START + UMODE_INPUT_SIZE;
 
 /// Address of the hypervisor stack top.
 pub const HYP_STACK_TOP: u64 = 0xffff_ffff_ffe0_0000;
 // Align stack top to 2M to ease huge page mappings.
 const_assert!(PageSize::Size2M.is_aligned(HYP_STACK_TOP));
-/// Number of 4k-pages reserved for each CPU as their stack.
-pub const HYP_STACK_PAGES: u64 = 0x80;
-/// Size of stack in bytes.
-pub const HYP_STACK_SIZE: u64 = HYP_STACK_PAGES * PageSize::Size4k as u64;
-/// End of stack (lower address)
-pub const HYP_STACK_BOTTOM: u64 = HYP_STACK_TOP - HYP_STACK_SIZE;
-/// Page address of the stack bottom.
-pub const HYP_STACK_BOTTOM_PAGE_ADDR: PageAddr<SupervisorVirt> =
-    PageAddr::new_const::<HYP_STACK_BOTTOM>();
+/// Default number of 4k-pages reserved for each CPU as their stack.
+pub const DEFAULT_HYP_STACK_PAGES: u64 = 0x80;
+/// Smallest per-CPU stack, in 4k-pages, that may be configured.
+pub const MIN_HYP_STACK_PAGES: u64 = 0x10;
+/// Largest per-CPU stack, in 4k-pages, that may be configured.
+pub const MAX_HYP_STACK_PAGES: u64 = 0x4000;
+const_assert!(HYP_STACK_TOP - MAX_HYP_STACK_PAGES * PageSize::Size4k as u64 >= UMODE_INPUT_END);
+
+// End of stack (lower address). Set once at boot, before any CPU switches to its mapped stack, and
+// read by the trap entry code to detect overflows.
+#[no_mangle]
+static hyp_stack_bottom: AtomicU64 =
+    AtomicU64::new(HYP_STACK_TOP - DEFAULT_HYP_STACK_PAGES * PageSize::Size4k as u64);
+
+/// Sets the number of 4k-pages in each CPU's stack. Must be called before any of the stacks are
+/// mapped.
+pub fn set_hyp_stack_pages(pages: u64) {
+    assert!((MIN_HYP_STACK_PAGES..=MAX_HYP_STACK_PAGES).contains(&pages));
+    hyp_stack_bottom.store(
+        HYP_STACK_TOP - pages * PageSize::Size4k as u64,
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns the number of 4k-pages in each CPU's stack.
+pub fn hyp_stack_pages() -> u64 {
+    (HYP_STACK_TOP - hyp_stack_bottom.load(Ordering::Relaxed)) / PageSize::Size4k as u64
+}
+
+/// Returns the page address of the stack bottom.
+pub fn hyp_stack_bottom_page_addr() -> PageAddr<SupervisorVirt> {
+    // Unwrap ok: the stack top is aligned and the stack is a whole number of pages.
+    PageAddr::new(RawAddr::supervisor_virt(
+        hyp_stack_bottom.load(Ordering::Relaxed),
+    ))
+    .unwrap()
+}
 
 // Returns true if `addr` is contained in the U-mode binary area.
 fn is_umode_binary_addr(addr: u64) -> bool {
@@ -105,3 +136,11 @@ fn is_umode_binary_addr(addr: u64) -> bool {
 pub fn is_valid_umode_binary_range(addr: u64, len: usize) -> bool {
     len != 0 && is_umode_binary_addr(addr) && is_umode_binary_addr(addr + len as u64 - 1)
 }
+
+/// Returns the start of a 4k-aligned range of `size` bytes in the U-mode binary area, picked from
+/// `seed`. Returns `None` if `size` doesn't fit in the area.
+pub fn random_umode_binary_base(size: u64, seed: u64) -> Option<u64> {
+    let size = PageSize::Size4k.round_up(size);
+    let slots = UMODE_BINARY_SIZE.checked_sub(size)? / PageSize::Size4k as u64 + 1;
+    Some(UMODE_BINARY_START + (seed % slots) * PageSize::Size4k as u64)
+}
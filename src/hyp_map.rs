@@ -4,11 +4,13 @@
 
 use crate::hyp_layout::*;
 
+use alloc::vec::Vec;
 use arrayvec::ArrayVec;
 use core::cell::RefCell;
+use core::ops::Range;
 use data_model::{DataInit, VolatileMemory, VolatileMemoryError, VolatileSlice};
 use page_tracking::{HwMemMap, HwMemRegion, HwMemRegionType, HwReservedMemType, HypPageAlloc};
-use riscv_elf::{ElfMap, ElfSegment, ElfSegmentPerms};
+use riscv_elf::{ElfMap, ElfSegment, ElfSegmentPerms, ElfTls};
 use riscv_page_tables::{
     FirstStageMapper, FirstStagePageTable, PageTableError, PagingMode, PteFieldBits, PteLeafPerms,
     Sv48,
@@ -46,6 +48,12 @@ pub enum Error {
     ElfUnalignedSegment,
     /// U-mode ELF segment is not in U-mode VA area.
     ElfInvalidAddress,
+    /// U-mode ELF segment is both writable and executable.
+    ElfWritableExecutable,
+    /// U-mode ELF TLS block needs more than page alignment.
+    ElfUnalignedTls,
+    /// U-mode ELF is too large to fit in the U-mode VA area.
+    ElfTooLarge,
     /// Not enough space in the U-mode map area.
     OutOfMap,
     /// Could not create a mapper for the U-mode area.
@@ -101,6 +109,7 @@ impl HwMapRegion {
             }
             HwMemRegionType::Reserved(HwReservedMemType::HypervisorHeap)
             | HwMemRegionType::Reserved(HwReservedMemType::PageMap)
+            | HwMemRegionType::Reserved(HwReservedMemType::CrashDump)
             | HwMemRegionType::Mmio(_) => Some(PteLeafPerms::RW),
         };
 
@@ -158,13 +167,18 @@ struct UmodeElfRegion {
     size: usize,
     // PTE bits for the mappings.
     pte_fields: PteFieldBits,
-    // Data to be populated at the beginning of the VA area
-    data: Option<&'static [u8]>,
+    // Data to be populated at the beginning of the VA area, relocated for the load address.
+    data: Option<Vec<u8>>,
 }
 
 impl UmodeElfRegion {
-    // Creates a region from an U-mode ELF segment.
-    fn from_umode_elf_segment(seg: &ElfSegment<'static>) -> Result<Self, Error> {
+    // Creates a region from an U-mode ELF segment, loaded `load_bias` bytes above its link-time
+    // address.
+    fn from_umode_elf_segment(
+        umode_elf: &ElfMap<'static>,
+        seg: &ElfSegment<'static>,
+        load_bias: u64,
+    ) -> Result<Self, Error> {
         // Sanity check for segment alignments.
         //
         // In general ELF might have segments overlapping in the same page, possibly with different
@@ -174,23 +188,59 @@ impl UmodeElfRegion {
         //
         // The following check enforces that the segment starts at a 4k page aligned address. Unless
         // the linking is completely corrupt, this also means that it starts at a different page.
-        let vaddr = PageAddr::new(RawAddr::supervisor_virt(seg.vaddr()))
+        let load_vaddr = seg.vaddr().wrapping_add(load_bias);
+        let vaddr = PageAddr::new(RawAddr::supervisor_virt(load_vaddr))
             .ok_or(Error::ElfUnalignedSegment)?;
         // Sanity check for VA area of the segment.
-        if !is_valid_umode_binary_range(seg.vaddr(), seg.size()) {
+        if !is_valid_umode_binary_range(load_vaddr, seg.size()) {
             return Err(Error::ElfInvalidAddress);
         }
         let pte_perms = match seg.perms() {
             ElfSegmentPerms::ReadOnly => PteLeafPerms::UR,
             ElfSegmentPerms::ReadWrite => PteLeafPerms::URW,
             ElfSegmentPerms::ReadOnlyExecute => PteLeafPerms::URX,
+            ElfSegmentPerms::ReadWriteExecute => return Err(Error::ElfWritableExecutable),
         };
         let pte_fields = PteFieldBits::leaf_with_perms(pte_perms);
+        let data = seg.data().map(|data| {
+            let mut data = data.to_vec();
+            umode_elf.relocate(load_bias, seg.vaddr(), &mut data);
+            data
+        });
         Ok(Self {
             vaddr,
             size: seg.size(),
             pte_fields,
-            data: seg.data(),
+            data,
+        })
+    }
+
+    // Creates a read-write region at `load_vaddr` holding the TLS block of the U-mode task,
+    // initialized from the ELF's TLS template.
+    fn from_umode_elf_tls(
+        umode_elf: &ElfMap<'static>,
+        tls: &ElfTls<'static>,
+        load_vaddr: u64,
+        load_bias: u64,
+    ) -> Result<Self, Error> {
+        if tls.align() > PageSize::Size4k as u64 {
+            return Err(Error::ElfUnalignedTls);
+        }
+        let vaddr = PageAddr::new(RawAddr::supervisor_virt(load_vaddr))
+            .ok_or(Error::ElfUnalignedSegment)?;
+        if !is_valid_umode_binary_range(load_vaddr, tls.size()) {
+            return Err(Error::ElfInvalidAddress);
+        }
+        let data = tls.data().map(|data| {
+            let mut data = data.to_vec();
+            umode_elf.relocate(load_bias, tls.vaddr(), &mut data);
+            data
+        });
+        Ok(Self {
+            vaddr,
+            size: tls.size(),
+            pte_fields: PteFieldBits::leaf_with_perms(PteLeafPerms::URW),
+            data,
         })
     }
 
@@ -204,7 +254,7 @@ impl UmodeElfRegion {
         let page_count = PageSize::num_4k_pages(self.size as u64);
         let pages = hyp_mem.take_pages_for_hyp_state(page_count as usize);
         // Copy data if present.
-        if let Some(data) = self.data {
+        if let Some(data) = &self.data {
             let dest = pages.base().bits() as *mut u8;
             let len = core::cmp::min(data.len(), self.size);
             // Safe because we copy the minimum between the data size and the VA size.
@@ -235,7 +285,7 @@ impl UmodeElfRegion {
         // We have to reset the full pages mapped for this segment.
         let mapped_size = PageSize::Size4k.round_up(self.size as u64) as usize;
         // Copy data at the beginning if it's present.
-        if let Some(data) = self.data {
+        if let Some(data) = &self.data {
             // In case data is bigger than region size, write up to region end only.
             let len = core::cmp::min(self.size, data.len());
             let data = &data[0..len];
@@ -339,7 +389,7 @@ pub struct HypStackRegion {
 impl HypStackRegion {
     fn new(stack_pages: SequentialPages<InternalDirty>) -> Result<Self, Error> {
         let page_count = stack_pages.len();
-        if page_count != HYP_STACK_PAGES {
+        if page_count != hyp_stack_pages() {
             return Err(Error::InvalidStackSize(page_count));
         }
         let paddr = stack_pages.base();
@@ -352,7 +402,7 @@ impl HypStackRegion {
         sv48: &FirstStagePageTable<Sv48>,
         hyp_mem: &mut HypPageAlloc,
     ) -> Result<(), Error> {
-        let page_count = HYP_STACK_PAGES;
+        let page_count = hyp_stack_pages();
         // Unmap stack pages from the 1:1 map.
         sv48.unmap_range(
             self.paddr.as_supervisor_virt(),
@@ -363,7 +413,7 @@ impl HypStackRegion {
         .count();
 
         // Map stack pages at stack virtual address.
-        let vaddr = HYP_STACK_BOTTOM_PAGE_ADDR;
+        let vaddr = hyp_stack_bottom_page_addr();
         let pte_fields = PteFieldBits::leaf_with_perms(PteLeafPerms::RW);
         let mapper = sv48
             .map_range(vaddr, PageSize::Size4k, page_count, &mut || {
@@ -508,22 +558,72 @@ impl UmodeSlotMapper<'_> {
 pub struct HypMap {
     hw_map_regions: HwMapRegionsVec,
     umode_elf_regions: UmodeElfRegionsVec,
+    umode_tls_pointer: Option<u64>,
+    mem_map: HwMemMap,
 }
 
 impl HypMap {
-    /// Creates a new hypervisor map from a hardware memory mem map and a umode ELF.
-    pub fn init(mem_map: HwMemMap, umode_elf: &ElfMap<'static>) -> Result<(), Error> {
+    /// Returns the range of link-time addresses that `umode_elf` takes in the U-mode VA area: its
+    /// loadable segments followed by the pages holding its TLS block, if it has one.
+    fn umode_image_range(umode_elf: &ElfMap) -> Range<u64> {
+        let range = umode_elf.vaddr_range();
+        let tls_size = umode_elf
+            .tls()
+            .map(|tls| PageSize::Size4k.round_up(tls.size() as u64))
+            .unwrap_or(0);
+        PageSize::Size4k.round_down(range.start)..PageSize::Size4k.round_up(range.end) + tls_size
+    }
+
+    /// Returns the offset from its link-time addresses at which `umode_elf` will be loaded. A
+    /// position-independent ELF is placed at a page-aligned address in the U-mode binary area
+    /// picked from `seed`, others are loaded at their link-time addresses.
+    pub fn umode_load_bias(umode_elf: &ElfMap, seed: u64) -> Result<u64, Error> {
+        if !umode_elf.is_pie() {
+            return Ok(0);
+        }
+        let range = Self::umode_image_range(umode_elf);
+        let base =
+            random_umode_binary_base(range.end - range.start, seed).ok_or(Error::ElfTooLarge)?;
+        Ok(base.wrapping_sub(range.start))
+    }
+
+    /// Creates a new hypervisor map from a hardware memory mem map and a umode ELF loaded
+    /// `load_bias` bytes above its link-time addresses.
+    pub fn init(
+        mem_map: HwMemMap,
+        umode_elf: &ElfMap<'static>,
+        load_bias: u64,
+    ) -> Result<(), Error> {
         let hw_map_regions = mem_map
             .regions()
             .filter_map(HwMapRegion::from_hw_mem_region)
             .collect();
-        let umode_elf_regions = umode_elf
+        let mut umode_elf_regions: UmodeElfRegionsVec = umode_elf
             .segments()
-            .map(UmodeElfRegion::from_umode_elf_segment)
+            .map(|seg| UmodeElfRegion::from_umode_elf_segment(umode_elf, seg, load_bias))
             .collect::<Result<_, _>>()?;
+        // The TLS block goes in the pages right after the loadable segments. Like the other
+        // regions, each page table gets its own copy, which is the block of the CPU's only
+        // U-mode thread.
+        let umode_tls_pointer = match umode_elf.tls() {
+            Some(tls) => {
+                let load_vaddr = PageSize::Size4k
+                    .round_up(umode_elf.vaddr_range().end)
+                    .wrapping_add(load_bias);
+                let region =
+                    UmodeElfRegion::from_umode_elf_tls(umode_elf, tls, load_vaddr, load_bias)?;
+                umode_elf_regions
+                    .try_push(region)
+                    .map_err(|_| Error::OutOfMap)?;
+                Some(load_vaddr)
+            }
+            None => None,
+        };
         let hypmap = HypMap {
             hw_map_regions,
             umode_elf_regions,
+            umode_tls_pointer,
+            mem_map,
         };
         HYPMAP.call_once(|| hypmap);
         Ok(())
@@ -535,6 +635,22 @@ impl HypMap {
         HYPMAP.get().unwrap()
     }
 
+    /// Gets the global reference to the Hypervisor Map, or `None` if it hasn't been initialized
+    /// yet. For use on paths that may run before `init`, such as the panic handler.
+    pub fn try_get() -> Option<&'static HypMap> {
+        HYPMAP.get()
+    }
+
+    /// Returns the hardware memory map the hypervisor map was created from.
+    pub fn mem_map(&self) -> &HwMemMap {
+        &self.mem_map
+    }
+
+    /// Returns the value of the thread pointer for the U-mode task, if it uses thread-local storage.
+    pub fn umode_tls_pointer(&self) -> Option<u64> {
+        self.umode_tls_pointer
+    }
+
     // Returns an iterator for the U-mode ELF regions.
     fn umode_elf_regions(&self) -> impl Iterator<Item = &UmodeElfRegion> {
         self.umode_elf_regions.iter()
@@ -0,0 +1,217 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Parsing of the header at the start of a RISC-V Linux kernel `Image`, as described in
+//! `Documentation/riscv/boot-image-header.rst` in the Linux tree.
+
+use core::fmt;
+
+/// Size of the header in bytes.
+pub const HEADER_SIZE: usize = 64;
+
+// Byte offsets of the fields we use.
+const TEXT_OFFSET_OFFSET: usize = 8;
+const IMAGE_SIZE_OFFSET: usize = 16;
+const FLAGS_OFFSET: usize = 24;
+const VERSION_OFFSET: usize = 32;
+const MAGIC_OFFSET: usize = 48;
+const MAGIC2_OFFSET: usize = 56;
+
+// "RISCV\0\0\0", deprecated as of header version 0.2 but still present.
+const MAGIC: u64 = 0x0000_0056_4353_4952;
+// "RSC\x05", used from header version 0.2 on.
+const MAGIC2: u32 = 0x0543_5352;
+
+// Set in `flags` if the kernel is big-endian.
+const FLAG_BE: u64 = 1 << 0;
+
+// No guest physical address space we support extends beyond this.
+const MAX_IMAGE_END: u64 = 1 << 50;
+
+/// Reasons a kernel image can't be loaded.
+#[derive(Debug)]
+pub enum Error {
+    /// The image is smaller than the header.
+    ImageTooSmall(usize),
+    /// Neither magic number is present; this isn't a RISC-V Linux `Image`.
+    BadMagic,
+    /// The kernel is big-endian.
+    BigEndian,
+    /// The header doesn't say how much memory the kernel needs.
+    MissingImageSize,
+    /// The kernel would extend beyond any guest physical address space.
+    ImageTooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            ImageTooSmall(size) => write!(f, "Image of {} bytes is too small for a header", size),
+            BadMagic => write!(f, "Not a RISC-V Linux Image"),
+            BigEndian => write!(f, "Big-endian kernels aren't supported"),
+            MissingImageSize => write!(f, "Image header has no image size"),
+            ImageTooLarge => write!(f, "Image text offset or size is too large"),
+        }
+    }
+}
+
+/// The header of a RISC-V Linux `Image`.
+#[derive(Clone, Copy, Debug)]
+pub struct LinuxImageHeader {
+    text_offset: u64,
+    image_size: u64,
+    version: u32,
+}
+
+impl LinuxImageHeader {
+    /// Parses and validates the header at the start of `image`.
+    pub fn parse(image: &[u8]) -> Result<Self, Error> {
+        if image.len() < HEADER_SIZE {
+            return Err(Error::ImageTooSmall(image.len()));
+        }
+        let read_u32 = |offset: usize| {
+            // Unwrap ok: the slice is 4 bytes long.
+            u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+        };
+        let read_u64 = |offset: usize| {
+            // Unwrap ok: the slice is 8 bytes long.
+            u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+        };
+
+        if read_u32(MAGIC2_OFFSET) != MAGIC2 && read_u64(MAGIC_OFFSET) != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if read_u64(FLAGS_OFFSET) & FLAG_BE != 0 {
+            return Err(Error::BigEndian);
+        }
+        let image_size = read_u64(IMAGE_SIZE_OFFSET);
+        if image_size == 0 {
+            return Err(Error::MissingImageSize);
+        }
+        let text_offset = read_u64(TEXT_OFFSET_OFFSET);
+        if text_offset
+            .checked_add(image_size)
+            .map_or(true, |end| end > MAX_IMAGE_END)
+        {
+            return Err(Error::ImageTooLarge);
+        }
+
+        Ok(Self {
+            text_offset,
+            image_size,
+            version: read_u32(VERSION_OFFSET),
+        })
+    }
+
+    /// Returns the offset from the start of RAM at which the kernel must be loaded.
+    pub fn text_offset(&self) -> u64 {
+        self.text_offset
+    }
+
+    /// Returns the amount of memory, starting at the load address, used by the kernel including
+    /// its BSS.
+    pub fn image_size(&self) -> u64 {
+        self.image_size
+    }
+
+    /// Returns the header version as a (major, minor) pair.
+    pub fn version(&self) -> (u16, u16) {
+        ((self.version >> 16) as u16, self.version as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    // Returns a header with the given fields and only the current magic number.
+    fn header(text_offset: u64, image_size: u64, flags: u64, version: u32) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[TEXT_OFFSET_OFFSET..TEXT_OFFSET_OFFSET + 8]
+            .copy_from_slice(&text_offset.to_le_bytes());
+        header[IMAGE_SIZE_OFFSET..IMAGE_SIZE_OFFSET + 8].copy_from_slice(&image_size.to_le_bytes());
+        header[FLAGS_OFFSET..FLAGS_OFFSET + 8].copy_from_slice(&flags.to_le_bytes());
+        header[VERSION_OFFSET..VERSION_OFFSET + 4].copy_from_slice(&version.to_le_bytes());
+        header[MAGIC2_OFFSET..MAGIC2_OFFSET + 4].copy_from_slice(b"RSC\x05");
+        header
+    }
+
+    #[test_case]
+    fn LinuxImageHeaderTest() -> TestResult {
+        let bytes = header(0x20_0000, 0x150_0000, 0, 0x0000_0002);
+        let parsed = LinuxImageHeader::parse(&bytes).expect("parse");
+        test_result_true!(
+            parsed.text_offset() == 0x20_0000
+                && parsed.image_size() == 0x150_0000
+                && parsed.version() == (0, 2),
+            "header fields"
+        )?;
+
+        // Older kernels may only have the deprecated magic number.
+        let mut bytes = header(0x20_0000, 0x150_0000, 0, 0x0000_0001);
+        bytes[MAGIC2_OFFSET..MAGIC2_OFFSET + 4].fill(0);
+        bytes[MAGIC_OFFSET..MAGIC_OFFSET + 8].copy_from_slice(b"RISCV\0\0\0");
+        let parsed = LinuxImageHeader::parse(&bytes).expect("parse old magic");
+        test_result_true!(parsed.version() == (0, 1), "old magic")?;
+
+        // Trailing image contents are ignored.
+        let mut image = [0u8; HEADER_SIZE + 16];
+        image[..HEADER_SIZE].copy_from_slice(&header(0, 0x1000, 0, 0x0001_0000));
+        let parsed = LinuxImageHeader::parse(&image).expect("parse image");
+        test_result_true!(
+            parsed.text_offset() == 0 && parsed.version() == (1, 0),
+            "image with contents"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn LinuxImageHeaderErrorsTest() -> TestResult {
+        let bytes = header(0x20_0000, 0x150_0000, 0, 2);
+        test_result_true!(
+            matches!(
+                LinuxImageHeader::parse(&bytes[..HEADER_SIZE - 1]),
+                Err(Error::ImageTooSmall(63))
+            ),
+            "too small"
+        )?;
+
+        let mut bytes = header(0x20_0000, 0x150_0000, 0, 2);
+        bytes[MAGIC2_OFFSET] = b'X';
+        test_result_true!(
+            matches!(LinuxImageHeader::parse(&bytes), Err(Error::BadMagic)),
+            "bad magic"
+        )?;
+
+        let bytes = header(0x20_0000, 0x150_0000, FLAG_BE, 2);
+        test_result_true!(
+            matches!(LinuxImageHeader::parse(&bytes), Err(Error::BigEndian)),
+            "big-endian"
+        )?;
+
+        let bytes = header(0x20_0000, 0, 0, 2);
+        test_result_true!(
+            matches!(
+                LinuxImageHeader::parse(&bytes),
+                Err(Error::MissingImageSize)
+            ),
+            "no image size"
+        )?;
+
+        let bytes = header(MAX_IMAGE_END - 0x1000, 0x2000, 0, 2);
+        test_result_true!(
+            matches!(LinuxImageHeader::parse(&bytes), Err(Error::ImageTooLarge)),
+            "too large"
+        )?;
+        let bytes = header(u64::MAX, 0x1000, 0, 2);
+        test_result_true!(
+            matches!(LinuxImageHeader::parse(&bytes), Err(Error::ImageTooLarge)),
+            "offset overflow"
+        )?;
+        Ok(())
+    }
+}
@@ -25,7 +25,7 @@
 #![cfg_attr(test, allow(unused))]
 #![feature(pointer_is_aligned)]
 
-use core::alloc::{Allocator, GlobalAlloc, Layout};
+use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::fmt::Display;
 use core::ptr::NonNull;
 use test_system::*;
@@ -33,35 +33,55 @@ extern crate alloc;
 
 mod asm;
 mod backtrace;
+mod boot_config;
+mod crash_dump;
+mod debug_console;
+#[cfg(feature = "gdbstub")]
+mod gdbstub;
 mod guest_tracking;
+mod host_kernel;
 mod host_vm;
 mod hyp_layout;
 mod hyp_map;
+mod linux_image;
+mod platform_log;
 mod smp;
 mod trap;
 mod umode;
 mod vm;
 mod vm_cpu;
+mod vm_debug;
 mod vm_id;
 mod vm_interrupts;
+mod vm_manifest;
 mod vm_pages;
+mod vm_pci;
 mod vm_pmu;
+mod vm_timer;
 
 use backtrace::backtrace;
-use device_tree::{DeviceTree, DeviceTreeError, Fdt};
+use boot_config::{BootConfig, Error as BootConfigError};
+use device_tree::{DeviceTree, DeviceTreeError, Fdt, FdtMemoryRegion};
 use drivers::{
-    imsic::Imsic, iommu::Iommu, pci::PcieRoot, pmu::PmuInfo, reset::ResetDriver, uart::UartDriver,
+    aplic::{Aplic, Error as AplicError},
+    imsic::{Imsic, ImsicFileId, ImsicInterruptId},
+    iommu::Iommu,
+    pci::PcieRoots,
+    pmu::PmuInfo,
+    reset::ResetDriver,
+    uart::UartDriver,
     CpuInfo,
 };
 use host_vm::{HostVm, HostVmLoader, HOST_VM_ALIGN};
-use hyp_alloc::HypAlloc;
+use hyp_alloc::{HypAlloc, SlabAlloc, SlabBackend};
 use hyp_map::HypMap;
 use page_tracking::*;
+use platform_log::{PlatformEventType, PlatformLog};
 use riscv_elf::ElfMap;
 use riscv_page_tables::*;
 use riscv_pages::*;
-use riscv_regs::{hedeleg, henvcfg, hideleg, hie, scounteren};
-use riscv_regs::{sstatus, vlenb, Readable, RiscvCsrInterface, MAX_VECTOR_REGISTER_LEN};
+use riscv_regs::{hedeleg, henvcfg, hideleg, hie, hvien, scounteren};
+use riscv_regs::{sstatus, vlenb, Readable, RiscvCsrInterface};
 use riscv_regs::{
     Exception, Interrupt, LocalRegisterCopy, ReadWriteable, Writeable, CSR, CSR_CYCLE, CSR_TIME,
 };
@@ -71,6 +91,7 @@ use s_mode_utils::sbi_console::SbiConsoleV01;
 use smp::PerCpu;
 use sync::Once;
 use umode::UmodeTask;
+use vm_cpu::VmCpus;
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -93,12 +114,15 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         });
     }
 
+    crash_dump::capture(info);
+
     abort()
 }
 
 extern "C" {
     static _start: u8;
     static _stack_end: u8;
+    static _text_end: u8;
     static _umode_bin: u8;
     static _umode_bin_len: u8;
 }
@@ -106,33 +130,69 @@ extern "C" {
 /// The allocator used for boot-time dynamic memory allocations.
 static HYPERVISOR_ALLOCATOR: Once<HypAlloc> = Once::new();
 
-// Implementation of GlobalAlloc that forwards allocations to the boot-time allocator.
+/// The allocator used for dynamic memory allocations once the boot-time allocator is sealed.
+static RUNTIME_ALLOCATOR: Once<SlabAlloc<RuntimeHeapBackend>> = Once::new();
+
+// Grows the runtime heap from the pages `PageTracker` sets aside for the hypervisor.
+struct RuntimeHeapBackend {
+    page_tracker: PageTracker,
+}
+
+impl SlabBackend for RuntimeHeapBackend {
+    fn take_pages(&self, count: usize) -> Option<SequentialPages<InternalClean>> {
+        self.page_tracker.take_hyp_pages(count)
+    }
+
+    fn cpu_index(&self) -> usize {
+        PerCpu::this_cpu().cpu_id().raw()
+    }
+}
+
+// Implementation of GlobalAlloc that forwards allocations to the boot-time allocator until it's
+// sealed, and to the runtime allocator after that.
 struct GeneralGlobalAlloc;
 
 unsafe impl GlobalAlloc for GeneralGlobalAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        HYPERVISOR_ALLOCATOR
-            .get()
-            .and_then(|a| a.allocate(layout).ok())
+        let result = match RUNTIME_ALLOCATOR.get() {
+            Some(a) => a.allocate(layout),
+            None => HYPERVISOR_ALLOCATOR
+                .get()
+                .ok_or(AllocError)
+                .and_then(|a| a.allocate(layout)),
+        };
+        result
             .map(|p| p.as_mut_ptr())
             .unwrap_or(core::ptr::null_mut())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // Unwrap ok, there must've been an allocator to allocate the pointer in the first place.
-        HYPERVISOR_ALLOCATOR
-            .get()
-            .unwrap()
-            .deallocate(NonNull::new(ptr).unwrap(), layout);
+        let ptr = NonNull::new(ptr).unwrap();
+        // Unwrap ok, there must've been a boot-time allocator to allocate anything in the first
+        // place, and a runtime allocator if the pointer didn't come from the boot-time one.
+        let boot_allocator = HYPERVISOR_ALLOCATOR.get().unwrap();
+        if boot_allocator.contains(ptr.as_ptr()) {
+            boot_allocator.deallocate(ptr, layout);
+        } else {
+            RUNTIME_ALLOCATOR.get().unwrap().deallocate(ptr, layout);
+        }
     }
 }
 
 #[global_allocator]
 static GENERAL_ALLOCATOR: GeneralGlobalAlloc = GeneralGlobalAlloc;
 
-/// Aborts if the system hits an allocation error.
+/// Reports which heap ran out and aborts if the system hits an allocation error.
 #[alloc_error_handler]
-pub fn alloc_error(_layout: Layout) -> ! {
+pub fn alloc_error(layout: Layout) -> ! {
+    match RUNTIME_ALLOCATOR.get() {
+        Some(alloc) => println!(
+            "Runtime heap exhausted allocating {:?}: {:?}; increase salus,runtime-heap-pages",
+            layout,
+            alloc.stats()
+        ),
+        None => println!("Boot heap exhausted allocating {:?}", layout),
+    }
     abort()
 }
 
@@ -147,8 +207,8 @@ static HOST_VM: Once<HostVm<Sv48x4>> = Once::new();
 
 /// Builds the hardware memory map from the device-tree. The kernel & initramfs image regions are
 /// aligned to `HOST_VM_ALIGN` so that they can be mapped directly into the host VM's guest
-/// physical address space.
-fn build_memory_map(fdt: &Fdt) -> MemMapResult<HwMemMap> {
+/// physical address space. `crash_dump` is the region to reserve for crash dumps, if any.
+fn build_memory_map(fdt: &Fdt, crash_dump: Option<FdtMemoryRegion>) -> MemMapResult<HwMemMap> {
     let mut builder = HwMemMapBuilder::new(HOST_VM_ALIGN as u64);
 
     // First add the memory regions.
@@ -217,9 +277,62 @@ fn build_memory_map(fdt: &Fdt) -> MemMapResult<HwMemMap> {
             HOST_VM_ALIGN.round_up(r.size()),
         )?;
     }
+
+    // Reserve the crash dump region so that a dump left there survives until it's read back.
+    if let Some(r) = crash_dump {
+        builder = builder.reserve_region(
+            HwReservedMemType::CrashDump,
+            RawAddr::supervisor(r.base()),
+            r.size(),
+        )?;
+    }
     Ok(builder.build())
 }
 
+// Returns the U-mode binary linked into the hypervisor image.
+fn umode_bytes() -> &'static [u8] {
+    // Safe, because it comes from the Linker
+    unsafe {
+        let umode_bin = core::ptr::addr_of!(_umode_bin) as *const u8;
+        let umode_bin_len = core::ptr::addr_of!(_umode_bin_len) as usize;
+        core::slice::from_raw_parts::<u8>(umode_bin, umode_bin_len)
+    }
+}
+
+/// Measures the hypervisor's own code and the host VM images loaded by firmware into `log`.
+///
+/// Note: this must run with a 1:1 map of physical memory, before the images can be modified.
+fn measure_boot_images(fdt: &Fdt, log: &mut PlatformLog) {
+    // Safe because we trust the linker placed these symbols correctly, and the text segment is
+    // never written.
+    let salus_image = unsafe {
+        let start = core::ptr::addr_of!(_start);
+        let end = core::ptr::addr_of!(_text_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    log.measure(PlatformEventType::SalusImage, salus_image);
+    log.measure(PlatformEventType::UmodeImage, umode_bytes());
+
+    // Safe because firmware loaded the images at these locations and we reserve them in the memory
+    // map; we're still running with a 1:1 map of physical memory. Use the sizes from the device tree
+    // rather than the memory map, which pads the images to `HOST_VM_ALIGN`.
+    let region_bytes = |base: u64, size: u64| unsafe {
+        core::slice::from_raw_parts(base as *const u8, size.try_into().unwrap())
+    };
+    if let Some(r) = fdt.host_kernel_region() {
+        log.measure(
+            PlatformEventType::HostKernel,
+            region_bytes(r.base(), r.size()),
+        );
+    }
+    if let Some(r) = fdt.host_initramfs_region() {
+        log.measure(
+            PlatformEventType::HostInitramfs,
+            region_bytes(r.base(), r.size()),
+        );
+    }
+}
+
 // Returns the base address of the first available region in the memory map that is at least `size`
 // bytes long. Returns None if no region is big enough.
 fn find_available_region(mem_map: &HwMemMap, size: u64) -> Option<SupervisorPageAddr> {
@@ -229,17 +342,16 @@ fn find_available_region(mem_map: &HwMemMap, size: u64) -> Option<SupervisorPage
         .map(|r| r.base())
 }
 
-/// Creates a heap from the given `mem_map`, marking the region occupied by the heap as reserved.
-fn create_heap(mem_map: &mut HwMemMap) -> Result<(), Error> {
-    const HEAP_SIZE: u64 = 16 * 1024 * 1024;
-
-    let heap_base = find_available_region(mem_map, HEAP_SIZE).ok_or(Error::HeapOutOfSpace)?;
+/// Creates a heap of `heap_size` bytes from the given `mem_map`, marking the region occupied by the
+/// heap as reserved.
+fn create_heap(mem_map: &mut HwMemMap, heap_size: u64) -> Result<(), Error> {
+    let heap_base = find_available_region(mem_map, heap_size).ok_or(Error::HeapOutOfSpace)?;
 
     mem_map
         .reserve_region(
             HwReservedMemType::HypervisorHeap,
             RawAddr::from(heap_base),
-            HEAP_SIZE,
+            heap_size,
         )
         .map_err(Error::HeapReserve)?;
     let pages: SequentialPages<InternalDirty> = unsafe {
@@ -247,7 +359,7 @@ fn create_heap(mem_map: &mut HwMemMap) -> Result<(), Error> {
         SequentialPages::from_mem_range(
             heap_base,
             PageSize::Size4k,
-            HEAP_SIZE / PageSize::Size4k as u64,
+            heap_size / PageSize::Size4k as u64,
         )
         .map_err(|_| Error::HeapUnaligned)
     }?;
@@ -255,6 +367,54 @@ fn create_heap(mem_map: &mut HwMemMap) -> Result<(), Error> {
     Ok(())
 }
 
+/// Reasons the UART's interrupt can't be routed to us.
+#[derive(Debug)]
+enum UartInterruptError {
+    /// The device tree doesn't describe the UART's interrupt.
+    NoInterrupt,
+    /// No usable APLIC domain delivering MSIs to our IMSIC.
+    Aplic(AplicError),
+    /// The UART's interrupt isn't wired to the APLIC domain.
+    NotWiredToAplic,
+}
+
+/// Routes the UART's receive interrupt to the IMSIC of the CPU with `hart_id` through the APLIC and
+/// enables it.
+fn setup_uart_interrupt(
+    dt: &DeviceTree,
+    mem_map: &mut HwMemMap,
+    hart_id: u64,
+) -> Result<(), UartInterruptError> {
+    // Unwrap ok, the UART is probed before the IMSIC.
+    let uart = UartDriver::get().unwrap();
+    let interrupt = uart.interrupt().ok_or(UartInterruptError::NoInterrupt)?;
+    let imsic = Imsic::get();
+    Aplic::probe_from(dt, mem_map, imsic.phandle()).map_err(UartInterruptError::Aplic)?;
+    // Unwrap ok, we just probed it.
+    let aplic = Aplic::get().unwrap();
+    if aplic.phandle() != interrupt.parent {
+        return Err(UartInterruptError::NotWiredToAplic);
+    }
+    // Unwrap ok, the CPU we're booting on must exist and have an IMSIC.
+    let cpu_id = CpuInfo::get()
+        .hart_id_to_cpu(hart_id.try_into().unwrap())
+        .unwrap();
+    let location = imsic
+        .phys_file_location(cpu_id, ImsicFileId::Supervisor)
+        .unwrap();
+    let hart_index = (location.group().bits() << imsic.phys_geometry().hart_index_bits())
+        | location.hart().bits();
+    aplic
+        .route_interrupt(
+            interrupt.source,
+            hart_index as u32,
+            ImsicInterruptId::Uart as u32,
+        )
+        .map_err(UartInterruptError::Aplic)?;
+    uart.enable_rx_interrupt();
+    Ok(())
+}
+
 /// Initialize (H)S-level CSRs to a reasonable state.
 pub fn setup_csrs() {
     // Clear and disable any interupts.
@@ -316,15 +476,12 @@ fn check_vector_width() {
 
     // vlenb converted from bytes to bits
     let rwidth = CSR.vlenb.read(vlenb::value);
-    println!("vector register width: {} bits", rwidth * 8);
-    if rwidth > MAX_VECTOR_REGISTER_LEN as u64 {
-        println!(
-            "Vector registers too wide: {} bits, maximum is {} bits",
-            rwidth * 8,
-            MAX_VECTOR_REGISTER_LEN * 8
-        );
-        panic!("Aborting boot.");
+    if log_enabled(LogLevel::Info) {
+        println!("vector register width: {} bits", rwidth * 8);
     }
+    // vCPU vector state is sized based on the register width, so this must be set before any vCPUs
+    // are created.
+    VmCpus::set_vector_register_len(rwidth);
     // Turn vectors off
     CSR.sstatus.read_and_clear_bits(sstatus::vs::Dirty.value);
 }
@@ -356,7 +513,6 @@ extern "C" fn _primary_main() {}
 #[derive(Debug)]
 enum RequiredCpuFeature {
     Aia,
-    Sstc,
 }
 
 #[derive(Debug)]
@@ -382,6 +538,8 @@ impl Display for RequiredDeviceProbe {
 /// Errors from initialization
 #[derive(Debug)]
 enum Error {
+    /// Invalid hypervisor configuration in the device tree
+    BootConfig(BootConfigError),
     /// Problem building memory map
     BuildMemoryMap(MemMapError),
     /// CPU missing feature
@@ -406,6 +564,8 @@ enum Error {
     HeapReserve(MemMapError),
     /// Kernel is missing
     KernelMissing,
+    /// Loading the host VM failed
+    LoadHostVm(host_vm::Error),
     /// Loading user-mode binary failed
     LoadUserMode(riscv_elf::Error),
     /// Probing of required device failed
@@ -422,6 +582,7 @@ impl Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         use Error::*;
         match self {
+            BootConfig(e) => write!(f, "Invalid boot configuration: {}", e),
             BuildMemoryMap(e) => write!(f, "Failed to build memory map: {:?}", e),
             CpuMissingFeature(feature) => write!(f, "Missing required CPU feature: {:?}", feature),
             CpuTopologyGeneration(e) => write!(f, "Failed to generate CPU topology: {}", e),
@@ -436,6 +597,7 @@ impl Display for Error {
             HeapUnaligned => write!(f, "Heap memory is unaligned"),
             HeapReserve(e) => write!(f, "Error reserving heap memory: {:?}", e),
             KernelMissing => write!(f, "No host kernel image"),
+            LoadHostVm(e) => write!(f, "Failed to load host VM: {}", e),
             LoadUserMode(e) => write!(f, "Cannot load user-mode ELF binary: {:?}", e),
             RequiredDeviceProbe(e) => write!(f, "Failed to probe required device: {}", e),
             SetupUserMode(e) => write!(f, "Failed to setup user-mode: {:?}", e),
@@ -463,7 +625,22 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
     let hyp_fdt =
         unsafe { Fdt::new_from_raw_pointer(fdt_addr as *const u8) }.map_err(Error::FdtParsing)?;
 
-    let mut mem_map = build_memory_map(&hyp_fdt).map_err(Error::BuildMemoryMap)?;
+    // Read the hypervisor's own settings before sizing anything.
+    let config = BootConfig::from_fdt(&hyp_fdt).map_err(Error::BootConfig)?;
+    set_log_level(config.log_level());
+    if log_enabled(LogLevel::Debug) {
+        println!("Boot configuration: {:?}", config);
+    }
+
+    let mut mem_map =
+        build_memory_map(&hyp_fdt, config.crash_dump()).map_err(Error::BuildMemoryMap)?;
+    if let Some(r) = config.crash_dump() {
+        crash_dump::init(r.base(), r.size(), hart_id);
+    }
+
+    // Measure the platform's code and the host's images before anything else touches them.
+    let mut platform_log = PlatformLog::default();
+    measure_boot_images(&hyp_fdt, &mut platform_log);
 
     // Find where QEMU loaded the host kernel image.
     let host_kernel = *mem_map
@@ -478,7 +655,7 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
         .cloned();
 
     // Create a heap for boot-time memory allocations.
-    create_heap(&mut mem_map)?;
+    create_heap(&mut mem_map, config.heap_size())?;
 
     let hyp_dt = DeviceTree::from(&hyp_fdt).map_err(Error::FdtCreation)?;
 
@@ -493,73 +670,105 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
         // We require AIA support for interrupts and SMP support; no point continuing without it.
         return Err(Error::CpuMissingFeature(RequiredCpuFeature::Aia));
     }
-    if !cpu_info.has_sstc() {
-        // We don't implement or use the SBI timer extension and thus require Sstc for timers.
-        return Err(Error::CpuMissingFeature(RequiredCpuFeature::Sstc));
+    if cpu_info.has_sstc() {
+        // Only write henvcfg when Sstc is present to avoid blowing up on versions of QEMU which
+        // don't support the *envcfg registers.
+        CSR.henvcfg.modify(henvcfg::stce.val(1));
+    } else {
+        // Without Sstc, guest timers are virtualized in software on top of the SBI TIME extension.
+        if log_enabled(LogLevel::Info) {
+            println!("No Sstc support, emulating guest timers");
+        }
     }
-    // Only write henvcfg when Sstc is present to avoid blowing up on versions of QEMU which
-    // don't support the *envcfg registers.
-    CSR.henvcfg.modify(henvcfg::stce.val(1));
     if cpu_info.has_sscofpmf() {
         // Only probe for PMU counters if we have Sscofpmf; we can't expose counters to guests
         // unless we have support for per-mode filtering.
-        println!("Sscofpmf support present");
+        if log_enabled(LogLevel::Info) {
+            println!("Sscofpmf support present");
+        }
         if let Err(e) = PmuInfo::init() {
             test_declare_fail!("PMU counters");
-            println!("PmuInfo::init() failed with {:?}", e);
+            if log_enabled(LogLevel::Warn) {
+                println!("PmuInfo::init() failed with {:?}", e);
+            }
         } else {
             test_declare_pass!("PMU counters");
+            // Counter overflow interrupts trap to us so that we can attribute them to the running
+            // vCPU before injecting them with HVIP.
+            CSR.hvien.modify(hvien::lcofi.val(1));
         }
     }
     if cpu_info.has_vector() {
-        // Will panic if register width too long. (currently 256 bits)
         check_vector_width();
-    } else {
+    } else if log_enabled(LogLevel::Info) {
         println!("No vector support");
     }
 
-    println!(
-        "{} CPU(s) present. Booting on CPU{} (hart {})",
-        cpu_info.num_cpus(),
-        cpu_info
-            .hart_id_to_cpu(hart_id.try_into().unwrap())
-            .unwrap()
-            .raw(),
-        hart_id
-    );
+    if log_enabled(LogLevel::Info) {
+        println!(
+            "{} CPU(s) present. Booting on CPU{} (hart {})",
+            cpu_info.num_cpus(),
+            cpu_info
+                .hart_id_to_cpu(hart_id.try_into().unwrap())
+                .unwrap()
+                .raw(),
+            hart_id
+        );
+    }
 
     // Probe for the IMSIC.
     Imsic::probe_from(&hyp_dt, &mut mem_map)
         .map_err(|e| Error::RequiredDeviceProbe(RequiredDeviceProbe::Imsic(e)))?;
     let imsic_geometry = Imsic::get().phys_geometry();
-    println!(
-        "IMSIC at 0x{:08x}; {} guest interrupt files supported",
-        imsic_geometry.base_addr().bits(),
-        imsic_geometry.guests_per_hart()
-    );
+    if log_enabled(LogLevel::Info) {
+        println!(
+            "IMSIC at 0x{:08x}; {} guest interrupt files supported",
+            imsic_geometry.base_addr().bits(),
+            imsic_geometry.guests_per_hart()
+        );
+    }
     Imsic::setup_this_cpu();
 
-    // Probe for a PCI bus.
-    PcieRoot::probe_from(&hyp_dt, &mut mem_map)
-        .map_err(|e| Error::RequiredDeviceProbe(RequiredDeviceProbe::Pci(e)))?;
-    let pci = PcieRoot::get();
-    for dev in pci.devices() {
-        let dev = dev.lock();
+    // Take console input on the UART's interrupt if we can, otherwise poll for it.
+    if let Err(e) = setup_uart_interrupt(&hyp_dt, &mut mem_map, hart_id) &&
+        log_enabled(LogLevel::Warn)
+    {
         println!(
-            "Found func {}; type: {}, MSI: {}, MSI-X: {}, PCIe: {}",
-            dev.info(),
-            dev.info().header_type(),
-            dev.has_msi(),
-            dev.has_msix(),
-            dev.is_pcie(),
+            "UART interrupt unavailable ({:?}), polling for console input",
+            e
         );
-        for bar in dev.bar_info().bars() {
+    }
+
+    // Probe for PCI host bridges.
+    PcieRoots::probe_from(&hyp_dt, &mut mem_map)
+        .map_err(|e| Error::RequiredDeviceProbe(RequiredDeviceProbe::Pci(e)))?;
+    let pci = PcieRoots::get();
+    if log_enabled(LogLevel::Info) {
+        for root in pci.iter() {
+            println!(
+                "PCI segment {} at 0x{:08x}",
+                root.segment(),
+                root.config_space().base().bits()
+            );
+        }
+        for dev in pci.devices() {
+            let dev = dev.lock();
             println!(
-                "BAR{:}: type {:?}, size 0x{:x}",
-                bar.index(),
-                bar.bar_type(),
-                bar.size()
+                "Found func {}; type: {}, MSI: {}, MSI-X: {}, PCIe: {}",
+                dev.info(),
+                dev.info().header_type(),
+                dev.has_msi(),
+                dev.has_msix(),
+                dev.is_pcie(),
             );
+            for bar in dev.bar_info().bars() {
+                println!(
+                    "BAR{:}: type {:?}, size 0x{:x}",
+                    bar.index(),
+                    bar.bar_type(),
+                    bar.size()
+                );
+            }
         }
     }
 
@@ -590,59 +799,69 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
         - mem_map.regions().next().unwrap().base().bits();
 
     // Parse the user-mode ELF containing the user-mode task.
-    // Safe, because it comes from the Linker
-    let umode_bytes = unsafe {
-        let umode_bin = core::ptr::addr_of!(_umode_bin) as *const u8;
-        let umode_bin_len = core::ptr::addr_of!(_umode_bin_len) as usize;
-        core::slice::from_raw_parts::<u8>(umode_bin, umode_bin_len)
-    };
-    let umode_elf = ElfMap::new(umode_bytes).map_err(Error::LoadUserMode)?;
-
-    println!("HW memory map:");
-    for (i, r) in mem_map.regions().enumerate() {
-        println!(
-            "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
-            i,
-            r.base().bits(),
-            r.end().bits() - 1,
-            r.region_type()
-        );
-    }
+    let umode_elf = ElfMap::new(umode_bytes()).map_err(Error::LoadUserMode)?;
+    // Pick where to load it. Without a seed from the bootloader fall back to the time since reset,
+    // which makes for a guessable address.
+    let umode_seed = config.rng_seed().unwrap_or_else(|| {
+        if umode_elf.is_pie() && log_enabled(LogLevel::Warn) {
+            println!("No /chosen/rng-seed, U-mode load address will be predictable");
+        }
+        CSR.hpmcounter[1].get_value()
+    });
+    let umode_load_bias =
+        HypMap::umode_load_bias(&umode_elf, umode_seed).map_err(Error::CreateHypervisorMap)?;
+
+    if log_enabled(LogLevel::Info) {
+        println!("HW memory map:");
+        for (i, r) in mem_map.regions().enumerate() {
+            println!(
+                "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
+                i,
+                r.base().bits(),
+                r.end().bits() - 1,
+                r.region_type()
+            );
+        }
 
-    println!("umode memory map:");
-    for (i, s) in umode_elf.segments().enumerate() {
-        println!(
-            "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
-            i,
-            s.vaddr(),
-            s.vaddr() + s.size() as u64,
-            s.perms()
-        );
+        println!("umode memory map:");
+        for (i, s) in umode_elf.segments().enumerate() {
+            println!(
+                "[{:02}] region: 0x{:016x} -> 0x{:016x}, {}",
+                i,
+                s.vaddr().wrapping_add(umode_load_bias),
+                (s.vaddr() + s.size() as u64).wrapping_add(umode_load_bias),
+                s.perms()
+            );
+        }
     }
 
     // Create the hypervisor mapping from the hardware memory map and the U-mode ELF.
-    HypMap::init(mem_map, &umode_elf).map_err(Error::CreateHypervisorMap)?;
+    HypMap::init(mem_map, &umode_elf, umode_load_bias).map_err(Error::CreateHypervisorMap)?;
 
     // Set up per-CPU memory and prepare the structures for secondary CPUs boot.
-    PerCpu::init(hart_id, &mut hyp_mem).map_err(Error::CreateSmpState)?;
+    PerCpu::init(hart_id, config.stack_pages(), &mut hyp_mem).map_err(Error::CreateSmpState)?;
 
     // Find and initialize the IOMMU.
-    match Iommu::probe_from(PcieRoot::get(), &mut || {
+    match Iommu::probe_from(PcieRoots::get(), &mut || {
         hyp_mem.take_pages_for_host_state(1).into_iter().next()
     }) {
         Ok(_) => {
-            println!(
-                "Found RISC-V IOMMU version 0x{:x}",
-                Iommu::get().unwrap().version()
-            );
+            if log_enabled(LogLevel::Info) {
+                println!(
+                    "Found RISC-V IOMMU version 0x{:x}",
+                    Iommu::get().unwrap().version()
+                );
+            }
         }
         Err(e) => {
-            println!("Failed to probe IOMMU: {:?}", e);
+            if log_enabled(LogLevel::Warn) {
+                println!("Failed to probe IOMMU: {:?}", e);
+            }
         }
     };
 
     // Initialize global Umode state.
-    UmodeTask::init(umode_elf);
+    UmodeTask::init(umode_elf, umode_load_bias);
 
     // Now load the host VM.
     let host = HostVmLoader::new(
@@ -651,13 +870,29 @@ fn primary_init(hart_id: u64, fdt_addr: u64) -> Result<CpuParams, Error> {
         host_initramfs,
         guest_ram_base,
         guest_phys_size,
+        config.max_tvms(),
+        config.runtime_heap_pages(),
         hyp_mem,
     )
-    .build_device_tree()
+    .map_err(Error::LoadHostVm)?
+    .build_device_tree(&mut platform_log)
     .build_address_space();
 
-    // Lock down the boot time allocator before allowing the host VM to be entered.
+    // The platform measurements are complete; make them available to TVMs before the host can
+    // create any.
+    if log_enabled(LogLevel::Info) {
+        println!("Platform event log:");
+        for e in platform_log.events() {
+            println!("  {}", e);
+        }
+    }
+    platform_log.seal();
+
+    // Lock down the boot time allocator before allowing the host VM to be entered, and switch to
+    // the runtime allocator which can free memory and grow on demand.
     HYPERVISOR_ALLOCATOR.get().unwrap().seal();
+    let page_tracker = host.vm().page_tracker();
+    RUNTIME_ALLOCATOR.call_once(|| SlabAlloc::new(RuntimeHeapBackend { page_tracker }));
 
     smp::start_secondary_cpus().map_err(Error::StartSecondaryCpus)?;
 
@@ -691,6 +926,8 @@ extern "C" fn _primary_init(hart_id: u64, fdt_addr: u64) -> CpuParams {
 fn primary_main() -> Result<(), Error> {
     // Install trap handler with stack overflow checking.
     trap::install_trap_handler();
+    #[cfg(feature = "gdbstub")]
+    gdbstub::wait_for_debugger();
     // Setup U-mode task for this CPU.
     UmodeTask::setup_this_cpu().map_err(Error::SetupUserMode)?;
     // Do a NOP request to the U-mode task to check it's functional in this CPU.
@@ -735,6 +972,9 @@ fn secondary_init(hart_id: u64) -> Result<CpuParams, Error> {
     if cpu_info.has_sstc() {
         CSR.henvcfg.modify(henvcfg::stce.val(1));
     }
+    if PmuInfo::get().is_ok() {
+        CSR.hvien.modify(hvien::lcofi.val(1));
+    }
     Imsic::setup_this_cpu();
 
     let this_cpu = PerCpu::this_cpu();
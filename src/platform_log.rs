@@ -0,0 +1,261 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! The platform event log: measurements of the code and configuration making up the platform's
+//! TCB, taken at boot and folded into the platform PCRs of each TVM's attestation evidence.
+//!
+//! As with a TCG event log, each event records the digest that its PCR was extended with, so a
+//! relying party can replay the log to check PCR0 and PCR1 and then evaluate each event. VMs fetch
+//! the log with `GET_PLATFORM_LOG_FID`.
+
+use arrayvec::ArrayVec;
+use attestation::{AttestationManager, Result as AttestationResult, TcgPcrIndex};
+use core::fmt;
+use digest::Digest;
+use sync::Once;
+
+// The digest algorithm used for platform measurements; the same as used for TVM measurements.
+type PlatformDigest = sha2::Sha384;
+
+// Size in bytes of a platform measurement.
+const PLATFORM_DIGEST_SIZE: usize = 48;
+
+// One of each event type at most.
+const MAX_PLATFORM_EVENTS: usize = 5;
+
+static PLATFORM_LOG: Once<PlatformLog> = Once::new();
+
+/// Function ID of the call returning the platform event log. This is a Salus extension to the
+/// attestation interface which `AttestationFunction` doesn't cover.
+///
+/// `(log_addr_out, log_size)`: writes the log to `log_addr_out` as one `PLATFORM_EVENT_SIZE`-byte
+/// record per event, in the order they were measured, and returns the size of the log in bytes.
+/// Fails with `InsufficientBufferCapacity` if `log_size` is too small for the whole log.
+pub const GET_PLATFORM_LOG_FID: u64 = 0x1000;
+
+/// The size of an event as returned by `GET_PLATFORM_LOG_FID`: the PCR index and event type as a
+/// byte each, 6 bytes of padding, the size of the measured component as a little-endian u64, and
+/// its digest.
+pub const PLATFORM_EVENT_SIZE: usize = 16 + PLATFORM_DIGEST_SIZE;
+
+/// The component of the platform measured by an event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlatformEventType {
+    /// Salus' text and read-only data, from `_start` to the end of the read-only segment.
+    SalusImage = 0,
+    /// The U-mode binary embedded in Salus.
+    UmodeImage = 1,
+    /// The host kernel image as loaded by firmware.
+    HostKernel = 2,
+    /// The host initramfs as loaded by firmware.
+    HostInitramfs = 3,
+    /// The flattened device tree given to the host.
+    HostDeviceTree = 4,
+}
+
+impl PlatformEventType {
+    /// Returns the PCR that events of this type are measured into. Salus' own code goes into
+    /// PCR0, while the host's images are platform configuration from a TVM's point of view.
+    pub fn pcr(&self) -> TcgPcrIndex {
+        use PlatformEventType::*;
+        match self {
+            SalusImage | UmodeImage => TcgPcrIndex::PlatformCode,
+            HostKernel | HostInitramfs | HostDeviceTree => TcgPcrIndex::PlatformConfiguration,
+        }
+    }
+}
+
+impl fmt::Display for PlatformEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PlatformEventType::*;
+        let name = match self {
+            SalusImage => "salus image",
+            UmodeImage => "umode image",
+            HostKernel => "host kernel",
+            HostInitramfs => "host initramfs",
+            HostDeviceTree => "host device tree",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A single measurement in the platform event log.
+#[derive(Clone, Debug)]
+pub struct PlatformEvent {
+    event_type: PlatformEventType,
+    size: u64,
+    digest: [u8; PLATFORM_DIGEST_SIZE],
+}
+
+impl fmt::Display for PlatformEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PCR{} {} (0x{:x} bytes): ",
+            self.event_type.pcr() as u8,
+            self.event_type,
+            self.size
+        )?;
+        for b in self.digest.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl PlatformEvent {
+    /// Returns the event as it's laid out in the log returned by `GET_PLATFORM_LOG_FID`.
+    pub fn encode(&self) -> [u8; PLATFORM_EVENT_SIZE] {
+        let mut bytes = [0u8; PLATFORM_EVENT_SIZE];
+        bytes[0] = self.event_type.pcr() as u8;
+        bytes[1] = self.event_type as u8;
+        bytes[8..16].copy_from_slice(&self.size.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.digest);
+        bytes
+    }
+}
+
+/// The log of platform measurements taken during boot. Built up by the boot CPU and then made
+/// globally available, read-only, with `seal()`.
+#[derive(Default)]
+pub struct PlatformLog {
+    events: ArrayVec<PlatformEvent, MAX_PLATFORM_EVENTS>,
+}
+
+impl PlatformLog {
+    /// Measures `bytes` as the component given by `event_type`, appending the event to the log.
+    pub fn measure(&mut self, event_type: PlatformEventType, bytes: &[u8]) {
+        assert!(self.events.iter().all(|e| e.event_type != event_type));
+        let mut digest = [0u8; PLATFORM_DIGEST_SIZE];
+        digest.copy_from_slice(&PlatformDigest::digest(bytes));
+        // Won't overflow since there's room for one event of each type.
+        self.events.push(PlatformEvent {
+            event_type,
+            size: bytes.len() as u64,
+            digest,
+        });
+    }
+
+    /// Returns an iterator over the events in the log, in the order they were measured.
+    pub fn events(&self) -> impl Iterator<Item = &PlatformEvent> {
+        self.events.iter()
+    }
+
+    /// Returns the size in bytes of the log as returned by `GET_PLATFORM_LOG_FID`.
+    pub fn encoded_size(&self) -> usize {
+        self.events.len() * PLATFORM_EVENT_SIZE
+    }
+
+    /// Extends the platform PCRs of `attestation_mgr` with each event in the log, in order.
+    pub fn extend_pcrs(
+        &self,
+        attestation_mgr: &AttestationManager<PlatformDigest>,
+    ) -> AttestationResult<()> {
+        for e in self.events() {
+            attestation_mgr.extend_msmt_register(e.event_type.pcr(), &e.digest, None)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the log globally available. No further measurements can be added after this.
+    pub fn seal(self) {
+        PLATFORM_LOG.call_once(|| self);
+    }
+
+    /// Returns the sealed platform log, or `None` if boot measurements haven't been completed.
+    pub fn get() -> Option<&'static PlatformLog> {
+        PLATFORM_LOG.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    fn test_log() -> PlatformLog {
+        let mut log = PlatformLog::default();
+        log.measure(PlatformEventType::SalusImage, b"salus");
+        log.measure(PlatformEventType::HostKernel, b"kernel");
+        log
+    }
+
+    #[test_case]
+    fn PlatformLogMeasureTest() -> TestResult {
+        let log = test_log();
+        let events: ArrayVec<&PlatformEvent, MAX_PLATFORM_EVENTS> = log.events().collect();
+        test_result_true!(
+            events.len() == 2
+                && events[0].event_type == PlatformEventType::SalusImage
+                && events[1].event_type == PlatformEventType::HostKernel,
+            "PlatformLog::events order"
+        )?;
+        test_result_true!(
+            events[1].size == 6 && events[1].digest[..] == PlatformDigest::digest(b"kernel")[..],
+            "PlatformLog::measure digest"
+        )?;
+        test_result_true!(
+            PlatformEventType::UmodeImage.pcr() == TcgPcrIndex::PlatformCode
+                && PlatformEventType::HostDeviceTree.pcr() == TcgPcrIndex::PlatformConfiguration,
+            "PlatformEventType::pcr"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn PlatformLogEncodeTest() -> TestResult {
+        let log = test_log();
+        test_result_true!(
+            log.encoded_size() == 2 * PLATFORM_EVENT_SIZE,
+            "PlatformLog::encoded_size"
+        )?;
+        let event = log.events().nth(1).unwrap().encode();
+        test_result_true!(
+            event[0] == TcgPcrIndex::PlatformConfiguration as u8
+                && event[1] == PlatformEventType::HostKernel as u8
+                && event[2..8] == [0; 6]
+                && event[8..16] == 6u64.to_le_bytes()
+                && event[16..] == PlatformDigest::digest(b"kernel")[..],
+            "PlatformEvent::encode"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn PlatformLogExtendPcrsTest() -> TestResult {
+        let log = test_log();
+        let attestation_mgr = AttestationManager::<PlatformDigest>::new(
+            b"TESTATTESTATIONCDI",
+            b"TESTSEALINGCDI",
+            1,
+            const_oid::db::rfc5912::ID_SHA_384,
+        )
+        .expect("AttestationManager::new");
+        log.extend_pcrs(&attestation_mgr)
+            .expect("PlatformLog::extend_pcrs");
+        // Each PCR starts out zeroed, and here has one event extended into it.
+        let pcr_after = |bytes: &[u8]| {
+            let mut hasher = PlatformDigest::new();
+            hasher.update([0u8; PLATFORM_DIGEST_SIZE]);
+            hasher.update(PlatformDigest::digest(bytes));
+            hasher.finalize()
+        };
+        test_result_true!(
+            attestation_mgr
+                .read_msmt_register(TcgPcrIndex::PlatformCode)
+                .expect("read PCR0")[..]
+                == pcr_after(b"salus")[..],
+            "PlatformLog::extend_pcrs PCR0"
+        )?;
+        test_result_true!(
+            attestation_mgr
+                .read_msmt_register(TcgPcrIndex::PlatformConfiguration)
+                .expect("read PCR1")[..]
+                == pcr_after(b"kernel")[..],
+            "PlatformLog::extend_pcrs PCR1"
+        )?;
+        Ok(())
+    }
+}
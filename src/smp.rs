@@ -15,10 +15,14 @@ use s_mode_utils::print::*;
 use sbi_rs::api::state;
 use sync::Once;
 
-use crate::hyp_layout::HYP_STACK_PAGES;
+use crate::hyp_layout;
 use crate::hyp_map::{self, HypMap, HypPageTable};
 use crate::umode::UmodeTask;
+use crate::vm_cpu::LazyRegsTracker;
+use crate::vm_debug::StepTrigger;
 use crate::vm_id::VmIdTracker;
+use crate::vm_interrupts::GuestFileBindings;
+use crate::vm_timer::VmTimerQueue;
 
 extern "C" {
     static _stack_start: u8;
@@ -33,6 +37,10 @@ extern "C" {
 pub struct PerCpu {
     cpu_id: CpuId,
     vmid_tracker: RefCell<VmIdTracker>,
+    guest_files: RefCell<GuestFileBindings>,
+    lazy_regs: RefCell<LazyRegsTracker>,
+    vm_timers: RefCell<VmTimerQueue>,
+    step_trigger: RefCell<StepTrigger>,
     page_table: HypPageTable,
     umode_task: Once<RefCell<UmodeTask>>,
     online: Once<bool>,
@@ -65,9 +73,14 @@ impl fmt::Display for Error {
 }
 
 impl PerCpu {
-    /// Initializes the `PerCpu` structures for each CPU, taking memory from `mem_map`. This (the
-    /// boot CPU's) per-CPU area is initialized and loaded into TP as well.
-    pub fn init(boot_hart_id: u64, hyp_mem: &mut HypPageAlloc) -> Result<(), Error> {
+    /// Initializes the `PerCpu` structures for each CPU, taking memory from `mem_map`. Each CPU gets
+    /// a stack of `stack_pages` 4k-pages. This (the boot CPU's) per-CPU area is initialized and
+    /// loaded into TP as well.
+    pub fn init(
+        boot_hart_id: u64,
+        stack_pages: u64,
+        hyp_mem: &mut HypPageAlloc,
+    ) -> Result<(), Error> {
         let cpu_info = CpuInfo::get();
         let boot_cpu = cpu_info
             .hart_id_to_cpu(boot_hart_id as u32)
@@ -81,16 +94,18 @@ impl PerCpu {
         PER_CPU_BASE.call_once(|| pcpu_base);
 
         VmIdTracker::init();
+        hyp_layout::set_hyp_stack_pages(stack_pages);
 
         for i in 0..cpu_info.num_cpus() {
             let cpu_id = CpuId::new(i);
-            // Boot CPU is special. Doesn't use the PCPU_BASE area as stack.
-            let stack_pages = if cpu_id == boot_cpu {
-                Self::boot_cpu_stack()?
+            // Boot CPU is special. It keeps the stack it's running on if that's big enough.
+            let boot_stack = if cpu_id == boot_cpu {
+                Self::boot_cpu_stack(stack_pages)
             } else {
-                // Unwrap okay. We allocated the area for `num_cpus() - 1` pages and we skip the
-                // bootstrap CPU above.
-                let pcpu_stack = hyp_mem.take_pages_for_hyp_state(HYP_STACK_PAGES as usize);
+                None
+            };
+            let stack = boot_stack.unwrap_or_else(|| {
+                let pcpu_stack = hyp_mem.take_pages_for_hyp_state(stack_pages as usize);
                 // Change state from InternalClean to InternalDirty. Pages are clean but this is not
                 // important for the stack (in the boot CPU case, pages are dirty because the stack is
                 // in use).
@@ -103,17 +118,18 @@ impl PerCpu {
                     )
                     .unwrap()
                 }
-            };
-            let stack_top_addr = stack_pages
-                .base()
-                .checked_add_pages(stack_pages.len())
-                .unwrap();
+            });
+            let stack_top_addr = stack.base().checked_add_pages(stack.len()).unwrap();
             let ptr = Self::ptr_for_cpu(cpu_id);
             let pcpu = PerCpu {
                 cpu_id,
                 vmid_tracker: RefCell::new(VmIdTracker::new()),
+                guest_files: RefCell::new(GuestFileBindings::new()),
+                lazy_regs: RefCell::new(LazyRegsTracker::new()),
+                vm_timers: RefCell::new(VmTimerQueue::new()),
+                step_trigger: RefCell::new(StepTrigger::new()),
                 page_table: HypMap::get()
-                    .new_page_table(hyp_mem, stack_pages)
+                    .new_page_table(hyp_mem, stack)
                     .map_err(Error::CreateStackPageTable)?,
                 umode_task: Once::new(),
                 online: Once::new(),
@@ -149,22 +165,29 @@ impl PerCpu {
         Ok(())
     }
 
-    fn boot_cpu_stack() -> Result<SequentialPages<InternalDirty>, Error> {
-        // Get the pages of the current stack as created by the linker.
-        // Safe because `_stack_start` is created by the linker.
-        let stack_startaddr = unsafe {
-            PageAddr::new(RawAddr::supervisor(core::ptr::addr_of!(_stack_start) as u64))
-                .expect("_stack_start is not page aligned.")
-        };
-        // Safe because `_stack_end` is created by the linker.
-        let stack_endaddr = unsafe {
-            PageAddr::new(RawAddr::supervisor(core::ptr::addr_of!(_stack_end) as u64))
-                .expect("_stack_end is not page aligned.")
+    // Returns the top `num_pages` pages of the stack created by the linker for the boot CPU, or
+    // `None` if it isn't that big. The boot CPU switches to the top of its mapped stack once the
+    // hypervisor page table is enabled, so it doesn't matter that the lower pages are in use now.
+    fn boot_cpu_stack(num_pages: u64) -> Option<SequentialPages<InternalDirty>> {
+        // Safe because `_stack_start` and `_stack_end` are created by the linker.
+        let (stack_start, stack_end) = unsafe {
+            (
+                core::ptr::addr_of!(_stack_start) as u64,
+                core::ptr::addr_of!(_stack_end) as u64,
+            )
         };
+        let stack_startaddr = stack_end
+            .checked_sub(num_pages * PageSize::Size4k as u64)
+            .filter(|&addr| addr >= stack_start)
+            .map(|addr| {
+                PageAddr::new(RawAddr::supervisor(addr)).expect("_stack_end is not page aligned.")
+            })?;
+        let stack_endaddr =
+            PageAddr::new(RawAddr::supervisor(stack_end)).expect("_stack_end is not page aligned.");
         // Safe because the pages in this range are in the `HypervisorImage` memory region and are only
         // used for the boot cpu stack.
         unsafe {
-            Ok(
+            Some(
                 SequentialPages::from_page_range(stack_startaddr, stack_endaddr, PageSize::Size4k)
                     .unwrap(),
             )
@@ -203,11 +226,39 @@ impl PerCpu {
         pcpu
     }
 
+    /// Returns this CPU's `PerCpu` structure, or `None` if TP doesn't point to one yet. For use on
+    /// paths that may run before this CPU is set up, such as the panic handler.
+    pub fn try_this_cpu() -> Option<&'static PerCpu> {
+        PER_CPU_BASE.get()?;
+        let tp: u64;
+        unsafe {
+            // Safe since we're the only users of TP.
+            asm!("mv {rd}, tp", rd = out(reg) tp)
+        };
+        (0..CpuInfo::get().num_cpus())
+            .map(CpuId::new)
+            .find(|&cpu_id| Self::ptr_for_cpu(cpu_id) as u64 == tp)
+            .map(|_| {
+                // Safe since TP points to one of the PerCpu structs set up in init().
+                unsafe { (tp as *const PerCpu).as_ref().unwrap() }
+            })
+    }
+
     /// Returns this CPU's ID.
     pub fn cpu_id(&self) -> CpuId {
         self.cpu_id
     }
 
+    /// Returns true if `cpu_id` has come online.
+    pub fn is_online(cpu_id: CpuId) -> bool {
+        if cpu_id.raw() >= CpuInfo::get().num_cpus() {
+            return false;
+        }
+        // Safe since the PerCpu structs for all CPUs are set up in init().
+        let pcpu = unsafe { PerCpu::ptr_for_cpu(cpu_id).as_ref().unwrap() };
+        pcpu.online.get().is_some()
+    }
+
     /// Marks this CPU as online.
     pub fn set_online(&self) {
         self.online.call_once(|| true);
@@ -236,6 +287,28 @@ impl PerCpu {
     pub fn vmid_tracker_mut(&self) -> RefMut<VmIdTracker> {
         self.vmid_tracker.borrow_mut()
     }
+
+    /// Returns a mutable reference to the vCPU bindings of this CPU's guest interrupt files.
+    pub fn guest_files_mut(&self) -> RefMut<GuestFileBindings> {
+        self.guest_files.borrow_mut()
+    }
+
+    /// Returns a mutable reference to the tracker of which vCPU state is loaded in this CPU's
+    /// lazily-switched registers.
+    pub fn lazy_regs_mut(&self) -> RefMut<LazyRegsTracker> {
+        self.lazy_regs.borrow_mut()
+    }
+
+    /// Returns a mutable reference to the queue of vCPU timer deadlines multiplexed onto this CPU's
+    /// supervisor timer.
+    pub fn vm_timers_mut(&self) -> RefMut<VmTimerQueue> {
+        self.vm_timers.borrow_mut()
+    }
+
+    /// Returns a mutable reference to the trigger used to single-step guests on this CPU.
+    pub fn step_trigger_mut(&self) -> RefMut<StepTrigger> {
+        self.step_trigger.borrow_mut()
+    }
 }
 
 // PerCpu state obviously cannot be shared between threads.
@@ -282,7 +355,9 @@ pub fn start_secondary_cpus() -> Result<(), Error> {
         pcpu.online.wait();
     }
 
-    println!("Brought online {} CPU(s)", cpu_info.num_cpus());
+    if log_enabled(LogLevel::Info) {
+        println!("Brought online {} CPU(s)", cpu_info.num_cpus());
+    }
 
     Ok(())
 }
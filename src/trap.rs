@@ -7,13 +7,20 @@ use core::fmt;
 use core::mem::size_of;
 use drivers::imsic::{Imsic, ImsicInterruptId};
 use memoffset::offset_of;
+#[cfg(feature = "gdbstub")]
+use riscv_regs::Exception;
 use riscv_regs::{
     sie, GeneralPurposeRegisters, GprIndex, Interrupt, Readable, RiscvCsrInterface, Trap,
     Writeable, CSR,
 };
 use s_mode_utils::print::*;
 
-use crate::hyp_layout::HYP_STACK_BOTTOM;
+use crate::backtrace::{backtrace_from, Symbolized};
+use crate::crash_dump::{self, TrapRegs};
+use crate::debug_console;
+#[cfg(feature = "gdbstub")]
+use crate::gdbstub;
+use crate::smp::PerCpu;
 
 #[no_mangle]
 static overflow_stack_lock: u64 = 0;
@@ -26,6 +33,21 @@ struct TrapFrame {
     sepc: u64,
 }
 
+impl TrapFrame {
+    // Returns the registers saved in this frame in the form kept in crash dumps.
+    fn regs(&self) -> TrapRegs {
+        let mut gprs = [0; 32];
+        for (i, reg) in gprs.iter_mut().enumerate() {
+            *reg = self.gprs.reg(GprIndex::from_raw(i as u32).unwrap());
+        }
+        TrapRegs {
+            sepc: self.sepc,
+            sstatus: self.sstatus,
+            gprs,
+        }
+    }
+}
+
 impl fmt::Display for TrapFrame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
@@ -151,25 +173,42 @@ global_asm!(
     tf_sp = const gpr_offset(GprIndex::SP),
     tf_sstatus = const offset_of!(TrapFrame, sstatus),
     tf_sepc = const offset_of!(TrapFrame, sepc),
-    hyp_stack_bottom = const HYP_STACK_BOTTOM,
 );
 
 /// Attempts to handle an interrupt, returning true if the interrupt was successfully handled.
-fn handle_interrupt(irq: Interrupt) -> bool {
+pub fn handle_interrupt(irq: Interrupt) -> bool {
+    handle_interrupt_from(irq, None)
+}
+
+// Handles an interrupt taken from the hypervisor, with the interrupted registers in `tf`, or from a
+// guest.
+fn handle_interrupt_from(irq: Interrupt, tf: Option<&TrapFrame>) -> bool {
     match irq {
         Interrupt::SupervisorExternal => {
             let mut handled = false;
             while let Some(id) = Imsic::next_pending_interrupt() {
                 match id {
-                    // For now IPIs just wake up the CPU.
+                    // For now IPIs just wake up the CPU, or halt it while another CPU is stopped in
+                    // the debugger or writing a crash dump.
                     ImsicInterruptId::Ipi => {
+                        crash_dump::handle_ipi(tf.map(|tf| tf.regs()).as_ref());
+                        #[cfg(feature = "gdbstub")]
+                        gdbstub::handle_ipi();
+                        handled = true;
+                    }
+                    ImsicInterruptId::Uart => {
+                        debug_console::poll_input();
                         handled = true;
                     }
                 }
             }
             handled
         }
-        // TODO: Handle supervisor guest external interrupts.
+        Interrupt::SupervisorGuestExternal => {
+            // A vCPU bound to one of our guest interrupt files received an interrupt while we were
+            // idle. Record it so the host can be told that the vCPU is runnable.
+            PerCpu::this_cpu().guest_files_mut().handle_interrupt()
+        }
         _ => false,
     }
 }
@@ -209,11 +248,22 @@ extern "C" fn handle_stack_overflow(tf_ptr: *mut TrapFrame) {
     let tf = unsafe { tf_ptr.as_mut().unwrap() };
     println!("Stack overflow (please note: T1 register is clobbered below)");
     println!("{}", tf);
+    crash_dump::record_fatal_trap(tf.regs());
+    // The panic below runs on the overflow stack, so walk the frames of the overflowing stack here.
+    println!("Stack overflow backtrace:");
+    println!("{}", Symbolized::pc(tf.sepc));
+    println!("{}", Symbolized::return_address(tf.gprs.reg(GprIndex::RA)));
+    if let Some(bt) = backtrace_from(tf.gprs.reg(GprIndex::S0)) {
+        bt.for_each(|frame| {
+            print!("{}", frame);
+        });
+    }
     panic!("Stack overflow!");
 }
 
 /// The rust entry point for handling traps. The only traps we expect to take in HS mode are IPIs
-/// (to wake the receiving CPU from WFI) and guest page faults while copying to/from guest memory.
+/// and guest external interrupts (to wake the receiving CPU from WFI) and guest page faults while
+/// copying to/from guest memory.
 /// For everything else we just dump state and panic.
 ///
 /// TODO: If/when the serial driver takes locks we will need to bust them here in order to avoid
@@ -228,7 +278,13 @@ extern "C" fn handle_trap(tf_ptr: *mut TrapFrame) {
     if let Ok(t) = Trap::from_scause(scause) {
         match t {
             Trap::Interrupt(i) => {
-                if handle_interrupt(i) {
+                if handle_interrupt_from(i, Some(&*tf)) {
+                    return;
+                }
+            }
+            #[cfg(feature = "gdbstub")]
+            Trap::Exception(Exception::Breakpoint) => {
+                if gdbstub::handle_breakpoint(&mut tf.gprs, &mut tf.sepc) {
                     return;
                 }
             }
@@ -249,6 +305,7 @@ extern "C" fn handle_trap(tf_ptr: *mut TrapFrame) {
 
     println!("SCAUSE: 0x{:08x}, STVAL: 0x{:08x}", scause, stval);
     println!("{}", tf);
+    crash_dump::record_fatal_trap(tf.regs());
 
     panic!("Unexpected trap");
 }
@@ -2,13 +2,14 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::backtrace::Symbolized;
 use crate::hyp_layout::{UmodeSlotId, UMODE_INPUT_SIZE, UMODE_INPUT_START};
 use crate::hyp_map::{Error as HypMapError, HypMap, UmodeSlotPerm};
 use crate::smp::PerCpu;
 use crate::vm::FinalizedVm;
 use crate::vm_pages::{Error as VmPagesError, FinalizedVmPages, GuestUmodeMapping};
 
-use attestation::{AttestationManager, Error as AttestationError};
+use attestation::{AttestationManager, Error as AttestationError, TcgPcrIndex};
 use core::arch::global_asm;
 use core::fmt;
 use core::mem::size_of;
@@ -23,6 +24,8 @@ use riscv_regs::{Exception::UserEnvCall, GeneralPurposeRegisters, GprIndex, Read
 use s_mode_utils::print::*;
 use signature::Signer;
 use sync::Once;
+use tvm_bundle::{MANIFEST_SIZE, SIGNATURE_SIZE};
+use u_mode_api::manifest::ManifestVerification;
 use u_mode_api::{
     CdiOp, CdiSel, Error as UmodeApiError, HypCall, OpResult, TryIntoRegisters, UmodeRequest,
     CDIOP_SIGN_MAXMSG,
@@ -283,6 +286,11 @@ struct UmodeExecutionContext<'a, T: DataInit> {
 
 // Entry for umode task.
 static UMODE_ENTRY: Once<u64> = Once::new();
+// Offset of the umode binary from its link-time addresses, to symbolize its backtraces.
+static UMODE_LOAD_BIAS: Once<u64> = Once::new();
+
+// Maximum number of frames printed in a umode backtrace.
+const UMODE_BACKTRACE_MAX_FRAMES: usize = 64;
 
 /// Represents a per-CPU U-mode task.
 pub struct UmodeTask {
@@ -290,9 +298,11 @@ pub struct UmodeTask {
 }
 
 impl UmodeTask {
-    /// Initialize U-mode tasks. Must be called once before `setup_this_cpu()`.
-    pub fn init(umode_elf: ElfMap) {
-        UMODE_ENTRY.call_once(|| umode_elf.entry());
+    /// Initialize U-mode tasks, loaded `load_bias` bytes above the ELF's link-time addresses. Must
+    /// be called once before `setup_this_cpu()`.
+    pub fn init(umode_elf: ElfMap, load_bias: u64) {
+        UMODE_ENTRY.call_once(|| umode_elf.entry().wrapping_add(load_bias));
+        UMODE_LOAD_BIAS.call_once(|| load_bias);
         // Consumes the ElfMap.
     }
 
@@ -389,6 +399,38 @@ impl UmodeTask {
         Self::execute_request(ctx)
     }
 
+    /// Verifies the signed manifest of a TVM, given as `manifest` and `signature`, against the TVM
+    /// page measurement in `attestation_mgr` and the configuration measurement it will have once
+    /// extended with the TVM's configuration. Neither measurement is changed.
+    pub fn verify_manifest(
+        manifest: &[u8; MANIFEST_SIZE],
+        signature: &[u8; SIGNATURE_SIZE],
+        attestation_mgr: &AttestationManager<sha2::Sha384>,
+    ) -> Result<u64, Error> {
+        let mut input_data = ManifestVerification {
+            manifest: *manifest,
+            signature: *signature,
+            tvm_page_digest: [0; u_mode_api::cert::SHA384_LEN],
+            tvm_config_digest: [0; u_mode_api::cert::SHA384_LEN],
+        };
+        input_data.tvm_page_digest.copy_from_slice(
+            &attestation_mgr
+                .read_msmt_register(TcgPcrIndex::TvmPage)
+                .map_err(Error::Attestation)?,
+        );
+        input_data.tvm_config_digest.copy_from_slice(
+            &attestation_mgr
+                .tvm_configuration_digest()
+                .map_err(Error::Attestation)?,
+        );
+        let ctx = UmodeExecutionContext {
+            input_data: Some(input_data),
+            req: UmodeRequest::VerifyManifest,
+            attestation: None,
+        };
+        Self::execute_request(ctx)
+    }
+
     fn reset(&mut self) -> Result<(), Error> {
         // Initialize umode CPU state to run at ELF entry.
         let mut arch = UmodeCpuArchState::default();
@@ -404,6 +446,10 @@ impl UmodeTask {
             .set_reg(GprIndex::A1, UMODE_INPUT_START);
         // Set U-mode Input Region size as a2.
         arch.umode_regs.gprs.set_reg(GprIndex::A2, UMODE_INPUT_SIZE);
+        // Point tp at the TLS block, if there's one.
+        if let Some(tls_pointer) = HypMap::get().umode_tls_pointer() {
+            arch.umode_regs.gprs.set_reg(GprIndex::TP, tls_pointer);
+        }
         // sstatus set to 0 (by default) is actually okay.
         self.arch = arch;
         // Run task until it initializes itself and calls HypCall::NextOp().
@@ -491,6 +537,38 @@ impl UmodeTask {
         }
     }
 
+    // Prints the umode task's call stack by following its frame pointers.
+    fn print_backtrace(&self) {
+        let load_bias = *UMODE_LOAD_BIAS.get().unwrap();
+        let regs = &self.arch.umode_regs;
+        println!("U-mode backtrace:");
+        println!("{}", Symbolized::umode(regs.sepc, load_bias, false));
+        let mut fp = regs.gprs.reg(GprIndex::S0);
+        for _ in 0..UMODE_BACKTRACE_MAX_FRAMES {
+            // The return address and the caller's frame pointer are saved right below the frame
+            // pointer. Stop at the first frame that can't be read.
+            let mut frame = [0u8; 2 * size_of::<u64>()];
+            if fp % size_of::<u64>() as u64 != 0
+                || fp < frame.len() as u64
+                || HypMap::copy_from_umode(
+                    &mut frame,
+                    RawAddr::supervisor_virt(fp - frame.len() as u64),
+                )
+                .is_err()
+            {
+                break;
+            }
+            // Unwrap ok: both halves are 8 bytes long.
+            let prev_fp = u64::from_le_bytes(frame[..8].try_into().unwrap());
+            let ra = u64::from_le_bytes(frame[8..].try_into().unwrap());
+            if ra == 0 {
+                break;
+            }
+            println!("{}", Symbolized::umode(ra, load_bias, true));
+            fp = prev_fp;
+        }
+    }
+
     fn handle_ecall(
         &mut self,
         attestation: Option<&AttestationManager<sha2::Sha384>>,
@@ -501,6 +579,7 @@ impl UmodeTask {
                 HypCall::Panic => {
                     println!("U-mode panic!");
                     println!("{}", self.arch);
+                    self.print_backtrace();
                     ControlFlow::Break(Err(ExecError::Panic))
                 }
                 HypCall::PutChar(byte) => {
@@ -4,29 +4,45 @@
 
 use attestation::{AttestationManager, Error as AttestationError, TcgPcrIndex};
 use core::{mem, num::Wrapping, ops::ControlFlow, ops::Neg, slice};
-use drivers::{imsic::*, pmu::PmuInfo};
+use drivers::{imsic::*, pci::PcieRoots, pmu::PmuInfo, CpuInfo};
 use page_tracking::collections::PageBox;
 use page_tracking::{LockedPageList, PageList, PageTracker};
-use riscv_page_tables::{GuestStagePageTable, GuestStagePagingMode};
+use riscv_page_tables::{tlb, GuestStagePageTable, GuestStagePagingMode};
 use riscv_pages::*;
-use riscv_regs::{DecodedInstruction, Exception, GprIndex, Instruction, Interrupt, Trap, CSR};
+use riscv_regs::{
+    DecodedInstruction, Exception, GprIndex, Instruction, Interrupt, PrivilegeLevel, Readable,
+    Trap, Writeable, CSR, CSR_STIMECMP,
+};
 use s_mode_utils::print::*;
 use sbi_rs::{salus::*, Error as SbiError, *};
 use sync::Once;
 use u_mode_api::Error as UmodeApiError;
 
-use crate::guest_tracking::{GuestStateGuard, GuestVm, Guests};
+use crate::debug_console;
+use crate::guest_tracking::{Error as GuestTrackingError, GuestStateGuard, GuestVm, Guests};
+use crate::platform_log::{self, PlatformLog, PLATFORM_EVENT_SIZE};
+use crate::smp::PerCpu;
+use crate::trap;
 use crate::umode::{Error as UmodeError, UmodeTask};
 use crate::vm_cpu::{ActiveVmCpu, VmCpu, VmCpuParent, VmCpuStatus, VmCpuTrap, VmCpus, VM_CPUS_MAX};
+use crate::vm_debug::{self, DebugReg, StepTrigger};
+use crate::vm_interrupts::{
+    defer_save, has_save_requests, request_save, take_save_requests, BindLocation,
+};
+use crate::vm_manifest::{self, SignedManifest, SIGNED_MANIFEST_SIZE};
 use crate::vm_pages::Error as VmPagesError;
 use crate::vm_pages::{
     ActiveVmPages, AnyVmPages, InstructionFetchError, PageFaultType, VmPages, VmPagesRef,
 };
+use crate::vm_pci;
+use crate::vm_pmu::{self, FirmwareEvent, PmuSnapshot};
+use crate::vm_timer;
 
 #[derive(Debug)]
 pub enum Error {
     AttestationManagerCreationFailed(attestation::Error),
     AttestationManagerFinalizeFailed(attestation::Error),
+    ManifestVerificationFailed(UmodeError),
     MissingImsicAddress,
     AliasedImsicAddresses,
     MissingBootCpu,
@@ -136,6 +152,7 @@ pub enum VmExitCause {
     Wfi(DecodedInstruction),
     HostInterrupt(Interrupt),
     UnhandledTrap(u64),
+    DebugBreakpoint,
 }
 
 impl VmExitCause {
@@ -252,26 +269,37 @@ pub struct Vm<T: GuestStagePagingMode> {
     attestation_mgr: AttestationSha384,
     // Latched htimedelta (-CSR_TIME) at the time of first VCPU run.
     htimedelta: Once<u64>,
+    // Set if the VM's host may inspect and modify its state through the TVM debug calls.
+    debuggable: bool,
 }
 
 impl<T: GuestStagePagingMode> Vm<T> {
-    /// Creates a new `Vm` using the given initial page table and vCPU tracking table.
+    /// Creates a new `Vm` using the given initial page table and vCPU tracking table. The platform
+    /// measurements taken at boot, if complete, are folded into the VM's platform PCRs; this is
+    /// the case for every VM but the host.
     pub fn new(vm_pages: VmPages<T>, vcpus: VmCpus) -> Result<Self> {
         let vm_id = vm_pages.page_owner_id().raw();
+        let attestation_mgr = AttestationSha384::new(
+            // Fake compound device identifiers (DICE CDI)
+            // TODO Get the CDI from e.g. the TSM driver.
+            b"RANDOMATTESTATIONCDI",
+            b"RANDOMSEALINGCDI",
+            vm_id,
+            const_oid::db::rfc5912::ID_SHA_384,
+        )
+        .map_err(Error::AttestationManagerCreationFailed)?;
+        if let Some(platform_log) = PlatformLog::get() {
+            platform_log
+                .extend_pcrs(&attestation_mgr)
+                .map_err(Error::AttestationManagerCreationFailed)?;
+        }
         Ok(Self {
             vcpus,
             vm_pages,
             guests: None,
-            attestation_mgr: AttestationSha384::new(
-                // Fake compound device identifiers (DICE CDI)
-                // TODO Get the CDI from e.g. the TSM driver.
-                b"RANDOMATTESTATIONCDI",
-                b"RANDOMSEALINGCDI",
-                vm_id,
-                const_oid::db::rfc5912::ID_SHA_384,
-            )
-            .map_err(Error::AttestationManagerCreationFailed)?,
+            attestation_mgr,
             htimedelta: Once::new(),
+            debuggable: false,
         })
     }
 
@@ -291,6 +319,13 @@ impl<T: GuestStagePagingMode> Vm<T> {
         self.vm_pages.page_owner_id()
     }
 
+    /// Sets the TVM attributes requested by the host at creation. They're included in the VM's
+    /// configuration measurement.
+    pub fn set_attributes(&mut self, attributes: u64) {
+        self.debuggable = attributes & vm_debug::TVM_ATTR_DEBUG != 0;
+        self.attestation_mgr.set_attributes(attributes);
+    }
+
     /// Returns the `PageTracker` singleton.
     pub fn page_tracker(&self) -> PageTracker {
         self.vm_pages.page_tracker()
@@ -328,7 +363,29 @@ impl<T: GuestStagePagingMode> Vm<T> {
 
     /// Completes intialization of the `Vm`, setting the entry point of the VM to `entry_sepc` and
     /// and `entry_arg`. The caller must ensure that it is currently in the initializing state.
-    pub fn finalize(&mut self, entry_sepc: u64, entry_arg: u64) -> Result<()> {
+    ///
+    /// If `signed_manifest` is given it must verify against the VM's measurements, and its signer is
+    /// measured into the VM's identity register. The VM is left unchanged if it doesn't verify, so
+    /// that finalization can be retried.
+    pub fn finalize(
+        &mut self,
+        entry_sepc: u64,
+        entry_arg: u64,
+        signed_manifest: Option<&SignedManifest>,
+    ) -> Result<()> {
+        self.validate_imsic_addrs()?;
+        // Check the manifest against the measurements the VM will have before measuring the entry
+        // point or enabling the boot vCPU.
+        self.attestation_mgr.set_epc(entry_sepc);
+        self.attestation_mgr.set_arg(entry_arg);
+        if let Some(signed_manifest) = signed_manifest {
+            UmodeTask::verify_manifest(
+                signed_manifest.manifest_bytes(),
+                signed_manifest.signature_bytes(),
+                &self.attestation_mgr,
+            )
+            .map_err(Error::ManifestVerificationFailed)?;
+        }
         // Enable the boot vCPU; we assume this is always vCPU 0.
         //
         // TODO: Should we allow a non-0 boot vCPU to be specified when creating the TVM?
@@ -337,9 +394,14 @@ impl<T: GuestStagePagingMode> Vm<T> {
             .and_then(|v| v.power_on(entry_sepc, entry_arg))
             .map_err(|_| Error::MissingBootCpu)?;
         // Measure the entry point of the boot vCPU.
-        self.attestation_mgr.set_epc(entry_sepc);
-        self.attestation_mgr.set_arg(entry_arg);
-        self.validate_imsic_addrs()?;
+        self.attestation_mgr
+            .extend_tvm_configuration()
+            .map_err(Error::AttestationManagerFinalizeFailed)?;
+        if let Some(signed_manifest) = signed_manifest {
+            signed_manifest
+                .extend_identity(&self.attestation_mgr)
+                .map_err(Error::AttestationManagerFinalizeFailed)?;
+        }
         self.attestation_mgr
             .finalize()
             .map_err(Error::AttestationManagerFinalizeFailed)
@@ -396,6 +458,27 @@ impl<'a, T: GuestStagePagingMode, S> VmRef<'a, T, S> {
         &self.vm().attestation_mgr
     }
 
+    /// Returns an iterator over the IDs and statuses of this VM's vCPUs.
+    pub fn vcpu_statuses(&self) -> impl Iterator<Item = (u64, VmCpuStatus)> + '_ {
+        self.vm().vcpus.statuses()
+    }
+
+    /// Calls `f` for each of the guest VMs tracked by this VM, if it tracks any.
+    pub fn for_each_guest<F: FnMut(&GuestVm<T>)>(&self, f: F) {
+        if let Some(guests) = self.vm().guests.as_ref() {
+            guests.for_each(f);
+        }
+    }
+
+    /// Like `for_each_guest()`, but gives up and returns false instead of waiting if the guest
+    /// tracking table is locked.
+    pub fn try_for_each_guest<F: FnMut(&GuestVm<T>)>(&self, f: F) -> bool {
+        match self.vm().guests.as_ref() {
+            Some(guests) => guests.try_for_each(f),
+            None => true,
+        }
+    }
+
     // Convenience function to turn a raw u64 from an SBI call to a `GuestPageAddr`.
     fn guest_addr_from_raw(&self, guest_addr: u64) -> EcallResult<GuestPageAddr> {
         PageAddr::new(RawAddr::guest(guest_addr, self.page_owner_id()))
@@ -581,8 +664,25 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             let exit = active_vcpu.run();
             use SbiReturnType::*;
             match exit {
-                VmCpuTrap::Ecall(Some(sbi_msg)) => {
-                    match self.handle_ecall(sbi_msg, &mut active_vcpu) {
+                VmCpuTrap::Ecall(sbi_msg) => {
+                    let action = match sbi_msg {
+                        Some(sbi_msg) => self.handle_ecall(sbi_msg, &mut active_vcpu),
+                        None if active_vcpu.get_gpr(GprIndex::A7)
+                            == debug_console::EXT_GET_CHAR =>
+                        {
+                            // The legacy getchar() returns the character, or -1 if there's none,
+                            // in A0 alone.
+                            let c = self.console_getchar(&active_vcpu);
+                            active_vcpu.set_ecall_result(Legacy(c));
+                            active_vcpu.pmu().count_firmware_event(FirmwareEvent::Ecall);
+                            continue;
+                        }
+                        None => self.handle_undecoded_ecall(&mut active_vcpu).into(),
+                    };
+                    // Count the ECALL once it's been handled so that a counter_fw_read() doesn't
+                    // count itself.
+                    active_vcpu.pmu().count_firmware_event(FirmwareEvent::Ecall);
+                    match action {
                         EcallAction::Unhandled => {
                             active_vcpu.set_ecall_result(Standard(SbiReturn::from(
                                 SbiError::NotSupported,
@@ -599,14 +699,15 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                             break VmExitCause::ForwardedEcall(sbi_msg);
                         }
                         EcallAction::Retry(reason) => {
+                            if matches!(reason, VmExitCause::PageFault(..)) {
+                                active_vcpu
+                                    .pmu()
+                                    .count_firmware_event(FirmwareEvent::PageFaultForwarded);
+                            }
                             break reason;
                         }
                     }
                 }
-                VmCpuTrap::Ecall(None) => {
-                    // Unrecognized ECALL, return an error.
-                    active_vcpu.set_ecall_result(Standard(SbiReturn::from(SbiError::NotSupported)));
-                }
                 VmCpuTrap::PageFault {
                     exception,
                     fault_addr,
@@ -619,6 +720,9 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                     use PageFaultType::*;
                     match pf {
                         Confidential | Shared | Imsic => {
+                            active_vcpu
+                                .pmu()
+                                .count_firmware_event(FirmwareEvent::PageFaultForwarded);
                             break VmExitCause::PageFault(
                                 exception,
                                 PageAddr::with_round_down(fault_addr, PageSize::Size4k),
@@ -661,6 +765,9 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                                 }
                             };
 
+                            active_vcpu
+                                .pmu()
+                                .count_firmware_event(FirmwareEvent::MmioEmulated);
                             break VmExitCause::MmioFault(mmio_op, fault_addr);
                         }
                         Unmapped => {
@@ -695,9 +802,33 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                         ControlFlow::Break(reason) => break reason,
                     };
                 }
+                VmCpuTrap::IllegalInstruction {
+                    fault_pc,
+                    priv_level,
+                } => {
+                    use InstructionFetchError::*;
+                    let inst = match active_vcpu
+                        .active_pages()
+                        .fetch_guest_instruction(fault_pc, priv_level)
+                    {
+                        Ok(inst) => inst,
+                        Err(FetchFault) => {
+                            continue;
+                        }
+                        Err(FailedDecode(raw_inst)) => {
+                            active_vcpu
+                                .inject_exception(Exception::IllegalInstruction, raw_inst as u64);
+                            continue;
+                        }
+                    };
+                    self.handle_illegal_instruction(inst, priv_level, &mut active_vcpu);
+                }
                 VmCpuTrap::DelegatedException { exception, stval } => {
                     active_vcpu.inject_exception(exception, stval);
                 }
+                VmCpuTrap::DebugBreakpoint => {
+                    break VmExitCause::DebugBreakpoint;
+                }
                 VmCpuTrap::OtherException(ref trap_csrs) => {
                     println!("Unhandled guest exit, SCAUSE = 0x{:08x}", trap_csrs.scause);
                     break VmExitCause::UnhandledTrap(trap_csrs.scause);
@@ -709,9 +840,26 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                     // Need to re-run the vCPU to inject the interrupt.
                     continue;
                 }
+                VmCpuTrap::SpuriousInterrupt => {
+                    continue;
+                }
                 VmCpuTrap::OtherInterrupt(i) => {
-                    // We don't expect Salus to take external interrupt itself, so everything else
-                    // is considered unexpected.
+                    // Interrupts directed at Salus itself, e.g. IPIs, are taken here if they
+                    // arrive while a guest is running.
+                    if trap::handle_interrupt(i) {
+                        // The monitor can't block in the interrupt handler, so if the interrupt
+                        // requested it, run it now that we're back in the run loop.
+                        debug_console::run_requested_monitor();
+                        // The IPI may be from a CPU that a vCPU bound here is moving to. The host
+                        // VM saves such vCPUs right away; a TVM vCPU exits to let its host do so.
+                        if has_save_requests() {
+                            if self.guests().is_none() {
+                                break VmExitCause::HostInterrupt(i);
+                            }
+                            self.save_migrating_vcpus();
+                        }
+                        continue;
+                    }
                     println!("Unexpected guest interrupt {:?}", i);
                     break VmExitCause::UnhandledTrap(Trap::Interrupt(i).to_scause());
                 }
@@ -751,6 +899,28 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         }
     }
 
+    // Handles an illegal instruction exception taken due to `inst` that we caught rather than
+    // delegating to the vCPU. Without Sstc, accesses to `stimecmp` from VS-mode are emulated on top
+    // of the vCPU's software timer. Anything else is passed on to the vCPU as it would have been.
+    fn handle_illegal_instruction(
+        &self,
+        inst: DecodedInstruction,
+        priv_level: PrivilegeLevel,
+        active_vcpu: &mut ActiveVmCpu<T>,
+    ) {
+        if !CpuInfo::get().has_sstc()
+            && matches!(priv_level, PrivilegeLevel::Supervisor)
+            && let Some((CSR_STIMECMP, value, mask, rd)) =
+                Self::decode_csr_instruction(inst, active_vcpu)
+        {
+            let prev = active_vcpu.emulate_stimecmp_rmw(value, mask);
+            active_vcpu.set_gpr(rd, prev);
+            active_vcpu.inc_sepc(inst.len() as u64);
+        } else {
+            active_vcpu.inject_exception(Exception::IllegalInstruction, inst.raw() as u64);
+        }
+    }
+
     // Decodes a CSR read-modify-write instruction into a (csr num, value, mask, dest) tuple.
     fn decode_csr_instruction(
         inst: DecodedInstruction,
@@ -838,21 +1008,263 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         }
     }
 
+    // Handles the ECALLs that `SbiMessage` doesn't decode: the TIME extension, the PMU snapshot
+    // area, console reads, the platform event log and the TVM debug calls.
+    fn handle_undecoded_ecall(&self, active_vcpu: &mut ActiveVmCpu<T>) -> EcallResult<u64> {
+        let a0 = active_vcpu.get_gpr(GprIndex::A0);
+        let a1 = active_vcpu.get_gpr(GprIndex::A1);
+        let a2 = active_vcpu.get_gpr(GprIndex::A2);
+        match (
+            active_vcpu.get_gpr(GprIndex::A7),
+            active_vcpu.get_gpr(GprIndex::A6),
+        ) {
+            (vm_timer::EXT_TIME, vm_timer::SET_TIMER_FID) => {
+                active_vcpu
+                    .pmu()
+                    .count_firmware_event(FirmwareEvent::SetTimer);
+                active_vcpu.set_timer(a0);
+                Ok(0)
+            }
+            (sbi_rs::EXT_PMU, vm_pmu::SNAPSHOT_SET_SHMEM_FID) if PmuInfo::get().is_ok() => {
+                self.set_pmu_snapshot_shmem(a0, a1, a2, active_vcpu)
+            }
+            (sbi_rs::EXT_DBCN, debug_console::CONSOLE_READ_FID) => {
+                self.console_read(a0, a1, a2, active_vcpu)
+            }
+            (sbi_rs::EXT_ATTESTATION, platform_log::GET_PLATFORM_LOG_FID) => {
+                self.guest_get_platform_log(a0, a1, active_vcpu.active_pages())
+            }
+            (sbi_rs::EXT_COVE_HOST, vm_manifest::TVM_FINALIZE_SIGNED_FID) => {
+                self.guest_finalize_signed(active_vcpu)
+            }
+            (sbi_rs::EXT_COVE_HOST, vm_pci::TVM_ASSIGN_VF_FID) => self.guest_assign_vf(a0, a1, a2),
+            (sbi_rs::EXT_COVE_HOST, fid) if vm_debug::is_debug_fid(fid) => {
+                self.handle_tvm_debug(fid, active_vcpu)
+            }
+            _ => Err(EcallError::Sbi(SbiError::NotSupported)),
+        }
+    }
+
+    // Reads up to `len` bytes of console input to `addr_lo` and `addr_hi` in the host's address
+    // space, returning the number of bytes read. Console input only goes to the host VM.
+    fn console_read(
+        &self,
+        len: u64,
+        addr_lo: u64,
+        addr_hi: u64,
+        active_vcpu: &mut ActiveVmCpu<T>,
+    ) -> EcallResult<u64> {
+        if !active_vcpu.is_host_vcpu() {
+            return Ok(0);
+        }
+        if addr_hi != 0 {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        let mut buf = [0u8; 64];
+        let len = len.min(buf.len() as u64) as usize;
+        let count = debug_console::read_host_input(&mut buf[..len]);
+        let addr = RawAddr::guest(addr_lo, self.page_owner_id());
+        active_vcpu
+            .active_pages()
+            .copy_to_guest(addr, &buf[..count])?;
+        Ok(count as u64)
+    }
+
+    // Returns the next byte of console input for the legacy getchar() call, or -1 if there's none.
+    fn console_getchar(&self, active_vcpu: &ActiveVmCpu<T>) -> u64 {
+        let mut c = [0u8];
+        if active_vcpu.is_host_vcpu() && debug_console::read_host_input(&mut c) == 1 {
+            c[0] as u64
+        } else {
+            u64::MAX
+        }
+    }
+
+    fn handle_tvm_debug(&self, fid: u64, active_vcpu: &mut ActiveVmCpu<T>) -> EcallResult<u64> {
+        if self.guests().is_none() {
+            return Err(EcallError::Sbi(SbiError::NotSupported));
+        }
+        let a1 = active_vcpu.get_gpr(GprIndex::A1);
+        let a2 = active_vcpu.get_gpr(GprIndex::A2);
+        let a3 = active_vcpu.get_gpr(GprIndex::A3);
+        let guest = self.guest_by_id(active_vcpu.get_gpr(GprIndex::A0))?;
+        let guest_vm = guest
+            .as_finalized_vm()
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        if !guest_vm.vm().debuggable {
+            return Err(EcallError::Sbi(SbiError::Denied));
+        }
+
+        use vm_debug::*;
+        match fid {
+            TVM_DEBUG_READ_REG_FID => guest_vm.debug_read_reg(a1, a2),
+            TVM_DEBUG_WRITE_REG_FID => guest_vm.debug_write_reg(a1, a2, a3).map(|_| 0),
+            TVM_DEBUG_READ_MEM_FID => {
+                self.debug_copy_guest_memory(&guest_vm, a1, a2, a3, false, active_vcpu)
+            }
+            TVM_DEBUG_WRITE_MEM_FID => {
+                self.debug_copy_guest_memory(&guest_vm, a1, a2, a3, true, active_vcpu)
+            }
+            TVM_DEBUG_SET_BREAKPOINT_EXITS_FID => {
+                guest_vm.debug_set_break_on_ebreak(a1, a2 != 0).map(|_| 0)
+            }
+            TVM_DEBUG_SET_SINGLE_STEP_FID => guest_vm.debug_set_single_step(a1, a2 != 0).map(|_| 0),
+            _ => Err(EcallError::Sbi(SbiError::NotSupported)),
+        }
+    }
+
+    // Copies up to `MAX_DEBUG_ACCESS_LEN` bytes between `guest_addr` in `guest_vm` and `host_addr`
+    // in our address space, returning the number of bytes copied.
+    fn debug_copy_guest_memory(
+        &self,
+        guest_vm: &FinalizedVm<T>,
+        guest_addr: u64,
+        host_addr: u64,
+        len: u64,
+        to_guest: bool,
+        active_vcpu: &mut ActiveVmCpu<T>,
+    ) -> EcallResult<u64> {
+        let len = len.min(vm_debug::MAX_DEBUG_ACCESS_LEN) as usize;
+        let guest_addr = RawAddr::guest(guest_addr, guest_vm.page_owner_id());
+        let host_addr = RawAddr::guest(host_addr, self.page_owner_id());
+        let mut buf = [0u8; vm_debug::MAX_DEBUG_ACCESS_LEN as usize];
+        let buf = &mut buf[..len];
+        if to_guest {
+            active_vcpu.active_pages().copy_from_guest(buf, host_addr)?;
+            guest_vm.debug_copy(guest_addr, buf, true)?;
+        } else {
+            guest_vm.debug_copy(guest_addr, buf, false)?;
+            active_vcpu.active_pages().copy_to_guest(host_addr, buf)?;
+        }
+        Ok(len as u64)
+    }
+
+    // Reads the register `reg_id` of `vcpu_id` on behalf of the host's debugger.
+    fn debug_read_reg(&self, vcpu_id: u64, reg_id: u64) -> EcallResult<u64> {
+        let reg = DebugReg::from_raw(reg_id).ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let vcpu = self
+            .vm()
+            .vcpus
+            .get_vcpu(vcpu_id)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+        vcpu.debug_read_reg(reg)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))
+    }
+
+    // Writes `val` to the register `reg_id` of `vcpu_id` on behalf of the host's debugger.
+    fn debug_write_reg(&self, vcpu_id: u64, reg_id: u64, val: u64) -> EcallResult<()> {
+        let reg = DebugReg::from_raw(reg_id).ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let vcpu = self
+            .vm()
+            .vcpus
+            .get_vcpu(vcpu_id)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+        vcpu.debug_write_reg(reg, val)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))
+    }
+
+    // Sets whether `vcpu_id` exits to the host's debugger on EBREAK.
+    fn debug_set_break_on_ebreak(&self, vcpu_id: u64, enable: bool) -> EcallResult<()> {
+        let vcpu = self
+            .vm()
+            .vcpus
+            .get_vcpu(vcpu_id)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+        vcpu.set_break_on_ebreak(enable);
+        Ok(())
+    }
+
+    // Sets whether `vcpu_id` exits to the host's debugger after each instruction it retires.
+    fn debug_set_single_step(&self, vcpu_id: u64, enable: bool) -> EcallResult<()> {
+        if enable && !StepTrigger::is_supported() {
+            return Err(EcallError::Sbi(SbiError::NotSupported));
+        }
+        let vcpu = self
+            .vm()
+            .vcpus
+            .get_vcpu(vcpu_id)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+        vcpu.set_single_step(enable);
+        Ok(())
+    }
+
+    // Copies between `buf` and this VM's memory at `addr` on behalf of the host's debugger. This
+    // VM's address space is made active on this CPU in place of the host's for the duration of the
+    // copy, under the VMID reserved for that purpose.
+    fn debug_copy(&self, addr: GuestPhysAddr, buf: &mut [u8], to_guest: bool) -> EcallResult<()> {
+        let vmid = PerCpu::this_cpu().vmid_tracker_mut().reserved_vmid();
+        tlb::hfence_gvma(None, Some(vmid.vmid()));
+        let prev_hgatp = CSR.hgatp.get();
+        let result = {
+            let active_pages = self.vm_pages().enter_with_vmid(vmid, None);
+            if to_guest {
+                active_pages.copy_to_guest(addr, buf)
+            } else {
+                active_pages.copy_from_guest(buf, addr)
+            }
+        };
+        CSR.hgatp.set(prev_hgatp);
+        // Don't leave this VM's translations cached under the reserved VMID for the next user.
+        tlb::hfence_gvma(None, Some(vmid.vmid()));
+        // Faults in this VM aren't something the host can resolve by retrying.
+        result.map_err(|_| EcallError::Sbi(SbiError::InvalidAddress))
+    }
+
+    fn set_pmu_snapshot_shmem(
+        &self,
+        shmem_lo: u64,
+        shmem_hi: u64,
+        flags: u64,
+        active_vcpu: &mut ActiveVmCpu<T>,
+    ) -> EcallResult<u64> {
+        if flags != 0 {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        if shmem_lo == u64::MAX && shmem_hi == u64::MAX {
+            active_vcpu.pmu().set_snapshot_addr(None);
+            return Ok(0);
+        }
+        // The upper half of the address is only used on RV32.
+        if shmem_hi != 0 {
+            return Err(EcallError::Sbi(SbiError::InvalidAddress));
+        }
+        if !PageSize::Size4k.is_aligned(shmem_lo) {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        // We copy to and from the area as the guest starts and stops counters rather than pinning
+        // it, so it may be confidential memory for a TVM. It must be memory though, not MMIO.
+        let addr = RawAddr::guest(shmem_lo, self.page_owner_id());
+        if !active_vcpu.active_pages().is_guest_memory(addr) {
+            return Err(EcallError::Sbi(SbiError::InvalidAddress));
+        }
+        active_vcpu.pmu().set_snapshot_addr(Some(addr));
+        Ok(0)
+    }
+
     fn handle_pmu_msg(
         &self,
         pmu_func: PmuFunction,
         active_vcpu: &mut ActiveVmCpu<T>,
     ) -> EcallResult<u64> {
         use PmuFunction::*;
-        fn get_num_counters() -> EcallResult<u64> {
-            let pmu_info = PmuInfo::get()?;
-            Ok(pmu_info.get_num_counters())
+        fn get_num_counters<T: GuestStagePagingMode>(
+            active_vcpu: &mut ActiveVmCpu<T>,
+        ) -> EcallResult<u64> {
+            Ok(active_vcpu.pmu().get_num_counters()?)
         }
 
-        fn get_counter_info(counter_index: u64) -> EcallResult<u64> {
-            let pmu_info = PmuInfo::get()?;
-            let info = pmu_info.get_counter_info(counter_index)?;
-            Ok(info.raw())
+        fn get_counter_info<T: GuestStagePagingMode>(
+            counter_index: u64,
+            active_vcpu: &mut ActiveVmCpu<T>,
+        ) -> EcallResult<u64> {
+            Ok(active_vcpu.pmu().get_counter_info(counter_index)?)
+        }
+
+        fn read_firmware_counter<T: GuestStagePagingMode>(
+            counter_index: u64,
+            active_vcpu: &mut ActiveVmCpu<T>,
+        ) -> EcallResult<u64> {
+            Ok(active_vcpu.pmu().read_firmware_counter(counter_index)?)
         }
 
         fn start_counters<T: GuestStagePagingMode>(
@@ -862,6 +1274,27 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             initial_value: u64,
             active_vcpu: &mut ActiveVmCpu<T>,
         ) -> EcallResult<u64> {
+            // `PmuCounterStartFlags` doesn't cover the snapshot flag, so check the raw flags.
+            if active_vcpu.get_gpr(GprIndex::A2) & vm_pmu::START_FLAG_INIT_SNAPSHOT != 0 {
+                let snapshot_addr = active_vcpu
+                    .pmu()
+                    .snapshot_addr()
+                    .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+                let mut snapshot_bytes = [0u8; mem::size_of::<PmuSnapshot>()];
+                active_vcpu
+                    .active_pages()
+                    .copy_from_guest(snapshot_bytes.as_mut_slice(), snapshot_addr)?;
+                // Safety: `snapshot_bytes` points to `size_of::<PmuSnapshot>()` contiguous,
+                // initialized bytes, and any bit pattern is a valid `PmuSnapshot`.
+                let snapshot: PmuSnapshot =
+                    unsafe { core::ptr::read_unaligned(snapshot_bytes.as_ptr().cast()) };
+                let result = active_vcpu.pmu().start_counters_from_snapshot(
+                    counter_index,
+                    counter_mask,
+                    &snapshot,
+                );
+                return result.map(|_| 0).map_err(EcallError::from);
+            }
             let result = active_vcpu.pmu().start_counters(
                 counter_index,
                 counter_mask,
@@ -877,10 +1310,30 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             stop_flags: PmuCounterStopFlags,
             active_vcpu: &mut ActiveVmCpu<T>,
         ) -> EcallResult<u64> {
-            let result = active_vcpu
+            // `PmuCounterStopFlags` doesn't cover the snapshot flag, so check the raw flags.
+            let take_snapshot =
+                active_vcpu.get_gpr(GprIndex::A2) & vm_pmu::STOP_FLAG_TAKE_SNAPSHOT != 0;
+            let snapshot_addr = active_vcpu.pmu().snapshot_addr();
+            if take_snapshot && snapshot_addr.is_none() {
+                return Err(EcallError::Sbi(SbiError::InvalidParam));
+            }
+            active_vcpu
                 .pmu()
-                .stop_counters(counter_index, counter_mask, stop_flags);
-            result.map(|_| 0).map_err(EcallError::from)
+                .stop_counters(counter_index, counter_mask, stop_flags)?;
+            if let Some(addr) = snapshot_addr && take_snapshot {
+                let snapshot = *active_vcpu.pmu().snapshot();
+                // Safety: &snapshot points to size_of::<PmuSnapshot>() bytes of initialized memory.
+                let snapshot_bytes: &[u8] = unsafe {
+                    slice::from_raw_parts(
+                        (&snapshot as *const PmuSnapshot).cast(),
+                        mem::size_of::<PmuSnapshot>(),
+                    )
+                };
+                active_vcpu
+                    .active_pages()
+                    .copy_to_guest(addr, snapshot_bytes)?;
+            }
+            Ok(0)
         }
 
         fn configure_counters<T: GuestStagePagingMode>(
@@ -891,6 +1344,19 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             event_data: u64,
             active_vcpu: &mut ActiveVmCpu<T>,
         ) -> EcallResult<u64> {
+            if matches!(event_type, PmuEventType::Firmware(_)) {
+                // Firmware events are counted by us rather than the platform. The event code is
+                // the low 16 bits of the raw event index.
+                let event_code = active_vcpu.get_gpr(GprIndex::A3) & 0xffff;
+                let result = active_vcpu.pmu().configure_firmware_counter(
+                    counter_index,
+                    counter_mask,
+                    config_flags,
+                    event_code,
+                    event_data,
+                );
+                return result.map_err(EcallError::from);
+            }
             let result = active_vcpu.pmu().configure_matching_counters(
                 counter_index,
                 counter_mask,
@@ -902,8 +1368,8 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         }
 
         match pmu_func {
-            GetNumCounters => get_num_counters(),
-            GetCounterInfo(counter_index) => get_counter_info(counter_index),
+            GetNumCounters => get_num_counters(active_vcpu),
+            GetCounterInfo(counter_index) => get_counter_info(counter_index, active_vcpu),
             StartCounters {
                 counter_index,
                 counter_mask,
@@ -935,7 +1401,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                 event_data,
                 active_vcpu,
             ),
-            ReadFirmwareCounter(_) => Err(EcallError::Sbi(SbiError::NotSupported)),
+            ReadFirmwareCounter(counter_index) => read_firmware_counter(counter_index, active_vcpu),
         }
     }
 
@@ -947,6 +1413,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             GetImplementationVersion => 0,
             ProbeSbiExtension(ext) => match ext {
                 sbi_rs::EXT_PUT_CHAR
+                | debug_console::EXT_GET_CHAR
                 | sbi_rs::EXT_BASE
                 | sbi_rs::EXT_HART_STATE
                 | sbi_rs::EXT_RESET
@@ -954,7 +1421,8 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                 | sbi_rs::EXT_NACL
                 | sbi_rs::EXT_COVE_HOST
                 | sbi_rs::EXT_COVE_INTERRUPT
-                | sbi_rs::EXT_ATTESTATION => 1,
+                | sbi_rs::EXT_ATTESTATION
+                | vm_timer::EXT_TIME => 1,
                 sbi_rs::EXT_PMU if PmuInfo::get().is_ok() => 1,
                 sbi_rs::EXT_COVE_GUEST => (!active_vcpu.is_host_vcpu()) as u64,
                 _ => 0,
@@ -1269,6 +1737,30 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         let params: sbi_rs::TvmCreateParams =
             unsafe { core::ptr::read_unaligned(param_bytes.as_slice().as_ptr().cast()) };
 
+        // Salus extends `TvmCreateParams` with a little-endian `u64` of TVM attributes placed right
+        // after it and covered by `len`. Hosts that don't know about the extension pass just the
+        // params and get a TVM without any attributes, but a `len` that covers only part of the
+        // attributes is rejected rather than ignored.
+        let mut attributes = 0;
+        let params_len = mem::size_of::<sbi_rs::TvmCreateParams>() as u64;
+        if len > params_len {
+            if len < params_len + mem::size_of::<u64>() as u64 {
+                return Err(EcallError::Sbi(SbiError::InvalidParam));
+            }
+            let attributes_addr = params_addr
+                .checked_increment(mem::size_of::<sbi_rs::TvmCreateParams>() as u64)
+                .ok_or(EcallError::Sbi(SbiError::InvalidAddress))?;
+            let mut attribute_bytes = [0u8; mem::size_of::<u64>()];
+            host_active_vcpu
+                .active_pages()
+                .copy_from_guest(attribute_bytes.as_mut_slice(), attributes_addr)
+                .map_err(EcallError::from)?;
+            attributes = u64::from_le_bytes(attribute_bytes);
+        }
+        if attributes & !vm_debug::TVM_ATTR_DEBUG != 0 {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+
         // Now claim the pages that the host donated to us.
         let page_root_addr = self.guest_addr_from_raw(params.tvm_page_directory_addr)?;
         let guest_root_pages = self
@@ -1309,11 +1801,12 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         let guest_root =
             GuestStagePageTable::new(guest_root_pages, id, self.page_tracker()).unwrap();
 
-        let vm = Vm::new(
+        let mut vm = Vm::new(
             VmPages::new(guest_root, self.vm_pages().nesting() + 1),
             VmCpus::new(),
         )
         .map_err(|_| EcallError::Sbi(SbiError::Failed))?;
+        vm.set_attributes(attributes);
 
         // Assert safe here. We checked above that `guest_box_pages` is contiguous.
         let guest_box_pages =
@@ -1333,6 +1826,9 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         self.guests()
             .and_then(|g| g.remove(guest_id).ok())
             .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        // Take back any VFs we assigned to the guest. They're attached to our IOMMU context once
+        // they've been reset.
+        PcieRoots::get().transfer_vfs(guest_id, self.page_owner_id());
         Ok(0)
     }
 
@@ -1351,8 +1847,85 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
     fn guest_finalize(&self, guest_id: u64, entry_sepc: u64, entry_arg: u64) -> EcallResult<u64> {
         let guest = self.guest_by_id(guest_id)?;
         guest
-            .finalize(entry_sepc, entry_arg)
+            .finalize(entry_sepc, entry_arg, None)
+            .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+        Ok(0)
+    }
+
+    // Same as `guest_finalize()`, but the TVM must match the signed manifest at `manifest_addr`,
+    // whose signer becomes part of the TVM's identity.
+    fn guest_finalize_signed(&self, active_vcpu: &mut ActiveVmCpu<T>) -> EcallResult<u64> {
+        if self.guests().is_none() {
+            return Err(EcallError::Sbi(SbiError::NotSupported));
+        }
+        let guest = self.guest_by_id(active_vcpu.get_gpr(GprIndex::A0))?;
+        let entry_sepc = active_vcpu.get_gpr(GprIndex::A1);
+        let entry_arg = active_vcpu.get_gpr(GprIndex::A2);
+        let manifest_addr = active_vcpu.get_gpr(GprIndex::A3);
+        if active_vcpu.get_gpr(GprIndex::A4) != SIGNED_MANIFEST_SIZE as u64 {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+
+        let mut manifest_bytes = [0u8; SIGNED_MANIFEST_SIZE];
+        active_vcpu.active_pages().copy_from_guest(
+            &mut manifest_bytes,
+            RawAddr::guest(manifest_addr, self.page_owner_id()),
+        )?;
+        let signed_manifest = SignedManifest::decode(&manifest_bytes)
             .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
+
+        guest
+            .finalize(entry_sepc, entry_arg, Some(&signed_manifest))
+            .map_err(|e| match e {
+                GuestTrackingError::VmFinalizeFailed(Error::ManifestVerificationFailed(_)) => {
+                    EcallError::Sbi(SbiError::Denied)
+                }
+                _ => EcallError::Sbi(SbiError::InvalidParam),
+            })?;
+        Ok(0)
+    }
+
+    // Assigns the SR-IOV virtual function at `segment` and `bdf` in our view of PCI to the guest
+    // with `guest_id`. The VF's physical function stays with us.
+    fn guest_assign_vf(&self, guest_id: u64, segment: u64, bdf: u64) -> EcallResult<u64> {
+        if self.guests().is_none() {
+            return Err(EcallError::Sbi(SbiError::NotSupported));
+        }
+        let guest = self.guest_by_id(guest_id)?;
+        let address =
+            vm_pci::vf_address(segment, bdf).ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let root = PcieRoots::get()
+            .get_root(address.segment())
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let vf_id = root
+            .device_by_virtual_address(address)
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        // Unwrap ok: the ID was just looked up in this root.
+        let vf = root.get_device(vf_id).unwrap();
+        let owner = self.page_owner_id();
+        {
+            let mut vf = vf.lock();
+            if !vf.is_vf() || vf.owner() != Some(owner) {
+                return Err(EcallError::Sbi(SbiError::InvalidParam));
+            }
+            // Stop DMA from the VF through our page tables before it changes hands.
+            if vf.is_iommu_attached() {
+                self.vm_pages()
+                    .detach_pci_device(&mut vf)
+                    .map_err(|_| EcallError::Sbi(SbiError::Failed))?;
+            }
+        }
+        if root
+            .transfer_vf(vf_id, owner, guest.page_owner_id())
+            .is_err()
+        {
+            // Give the VF back its DMA mappings if it's still ours.
+            let mut vf = vf.lock();
+            if vf.owner() == Some(owner) {
+                let _ = self.vm_pages().attach_pci_device(&mut vf);
+            }
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
         Ok(0)
     }
 
@@ -1387,8 +1960,9 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         let vcpu_pages =
             SequentialPages::from_pages(Self::assign_pages(pages, guest_vm.page_owner_id()))
                 .unwrap();
-        let vcpu_box = PageBox::new_with(
-            VmCpu::new(vcpu_id, guest_vm.page_owner_id()),
+        let vcpu_box = VmCpu::new(
+            vcpu_id,
+            guest_vm.page_owner_id(),
             vcpu_pages,
             self.page_tracker(),
         );
@@ -1404,11 +1978,158 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         vcpu_id: u64,
         active_vcpu: &mut ActiveVmCpu<T>,
     ) -> EcallResult<u64> {
-        let guest = self.guest_by_id(guest_id)?;
-        let guest_vm = guest
-            .as_finalized_vm()
-            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-        guest_vm.run_vcpu(vcpu_id, VmCpuParent::HostVm(active_vcpu))
+        // Take care of any vCPUs that are being moved away from this CPU first so that they don't
+        // hold up the CPUs they're moving to.
+        self.save_migrating_vcpus();
+
+        let result = {
+            let guest = self.guest_by_id(guest_id)?;
+            let guest_vm = guest
+                .as_finalized_vm()
+                .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+            self.prepare_vcpu_for_this_cpu(&guest_vm, vcpu_id, active_vcpu)?;
+            guest_vm.run_vcpu(vcpu_id, VmCpuParent::HostVm(active_vcpu))
+        };
+        // The vCPU may have exited because another CPU asked for a vCPU to be saved.
+        self.save_migrating_vcpus();
+        result
+    }
+
+    // Makes sure `vcpu_id` in `guest_vm` can be run on the current CPU. A vCPU that isn't bound to
+    // any guest interrupt file is bound to a free one on this CPU if there is any, and otherwise
+    // runs with its interrupt file emulated. A vCPU that is bound to another CPU is moved to a free
+    // guest interrupt file on this CPU, or unbound if there isn't one: the previous CPU is asked to
+    // save the state of its interrupt file, and `AlreadyStarted` is returned until that's done, at
+    // which point the host should retry running the vCPU.
+    fn prepare_vcpu_for_this_cpu(
+        &self,
+        guest_vm: &FinalizedVm<T>,
+        vcpu_id: u64,
+        active_vcpu: &ActiveVmCpu<T>,
+    ) -> EcallResult<()> {
+        let Some(location) = guest_vm.vcpu_bind_location(vcpu_id) else {
+            // No IMSIC virtualization for this vCPU (or no such vCPU, which will be caught when
+            // we try to run it).
+            return Ok(());
+        };
+        use BindLocation::*;
+        match location {
+            // Let activation sort out whether the vCPU can actually run.
+            ThisCpu | Busy => Ok(()),
+            Unbound => {
+                if let Some(imsic_pages) = self.take_free_guest_file(active_vcpu) {
+                    self.bind_guest_file(guest_vm, vcpu_id, imsic_pages)?;
+                }
+                Ok(())
+            }
+            OtherCpu(prev_cpu, prev_file) => {
+                // Without a free interrupt file here, the previous CPU unbinds the vCPU instead.
+                if let Some(imsic_pages) = self.take_free_guest_file(active_vcpu) {
+                    self.rebind_guest_file(guest_vm, vcpu_id, imsic_pages)?;
+                    // The previous interrupt file can't be saved until the vCPU's accesses to it
+                    // have been fenced. If a fence is already in progress the previous CPU will try
+                    // again.
+                    let _ = guest_vm.vm_pages().initiate_fence();
+                }
+                request_save(prev_cpu, prev_file);
+                Err(EcallError::Sbi(SbiError::AlreadyStarted))
+            }
+            Saving | Unbinding(_) => Err(EcallError::Sbi(SbiError::AlreadyStarted)),
+            Saved => guest_vm.rebind_vcpu_end(vcpu_id),
+        }
+    }
+
+    /// Saves the guest interrupt files on the current CPU of the vCPUs that are being moved to
+    /// another CPU, as requested by the CPU they're moving to. If that CPU had no free guest
+    /// interrupt file, the vCPU is unbound instead. Does nothing if this VM doesn't host any
+    /// guests.
+    pub fn save_migrating_vcpus(&self) {
+        let Some(guests) = self.guests() else {
+            return;
+        };
+        let this_cpu = PerCpu::this_cpu().cpu_id();
+        let mut requests = take_save_requests();
+        while requests != 0 {
+            let bit = requests.trailing_zeros();
+            requests &= !(1 << bit);
+            let Some(index) = bit.checked_sub(1) else {
+                continue;
+            };
+            let interrupt_file = ImsicFileId::guest(index);
+            let Some(vcpu) = PerCpu::this_cpu()
+                .guest_files_mut()
+                .vcpu_for(interrupt_file)
+            else {
+                continue;
+            };
+            let Some(guest) = guests.get(vcpu.guest_id()) else {
+                continue;
+            };
+            let Some(guest_vm) = guest.as_finalized_vm() else {
+                continue;
+            };
+            let vcpu_id = vcpu.vcpu_id();
+            let fence_pending = match guest_vm.vcpu_bind_location(vcpu_id) {
+                Some(BindLocation::ThisCpu) => {
+                    let Ok(imsic_addr) = guest_vm.get_vcpu_imsic_addr(vcpu_id) else {
+                        continue;
+                    };
+                    if guest_vm.unbind_vcpu_begin(vcpu_id).is_err() {
+                        continue;
+                    }
+                    // Unwrap ok: the IMSIC page must be mapped if the vCPU is bound to it.
+                    guest_vm
+                        .vm_pages()
+                        .unassign_imsic_begin(imsic_addr)
+                        .unwrap();
+                    true
+                }
+                Some(BindLocation::Unbinding(cpu)) if cpu == this_cpu => {
+                    let Ok(imsic_addr) = guest_vm.get_vcpu_imsic_addr(vcpu_id) else {
+                        continue;
+                    };
+                    let fenced = guest_vm.vm_pages().unassign_imsic_end(imsic_addr).is_ok();
+                    if fenced {
+                        // Unwrap ok: the vCPU is being unbound from this CPU.
+                        guest_vm.unbind_vcpu_end(vcpu_id).unwrap();
+                    }
+                    !fenced
+                }
+                _ => {
+                    // Drop the request if the vCPU is no longer being moved.
+                    let Ok(prev_imsic_addr) = guest_vm.prev_imsic_addr(vcpu_id) else {
+                        continue;
+                    };
+                    let fenced = guest_vm
+                        .vm_pages()
+                        .remove_imsic_page(prev_imsic_addr)
+                        .is_ok();
+                    if fenced {
+                        // Unwrap ok: the vCPU is in the "rebinding" state and was bound to this
+                        // CPU.
+                        guest_vm.rebind_vcpu_clone(vcpu_id).unwrap();
+                    }
+                    !fenced
+                }
+            };
+            if fence_pending {
+                // The TLB fence hasn't completed yet. Make sure one gets started and try again
+                // later.
+                let _ = guest_vm.vm_pages().initiate_fence();
+                defer_save(interrupt_file);
+            }
+        }
+    }
+
+    // Returns the pages of a guest interrupt file on the current CPU that have been converted by
+    // the host but are not yet assigned to any vCPU, if there are any.
+    fn take_free_guest_file(
+        &self,
+        active_vcpu: &ActiveVmCpu<T>,
+    ) -> Option<LockedPageList<ImsicGuestPage<ConvertedClean>>> {
+        let guests_per_hart = self.vm_pages().imsic_geometry()?.guests_per_hart();
+        (0..guests_per_hart as u32)
+            .find_map(|index| self.get_guest_file_pages(index, active_vcpu).ok())
     }
 
     fn guest_add_page_table_pages(
@@ -1896,10 +2617,32 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         Ok(measurement_data.len() as u64)
     }
 
+    // Copies the platform event log to `log_addr` in the VM's address space, returning its size.
+    fn guest_get_platform_log(
+        &self,
+        log_addr: u64,
+        log_size: u64,
+        active_pages: &ActiveVmPages<T>,
+    ) -> EcallResult<u64> {
+        // Unwrap ok: the log is sealed before any VM runs.
+        let log = PlatformLog::get().unwrap();
+        let size = log.encoded_size();
+        if log_size < size as u64 {
+            return Err(EcallError::Sbi(SbiError::InsufficientBufferCapacity));
+        }
+        for (i, e) in log.events().enumerate() {
+            let addr = log_addr
+                .checked_add((i * PLATFORM_EVENT_SIZE) as u64)
+                .ok_or(EcallError::Sbi(SbiError::InvalidAddress))?;
+            active_pages.copy_to_guest(RawAddr::guest(addr, self.page_owner_id()), &e.encode())?;
+        }
+        Ok(size as u64)
+    }
+
     fn handle_cove_interrupt_msg(
         &self,
         interrupt_func: CoveInterruptFunction,
-        active_vcpu: &ActiveVmCpu<T>,
+        active_vcpu: &mut ActiveVmCpu<T>,
     ) -> EcallAction {
         use CoveInterruptFunction::*;
         match interrupt_func {
@@ -1941,9 +2684,15 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
                 tvm_id,
                 vcpu_id,
                 interrupt_id,
-            } => self
-                .guest_inject_ext_interrupt(tvm_id, vcpu_id, interrupt_id)
-                .into(),
+            } => {
+                let result = self.guest_inject_ext_interrupt(tvm_id, vcpu_id, interrupt_id);
+                if result.is_ok() {
+                    active_vcpu
+                        .pmu()
+                        .count_firmware_event(FirmwareEvent::IpiSent);
+                }
+                result.into()
+            }
             TvmCpuRebindImsicBegin {
                 tvm_id,
                 vcpu_id,
@@ -2046,6 +2795,16 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         Ok(0)
     }
 
+    // Returns the location of `vcpu_id`'s guest interrupt file relative to the current CPU, or
+    // `None` if the vCPU doesn't exist or doesn't have IMSIC virtualization enabled.
+    fn vcpu_bind_location(&self, vcpu_id: u64) -> Option<BindLocation> {
+        self.vm()
+            .vcpus
+            .get_vcpu(vcpu_id)
+            .and_then(|vcpu| vcpu.imsic_bind_location())
+            .ok()
+    }
+
     /// Begins the process of binding `vcpu_id` to the given guest interrupt file on the current
     /// CPU by initializing the interrupt file.
     pub fn bind_vcpu_begin(&self, vcpu_id: u64, interrupt_file: ImsicFileId) -> EcallResult<()> {
@@ -2066,29 +2825,13 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))
     }
 
-    fn guest_bind_vcpu(
+    // Returns the converted pages of guest interrupt file `imsic_index` on the current CPU, as
+    // seen through `active_vcpu`'s virtualized IMSIC.
+    fn get_guest_file_pages(
         &self,
-        guest_id: u64,
-        vcpu_id: u64,
-        imsic_mask: u64,
+        imsic_index: u32,
         active_vcpu: &ActiveVmCpu<T>,
-    ) -> EcallResult<u64> {
-        let guest = self.guest_by_id(guest_id)?;
-        let guest_vm = guest
-            .as_finalized_vm()
-            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-
-        // imsic_mask is in the same format as HGEIE, where bits [1:N] specify the guest interrupt
-        // files. We only support binding a single interrupt file for now.
-        if imsic_mask.count_ones() != 1 {
-            return Err(EcallError::Sbi(SbiError::InvalidParam))?;
-        }
-        let imsic_index = imsic_mask
-            .trailing_zeros()
-            .checked_sub(1)
-            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-
-        // Get the IMSIC page that we're going to assign.
+    ) -> EcallResult<LockedPageList<ImsicGuestPage<ConvertedClean>>> {
         let base_location = active_vcpu
             .get_imsic_location()
             .ok_or(EcallError::Sbi(SbiError::NotSupported))?;
@@ -2102,11 +2845,49 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             .imsic_geometry()
             .and_then(|g| g.location_to_addr(src_location))
             .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-        let imsic_pages = self
-            .vm_pages()
+        self.vm_pages()
             .get_converted_imsic(from_page_addr)
-            .map_err(EcallError::from)?;
+            .map_err(EcallError::from)
+    }
+
+    // Converts an IMSIC mask in HGEIE format, where bits [1:N] specify the guest interrupt files,
+    // to a guest interrupt file index. We only support binding a single interrupt file for now.
+    fn imsic_mask_to_index(imsic_mask: u64) -> EcallResult<u32> {
+        if imsic_mask.count_ones() != 1 {
+            return Err(EcallError::Sbi(SbiError::InvalidParam));
+        }
+        imsic_mask
+            .trailing_zeros()
+            .checked_sub(1)
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))
+    }
 
+    fn guest_bind_vcpu(
+        &self,
+        guest_id: u64,
+        vcpu_id: u64,
+        imsic_mask: u64,
+        active_vcpu: &ActiveVmCpu<T>,
+    ) -> EcallResult<u64> {
+        let guest = self.guest_by_id(guest_id)?;
+        let guest_vm = guest
+            .as_finalized_vm()
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+
+        // Get the IMSIC page that we're going to assign.
+        let imsic_index = Self::imsic_mask_to_index(imsic_mask)?;
+        let imsic_pages = self.get_guest_file_pages(imsic_index, active_vcpu)?;
+        self.bind_guest_file(&guest_vm, vcpu_id, imsic_pages)?;
+        Ok(0)
+    }
+
+    // Binds `vcpu_id` in `guest_vm` to the guest interrupt file in `imsic_pages`.
+    fn bind_guest_file(
+        &self,
+        guest_vm: &FinalizedVm<T>,
+        vcpu_id: u64,
+        imsic_pages: LockedPageList<ImsicGuestPage<ConvertedClean>>,
+    ) -> EcallResult<()> {
         // Make sure we can map the page before starting the bind process.
         let to_page_addr = guest_vm.get_vcpu_imsic_addr(vcpu_id)?;
         let mapper = guest_vm
@@ -2138,7 +2919,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
         // Unwrap ok: we know the vCPU is already in the "binding" state.
         guest_vm.bind_vcpu_end(vcpu_id).unwrap();
 
-        Ok(0)
+        Ok(())
     }
 
     fn rebind_vcpu_begin(&self, vcpu_id: u64, interrupt_file: ImsicFileId) -> EcallResult<()> {
@@ -2161,35 +2942,21 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             .as_finalized_vm()
             .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
 
-        // imsic_mask is in the same format as HGEIE, where bits [1:N] specify the guest interrupt
-        // files. We only support binding a single interrupt file for now.
-        if imsic_mask.count_ones() != 1 {
-            return Err(EcallError::Sbi(SbiError::InvalidParam))?;
-        }
-        let imsic_index = imsic_mask
-            .trailing_zeros()
-            .checked_sub(1)
-            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-
         // Get the IMSIC page that we're going to assign.
-        let base_location = active_vcpu
-            .get_imsic_location()
-            .ok_or(EcallError::Sbi(SbiError::NotSupported))?;
-        let src_location = ImsicLocation::new(
-            base_location.group(),
-            base_location.hart(),
-            ImsicFileId::guest(imsic_index),
-        );
-        let from_page_addr = self
-            .vm_pages()
-            .imsic_geometry()
-            .and_then(|g| g.location_to_addr(src_location))
-            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-        let imsic_pages = self
-            .vm_pages()
-            .get_converted_imsic(from_page_addr)
-            .map_err(EcallError::from)?;
+        let imsic_index = Self::imsic_mask_to_index(imsic_mask)?;
+        let imsic_pages = self.get_guest_file_pages(imsic_index, active_vcpu)?;
+        self.rebind_guest_file(&guest_vm, vcpu_id, imsic_pages)?;
+        Ok(0)
+    }
 
+    // Starts moving `vcpu_id` in `guest_vm` to the guest interrupt file in `imsic_pages`, blocking
+    // the vCPU's previous interrupt file.
+    fn rebind_guest_file(
+        &self,
+        guest_vm: &FinalizedVm<T>,
+        vcpu_id: u64,
+        imsic_pages: LockedPageList<ImsicGuestPage<ConvertedClean>>,
+    ) -> EcallResult<()> {
         // to_page_addr contains the virtual address set by host. it's where guest vcpu views
         // its interrupt file.
         let to_page_addr = guest_vm.get_vcpu_imsic_addr(vcpu_id)?;
@@ -2226,7 +2993,7 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             guest_vm.vm_pages().block_imsic_page(prev_page).unwrap();
         }
 
-        Ok(0)
+        Ok(())
     }
 
     fn rebind_vcpu_clone(&self, vcpu_id: u64) -> EcallResult<()> {
@@ -2237,23 +3004,27 @@ impl<'a, T: GuestStagePagingMode> FinalizedVm<'a, T> {
             .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))
     }
 
-    fn guest_rebind_vcpu_clone(&self, guest_id: u64, vcpu_id: u64) -> EcallResult<u64> {
-        let guest = self.guest_by_id(guest_id)?;
-        let guest_vm = guest
-            .as_finalized_vm()
-            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
-
-        let prev_imisc_loc = guest_vm
+    // Returns the address of the guest interrupt file `vcpu_id` is being moved away from.
+    fn prev_imsic_addr(&self, vcpu_id: u64) -> EcallResult<SupervisorPageAddr> {
+        let prev_imisc_loc = self
             .vm()
             .vcpus
             .get_vcpu(vcpu_id)
             .and_then(|vcpu| vcpu.prev_imsic_location())
             .map_err(|_| EcallError::Sbi(SbiError::InvalidParam))?;
         // Unwrap ok: prev_imisc_loc must've been a valid location given it was bound to the vCPU.
-        let prev_imsic_addr = Imsic::get()
+        Ok(Imsic::get()
             .phys_geometry()
             .location_to_addr(prev_imisc_loc)
-            .unwrap();
+            .unwrap())
+    }
+
+    fn guest_rebind_vcpu_clone(&self, guest_id: u64, vcpu_id: u64) -> EcallResult<u64> {
+        let guest = self.guest_by_id(guest_id)?;
+        let guest_vm = guest
+            .as_finalized_vm()
+            .ok_or(EcallError::Sbi(SbiError::InvalidParam))?;
+        let prev_imsic_addr = guest_vm.prev_imsic_addr(vcpu_id)?;
 
         // Makes sure the TLB flush has been completed and unassigns the previous imsic page from
         // page_tracker.
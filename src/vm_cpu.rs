@@ -7,19 +7,23 @@ use core::{mem::size_of, ptr::NonNull};
 use drivers::{imsic::*, CpuId, CpuInfo, MAX_CPUS};
 use memoffset::offset_of;
 use page_tracking::collections::PageBox;
-use page_tracking::TlbVersion;
+use page_tracking::{PageTracker, TlbVersion};
 use riscv_page_tables::GuestStagePagingMode;
-use riscv_pages::{GuestPhysAddr, GuestVirtAddr, PageOwnerId, RawAddr};
+use riscv_pages::{
+    GuestPhysAddr, GuestVirtAddr, InternalClean, PageOwnerId, PageSize, RawAddr, SequentialPages,
+};
 use riscv_regs::*;
 use sbi_rs::{self, api::cove_host::TsmShmemAreaRef, SbiMessage, SbiReturn, SbiReturnType};
 use sync::{Mutex, MutexGuard, Once, RwLock};
 
 use crate::smp::PerCpu;
 use crate::vm::{MmioOpcode, MmioOperation, VmExitCause};
+use crate::vm_debug::DebugReg;
 use crate::vm_id::*;
-use crate::vm_interrupts::{self, VmCpuExtInterrupts};
+use crate::vm_interrupts::{self, BindLocation, BoundVmCpu, VmCpuExtInterrupts};
 use crate::vm_pages::{ActiveVmPages, FinalizedVmPages, PinnedPages};
-use crate::vm_pmu::VmPmuState;
+use crate::vm_pmu::{FirmwareEvent, VmPmuState};
+use crate::vm_timer;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Error {
@@ -42,6 +46,7 @@ pub enum Error {
     DenyingInterrupt(vm_interrupts::Error),
     InjectingInterrupt(vm_interrupts::Error),
     InvalidCsrAccess,
+    ArmingTimer(vm_timer::Error),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -49,6 +54,18 @@ pub type Result<T> = core::result::Result<T, Error>;
 /// The maximum number of vCPUs supported by a VM.
 pub const VM_CPUS_MAX: usize = MAX_CPUS;
 
+// CSRs that the host's debugger may read but not write: `vl` and `vtype` are only set through
+// `vsetvl`, and `htimedelta` is fixed by the TSM.
+const DEBUG_READ_ONLY_CSRS: [u16; 3] = [CSR_VL, CSR_VTYPE, CSR_HTIMEDELTA];
+
+// The width of a vector register in bytes, as read from vlenb at boot.
+static VECTOR_REGISTER_LEN: Once<u64> = Once::new();
+
+// Returns the width of a vector register in bytes, or 0 if the vector extension isn't supported.
+fn vector_register_len() -> u64 {
+    VECTOR_REGISTER_LEN.get().copied().unwrap_or(0)
+}
+
 /// Hypervisor GPR and CSR state which must be saved/restored when entering/exiting virtualization.
 #[derive(Default)]
 #[repr(C)]
@@ -67,7 +84,9 @@ struct HypervisorCpuState {
 struct GuestCpuState {
     gprs: GeneralPurposeRegisters,
     fprs: FloatingPointRegisters,
-    vprs: VectorRegisters,
+    // Address of the vector register save area, which is sized at runtime based on vlenb. Zero if
+    // vectors aren't supported.
+    vprs: u64,
     fcsr: u64,
     sstatus: u64,
     hstatus: u64,
@@ -171,12 +190,6 @@ const fn guest_fpr_offset(index: usize) -> usize {
         + index * size_of::<u64>()
 }
 
-const fn guest_vpr_offset(index: usize) -> usize {
-    offset_of!(VmCpuRegisters, guest_regs)
-        + offset_of!(GuestCpuState, vprs)
-        + index * size_of::<riscv_regs::VectorRegister>()
-}
-
 macro_rules! hyp_csr_offset {
     ($reg:tt) => {
         offset_of!(VmCpuRegisters, hyp_regs) + offset_of!(HypervisorCpuState, $reg)
@@ -283,10 +296,7 @@ global_asm!(
     guest_f30 = const guest_fpr_offset(30),
     guest_f31 = const guest_fpr_offset(31),
     guest_fcsr = const guest_csr_offset!(fcsr),
-    guest_v0 = const guest_vpr_offset(0),
-    guest_v8 = const guest_vpr_offset(8),
-    guest_v16 = const guest_vpr_offset(16),
-    guest_v24 = const guest_vpr_offset(24),
+    guest_vprs = const guest_csr_offset!(vprs),
     guest_vstart = const guest_csr_offset!(vstart),
     guest_vcsr = const guest_csr_offset!(vcsr),
     guest_vtype = const guest_csr_offset!(vtype),
@@ -343,15 +353,25 @@ pub enum VmCpuTrap {
         fault_pc: GuestVirtAddr,
         priv_level: PrivilegeLevel,
     },
+    /// An illegal instruction exception we caught rather than delegating it to the guest, e.g. an
+    /// access to `stimecmp` on a CPU without Sstc.
+    IllegalInstruction {
+        fault_pc: GuestVirtAddr,
+        priv_level: PrivilegeLevel,
+    },
     /// An exception which we expected to handle directly at VS, but trapped to HS instead.
     DelegatedException { exception: Exception, stval: u64 },
     /// Everything other exception that we currently don't or can't handle.
     OtherException(VmCpuTrapState),
+    /// An EBREAK from a vCPU that's being debugged by its host.
+    DebugBreakpoint,
     /// An interrupt intended for the vCPU's host.
     HostInterrupt(Interrupt),
     /// An interrupt for the running vCPU that can't be delegated and must be injected. The
     /// interrupt is injected the vCPU is run.
     InterruptEmulation,
+    /// An interrupt that, once handled, turned out to have nothing for the vCPU or its host.
+    SpuriousInterrupt,
     /// Unknown / unexpected interupt.
     OtherInterrupt(Interrupt),
     // TODO: Add other exit causes as needed.
@@ -364,6 +384,85 @@ struct PrevTlb {
     tlb_version: TlbVersion,
 }
 
+/// The register files whose state is switched lazily between vCPUs.
+#[derive(Clone, Copy, Debug)]
+pub enum LazyRegs {
+    Fp,
+    Vector,
+}
+
+/// Tracks the contents of the lazily-switched register files of a physical CPU. The generation of
+/// a register file is advanced whenever a vCPU loads its state into it, so a vCPU's state is still
+/// live in the register file if the generation hasn't changed since the vCPU loaded it.
+#[derive(Default)]
+pub struct LazyRegsTracker {
+    fp_generation: u64,
+    vector_generation: u64,
+}
+
+impl LazyRegsTracker {
+    /// Returns a new `LazyRegsTracker`.
+    pub const fn new() -> Self {
+        Self {
+            fp_generation: 0,
+            vector_generation: 0,
+        }
+    }
+
+    // Returns the current generation of `regs`.
+    fn generation(&self, regs: LazyRegs) -> u64 {
+        match regs {
+            LazyRegs::Fp => self.fp_generation,
+            LazyRegs::Vector => self.vector_generation,
+        }
+    }
+
+    // Advances and returns the generation of `regs`.
+    fn next_generation(&mut self, regs: LazyRegs) -> u64 {
+        let generation = match regs {
+            LazyRegs::Fp => &mut self.fp_generation,
+            LazyRegs::Vector => &mut self.vector_generation,
+        };
+        *generation += 1;
+        *generation
+    }
+}
+
+// The physical CPU and register file generation a vCPU last loaded its lazily-switched state into.
+struct LoadedRegs {
+    cpu: CpuId,
+    generation: u64,
+}
+
+impl LoadedRegs {
+    // Returns if the state loaded into `regs` is still live on `cpu`, whose lazily-switched
+    // registers are tracked by `tracker`.
+    fn is_live(&self, cpu: CpuId, tracker: &LazyRegsTracker, regs: LazyRegs) -> bool {
+        self.cpu == cpu && self.generation == tracker.generation(regs)
+    }
+}
+
+// Turns off FS and VS in the guest's `sstatus` for the register files whose state isn't live
+// according to `is_live`, so that the guest traps on its first use of them. Register files the
+// guest has turned off are left alone. Returns whether the FP and vector state were held back.
+fn hold_back_lazy_regs(
+    sstatus: &mut LocalRegisterCopy<u64, sstatus::Register>,
+    has_vector: bool,
+    is_live: impl Fn(LazyRegs) -> bool,
+) -> (bool, bool) {
+    let lazy_fp = sstatus.read(sstatus::fs) != sstatus::fs::Off.value && !is_live(LazyRegs::Fp);
+    let lazy_vector = has_vector
+        && sstatus.read(sstatus::vs) != sstatus::vs::Off.value
+        && !is_live(LazyRegs::Vector);
+    if lazy_fp {
+        sstatus.modify(sstatus::fs::Off);
+    }
+    if lazy_vector {
+        sstatus.modify(sstatus::vs::Off);
+    }
+    (lazy_fp, lazy_vector)
+}
+
 // An operation that's pending a return value from the vCPU's host.
 pub enum PendingOperation {
     Mmio(MmioOperation),
@@ -375,14 +474,22 @@ struct VmCpuArchState {
     regs: VmCpuRegisters,
     pmu: VmPmuState,
     prev_tlb: Option<PrevTlb>,
+    fp_loaded: Option<LoadedRegs>,
+    vector_loaded: Option<LoadedRegs>,
     pending_op: Option<PendingOperation>,
     shmem_area: Option<PinnedTsmShmemArea>,
+    // Set if EBREAKs exit to the host's debugger rather than being delegated to the guest.
+    break_on_ebreak: bool,
+    // Set if the vCPU exits to the host's debugger after each instruction it retires.
+    single_step: bool,
 }
 
 impl VmCpuArchState {
-    // Sets up the architectural state for a new vCPU.
-    fn new(guest_id: PageOwnerId) -> Self {
+    // Sets up the architectural state for a new vCPU, using the buffer at `vprs` to hold the vector
+    // register file.
+    fn new(guest_id: PageOwnerId, vprs: u64) -> Self {
         let mut regs = VmCpuRegisters::default();
+        regs.guest_regs.vprs = vprs;
 
         let mut hstatus = LocalRegisterCopy::<u64, hstatus::Register>::new(0);
         hstatus.modify(hstatus::spv.val(1));
@@ -412,8 +519,12 @@ impl VmCpuArchState {
             regs,
             pmu: VmPmuState::default(),
             prev_tlb: None,
+            fp_loaded: None,
+            vector_loaded: None,
             pending_op: None,
             shmem_area: None,
+            break_on_ebreak: false,
+            single_step: false,
         }
     }
 }
@@ -540,6 +651,17 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
             arch.prev_tlb = None;
         }
 
+        // Without Sstc our timer is tracked by this CPU's timer queue, so make sure there's room for
+        // it before going any further. The deadline is brought up to date each time we're run.
+        if !CpuInfo::get().has_sstc() {
+            let vs_csrs = &arch.regs.vs_csrs;
+            let deadline = vm_timer::host_deadline(vs_csrs.vstimecmp, vs_csrs.htimedelta);
+            PerCpu::this_cpu()
+                .vm_timers_mut()
+                .arm(vcpu.guest_id, vcpu.vcpu_id, deadline)
+                .map_err(Error::ArmingTimer)?;
+        }
+
         // Save the state of the host vCPU (if any) and swap in ours.
         if let VmCpuParent::HostVm(ref mut host_vcpu) = host_context {
             host_vcpu.save();
@@ -559,6 +681,10 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
 
     /// Runs this vCPU until it traps.
     pub fn run(&mut self) -> VmCpuTrap {
+        if !CpuInfo::get().has_sstc() {
+            self.update_virtual_timer();
+        }
+
         match self.host_context {
             VmCpuParent::HostVm(ref host_vcpu) => {
                 // TODO: Consider bailing out early if any of the below host interrupts are pending.
@@ -576,9 +702,14 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                 }
             }
             VmCpuParent::Tsm(_) => {
+                // TVM vCPUs which received an interrupt while we were idle are now runnable. Ring
+                // the host's doorbell by injecting SG_EXT; the host can then use HGEIP, which
+                // reports the woken vCPUs' guest interrupt files, to find which of the vCPUs it
+                // bound to this CPU need to be run.
+                let woken = PerCpu::this_cpu().guest_files_mut().has_runnable();
                 let hie = LocalRegisterCopy::new(self.arch.regs.virtual_hs_csrs.hie);
                 if hie.read(hie::sgext) != 0 {
-                    if CSR.hip.read(hip::sgext) != 0 {
+                    if CSR.hip.read(hip::sgext) != 0 || woken {
                         // SG_EXT is pending; inject it with HVICTL. Leave it disabled in HIE to avoid
                         // trapping immediately once we enter the host VM.
                         let mut hvictl = LocalRegisterCopy::new(0);
@@ -596,26 +727,121 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
             }
         }
 
+        // Take local counter overflow interrupts while the vCPU has counters running so that we can
+        // attribute them, and inject any overflow that's still pending for the vCPU.
+        let has_sscofpmf = CpuInfo::get().has_sscofpmf();
+        if has_sscofpmf {
+            if self.arch.pmu.has_started_counters() {
+                CSR.sie.read_and_set_field(sie::lcofi);
+            }
+            if self.arch.pmu.lcofi_pending() {
+                CSR.hvip.read_and_set_field(hvip::lcofi);
+            } else {
+                CSR.hvip.read_and_clear_field(hvip::lcofi);
+            }
+        }
+
+        // Without a guest interrupt file, external interrupts are delivered from the vCPU's software
+        // interrupt file by asserting VSEIP through HVIP. Also pick up the interrupts injected by
+        // the host since we last ran, as they may have been injected from another CPU.
+        let (emulated_pending, injected) = self.vcpu.ext_interrupts().map_or((false, 0), |ei| {
+            let mut ei = ei.lock();
+            (ei.emulated_interrupt_pending(), ei.take_injected_count())
+        });
+        self.pmu()
+            .count_firmware_events(FirmwareEvent::IpiReceived, injected);
+        if emulated_pending {
+            CSR.hvip.read_and_set_field(hvip::vsext);
+        } else {
+            CSR.hvip.read_and_clear_field(hvip::vsext);
+        }
+
         let has_vector = CpuInfo::get().has_vector();
         let guest_id = self.vcpu.guest_id;
-        let regs = &mut self.arch.regs;
 
-        unsafe {
-            // Safe since _restore_vector() only reads within the bounds of the vector register
-            // state in VmCpuRegisters.
-            if has_vector {
-                _restore_vector(regs);
+        // FP and vector state is restored lazily: if our state isn't already live in this CPU's
+        // registers, enter with FS/VS off and restore it when the guest first traps on using it.
+        let mut sstatus = LocalRegisterCopy::new(self.arch.regs.guest_regs.sstatus);
+        let guest_fs = sstatus.read(sstatus::fs);
+        let guest_vs = sstatus.read(sstatus::vs);
+        let (mut lazy_fp, mut lazy_vector) =
+            hold_back_lazy_regs(&mut sstatus, has_vector, |regs| self.regs_are_live(regs));
+        self.arch.regs.guest_regs.sstatus = sstatus.get();
+        // Illegal instruction exceptions are normally delegated to the guest, so we have to catch
+        // them ourselves in order to see the guest's first use of FP or vector instructions, and,
+        // without Sstc, its accesses to `stimecmp`.
+        let catch_illegal_instr = (lazy_fp || lazy_vector || !CpuInfo::get().has_sstc())
+            && CSR.hedeleg.read_and_clear_field(hedeleg::illegal_instr) != 0;
+        // Likewise for breakpoints if we're being debugged, including those raised by the step
+        // trigger once the guest retires an instruction. The host checked that firmware supports
+        // the trigger when stepping was enabled, so if it fails now just run the guest unstepped.
+        let step_trigger = self
+            .arch
+            .single_step
+            .then(|| PerCpu::this_cpu().step_trigger_mut().install().ok())
+            .flatten();
+        let break_on_ebreak = self.arch.break_on_ebreak || step_trigger.is_some();
+        let catch_breakpoint =
+            break_on_ebreak && CSR.hedeleg.read_and_clear_field(hedeleg::breakpoint) != 0;
+
+        loop {
+            // Safe to run the guest as it only touches memory assigned to it by being owned
+            // by its page table.
+            unsafe { _run_guest(&mut self.arch.regs) };
+
+            if !(lazy_fp || lazy_vector)
+                || !matches!(
+                    Trap::from_scause(CSR.scause.get()),
+                    Ok(Trap::Exception(Exception::IllegalInstruction))
+                )
+            {
+                break;
             }
 
-            // Safe since _restore_fp() only reads within the bounds of the floating point
-            // register state in VmCpuRegisters.
-            _restore_fp(regs);
+            // Restore whatever state we held back and retry the instruction. If it was illegal for
+            // some other reason it'll trap again, this time with FS/VS in their proper state.
+            let mut sstatus = LocalRegisterCopy::new(self.arch.regs.guest_regs.sstatus);
+            if lazy_fp {
+                self.load_regs(LazyRegs::Fp);
+                sstatus.modify(sstatus::fs.val(guest_fs));
+                lazy_fp = false;
+            }
+            if lazy_vector {
+                self.load_regs(LazyRegs::Vector);
+                sstatus.modify(sstatus::vs.val(guest_vs));
+                lazy_vector = false;
+            }
+            self.arch.regs.guest_regs.sstatus = sstatus.get();
+        }
 
-            // Safe to run the guest as it only touches memory assigned to it by being owned
-            // by its page table.
-            _run_guest(regs);
+        if catch_illegal_instr {
+            CSR.hedeleg.read_and_set_field(hedeleg::illegal_instr);
+        }
+        if catch_breakpoint {
+            CSR.hedeleg.read_and_set_field(hedeleg::breakpoint);
+        }
+        if let Some(index) = step_trigger {
+            PerCpu::this_cpu().step_trigger_mut().uninstall(index);
+        }
+        // The guest couldn't have touched any state we held back, so just restore its FS/VS.
+        let mut sstatus = LocalRegisterCopy::new(self.arch.regs.guest_regs.sstatus);
+        if lazy_fp {
+            sstatus.modify(sstatus::fs.val(guest_fs));
+        }
+        if lazy_vector {
+            sstatus.modify(sstatus::vs.val(guest_vs));
+        }
+        self.arch.regs.guest_regs.sstatus = sstatus.get();
+
+        if has_sscofpmf {
+            // The guest may have cleared the interrupt through VSIP.
+            CSR.sie.read_and_clear_field(sie::lcofi);
+            self.arch
+                .pmu
+                .set_lcofi_pending(CSR.hvip.read(hvip::lcofi) != 0);
         }
 
+        let regs = &mut self.arch.regs;
         // Save off the trap information.
         regs.trap_csrs.scause = CSR.scause.get();
         regs.trap_csrs.stval = CSR.stval.get();
@@ -671,6 +897,14 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                     priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
                 }
             }
+            Trap::Exception(Breakpoint) if break_on_ebreak => VmCpuTrap::DebugBreakpoint,
+            Trap::Exception(IllegalInstruction) if catch_illegal_instr => {
+                VmCpuTrap::IllegalInstruction {
+                    // See above re: this address being guest virtual.
+                    fault_pc: RawAddr::guest_virt(regs.guest_regs.sepc, guest_id),
+                    priv_level: PrivilegeLevel::from_hstatus(regs.guest_regs.hstatus),
+                }
+            }
             Trap::Exception(VirtualInstruction) => {
                 VmCpuTrap::VirtualInstruction {
                     // See above re: this address being guest virtual.
@@ -692,7 +926,30 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                     VmCpuTrap::OtherException(regs.trap_csrs.clone())
                 }
             }
+            Trap::Interrupt(SupervisorTimer) if !CpuInfo::get().has_sstc() => {
+                // One of the deadlines multiplexed onto our timer expired. If it was our host's,
+                // let it handle the interrupt. Otherwise it was ours and we need to re-run the vCPU
+                // to inject it.
+                PerCpu::this_cpu().vm_timers_mut().handle_interrupt();
+                if self.host_timer_expired() {
+                    VmCpuTrap::HostInterrupt(SupervisorTimer)
+                } else {
+                    VmCpuTrap::InterruptEmulation
+                }
+            }
             Trap::Interrupt(SupervisorTimer) => VmCpuTrap::HostInterrupt(SupervisorTimer),
+            Trap::Interrupt(LocalCounterOverflow) => {
+                // Only the counters of the vCPU we were running can overflow, so this is never
+                // reported to our host: it mustn't learn anything about a TVM's counters.
+                if self.arch.pmu.handle_overflow() {
+                    // Re-run the vCPU to inject the interrupt.
+                    VmCpuTrap::InterruptEmulation
+                } else {
+                    // None of its started counters overflowed, e.g. the counter was stopped just
+                    // as it overflowed.
+                    VmCpuTrap::SpuriousInterrupt
+                }
+            }
             Trap::Interrupt(SupervisorGuestExternal) => {
                 if let VmCpuParent::HostVm(ref host_vcpu) = self.host_context {
                     // We may have gotten an SG_EXT because of an external interrupt directed at
@@ -775,6 +1032,17 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
             .set_csr(CSR_SCAUSE, Exception::VirtualInstruction as u64);
     }
 
+    fn report_breakpoint_exit(&mut self) {
+        // The vCPU resumes at the EBREAK, or after the instruction it stepped, so let the host know
+        // where it is.
+        let pc = self.arch.regs.guest_regs.sepc;
+        self.host_context.set_csr(CSR_STVAL, pc);
+        self.host_context.set_csr(CSR_HTVAL, 0);
+        self.host_context.set_csr(CSR_HTINST, 0);
+        self.host_context
+            .set_csr(CSR_SCAUSE, Exception::Breakpoint as u64);
+    }
+
     fn report_unhandled_exit(&mut self, scause: u64) {
         self.host_context.set_csr(CSR_STVAL, 0);
         self.host_context.set_csr(CSR_HTVAL, 0);
@@ -786,8 +1054,8 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
     /// either returned to the `Available` or `PoweredOff` state, depending on if the exit cause is
     /// resumable.
     pub fn exit(mut self, cause: VmExitCause) {
-        self.host_context
-            .set_csr(CSR_VSTIMECMP, CSR.vstimecmp.get());
+        let vstimecmp = self.vstimecmp();
+        self.host_context.set_csr(CSR_VSTIMECMP, vstimecmp);
         self.host_context.set_csr(CSR_VSIE, CSR.vsie.get());
         // We need to report guest's htimedelta to Host so that it can schedule timer properly.
         //
@@ -858,6 +1126,9 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
             UnhandledTrap(scause) => {
                 self.report_unhandled_exit(scause);
             }
+            DebugBreakpoint => {
+                self.report_breakpoint_exit();
+            }
             HostInterrupt(i) => {
                 // Just set up SCAUSE on an interrupt. The interrupt will pend (and trap, if
                 // necessary) when the host is swapped in.
@@ -984,7 +1255,10 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                         //
                         // Unwrap ok: We must be bound to have been activated with external interrupts.
                         let vgein = self.bound_interrupt_file().unwrap().bits();
-                        let vhgeip = (CSR.hgeip.get() >> (vgein + 1)) << 1;
+                        // Along with the files that have interrupts pending now, report those whose
+                        // vCPUs were woken while we were idle and that the host hasn't been told of.
+                        let woken = PerCpu::this_cpu().guest_files_mut().take_runnable();
+                        let vhgeip = ((CSR.hgeip.get() | woken) >> (vgein + 1)) << 1;
                         Ok(vhgeip & valid)
                     } else {
                         Ok(0)
@@ -1025,14 +1299,30 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
                     Ok(sip)
                 }
                 CSR_STIMECMP => {
-                    let stimecmp = CSR.vstimecmp.get();
-                    CSR.vstimecmp.set((stimecmp & !mask) | (value & mask));
+                    let stimecmp = self.vstimecmp();
+                    self.set_timer((stimecmp & !mask) | (value & mask));
                     Ok(stimecmp)
                 }
                 _ => Err(Error::InvalidCsrAccess),
             }
         } else {
-            Err(Error::InvalidCsrAccess)
+            // Accesses to the interrupt file of a vCPU that isn't bound to a guest interrupt file
+            // trap, so emulate them with its software interrupt file.
+            let mut ext_interrupts = self
+                .vcpu
+                .ext_interrupts()
+                .map_err(|_| Error::InvalidCsrAccess)?
+                .lock();
+            if !ext_interrupts.is_emulated() {
+                return Err(Error::InvalidCsrAccess);
+            }
+            match csr_num {
+                CSR_STOPEI => Ok(ext_interrupts.emulate_stopei_rmw(mask != 0)),
+                CSR_SIREG => ext_interrupts
+                    .emulate_sireg_rmw(CSR.vsiselect.get(), value, mask)
+                    .map_err(|_| Error::InvalidCsrAccess),
+                _ => Err(Error::InvalidCsrAccess),
+            }
         }
     }
 
@@ -1192,7 +1482,10 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
         // mode. Needed because hgatp can't be cleared atomically when setting vsatp so in some
         // micro-architectures cached translations have a window where they can be created.
         vs_csrs.vsatp = CSR.vsatp.atomic_replace(0);
-        vs_csrs.vstimecmp = CSR.vstimecmp.get();
+        // Without Sstc the timer deadline only ever lives in `vs_csrs`.
+        if CpuInfo::get().has_sstc() {
+            vs_csrs.vstimecmp = CSR.vstimecmp.get();
+        }
     }
 
     fn restore(&mut self) {
@@ -1205,13 +1498,16 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
         match self.host_context {
             VmCpuParent::HostVm(ref host_vcpu) => {
                 // Set our host's timer to the HS-level STIMECMP so that we'll trap if the host's
-                // timer expires.
-                CSR.stimecmp.set(
-                    host_vcpu
-                        .vs_csrs()
-                        .vstimecmp
-                        .wrapping_sub(host_vcpu.vs_csrs().htimedelta),
-                );
+                // timer expires. Without Sstc the host's deadline is already in this CPU's timer
+                // queue.
+                if CpuInfo::get().has_sstc() {
+                    CSR.stimecmp.set(
+                        host_vcpu
+                            .vs_csrs()
+                            .vstimecmp
+                            .wrapping_sub(host_vcpu.vs_csrs().htimedelta),
+                    );
+                }
 
                 // If our host has external interrupts enabled, set the bit for their interrupt file
                 // in HGEIE so that we trap on any external interrupts they receive.
@@ -1246,7 +1542,9 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
         CSR.vscause.set(vs_csrs.vscause);
         CSR.vstval.set(vs_csrs.vstval);
         CSR.vsatp.set(vs_csrs.vsatp);
-        CSR.vstimecmp.set(vs_csrs.vstimecmp);
+        if CpuInfo::get().has_sstc() {
+            CSR.vstimecmp.set(vs_csrs.vstimecmp);
+        }
     }
 
     // Restores the VM's address space.
@@ -1279,6 +1577,103 @@ impl<'vcpu, 'pages, 'host, T: GuestStagePagingMode> ActiveVmCpu<'vcpu, 'pages, '
     pub fn is_host_vcpu(&self) -> bool {
         self.vcpu.guest_id.is_host()
     }
+
+    // Returns this vCPU's timer deadline, in its own timebase.
+    fn vstimecmp(&self) -> u64 {
+        if CpuInfo::get().has_sstc() {
+            CSR.vstimecmp.get()
+        } else {
+            self.arch.regs.vs_csrs.vstimecmp
+        }
+    }
+
+    /// Sets this vCPU's timer deadline to `vstimecmp`, in its own timebase, clearing any pending
+    /// timer interrupt if the deadline is in the future.
+    pub fn set_timer(&mut self, vstimecmp: u64) {
+        if CpuInfo::get().has_sstc() {
+            CSR.vstimecmp.set(vstimecmp);
+        } else {
+            // Picked up by `update_virtual_timer()` the next time the vCPU is run.
+            self.arch.regs.vs_csrs.vstimecmp = vstimecmp;
+        }
+    }
+
+    /// Emulates a read-modify-write of `stimecmp` by a vCPU running on a CPU without Sstc, where the
+    /// access raises an illegal instruction exception. Returns the previous value of `stimecmp`.
+    pub fn emulate_stimecmp_rmw(&mut self, value: u64, mask: u64) -> u64 {
+        let stimecmp = self.vstimecmp();
+        self.set_timer((stimecmp & !mask) | (value & mask));
+        stimecmp
+    }
+
+    // Emulates Sstc for CPUs that lack it by arming this CPU's timer queue with our deadline and
+    // asserting VSTIP through HVIP if the deadline has already passed.
+    fn update_virtual_timer(&mut self) {
+        let vs_csrs = &self.arch.regs.vs_csrs;
+        let deadline = vm_timer::host_deadline(vs_csrs.vstimecmp, vs_csrs.htimedelta);
+        let mut vm_timers = PerCpu::this_cpu().vm_timers_mut();
+        // Unwrap ok: room for our timer was made when we were activated.
+        vm_timers
+            .arm(self.vcpu.guest_id, self.vcpu.vcpu_id, deadline)
+            .unwrap();
+        if vm_timer::read_time() >= deadline {
+            CSR.hvip.read_and_set_field(hvip::vstimer);
+        } else {
+            CSR.hvip.read_and_clear_field(hvip::vstimer);
+        }
+        if vm_timers.is_armed() {
+            CSR.sie.read_and_set_field(sie::stimer);
+        }
+    }
+
+    // Returns true if we're being run by a host vCPU whose timer has expired with timer interrupts
+    // enabled.
+    fn host_timer_expired(&self) -> bool {
+        if let VmCpuParent::HostVm(ref host_vcpu) = self.host_context {
+            let vs_csrs = host_vcpu.vs_csrs();
+            let host_sie = LocalRegisterCopy::<u64, sie::Register>::new(vs_csrs.vsie);
+            host_sie.read(sie::stimer) != 0
+                && vm_timer::read_time()
+                    >= vm_timer::host_deadline(vs_csrs.vstimecmp, vs_csrs.htimedelta)
+        } else {
+            false
+        }
+    }
+
+    // Returns if this vCPU's state for `regs` is still loaded in the current CPU's registers.
+    fn regs_are_live(&self, regs: LazyRegs) -> bool {
+        let loaded = match regs {
+            LazyRegs::Fp => &self.arch.fp_loaded,
+            LazyRegs::Vector => &self.arch.vector_loaded,
+        };
+        let this_cpu = PerCpu::this_cpu();
+        loaded
+            .as_ref()
+            .is_some_and(|l| l.is_live(this_cpu.cpu_id(), &this_cpu.lazy_regs_mut(), regs))
+    }
+
+    // Loads this vCPU's state for `regs` into the current CPU's registers.
+    fn load_regs(&mut self, regs: LazyRegs) {
+        let this_cpu = PerCpu::this_cpu();
+        let loaded = Some(LoadedRegs {
+            cpu: this_cpu.cpu_id(),
+            generation: this_cpu.lazy_regs_mut().next_generation(regs),
+        });
+        match regs {
+            LazyRegs::Fp => {
+                // Safe since _restore_fp() only reads within the bounds of the floating point
+                // register state in VmCpuRegisters.
+                unsafe { _restore_fp(&mut self.arch.regs) };
+                self.arch.fp_loaded = loaded;
+            }
+            LazyRegs::Vector => {
+                // Safe since _restore_vector() only reads within the bounds of the vector register
+                // state in VmCpuRegisters.
+                unsafe { _restore_vector(&mut self.arch.regs) };
+                self.arch.vector_loaded = loaded;
+            }
+        }
+    }
 }
 
 impl<T: GuestStagePagingMode> VmCpuExitReporting for ActiveVmCpu<'_, '_, '_, T> {
@@ -1347,6 +1742,13 @@ impl<T: GuestStagePagingMode> HostVmCpu for ActiveVmCpu<'_, '_, '_, T> {
 
 impl<T: GuestStagePagingMode> Drop for ActiveVmCpu<'_, '_, '_, T> {
     fn drop(&mut self) {
+        if !CpuInfo::get().has_sstc() && !self.is_host_vcpu() {
+            // TVM vCPUs may move between CPUs, so we only track their timers while they're running;
+            // ours gets re-armed when we're next run. The host VM's vCPUs never leave their CPU.
+            PerCpu::this_cpu()
+                .vm_timers_mut()
+                .disarm(self.vcpu.guest_id, self.vcpu.vcpu_id);
+        }
         // Context-switch back to the host (v)CPU.
         self.save();
         if let VmCpuParent::HostVm(ref mut host_vcpu) = self.host_context {
@@ -1381,15 +1783,30 @@ pub struct VmCpu {
 }
 
 impl VmCpu {
-    /// Creates a new `VmCpu` with ID `vcpu_id`. The returned `VmCpu` is initially powered off.
-    pub fn new(vcpu_id: u64, guest_id: PageOwnerId) -> VmCpu {
-        VmCpu {
+    /// Creates a new `VmCpu` with ID `vcpu_id` using `pages` to hold its state. `pages` must be at
+    /// least `VmCpus::required_state_pages_per_vcpu()` pages long. The returned `VmCpu` is
+    /// initially powered off.
+    pub fn new(
+        vcpu_id: u64,
+        guest_id: PageOwnerId,
+        pages: SequentialPages<InternalClean>,
+        page_tracker: PageTracker,
+    ) -> PageBox<VmCpu> {
+        assert!(pages.len() >= VmCpus::required_state_pages_per_vcpu());
+        // The vector register file lives in the pages immediately following the `VmCpu` itself.
+        let vprs = if vector_register_len() != 0 {
+            pages.base().bits() + PageBox::<VmCpu>::required_pages() * PageSize::Size4k as u64
+        } else {
+            0
+        };
+        let vcpu = VmCpu {
             status: RwLock::new(VmCpuStatus::PoweredOff),
-            arch: Mutex::new(VmCpuArchState::new(guest_id)),
+            arch: Mutex::new(VmCpuArchState::new(guest_id, vprs)),
             ext_interrupts: Once::new(),
             guest_id,
             vcpu_id,
-        }
+        };
+        PageBox::new_with(vcpu, pages, page_tracker)
     }
 
     /// Powers on this vCPU and sets its entry point to the specified SEPC and A1 values.
@@ -1415,6 +1832,88 @@ impl VmCpu {
         arch.regs.vs_csrs.htimedelta = htimedelta;
     }
 
+    /// Reads `reg` from the saved state of this vCPU on behalf of a debugger. The vCPU must not be
+    /// running.
+    pub fn debug_read_reg(&self, reg: DebugReg) -> Result<u64> {
+        let status = self.status.read();
+        if *status == VmCpuStatus::Running {
+            return Err(Error::VmCpuRunning);
+        }
+        let mut arch = self.arch.lock();
+        let regs = &mut arch.regs;
+        match reg {
+            DebugReg::Gpr(gpr) => Ok(regs.guest_regs.gprs.reg(gpr)),
+            DebugReg::Pc => Ok(regs.guest_regs.sepc),
+            DebugReg::Csr(csr) => Self::debug_csr(regs, csr).map(|val| *val),
+        }
+    }
+
+    /// Writes `val` to `reg` in the saved state of this vCPU on behalf of a debugger. The vCPU must
+    /// not be running.
+    pub fn debug_write_reg(&self, reg: DebugReg, val: u64) -> Result<()> {
+        let status = self.status.read();
+        if *status == VmCpuStatus::Running {
+            return Err(Error::VmCpuRunning);
+        }
+        let mut arch = self.arch.lock();
+        let regs = &mut arch.regs;
+        match reg {
+            DebugReg::Gpr(gpr) => regs.guest_regs.gprs.set_reg(gpr, val),
+            DebugReg::Pc => regs.guest_regs.sepc = val,
+            DebugReg::Csr(csr) if DEBUG_READ_ONLY_CSRS.contains(&csr) => {
+                return Err(Error::InvalidCsrAccess);
+            }
+            DebugReg::Csr(csr) => {
+                *Self::debug_csr(regs, csr)? = val;
+                // Make sure the new value is what gets loaded if the guest's FP or vector state is
+                // still live in some CPU's registers.
+                match csr {
+                    CSR_FCSR => arch.fp_loaded = None,
+                    CSR_VSTART | CSR_VCSR => arch.vector_loaded = None,
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the saved value of the CSR `csr` as seen by the guest. FP and vector CSRs are saved
+    // on every exit from the guest if it's changed them, so they're up to date as well.
+    fn debug_csr(regs: &mut VmCpuRegisters, csr: u16) -> Result<&mut u64> {
+        let guest_regs = &mut regs.guest_regs;
+        let vs_csrs = &mut regs.vs_csrs;
+        let val = match csr {
+            CSR_FCSR => &mut guest_regs.fcsr,
+            CSR_VSTART => &mut guest_regs.vstart,
+            CSR_VCSR => &mut guest_regs.vcsr,
+            CSR_VL => &mut guest_regs.vl,
+            CSR_VTYPE => &mut guest_regs.vtype,
+            CSR_SCOUNTEREN => &mut guest_regs.scounteren,
+            CSR_HTIMEDELTA => &mut vs_csrs.htimedelta,
+            CSR_SSTATUS => &mut vs_csrs.vsstatus,
+            CSR_SIE => &mut vs_csrs.vsie,
+            CSR_STVEC => &mut vs_csrs.vstvec,
+            CSR_SSCRATCH => &mut vs_csrs.vsscratch,
+            CSR_SEPC => &mut vs_csrs.vsepc,
+            CSR_SCAUSE => &mut vs_csrs.vscause,
+            CSR_STVAL => &mut vs_csrs.vstval,
+            CSR_SATP => &mut vs_csrs.vsatp,
+            CSR_STIMECMP => &mut vs_csrs.vstimecmp,
+            _ => return Err(Error::InvalidCsrAccess),
+        };
+        Ok(val)
+    }
+
+    /// Sets whether EBREAKs executed by this vCPU exit to its host's debugger.
+    pub fn set_break_on_ebreak(&self, enable: bool) {
+        self.arch.lock().break_on_ebreak = enable;
+    }
+
+    /// Sets whether this vCPU exits to its host's debugger after each instruction it retires.
+    pub fn set_single_step(&self, enable: bool) {
+        self.arch.lock().single_step = enable;
+    }
+
     /// Returns the ID of the vCPU in the guest.
     pub fn vcpu_id(&self) -> u64 {
         self.vcpu_id
@@ -1441,9 +1940,10 @@ impl VmCpu {
                     return Err(Error::WrongAddressSpace);
                 }
 
-                // We must be bound to the current CPU if IMSIC virtualization is enabled.
+                // If IMSIC virtualization is enabled we must either be bound to the current CPU or
+                // not be bound at all, in which case our interrupt file is emulated.
                 if let Some(ext_interrupts) = self.ext_interrupts.get() &&
-                    !ext_interrupts.lock().is_bound_on_this_cpu()
+                    !ext_interrupts.lock().can_run_on_this_cpu()
                 {
                     return Err(Error::VmCpuNotBound);
                 }
@@ -1485,6 +1985,12 @@ impl VmCpu {
             .map(|ei| ei.lock().imsic_location())
     }
 
+    /// Returns the location of this vCPU's guest interrupt file relative to the current physical
+    /// CPU.
+    pub fn imsic_bind_location(&self) -> Result<BindLocation> {
+        Ok(self.ext_interrupts()?.lock().bind_location())
+    }
+
     /// Prepares to bind this vCPU to `interrupt_file` on the current physical CPU.
     pub fn bind_imsic_prepare(&self, interrupt_file: ImsicFileId) -> Result<()> {
         // We skip the self.status check here (and similarly for the other bind/unbind calls)
@@ -1503,6 +2009,7 @@ impl VmCpu {
             .lock()
             .bind_imsic_finish()
             .map_err(Error::Binding)?;
+        self.track_guest_file(interrupt_file, true);
 
         // Update VGEIN so that the selected interrupt file gets used next time the vCPU is run.
         let mut arch = self.arch.lock();
@@ -1524,10 +2031,12 @@ impl VmCpu {
 
     /// Copies the guest interrupt file state on the previous CPU.
     pub fn rebind_imsic_clone(&self) -> Result<()> {
-        self.ext_interrupts()?
+        let prev_file = self
+            .ext_interrupts()?
             .lock()
             .rebind_imsic_clone()
             .map_err(Error::Rebinding)?;
+        self.track_guest_file(prev_file, false);
         Ok(())
     }
 
@@ -1548,6 +2057,7 @@ impl VmCpu {
             .lock()
             .rebind_imsic_finish()
             .map_err(Error::Rebinding)?;
+        self.track_guest_file(interrupt_file, true);
 
         // Update VGEIN so that the selected interrupt file gets used next time the vCPU is run.
         let mut arch = self.arch.lock();
@@ -1568,10 +2078,37 @@ impl VmCpu {
 
     /// Completes the IMSIC unbind operation started in `unbind_imsic_prepare()`.
     pub fn unbind_imsic_finish(&self) -> Result<()> {
-        self.ext_interrupts()?
+        let interrupt_file = self
+            .ext_interrupts()?
             .lock()
             .unbind_imsic_finish()
-            .map_err(Error::Unbinding)
+            .map_err(Error::Unbinding)?;
+        self.track_guest_file(interrupt_file, false);
+
+        // Clear VGEIN so that the interrupt file is no longer used (and the software interrupt file
+        // is emulated instead) next time the vCPU is run.
+        let mut arch = self.arch.lock();
+        let mut hstatus =
+            LocalRegisterCopy::<u64, hstatus::Register>::new(arch.regs.guest_regs.hstatus);
+        hstatus.modify(hstatus::vgein.val(0));
+        arch.regs.guest_regs.hstatus = hstatus.get();
+        Ok(())
+    }
+
+    // Updates the current CPU's record of which vCPU is bound to `interrupt_file` so that
+    // supervisor guest external interrupts taken while this vCPU isn't running can be attributed to
+    // it. The host VM's vCPUs are never run from another VM, so they aren't tracked.
+    fn track_guest_file(&self, interrupt_file: ImsicFileId, bound: bool) {
+        if self.guest_id == PageOwnerId::host() {
+            return;
+        }
+        let vcpu = BoundVmCpu::new(self.guest_id, self.vcpu_id);
+        let mut guest_files = PerCpu::this_cpu().guest_files_mut();
+        if bound {
+            guest_files.bind(interrupt_file, vcpu);
+        } else {
+            guest_files.unbind(interrupt_file, vcpu);
+        }
     }
 
     /// Injects the specified external interrupt ID into this vCPU, if allowed.
@@ -1618,6 +2155,14 @@ impl VmCpus {
         }
     }
 
+    /// Returns an iterator over the IDs and statuses of the vCPUs that have been added.
+    pub fn statuses(&self) -> impl Iterator<Item = (u64, VmCpuStatus)> + '_ {
+        self.inner
+            .iter()
+            .filter_map(|once| once.get())
+            .map(|vcpu| (vcpu.vcpu_id(), vcpu.status()))
+    }
+
     /// Returns a reference to the vCPU with `vcpu_id` if it exists.
     pub fn get_vcpu(&self, vcpu_id: u64) -> Result<&VmCpu> {
         let vcpu = self
@@ -1628,9 +2173,16 @@ impl VmCpus {
         Ok(vcpu)
     }
 
+    /// Records the width in bytes of a vector register on this platform, as reported by the vlenb
+    /// CSR. Must be called before any vCPUs are created on platforms with the vector extension.
+    pub fn set_vector_register_len(vlenb: u64) {
+        VECTOR_REGISTER_LEN.call_once(|| vlenb);
+    }
+
     /// Returns the number of pages that must be donated to create a vCPU.
-    pub const fn required_state_pages_per_vcpu() -> u64 {
-        PageBox::<VmCpu>::required_pages()
+    pub fn required_state_pages_per_vcpu() -> u64 {
+        let vector_bytes = vector_register_file_size(vector_register_len());
+        PageBox::<VmCpu>::required_pages() + PageSize::num_4k_pages(vector_bytes)
     }
 }
 
@@ -1639,3 +2191,89 @@ impl VmCpus {
 // the shared-memory vCPU state structure.
 unsafe impl Sync for VmCpus {}
 unsafe impl Send for VmCpus {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    #[test_case]
+    fn LazyRegsLivenessTest() -> TestResult {
+        let cpu = CpuId::new(0);
+        let mut tracker = LazyRegsTracker::new();
+        let fp = LoadedRegs {
+            cpu,
+            generation: tracker.next_generation(LazyRegs::Fp),
+        };
+        test_result_true!(fp.is_live(cpu, &tracker, LazyRegs::Fp), "FP live")?;
+        test_result_true!(
+            !fp.is_live(CpuId::new(1), &tracker, LazyRegs::Fp),
+            "FP not live on another CPU"
+        )?;
+        // Loading vector state doesn't disturb FP state.
+        let vector = LoadedRegs {
+            cpu,
+            generation: tracker.next_generation(LazyRegs::Vector),
+        };
+        test_result_true!(
+            fp.is_live(cpu, &tracker, LazyRegs::Fp)
+                && vector.is_live(cpu, &tracker, LazyRegs::Vector),
+            "FP and vector live"
+        )?;
+        // Another vCPU loading its FP state replaces ours.
+        tracker.next_generation(LazyRegs::Fp);
+        test_result_true!(!fp.is_live(cpu, &tracker, LazyRegs::Fp), "FP replaced")?;
+        test_result_true!(
+            vector.is_live(cpu, &tracker, LazyRegs::Vector),
+            "vector still live"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn HoldBackLazyRegsTest() -> TestResult {
+        let guest_sstatus = |fs, vs| {
+            let mut sstatus = LocalRegisterCopy::<u64, sstatus::Register>::new(0);
+            sstatus.modify(sstatus::fs.val(fs) + sstatus::vs.val(vs));
+            sstatus
+        };
+        let dirty = sstatus::fs::Dirty.value;
+        let off = sstatus::fs::Off.value;
+
+        // State that isn't live is held back by turning it off.
+        let mut sstatus = guest_sstatus(dirty, dirty);
+        let held_back = hold_back_lazy_regs(&mut sstatus, true, |_| false);
+        test_result_true!(
+            held_back == (true, true)
+                && sstatus.read(sstatus::fs) == off
+                && sstatus.read(sstatus::vs) == off,
+            "hold back FP and vector"
+        )?;
+
+        // Live state is left on.
+        let mut sstatus = guest_sstatus(dirty, dirty);
+        let held_back =
+            hold_back_lazy_regs(&mut sstatus, true, |regs| matches!(regs, LazyRegs::Fp));
+        test_result_true!(
+            held_back == (false, true)
+                && sstatus.read(sstatus::fs) == dirty
+                && sstatus.read(sstatus::vs) == off,
+            "FP live"
+        )?;
+
+        // Nothing to restore if the guest has FP and vectors off, or there are no vectors.
+        let mut sstatus = guest_sstatus(off, off);
+        test_result_true!(
+            hold_back_lazy_regs(&mut sstatus, true, |_| false) == (false, false),
+            "guest FP and vector off"
+        )?;
+        let mut sstatus = guest_sstatus(dirty, dirty);
+        let held_back = hold_back_lazy_regs(&mut sstatus, false, |_| false);
+        test_result_true!(
+            held_back == (true, false) && sstatus.read(sstatus::vs) == dirty,
+            "no vectors"
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use core::arch::asm;
+use riscv_regs::GprIndex;
+
+// Function IDs of the TVM debug calls. These are a Salus extension to the CoVE host interface which
+// `CoveHostFunction` doesn't cover, so we decode them ourselves. They're only available for TVMs
+// created with `TVM_ATTR_DEBUG`.
+
+/// Reads a register of a TVM vCPU: `(tvm_id, vcpu_id, reg_id) -> value`.
+pub const TVM_DEBUG_READ_REG_FID: u64 = 0x1000;
+/// Writes a register of a TVM vCPU: `(tvm_id, vcpu_id, reg_id, value)`.
+pub const TVM_DEBUG_WRITE_REG_FID: u64 = 0x1001;
+/// Copies TVM memory to the host: `(tvm_id, tvm_addr, host_addr, len) -> bytes copied`.
+pub const TVM_DEBUG_READ_MEM_FID: u64 = 0x1002;
+/// Copies host memory into the TVM: `(tvm_id, tvm_addr, host_addr, len) -> bytes copied`.
+pub const TVM_DEBUG_WRITE_MEM_FID: u64 = 0x1003;
+/// Sets whether a TVM vCPU exits to the host on EBREAK: `(tvm_id, vcpu_id, enable)`.
+pub const TVM_DEBUG_SET_BREAKPOINT_EXITS_FID: u64 = 0x1004;
+/// Sets whether a TVM vCPU exits to the host with a breakpoint after each instruction it retires:
+/// `(tvm_id, vcpu_id, enable)`. Not supported if firmware lacks instruction count triggers.
+pub const TVM_DEBUG_SET_SINGLE_STEP_FID: u64 = 0x1005;
+
+/// Returns true if `fid` is one of the TVM debug calls.
+pub fn is_debug_fid(fid: u64) -> bool {
+    (TVM_DEBUG_READ_REG_FID..=TVM_DEBUG_SET_SINGLE_STEP_FID).contains(&fid)
+}
+
+/// TVM attribute that allows the host to debug the TVM. TVM attributes are a Salus extension of
+/// `TvmCreate`: a little-endian `u64` that follows `TvmCreateParams`, with the `len` passed to
+/// `TvmCreate` grown to cover it. Recorded in the TVM's configuration measurement so that
+/// attestation reveals it.
+pub const TVM_ATTR_DEBUG: u64 = 1 << 0;
+
+/// The most memory copied by a single debug memory access. Larger accesses are split by the caller.
+pub const MAX_DEBUG_ACCESS_LEN: u64 = 4096;
+
+// Register IDs above this are CSRs.
+const REG_ID_CSR_BASE: u64 = 0x1000;
+
+/// A vCPU register accessible through the debug calls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugReg {
+    /// A general purpose register; IDs 0-31.
+    Gpr(GprIndex),
+    /// The PC the vCPU will resume at; ID 32.
+    Pc,
+    /// A supervisor CSR as seen by the guest; ID 0x1000 plus the CSR number.
+    Csr(u16),
+}
+
+impl DebugReg {
+    /// Returns the register for the given raw register ID.
+    pub fn from_raw(reg_id: u64) -> Option<Self> {
+        match reg_id {
+            0..=31 => GprIndex::from_raw(reg_id as u32).map(DebugReg::Gpr),
+            32 => Some(DebugReg::Pc),
+            _ => reg_id
+                .checked_sub(REG_ID_CSR_BASE)
+                .and_then(|csr| u16::try_from(csr).ok())
+                .map(DebugReg::Csr),
+        }
+    }
+}
+
+/// The SBI debug triggers extension ID ("DBTR"). Not covered by `SbiMessage`, so we make the calls
+/// ourselves.
+pub const EXT_DBTR: u64 = 0x4442_5452;
+const DBTR_NUM_TRIGGERS_FID: u64 = 0;
+const DBTR_SET_SHMEM_FID: u64 = 1;
+const DBTR_INSTALL_TRIGGERS_FID: u64 = 3;
+const DBTR_UNINSTALL_TRIGGERS_FID: u64 = 5;
+
+// Fields of `tdata1` for an instruction count (icount) trigger.
+const ICOUNT_TYPE: u64 = 3 << 60;
+const ICOUNT_VS: u64 = 1 << 26;
+const ICOUNT_VU: u64 = 1 << 25;
+const ICOUNT_COUNT_SHIFT: u64 = 10;
+
+// An icount trigger that raises a breakpoint exception once VS or VU mode retires an instruction.
+// It doesn't count in HS mode, so it can be installed well before entering the guest.
+const STEP_TDATA1: u64 = ICOUNT_TYPE | ICOUNT_VS | ICOUNT_VU | (1 << ICOUNT_COUNT_SHIFT);
+
+/// Errors from single-stepping guests.
+#[derive(Debug)]
+pub enum Error {
+    /// Firmware failed a debug triggers call with the given SBI error code.
+    Dbtr(i64),
+}
+
+/// Holds the result of single-stepping operations.
+pub type Result<T> = core::result::Result<T, Error>;
+
+// Makes the DBTR call `fid`, returning its value.
+fn sbi_dbtr(fid: u64, arg0: u64, arg1: u64, arg2: u64) -> Result<u64> {
+    let error: i64;
+    let value: u64;
+    // Safety: DBTR calls only access the shared memory we've registered, which is owned by the
+    // calling CPU's `StepTrigger`, and only clobber A0 and A1.
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") EXT_DBTR,
+            options(nostack)
+        )
+    };
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(Error::Dbtr(error))
+    }
+}
+
+/// Single-steps the guests run on a CPU with an instruction count trigger installed by firmware.
+/// Lives in the CPU's identity-mapped per-CPU area so that its buffer can be shared with firmware.
+#[repr(C, align(64))]
+pub struct StepTrigger {
+    // The DBTR shared memory: one trigger's `tdata1`-`tdata3` on install, its index on return.
+    shmem: [u64; 3],
+    shmem_registered: bool,
+}
+
+impl StepTrigger {
+    /// Creates a `StepTrigger` with no shared memory registered with firmware.
+    pub const fn new() -> Self {
+        Self {
+            shmem: [0; 3],
+            shmem_registered: false,
+        }
+    }
+
+    /// Returns true if firmware can install the trigger on this CPU.
+    pub fn is_supported() -> bool {
+        sbi_dbtr(DBTR_NUM_TRIGGERS_FID, ICOUNT_TYPE, 0, 0).is_ok_and(|n| n != 0)
+    }
+
+    /// Installs the trigger, which breaks once the guest retires an instruction. Returns the index
+    /// of the trigger, to be passed to `uninstall()` on exit from the guest.
+    pub fn install(&mut self) -> Result<u64> {
+        if !self.shmem_registered {
+            // The per-CPU area is identity-mapped, so this is also the physical address.
+            let addr = self.shmem.as_ptr() as u64;
+            sbi_dbtr(DBTR_SET_SHMEM_FID, addr, 0, 0)?;
+            self.shmem_registered = true;
+        }
+        self.shmem = [STEP_TDATA1, 0, 0];
+        sbi_dbtr(DBTR_INSTALL_TRIGGERS_FID, 1, 0, 0)?;
+        Ok(self.shmem[0])
+    }
+
+    /// Uninstalls the trigger at `index`.
+    pub fn uninstall(&mut self, index: u64) {
+        // Nothing to be done if firmware refuses; the trigger doesn't fire outside the guest and
+        // the next step installs a new one.
+        let _ = sbi_dbtr(DBTR_UNINSTALL_TRIGGERS_FID, index, 1, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    #[test_case]
+    fn DebugRegFromRawTest() -> TestResult {
+        test_result_true!(
+            DebugReg::from_raw(0) == Some(DebugReg::Gpr(GprIndex::Zero)),
+            "from_raw zero"
+        )?;
+        test_result_true!(
+            DebugReg::from_raw(31) == Some(DebugReg::Gpr(GprIndex::T6)),
+            "from_raw t6"
+        )?;
+        test_result_true!(DebugReg::from_raw(32) == Some(DebugReg::Pc), "from_raw pc")?;
+        // IDs between the PC and the CSRs are unused.
+        test_result_true!(DebugReg::from_raw(33).is_none(), "from_raw 33")?;
+        test_result_true!(DebugReg::from_raw(0xfff).is_none(), "from_raw 0xfff")?;
+        test_result_true!(
+            DebugReg::from_raw(0x1100) == Some(DebugReg::Csr(0x100)),
+            "from_raw sstatus"
+        )?;
+        test_result_true!(
+            DebugReg::from_raw(0x1000 + 0xffff) == Some(DebugReg::Csr(0xffff)),
+            "from_raw last CSR"
+        )?;
+        test_result_true!(
+            DebugReg::from_raw(0x1000 + 0x10000).is_none(),
+            "from_raw beyond CSRs"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn DebugFidTest() -> TestResult {
+        test_result_true!(!is_debug_fid(0xfff), "is_debug_fid below")?;
+        test_result_true!(is_debug_fid(TVM_DEBUG_READ_REG_FID), "is_debug_fid first")?;
+        test_result_true!(
+            is_debug_fid(TVM_DEBUG_SET_SINGLE_STEP_FID),
+            "is_debug_fid single step"
+        )?;
+        test_result_true!(!is_debug_fid(0x1006), "is_debug_fid above")?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn StepTriggerTdata1Test() -> TestResult {
+        // type 3, vs and vu set, count 1, action 0 (breakpoint exception).
+        test_result_true!(STEP_TDATA1 == 0x3000_0000_0600_0400, "icount tdata1 value")?;
+        Ok(())
+    }
+}
@@ -44,6 +44,21 @@ fn get_vmid_bits() -> u64 {
     vmid_bits
 }
 
+// The VMID reserved for `VmIdTracker::reserved_vmid()`: the highest one supported.
+fn reserved_vmid(vmid_bits: u64) -> u64 {
+    (1 << vmid_bits) - 1
+}
+
+// Returns the VMID assigned after `vmid`, rolling over to 0 rather than assigning the reserved VMID.
+fn vmid_after(vmid: u64, vmid_bits: u64) -> u64 {
+    let next = vmid + 1;
+    if next >= reserved_vmid(vmid_bits) {
+        0
+    } else {
+        next
+    }
+}
+
 /// Returns the VMID length.
 pub fn get_vmid_len() -> u64 {
     // Unwrap okay: this is called after `VmIdTracker::init()`
@@ -78,10 +93,44 @@ impl VmIdTracker {
             tlb::hfence_gvma(None, None);
         }
         let vmid = self.next_vmid;
-        self.next_vmid = (self.next_vmid + 1) & ((1 << vmid_bits) - 1);
+        self.next_vmid = vmid_after(vmid, vmid_bits);
         VmId {
             vmid,
             version: self.current_version,
         }
     }
+
+    /// Returns the VMID reserved for accessing a VM's memory outside of running it. `next_vmid()`
+    /// never assigns it, so it can't alias a running VM's translations, but the caller must flush
+    /// it before and after use. With no VMID bits implemented every VM shares VMID 0, which is why
+    /// it must be flushed beforehand as well.
+    pub fn reserved_vmid(&self) -> VmId {
+        VmId {
+            vmid: reserved_vmid(get_vmid_len()),
+            version: self.current_version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    #[test_case]
+    fn VmIdReservedTest() -> TestResult {
+        test_result_true!(reserved_vmid(14) == 0x3fff, "reserved_vmid 14 bits")?;
+        test_result_true!(vmid_after(0, 14) == 1, "vmid_after first")?;
+        test_result_true!(
+            vmid_after(0x3ffd, 14) == 0x3ffe,
+            "vmid_after below reserved"
+        )?;
+        // The reserved VMID is skipped when rolling over.
+        test_result_true!(vmid_after(0x3ffe, 14) == 0, "vmid_after rollover")?;
+        // With a single VMID bit only VMID 0 is assigned.
+        test_result_true!(vmid_after(0, 1) == 0, "vmid_after 1 bit")?;
+        test_result_true!(vmid_after(0, 0) == 0, "vmid_after 0 bits")?;
+        Ok(())
+    }
 }
@@ -3,9 +3,15 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use arrayvec::ArrayVec;
-use drivers::{imsic::*, CpuId};
+use core::sync::atomic::{AtomicU64, Ordering};
+use drivers::{imsic::*, CpuId, MAX_CPUS};
+use riscv_pages::PageOwnerId;
+use riscv_regs::{
+    hie, stopei, LocalRegisterCopy, Readable, RiscvCsrInterface, Writeable, CSR,
+    ISELECT_EIDELIVERY, ISELECT_EIE_BASE, ISELECT_EIP_BASE, ISELECT_EITHRESHOLD,
+};
 
-use crate::smp::PerCpu;
+use crate::smp::{self, PerCpu};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Error {
@@ -16,6 +22,7 @@ pub enum Error {
     WrongPhysicalCpu,
     InvalidInterruptId(usize),
     DeniedInterruptId(usize),
+    InvalidIselect(u64),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -32,6 +39,27 @@ enum BindStatus {
     Unbound,
 }
 
+/// The location of a vCPU's guest interrupt file relative to the current physical CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BindLocation {
+    /// The vCPU isn't bound to a guest interrupt file.
+    Unbound,
+    /// The vCPU is bound to a guest interrupt file on the current CPU.
+    ThisCpu,
+    /// The vCPU is bound to the given guest interrupt file on another CPU.
+    OtherCpu(CpuId, ImsicFileId),
+    /// The vCPU is being moved to the current CPU and its interrupt file has yet to be saved on
+    /// the CPU it was previously bound to.
+    Saving,
+    /// The vCPU is being moved to the current CPU and its saved interrupt file is ready to be
+    /// restored.
+    Saved,
+    /// The vCPU is being unbound from its guest interrupt file on the given CPU.
+    Unbinding(CpuId),
+    /// The vCPU is in the middle of some other bind or unbind operation.
+    Busy,
+}
+
 const ALLOW_LIST_ENTRIES: usize = MAX_INTERRUPT_IDS / 64;
 
 // Bitmap tracking the per-vCPU allowed external interrupts.
@@ -89,6 +117,8 @@ pub struct VmCpuExtInterrupts {
     sw_file: SwFile,
     allowed_ids: AllowList,
     num_guests: usize,
+    // Interrupts injected since the vCPU last collected them for its PMU.
+    injected_count: u64,
 }
 
 impl VmCpuExtInterrupts {
@@ -102,6 +132,7 @@ impl VmCpuExtInterrupts {
             sw_file: SwFile::new(),
             allowed_ids: AllowList::new(Imsic::get().interrupt_ids()),
             num_guests,
+            injected_count: 0,
         }
     }
 
@@ -168,8 +199,9 @@ impl VmCpuExtInterrupts {
         Ok(())
     }
 
-    /// Copies the guest interrupt file over to sw file.
-    pub fn rebind_imsic_clone(&mut self) -> Result<()> {
+    /// Copies the guest interrupt file over to sw file, returning the interrupt file the vCPU was
+    /// previously bound to.
+    pub fn rebind_imsic_clone(&mut self) -> Result<ImsicFileId> {
         // Make sure there's a rebind operation in progress and we are on old CPU right now.
         let (cpu, interrupt_file, new_cpu, new_file) = match self.bind_status {
             BindStatus::Rebinding(cpu, interrupt_file, new_cpu, new_file) => {
@@ -191,7 +223,7 @@ impl VmCpuExtInterrupts {
             .save_guest_file_finish(interrupt_file, &mut self.sw_file)
             .map_err(Error::RebindingImsic)?;
         self.bind_status = BindStatus::Cloned(new_cpu, new_file);
-        Ok(())
+        Ok(interrupt_file)
     }
 
     /// Get the previous guest interrupt file's location.
@@ -254,8 +286,9 @@ impl VmCpuExtInterrupts {
         Ok(())
     }
 
-    /// Completes the IMSIC unbind operation started in `unbind_imsic_prepare()`.
-    pub fn unbind_imsic_finish(&mut self) -> Result<()> {
+    /// Completes the IMSIC unbind operation started in `unbind_imsic_prepare()`, returning the
+    /// interrupt file the vCPU was bound to.
+    pub fn unbind_imsic_finish(&mut self) -> Result<ImsicFileId> {
         // We must be bound (to the current CPU) to start an unbind.
         let (cpu, interrupt_file) = match self.bind_status {
             BindStatus::Unbinding(cpu, interrupt_file) => (cpu, interrupt_file),
@@ -272,7 +305,21 @@ impl VmCpuExtInterrupts {
             .save_guest_file_finish(interrupt_file, &mut self.sw_file)
             .map_err(Error::UnbindingImsic)?;
         self.bind_status = BindStatus::Unbound;
-        Ok(())
+        Ok(interrupt_file)
+    }
+
+    /// Returns the location of this vCPU's guest interrupt file relative to the current CPU.
+    pub fn bind_location(&self) -> BindLocation {
+        let this_cpu = PerCpu::this_cpu().cpu_id();
+        match self.bind_status {
+            BindStatus::Unbound => BindLocation::Unbound,
+            BindStatus::Bound(cpu_id, _) if cpu_id == this_cpu => BindLocation::ThisCpu,
+            BindStatus::Bound(cpu_id, file) => BindLocation::OtherCpu(cpu_id, file),
+            BindStatus::Rebinding(_, _, cpu_id, _) if cpu_id == this_cpu => BindLocation::Saving,
+            BindStatus::Cloned(cpu_id, _) if cpu_id == this_cpu => BindLocation::Saved,
+            BindStatus::Unbinding(cpu_id, _) => BindLocation::Unbinding(cpu_id),
+            _ => BindLocation::Busy,
+        }
     }
 
     /// Returns true if this vCPU is bound to the current physical CPU.
@@ -283,6 +330,107 @@ impl VmCpuExtInterrupts {
         }
     }
 
+    /// Returns true if this vCPU isn't bound to any guest interrupt file, in which case its
+    /// interrupt file is emulated in software using `sw_file` when it runs.
+    pub fn is_emulated(&self) -> bool {
+        self.bind_status == BindStatus::Unbound
+    }
+
+    /// Returns true if this vCPU can be run on the current physical CPU, either because it's bound
+    /// to one of the CPU's guest interrupt files or because its interrupt file is emulated.
+    pub fn can_run_on_this_cpu(&self) -> bool {
+        self.is_bound_on_this_cpu() || self.is_emulated()
+    }
+
+    // Returns the bits of EIP/EIE register `index` in the software interrupt file that correspond
+    // to implemented interrupt IDs. Interrupt ID 0 doesn't exist.
+    fn sw_file_valid_bits(&self, index: usize) -> u64 {
+        let num_ids = self.allowed_ids.num_ids.saturating_sub(index * 64).min(64);
+        let bits = if num_ids == 64 {
+            !0
+        } else {
+            (1 << num_ids) - 1
+        };
+        if index == 0 {
+            bits & !1
+        } else {
+            bits
+        }
+    }
+
+    // Returns the interrupt that `stopei` would report for the software interrupt file: the
+    // lowest-numbered (and thus highest priority) interrupt that's both pending and enabled, if it's
+    // below the interrupt threshold.
+    fn sw_file_top_interrupt(&self) -> Option<usize> {
+        let threshold = self.sw_file.eithreshold() as usize;
+        (0..SW_FILE_ENTRIES)
+            .find_map(|i| {
+                let bits = self.sw_file.eip(i) & self.sw_file.eie(i) & self.sw_file_valid_bits(i);
+                (bits != 0).then(|| i * 64 + bits.trailing_zeros() as usize)
+            })
+            .filter(|&id| threshold == 0 || id < threshold)
+    }
+
+    /// Returns true if this vCPU's interrupt file is emulated and has an interrupt to deliver.
+    pub fn emulated_interrupt_pending(&self) -> bool {
+        self.is_emulated()
+            && self.sw_file.eidelivery() & 1 != 0
+            && self.sw_file_top_interrupt().is_some()
+    }
+
+    /// Emulates a read-modify-write of `stopei` using the software interrupt file, returning the
+    /// previous value. As with a real interrupt file, any write claims the reported interrupt.
+    pub fn emulate_stopei_rmw(&mut self, write: bool) -> u64 {
+        let Some(id) = self.sw_file_top_interrupt() else {
+            return 0;
+        };
+        if write {
+            let index = id / 64;
+            self.sw_file
+                .set_eip(index, self.sw_file.eip(index) & !(1 << (id % 64)));
+        }
+        // The priority of an IMSIC interrupt is its ID.
+        let mut topei = LocalRegisterCopy::<u64, stopei::Register>::new(0);
+        topei.modify(stopei::interrupt_id.val(id as u64));
+        topei.modify(stopei::interrupt_prio.val(id as u64));
+        topei.get()
+    }
+
+    /// Emulates a read-modify-write of the interrupt file register selected by `iselect` through
+    /// `sireg` using the software interrupt file, returning the previous value.
+    pub fn emulate_sireg_rmw(&mut self, iselect: u64, value: u64, mask: u64) -> Result<u64> {
+        let update = |prev: u64| (prev & !mask) | (value & mask);
+        match iselect {
+            ISELECT_EIDELIVERY => {
+                let prev = self.sw_file.eidelivery();
+                self.sw_file.set_eidelivery(update(prev) & 1);
+                Ok(prev)
+            }
+            ISELECT_EITHRESHOLD => {
+                let prev = self.sw_file.eithreshold();
+                let valid = self.allowed_ids.num_ids.next_power_of_two() as u64 - 1;
+                self.sw_file.set_eithreshold(update(prev) & valid);
+                Ok(prev)
+            }
+            // Only the even-numbered registers exist on RV64.
+            ISELECT_EIP_BASE..=0xbf if iselect % 2 == 0 => {
+                let index = ((iselect - ISELECT_EIP_BASE) / 2) as usize;
+                let prev = self.sw_file.eip(index);
+                let valid = self.sw_file_valid_bits(index);
+                self.sw_file.set_eip(index, update(prev) & valid);
+                Ok(prev)
+            }
+            ISELECT_EIE_BASE..=0xff if iselect % 2 == 0 => {
+                let index = ((iselect - ISELECT_EIE_BASE) / 2) as usize;
+                let prev = self.sw_file.eie(index);
+                let valid = self.sw_file_valid_bits(index);
+                self.sw_file.set_eie(index, update(prev) & valid);
+                Ok(prev)
+            }
+            _ => Err(Error::InvalidIselect(iselect)),
+        }
+    }
+
     /// Adds `id` to the list of injectable external interrupts.
     pub fn allow_interrupt(&mut self, id: usize) -> Result<()> {
         self.allowed_ids.allow_id(id)
@@ -318,11 +466,278 @@ impl VmCpuExtInterrupts {
                 self.sw_file.set_eip_bit(id);
             }
         }
+        self.injected_count += 1;
         Ok(())
     }
 
+    /// Returns the number of interrupts injected since this was last called.
+    pub fn take_injected_count(&mut self) -> u64 {
+        core::mem::take(&mut self.injected_count)
+    }
+
     /// Returns the number of guest interrupt files this vCPU has.
     pub fn num_guests(&self) -> usize {
         self.num_guests
     }
 }
+
+// The number of bits in HGEIE/HGEIP. Bit 0 is reserved, so at most 63 guest interrupt files may be
+// implemented per physical CPU.
+const HGEIP_BITS: usize = 64;
+
+/// A TVM vCPU bound to one of the guest interrupt files of a physical CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoundVmCpu {
+    guest_id: PageOwnerId,
+    vcpu_id: u64,
+}
+
+impl BoundVmCpu {
+    /// Creates a new `BoundVmCpu` for `vcpu_id` in the VM with `guest_id`.
+    pub fn new(guest_id: PageOwnerId, vcpu_id: u64) -> Self {
+        Self { guest_id, vcpu_id }
+    }
+
+    /// Returns the ID of the VM the vCPU belongs to.
+    pub fn guest_id(&self) -> PageOwnerId {
+        self.guest_id
+    }
+
+    /// Returns the ID of the vCPU within its VM.
+    pub fn vcpu_id(&self) -> u64 {
+        self.vcpu_id
+    }
+}
+
+// Guest interrupt files, in HGEIP format, whose vCPUs are being moved to another CPU and need
+// their state saved by the CPU they're bound to. Indexed by CPU ID.
+static PENDING_SAVES: [AtomicU64; MAX_CPUS] = {
+    const NONE_PENDING: AtomicU64 = AtomicU64::new(0);
+    [NONE_PENDING; MAX_CPUS]
+};
+
+/// Asks `cpu` to save the state of the vCPU bound to its `interrupt_file` so that the vCPU can be
+/// moved to another CPU. `cpu` is sent an IPI so that it handles the request even if it's busy
+/// running a vCPU or idle.
+pub fn request_save(cpu: CpuId, interrupt_file: ImsicFileId) {
+    if cpu == PerCpu::this_cpu().cpu_id() {
+        defer_save(interrupt_file);
+    } else if let Some(pending) = PENDING_SAVES.get(cpu.raw()) {
+        pending.fetch_or(1 << interrupt_file.bits(), Ordering::AcqRel);
+        smp::send_ipi(cpu);
+    }
+}
+
+// Save requests for the guest interrupt files of the current CPU that couldn't be completed yet,
+// e.g. because they're waiting on a TLB fence, and are to be retried. Indexed by CPU ID.
+static DEFERRED_SAVES: [AtomicU64; MAX_CPUS] = {
+    const NONE_DEFERRED: AtomicU64 = AtomicU64::new(0);
+    [NONE_DEFERRED; MAX_CPUS]
+};
+
+/// Asks the current CPU to retry saving the state of the vCPU bound to its `interrupt_file` the
+/// next time it handles save requests. Unlike `request_save()`, this doesn't cause
+/// `has_save_requests()` to return true.
+pub fn defer_save(interrupt_file: ImsicFileId) {
+    DEFERRED_SAVES[PerCpu::this_cpu().cpu_id().raw()]
+        .fetch_or(1 << interrupt_file.bits(), Ordering::AcqRel);
+}
+
+/// Returns true if another CPU has sent the current CPU requests to save guest interrupt files.
+pub fn has_save_requests() -> bool {
+    PENDING_SAVES[PerCpu::this_cpu().cpu_id().raw()].load(Ordering::Acquire) != 0
+}
+
+/// Returns true if the current CPU has save requests that were deferred with `defer_save()`.
+pub fn has_deferred_saves() -> bool {
+    DEFERRED_SAVES[PerCpu::this_cpu().cpu_id().raw()].load(Ordering::Acquire) != 0
+}
+
+/// Takes the guest interrupt files on the current CPU whose state was requested to be saved with
+/// `request_save()` or `defer_save()`.
+pub fn take_save_requests() -> u64 {
+    let cpu = PerCpu::this_cpu().cpu_id().raw();
+    PENDING_SAVES[cpu].swap(0, Ordering::AcqRel) | DEFERRED_SAVES[cpu].swap(0, Ordering::AcqRel)
+}
+
+/// Tracks the TVM vCPUs bound to the guest interrupt files of a physical CPU, and which of those
+/// vCPUs have become runnable due to a supervisor guest external interrupt (SGEI) being taken while
+/// the vCPU wasn't running.
+///
+/// Bits in HGEIP are level-sensitive and remain set until the guest claims the interrupt from its
+/// interrupt file, so when an SGEI is taken in HS mode the corresponding bit in HGEIE is masked
+/// until wakeups are disarmed.
+pub struct GuestFileBindings {
+    vcpus: [Option<BoundVmCpu>; HGEIP_BITS],
+    // Guest interrupt files, in HGEIP format, whose vCPUs have pending wakeups.
+    runnable: u64,
+    // The value of HGEIE to restore when wakeups are disarmed.
+    saved_hgeie: Option<u64>,
+}
+
+impl GuestFileBindings {
+    /// Creates an empty set of guest interrupt file bindings.
+    pub fn new() -> Self {
+        Self {
+            vcpus: [None; HGEIP_BITS],
+            runnable: 0,
+            saved_hgeie: None,
+        }
+    }
+
+    /// Records that `vcpu` is bound to `interrupt_file`.
+    pub fn bind(&mut self, interrupt_file: ImsicFileId, vcpu: BoundVmCpu) {
+        let index = interrupt_file.bits() as usize;
+        if index != 0 && index < HGEIP_BITS {
+            self.vcpus[index] = Some(vcpu);
+            self.runnable &= !(1 << index);
+        }
+    }
+
+    /// Removes the binding of `vcpu` to `interrupt_file`, dropping any pending wakeup for it.
+    pub fn unbind(&mut self, interrupt_file: ImsicFileId, vcpu: BoundVmCpu) {
+        let index = interrupt_file.bits() as usize;
+        if index < HGEIP_BITS && self.vcpus[index] == Some(vcpu) {
+            self.vcpus[index] = None;
+            self.runnable &= !(1 << index);
+        }
+    }
+
+    /// Returns the vCPU bound to `interrupt_file`, if any.
+    pub fn vcpu_for(&self, interrupt_file: ImsicFileId) -> Option<BoundVmCpu> {
+        self.vcpus
+            .get(interrupt_file.bits() as usize)
+            .copied()
+            .flatten()
+    }
+
+    // Returns the guest interrupt files, in HGEIP format, that have a vCPU bound to them.
+    fn bound_files(&self) -> u64 {
+        self.vcpus
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.is_some())
+            .fold(0, |mask, (i, _)| mask | (1 << i))
+    }
+
+    /// Enables SGEIs for the guest interrupt files that have a vCPU bound to them so that this CPU
+    /// is woken if one of those vCPUs receives an external interrupt while the CPU is idle.
+    pub fn arm_wakeups(&mut self) {
+        if self.saved_hgeie.is_none() {
+            self.saved_hgeie = Some(CSR.hgeie.get());
+        }
+        CSR.hgeie.set(self.bound_files() & !self.runnable);
+        CSR.hie.read_and_set_field(hie::sgext);
+    }
+
+    /// Disables the SGEIs enabled by `arm_wakeups()` and restores HGEIE to its previous value.
+    pub fn disarm_wakeups(&mut self) {
+        CSR.hie.read_and_clear_field(hie::sgext);
+        if let Some(hgeie) = self.saved_hgeie.take() {
+            CSR.hgeie.set(hgeie);
+        }
+    }
+
+    /// Handles an SGEI taken in HS mode by marking the vCPUs bound to the pending guest interrupt
+    /// files as runnable. Returns true if the interrupt was handled.
+    pub fn handle_interrupt(&mut self) -> bool {
+        let pending = CSR.hgeip.get() & CSR.hgeie.get();
+        if pending == 0 {
+            return false;
+        }
+        // Mask the pending files to stop the interrupt from being retaken. Files without a bound
+        // vCPU are left masked since there's no one to deliver the interrupt to.
+        CSR.hgeie.read_and_clear_bits(pending);
+        self.runnable |= pending & self.bound_files();
+        true
+    }
+
+    /// Returns true if any vCPU has become runnable and hasn't yet been reported to the host.
+    pub fn has_runnable(&self) -> bool {
+        self.runnable != 0
+    }
+
+    /// Takes the guest interrupt files, in HGEIP format, whose vCPUs have become runnable since the
+    /// last call. These are reported to the host, which knows which vCPU it bound to each file.
+    pub fn take_runnable(&mut self) -> u64 {
+        core::mem::take(&mut self.runnable) & self.bound_files()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    fn vcpu(guest: u64, vcpu_id: u64) -> BoundVmCpu {
+        BoundVmCpu::new(PageOwnerId::new(guest).unwrap(), vcpu_id)
+    }
+
+    #[test_case]
+    fn GuestFileBindingsBindTest() -> TestResult {
+        let mut bindings = GuestFileBindings::new();
+        let file = ImsicFileId::guest(0);
+        bindings.bind(file, vcpu(2, 0));
+        test_result_true!(bindings.vcpu_for(file) == Some(vcpu(2, 0)), "bound")?;
+        // The supervisor file can't be bound to a vCPU.
+        bindings.bind(ImsicFileId::supervisor(), vcpu(2, 1));
+        test_result_true!(
+            bindings.vcpu_for(ImsicFileId::supervisor()).is_none(),
+            "supervisor file bound"
+        )?;
+        // Only the vCPU that's bound may be unbound.
+        bindings.unbind(file, vcpu(2, 1));
+        test_result_true!(
+            bindings.vcpu_for(file) == Some(vcpu(2, 0)),
+            "wrong vCPU unbound"
+        )?;
+        bindings.unbind(file, vcpu(2, 0));
+        test_result_true!(bindings.vcpu_for(file).is_none(), "unbound")?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn GuestFileBindingsRebindTest() -> TestResult {
+        let mut bindings = GuestFileBindings::new();
+        let file = ImsicFileId::guest(1);
+        let file_bit = 1 << file.bits();
+        bindings.bind(file, vcpu(2, 0));
+        // An SGEI was taken for the first vCPU.
+        bindings.runnable |= file_bit;
+        // Binding another vCPU to the file drops the wakeup meant for the first one, and unbinding
+        // the first one after the fact leaves the new binding in place.
+        bindings.bind(file, vcpu(3, 0));
+        bindings.unbind(file, vcpu(2, 0));
+        test_result_true!(bindings.vcpu_for(file) == Some(vcpu(3, 0)), "rebound")?;
+        test_result_true!(!bindings.has_runnable(), "stale wakeup kept")?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn GuestFileBindingsMissedWakeupTest() -> TestResult {
+        let mut bindings = GuestFileBindings::new();
+        let file = ImsicFileId::guest(0);
+        let file_bit = 1 << file.bits();
+        bindings.bind(file, vcpu(2, 0));
+        // An SGEI was taken for the vCPU while wakeups were armed, but the host hasn't been told
+        // yet. HGEIP can't be set from here, so mark the vCPU runnable as `handle_interrupt()` does.
+        bindings.arm_wakeups();
+        bindings.runnable |= file_bit;
+        bindings.disarm_wakeups();
+        // The wakeup must survive until it's reported, and the file stays masked when rearmed so
+        // that the still-pending interrupt doesn't retrigger the SGEI.
+        bindings.arm_wakeups();
+        let hgeie = CSR.hgeie.get();
+        bindings.disarm_wakeups();
+        test_result_true!(hgeie & file_bit == 0, "runnable file unmasked")?;
+        test_result_true!(bindings.has_runnable(), "wakeup lost")?;
+        test_result_true!(bindings.take_runnable() == file_bit, "wakeup not reported")?;
+        test_result_true!(!bindings.has_runnable(), "wakeup reported twice")?;
+        // A wakeup for a vCPU that was unbound before it was reported is dropped.
+        bindings.runnable |= file_bit;
+        bindings.unbind(file, vcpu(2, 0));
+        test_result_true!(bindings.take_runnable() == 0, "wakeup for unbound vCPU")?;
+        Ok(())
+    }
+}
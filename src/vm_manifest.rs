@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use attestation::{AttestationManager, Result as AttestationResult};
+use tvm_bundle::{
+    Error as BundleError, Manifest, Result as BundleResult, Signature, MANIFEST_SIZE,
+    SIGNATURE_SIZE,
+};
+
+/// Function ID of the signed TVM finalize call. Like the TVM debug calls, this is a Salus extension
+/// to the CoVE host interface which `CoveHostFunction` doesn't cover.
+///
+/// `(tvm_id, entry_sepc, entry_arg, manifest_addr, manifest_len)`: verifies the signed manifest at
+/// `manifest_addr` against the TVM's page and configuration measurements, then finalizes the TVM as
+/// `Finalize` does and measures the manifest's signer into the TVM identity register. The TVM is
+/// left initializing if the manifest doesn't verify.
+pub const TVM_FINALIZE_SIGNED_FID: u64 = 0x1010;
+
+/// The size of a signed manifest: the manifest followed by its signature block, as they appear in a
+/// TVM bundle.
+pub const SIGNED_MANIFEST_SIZE: usize = MANIFEST_SIZE + SIGNATURE_SIZE;
+
+/// A TVM's manifest of expected measurements and the signature over it, as passed by the host to
+/// `TVM_FINALIZE_SIGNED_FID`.
+pub struct SignedManifest {
+    manifest_bytes: [u8; MANIFEST_SIZE],
+    signature_bytes: [u8; SIGNATURE_SIZE],
+    manifest: Manifest,
+    signature: Signature,
+}
+
+impl SignedManifest {
+    /// Decodes the signed manifest at the start of `bytes`. The signature isn't checked; that's
+    /// done by U-mode.
+    pub fn decode(bytes: &[u8]) -> BundleResult<Self> {
+        let (manifest_bytes, signature_bytes) = bytes
+            .get(..SIGNED_MANIFEST_SIZE)
+            .ok_or(BundleError::Truncated)?
+            .split_at(MANIFEST_SIZE);
+        // Unwraps ok: the slices are split to the sizes of the arrays.
+        let manifest_bytes: [u8; MANIFEST_SIZE] = manifest_bytes.try_into().unwrap();
+        let signature_bytes: [u8; SIGNATURE_SIZE] = signature_bytes.try_into().unwrap();
+        Ok(Self {
+            manifest: Manifest::decode(&manifest_bytes)?,
+            signature: Signature::decode(&signature_bytes)?,
+            manifest_bytes,
+            signature_bytes,
+        })
+    }
+
+    /// Returns the encoded manifest.
+    pub fn manifest_bytes(&self) -> &[u8; MANIFEST_SIZE] {
+        &self.manifest_bytes
+    }
+
+    /// Returns the encoded signature block.
+    pub fn signature_bytes(&self) -> &[u8; SIGNATURE_SIZE] {
+        &self.signature_bytes
+    }
+
+    /// Measures the signer of the manifest, and the product ID and SVN it signed, into the TVM
+    /// identity register of `attestation_mgr`. Must only be called once the manifest is verified.
+    pub fn extend_identity(
+        &self,
+        attestation_mgr: &AttestationManager<sha2::Sha384>,
+    ) -> AttestationResult<()> {
+        attestation_mgr.extend_tvm_identity(
+            self.signature.public_key(),
+            &self.manifest.product_id,
+            self.manifest.svn,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+    use tvm_bundle::{HashAlgorithm, SignatureAlgorithm};
+
+    // Returns an encoded signed manifest. Decoding doesn't check the signature, so it's arbitrary.
+    fn signed_manifest_bytes() -> [u8; SIGNED_MANIFEST_SIZE] {
+        let manifest = Manifest {
+            hash_algorithm: HashAlgorithm::Sha384,
+            svn: 3,
+            product_id: [0x11; 16],
+            tvm_page_digest: [0x22; 48],
+            tvm_config_digest: [0x33; 48],
+        };
+        let signature = Signature::new(SignatureAlgorithm::Ed25519, &[0x44; 32], &[0x55; 64])
+            .expect("SignedManifestTest signature");
+        let mut bytes = [0u8; SIGNED_MANIFEST_SIZE];
+        bytes[..MANIFEST_SIZE].copy_from_slice(&manifest.encode());
+        bytes[MANIFEST_SIZE..].copy_from_slice(&signature.encode());
+        bytes
+    }
+
+    #[test_case]
+    fn SignedManifestDecodeTest() -> TestResult {
+        let bytes = signed_manifest_bytes();
+        let signed_manifest = SignedManifest::decode(&bytes).expect("SignedManifest::decode");
+        test_result_true!(
+            signed_manifest.manifest_bytes()[..] == bytes[..MANIFEST_SIZE],
+            "SignedManifest::manifest_bytes"
+        )?;
+        test_result_true!(
+            signed_manifest.signature_bytes()[..] == bytes[MANIFEST_SIZE..],
+            "SignedManifest::signature_bytes"
+        )?;
+        test_result_true!(
+            signed_manifest.manifest.svn == 3 && signed_manifest.manifest.product_id == [0x11; 16],
+            "SignedManifest::decode manifest"
+        )?;
+        test_result_true!(
+            signed_manifest.signature.public_key() == [0x44; 32],
+            "SignedManifest::decode signature"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn SignedManifestTruncatedTest() -> TestResult {
+        let bytes = signed_manifest_bytes();
+        test_result_true!(
+            matches!(
+                SignedManifest::decode(&bytes[..SIGNED_MANIFEST_SIZE - 1]),
+                Err(BundleError::Truncated)
+            ),
+            "SignedManifest::decode truncated"
+        )?;
+        test_result_true!(
+            matches!(
+                SignedManifest::decode(&bytes[..MANIFEST_SIZE]),
+                Err(BundleError::Truncated)
+            ),
+            "SignedManifest::decode without signature"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn SignedManifestBadSignatureTest() -> TestResult {
+        // A signature block whose signature length doesn't match its algorithm.
+        let mut bytes = signed_manifest_bytes();
+        bytes[MANIFEST_SIZE + 8] = 32;
+        test_result_true!(
+            matches!(
+                SignedManifest::decode(&bytes),
+                Err(BundleError::BadSignatureLength)
+            ),
+            "SignedManifest::decode bad signature length"
+        )?;
+        // An unknown hash algorithm in the manifest.
+        let mut bytes = signed_manifest_bytes();
+        bytes[0] = 0xff;
+        test_result_true!(
+            matches!(
+                SignedManifest::decode(&bytes),
+                Err(BundleError::BadHashAlgorithm(_))
+            ),
+            "SignedManifest::decode bad hash algorithm"
+        )?;
+        Ok(())
+    }
+}
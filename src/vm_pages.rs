@@ -5,7 +5,7 @@
 use arrayvec::ArrayVec;
 use attestation::AttestationManager;
 use core::marker::PhantomData;
-use drivers::{imsic::*, iommu::*, pci::PciBarPage, pci::PciDevice, pci::PcieRoot};
+use drivers::{imsic::*, iommu::*, pci::PciBarPage, pci::PciDevice, pci::PcieRoots};
 use page_tracking::{
     LockedPageList, PageList, PageTracker, PageTrackingError, TlbVersion, MAX_PAGE_OWNERS,
 };
@@ -55,6 +55,7 @@ pub enum Error {
     InvalidImsicLocation,
     MsiTableMapping(IommuError),
     AttachingDevice(IommuError),
+    DetachingDevice(IommuError),
     PageTracker(PageTrackingError),
     HypMap(HypMapError),
     InsufficientPtePages,
@@ -907,6 +908,16 @@ impl<'a, T: GuestStagePagingMode> ActiveVmPages<'a, T> {
             _ => Unmapped,
         }
     }
+
+    /// Returns true if `addr` is in one of this VM's confidential or shared memory regions, rather
+    /// than in an MMIO, IMSIC or PCI region or outside of any region.
+    pub fn is_guest_memory(&self, addr: GuestPhysAddr) -> bool {
+        use VmRegionType::*;
+        matches!(
+            self.vm_pages.inner.regions.read().find(addr),
+            Some(Confidential | ConfidentialRemovable | Shared | SharedRemovable)
+        )
+    }
 }
 
 /// A pool of page-table pages for a VM. Left over pages are released when the pool is dropped.
@@ -973,10 +984,10 @@ impl Drop for VmIommuContext {
 
         // Detach any devices we own from the IOMMU.
         let owner = self.msi_page_table.owner();
-        let pci = PcieRoot::get();
+        let pci = PcieRoots::get();
         for dev in pci.devices() {
             let mut dev = dev.lock();
-            if dev.owner() == Some(owner) {
+            if dev.owner() == Some(owner) && dev.is_iommu_attached() {
                 // Unwrap ok: `self.gscid` must be valid and match the ownership of the device
                 // to have been attached in the first place.
                 //
@@ -986,6 +997,9 @@ impl Drop for VmIommuContext {
             }
         }
 
+        // Hand the devices back, reset, so that they can be assigned to someone else.
+        pci.release_devices(owner);
+
         // Unwrap ok: `self.gscid` must be valid and freeable since we've detached all devices
         // using it.
         iommu.free_gscid(self.gscid).unwrap();
@@ -2206,6 +2220,15 @@ impl<'a, T: GuestStagePagingMode> InitializingVmPages<'a, T> {
             )
             .map_err(Error::AttachingDevice)
     }
+
+    /// Detaches the given PCI device from this VM, disabling DMA from it.
+    pub fn detach_pci_device(&self, dev: &mut PciDevice) -> Result<()> {
+        let iommu_context = self.inner.iommu_context.get().ok_or(Error::NoIommu)?;
+        Iommu::get()
+            .unwrap()
+            .detach_pci_device(dev, iommu_context.gscid)
+            .map_err(Error::DetachingDevice)
+    }
 }
 
 impl<'a, T: GuestStagePagingMode> From<InitializingVmPages<'a, T>> for AnyVmPages<'a, T> {
@@ -0,0 +1,29 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use drivers::pci::Address;
+
+/// Function ID of the TVM VF assignment call. Like the TVM debug calls, this is a Salus extension to
+/// the CoVE host interface which `CoveHostFunction` doesn't cover.
+///
+/// `(tvm_id, segment, bdf)`: assigns the enabled SR-IOV virtual function at `segment` and
+/// bus/device/function `bdf` (as in `bus << 8 | device << 3 | function`) of the host's view of PCI
+/// to the TVM. The VF is detached from the host's IOMMU context and reset before the TVM can use
+/// it; its physical function stays with the host. The VF is handed back to the host, reset again,
+/// when the TVM is destroyed.
+pub const TVM_ASSIGN_VF_FID: u64 = 0x1020;
+
+/// Returns the PCI address given by `segment` and `bdf` in a call to `TVM_ASSIGN_VF_FID`, or `None`
+/// if they're out of range.
+pub fn vf_address(segment: u64, bdf: u64) -> Option<Address> {
+    if bdf > u16::MAX as u64 {
+        return None;
+    }
+    Address::try_from_components(
+        u32::try_from(segment).ok()?,
+        (bdf >> 8) as u32,
+        ((bdf >> 3) & 0x1f) as u32,
+        (bdf & 0x7) as u32,
+    )
+}
@@ -3,13 +3,100 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use drivers::pmu;
-use riscv_regs::{RiscvCsrInterface, CSR, CSR_CYCLE};
+use riscv_pages::GuestPhysAddr;
+use riscv_regs::{sip, Readable, RiscvCsrInterface, CSR, CSR_CYCLE};
 use s_mode_utils::print::*;
 use sbi_rs::{
     Error as SbiError, PmuCounterConfigFlags, PmuCounterStartFlags, PmuCounterStopFlags,
     PmuEventType, Result as SbiResult,
 };
 
+/// The function ID of `sbi_pmu_snapshot_set_shmem()`. Not covered by `PmuFunction`, so we decode it
+/// ourselves.
+pub const SNAPSHOT_SET_SHMEM_FID: u64 = 7;
+/// Start flag requesting that counters be started with the values in the snapshot area.
+pub const START_FLAG_INIT_SNAPSHOT: u64 = 1 << 1;
+/// Stop flag requesting that the values of stopped counters be saved to the snapshot area.
+pub const STOP_FLAG_TAKE_SNAPSHOT: u64 = 1 << 1;
+
+// The number of counter values held in the snapshot area.
+const MAX_SNAPSHOT_COUNTERS: usize = 64;
+
+/// Number of firmware counters exposed to each vCPU. They're numbered after the hardware counters.
+pub const NUM_FIRMWARE_COUNTERS: usize = 8;
+
+// SBI firmware event codes for the events we count.
+const FW_EVENT_SET_TIMER: u64 = 5;
+const FW_EVENT_IPI_SENT: u64 = 6;
+const FW_EVENT_IPI_RECEIVED: u64 = 7;
+// The SBI's platform-specific firmware event code, with the event selected by the event data.
+const FW_EVENT_PLATFORM: u64 = 0xffff;
+
+// `counter_get_info()` type bit marking a firmware counter.
+const COUNTER_INFO_FIRMWARE: u64 = 1 << 63;
+
+/// Firmware events counted by Salus on behalf of a vCPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirmwareEvent {
+    /// The vCPU set its timer with `sbi_set_timer()`.
+    SetTimer,
+    /// The vCPU injected an external interrupt into one of its TVM's vCPUs.
+    IpiSent,
+    /// An external interrupt was injected into the vCPU by its host.
+    IpiReceived,
+    /// An ECALL from the vCPU was handled. Platform-specific event 0.
+    Ecall,
+    /// A guest page fault taken by the vCPU was forwarded to its host. Platform-specific event 1.
+    PageFaultForwarded,
+    /// An MMIO access by the vCPU was forwarded to its host for emulation. Platform-specific
+    /// event 2.
+    MmioEmulated,
+}
+
+impl FirmwareEvent {
+    // Returns the event for the given SBI firmware event code and event data.
+    fn from_code(event_code: u64, event_data: u64) -> Option<Self> {
+        use FirmwareEvent::*;
+        match (event_code, event_data) {
+            (FW_EVENT_SET_TIMER, _) => Some(SetTimer),
+            (FW_EVENT_IPI_SENT, _) => Some(IpiSent),
+            (FW_EVENT_IPI_RECEIVED, _) => Some(IpiReceived),
+            (FW_EVENT_PLATFORM, 0) => Some(Ecall),
+            (FW_EVENT_PLATFORM, 1) => Some(PageFaultForwarded),
+            (FW_EVENT_PLATFORM, 2) => Some(MmioEmulated),
+            _ => None,
+        }
+    }
+}
+
+// A counter of firmware events, maintained in software.
+#[derive(Default, Copy, Clone)]
+struct FirmwareCounter {
+    // The event being counted, or None if the counter isn't configured.
+    event: Option<FirmwareEvent>,
+    started: bool,
+    value: u64,
+}
+
+/// The layout of the SBI PMU snapshot area, minus the reserved space at the end of its 4kB page.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PmuSnapshot {
+    /// Bitmap of the counters that have overflowed since they were last started.
+    pub counter_overflow_bitmap: u64,
+    /// Counter values as of their last stop, indexed by counter index.
+    pub counter_values: [u64; MAX_SNAPSHOT_COUNTERS],
+}
+
+impl Default for PmuSnapshot {
+    fn default() -> Self {
+        Self {
+            counter_overflow_bitmap: 0,
+            counter_values: [0; MAX_SNAPSHOT_COUNTERS],
+        }
+    }
+}
+
 #[derive(Default, Copy, Clone)]
 struct CounterMaskIter {
     counter_index: u64,
@@ -72,12 +159,25 @@ impl Default for PmuCounterState {
 pub struct VmPmuState {
     // Stores information about the current state of PMU counters.
     counter_state: [PmuCounterState; drivers::pmu::MAX_HARDWARE_COUNTERS],
+    // Counter values as of their last stop and the counters that have overflowed. Copied to the
+    // snapshot area when the VM asks for it.
+    snapshot: PmuSnapshot,
+    // The guest physical address of the VM's snapshot area, if it registered one.
+    snapshot_addr: Option<GuestPhysAddr>,
+    // Set if a local counter overflow interrupt is pending for the vCPU.
+    lcofi_pending: bool,
+    // Firmware counters, which follow the hardware counters in the vCPU's counter numbering.
+    fw_counters: [FirmwareCounter; NUM_FIRMWARE_COUNTERS],
 }
 
 impl Default for VmPmuState {
     fn default() -> Self {
         Self {
             counter_state: [PmuCounterState::default(); drivers::pmu::MAX_HARDWARE_COUNTERS],
+            snapshot: PmuSnapshot::default(),
+            snapshot_addr: None,
+            lcofi_pending: false,
+            fw_counters: [FirmwareCounter::default(); NUM_FIRMWARE_COUNTERS],
         }
     }
 }
@@ -155,6 +255,7 @@ impl VmPmuState {
         for i in bitmask_iter {
             if let Configured(c) = self.counter_state[i] {
                 self.counter_state[i] = Started(c);
+                self.snapshot.counter_overflow_bitmap &= !(1 << i);
                 Self::set_hcounteren_bit(i as u64);
             }
         }
@@ -177,6 +278,11 @@ impl VmPmuState {
                 // Deliberately more permissive since the implementation permits
                 // operations even on stopped counters (example: stop_flag_reset).
                 Configured(c) | Started(c) => {
+                    self.snapshot.counter_values[i] = if is_started_counter {
+                        VmPmuState::read_counter_csr(i as u64)
+                    } else {
+                        c.value
+                    };
                     if stop_flags.is_reset_flag() {
                         Self::clear_hcounteren_bit(i as u64);
                         *state = NotConfigured;
@@ -251,6 +357,231 @@ impl VmPmuState {
         CSR.hpmcounter[(csr - CSR_CYCLE as u64) as usize].get_value()
     }
 
+    /// Sets the guest physical address of the VM's snapshot area, or disables snapshots if `None`.
+    pub fn set_snapshot_addr(&mut self, addr: Option<GuestPhysAddr>) {
+        self.snapshot_addr = addr;
+    }
+
+    /// Returns the guest physical address of the VM's snapshot area, if it registered one.
+    pub fn snapshot_addr(&self) -> Option<GuestPhysAddr> {
+        self.snapshot_addr
+    }
+
+    /// Returns the snapshot of counter values and overflows to be copied to the snapshot area.
+    pub fn snapshot(&self) -> &PmuSnapshot {
+        &self.snapshot
+    }
+
+    /// Handles a local counter overflow interrupt taken while the vCPU was running. Overflows are
+    /// attributed to the vCPU's started counters, in which case the interrupt is made pending for
+    /// the vCPU and true is returned.
+    pub fn handle_overflow(&mut self) -> bool {
+        use PmuCounterState::*;
+        CSR.sip.read_and_clear_field(sip::lcofi);
+        let Ok(pmu_info) = pmu::PmuInfo::get() else {
+            return false;
+        };
+        let scountovf = CSR.scountovf.get();
+        let num_counters = pmu_info.get_num_counters() as usize;
+        let mut overflowed = 0;
+        for (i, state) in self.counter_state.iter().take(num_counters).enumerate() {
+            if matches!(state, Started(_)) &&
+                let Ok(csr) = pmu_info.counter_index_to_csr(i as u64) &&
+                scountovf & (1 << (csr - CSR_CYCLE as u64)) != 0
+            {
+                overflowed |= 1 << i;
+            }
+        }
+        self.snapshot.counter_overflow_bitmap |= overflowed;
+        self.lcofi_pending |= overflowed != 0;
+        overflowed != 0
+    }
+
+    /// Returns true if a local counter overflow interrupt is pending for the vCPU.
+    pub fn lcofi_pending(&self) -> bool {
+        self.lcofi_pending
+    }
+
+    /// Updates the pending state of the vCPU's local counter overflow interrupt, e.g. after the
+    /// guest has cleared it.
+    pub fn set_lcofi_pending(&mut self, pending: bool) {
+        self.lcofi_pending = pending;
+    }
+
+    /// Returns true if any of the vCPU's counters are started, i.e. could overflow.
+    pub fn has_started_counters(&self) -> bool {
+        self.counter_state
+            .iter()
+            .any(|s| matches!(s, PmuCounterState::Started(_)))
+    }
+
+    // Splits the counters selected by `counter_index` and `counter_mask` into a mask of hardware
+    // counters, relative to `counter_index`, and a mask of firmware counters, relative to the first
+    // firmware counter.
+    fn split_counter_mask(&self, counter_index: u64, counter_mask: u64) -> SbiResult<(u64, u64)> {
+        let num_hw_counters = pmu::PmuInfo::get()?.get_num_counters();
+        let mut hw_mask = 0;
+        let mut fw_mask = 0;
+        for i in CounterMaskIter::new(counter_index, counter_mask) {
+            let i = i as u64;
+            if i < num_hw_counters {
+                hw_mask |= 1 << (i - counter_index);
+            } else if i < num_hw_counters + NUM_FIRMWARE_COUNTERS as u64 {
+                fw_mask |= 1 << (i - num_hw_counters);
+            } else {
+                return Err(SbiError::InvalidParam);
+            }
+        }
+        Ok((hw_mask, fw_mask))
+    }
+
+    // Returns the firmware counter number for counter_index.
+    fn firmware_counter(counter_index: u64) -> SbiResult<usize> {
+        let num_hw_counters = pmu::PmuInfo::get()?.get_num_counters();
+        counter_index
+            .checked_sub(num_hw_counters)
+            .filter(|&i| i < NUM_FIRMWARE_COUNTERS as u64)
+            .map(|i| i as usize)
+            .ok_or(SbiError::InvalidParam)
+    }
+
+    /// Returns the total number of counters, hardware and firmware, available to the vCPU.
+    pub fn get_num_counters(&self) -> SbiResult<u64> {
+        Ok(pmu::PmuInfo::get()?.get_num_counters() + NUM_FIRMWARE_COUNTERS as u64)
+    }
+
+    /// Returns the raw `counter_get_info()` value for counter_index.
+    pub fn get_counter_info(&self, counter_index: u64) -> SbiResult<u64> {
+        let pmu_info = pmu::PmuInfo::get()?;
+        if counter_index < pmu_info.get_num_counters() {
+            Ok(pmu_info.get_counter_info(counter_index)?.raw())
+        } else {
+            Self::firmware_counter(counter_index).map(|_| COUNTER_INFO_FIRMWARE)
+        }
+    }
+
+    /// Calls the SBI configure_matching_counters() for firmware events, picking a firmware counter
+    /// from the given range to count the event specified by `event_code` and `event_data`.
+    pub fn configure_firmware_counter(
+        &mut self,
+        counter_index: u64,
+        counter_mask: u64,
+        config_flags: PmuCounterConfigFlags,
+        event_code: u64,
+        event_data: u64,
+    ) -> SbiResult<u64> {
+        let event =
+            FirmwareEvent::from_code(event_code, event_data).ok_or(SbiError::NotSupported)?;
+        let (_, fw_mask) = self.split_counter_mask(counter_index, counter_mask)?;
+        let fw_index = if config_flags.is_skip_match() {
+            // The counter at counter_index must already be configured.
+            let i = Self::firmware_counter(counter_index)?;
+            if self.fw_counters[i].event.is_none() {
+                return Err(SbiError::InvalidParam);
+            }
+            i
+        } else {
+            (0..NUM_FIRMWARE_COUNTERS)
+                .find(|&i| fw_mask & (1 << i) != 0 && self.fw_counters[i].event.is_none())
+                .ok_or(SbiError::NotSupported)?
+        };
+        let num_hw_counters = pmu::PmuInfo::get()?.get_num_counters();
+        let counter = &mut self.fw_counters[fw_index];
+        counter.event = Some(event);
+        if config_flags.is_clear_value() {
+            counter.value = 0;
+        }
+        if config_flags.is_auto_start() {
+            counter.started = true;
+        }
+        Ok(num_hw_counters + fw_index as u64)
+    }
+
+    // Starts the firmware counters in fw_mask, optionally setting their initial values. None of
+    // them are started if any of them isn't configured.
+    fn start_firmware_counters(
+        &mut self,
+        num_hw_counters: usize,
+        fw_mask: u64,
+        initial_value: impl Fn(usize) -> Option<u64>,
+    ) -> SbiResult<()> {
+        let selected = |i: usize| fw_mask & (1 << i) != 0;
+        if (0..NUM_FIRMWARE_COUNTERS).any(|i| selected(i) && self.fw_counters[i].event.is_none()) {
+            return Err(SbiError::InvalidParam);
+        }
+        let mut ret = Ok(());
+        for (i, counter) in self.fw_counters.iter_mut().enumerate() {
+            if !selected(i) {
+                continue;
+            }
+            if counter.started {
+                ret = Err(SbiError::AlreadyStarted);
+                continue;
+            }
+            if let Some(value) = initial_value(num_hw_counters + i) {
+                counter.value = value;
+            }
+            counter.started = true;
+            self.snapshot.counter_overflow_bitmap &= !(1 << (num_hw_counters + i));
+        }
+        ret
+    }
+
+    // Stops the firmware counters in fw_mask, recording their values in the snapshot. None of them
+    // are stopped if any of them isn't configured.
+    fn stop_firmware_counters(
+        &mut self,
+        num_hw_counters: usize,
+        fw_mask: u64,
+        stop_flags: PmuCounterStopFlags,
+    ) -> SbiResult<()> {
+        let selected = |i: usize| fw_mask & (1 << i) != 0;
+        if (0..NUM_FIRMWARE_COUNTERS).any(|i| selected(i) && self.fw_counters[i].event.is_none()) {
+            return Err(SbiError::InvalidParam);
+        }
+        let mut ret = Ok(());
+        for (i, counter) in self.fw_counters.iter_mut().enumerate() {
+            if !selected(i) {
+                continue;
+            }
+            if !counter.started && !stop_flags.is_reset_flag() {
+                ret = Err(SbiError::AlreadyStopped);
+                continue;
+            }
+            self.snapshot.counter_values[num_hw_counters + i] = counter.value;
+            counter.started = false;
+            if stop_flags.is_reset_flag() {
+                *counter = FirmwareCounter::default();
+            }
+        }
+        ret
+    }
+
+    /// Returns the value of the firmware counter at counter_index for `counter_fw_read()`.
+    pub fn read_firmware_counter(&self, counter_index: u64) -> SbiResult<u64> {
+        let counter = &self.fw_counters[Self::firmware_counter(counter_index)?];
+        if counter.event.is_none() {
+            return Err(SbiError::InvalidParam);
+        }
+        Ok(counter.value)
+    }
+
+    /// Counts an occurrence of `event` in any of the vCPU's started firmware counters.
+    pub fn count_firmware_event(&mut self, event: FirmwareEvent) {
+        self.count_firmware_events(event, 1);
+    }
+
+    /// Counts `count` occurrences of `event` in any of the vCPU's started firmware counters.
+    pub fn count_firmware_events(&mut self, event: FirmwareEvent, count: u64) {
+        for counter in self
+            .fw_counters
+            .iter_mut()
+            .filter(|c| c.started && c.event == Some(event))
+        {
+            counter.value = counter.value.wrapping_add(count);
+        }
+    }
+
     /// Returns the cached value for a PMU CSR. We return 0 for counters that couldn't be
     /// configured or started on the resume path.
     pub fn get_cached_csr_value(&self, csr: u64) -> SbiResult<u64> {
@@ -303,7 +634,7 @@ impl VmPmuState {
             }
 
             let result = self.reset_all_counters(counter_mask);
-            if result.is_err() {
+            if result.is_err() && log_enabled(LogLevel::Warn) {
                 println!(
                     "Warning: PMU failed to reset counters with mask {counter_mask:x}, {result:?}"
                 );
@@ -354,9 +685,11 @@ impl VmPmuState {
                         });
                         if result.is_err() {
                             self.counter_state[i] = Poisoned(*c);
-                            println!(
-                                "Warning: Failed to restore counter {counter_index}, {result:?}"
-                            );
+                            if log_enabled(LogLevel::Warn) {
+                                println!(
+                                    "Warning: Failed to restore counter {counter_index}, {result:?}"
+                                );
+                            }
                         }
                     }
                     _ => {}
@@ -400,8 +733,10 @@ impl VmPmuState {
         event_type: PmuEventType,
         event_data: u64,
     ) -> SbiResult<u64> {
+        // Firmware counters can't count hardware events, so leave them out of the search.
+        let (hw_mask, _) = self.split_counter_mask(counter_index, counter_mask)?;
         let counter_mask =
-            self.get_configurable_counter_range(counter_index, counter_mask, config_flags)?;
+            self.get_configurable_counter_range(counter_index, hw_mask, config_flags)?;
         let platform_counter_index = self.configure_matching_counters_internal(
             counter_index,
             counter_mask,
@@ -436,7 +771,24 @@ impl VmPmuState {
         start_flags: PmuCounterStartFlags,
         initial_value: u64,
     ) -> SbiResult<()> {
-        let counter_mask = self.get_startable_counter_range(counter_index, counter_mask)?;
+        let (hw_mask, fw_mask) = self.split_counter_mask(counter_index, counter_mask)?;
+        // Check the hardware counters before starting any firmware counters so that a bad range
+        // doesn't leave the request half done.
+        let hw_counter_mask = match hw_mask {
+            0 => None,
+            _ => Some(self.get_startable_counter_range(counter_index, hw_mask)?),
+        };
+        let num_hw_counters = pmu::PmuInfo::get()?.get_num_counters() as usize;
+        let set_value = start_flags.is_init_value();
+        let fw_result = self.start_firmware_counters(num_hw_counters, fw_mask, |_| {
+            set_value.then_some(initial_value)
+        });
+        if matches!(fw_result, Err(SbiError::InvalidParam)) {
+            return fw_result;
+        }
+        let Some(counter_mask) = hw_counter_mask else {
+            return fw_result;
+        };
         let result =
             self.start_counters_internal(counter_index, counter_mask, start_flags, initial_value);
         // Special case "already started" to handle counters that are autostarted following configuration.
@@ -444,7 +796,50 @@ impl VmPmuState {
         if result.is_ok() || matches!(result, Err(SbiError::AlreadyStarted)) {
             self.update_started_counters(counter_index, counter_mask);
         }
-        result
+        result.and(fw_result)
+    }
+
+    /// Starts the counters in the given range using their initial values from `snapshot`, which
+    /// was read from the VM's snapshot area.
+    pub fn start_counters_from_snapshot(
+        &mut self,
+        counter_index: u64,
+        counter_mask: u64,
+        snapshot: &PmuSnapshot,
+    ) -> SbiResult<()> {
+        let (hw_mask, fw_mask) = self.split_counter_mask(counter_index, counter_mask)?;
+        let hw_counter_mask = match hw_mask {
+            0 => None,
+            _ => Some(self.get_startable_counter_range(counter_index, hw_mask)?),
+        };
+        let num_hw_counters = pmu::PmuInfo::get()?.get_num_counters() as usize;
+        let fw_result = self.start_firmware_counters(num_hw_counters, fw_mask, |i| {
+            Some(snapshot.counter_values[i])
+        });
+        if matches!(fw_result, Err(SbiError::InvalidParam)) {
+            return fw_result;
+        }
+        let Some(counter_mask) = hw_counter_mask else {
+            return fw_result;
+        };
+        let start_flags = PmuCounterStartFlags::default().set_init_value();
+        let mut ret = fw_result;
+        // Each counter has its own initial value, so they have to be started one at a time.
+        for i in CounterMaskIter::new(counter_index, counter_mask) {
+            let result = self.start_counters_internal(
+                i as u64,
+                0x1,
+                start_flags,
+                snapshot.counter_values[i],
+            );
+            if result.is_ok() || matches!(result, Err(SbiError::AlreadyStarted)) {
+                self.update_started_counters(i as u64, 0x1);
+            }
+            if result.is_err() {
+                ret = result;
+            }
+        }
+        ret
     }
 
     fn stop_counters_internal(
@@ -463,7 +858,20 @@ impl VmPmuState {
         counter_mask: u64,
         stop_flags: PmuCounterStopFlags,
     ) -> SbiResult<()> {
-        let counter_mask = self.get_stoppable_counter_range(counter_index, counter_mask)?;
+        let (hw_mask, fw_mask) = self.split_counter_mask(counter_index, counter_mask)?;
+        // As for starting, check the hardware counters before stopping any firmware counters.
+        let hw_counter_mask = match hw_mask {
+            0 => None,
+            _ => Some(self.get_stoppable_counter_range(counter_index, hw_mask)?),
+        };
+        let num_hw_counters = pmu::PmuInfo::get()?.get_num_counters() as usize;
+        let fw_result = self.stop_firmware_counters(num_hw_counters, fw_mask, stop_flags);
+        if matches!(fw_result, Err(SbiError::InvalidParam)) {
+            return fw_result;
+        }
+        let Some(counter_mask) = hw_counter_mask else {
+            return fw_result;
+        };
         let result = self.stop_counters_internal(counter_index, counter_mask, stop_flags);
         // Special case "already stopped" to handle counters that can be reset following a stop
         if result.is_ok()
@@ -471,15 +879,153 @@ impl VmPmuState {
         {
             self.update_stopped_counters(counter_index, counter_mask, stop_flags);
         }
-        result
+        result.and(fw_result)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use memoffset::offset_of;
+    use riscv_pages::{PageOwnerId, RawAddr};
+    use s_mode_utils::print::*;
     use test_system::*;
 
+    #[test_case]
+    fn PmuSnapshotLayoutTest() -> TestResult {
+        // The overflow bitmap is followed by the counter values, as in the SBI snapshot area.
+        test_result_true!(
+            core::mem::size_of::<PmuSnapshot>() == 8 + 8 * MAX_SNAPSHOT_COUNTERS,
+            "snapshot size"
+        )?;
+        test_result_true!(
+            offset_of!(PmuSnapshot, counter_values) == 8,
+            "snapshot counter values offset"
+        )?;
+        let mut pmu = VmPmuState::default();
+        test_result_true!(pmu.snapshot_addr().is_none(), "no snapshot area by default")?;
+        let addr = RawAddr::guest(0x8000_1000, PageOwnerId::host());
+        pmu.set_snapshot_addr(Some(addr));
+        test_result_true!(pmu.snapshot_addr() == Some(addr), "snapshot area set")?;
+        pmu.set_snapshot_addr(None);
+        test_result_true!(pmu.snapshot_addr().is_none(), "snapshot area cleared")?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn PmuUnattributedOverflowTest() -> TestResult {
+        // An overflow when the vCPU has no started counters isn't its own, so there's nothing to
+        // inject.
+        let mut pmu = VmPmuState::default();
+        test_result_true!(!pmu.handle_overflow(), "overflow not attributed")?;
+        test_result_true!(!pmu.lcofi_pending(), "no interrupt pending")?;
+        test_result_true!(
+            pmu.snapshot().counter_overflow_bitmap == 0,
+            "no overflow recorded"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn FirmwareEventFromCodeTest() -> TestResult {
+        use FirmwareEvent::*;
+        test_result_true!(
+            FirmwareEvent::from_code(FW_EVENT_SET_TIMER, 0) == Some(SetTimer),
+            "set timer"
+        )?;
+        test_result_true!(
+            FirmwareEvent::from_code(FW_EVENT_IPI_SENT, 0) == Some(IpiSent),
+            "IPI sent"
+        )?;
+        test_result_true!(
+            FirmwareEvent::from_code(FW_EVENT_IPI_RECEIVED, 0) == Some(IpiReceived),
+            "IPI received"
+        )?;
+        test_result_true!(
+            FirmwareEvent::from_code(FW_EVENT_PLATFORM, 0) == Some(Ecall),
+            "ECALL"
+        )?;
+        // Misaligned loads are handled below Salus, so they can't be counted.
+        test_result_true!(FirmwareEvent::from_code(0, 0).is_none(), "misaligned load")?;
+        test_result_true!(
+            FirmwareEvent::from_code(FW_EVENT_PLATFORM, 3).is_none(),
+            "unknown platform event"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn FirmwareCounterTest() -> TestResult {
+        const NUM_HW_COUNTERS: usize = 4;
+        let mut pmu = VmPmuState::default();
+        pmu.fw_counters[0].event = Some(FirmwareEvent::Ecall);
+        pmu.fw_counters[1].event = Some(FirmwareEvent::SetTimer);
+
+        // Nothing is started if one of the counters isn't configured.
+        test_result_true!(
+            matches!(
+                pmu.start_firmware_counters(NUM_HW_COUNTERS, 0b101, |_| None),
+                Err(SbiError::InvalidParam)
+            ),
+            "start unconfigured"
+        )?;
+        test_result_true!(!pmu.fw_counters[0].started, "no partial start")?;
+
+        pmu.start_firmware_counters(NUM_HW_COUNTERS, 0b11, |i| Some(i as u64))
+            .map_err(|_| TestFailure::FailedAt("start"))?;
+        test_result_true!(
+            pmu.fw_counters[0].value == NUM_HW_COUNTERS as u64,
+            "initial value"
+        )?;
+        test_result_true!(
+            matches!(
+                pmu.start_firmware_counters(NUM_HW_COUNTERS, 0b1, |_| None),
+                Err(SbiError::AlreadyStarted)
+            ),
+            "already started"
+        )?;
+
+        // Only started counters for the event count it.
+        pmu.count_firmware_event(FirmwareEvent::Ecall);
+        test_result_true!(
+            pmu.fw_counters[0].value == NUM_HW_COUNTERS as u64 + 1,
+            "ecall counted"
+        )?;
+        test_result_true!(
+            pmu.fw_counters[1].value == NUM_HW_COUNTERS as u64 + 1,
+            "set timer not counted"
+        )?;
+
+        let stop_flags = PmuCounterStopFlags::default();
+        test_result_true!(
+            matches!(
+                pmu.stop_firmware_counters(NUM_HW_COUNTERS, 0b101, stop_flags),
+                Err(SbiError::InvalidParam)
+            ),
+            "stop unconfigured"
+        )?;
+        test_result_true!(pmu.fw_counters[0].started, "no partial stop")?;
+        pmu.stop_firmware_counters(NUM_HW_COUNTERS, 0b1, stop_flags)
+            .map_err(|_| TestFailure::FailedAt("stop"))?;
+        test_result_true!(
+            pmu.snapshot.counter_values[NUM_HW_COUNTERS] == NUM_HW_COUNTERS as u64 + 1,
+            "stopped value saved"
+        )?;
+        pmu.count_firmware_event(FirmwareEvent::Ecall);
+        test_result_true!(
+            pmu.fw_counters[0].value == NUM_HW_COUNTERS as u64 + 1,
+            "stopped counter not counted"
+        )?;
+        test_result_true!(
+            matches!(
+                pmu.stop_firmware_counters(NUM_HW_COUNTERS, 0b1, stop_flags),
+                Err(SbiError::AlreadyStopped)
+            ),
+            "already stopped"
+        )?;
+        Ok(())
+    }
+
     #[test_case]
     fn CounterMaskIterTest() -> TestResult {
         let mut cmi = CounterMaskIter::new(0xff, 0x100).into_iter();
@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use arrayvec::ArrayVec;
+use core::arch::asm;
+use riscv_pages::PageOwnerId;
+use riscv_regs::CSR;
+
+/// The SBI TIME extension ID ("TIME"). Not covered by `SbiMessage`, so we decode it ourselves.
+pub const EXT_TIME: u64 = 0x5449_4d45;
+/// The function ID of `sbi_set_timer()` within the TIME extension.
+pub const SET_TIMER_FID: u64 = 0;
+
+// Each CPU tracks the timer of the host VM's vCPU bound to it and of the TVM vCPU it's currently
+// running, if any.
+const MAX_VM_TIMERS: usize = 2;
+
+/// Errors from tracking the timers of the vCPUs running on a CPU.
+#[derive(Debug)]
+pub enum Error {
+    /// The CPU is already tracking as many vCPU timers as it can.
+    TooManyTimers,
+}
+
+/// Holds the result of timer queue operations.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Returns the current value of the `time` CSR.
+pub fn read_time() -> u64 {
+    CSR.hpmcounter[1].get_value()
+}
+
+/// Converts `vstimecmp`, a deadline in the timebase of a guest with the given `htimedelta`, to the
+/// hypervisor's timebase. Deadlines which can't be reached in our timebase saturate to `u64::MAX`.
+pub fn host_deadline(vstimecmp: u64, htimedelta: u64) -> u64 {
+    let deadline = vstimecmp as i128 - htimedelta as i64 as i128;
+    deadline.clamp(0, u64::MAX as i128) as u64
+}
+
+// Programs this CPU's supervisor timer with `sbi_set_timer()`, which also clears any pending
+// supervisor timer interrupt.
+fn sbi_set_timer(deadline: u64) {
+    // Safety: sbi_set_timer() doesn't touch memory and only clobbers A0 and A1.
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") deadline => _,
+            lateout("a1") _,
+            in("a6") SET_TIMER_FID,
+            in("a7") EXT_TIME,
+            options(nostack)
+        )
+    };
+}
+
+// The timer deadline of a running vCPU.
+struct VmTimer {
+    guest_id: PageOwnerId,
+    vcpu_id: u64,
+    deadline: u64,
+}
+
+/// Tracks the timer deadlines of the vCPUs running on a CPU when timers are virtualized in
+/// software, i.e. when the CPU lacks Sstc. The deadlines, in the hypervisor's timebase, are
+/// multiplexed onto the CPU's supervisor timer so that we trap whenever the earliest of them
+/// expires; it's then up to the vCPU to inject `VirtualSupervisorTimer` into the guest.
+pub struct VmTimerQueue {
+    timers: ArrayVec<VmTimer, MAX_VM_TIMERS>,
+    // The deadline the supervisor timer is currently programmed with.
+    programmed: u64,
+}
+
+impl VmTimerQueue {
+    /// Creates an empty timer queue.
+    pub fn new() -> Self {
+        Self {
+            timers: ArrayVec::new(),
+            programmed: u64::MAX,
+        }
+    }
+
+    /// Sets the timer deadline of `vcpu_id` in the VM with `guest_id`, replacing any previous
+    /// deadline. Fails if the vCPU's timer isn't already tracked and there's no room for it.
+    pub fn arm(&mut self, guest_id: PageOwnerId, vcpu_id: u64, deadline: u64) -> Result<()> {
+        if let Some(timer) = self
+            .timers
+            .iter_mut()
+            .find(|t| t.guest_id == guest_id && t.vcpu_id == vcpu_id)
+        {
+            timer.deadline = deadline;
+        } else {
+            self.timers
+                .try_push(VmTimer {
+                    guest_id,
+                    vcpu_id,
+                    deadline,
+                })
+                .map_err(|_| Error::TooManyTimers)?;
+        }
+        self.reprogram();
+        Ok(())
+    }
+
+    /// Stops tracking the timer deadline of `vcpu_id` in the VM with `guest_id`.
+    pub fn disarm(&mut self, guest_id: PageOwnerId, vcpu_id: u64) {
+        self.timers
+            .retain(|t| t.guest_id != guest_id || t.vcpu_id != vcpu_id);
+        self.reprogram();
+    }
+
+    /// Returns true if there are any unexpired deadlines the supervisor timer is armed for.
+    pub fn is_armed(&self) -> bool {
+        self.programmed != u64::MAX
+    }
+
+    /// Handles a supervisor timer interrupt taken in HS mode by re-arming the supervisor timer for
+    /// the earliest deadline that has yet to expire.
+    pub fn handle_interrupt(&mut self) {
+        // Force a reprogram since the firmware may have left the interrupt pending.
+        self.programmed = 0;
+        self.reprogram();
+    }
+
+    // Programs the supervisor timer for the earliest unexpired deadline. Deadlines that have already
+    // passed are left for the vCPU to pick up when it's next run.
+    fn reprogram(&mut self) {
+        let now = read_time();
+        let next = self
+            .timers
+            .iter()
+            .map(|t| t.deadline)
+            .filter(|&d| d > now)
+            .min()
+            .unwrap_or(u64::MAX);
+        if next != self.programmed {
+            sbi_set_timer(next);
+            self.programmed = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use s_mode_utils::print::*;
+    use test_system::*;
+
+    #[test_case]
+    fn HostDeadlineTest() -> TestResult {
+        test_result_true!(
+            host_deadline(0x1000, 0) == 0x1000,
+            "host_deadline without offset"
+        )?;
+        // A guest whose time runs ahead of ours.
+        test_result_true!(
+            host_deadline(0x1000, 0x100) == 0xf00,
+            "host_deadline positive htimedelta"
+        )?;
+        // A guest whose time runs behind ours; HTIMEDELTA is a two's complement offset.
+        test_result_true!(
+            host_deadline(0x1000, (-0x100i64) as u64) == 0x1100,
+            "host_deadline negative htimedelta"
+        )?;
+        Ok(())
+    }
+
+    #[test_case]
+    fn HostDeadlineSaturatesTest() -> TestResult {
+        // A timer disarmed by a guest whose time runs behind ours stays disarmed.
+        test_result_true!(
+            host_deadline(u64::MAX, (-1i64) as u64) == u64::MAX,
+            "host_deadline saturates at u64::MAX"
+        )?;
+        // Deadlines before the start of our timebase have already passed.
+        test_result_true!(
+            host_deadline(0x100, 0x1000) == 0,
+            "host_deadline saturates at 0"
+        )?;
+        test_result_true!(
+            host_deadline(u64::MAX, 1) == u64::MAX - 1,
+            "host_deadline near u64::MAX"
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Builds the symbol table section embedded in the salus image.
+//!
+//! Usage: symbol_table_builder <OUTPUT> <IMAGE>=<NM_OUTPUT>...
+//!
+//! Each NM_OUTPUT is the output of `nm --defined-only -S -C` for the named image (at most 8
+//! characters, e.g. "salus" or "umode"). Only function symbols are kept. The output is padded to
+//! the size of the section reserved in the salus image.
+
+use std::env;
+use std::fs;
+use std::process;
+use symbol_table::*;
+
+struct Function {
+    address: u64,
+    size: u64,
+    global: bool,
+    name: String,
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("Usage: symbol_table_builder <OUTPUT> <IMAGE>=<NM_OUTPUT>...");
+    process::exit(1);
+}
+
+// Strips the hash the legacy Rust mangling scheme appends to every path, e.g.
+// `::h0123456789abcdef`.
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+// Parses a line of `nm -S` output: an address, an optional size, the symbol type and the name.
+// Names may contain spaces once demangled.
+fn parse_line(line: &str) -> Option<Function> {
+    let mut fields = line.splitn(2, ' ');
+    let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let rest = fields.next()?;
+    let (size, rest) = match rest.split_once(' ') {
+        Some((size, rest)) if size.len() > 1 => (u64::from_str_radix(size, 16).ok()?, rest),
+        _ => (0, rest),
+    };
+    let (kind, name) = rest.split_once(' ')?;
+    // Skip assembler-local labels and mapping symbols; they aren't functions.
+    if !matches!(kind, "T" | "t" | "W" | "w") || name.is_empty() || name.starts_with(['.', '$']) {
+        return None;
+    }
+    Some(Function {
+        address,
+        size,
+        global: kind == "T" || kind == "W",
+        name: strip_hash(name).to_string(),
+    })
+}
+
+fn build_table(image: &str, nm_output: &str) -> Vec<u8> {
+    let mut functions: Vec<Function> = nm_output.lines().filter_map(parse_line).collect();
+    functions.sort_by_key(|f| (f.address, !f.global));
+    // Keep one name for aliases of the same function, preferring global ones.
+    functions.dedup_by_key(|f| f.address);
+    let base = functions.first().map(|f| f.address).unwrap_or(0);
+
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+    for f in functions {
+        let offset = u32::try_from(f.address - base)
+            .unwrap_or_else(|_| usage(&format!("{}: symbol '{}' is too far", image, f.name)));
+        entries.push(SymbolEntry {
+            offset,
+            size: u32::try_from(f.size).unwrap_or(0),
+            name_offset: names.len() as u32,
+            name_len: f.name.len() as u32,
+        });
+        names.extend_from_slice(f.name.as_bytes());
+    }
+
+    let mut header = Header {
+        base,
+        num_symbols: entries.len() as u32,
+        names_size: u32::try_from(names.len())
+            .unwrap_or_else(|_| usage(&format!("{}: too many symbols", image))),
+        ..Default::default()
+    };
+    header.image[..image.len()].copy_from_slice(image.as_bytes());
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&header.encode());
+    for entry in entries {
+        bytes.extend_from_slice(&entry.encode());
+    }
+    bytes.extend_from_slice(&names);
+    bytes.resize(header.table_size(), 0);
+    bytes
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let output_path = args.next().unwrap_or_else(|| usage("No output path"));
+
+    let mut section = Vec::new();
+    for arg in args {
+        let (image, nm_path) = arg
+            .split_once('=')
+            .unwrap_or_else(|| usage(&format!("Invalid argument '{}'", arg)));
+        if image.is_empty() || image.len() > IMAGE_NAME_SIZE {
+            usage(&format!("Invalid image name '{}'", image));
+        }
+        let nm_output = fs::read_to_string(nm_path).expect("error reading nm output");
+        section.extend(build_table(image, &nm_output));
+    }
+    if section.len() > SECTION_SIZE {
+        usage(&format!(
+            "Symbol tables take {} bytes, more than the {} reserved",
+            section.len(),
+            SECTION_SIZE
+        ));
+    }
+    section.resize(SECTION_SIZE, 0);
+
+    fs::write(&output_path, &section).expect("error writing symbol table");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_hash_removes_legacy_hash() {
+        assert_eq!(
+            strip_hash("core::fmt::write::h0123456789abcdef"),
+            "core::fmt::write"
+        );
+        assert_eq!(
+            strip_hash("<salus::vm::Vm as core::fmt::Debug>::fmt::hFEDCBA9876543210"),
+            "<salus::vm::Vm as core::fmt::Debug>::fmt"
+        );
+    }
+
+    #[test]
+    fn strip_hash_keeps_other_names() {
+        assert_eq!(strip_hash("_start"), "_start");
+        assert_eq!(strip_hash("salus::handle_trap"), "salus::handle_trap");
+        // Not 16 hex digits.
+        assert_eq!(strip_hash("salus::h0123"), "salus::h0123");
+        assert_eq!(
+            strip_hash("salus::hello_world_function"),
+            "salus::hello_world_function"
+        );
+        // Mangled names are left alone.
+        assert_eq!(
+            strip_hash("_ZN4core3fmt5write17h0123456789abcdefE"),
+            "_ZN4core3fmt5write17h0123456789abcdefE"
+        );
+    }
+
+    #[test]
+    fn parse_line_with_size() {
+        let f = parse_line(
+            "0000000080200010 0000000000000024 T <salus::vm::Vm as core::fmt::Debug>::fmt::h0123456789abcdef",
+        )
+        .unwrap();
+        assert_eq!(f.address, 0x8020_0010);
+        assert_eq!(f.size, 0x24);
+        assert!(f.global);
+        assert_eq!(f.name, "<salus::vm::Vm as core::fmt::Debug>::fmt");
+    }
+
+    #[test]
+    fn parse_line_without_size() {
+        let f = parse_line("0000000080200000 T _start").unwrap();
+        assert_eq!(f.address, 0x8020_0000);
+        assert_eq!(f.size, 0);
+        assert!(f.global);
+        assert_eq!(f.name, "_start");
+    }
+
+    #[test]
+    fn parse_line_mangled_name() {
+        let f = parse_line(
+            "0000000080201000 0000000000000008 t _ZN4core3fmt5write17h0123456789abcdefE",
+        )
+        .unwrap();
+        assert_eq!(f.size, 8);
+        assert!(!f.global);
+        assert_eq!(f.name, "_ZN4core3fmt5write17h0123456789abcdefE");
+    }
+
+    #[test]
+    fn parse_line_symbol_kinds() {
+        assert!(parse_line("0000000080201000 W weak_fn").unwrap().global);
+        assert!(
+            !parse_line("0000000080201000 w local_weak_fn")
+                .unwrap()
+                .global
+        );
+        assert!(parse_line("0000000080300000 0000000000000008 D DATA").is_none());
+        assert!(parse_line("0000000080400000 0000000000001000 b bss").is_none());
+    }
+
+    #[test]
+    fn parse_line_skips_labels_and_garbage() {
+        assert!(parse_line("0000000080200004 t .Lfoo").is_none());
+        assert!(parse_line("0000000080200004 t $x").is_none());
+        assert!(parse_line("").is_none());
+        assert!(parse_line("                 U undefined_sym").is_none());
+        assert!(parse_line("not_an_address T foo").is_none());
+        assert!(parse_line("0000000080200000 zzzz T foo").is_none());
+        assert!(parse_line("0000000080200000 T").is_none());
+    }
+}
@@ -0,0 +1,411 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_std]
+
+//! A compact table of function symbols, embedded in the salus image at build time so that
+//! backtraces can be printed as `function+offset` without the matching build at hand.
+//!
+//! The section reserved for symbols holds one table per image (salus itself and the U-mode task),
+//! one after the other. All fields are little-endian. A table is laid out as follows:
+//!
+//! ```text
+//! +----------------------------+ 0
+//! | Header                     |
+//! +----------------------------+ HEADER_SIZE
+//! | Symbols                    | num_symbols * SYMBOL_SIZE, sorted by address
+//! +----------------------------+
+//! | Names                      | names_size, padded to TABLE_ALIGN
+//! +----------------------------+
+//! ```
+//!
+//! Symbol addresses are stored as 32-bit offsets from the table's base address. The section is
+//! zero-filled if no tables were added to the image.
+
+// For testing use the std crate.
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::{fmt, result, str};
+
+/// The magic number at the start of every table.
+pub const MAGIC: [u8; 8] = *b"SALUSSYM";
+
+/// Size in bytes of the section reserved for symbol tables in the salus image.
+pub const SECTION_SIZE: usize = 1024 * 1024;
+/// Size in bytes of a table header.
+pub const HEADER_SIZE: usize = 32;
+/// Size in bytes of a symbol entry.
+pub const SYMBOL_SIZE: usize = 16;
+/// Size in bytes of the image name in the header.
+pub const IMAGE_NAME_SIZE: usize = 8;
+/// Alignment of each table within the section.
+pub const TABLE_ALIGN: usize = 8;
+
+/// Symbol table parsing errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The table is smaller than its header or than the size the header gives.
+    Truncated,
+    /// The magic number is wrong.
+    BadMagic,
+    /// A symbol's name is out of bounds or isn't valid UTF-8.
+    BadName,
+}
+
+/// Custom symbol table result.
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Truncated => write!(f, "Symbol table is truncated"),
+            BadMagic => write!(f, "Not a symbol table"),
+            BadName => write!(f, "Bad symbol name"),
+        }
+    }
+}
+
+// Reads little-endian integers out of a fixed-layout structure.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&self, offset: usize) -> u32 {
+        // Unwrap ok: the slice is 4 bytes long.
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        // Unwrap ok: the slice is 8 bytes long.
+        u64::from_le_bytes(self.bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn array<const N: usize>(&self, offset: usize) -> [u8; N] {
+        // Unwrap ok: the slice is N bytes long.
+        self.bytes[offset..offset + N].try_into().unwrap()
+    }
+}
+
+// Writes little-endian integers into a fixed-layout structure.
+struct Writer<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> Writer<'a> {
+    fn u32(&mut self, offset: usize, val: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn u64(&mut self, offset: usize, val: u64) {
+        self.bytes[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn slice(&mut self, offset: usize, val: &[u8]) {
+        self.bytes[offset..offset + val.len()].copy_from_slice(val);
+    }
+}
+
+/// The fixed-size header at the start of a table.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+    /// Name of the image the symbols belong to, NUL-padded.
+    pub image: [u8; IMAGE_NAME_SIZE],
+    /// Address the symbol offsets are relative to.
+    pub base: u64,
+    /// The number of entries in the symbol table.
+    pub num_symbols: u32,
+    /// Size in bytes of the names, without padding.
+    pub names_size: u32,
+}
+
+impl Header {
+    /// Decodes and validates the header at the start of `bytes`.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        let r = Reader { bytes };
+        if r.array::<8>(0) != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        Ok(Self {
+            image: r.array(8),
+            base: r.u64(16),
+            num_symbols: r.u32(24),
+            names_size: r.u32(28),
+        })
+    }
+
+    /// Encodes the header.
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.slice(0, &MAGIC);
+        w.slice(8, &self.image);
+        w.u64(16, self.base);
+        w.u32(24, self.num_symbols);
+        w.u32(28, self.names_size);
+        bytes
+    }
+
+    /// Returns true if the table holds the symbols of `image`.
+    pub fn is_image(&self, image: &str) -> bool {
+        let len = self
+            .image
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(IMAGE_NAME_SIZE);
+        &self.image[..len] == image.as_bytes()
+    }
+
+    /// Returns the size of the table, including the padding after the names.
+    pub fn table_size(&self) -> usize {
+        let size = HEADER_SIZE + self.num_symbols as usize * SYMBOL_SIZE + self.names_size as usize;
+        (size + TABLE_ALIGN - 1) & !(TABLE_ALIGN - 1)
+    }
+}
+
+/// An entry of the symbol table.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SymbolEntry {
+    /// Offset of the symbol from the table's base address.
+    pub offset: u32,
+    /// Size of the function in bytes, or 0 if unknown.
+    pub size: u32,
+    /// Offset of the symbol's name from the start of the names.
+    pub name_offset: u32,
+    /// Length of the symbol's name in bytes.
+    pub name_len: u32,
+}
+
+impl SymbolEntry {
+    /// Decodes a symbol entry.
+    pub fn decode(bytes: &[u8; SYMBOL_SIZE]) -> Self {
+        let r = Reader { bytes };
+        Self {
+            offset: r.u32(0),
+            size: r.u32(4),
+            name_offset: r.u32(8),
+            name_len: r.u32(12),
+        }
+    }
+
+    /// Encodes the symbol entry.
+    pub fn encode(&self) -> [u8; SYMBOL_SIZE] {
+        let mut bytes = [0u8; SYMBOL_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u32(0, self.offset);
+        w.u32(4, self.size);
+        w.u32(8, self.name_offset);
+        w.u32(12, self.name_len);
+        bytes
+    }
+}
+
+/// A function symbol found in a table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Symbol<'a> {
+    /// The (demangled) name of the function.
+    pub name: &'a str,
+    /// The address of the start of the function.
+    pub address: u64,
+}
+
+/// A parsed symbol table.
+#[derive(Clone, Copy, Debug)]
+pub struct SymbolTable<'a> {
+    header: Header,
+    symbols: &'a [u8],
+    names: &'a [u8],
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parses the table at the start of `bytes`.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let header = Header::decode(bytes)?;
+        let symbols_end = HEADER_SIZE + header.num_symbols as usize * SYMBOL_SIZE;
+        let names_end = symbols_end + header.names_size as usize;
+        if bytes.len() < names_end {
+            return Err(Error::Truncated);
+        }
+        Ok(Self {
+            header,
+            symbols: &bytes[HEADER_SIZE..symbols_end],
+            names: &bytes[symbols_end..names_end],
+        })
+    }
+
+    /// Finds the table for `image` among the tables packed one after the other in `bytes`.
+    pub fn find(bytes: &'a [u8], image: &str) -> Result<Self> {
+        let mut bytes = bytes;
+        loop {
+            let table = Self::parse(bytes)?;
+            if table.header.is_image(image) {
+                return Ok(table);
+            }
+            bytes = bytes
+                .get(table.header.table_size()..)
+                .ok_or(Error::Truncated)?;
+        }
+    }
+
+    /// Returns the table's header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // Returns the i-th symbol entry.
+    fn entry(&self, index: usize) -> SymbolEntry {
+        let start = index * SYMBOL_SIZE;
+        // Unwrap ok: `symbols` holds `num_symbols` entries.
+        SymbolEntry::decode(self.symbols[start..start + SYMBOL_SIZE].try_into().unwrap())
+    }
+
+    /// Returns the function containing `addr`, if any.
+    pub fn lookup(&self, addr: u64) -> Result<Option<Symbol<'a>>> {
+        let offset = match addr
+            .checked_sub(self.header.base)
+            .and_then(|offset| u32::try_from(offset).ok())
+        {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        // Binary search for the first symbol starting after `offset`; the one before it is the
+        // candidate.
+        let (mut lo, mut hi) = (0, self.header.num_symbols as usize);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid).offset <= offset {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            return Ok(None);
+        }
+        let entry = self.entry(lo - 1);
+        // Addresses past the end of a sized function aren't part of it.
+        if entry.size != 0 && offset - entry.offset >= entry.size {
+            return Ok(None);
+        }
+        let start = entry.name_offset as usize;
+        let name = self
+            .names
+            .get(start..start + entry.name_len as usize)
+            .and_then(|name| str::from_utf8(name).ok())
+            .ok_or(Error::BadName)?;
+        Ok(Some(Symbol {
+            name,
+            address: self.header.base + entry.offset as u64,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn build_table(image: &str, base: u64, symbols: &[(u32, u32, &str)]) -> Vec<u8> {
+        let mut names = Vec::new();
+        let mut entries = Vec::new();
+        for &(offset, size, name) in symbols {
+            entries.push(SymbolEntry {
+                offset,
+                size,
+                name_offset: names.len() as u32,
+                name_len: name.len() as u32,
+            });
+            names.extend_from_slice(name.as_bytes());
+        }
+        let mut header = Header {
+            base,
+            num_symbols: entries.len() as u32,
+            names_size: names.len() as u32,
+            ..Default::default()
+        };
+        header.image[..image.len()].copy_from_slice(image.as_bytes());
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&header.encode());
+        for entry in entries {
+            bytes.extend_from_slice(&entry.encode());
+        }
+        bytes.extend_from_slice(&names);
+        bytes.resize(header.table_size(), 0);
+        bytes
+    }
+
+    #[test]
+    fn lookup() {
+        let bytes = build_table(
+            "salus",
+            0x8020_0000,
+            &[
+                (0, 0x10, "_start"),
+                (0x100, 0x40, "kernel_init"),
+                (0x200, 0, "foo::bar"),
+            ],
+        );
+        let table = SymbolTable::parse(&bytes).unwrap();
+        assert!(table.header().is_image("salus"));
+        assert!(!table.header().is_image("sal"));
+
+        let sym = table.lookup(0x8020_0108).unwrap().unwrap();
+        assert_eq!(sym.name, "kernel_init");
+        assert_eq!(sym.address, 0x8020_0100);
+        assert_eq!(table.lookup(0x8020_0000).unwrap().unwrap().name, "_start");
+        // Unsized symbols extend to the next symbol.
+        assert_eq!(table.lookup(0x8020_1000).unwrap().unwrap().name, "foo::bar");
+        // Between symbols, before the base and too far past it.
+        assert_eq!(table.lookup(0x8020_0010).unwrap(), None);
+        assert_eq!(table.lookup(0x8020_0140).unwrap(), None);
+        assert_eq!(table.lookup(0x801f_ffff).unwrap(), None);
+        assert_eq!(table.lookup(0x1_8020_0000).unwrap(), None);
+    }
+
+    #[test]
+    fn find() {
+        let mut bytes = build_table("salus", 0x8020_0000, &[(0, 0x10, "salus_fn")]);
+        bytes.extend(build_table("umode", 0, &[(0x20, 0x10, "umode_fn")]));
+        let section_size = bytes.len() + 64;
+        bytes.resize(section_size, 0);
+
+        let salus = SymbolTable::find(&bytes, "salus").unwrap();
+        assert_eq!(salus.lookup(0x8020_0004).unwrap().unwrap().name, "salus_fn");
+        let umode = SymbolTable::find(&bytes, "umode").unwrap();
+        assert_eq!(umode.lookup(0x24).unwrap().unwrap().name, "umode_fn");
+        assert_eq!(
+            SymbolTable::find(&bytes, "tellus").err(),
+            Some(Error::BadMagic)
+        );
+        assert_eq!(
+            SymbolTable::find(&[0u8; SECTION_SIZE], "salus").err(),
+            Some(Error::BadMagic)
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = build_table("salus", 0, &[(0, 0x10, "salus_fn")]);
+        assert_eq!(
+            SymbolTable::parse(&bytes[..HEADER_SIZE + 4]).err(),
+            Some(Error::Truncated)
+        );
+        assert_eq!(
+            SymbolTable::parse(&bytes[..8]).err(),
+            Some(Error::Truncated)
+        );
+
+        let mut bad = bytes.clone();
+        bad[HEADER_SIZE + 12] = 0x20;
+        let table = SymbolTable::parse(&bad).unwrap();
+        assert_eq!(table.lookup(0).err(), Some(Error::BadName));
+    }
+}
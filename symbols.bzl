@@ -0,0 +1,88 @@
+# SPDX-FileCopyrightText: 2023 Rivos Inc.
+#
+# SPDX-License-Identifier: Apache-2.0
+
+load(
+    "@bazel_tools//tools/cpp:toolchain_utils.bzl",
+    "find_cpp_toolchain",
+    "use_cpp_toolchain",
+)
+
+def _symbolize_impl(ctx):
+    cc_toolchain = find_cpp_toolchain(ctx)
+    src = ctx.files.src[0]
+    out = ctx.actions.declare_file(ctx.label.name)
+
+    # Dump the function symbols of each image with nm.
+    nm_outputs = []
+    builder_args = []
+    images = [(src, "salus")] + [
+        (target.files.to_list()[0], name)
+        for target, name in ctx.attr.images.items()
+    ]
+    for image, name in images:
+        nm_out = ctx.actions.declare_file(ctx.label.name + "." + name + ".nm")
+        ctx.actions.run_shell(
+            mnemonic = "NmSymbols",
+            command = "\"$1\" --defined-only -S -C \"$2\" > \"$3\"",
+            arguments = [cc_toolchain.nm_executable, image.path, nm_out.path],
+            inputs = depset(
+                [image],
+                transitive = [cc_toolchain.all_files],
+            ),
+            outputs = [nm_out],
+        )
+        nm_outputs.append(nm_out)
+        builder_args.append(name + "=" + nm_out.path)
+
+    # Pack them into the format salus reads at panic time.
+    table = ctx.actions.declare_file(ctx.label.name + ".symbols")
+    ctx.actions.run(
+        mnemonic = "BuildSymbolTable",
+        executable = ctx.executable._builder,
+        arguments = [table.path] + builder_args,
+        inputs = nm_outputs,
+        outputs = [table],
+    )
+
+    # And replace the contents of the section reserved for them.
+    ctx.actions.run(
+        mnemonic = "ObjCopyUpdateSymbols",
+        executable = cc_toolchain.objcopy_executable,
+        arguments = ["--update-section", ".salus_symbols=" + table.path, src.path, out.path],
+        inputs = depset(
+            [src, table],
+            transitive = [cc_toolchain.all_files],
+        ),
+        outputs = [out],
+    )
+
+    return [DefaultInfo(files = depset([out]), executable = out)]
+
+# Embeds the function symbols of `src` and of `images`, a map from each embedded image to its name,
+# in the `.salus_symbols` section of `src`.
+symbolize = rule(
+    implementation = _symbolize_impl,
+    attrs = {
+        "src": attr.label(
+            mandatory = True,
+            allow_single_file = True,
+            executable = True,
+            cfg = "target",
+        ),
+        "images": attr.label_keyed_string_dict(
+            allow_files = True,
+            cfg = "target",
+        ),
+        "_builder": attr.label(
+            default = Label("//symbol-table:symbol_table_builder"),
+            executable = True,
+            cfg = "exec",
+        ),
+        "_cc_toolchain": attr.label(
+            default = Label("@bazel_tools//tools/cpp:current_cc_toolchain"),
+        ),
+    },
+    executable = True,
+    toolchains = use_cpp_toolchain(),
+)
@@ -14,7 +14,7 @@ fn main() {
     let mut arg_list = env::args().skip(1);
 
     let file1 = arg_list.next().expect("No Tellus path");
-    let file2 = arg_list.next().expect("No Guestvm path");
+    let file2 = arg_list.next().expect("No guest bundle path");
     let file3 = arg_list.next().expect("No Output path");
     let offset = arg_list.next().expect("No Max Tellus Size");
 
@@ -26,7 +26,7 @@ fn main() {
     if tellus_len > offset {
         panic!("Tellus longer than Max Tellus size!");
     }
-    let f2 = File::open(file2).expect("error reading Guestvm");
+    let f2 = File::open(file2).expect("error reading guest bundle");
     let mut f3 = File::create(file3).expect("error opening Output");
 
     // create reader/writer
@@ -42,5 +42,5 @@ fn main() {
     buf_writer
         .seek(SeekFrom::Start(offset))
         .expect("Problem Seeking in Output");
-    copy(&mut buf_reader2, &mut buf_writer).expect("error writing Guestvm to Output");
+    copy(&mut buf_reader2, &mut buf_writer).expect("error writing guest bundle to Output");
 }
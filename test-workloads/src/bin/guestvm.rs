@@ -15,13 +15,15 @@ use hex_literal::hex;
 extern crate alloc;
 extern crate test_workloads;
 
-use ::attestation::TcgPcrIndex::{RuntimePcr1, TvmPage};
+use ::attestation::TcgPcrIndex::{RuntimePcr1, TvmIdentity, TvmPage};
 use rice::x509::{
     certificate::Certificate,
     extensions::dice::tcbinfo::{DiceTcbInfo, TCG_DICE_TCB_INFO},
     MAX_CSR_LEN,
 };
-use riscv_regs::{sie, stopi, Interrupt, Readable, RiscvCsrInterface, Writeable, CSR};
+use riscv_regs::{
+    sie, sstatus, stopi, Interrupt, ReadWriteable, Readable, RiscvCsrInterface, Writeable, CSR,
+};
 use s_mode_utils::abort::abort;
 use s_mode_utils::{print::*, sbi_console::SbiConsole};
 use sbi_rs::api::{attestation, base, cove_guest, reset};
@@ -49,6 +51,14 @@ pub fn alloc_error(_layout: Layout) -> ! {
     abort()
 }
 
+// Returns true if the CPU implements vectors; sstatus.VS is read-only zero otherwise.
+fn cpu_has_vectors() -> bool {
+    CSR.sstatus.modify(sstatus::vs::Initial);
+    let has_vectors = CSR.sstatus.read(sstatus::vs) != 0;
+    CSR.sstatus.modify(sstatus::vs::Off);
+    has_vectors
+}
+
 pub fn print_vector_csrs() {
     let mut vl: u64;
     let mut vcsr: u64;
@@ -219,6 +229,11 @@ fn test_attestation() -> TestResult {
         return Err(TestFailure::Fail);
     }
 
+    // Set if we were finalized with a signed manifest.
+    let identity = attestation::read_measurement(TvmIdentity as usize)
+        .expect("Failed to read TVM identity PCR");
+    println!("TVM identity measurement: {:x?}", identity.as_slice());
+
     if TEST_CSR.len() > MAX_CSR_LEN {
         println!("Test CSR is too large");
         return Err(TestFailure::Fail);
@@ -464,7 +479,9 @@ extern "C" fn kernel_init(hart_id: u64, boot_args: u64) {
     println!("Hello world from Tellus guest            ");
     test_declare_pass!("guestvm boot", hart_id);
 
-    let vectors_enabled = boot_args & BOOT_ARG_VECTORS_ENABLED != 0;
+    // Our boot argument is fixed by our measurement, so it only says whether to use vectors if the
+    // CPU has them.
+    let vectors_enabled = boot_args & BOOT_ARG_VECTORS_ENABLED != 0 && cpu_has_vectors();
     if vectors_enabled {
         println!("guestvm vector extension enabled (on)");
     } else {
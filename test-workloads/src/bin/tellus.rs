@@ -37,6 +37,7 @@ use sbi_rs::{
 use sync::{Mutex, Once};
 use test_system::*;
 use test_workloads::consts::*;
+use test_workloads::tvm_loader::{self, TvmLoader};
 
 // The secondary CPU entry point, defined in start.S. Calls secondary_init below.
 extern "C" {
@@ -256,6 +257,21 @@ unsafe fn convert_pages(addr: u64, num_pages: u64) {
     fence_memory();
 }
 
+// Converts `num_pages` pages at the first `align`-aligned address from `next_page`, returning their
+// address. Pages skipped to align them are converted too so that all the pages donated from
+// `next_page` can be reclaimed as one range.
+fn donate_pages(next_page: &mut u64, num_pages: u64, align: u64) -> u64 {
+    let base = (*next_page + align - 1) & !(align - 1);
+    let num_converted = (base - *next_page) / PAGE_SIZE_4K + num_pages;
+    // Safety: The pages at `next_page` are unmapped and we do not access them again until they're
+    // reclaimed.
+    unsafe {
+        convert_pages(*next_page, num_converted);
+    }
+    *next_page += PAGE_SIZE_4K * num_converted;
+    base
+}
+
 fn reclaim_pages(addr: u64, num_pages: u64) {
     cove_host::reclaim_pages(addr, num_pages).expect("TsmReclaimPages failed");
 
@@ -318,6 +334,53 @@ fn exercise_pmu_functionality() {
     .expect_err("Successfully configured FW counter");
 }
 
+// Returns the current value of the `time` CSR.
+fn read_time() -> u64 {
+    let time: u64;
+    // Safety: reading the time CSR has no side effects.
+    unsafe { asm!("rdtime {}", out(reg) time) };
+    time
+}
+
+// Unbinds vCPU 0 of `vmid` from its guest interrupt file, leaving the file converted.
+fn unbind_vcpu(vmid: u64) {
+    cove_interrupt::unbind_vcpu_imsic_begin(vmid, 0).expect("Tellus - TvmCpuUnbindImsic failed");
+    cove_host::tvm_initiate_fence(vmid).expect("Tellus - TvmInitiateFence failed");
+    cove_interrupt::unbind_vcpu_imsic_end(vmid, 0).expect("Tellus - TvmCpuUnbindImsic failed");
+}
+
+// Runs vCPU 0 of `vmid`, returning non-zero if it exited fatally. The TSM returns `AlreadyStarted`
+// while it's moving the vCPU from the CPU it was last bound to, in which case the run is retried.
+fn run_tvm_vcpu(vmid: u64) -> u64 {
+    loop {
+        // Safety: running a VM will only write the `TsmShmemArea` struct that was registered
+        // with `register_shmem()`.
+        match cove_host::tvm_run(vmid, 0) {
+            Err(SbiError::AlreadyStarted) => core::hint::spin_loop(),
+            result => return result.expect("Could not run guest VM"),
+        }
+    }
+}
+
+// Like `run_tvm_vcpu()`, but for the first run after vCPU 0 of `vmid` was bound to another CPU.
+// Reports how long the TSM took to move the vCPU, i.e. until the other CPU saved its interrupt file.
+fn run_migrated_tvm_vcpu(vmid: u64) -> u64 {
+    let start = read_time();
+    let mut saved = start;
+    loop {
+        match cove_host::tvm_run(vmid, 0) {
+            Err(SbiError::AlreadyStarted) => {
+                saved = read_time();
+                core::hint::spin_loop();
+            }
+            result => {
+                println!("Tellus - vCPU migration took {} ticks", saved - start);
+                return result.expect("Could not run guest VM");
+            }
+        }
+    }
+}
+
 fn store_into_vectors() {
     let vec_len: u64 = 8;
     let vtype: u64 = 0xda;
@@ -577,16 +640,35 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
         .expect("Tellus - AddPageTablePages returned error");
     next_page += PAGE_SIZE_4K * NUM_COVE_PTE_PAGES;
 
-    // Add vCPU0.
+    // The guest is packaged as a TVM bundle following the tellus image.
+    let guest_bundle_base = USABLE_RAM_START_ADDRESS + PAGE_SIZE_4K * NUM_TELLUS_IMAGE_PAGES;
+    // Safety: The bundle is read-only and not otherwise used by this program.
+    let loader = unsafe { TvmLoader::from_raw_pointer(guest_bundle_base as *const u8) }
+        .expect("Tellus - Invalid guest bundle");
+    let bundle = loader.bundle();
+    let num_guest_data_pages = bundle.num_4k_pages();
+    // The guest's zero pages must follow its image.
+    assert!(
+        bundle
+            .page_ranges()
+            .all(|r| r.gpa() + r.data().len() as u64 <= GUEST_ZERO_PAGES_START_ADDRESS),
+        "Tellus - Guest image overlaps its zero pages"
+    );
+    println!(
+        "Tellus - Guest bundle: {} vCPU(s), {} data pages, entry 0x{:x}",
+        bundle.num_vcpus(),
+        num_guest_data_pages,
+        bundle.entry_pc()
+    );
 
-    // Safety: The passed-in pages are unmapped and we do not access them again until they're
-    // reclaimed.
+    // Add the guest's vCPUs.
     let vcpu_pages_base = next_page;
-    unsafe {
-        convert_pages(vcpu_pages_base, tsm_info.tvm_vcpu_state_pages);
-    }
-    next_page += PAGE_SIZE_4K * tsm_info.tvm_vcpu_state_pages;
-    cove_host::add_vcpu(vmid, 0, vcpu_pages_base).expect("Tellus - TvmCpuCreate returned error");
+    loader
+        .add_vcpus(vmid, tsm_info.tvm_vcpu_state_pages, &mut |n, align| {
+            donate_pages(&mut next_page, n, align)
+        })
+        .expect("Tellus - TvmCpuCreate returned error");
+    let num_vcpu_pages = tsm_info.tvm_vcpu_state_pages * bundle.num_vcpus() as u64;
 
     let has_aia = base::probe_sbi_extension(EXT_COVE_INTERRUPT).is_ok();
     // CPU0, guest interrupt file 0 (which is at index 1 since supervisor file is at 0).
@@ -598,18 +680,10 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
         let hgeie = CSR.hgeie.atomic_replace(0);
         println!("Found {:} guest interrupt files", hgeie.count_ones());
 
-        // Set the IMSIC params for the TVM.
-        let aia_params = sbi_rs::TvmAiaParams {
-            imsic_base_addr: 0x2800_0000,
-            group_index_bits: 0,
-            group_index_shift: 24,
-            hart_index_bits: 8,
-            guest_index_bits: 0,
-            guests_per_hart: 0,
-        };
-        cove_interrupt::tvm_aia_init(vmid, aia_params).expect("Tellus - TvmAiaInit failed");
-        cove_interrupt::set_vcpu_imsic_addr(vmid, 0, 0x2800_0000)
-            .expect("Tellus - TvmCpuSetImsicAddr failed");
+        // Set the IMSIC params for the TVM and its vCPUs.
+        loader
+            .init_aia(vmid)
+            .expect("Tellus - TvmAiaInit or TvmCpuSetImsicAddr failed");
 
         // Try to convert a guest interrupt file.
         //
@@ -622,40 +696,14 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
         println!("Platform doesn't support COVE AIA extension");
     }
 
-    let guest_image_base = USABLE_RAM_START_ADDRESS + PAGE_SIZE_4K * NUM_TELLUS_IMAGE_PAGES;
-    // Safety: Safe to make a slice out of the guest image as it is read-only and not used by this
-    // program.
-    let guest_image = unsafe {
-        core::slice::from_raw_parts(
-            guest_image_base as *const u8,
-            (PAGE_SIZE_4K * NUM_GUEST_DATA_PAGES) as usize,
-        )
-    };
+    // Declare the confidential regions of the guest's physical address space and add its data
+    // pages.
     let donated_pages_base = next_page;
+    loader
+        .add_memory(vmid, &mut |n, align| donate_pages(&mut next_page, n, align))
+        .expect("Tellus - TvmAddMemoryRegion or TvmAddMeasuredPages failed");
 
-    // Declare the confidential region of the guest's physical address space.
-    cove_host::add_memory_region(
-        vmid,
-        USABLE_RAM_START_ADDRESS,
-        GUEST_RAM_END_ADDRESS - USABLE_RAM_START_ADDRESS,
-    )
-    .expect("Tellus - TvmAddMemoryRegion failed");
-
-    // Add data pages
-    // Safety: The passed-in pages are unmapped and we do not access them again until they're
-    // reclaimed.
-    unsafe {
-        convert_pages(next_page, NUM_GUEST_DATA_PAGES);
-    }
-    cove_host::add_measured_pages(
-        vmid,
-        guest_image,
-        next_page,
-        sbi_rs::TsmPageType::Page4k,
-        USABLE_RAM_START_ADDRESS,
-    )
-    .expect("Tellus - TvmAddMeasuredPages returned error");
-    next_page += PAGE_SIZE_4K * NUM_GUEST_DATA_PAGES;
+    let num_donated_pages = (next_page - donated_pages_base) / PAGE_SIZE_4K;
 
     // Convert pages to handle confidential page faults.
     let zero_pages_base = next_page;
@@ -680,14 +728,26 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
         convert_pages(huge_page_base, NUM_GUEST_ZERO_HUGE_PAGES);
     }
 
-    // Tell the guest if we have vector support via its boot argument.
-    let boot_arg = if vector_enabled {
-        BOOT_ARG_VECTORS_ENABLED
-    } else {
-        0
-    };
+    // Boot the guest with the argument it was measured with. It checks for vector support itself.
+    let boot_arg = bundle.entry_arg();
+    if bundle.signature().is_some() {
+        println!("Tellus - Finalizing guest with its signed manifest");
+    }
+    if let Some(mut signed_manifest) = loader.signed_manifest() {
+        // Change the manifest's SVN so its signature no longer verifies. The TSM must reject it
+        // and leave the guest initializing, so that finalizing it below still works.
+        signed_manifest[4] ^= 1;
+        let result =
+            tvm_loader::tvm_finalize_signed(vmid, bundle.entry_pc(), boot_arg, &signed_manifest);
+        assert!(
+            matches!(result, Err(tvm_loader::Error::Sbi(SbiError::Denied))),
+            "Tellus - Tampered manifest wasn't denied"
+        );
+    }
     // TODO test that access to pages crashes somehow
-    cove_host::tvm_finalize(vmid, 0x8020_0000, boot_arg).expect("Tellus - Finalize returned error");
+    loader
+        .finalize(vmid, boot_arg)
+        .expect("Tellus - Finalize returned error");
 
     // Map a few zero pages up front. We'll fault the rest in as necessary.
     cove_host::add_zero_pages(
@@ -704,6 +764,9 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
         store_into_vectors();
     }
 
+    // Set if the vCPU is left bound to another CPU, in which case the first run moves it here.
+    let mut time_migration = false;
+
     // Bind to a guest interrupt file if AIA is enabled.
     if has_aia {
         cove_interrupt::bind_vcpu_imsic(vmid, 0, 1 << 1).expect("Tellus - TvmCpuBindImsic failed");
@@ -724,6 +787,7 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
             .expect("Tellus - TsmConvertImsic failed");
         fence_memory();
 
+        let start = read_time();
         cove_interrupt::rebind_vcpu_imsic_begin(vmid, 0, 1 << imsic_file_num)
             .expect("Tellus - TvmCpuRebindImsicBegin failed");
         cove_host::tvm_initiate_fence(vmid).expect("Tellus - TvmInitiateFence failed");
@@ -731,11 +795,47 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
             .expect("Tellus - TvmCpuRebindImsicClone failed");
         cove_interrupt::rebind_vcpu_imsic_end(vmid, 0)
             .expect("Tellus - TvmCpuRebindImsicEnd failed");
+        println!(
+            "Tellus - Explicit vCPU rebind took {} ticks",
+            read_time() - start
+        );
 
         // Reclaim previous imsic file address.
         cove_interrupt::reclaim_imsic(Imsic::get().file_address(0, previous_imsic_file_num))
             .expect("Tellus - TsmReclaimImsic failed");
 
+        // Time binding the vCPU to a free guest interrupt file, which is what the first TvmCpuRun
+        // of an unbound vCPU does before running it.
+        unbind_vcpu(vmid);
+        let start = read_time();
+        cove_interrupt::bind_vcpu_imsic(vmid, 0, 1 << imsic_file_num)
+            .expect("Tellus - TvmCpuBindImsic failed");
+        println!("Tellus - vCPU bind took {} ticks", read_time() - start);
+
+        // Unbind the vCPU again, leaving its guest interrupt file converted, so that the first
+        // TvmCpuRun below has to bind it to a free guest interrupt file on its own.
+        unbind_vcpu(vmid);
+
+        // If there's another CPU, bind the vCPU to one of its guest interrupt files instead, so
+        // that the first TvmCpuRun below has to move the vCPU to this CPU.
+        if let Some(other_cpu) = PER_CPU
+            .get()
+            .unwrap()
+            .iter()
+            .find(|c| PerCpu::get().hart_id != c.hart_id)
+        {
+            let other_file_addr = Imsic::get().file_address(other_cpu.runner.hart_index, 1);
+            // Safety: As above, for the other CPU's first guest interrupt file.
+            unsafe { cove_interrupt::convert_imsic(other_file_addr) }
+                .expect("Tellus - TsmConvertImsic failed");
+            fence_memory();
+            other_cpu.runner.run(|| {
+                cove_interrupt::bind_vcpu_imsic(vmid, 0, 1 << 1)
+                    .expect("Tellus - TvmCpuBindImsic failed");
+            });
+            time_migration = true;
+        }
+
         CSR.hgeie.set(1 << imsic_file_num);
     }
 
@@ -764,9 +864,11 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
 
     let mut mmio_region: Option<Range<u64>> = None;
     loop {
-        // Safety: running a VM will only write the `TsmShmemArea` struct that was registered
-        // with `register_shmem()`.
-        let fatal = cove_host::tvm_run(vmid, 0).expect("Could not run guest VM") != 0;
+        let fatal = if core::mem::take(&mut time_migration) {
+            run_migrated_tvm_vcpu(vmid)
+        } else {
+            run_tvm_vcpu(vmid)
+        } != 0;
         let scause = CSR.scause.get();
         if let Ok(t) = Trap::from_scause(scause) {
             use Exception::*;
@@ -1137,10 +1239,10 @@ extern "C" fn kernel_init(hart_id: u64, fdt_addr: u64) {
     reclaim_pages(huge_page_base, NUM_GUEST_ZERO_HUGE_PAGES);
     reclaim_pages(
         donated_pages_base,
-        NUM_GUEST_DATA_PAGES + NUM_CONVERTED_ZERO_PAGES,
+        num_donated_pages + NUM_CONVERTED_ZERO_PAGES,
     );
     reclaim_pages(state_pages_base, tvm_create_pages);
-    reclaim_pages(vcpu_pages_base, tsm_info.tvm_vcpu_state_pages);
+    reclaim_pages(vcpu_pages_base, num_vcpu_pages);
     if has_aia {
         cove_interrupt::reclaim_imsic(imsic_file_addr).expect("Tellus - TsmReclaimImsic failed");
     }
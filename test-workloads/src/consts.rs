@@ -13,7 +13,7 @@
 /// +-------------------------+ 0x8020_0000
 /// | Tellus image            |
 /// +-------------------------+ +NUM_TELLUS_IMAGE_PAGES
-/// | Guest image             |
+/// | Guest TVM bundle        |
 /// +-------------------------+
 ///
 /// The guest VM's address space is constructed to look like this:
@@ -46,7 +46,9 @@
 
 pub const PAGE_SIZE_4K: u64 = 4096;
 pub const PAGE_SIZE_2M: u64 = 2097152;
-// If NUM_TELLUS_IMAGE_PAGES changes, you must also change it in the BUILD file
+// If any of NUM_TELLUS_IMAGE_PAGES, NUM_GUEST_DATA_PAGES, IMSIC_START_ADDRESS,
+// USABLE_RAM_START_ADDRESS or GUEST_RAM_END_ADDRESS change, you must also change them in the BUILD
+// file
 pub const NUM_TELLUS_IMAGE_PAGES: u64 = 512;
 pub const GUEST_MMIO_START_ADDRESS: u64 = 0x1000_8000;
 pub const GUEST_MMIO_END_ADDRESS: u64 = GUEST_MMIO_START_ADDRESS + PAGE_SIZE_4K - 1;
@@ -12,6 +12,7 @@ use s_mode_utils::print::*;
 
 mod asm;
 pub mod consts;
+pub mod tvm_loader;
 
 /// Panics the running test workload.
 #[panic_handler]
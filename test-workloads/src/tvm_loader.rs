@@ -0,0 +1,197 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A reference loader that builds a TVM from a TVM bundle using the CoVE host ABI.
+
+use core::arch::asm;
+use sbi_rs::api::{cove_host, cove_interrupt};
+use sbi_rs::{Error as SbiError, TsmPageType};
+use tvm_bundle::{
+    Error as BundleError, PageType, TvmBundle, HEADER_SIZE, MANIFEST_SIZE, SIGNATURE_SIZE,
+};
+
+/// Errors from loading a TVM bundle.
+#[derive(Debug)]
+pub enum Error {
+    /// The bundle is malformed.
+    Bundle(BundleError),
+    /// A CoVE host call failed.
+    Sbi(SbiError),
+    /// The bundle is signed but the entry argument isn't the one its manifest was built with.
+    EntryArgMismatch,
+}
+
+/// Custom loader result.
+pub type Result<T> = core::result::Result<T, Error>;
+
+impl From<SbiError> for Error {
+    fn from(e: SbiError) -> Self {
+        Error::Sbi(e)
+    }
+}
+
+// Function ID of Salus' signed finalize call, an extension to the CoVE host interface:
+// `(tvm_id, entry_sepc, entry_arg, manifest_addr, manifest_len)`.
+const TVM_FINALIZE_SIGNED_FID: u64 = 0x1010;
+
+/// Finalizes `vmid` like `cove_host::tvm_finalize`, but has the TSM verify `signed_manifest` (a
+/// manifest followed by its signature block) against the TVM first and measure the signer.
+pub fn tvm_finalize_signed(
+    vmid: u64,
+    entry_pc: u64,
+    entry_arg: u64,
+    signed_manifest: &[u8],
+) -> Result<()> {
+    let error: i64;
+    // Safety: the call only reads `signed_manifest`.
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") vmid => error,
+            inlateout("a1") entry_pc => _,
+            in("a2") entry_arg,
+            in("a3") signed_manifest.as_ptr() as u64,
+            in("a4") signed_manifest.len() as u64,
+            in("a6") TVM_FINALIZE_SIGNED_FID,
+            in("a7") sbi_rs::EXT_COVE_HOST,
+            options(nostack),
+        );
+    }
+    match error {
+        0 => Ok(()),
+        e => Err(Error::Sbi(SbiError::from_code(e))),
+    }
+}
+
+fn tsm_page_type(page_type: PageType) -> TsmPageType {
+    match page_type {
+        PageType::Page4k => TsmPageType::Page4k,
+        PageType::Page2M => TsmPageType::Page2M,
+        PageType::Page1G => TsmPageType::Page1G,
+    }
+}
+
+/// Drives the creation of a TVM from the contents of a bundle. The caller creates the TVM and
+/// donates pages to the TSM; the loader adds the vCPUs, memory and measured pages described by the
+/// bundle and finalizes the TVM.
+///
+/// Pages are donated through a callback which takes a number of 4kB pages and an alignment in bytes,
+/// and returns the address of that many contiguous pages, aligned as requested and already converted
+/// for use by the TSM.
+pub struct TvmLoader<'a> {
+    bundle: TvmBundle<'a>,
+}
+
+impl<'a> TvmLoader<'a> {
+    /// Creates a loader for the bundle in `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Result<Self> {
+        let bundle = TvmBundle::parse(bytes).map_err(Error::Bundle)?;
+        Ok(Self { bundle })
+    }
+
+    /// Creates a loader for the bundle at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must point to a bundle which remains valid and unmodified for the lifetime of the
+    /// loader.
+    pub unsafe fn from_raw_pointer(addr: *const u8) -> Result<Self> {
+        let header = core::slice::from_raw_parts(addr, HEADER_SIZE);
+        let size = TvmBundle::size_from_header(header).map_err(Error::Bundle)?;
+        Self::new(core::slice::from_raw_parts(addr, size as usize))
+    }
+
+    /// Returns the bundle being loaded.
+    pub fn bundle(&self) -> &TvmBundle<'a> {
+        &self.bundle
+    }
+
+    /// Adds the bundle's vCPUs to `vmid`, each with `vcpu_state_pages` donated pages.
+    pub fn add_vcpus(
+        &self,
+        vmid: u64,
+        vcpu_state_pages: u64,
+        donate: &mut dyn FnMut(u64, u64) -> u64,
+    ) -> Result<()> {
+        let align = PageType::Page4k.size();
+        for vcpu_id in 0..self.bundle.num_vcpus() as u64 {
+            cove_host::add_vcpu(vmid, vcpu_id, donate(vcpu_state_pages, align))?;
+        }
+        Ok(())
+    }
+
+    /// Configures the TVM's IMSIC layout and the IMSIC address of each vCPU, if the bundle
+    /// describes one. Must be called after `add_vcpus()`.
+    pub fn init_aia(&self, vmid: u64) -> Result<()> {
+        let Some(aia) = self.bundle.aia_layout() else {
+            return Ok(());
+        };
+        let aia_params = sbi_rs::TvmAiaParams {
+            imsic_base_addr: aia.imsic_base_addr,
+            group_index_bits: aia.group_index_bits,
+            group_index_shift: aia.group_index_shift,
+            hart_index_bits: aia.hart_index_bits,
+            guest_index_bits: aia.guest_index_bits,
+            guests_per_hart: aia.guests_per_hart,
+        };
+        cove_interrupt::tvm_aia_init(vmid, aia_params)?;
+        for vcpu_id in 0..self.bundle.num_vcpus() as u64 {
+            cove_interrupt::set_vcpu_imsic_addr(vmid, vcpu_id, aia.vcpu_imsic_addr(vcpu_id))?;
+        }
+        Ok(())
+    }
+
+    /// Declares the bundle's confidential memory regions and adds its measured pages, in order, to
+    /// `vmid`. The pages of each range are donated aligned to the range's page size.
+    pub fn add_memory(&self, vmid: u64, donate: &mut dyn FnMut(u64, u64) -> u64) -> Result<()> {
+        for r in self.bundle.memory_regions() {
+            cove_host::add_memory_region(vmid, r.gpa, r.size)?;
+        }
+        for r in self.bundle.page_ranges() {
+            let num_4k_pages = r.data().len() as u64 / PageType::Page4k.size();
+            let dest_addr = donate(num_4k_pages, r.page_type().size());
+            cove_host::add_measured_pages(
+                vmid,
+                r.data(),
+                dest_addr,
+                tsm_page_type(r.page_type()),
+                r.gpa(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the bundle's manifest followed by its signature block, as passed to the TSM's
+    /// signed finalize call, if the bundle is signed.
+    pub fn signed_manifest(&self) -> Option<[u8; MANIFEST_SIZE + SIGNATURE_SIZE]> {
+        let signature = self.bundle.signature()?;
+        let mut signed_manifest = [0u8; MANIFEST_SIZE + SIGNATURE_SIZE];
+        signed_manifest[..MANIFEST_SIZE].copy_from_slice(&self.bundle.manifest().encode());
+        signed_manifest[MANIFEST_SIZE..].copy_from_slice(&signature.encode());
+        Some(signed_manifest)
+    }
+
+    /// Finalizes `vmid`, starting its boot vCPU at the bundle's entry point with `entry_arg`. The
+    /// TVM's configuration measurement only matches the manifest if `entry_arg` is the bundle's.
+    ///
+    /// If the bundle is signed, the TSM checks the TVM against the signed manifest and measures the
+    /// signer into the TVM's identity; finalization fails if the manifest doesn't match. A signed
+    /// bundle can't be finalized with any `entry_arg` other than the bundle's, since it couldn't
+    /// match.
+    pub fn finalize(&self, vmid: u64, entry_arg: u64) -> Result<()> {
+        let entry_pc = self.bundle.entry_pc();
+        match self.signed_manifest() {
+            Some(_) if entry_arg != self.bundle.entry_arg() => {
+                return Err(Error::EntryArgMismatch);
+            }
+            Some(signed_manifest) => {
+                tvm_finalize_signed(vmid, entry_pc, entry_arg, &signed_manifest)?;
+            }
+            None => {
+                cove_host::tvm_finalize(vmid, entry_pc, entry_arg)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Builds a TVM bundle from an ELF image.
+//!
+//! Usage: tvm_bundle_builder <ELF> <OUTPUT> [OPTIONS]
+//!
+//! Options:
+//!   --memory-region GPA:SIZE  Confidential memory region to declare; may be repeated. Defaults to
+//!                             the range covered by the image.
+//!   --measured-size SIZE      Minimum number of bytes from the start of the image to add as
+//!                             measured pages, e.g. to cover a stack that isn't part of the ELF.
+//!   --num-vcpus N             Number of vCPUs to create. Defaults to 1.
+//!   --entry-arg ARG           Boot argument to compute the expected measurements with.
+//!   --imsic GPA               Address of the first vCPU's IMSIC interrupt file; enables AIA.
+//!   --svn N                   Security version number to put in the manifest.
+//!   --product-id HEX          Product ID, up to 16 bytes, to put in the manifest.
+//!   --signing-key FILE        Sign the manifest with the raw 32-byte Ed25519 key in FILE.
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use riscv_elf::ElfMap;
+use std::env;
+use std::fs;
+use std::process;
+use tvm_bundle::*;
+
+mod measure;
+use measure::expected_measurements;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Default IMSIC geometry, matching QEMU's virt machine.
+const IMSIC_GROUP_INDEX_SHIFT: u32 = 24;
+const IMSIC_HART_INDEX_BITS: u32 = 8;
+
+struct Options {
+    elf_path: String,
+    output_path: String,
+    memory_regions: Vec<MemoryRegion>,
+    measured_size: u64,
+    num_vcpus: u32,
+    entry_arg: u64,
+    imsic_base: Option<u64>,
+    svn: u32,
+    product_id: [u8; PRODUCT_ID_SIZE],
+    signing_key: Option<String>,
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    eprintln!("Usage: tvm_bundle_builder <ELF> <OUTPUT> [OPTIONS]");
+    process::exit(1);
+}
+
+fn parse_u64(s: &str) -> u64 {
+    let val = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    val.unwrap_or_else(|_| usage(&format!("Invalid number '{}'", s)))
+}
+
+fn parse_product_id(s: &str) -> [u8; PRODUCT_ID_SIZE] {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.len() > PRODUCT_ID_SIZE * 2 || s.len() % 2 != 0 {
+        usage("Product ID must be an even number of hex digits, at most 16 bytes");
+    }
+    let mut id = [0u8; PRODUCT_ID_SIZE];
+    for (i, byte) in id.iter_mut().enumerate().take(s.len() / 2) {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .unwrap_or_else(|_| usage("Invalid product ID"));
+    }
+    id
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let elf_path = args.next().unwrap_or_else(|| usage("No ELF path"));
+    let output_path = args.next().unwrap_or_else(|| usage("No output path"));
+    let mut opts = Options {
+        elf_path,
+        output_path,
+        memory_regions: Vec::new(),
+        measured_size: 0,
+        num_vcpus: 1,
+        entry_arg: 0,
+        imsic_base: None,
+        svn: 0,
+        product_id: [0; PRODUCT_ID_SIZE],
+        signing_key: None,
+    };
+    while let Some(arg) = args.next() {
+        let val = args
+            .next()
+            .unwrap_or_else(|| usage(&format!("No value for {}", arg)));
+        match arg.as_str() {
+            "--memory-region" => {
+                let (gpa, size) = val
+                    .split_once(':')
+                    .unwrap_or_else(|| usage("Memory regions are given as GPA:SIZE"));
+                opts.memory_regions.push(MemoryRegion {
+                    gpa: parse_u64(gpa),
+                    size: parse_u64(size),
+                });
+            }
+            "--measured-size" => opts.measured_size = parse_u64(&val),
+            "--num-vcpus" => {
+                opts.num_vcpus = parse_u64(&val)
+                    .try_into()
+                    .unwrap_or_else(|_| usage("Too many vCPUs"))
+            }
+            "--entry-arg" => opts.entry_arg = parse_u64(&val),
+            "--imsic" => opts.imsic_base = Some(parse_u64(&val)),
+            "--svn" => {
+                opts.svn = parse_u64(&val)
+                    .try_into()
+                    .unwrap_or_else(|_| usage("SVN is too large"))
+            }
+            "--product-id" => opts.product_id = parse_product_id(&val),
+            "--signing-key" => opts.signing_key = Some(val),
+            _ => usage(&format!("Unknown option {}", arg)),
+        }
+    }
+    opts
+}
+
+fn round_up(val: u64, align: u64) -> u64 {
+    (val + align - 1) & !(align - 1)
+}
+
+// Lays out the ELF's segments as they're linked, returning the address of the first page and the
+// contents of the pages.
+fn load_image(elf: &ElfMap, measured_size: u64) -> (u64, Vec<u8>) {
+    let base = elf
+        .segments()
+        .map(|s| s.vaddr())
+        .min()
+        .unwrap_or_else(|| usage("ELF has no loadable segments"))
+        & !(PAGE_SIZE - 1);
+    let end = elf
+        .segments()
+        .map(|s| s.vaddr() + s.size() as u64)
+        .max()
+        .unwrap();
+    let len = round_up((end - base).max(measured_size), PAGE_SIZE);
+    let mut pages = vec![0u8; len as usize];
+    for s in elf.segments() {
+        if let Some(data) = s.data() {
+            let len = data.len().min(s.size());
+            let start = (s.vaddr() - base) as usize;
+            pages[start..start + len].copy_from_slice(&data[..len]);
+        }
+    }
+    // Position-independent ELFs are loaded at their link-time addresses.
+    elf.relocate(0, base, &mut pages);
+    (base, pages)
+}
+
+fn sign(manifest: &Manifest, key_path: &str) -> Signature {
+    let key_bytes = fs::read(key_path).expect("error reading signing key");
+    let secret = SecretKey::from_bytes(&key_bytes)
+        .unwrap_or_else(|_| usage("Signing key must be a raw 32-byte Ed25519 key"));
+    let public = PublicKey::from(&secret);
+    let keypair = Keypair { secret, public };
+    let signature = keypair.sign(&manifest.encode());
+    // Unwrap ok: the lengths match the algorithm.
+    Signature::new(
+        SignatureAlgorithm::Ed25519,
+        public.as_bytes(),
+        &signature.to_bytes(),
+    )
+    .unwrap()
+}
+
+fn main() {
+    let opts = parse_args();
+
+    let elf_bytes = fs::read(&opts.elf_path).expect("error reading ELF");
+    let elf = ElfMap::new(&elf_bytes).unwrap_or_else(|e| usage(&format!("Invalid ELF: {:?}", e)));
+    let (gpa, pages) = load_image(&elf, opts.measured_size);
+    let num_pages = pages.len() as u64 / PAGE_SIZE;
+
+    let mut memory_regions = opts.memory_regions;
+    if memory_regions.is_empty() {
+        memory_regions.push(MemoryRegion {
+            gpa,
+            size: pages.len() as u64,
+        });
+    }
+    if !memory_regions
+        .iter()
+        .any(|r| gpa >= r.gpa && gpa + pages.len() as u64 <= r.gpa + r.size)
+    {
+        usage("The image isn't within a memory region");
+    }
+
+    let (tvm_page_digest, tvm_config_digest) =
+        expected_measurements(gpa, &pages, elf.entry(), opts.entry_arg);
+    let manifest = Manifest {
+        hash_algorithm: HashAlgorithm::Sha384,
+        svn: opts.svn,
+        product_id: opts.product_id,
+        tvm_page_digest,
+        tvm_config_digest,
+    };
+    let signature = opts.signing_key.map(|path| sign(&manifest, &path));
+
+    let mut header = Header {
+        entry_pc: elf.entry(),
+        entry_arg: opts.entry_arg,
+        num_vcpus: opts.num_vcpus,
+        num_regions: memory_regions.len() as u32,
+        num_page_ranges: 1,
+        aia: opts.imsic_base.map(|imsic_base_addr| AiaLayout {
+            imsic_base_addr,
+            group_index_shift: IMSIC_GROUP_INDEX_SHIFT,
+            hart_index_bits: IMSIC_HART_INDEX_BITS,
+            ..Default::default()
+        }),
+        signed: signature.is_some(),
+        ..Default::default()
+    };
+    let data_offset = round_up(header.tables_size() as u64, PAGE_DATA_ALIGN);
+    header.total_size = data_offset + pages.len() as u64;
+
+    let mut bundle = Vec::with_capacity(header.total_size as usize);
+    bundle.extend_from_slice(&header.encode());
+    for r in memory_regions.iter() {
+        bundle.extend_from_slice(&r.encode());
+    }
+    let range = PageRangeEntry {
+        gpa,
+        num_pages,
+        data_offset,
+        page_type: PageType::Page4k,
+    };
+    bundle.extend_from_slice(&range.encode());
+    bundle.extend_from_slice(&manifest.encode());
+    if let Some(signature) = signature {
+        bundle.extend_from_slice(&signature.encode());
+    }
+    bundle.resize(data_offset as usize, 0);
+    bundle.extend_from_slice(&pages);
+
+    // Make sure what we wrote can be read back.
+    TvmBundle::parse(&bundle).expect("built an invalid bundle");
+    fs::write(&opts.output_path, &bundle).expect("error writing bundle");
+}
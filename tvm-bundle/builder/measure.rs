@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Computes the measurements the TSM takes of a TVM built from a bundle.
+
+use sha2::{Digest, Sha384};
+use tvm_bundle::DIGEST_SIZE;
+
+use crate::PAGE_SIZE;
+
+// Extends a measurement register the way the TSM does: the new digest is the hash of the old one,
+// the address of the measured bytes if there is one, and the bytes.
+fn extend(digest: &mut [u8; DIGEST_SIZE], address: Option<u64>, bytes: &[u8]) {
+    let mut hasher = Sha384::new_with_prefix(&digest[..]);
+    if let Some(address) = address {
+        hasher.update(address.to_le_bytes());
+    }
+    hasher.update(bytes);
+    digest.copy_from_slice(&hasher.finalize());
+}
+
+/// Computes the expected TVM page and configuration measurements of a TVM whose measured `pages`
+/// are added at `gpa`, and which is finalized with `entry_pc` and `entry_arg` and no attributes.
+pub fn expected_measurements(
+    gpa: u64,
+    pages: &[u8],
+    entry_pc: u64,
+    entry_arg: u64,
+) -> ([u8; DIGEST_SIZE], [u8; DIGEST_SIZE]) {
+    let mut tvm_page_digest = [0u8; DIGEST_SIZE];
+    for (i, page) in pages.chunks(PAGE_SIZE as usize).enumerate() {
+        extend(&mut tvm_page_digest, Some(gpa + i as u64 * PAGE_SIZE), page);
+    }
+    let mut tvm_config_digest = [0u8; DIGEST_SIZE];
+    extend(&mut tvm_config_digest, None, &entry_pc.to_le_bytes());
+    extend(&mut tvm_config_digest, None, &entry_arg.to_le_bytes());
+    (tvm_page_digest, tvm_config_digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn known_measurements() {
+        let mut pages = vec![0u8; 2 * PAGE_SIZE as usize];
+        pages[PAGE_SIZE as usize..].fill(0x11);
+        let (page_digest, config_digest) =
+            expected_measurements(0x8000_0000, &pages, 0x8000_0000, 1);
+        // SHA-384(SHA-384(0^48 || 0x80000000 || page 0) || 0x80001000 || page 1), with addresses
+        // in little-endian.
+        let expected = hex!("2dada3c1185bb5f3598a6a221b4856e8f765a065e7b8a58820b4c6bf2779b2276aac3acfddd2ed628e33ce856eddb0bf");
+        assert_eq!(page_digest, expected);
+        // SHA-384(SHA-384(0^48 || entry_pc) || entry_arg), in little-endian.
+        let expected = hex!("e28dcc0bca14dc0b071316fae81d2847ad9ce34909d838bd0143186eb43bef0be67dfda5e45d66f90136d51fed6980c5");
+        assert_eq!(config_digest, expected);
+    }
+
+    #[test]
+    fn measurements_depend_on_addresses_and_arguments() {
+        let pages = vec![0u8; PAGE_SIZE as usize];
+        let (page_digest, config_digest) = expected_measurements(0x8000_0000, &pages, 0, 0);
+        let (moved_page_digest, _) = expected_measurements(0x8000_1000, &pages, 0, 0);
+        assert_ne!(page_digest, moved_page_digest);
+        let (_, other_config_digest) = expected_measurements(0x8000_0000, &pages, 0, 1);
+        assert_ne!(config_digest, other_config_digest);
+    }
+}
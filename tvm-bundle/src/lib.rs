@@ -0,0 +1,836 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#![no_std]
+
+//! The TVM bundle format: a single artifact describing everything a host needs to build a TVM
+//! through the CoVE host ABI, and the measurements the TSM is expected to produce for it.
+//!
+//! All fields are little-endian. A bundle is laid out as follows:
+//!
+//! ```text
+//! +----------------------------+ 0
+//! | Header                     |
+//! +----------------------------+ HEADER_SIZE
+//! | Memory regions             | num_regions * MEMORY_REGION_SIZE
+//! +----------------------------+
+//! | Page ranges                | num_page_ranges * PAGE_RANGE_SIZE
+//! +----------------------------+
+//! | Manifest                   | MANIFEST_SIZE
+//! +----------------------------+
+//! | Signature (optional)       | SIGNATURE_SIZE, if FLAG_SIGNED is set
+//! +----------------------------+
+//! | Page data                  | 4kB aligned, referenced by the page ranges
+//! +----------------------------+ total_size
+//! ```
+//!
+//! The manifest holds the expected values of the TVM page (PCR2) and configuration (PCR3)
+//! measurements, along with a product ID and security version number. If the bundle is signed,
+//! the signature covers the encoded manifest.
+
+// For testing use the std crate.
+#[cfg(test)]
+#[macro_use]
+extern crate std;
+
+use core::{fmt, result};
+
+/// The magic number at the start of every bundle.
+pub const MAGIC: [u8; 8] = *b"TVMBNDL\0";
+/// The version of the bundle format described here.
+pub const VERSION: u32 = 1;
+
+/// Size in bytes of the bundle header.
+pub const HEADER_SIZE: usize = 96;
+/// Size in bytes of a memory region entry.
+pub const MEMORY_REGION_SIZE: usize = 16;
+/// Size in bytes of a page range entry.
+pub const PAGE_RANGE_SIZE: usize = 32;
+/// Size in bytes of the manifest.
+pub const MANIFEST_SIZE: usize = 128;
+/// Size in bytes of the signature block.
+pub const SIGNATURE_SIZE: usize = 272;
+/// Alignment of page data within the bundle.
+pub const PAGE_DATA_ALIGN: u64 = 4096;
+
+/// Size in bytes of the measurement digests in the manifest.
+pub const DIGEST_SIZE: usize = 48;
+/// Size in bytes of the product ID in the manifest.
+pub const PRODUCT_ID_SIZE: usize = 16;
+/// Maximum size in bytes of a public key in the signature block.
+pub const MAX_PUBLIC_KEY_SIZE: usize = 128;
+/// Maximum size in bytes of a signature in the signature block.
+pub const MAX_SIGNATURE_SIZE: usize = 128;
+
+/// Set in the header flags if the bundle describes the TVM's IMSIC layout.
+pub const FLAG_AIA: u32 = 1 << 0;
+/// Set in the header flags if the bundle has a signature block.
+pub const FLAG_SIGNED: u32 = 1 << 1;
+const FLAGS_ALL: u32 = FLAG_AIA | FLAG_SIGNED;
+
+/// Bundle parsing errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The bundle is smaller than its header or than the size the header gives.
+    Truncated,
+    /// The magic number is wrong.
+    BadMagic,
+    /// The bundle is of a format version we don't understand.
+    UnsupportedVersion(u32),
+    /// Unknown flags are set in the header.
+    UnknownFlags(u32),
+    /// The tables or page data don't fit within the bundle.
+    BadOffset,
+    /// A page range has an unknown page type.
+    BadPageType(u32),
+    /// A page range's data isn't aligned or its GPA isn't aligned to its page size.
+    Unaligned,
+    /// The manifest uses an unknown measurement algorithm.
+    BadHashAlgorithm(u32),
+    /// The signature block uses an unknown algorithm.
+    BadSignatureAlgorithm(u32),
+    /// The signature block's key or signature length is out of range for its algorithm.
+    BadSignatureLength,
+}
+
+/// Custom bundle result.
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Truncated => write!(f, "Bundle is truncated"),
+            BadMagic => write!(f, "Not a TVM bundle"),
+            UnsupportedVersion(v) => write!(f, "Unsupported bundle version {}", v),
+            UnknownFlags(flags) => write!(f, "Unknown bundle flags 0x{:x}", flags),
+            BadOffset => write!(f, "Bundle table or page data out of bounds"),
+            BadPageType(t) => write!(f, "Unknown page type {}", t),
+            Unaligned => write!(f, "Page range is not aligned"),
+            BadHashAlgorithm(a) => write!(f, "Unknown measurement algorithm {}", a),
+            BadSignatureAlgorithm(a) => write!(f, "Unknown signature algorithm {}", a),
+            BadSignatureLength => write!(f, "Bad signature or public key length"),
+        }
+    }
+}
+
+// Reads little-endian integers out of a fixed-layout structure.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&self, offset: usize) -> u32 {
+        // Unwrap ok: the slice is 4 bytes long.
+        u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64(&self, offset: usize) -> u64 {
+        // Unwrap ok: the slice is 8 bytes long.
+        u64::from_le_bytes(self.bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn array<const N: usize>(&self, offset: usize) -> [u8; N] {
+        // Unwrap ok: the slice is N bytes long.
+        self.bytes[offset..offset + N].try_into().unwrap()
+    }
+}
+
+// Writes little-endian integers into a fixed-layout structure.
+struct Writer<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> Writer<'a> {
+    fn u32(&mut self, offset: usize, val: u32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn u64(&mut self, offset: usize, val: u64) {
+        self.bytes[offset..offset + 8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn slice(&mut self, offset: usize, val: &[u8]) {
+        self.bytes[offset..offset + val.len()].copy_from_slice(val);
+    }
+}
+
+/// The IMSIC layout of the TVM, as passed to `TvmAiaInit`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AiaLayout {
+    /// Guest physical address of the IMSIC interrupt file of the TVM's first vCPU.
+    pub imsic_base_addr: u64,
+    /// Number of group index bits in IMSIC addresses.
+    pub group_index_bits: u32,
+    /// Position of the group index in IMSIC addresses.
+    pub group_index_shift: u32,
+    /// Number of hart index bits in IMSIC addresses.
+    pub hart_index_bits: u32,
+    /// Number of guest index bits in IMSIC addresses.
+    pub guest_index_bits: u32,
+    /// Number of guest interrupt files per hart.
+    pub guests_per_hart: u32,
+}
+
+impl AiaLayout {
+    /// Returns the guest physical address of the IMSIC interrupt file for `vcpu_id`, assuming vCPU
+    /// IDs are used as hart indices within group 0.
+    pub fn vcpu_imsic_addr(&self, vcpu_id: u64) -> u64 {
+        self.imsic_base_addr + (vcpu_id << (12 + self.guest_index_bits))
+    }
+}
+
+/// The fixed-size header at the start of a bundle.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Header {
+    /// Total size of the bundle in bytes, including page data.
+    pub total_size: u64,
+    /// Initial program counter of the TVM's boot vCPU.
+    pub entry_pc: u64,
+    /// The boot argument assumed by the manifest's configuration measurement.
+    pub entry_arg: u64,
+    /// The number of vCPUs to create.
+    pub num_vcpus: u32,
+    /// The number of entries in the memory region table.
+    pub num_regions: u32,
+    /// The number of entries in the page range table.
+    pub num_page_ranges: u32,
+    /// The TVM's IMSIC layout, if it uses AIA.
+    pub aia: Option<AiaLayout>,
+    /// Whether the bundle has a signature block.
+    pub signed: bool,
+}
+
+impl Header {
+    /// Decodes and validates the header at the start of `bytes`.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::Truncated);
+        }
+        let r = Reader { bytes };
+        if r.array::<8>(0) != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let version = r.u32(8);
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let flags = r.u32(12);
+        if flags & !FLAGS_ALL != 0 {
+            return Err(Error::UnknownFlags(flags & !FLAGS_ALL));
+        }
+        let aia = (flags & FLAG_AIA != 0).then(|| AiaLayout {
+            imsic_base_addr: r.u64(56),
+            group_index_bits: r.u32(64),
+            group_index_shift: r.u32(68),
+            hart_index_bits: r.u32(72),
+            guest_index_bits: r.u32(76),
+            guests_per_hart: r.u32(80),
+        });
+        Ok(Self {
+            total_size: r.u64(16),
+            entry_pc: r.u64(24),
+            entry_arg: r.u64(32),
+            num_vcpus: r.u32(40),
+            num_regions: r.u32(44),
+            num_page_ranges: r.u32(48),
+            aia,
+            signed: flags & FLAG_SIGNED != 0,
+        })
+    }
+
+    /// Encodes the header.
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.slice(0, &MAGIC);
+        w.u32(8, VERSION);
+        let mut flags = 0;
+        if let Some(aia) = self.aia {
+            flags |= FLAG_AIA;
+            w.u64(56, aia.imsic_base_addr);
+            w.u32(64, aia.group_index_bits);
+            w.u32(68, aia.group_index_shift);
+            w.u32(72, aia.hart_index_bits);
+            w.u32(76, aia.guest_index_bits);
+            w.u32(80, aia.guests_per_hart);
+        }
+        if self.signed {
+            flags |= FLAG_SIGNED;
+        }
+        w.u32(12, flags);
+        w.u64(16, self.total_size);
+        w.u64(24, self.entry_pc);
+        w.u64(32, self.entry_arg);
+        w.u32(40, self.num_vcpus);
+        w.u32(44, self.num_regions);
+        w.u32(48, self.num_page_ranges);
+        bytes
+    }
+
+    // Returns the offset of the manifest from the start of the bundle.
+    fn manifest_offset(&self) -> usize {
+        HEADER_SIZE
+            + self.num_regions as usize * MEMORY_REGION_SIZE
+            + self.num_page_ranges as usize * PAGE_RANGE_SIZE
+    }
+
+    /// Returns the size of the header and tables, i.e. the offset at which page data may start.
+    pub fn tables_size(&self) -> usize {
+        let signature_size = if self.signed { SIGNATURE_SIZE } else { 0 };
+        self.manifest_offset() + MANIFEST_SIZE + signature_size
+    }
+}
+
+/// A range of the TVM's guest physical address space to declare as confidential memory.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryRegion {
+    /// The guest physical address at which the region starts.
+    pub gpa: u64,
+    /// The length of the region in bytes.
+    pub size: u64,
+}
+
+impl MemoryRegion {
+    /// Decodes a memory region entry.
+    pub fn decode(bytes: &[u8; MEMORY_REGION_SIZE]) -> Self {
+        let r = Reader { bytes };
+        Self {
+            gpa: r.u64(0),
+            size: r.u64(8),
+        }
+    }
+
+    /// Encodes the memory region entry.
+    pub fn encode(&self) -> [u8; MEMORY_REGION_SIZE] {
+        let mut bytes = [0u8; MEMORY_REGION_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u64(0, self.gpa);
+        w.u64(8, self.size);
+        bytes
+    }
+}
+
+/// The size of the pages in a page range, matching the CoVE `TsmPageType` values.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageType {
+    /// 4kB pages.
+    Page4k = 0,
+    /// 2MB pages.
+    Page2M = 1,
+    /// 1GB pages.
+    Page1G = 2,
+}
+
+impl PageType {
+    /// Returns the size of a page of this type in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            PageType::Page4k => 1 << 12,
+            PageType::Page2M => 1 << 21,
+            PageType::Page1G => 1 << 30,
+        }
+    }
+}
+
+impl TryFrom<u32> for PageType {
+    type Error = Error;
+    fn try_from(val: u32) -> Result<Self> {
+        match val {
+            0 => Ok(PageType::Page4k),
+            1 => Ok(PageType::Page2M),
+            2 => Ok(PageType::Page1G),
+            _ => Err(Error::BadPageType(val)),
+        }
+    }
+}
+
+/// A range of measured pages to add to the TVM, with their contents stored in the bundle.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageRangeEntry {
+    /// The guest physical address at which to map the first page.
+    pub gpa: u64,
+    /// The number of pages in the range.
+    pub num_pages: u64,
+    /// Offset from the start of the bundle of the pages' contents.
+    pub data_offset: u64,
+    /// The size of the pages.
+    pub page_type: PageType,
+}
+
+impl PageRangeEntry {
+    /// Decodes a page range entry.
+    pub fn decode(bytes: &[u8; PAGE_RANGE_SIZE]) -> Result<Self> {
+        let r = Reader { bytes };
+        Ok(Self {
+            gpa: r.u64(0),
+            num_pages: r.u64(8),
+            data_offset: r.u64(16),
+            page_type: r.u32(24).try_into()?,
+        })
+    }
+
+    /// Encodes the page range entry.
+    pub fn encode(&self) -> [u8; PAGE_RANGE_SIZE] {
+        let mut bytes = [0u8; PAGE_RANGE_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u64(0, self.gpa);
+        w.u64(8, self.num_pages);
+        w.u64(16, self.data_offset);
+        w.u32(24, self.page_type as u32);
+        bytes
+    }
+
+    /// Returns the length in bytes of the range.
+    pub fn length_bytes(&self) -> Option<u64> {
+        self.num_pages.checked_mul(self.page_type.size())
+    }
+}
+
+/// A range of measured pages in a parsed bundle.
+#[derive(Clone, Copy, Debug)]
+pub struct PageRange<'a> {
+    entry: PageRangeEntry,
+    data: &'a [u8],
+}
+
+impl<'a> PageRange<'a> {
+    /// Returns the guest physical address at which to map the first page.
+    pub fn gpa(&self) -> u64 {
+        self.entry.gpa
+    }
+
+    /// Returns the number of pages in the range.
+    pub fn num_pages(&self) -> u64 {
+        self.entry.num_pages
+    }
+
+    /// Returns the size of the pages.
+    pub fn page_type(&self) -> PageType {
+        self.entry.page_type
+    }
+
+    /// Returns the contents of the pages.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// The hash algorithm used for the manifest's measurements.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    /// SHA-384, as used by the TSM for TVM measurements.
+    Sha384 = 1,
+}
+
+impl TryFrom<u32> for HashAlgorithm {
+    type Error = Error;
+    fn try_from(val: u32) -> Result<Self> {
+        match val {
+            1 => Ok(HashAlgorithm::Sha384),
+            _ => Err(Error::BadHashAlgorithm(val)),
+        }
+    }
+}
+
+/// The measurements the TSM is expected to produce for the TVM described by the bundle.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    /// The algorithm used for the measurements.
+    pub hash_algorithm: HashAlgorithm,
+    /// The security version number of the TVM image.
+    pub svn: u32,
+    /// Identifies the product the TVM image belongs to.
+    pub product_id: [u8; PRODUCT_ID_SIZE],
+    /// The expected TVM page measurement (PCR2).
+    pub tvm_page_digest: [u8; DIGEST_SIZE],
+    /// The expected TVM configuration measurement (PCR3).
+    pub tvm_config_digest: [u8; DIGEST_SIZE],
+}
+
+impl Manifest {
+    /// Decodes the manifest.
+    pub fn decode(bytes: &[u8; MANIFEST_SIZE]) -> Result<Self> {
+        let r = Reader { bytes };
+        Ok(Self {
+            hash_algorithm: r.u32(0).try_into()?,
+            svn: r.u32(4),
+            product_id: r.array(8),
+            tvm_page_digest: r.array(24),
+            tvm_config_digest: r.array(72),
+        })
+    }
+
+    /// Encodes the manifest. This is the message covered by the bundle's signature.
+    pub fn encode(&self) -> [u8; MANIFEST_SIZE] {
+        let mut bytes = [0u8; MANIFEST_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        w.u32(0, self.hash_algorithm as u32);
+        w.u32(4, self.svn);
+        w.slice(8, &self.product_id);
+        w.slice(24, &self.tvm_page_digest);
+        w.slice(72, &self.tvm_config_digest);
+        bytes
+    }
+}
+
+/// The algorithm used to sign a bundle's manifest.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SignatureAlgorithm {
+    /// Ed25519, with a 32-byte public key and a 64-byte signature.
+    Ed25519 = 1,
+    /// ECDSA over P-384 with SHA-384, with a SEC1 uncompressed public key and a fixed-size
+    /// (r, s) signature.
+    EcdsaP384 = 2,
+}
+
+impl SignatureAlgorithm {
+    /// Returns the sizes in bytes of the public key and signature for this algorithm.
+    pub fn sizes(&self) -> (usize, usize) {
+        match self {
+            SignatureAlgorithm::Ed25519 => (32, 64),
+            SignatureAlgorithm::EcdsaP384 => (97, 96),
+        }
+    }
+}
+
+impl TryFrom<u32> for SignatureAlgorithm {
+    type Error = Error;
+    fn try_from(val: u32) -> Result<Self> {
+        match val {
+            1 => Ok(SignatureAlgorithm::Ed25519),
+            2 => Ok(SignatureAlgorithm::EcdsaP384),
+            _ => Err(Error::BadSignatureAlgorithm(val)),
+        }
+    }
+}
+
+/// The signature over a bundle's manifest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Signature {
+    algorithm: SignatureAlgorithm,
+    public_key: [u8; MAX_PUBLIC_KEY_SIZE],
+    signature: [u8; MAX_SIGNATURE_SIZE],
+}
+
+impl Signature {
+    /// Creates a signature block from the signer's public key and its signature of the manifest.
+    pub fn new(algorithm: SignatureAlgorithm, public_key: &[u8], signature: &[u8]) -> Result<Self> {
+        if algorithm.sizes() != (public_key.len(), signature.len()) {
+            return Err(Error::BadSignatureLength);
+        }
+        let mut this = Self {
+            algorithm,
+            public_key: [0; MAX_PUBLIC_KEY_SIZE],
+            signature: [0; MAX_SIGNATURE_SIZE],
+        };
+        this.public_key[..public_key.len()].copy_from_slice(public_key);
+        this.signature[..signature.len()].copy_from_slice(signature);
+        Ok(this)
+    }
+
+    /// Decodes a signature block.
+    pub fn decode(bytes: &[u8; SIGNATURE_SIZE]) -> Result<Self> {
+        let r = Reader { bytes };
+        let algorithm: SignatureAlgorithm = r.u32(0).try_into()?;
+        if algorithm.sizes() != (r.u32(4) as usize, r.u32(8) as usize) {
+            return Err(Error::BadSignatureLength);
+        }
+        Ok(Self {
+            algorithm,
+            public_key: r.array(16),
+            signature: r.array(16 + MAX_PUBLIC_KEY_SIZE),
+        })
+    }
+
+    /// Encodes the signature block.
+    pub fn encode(&self) -> [u8; SIGNATURE_SIZE] {
+        let mut bytes = [0u8; SIGNATURE_SIZE];
+        let mut w = Writer { bytes: &mut bytes };
+        let (key_len, sig_len) = self.algorithm.sizes();
+        w.u32(0, self.algorithm as u32);
+        w.u32(4, key_len as u32);
+        w.u32(8, sig_len as u32);
+        w.slice(16, &self.public_key);
+        w.slice(16 + MAX_PUBLIC_KEY_SIZE, &self.signature);
+        bytes
+    }
+
+    /// Returns the signature algorithm.
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the signer's public key.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key[..self.algorithm.sizes().0]
+    }
+
+    /// Returns the signature of the encoded manifest.
+    pub fn signature(&self) -> &[u8] {
+        &self.signature[..self.algorithm.sizes().1]
+    }
+}
+
+/// A parsed TVM bundle.
+#[derive(Clone, Copy, Debug)]
+pub struct TvmBundle<'a> {
+    bytes: &'a [u8],
+    header: Header,
+    manifest: Manifest,
+    signature: Option<Signature>,
+}
+
+impl<'a> TvmBundle<'a> {
+    /// Parses and validates the bundle in `bytes`, which may extend beyond the end of the bundle.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let header = Header::decode(bytes)?;
+        let bytes = usize::try_from(header.total_size)
+            .ok()
+            .and_then(|size| bytes.get(..size))
+            .ok_or(Error::Truncated)?;
+        if header.tables_size() > bytes.len() {
+            return Err(Error::BadOffset);
+        }
+
+        let manifest_offset = header.manifest_offset();
+        // Unwraps ok: we checked above that the tables are within the bundle.
+        let manifest = Manifest::decode(
+            bytes[manifest_offset..manifest_offset + MANIFEST_SIZE]
+                .try_into()
+                .unwrap(),
+        )?;
+        let signature = if header.signed {
+            let offset = manifest_offset + MANIFEST_SIZE;
+            Some(Signature::decode(
+                bytes[offset..offset + SIGNATURE_SIZE].try_into().unwrap(),
+            )?)
+        } else {
+            None
+        };
+
+        let this = Self {
+            bytes,
+            header,
+            manifest,
+            signature,
+        };
+        // Check the page ranges up front so that iterating over them can't fail.
+        for i in 0..header.num_page_ranges as usize {
+            this.page_range_at(i)?;
+        }
+        Ok(this)
+    }
+
+    /// Returns the size of the bundle whose header is at the start of `bytes`. Useful to find the
+    /// extent of a bundle in memory before parsing it.
+    pub fn size_from_header(bytes: &[u8]) -> Result<u64> {
+        Ok(Header::decode(bytes)?.total_size)
+    }
+
+    fn page_range_at(&self, index: usize) -> Result<PageRange<'a>> {
+        let offset = HEADER_SIZE
+            + self.header.num_regions as usize * MEMORY_REGION_SIZE
+            + index * PAGE_RANGE_SIZE;
+        // Unwrap ok: `parse()` checked that the tables are within the bundle.
+        let entry = PageRangeEntry::decode(
+            self.bytes[offset..offset + PAGE_RANGE_SIZE]
+                .try_into()
+                .unwrap(),
+        )?;
+        if entry.data_offset % PAGE_DATA_ALIGN != 0 || entry.gpa % entry.page_type.size() != 0 {
+            return Err(Error::Unaligned);
+        }
+        let data = entry
+            .length_bytes()
+            .and_then(|len| entry.data_offset.checked_add(len))
+            .and_then(|end| {
+                self.bytes
+                    .get(entry.data_offset as usize..usize::try_from(end).ok()?)
+            })
+            .ok_or(Error::BadOffset)?;
+        Ok(PageRange { entry, data })
+    }
+
+    /// Returns the bundle's header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns the initial program counter of the boot vCPU.
+    pub fn entry_pc(&self) -> u64 {
+        self.header.entry_pc
+    }
+
+    /// Returns the boot argument the manifest's measurements assume.
+    pub fn entry_arg(&self) -> u64 {
+        self.header.entry_arg
+    }
+
+    /// Returns the number of vCPUs to create.
+    pub fn num_vcpus(&self) -> u32 {
+        self.header.num_vcpus
+    }
+
+    /// Returns the TVM's IMSIC layout, if it uses AIA.
+    pub fn aia_layout(&self) -> Option<AiaLayout> {
+        self.header.aia
+    }
+
+    /// Returns an iterator over the confidential memory regions of the TVM.
+    pub fn memory_regions(&self) -> impl Iterator<Item = MemoryRegion> + 'a {
+        let bytes = self.bytes;
+        (0..self.header.num_regions as usize).map(move |i| {
+            let offset = HEADER_SIZE + i * MEMORY_REGION_SIZE;
+            // Unwrap ok: `parse()` checked that the tables are within the bundle.
+            MemoryRegion::decode(
+                bytes[offset..offset + MEMORY_REGION_SIZE]
+                    .try_into()
+                    .unwrap(),
+            )
+        })
+    }
+
+    /// Returns an iterator over the measured page ranges of the TVM, in the order they must be
+    /// added to produce the expected measurement.
+    pub fn page_ranges(&self) -> impl Iterator<Item = PageRange<'a>> + '_ {
+        // Unwrap ok: `parse()` checked that each page range is valid.
+        (0..self.header.num_page_ranges as usize).map(|i| self.page_range_at(i).unwrap())
+    }
+
+    /// Returns the total number of 4kB pages in the bundle's page ranges.
+    pub fn num_4k_pages(&self) -> u64 {
+        self.page_ranges()
+            .map(|r| r.num_pages() * (r.page_type().size() / PageType::Page4k.size()))
+            .sum()
+    }
+
+    /// Returns the manifest of expected measurements.
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Returns the signature over the manifest, if the bundle is signed.
+    pub fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn build_bundle(signed: bool) -> Vec<u8> {
+        let mut header = Header {
+            entry_pc: 0x8020_0000,
+            entry_arg: 1,
+            num_vcpus: 2,
+            num_regions: 1,
+            num_page_ranges: 1,
+            aia: Some(AiaLayout {
+                imsic_base_addr: 0x2800_0000,
+                group_index_shift: 24,
+                hart_index_bits: 8,
+                ..Default::default()
+            }),
+            signed,
+            ..Default::default()
+        };
+        let data_offset =
+            (header.tables_size() as u64 + PAGE_DATA_ALIGN - 1) & !(PAGE_DATA_ALIGN - 1);
+        header.total_size = data_offset + 2 * PageType::Page4k.size();
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&header.encode());
+        let region = MemoryRegion {
+            gpa: 0x8020_0000,
+            size: 0x10_0000,
+        };
+        bytes.extend_from_slice(&region.encode());
+        let range = PageRangeEntry {
+            gpa: 0x8020_0000,
+            num_pages: 2,
+            data_offset,
+            page_type: PageType::Page4k,
+        };
+        bytes.extend_from_slice(&range.encode());
+        let manifest = Manifest {
+            hash_algorithm: HashAlgorithm::Sha384,
+            svn: 3,
+            product_id: [0xa5; PRODUCT_ID_SIZE],
+            tvm_page_digest: [1; DIGEST_SIZE],
+            tvm_config_digest: [2; DIGEST_SIZE],
+        };
+        bytes.extend_from_slice(&manifest.encode());
+        if signed {
+            let sig = Signature::new(SignatureAlgorithm::Ed25519, &[3; 32], &[4; 64]).unwrap();
+            bytes.extend_from_slice(&sig.encode());
+        }
+        bytes.resize(data_offset as usize, 0);
+        bytes.resize(header.total_size as usize, 0x5a);
+        bytes
+    }
+
+    #[test]
+    fn parse_bundle() {
+        let bytes = build_bundle(false);
+        let bundle = TvmBundle::parse(&bytes).unwrap();
+        assert_eq!(bundle.entry_pc(), 0x8020_0000);
+        assert_eq!(bundle.entry_arg(), 1);
+        assert_eq!(bundle.num_vcpus(), 2);
+        let aia = bundle.aia_layout().unwrap();
+        assert_eq!(aia.vcpu_imsic_addr(1), 0x2800_1000);
+        let regions: Vec<_> = bundle.memory_regions().collect();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].size, 0x10_0000);
+        let ranges: Vec<_> = bundle.page_ranges().collect();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0].data().len(), 0x2000);
+        assert!(ranges[0].data().iter().all(|b| *b == 0x5a));
+        assert_eq!(bundle.num_4k_pages(), 2);
+        assert_eq!(bundle.manifest().svn, 3);
+        assert_eq!(bundle.manifest().tvm_config_digest, [2; DIGEST_SIZE]);
+        assert!(bundle.signature().is_none());
+    }
+
+    #[test]
+    fn parse_signed_bundle() {
+        let bytes = build_bundle(true);
+        let bundle = TvmBundle::parse(&bytes).unwrap();
+        let sig = bundle.signature().unwrap();
+        assert_eq!(sig.algorithm(), SignatureAlgorithm::Ed25519);
+        assert_eq!(sig.public_key(), &[3; 32]);
+        assert_eq!(sig.signature(), &[4; 64]);
+        assert_eq!(bundle.page_ranges().next().unwrap().data()[0], 0x5a);
+    }
+
+    #[test]
+    fn bad_bundles() {
+        let mut bytes = build_bundle(false);
+        assert_eq!(
+            TvmBundle::parse(&bytes[..bytes.len() - 1]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            TvmBundle::parse(&bytes[..HEADER_SIZE - 1]).unwrap_err(),
+            Error::Truncated
+        );
+
+        // Move the page data out of bounds.
+        let range_offset = HEADER_SIZE + MEMORY_REGION_SIZE;
+        let mut bad = bytes.clone();
+        bad[range_offset + 16..range_offset + 24].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        assert_eq!(TvmBundle::parse(&bad).unwrap_err(), Error::BadOffset);
+        let mut bad = bytes.clone();
+        bad[range_offset + 16..range_offset + 24].copy_from_slice(&0x1001u64.to_le_bytes());
+        assert_eq!(TvmBundle::parse(&bad).unwrap_err(), Error::Unaligned);
+
+        bytes[0] = b'X';
+        assert_eq!(TvmBundle::parse(&bytes).unwrap_err(), Error::BadMagic);
+    }
+}
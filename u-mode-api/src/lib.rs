@@ -46,6 +46,8 @@
 
 /// Attestation-related data structures.
 pub mod cert;
+/// TVM manifest verification data structures.
+pub mod manifest;
 
 /// The Error type returned returned from this library.
 #[derive(Debug, Clone, Copy)]
@@ -139,11 +141,16 @@ pub enum UmodeRequest {
         /// size of the output Certificate.
         certout_len: usize,
     },
+    /// Verify a TVM's signed manifest against its measurements.
+    ///
+    /// Umode Input Region: contains `ManifestVerification`.
+    VerifyManifest,
 }
 
 // Mappings of A0 register to U-mode operation.
 const UMOP_NOP: u64 = 0;
 const UMOP_GET_EVIDENCE: u64 = 1;
+const UMOP_VERIFY_MANIFEST: u64 = 2;
 
 impl TryIntoRegisters for UmodeRequest {
     fn try_from_registers(regs: &[u64]) -> Result<UmodeRequest, Error> {
@@ -155,6 +162,7 @@ impl TryIntoRegisters for UmodeRequest {
                 certout_addr: regs[3],
                 certout_len: regs[3] as usize,
             }),
+            UMOP_VERIFY_MANIFEST => Ok(UmodeRequest::VerifyManifest),
             _ => Err(Error::RequestNotSupported),
         }
     }
@@ -176,6 +184,9 @@ impl TryIntoRegisters for UmodeRequest {
                 regs[3] = certout_addr;
                 regs[4] = certout_len as u64;
             }
+            UmodeRequest::VerifyManifest => {
+                regs[0] = UMOP_VERIFY_MANIFEST;
+            }
         }
     }
 }
@@ -0,0 +1,27 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::cert::MeasurementRegisterSha384;
+use data_model::DataInit;
+use tvm_bundle::{MANIFEST_SIZE, SIGNATURE_SIZE};
+
+/// Structure passed with `VerifyManifest` in the Umode Input Region.
+/// Holds a TVM's signed manifest, encoded as in a TVM bundle, and the
+/// measurements it must match.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ManifestVerification {
+    /// The encoded manifest.
+    pub manifest: [u8; MANIFEST_SIZE],
+    /// The encoded signature block over `manifest`.
+    pub signature: [u8; SIGNATURE_SIZE],
+    /// The TVM page measurement (PCR2) of the TVM being finalized.
+    pub tvm_page_digest: MeasurementRegisterSha384,
+    /// The TVM configuration measurement (PCR3) of the TVM being finalized.
+    pub tvm_config_digest: MeasurementRegisterSha384,
+}
+
+// Safety: `ManifestVerification` is a POD struct without implicit padding and therefore can be
+// initialized from a byte array.
+unsafe impl DataInit for ManifestVerification {}
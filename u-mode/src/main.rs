@@ -23,6 +23,7 @@ use test_system::*;
 use u_mode_api::{Error as UmodeApiError, UmodeRequest};
 
 mod cert;
+mod manifest;
 
 // Dummy global allocator - panic if anything tries to do an allocation.
 struct GeneralGlobalAlloc;
@@ -89,6 +90,24 @@ impl UmodeTask {
         })
     }
 
+    // Verify a TVM's signed manifest.
+    // Checks that the manifest is signed by the key in its signature block and that the
+    // measurements it signs match the ones of the TVM being finalized.
+    //
+    // U-mode Input Region: contains an instance of `ManifestVerification`.
+    fn op_verify_manifest(&self) -> Result<u64, UmodeApiError> {
+        let input_data = self
+            .vslice
+            .get_ref(0)
+            .map_err(|_| UmodeApiError::Failed)?
+            .load();
+        manifest::verify_manifest(&input_data).map_err(|e| {
+            println!("verify_manifest failed: {:?}", e);
+            UmodeApiError::InvalidArgument
+        })?;
+        Ok(0)
+    }
+
     // Run the main loop, receiving requests from the hypervisor and executing them.
     fn run_loop(&self) -> ! {
         let mut res = Ok(0);
@@ -104,6 +123,7 @@ impl UmodeTask {
                         certout_addr,
                         certout_len,
                     } => self.op_get_evidence(csr_addr, csr_len, certout_addr, certout_len),
+                    UmodeRequest::VerifyManifest => self.op_verify_manifest(),
                 },
                 Err(err) => Err(err),
             };
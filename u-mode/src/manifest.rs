@@ -0,0 +1,150 @@
+// SPDX-FileCopyrightText: 2023 Rivos Inc.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use ed25519_dalek::{PublicKey, Signature as Ed25519Signature};
+use tvm_bundle::{Error as BundleError, Manifest, Signature, SignatureAlgorithm};
+use u_mode_api::manifest::ManifestVerification;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot decode the manifest or its signature block.
+    Decode(BundleError),
+    /// The TVM page measurement doesn't match the manifest.
+    TvmPageMismatch,
+    /// The TVM configuration measurement doesn't match the manifest.
+    TvmConfigurationMismatch,
+    /// No implementation of the signature algorithm is available.
+    UnsupportedAlgorithm(SignatureAlgorithm),
+    /// The public key in the signature block is malformed.
+    BadPublicKey,
+    /// The signature doesn't verify against the manifest.
+    BadSignature,
+}
+
+// Verifies an Ed25519 signature of `msg`.
+fn verify_ed25519(public_key: &[u8], signature: &[u8], msg: &[u8]) -> Result<(), Error> {
+    let public_key = PublicKey::from_bytes(public_key).map_err(|_| Error::BadPublicKey)?;
+    let signature = Ed25519Signature::try_from(signature).map_err(|_| Error::BadSignature)?;
+    public_key
+        .verify_strict(msg, &signature)
+        .map_err(|_| Error::BadSignature)
+}
+
+// Checks that the manifest in `input` was signed by the key in its signature block and that it
+// matches the TVM's measurements.
+pub fn verify_manifest(input: &ManifestVerification) -> Result<(), Error> {
+    let manifest = Manifest::decode(&input.manifest).map_err(Error::Decode)?;
+    let signature = Signature::decode(&input.signature).map_err(Error::Decode)?;
+    match signature.algorithm() {
+        SignatureAlgorithm::Ed25519 => verify_ed25519(
+            signature.public_key(),
+            signature.signature(),
+            &input.manifest,
+        )?,
+        // TODO: Verify ECDSA P-384 signatures once we have an implementation of the curve.
+        alg => return Err(Error::UnsupportedAlgorithm(alg)),
+    }
+    if manifest.tvm_page_digest != input.tvm_page_digest {
+        return Err(Error::TvmPageMismatch);
+    }
+    if manifest.tvm_config_digest != input.tvm_config_digest {
+        return Err(Error::TvmConfigurationMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Keypair, SecretKey, Signer};
+    use tvm_bundle::HashAlgorithm;
+
+    const TVM_PAGE_DIGEST: [u8; 48] = [0x22; 48];
+    const TVM_CONFIG_DIGEST: [u8; 48] = [0x33; 48];
+
+    // Returns a manifest for TVM_PAGE_DIGEST and TVM_CONFIG_DIGEST signed with a test key, and
+    // those measurements.
+    fn signed_input() -> ManifestVerification {
+        let manifest = Manifest {
+            hash_algorithm: HashAlgorithm::Sha384,
+            svn: 3,
+            product_id: [0x11; 16],
+            tvm_page_digest: TVM_PAGE_DIGEST,
+            tvm_config_digest: TVM_CONFIG_DIGEST,
+        }
+        .encode();
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = PublicKey::from(&secret);
+        let keypair = Keypair { secret, public };
+        let signature = Signature::new(
+            SignatureAlgorithm::Ed25519,
+            public.as_bytes(),
+            &keypair.sign(&manifest).to_bytes(),
+        )
+        .unwrap();
+        ManifestVerification {
+            manifest,
+            signature: signature.encode(),
+            tvm_page_digest: TVM_PAGE_DIGEST,
+            tvm_config_digest: TVM_CONFIG_DIGEST,
+        }
+    }
+
+    #[test]
+    fn good_signature() {
+        assert!(verify_manifest(&signed_input()).is_ok());
+    }
+
+    #[test]
+    fn bad_signature() {
+        // Change the signed SVN.
+        let mut input = signed_input();
+        input.manifest[4] ^= 1;
+        assert!(matches!(verify_manifest(&input), Err(Error::BadSignature)));
+
+        // Change the signature itself.
+        let mut input = signed_input();
+        input.signature[16 + tvm_bundle::MAX_PUBLIC_KEY_SIZE] ^= 1;
+        assert!(matches!(verify_manifest(&input), Err(Error::BadSignature)));
+    }
+
+    #[test]
+    fn measurement_mismatch() {
+        let mut input = signed_input();
+        input.tvm_page_digest[0] ^= 1;
+        assert!(matches!(
+            verify_manifest(&input),
+            Err(Error::TvmPageMismatch)
+        ));
+
+        let mut input = signed_input();
+        input.tvm_config_digest[47] ^= 1;
+        assert!(matches!(
+            verify_manifest(&input),
+            Err(Error::TvmConfigurationMismatch)
+        ));
+    }
+
+    #[test]
+    fn truncated_signature() {
+        // A signature block claiming a shorter signature than Ed25519's.
+        let mut input = signed_input();
+        input.signature[8..12].copy_from_slice(&32u32.to_le_bytes());
+        assert!(matches!(
+            verify_manifest(&input),
+            Err(Error::Decode(BundleError::BadSignatureLength))
+        ));
+    }
+
+    #[test]
+    fn unsupported_algorithm() {
+        let mut input = signed_input();
+        let signature = Signature::new(SignatureAlgorithm::EcdsaP384, &[0; 97], &[0; 96]).unwrap();
+        input.signature = signature.encode();
+        assert!(matches!(
+            verify_manifest(&input),
+            Err(Error::UnsupportedAlgorithm(SignatureAlgorithm::EcdsaP384))
+        ));
+    }
+}